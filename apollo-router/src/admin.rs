@@ -0,0 +1,800 @@
+//! Admin API exposing the runtime state of the router.
+//!
+//! The admin API is served on its own listen address so that it can be kept off the public
+//! network, and every request must carry the configured bearer token.
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::extract::Extension;
+use axum::extract::Path;
+use axum::http::header::AUTHORIZATION;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::middleware::{self};
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum::routing::get;
+use axum::routing::put;
+use axum::Json;
+use axum::Router;
+use futures::channel::oneshot;
+use futures::prelude::*;
+use http::Request;
+use serde::Deserialize;
+use serde::Serialize;
+use subtle::ConstantTimeEq;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+use crate::cache::CacheStatistics;
+use crate::cache::CacheStats;
+use crate::configuration::Admin;
+use crate::configuration::Configuration;
+use crate::entitlement::Entitlement;
+use crate::executable::current_log_level;
+use crate::executable::set_log_level;
+use crate::load_shedding::shed_load;
+use crate::load_shedding::LoadShedder;
+use crate::plugins::traffic_shaping::CircuitState;
+use crate::plugins::traffic_shaping::SubgraphHealth;
+use crate::router::ApolloRouterError;
+use crate::services::layers::maintenance::MaintenanceLayer;
+use crate::services::layers::persisted_queries::Manifest;
+use crate::services::layers::persisted_queries::PersistedQueryRegistry;
+use crate::services::layers::persisted_queries::RegistrationError;
+use crate::services::layers::quotas::QuotaEnforcer;
+use crate::Schema;
+
+/// State of a running router, as exposed by the admin API.
+///
+/// It is provided by the `SupergraphServiceFactory` and replaced on every reload.
+#[derive(Clone, Default)]
+pub(crate) struct RuntimeState {
+    pub(crate) schema: Option<Arc<Schema>>,
+    pub(crate) plugins: Vec<PluginState>,
+    pub(crate) caches: Vec<(&'static str, Arc<dyn CacheStatistics>)>,
+    pub(crate) persisted_queries: Option<PersistedQueryRegistry>,
+    pub(crate) quotas: Option<QuotaEnforcer>,
+    pub(crate) maintenance: Option<MaintenanceLayer>,
+    pub(crate) subgraph_health: SubgraphHealth,
+    /// The entitlement the configuration was loaded with.
+    pub(crate) entitlement: Option<Entitlement>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct PluginState {
+    pub(crate) name: String,
+    /// The version of the crate registering the plugin, unknown for the plugins added
+    /// programmatically.
+    pub(crate) version: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct StateResponse {
+    schema_hash: Option<String>,
+    plugins: Vec<PluginState>,
+    caches: BTreeMap<&'static str, CacheStats>,
+    subgraphs: BTreeMap<String, SubgraphState>,
+    configuration: ConfigurationState,
+}
+
+#[derive(Debug, Serialize)]
+struct SubgraphState {
+    url: String,
+    /// Whether the subgraph passes its health checks, if it has any.
+    healthy: Option<bool>,
+    /// The state of its circuit, if it has a circuit breaker.
+    circuit: Option<CircuitState>,
+}
+
+/// The active configuration, restricted to the settings that can not hold a secret: the
+/// configuration carries credentials, signing keys and the values of the secrets providers, so
+/// it is never exposed as a whole.
+#[derive(Debug, Serialize)]
+struct ConfigurationState {
+    listen: String,
+    graphql_path: String,
+    introspection: bool,
+    admin_listen: SocketAddr,
+    contracts: Vec<String>,
+    /// The optional features which are configured.
+    features: Vec<&'static str>,
+}
+
+impl ConfigurationState {
+    fn new(configuration: &Configuration) -> Self {
+        let mut contracts: Vec<String> = configuration.contracts.keys().cloned().collect();
+        contracts.sort();
+        let features = [
+            ("load_shedding", configuration.load_shedding.is_some()),
+            ("memory", configuration.memory.is_some()),
+            ("quotas", configuration.quotas.is_some()),
+            ("webhooks", configuration.webhooks.is_some()),
+            ("maintenance", configuration.maintenance.is_some()),
+            ("dns", configuration.dns.is_some()),
+            ("proxy", configuration.proxy.is_some()),
+            (
+                "cache_partitioning",
+                configuration.cache_partitioning.is_some(),
+            ),
+            ("storage", configuration.storage.is_some()),
+            ("crash_reports", configuration.crash_reports.is_some()),
+            ("data_masking", configuration.data_masking.is_some()),
+            ("canary", configuration.canary.is_some()),
+            (
+                "operation_registry",
+                configuration.operation_registry.is_some(),
+            ),
+            ("surrogate_keys", configuration.surrogate_keys.is_some()),
+        ]
+        .into_iter()
+        .filter(|(_, configured)| *configured)
+        .map(|(feature, _)| feature)
+        .collect();
+        ConfigurationState {
+            listen: configuration.server.listen.to_string(),
+            graphql_path: configuration.server.graphql_path.clone(),
+            introspection: configuration.server.introspection,
+            admin_listen: configuration.admin.listen,
+            contracts,
+            features,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct AdminContext {
+    state: RuntimeState,
+    configuration: Arc<Configuration>,
+}
+
+pub(crate) fn make_admin_router(configuration: Arc<Configuration>, state: RuntimeState) -> Router {
+    let token = configuration.admin.token.clone().unwrap_or_default();
+    let shedder = configuration
+        .admin_load_shedding()
+        .map(|load_shedding| Arc::new(LoadShedder::new(load_shedding.clone())));
+    let router = Router::new()
+        .route("/state", get(handle_state))
+        .route("/log-level", get(get_log_level).put(put_log_level))
+        .route(
+            "/persisted-queries",
+            get(get_persisted_queries).post(post_persisted_queries),
+        )
+        .route(
+            "/persisted-queries/active",
+            put(put_active_persisted_queries),
+        )
+        .route("/quotas/:client", get(get_quota_usage))
+        .route("/maintenance", get(get_maintenance).put(put_maintenance))
+        .route("/entitlement", get(get_entitlement))
+        .layer(Extension(AdminContext {
+            state,
+            configuration,
+        }))
+        .layer(middleware::from_fn(move |request, next| {
+            authenticate(token.clone(), request, next)
+        }));
+    match shedder {
+        Some(shedder) => router.layer(middleware::from_fn(move |request, next| {
+            shed_load(shedder.clone(), request, next)
+        })),
+        None => router,
+    }
+}
+
+async fn authenticate<B>(token: String, request: Request<B>, next: Next<B>) -> Response {
+    let authorized = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        // compared in constant time, so that the token can not be guessed from response times
+        .map(|value| !token.is_empty() && bool::from(value.as_bytes().ct_eq(token.as_bytes())))
+        .unwrap_or(false);
+
+    if authorized {
+        next.run(request).await
+    } else {
+        StatusCode::UNAUTHORIZED.into_response()
+    }
+}
+
+async fn handle_state(Extension(context): Extension<AdminContext>) -> impl IntoResponse {
+    let mut caches = BTreeMap::new();
+    for (name, cache) in &context.state.caches {
+        caches.insert(*name, cache.stats().await);
+    }
+
+    let subgraphs = context
+        .state
+        .schema
+        .iter()
+        .flat_map(|schema| schema.subgraphs())
+        .map(|(name, url)| {
+            (
+                name.clone(),
+                SubgraphState {
+                    url: url.to_string(),
+                    healthy: context.state.subgraph_health.healthy(name),
+                    circuit: context.state.subgraph_health.circuit(name),
+                },
+            )
+        })
+        .collect();
+
+    Json(StateResponse {
+        schema_hash: context
+            .state
+            .schema
+            .as_ref()
+            .and_then(|schema| schema.api_schema().schema_id.clone()),
+        plugins: context.state.plugins.clone(),
+        caches,
+        subgraphs,
+        configuration: ConfigurationState::new(&context.configuration),
+    })
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct LogLevel {
+    level: String,
+}
+
+async fn get_log_level() -> impl IntoResponse {
+    Json(LogLevel {
+        level: current_log_level(),
+    })
+}
+
+async fn put_log_level(Json(LogLevel { level }): Json<LogLevel>) -> impl IntoResponse {
+    match set_log_level(&level) {
+        Ok(()) => {
+            tracing::info!("log level changed to '{}' through the admin API", level);
+            Json(LogLevel { level }).into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ActiveVersion {
+    version: String,
+}
+
+fn persisted_query_registry(context: &AdminContext) -> Result<&PersistedQueryRegistry, Response> {
+    context.state.persisted_queries.as_ref().ok_or_else(|| {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "the router is not serving requests yet",
+        )
+            .into_response()
+    })
+}
+
+fn registration_error(error: RegistrationError) -> Response {
+    let status = match error {
+        RegistrationError::VersionExists(_) => StatusCode::CONFLICT,
+        RegistrationError::UnknownVersion(_) => StatusCode::NOT_FOUND,
+        RegistrationError::InvalidOperation(_) => StatusCode::BAD_REQUEST,
+    };
+    (status, error.to_string()).into_response()
+}
+
+async fn get_persisted_queries(Extension(context): Extension<AdminContext>) -> Response {
+    match persisted_query_registry(&context) {
+        Ok(registry) => Json(registry.status()).into_response(),
+        Err(response) => response,
+    }
+}
+
+async fn post_persisted_queries(
+    Extension(context): Extension<AdminContext>,
+    Json(manifest): Json<Manifest>,
+) -> Response {
+    let registry = match persisted_query_registry(&context) {
+        Ok(registry) => registry,
+        Err(response) => return response,
+    };
+    let version = manifest.version.clone();
+    let activate = manifest.activate;
+    match registry.register(manifest) {
+        Ok(()) => {
+            tracing::info!(
+                "persisted query manifest '{}' registered{} through the admin API",
+                version,
+                if activate { " and activated" } else { "" }
+            );
+            Json(registry.status()).into_response()
+        }
+        Err(e) => registration_error(e),
+    }
+}
+
+async fn put_active_persisted_queries(
+    Extension(context): Extension<AdminContext>,
+    Json(ActiveVersion { version }): Json<ActiveVersion>,
+) -> Response {
+    let registry = match persisted_query_registry(&context) {
+        Ok(registry) => registry,
+        Err(response) => return response,
+    };
+    match registry.activate(&version) {
+        Ok(()) => {
+            tracing::info!(
+                "persisted query manifest '{}' activated through the admin API",
+                version
+            );
+            Json(registry.status()).into_response()
+        }
+        Err(e) => registration_error(e),
+    }
+}
+
+async fn get_quota_usage(
+    Extension(context): Extension<AdminContext>,
+    Path(client): Path<String>,
+) -> impl IntoResponse {
+    let quotas = match &context.state.quotas {
+        Some(quotas) => quotas,
+        None => return (StatusCode::NOT_FOUND, "quotas are not enabled").into_response(),
+    };
+    match quotas.usage(&client).await {
+        Ok(usage) => Json(usage).into_response(),
+        Err(e) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("couldn't read the usage of client '{}': {}", client, e),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct MaintenanceMode {
+    /// `null` follows the configuration again.
+    enabled: Option<bool>,
+}
+
+fn maintenance_layer(context: &AdminContext) -> Result<&MaintenanceLayer, Response> {
+    context.state.maintenance.as_ref().ok_or_else(|| {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "the router is not serving requests yet",
+        )
+            .into_response()
+    })
+}
+
+async fn get_entitlement(Extension(context): Extension<AdminContext>) -> Response {
+    match &context.state.entitlement {
+        Some(entitlement) => Json(entitlement.report(&context.configuration)).into_response(),
+        None => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "the router is not serving requests yet",
+        )
+            .into_response(),
+    }
+}
+
+async fn get_maintenance(Extension(context): Extension<AdminContext>) -> Response {
+    match maintenance_layer(&context) {
+        Ok(maintenance) => Json(MaintenanceMode {
+            enabled: Some(maintenance.is_enabled()),
+        })
+        .into_response(),
+        Err(response) => response,
+    }
+}
+
+async fn put_maintenance(
+    Extension(context): Extension<AdminContext>,
+    Json(MaintenanceMode { enabled }): Json<MaintenanceMode>,
+) -> Response {
+    let maintenance = match maintenance_layer(&context) {
+        Ok(maintenance) => maintenance,
+        Err(response) => return response,
+    };
+    maintenance.switch().set(enabled);
+    tracing::info!(
+        "maintenance mode {} through the admin API",
+        match enabled {
+            Some(true) => "enabled",
+            Some(false) => "disabled",
+            None => "reset to the configuration",
+        }
+    );
+    Json(MaintenanceMode {
+        enabled: Some(maintenance.is_enabled()),
+    })
+    .into_response()
+}
+
+/// A handle on the admin server, used to stop it along with the main server.
+pub(crate) struct AdminServerHandle {
+    shutdown_sender: oneshot::Sender<()>,
+    server: JoinHandle<Result<(), hyper::Error>>,
+}
+
+impl AdminServerHandle {
+    pub(crate) async fn shutdown(self) {
+        let _ = self.shutdown_sender.send(());
+        match self.server.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::error!("admin server failed: {}", e),
+            Err(e) => tracing::error!("admin server task failed: {}", e),
+        }
+    }
+}
+
+/// Start the admin server if it is enabled in the configuration.
+pub(crate) async fn serve(
+    configuration: Arc<Configuration>,
+    state: RuntimeState,
+) -> Result<Option<AdminServerHandle>, ApolloRouterError> {
+    let Admin {
+        enabled,
+        listen,
+        token,
+        ..
+    } = configuration.admin.clone();
+    if !enabled {
+        return Ok(None);
+    }
+    if token.unwrap_or_default().is_empty() {
+        return Err(ApolloRouterError::ServerCreationError(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "the admin API requires 'admin.token' to be set",
+        )));
+    }
+
+    let listener = TcpListener::bind(listen)
+        .await
+        .and_then(|listener| listener.into_std())
+        .map_err(ApolloRouterError::ServerCreationError)?;
+    let actual_listen_address = listener
+        .local_addr()
+        .map_err(ApolloRouterError::ServerCreationError)?;
+    let router = make_admin_router(configuration, state);
+    let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+
+    let server = hyper::Server::from_tcp(listener)
+        .map_err(|e| {
+            ApolloRouterError::ServerCreationError(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                e,
+            ))
+        })?
+        .serve(router.into_make_service())
+        .with_graceful_shutdown(shutdown_receiver.map(|_| ()));
+
+    tracing::info!("admin API exposed at http://{}", actual_listen_address);
+
+    Ok(Some(AdminServerHandle {
+        shutdown_sender,
+        server: tokio::task::spawn(server),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::cache::DeduplicatingCache;
+
+    async fn state_request(
+        configuration: Configuration,
+        authorization: Option<&str>,
+    ) -> http::Response<axum::body::BoxBody> {
+        let cache: DeduplicatingCache<String, String> = DeduplicatingCache::with_capacity(10).await;
+        cache
+            .get(&"key".to_string())
+            .await
+            .insert("value".into())
+            .await;
+
+        let router = make_admin_router(
+            Arc::new(configuration),
+            RuntimeState {
+                schema: None,
+                plugins: vec![PluginState {
+                    name: "apollo.telemetry".to_string(),
+                    version: Some("1.0.0".to_string()),
+                }],
+                caches: vec![("apq", Arc::new(cache))],
+                ..Default::default()
+            },
+        );
+
+        let mut request = Request::get("/state");
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        router
+            .oneshot(request.body(hyper::Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    fn configuration_with_token() -> Configuration {
+        serde_json::from_value(serde_json::json!({
+            "admin": {
+                "enabled": true,
+                "token": "secret"
+            }
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_rejects_unauthenticated_requests() {
+        let response = state_request(configuration_with_token(), None).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = state_request(configuration_with_token(), Some("Bearer wrong")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn it_rejects_requests_when_no_token_is_configured() {
+        let response = state_request(Configuration::default(), Some("Bearer ")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn it_does_not_start_without_a_token() {
+        let configuration: Configuration = serde_json::from_value(serde_json::json!({
+            "admin": {
+                "enabled": true,
+                "listen": "127.0.0.1:0"
+            }
+        }))
+        .unwrap();
+        assert!(serve(Arc::new(configuration), RuntimeState::default())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_invalid_log_levels() {
+        let router = make_admin_router(
+            Arc::new(configuration_with_token()),
+            RuntimeState::default(),
+        );
+        let response = router
+            .oneshot(
+                Request::put("/log-level")
+                    .header(AUTHORIZATION, "Bearer secret")
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .body(hyper::Body::from(r#"{"level":"apollo_router=notalevel"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn it_exposes_the_runtime_state() {
+        let configuration = serde_json::from_value(serde_json::json!({
+            "admin": {
+                "enabled": true,
+                "token": "secret"
+            },
+            "quotas": {
+                "redis_url": "redis://:password@redis:6379"
+            }
+        }))
+        .unwrap();
+        let response = state_request(configuration, Some("Bearer secret")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let state: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            state["plugins"],
+            serde_json::json!([{ "name": "apollo.telemetry", "version": "1.0.0" }])
+        );
+        assert_eq!(
+            state["caches"]["apq"],
+            serde_json::json!({"capacity": 10, "size": 1, "hits": 0, "misses": 1})
+        );
+        assert_eq!(
+            state["configuration"]["features"],
+            serde_json::json!(["quotas"])
+        );
+        // the secrets of the configuration are never exposed
+        assert!(!body.windows(6).any(|window| window == b"secret"));
+        assert!(!body.windows(8).any(|window| window == b"password"));
+    }
+
+    #[tokio::test]
+    async fn it_exposes_the_quota_usage() {
+        let quotas = QuotaEnforcer::new(
+            serde_json::from_value(serde_json::json!({
+                "limits": { "daily_operations": 10 }
+            }))
+            .unwrap(),
+            Default::default(),
+            None,
+        );
+        let request = || {
+            Request::get("/quotas/my-client")
+                .header(AUTHORIZATION, "Bearer secret")
+                .body(hyper::Body::empty())
+                .unwrap()
+        };
+
+        let router = make_admin_router(
+            Arc::new(configuration_with_token()),
+            RuntimeState {
+                quotas: Some(quotas),
+                ..Default::default()
+            },
+        );
+        let response = router.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let usage: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(usage["client"], "my-client");
+        assert_eq!(usage["daily"]["operations"], 0);
+        assert_eq!(usage["limits"]["daily_operations"], 10);
+
+        let router = make_admin_router(
+            Arc::new(configuration_with_token()),
+            RuntimeState::default(),
+        );
+        let response = router.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn it_exposes_the_entitlement_state() {
+        let request = || {
+            Request::get("/entitlement")
+                .header(AUTHORIZATION, "Bearer secret")
+                .body(hyper::Body::empty())
+                .unwrap()
+        };
+
+        let router = make_admin_router(
+            Arc::new(configuration_with_token()),
+            RuntimeState {
+                entitlement: Some(Entitlement::load(None)),
+                ..Default::default()
+            },
+        );
+        let response = router.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&body).unwrap(),
+            serde_json::json!({ "state": "unenforced", "commercial_features": [] })
+        );
+
+        let router = make_admin_router(
+            Arc::new(configuration_with_token()),
+            RuntimeState::default(),
+        );
+        let response = router.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn it_switches_the_maintenance_mode() {
+        let maintenance = MaintenanceLayer::new(None, Default::default()).unwrap();
+        let router = make_admin_router(
+            Arc::new(configuration_with_token()),
+            RuntimeState {
+                maintenance: Some(maintenance.clone()),
+                ..Default::default()
+            },
+        );
+        let request = |enabled: Option<bool>| {
+            Request::put("/maintenance")
+                .header(AUTHORIZATION, "Bearer secret")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(hyper::Body::from(
+                    serde_json::json!({ "enabled": enabled }).to_string(),
+                ))
+                .unwrap()
+        };
+
+        let response = router.clone().oneshot(request(Some(true))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&body).unwrap(),
+            serde_json::json!({ "enabled": true })
+        );
+        assert!(maintenance.is_enabled());
+
+        let response = router.oneshot(request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!maintenance.is_enabled());
+    }
+
+    #[tokio::test]
+    async fn it_registers_and_activates_persisted_query_manifests() {
+        let registry = PersistedQueryRegistry::default();
+        let router = make_admin_router(
+            Arc::new(configuration_with_token()),
+            RuntimeState {
+                persisted_queries: Some(registry.clone()),
+                ..Default::default()
+            },
+        );
+        let request = |method: http::Method, uri: &str, body: serde_json::Value| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(AUTHORIZATION, "Bearer secret")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(hyper::Body::from(body.to_string()))
+                .unwrap()
+        };
+        let manifest = serde_json::json!({
+            "version": "v1",
+            "operations": [{
+                "id": "ecf4edb46db40b5132295c0291d62fb65d6759a9eedfa4d5d612dd5ec54a6b38",
+                "body": "{__typename}"
+            }]
+        });
+
+        let response = router
+            .clone()
+            .oneshot(request(
+                http::Method::POST,
+                "/persisted-queries",
+                manifest.clone(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(registry.status().active, None);
+
+        let response = router
+            .clone()
+            .oneshot(request(http::Method::POST, "/persisted-queries", manifest))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let response = router
+            .clone()
+            .oneshot(request(
+                http::Method::PUT,
+                "/persisted-queries/active",
+                serde_json::json!({ "version": "v2" }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = router
+            .oneshot(request(
+                http::Method::PUT,
+                "/persisted-queries/active",
+                serde_json::json!({ "version": "v1" }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let status: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            status,
+            serde_json::json!({
+                "active": "v1",
+                "versions": [{ "version": "v1", "operations": 1 }]
+            })
+        );
+        assert_eq!(
+            registry
+                .get("ecf4edb46db40b5132295c0291d62fb65d6759a9eedfa4d5d612dd5ec54a6b38")
+                .as_deref(),
+            Some("{__typename}")
+        );
+    }
+}
@@ -1,8 +1,13 @@
 //! Axum http server factory. Axum provides routing capability on top of Hyper HTTP.
 use std::collections::HashMap;
+use std::io;
+use std::io::Write;
 use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
 use std::time::Duration;
 use std::time::Instant;
 
@@ -11,30 +16,51 @@ use async_compression::tokio::write::GzipDecoder;
 use async_compression::tokio::write::ZlibDecoder;
 use axum::body::StreamBody;
 use axum::extract::Extension;
+use axum::extract::FromRequest;
 use axum::extract::Host;
 use axum::extract::OriginalUri;
+use axum::extract::Path;
+use axum::extract::Query;
+use axum::extract::RequestParts;
+use axum::handler::Handler as _;
 use axum::http::header::HeaderMap;
 use axum::http::StatusCode;
 use axum::middleware::Next;
 use axum::middleware::{self};
 use axum::response::*;
+use axum::routing::any;
 use axum::routing::get;
+use axum::routing::MethodRouter;
 use axum::Router;
 use bytes::Bytes;
 use futures::channel::oneshot;
 use futures::future::ready;
+use futures::future::Either;
 use futures::prelude::*;
 use futures::stream::once;
-use futures::stream::BoxStream;
 use futures::StreamExt;
+use http::header::HeaderName;
+use http::header::ACCESS_CONTROL_REQUEST_METHOD;
+use http::header::ALLOW;
+use http::header::AUTHORIZATION;
+use http::header::CONNECTION;
 use http::header::CONTENT_ENCODING;
+use http::header::CONTENT_LENGTH;
 use http::header::CONTENT_TYPE;
+use http::header::COOKIE;
+use http::header::ETAG;
+use http::header::HOST;
+use http::header::IF_NONE_MATCH;
+use http::header::ORIGIN;
 use http::header::VARY;
 use http::HeaderValue;
+use http::Method;
 use http::Request;
 use http::Uri;
+use hyper::client::HttpConnector;
 use hyper::server::conn::Http;
 use hyper::Body;
+use hyper_rustls::HttpsConnector;
 use mediatype::names::HTML;
 use mediatype::names::TEXT;
 use mediatype::MediaType;
@@ -43,45 +69,114 @@ use opentelemetry::global;
 use opentelemetry::trace::SpanKind;
 use opentelemetry::trace::TraceContextExt;
 use serde_json::json;
+use sha2::Digest;
+use sha2::Sha256;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpListener;
 #[cfg(unix)]
 use tokio::net::UnixListener;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::Notify;
-use tower::util::BoxService;
+use tokio_stream::wrappers::ReceiverStream;
+use tower::buffer::error::Closed;
+use tower::buffer::error::ServiceError;
 use tower::BoxError;
+use tower::Layer;
 use tower::ServiceExt;
 use tower_http::compression::CompressionLayer;
+use tower_http::cors::Cors;
+use tower_http::cors::CorsLayer;
 use tower_http::trace::MakeSpan;
 use tower_http::trace::TraceLayer;
 use tower_service::Service;
 use tracing::Level;
 use tracing::Span;
 
+use crate::admin;
 use crate::configuration::Configuration;
+use crate::configuration::GraphqlOverHttp;
 use crate::configuration::ListenAddr;
+use crate::configuration::ResponseSerialization;
+use crate::configuration::Route;
+use crate::configuration::RouteTarget;
 use crate::graphql;
-use crate::http_ext;
+use crate::graphql_over_http;
+use crate::graphql_over_http::ResponseMediaType;
+use crate::graphql_over_http::GRAPHQL_RESPONSE_CONTENT_TYPE;
+use crate::http_ext::ResponseBodySize;
 use crate::http_server_factory::HttpServerFactory;
 use crate::http_server_factory::HttpServerHandle;
+use crate::http_server_factory::InheritedListener;
 use crate::http_server_factory::Listener;
 use crate::http_server_factory::NetworkStream;
+use crate::load_shedding::shed_load;
+use crate::load_shedding::shed_memory;
+use crate::load_shedding::LoadShedder;
+use crate::load_shedding::MemoryShedder;
+use crate::panics;
+use crate::panics::RequestInfo;
 use crate::plugin::Handler;
+use crate::plugin::ResponseBody;
+use crate::plugin::ResponseBodyTransformer;
 use crate::plugins::traffic_shaping::Elapsed;
+use crate::plugins::traffic_shaping::Overloaded;
 use crate::plugins::traffic_shaping::RateLimited;
 use crate::router::ApolloRouterError;
 use crate::router_factory::SupergraphServiceFactory;
+use crate::serialization;
+use crate::serialization::SerializationOptions;
 use crate::services::MULTIPART_DEFER_CONTENT_TYPE;
 
+/// Size up to which the responses are serialized whole on the task handling their request. The
+/// larger ones are serialized on the blocking pool, so that they do not hold up the other requests.
+const INLINE_SERIALIZATION_LIMIT: usize = 64 * 1024;
+/// Size of the chunks of the streamed responses, sent as soon as they are written.
+const RESPONSE_CHUNK_SIZE: usize = 16 * 1024;
+/// Number of chunks of a streamed response buffered for the client.
+const RESPONSE_CHUNKS_BUFFER: usize = 4;
+
+/// The last part of a multipart response which next part could not be serialized.
+const SERIALIZATION_ERROR_PART: &[u8] = b"content-type: application/json\r\n\r\n{\"hasNext\":false,\"errors\":[{\"message\":\"could not serialize the response\"}]}\r\n--graphql--\r\n";
+
+/// Timeout of the requests forwarded to the backends of the routes.
+const DEFAULT_PROXY_ROUTE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Headers of a single connection, never forwarded by the routes proxying to a backend.
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Methods allowed on the GraphQL endpoints, `HEAD` being answered like `GET`.
+const GRAPHQL_ALLOWED_METHODS: &str = "GET, HEAD, POST, OPTIONS";
+
 /// A basic http server using Axum.
 /// Uses streaming as primary method of response.
 /// Redirects to studio for GET requests.
 #[derive(Debug)]
-pub(crate) struct AxumHttpServerFactory;
+pub(crate) struct AxumHttpServerFactory {
+    /// The listener inherited by the router process, used the first time the server is created.
+    inherited_listener: Mutex<Option<InheritedListener>>,
+}
 
 impl AxumHttpServerFactory {
+    #[cfg(test)]
     pub(crate) fn new() -> Self {
-        Self
+        Self::with_inherited_listener(None)
+    }
+
+    pub(crate) fn with_inherited_listener(inherited_listener: Option<InheritedListener>) -> Self {
+        Self {
+            inherited_listener: Mutex::new(inherited_listener),
+        }
     }
 }
 
@@ -96,43 +191,50 @@ where
     let cors = configuration.cors.clone().into_layer().map_err(|e| {
         ApolloRouterError::ServiceCreationError(format!("CORS configuration error: {e}").into())
     })?;
-    let graphql_path = if configuration.server.graphql_path.ends_with("/*") {
-        // Needed for axum (check the axum docs for more information about wildcards https://docs.rs/axum/latest/axum/struct.Router.html#wildcards)
-        format!("{}router_extra_path", configuration.server.graphql_path)
-    } else {
-        configuration.server.graphql_path.clone()
-    };
-    let mut router = Router::<hyper::Body>::new()
-        .route(
-            &graphql_path,
-            get({
-                let display_landing_page = configuration.server.landing_page;
-                move |host: Host, Extension(service): Extension<RF>, http_request: Request<Body>| {
-                    handle_get(
-                        host,
-                        service.new_service().boxed(),
-                        http_request,
-                        display_landing_page,
-                    )
-                }
-            })
-            .post({
-                move |host: Host,
-                      uri: OriginalUri,
-                      request: Json<graphql::Request>,
-                      Extension(service): Extension<RF>,
-                      header_map: HeaderMap| {
-                    handle_post(
-                        host,
-                        uri,
-                        request,
-                        service.new_service().boxed(),
-                        header_map,
-                    )
-                }
-            }),
-        )
-        .layer(middleware::from_fn(decompress_request_body))
+    let serialization = Arc::new(
+        configuration
+            .server
+            .experimental_response_serialization
+            .clone(),
+    );
+    let mut router = Router::<hyper::Body>::new();
+    for graphql_path in configuration.server.graphql_paths() {
+        router = router.route(
+            &axum_path(graphql_path),
+            graphql_route::<RF>(
+                configuration.server.landing_page,
+                configuration.server.experimental_graphql_over_http,
+                serialization.clone(),
+            ),
+        );
+    }
+    // contracts are served by the same service, which picks the schema from the request path
+    for contract in configuration.contracts.values() {
+        if let Some(path) = &contract.graphql_path {
+            router = router.route(
+                path,
+                graphql_route::<RF>(
+                    configuration.server.landing_page,
+                    configuration.server.experimental_graphql_over_http,
+                    serialization.clone(),
+                ),
+            );
+        }
+    }
+    let mut router = router.layer(middleware::from_fn(decompress_request_body));
+    if let Some(load_shedding) = configuration.server_load_shedding() {
+        let shedder = Arc::new(LoadShedder::new(load_shedding.clone()));
+        router = router.layer(middleware::from_fn(move |request, next| {
+            shed_load(shedder.clone(), request, next)
+        }));
+    }
+    if let Some(memory) = configuration.memory.clone() {
+        let shedder = Arc::new(MemoryShedder::new(memory));
+        router = router.layer(middleware::from_fn(move |request, next| {
+            shed_memory(shedder.clone(), request, next)
+        }));
+    }
+    let mut router = router
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(PropagatingMakeSpan::new())
@@ -150,9 +252,22 @@ where
                     }
                 }),
         )
-        .route(&configuration.server.health_check_path, get(health_check))
+        .route(
+            &configuration.server.health_check_path,
+            get(health_check::<RF>),
+        );
+    for route in &configuration.server.routes {
+        router = router.route(&axum_path(&route.path), extra_route(route)?);
+    }
+    if let Some(path) = &configuration.server.experimental_persisted_query_check_path {
+        router = router.route(
+            &format!("{}/:hash", path.trim_end_matches('/')),
+            get(check_persisted_query::<RF>),
+        );
+    }
+    let mut router = router
         .layer(Extension(service_factory))
-        .layer(cors)
+        .layer(PreflightCorsLayer(cors))
         .layer(CompressionLayer::new()); // To compress response body
 
     for (plugin_name, handler) in plugin_handlers {
@@ -175,6 +290,263 @@ where
     Ok(router)
 }
 
+/// The path of a route in axum, which needs a name for its wildcard.
+fn axum_path(path: &str) -> String {
+    if path.ends_with("/*") {
+        // Needed for axum (check the axum docs for more information about wildcards https://docs.rs/axum/latest/axum/struct.Router.html#wildcards)
+        format!("{}router_extra_path", path)
+    } else {
+        path.to_string()
+    }
+}
+
+/// A route of the `server.routes` configuration.
+fn extra_route(route: &Route) -> Result<MethodRouter<Body>, ApolloRouterError> {
+    let (body, default_content_type) = match &route.serve {
+        RouteTarget::File(path) => {
+            let content = std::fs::read(path).map_err(|e| {
+                ApolloRouterError::ServiceCreationError(
+                    format!("could not read the file of route '{}': {}", route.path, e).into(),
+                )
+            })?;
+            (Bytes::from(content), "application/octet-stream")
+        }
+        RouteTarget::Body(body) => (Bytes::from(body.clone()), "text/plain; charset=utf-8"),
+        RouteTarget::Proxy(url) => {
+            let backend = Arc::new(ProxyBackend {
+                url: url.trim_end_matches('/').to_string(),
+                timeout: route.timeout.unwrap_or(DEFAULT_PROXY_ROUTE_TIMEOUT),
+                forward_credentials: route.forward_credentials,
+            });
+            let client = hyper::Client::builder().build(
+                hyper_rustls::HttpsConnectorBuilder::new()
+                    .with_native_roots()
+                    .https_or_http()
+                    .enable_http1()
+                    .build(),
+            );
+            return Ok(any(move |request: Request<Body>| {
+                proxy_request(client.clone(), backend.clone(), request)
+            }));
+        }
+    };
+    let content_type = HeaderValue::from_str(
+        route
+            .content_type
+            .as_deref()
+            .unwrap_or(default_content_type),
+    )
+    .map_err(|e| {
+        ApolloRouterError::ServiceCreationError(
+            format!("invalid content type for route '{}': {}", route.path, e).into(),
+        )
+    })?;
+    Ok(get(move || {
+        ready(([(CONTENT_TYPE, content_type.clone())], body.clone()))
+    }))
+}
+
+/// Check whether a persisted query is registered, without executing it.
+async fn check_persisted_query<RF>(
+    Path(hash): Path<String>,
+    Extension(service_factory): Extension<RF>,
+) -> StatusCode
+where
+    RF: SupergraphServiceFactory,
+{
+    match service_factory.apq() {
+        Some(apq) if apq.is_registered(&hash).await => StatusCode::NO_CONTENT,
+        _ => StatusCode::NOT_FOUND,
+    }
+}
+
+/// The backend of a route the requests are forwarded to.
+struct ProxyBackend {
+    url: String,
+    timeout: Duration,
+    forward_credentials: bool,
+}
+
+/// Forward a request to the backend of a route, with its path and query.
+async fn proxy_request(
+    client: hyper::Client<HttpsConnector<HttpConnector>>,
+    backend: Arc<ProxyBackend>,
+    mut request: Request<Body>,
+) -> Response {
+    let path_and_query = request
+        .uri()
+        .path_and_query()
+        .map(|path_and_query| path_and_query.as_str())
+        .unwrap_or("/");
+    match Uri::from_str(&format!("{}{}", backend.url, path_and_query)) {
+        Ok(uri) => *request.uri_mut() = uri,
+        Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+    }
+    // the connection to the backend is not the one of the client
+    *request.version_mut() = http::Version::HTTP_11;
+    let headers = request.headers_mut();
+    remove_hop_by_hop_headers(headers);
+    headers.remove(HOST);
+    if !backend.forward_credentials {
+        headers.remove(AUTHORIZATION);
+        headers.remove(COOKIE);
+    }
+    match tokio::time::timeout(backend.timeout, client.request(request)).await {
+        Ok(Ok(mut response)) => {
+            remove_hop_by_hop_headers(response.headers_mut());
+            response.into_response()
+        }
+        Ok(Err(err)) => {
+            tracing::error!(
+                "could not forward the request to '{}': {}",
+                backend.url,
+                err
+            );
+            StatusCode::BAD_GATEWAY.into_response()
+        }
+        Err(_) => {
+            tracing::error!(
+                "the request forwarded to '{}' timed out after {:?}",
+                backend.url,
+                backend.timeout
+            );
+            StatusCode::GATEWAY_TIMEOUT.into_response()
+        }
+    }
+}
+
+/// Remove the headers describing the connection to the client or to the backend rather than the
+/// message, which proxies must not forward (RFC 7230, section 6.1).
+fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_str(name.trim()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+}
+
+fn graphql_route<RF>(
+    display_landing_page: bool,
+    mode: GraphqlOverHttp,
+    serialization: Arc<ResponseSerialization>,
+) -> MethodRouter<Body>
+where
+    RF: SupergraphServiceFactory,
+{
+    let post_serialization = serialization.clone();
+    get(
+        move |host: Host,
+              Extension(service_factory): Extension<RF>,
+              http_request: Request<Body>| {
+            handle_get(
+                host,
+                service_factory,
+                http_request,
+                display_landing_page,
+                mode,
+                serialization.clone(),
+            )
+        },
+    )
+    .post(
+        move |host: Host,
+              uri: OriginalUri,
+              Extension(service_factory): Extension<RF>,
+              http_request: Request<Body>| {
+            handle_post(
+                host,
+                uri,
+                http_request,
+                service_factory,
+                mode,
+                post_serialization.clone(),
+            )
+        },
+    )
+    .options(allowed_methods)
+    .fallback(method_not_allowed.into_service())
+}
+
+/// The answer to the OPTIONS requests which are not CORS preflight requests.
+async fn allowed_methods() -> impl IntoResponse {
+    (
+        StatusCode::NO_CONTENT,
+        [(ALLOW, HeaderValue::from_static(GRAPHQL_ALLOWED_METHODS))],
+    )
+}
+
+async fn method_not_allowed() -> impl IntoResponse {
+    (
+        StatusCode::METHOD_NOT_ALLOWED,
+        [(ALLOW, HeaderValue::from_static(GRAPHQL_ALLOWED_METHODS))],
+    )
+}
+
+/// Applies a CORS layer to the requests, except to the OPTIONS requests which are not CORS
+/// preflight requests: the CORS layer answers every OPTIONS request, so these ones bypass it to
+/// get the methods allowed by their route.
+#[derive(Clone)]
+struct PreflightCorsLayer(CorsLayer);
+
+impl<S: Clone> Layer<S> for PreflightCorsLayer {
+    type Service = PreflightCors<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        PreflightCors {
+            cors: self.0.layer(inner.clone()),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct PreflightCors<S> {
+    cors: Cors<S>,
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for PreflightCors<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    ResBody: Default,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Either<<Cors<S> as Service<Request<ReqBody>>>::Future, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self.cors.poll_ready(cx) {
+            Poll::Ready(Ok(())) => self.inner.poll_ready(cx),
+            other => other,
+        }
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        if request.method() == Method::OPTIONS && !is_cors_preflight(&request) {
+            Either::Right(self.inner.call(request))
+        } else {
+            Either::Left(self.cors.call(request))
+        }
+    }
+}
+
+/// Whether a request is a CORS preflight request, rather than a plain OPTIONS request.
+fn is_cors_preflight<B>(request: &Request<B>) -> bool {
+    request.method() == Method::OPTIONS
+        && request.headers().contains_key(ORIGIN)
+        && request
+            .headers()
+            .contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+}
+
 impl HttpServerFactory for AxumHttpServerFactory {
     type Future = Pin<Box<dyn Future<Output = Result<HttpServerHandle, ApolloRouterError>> + Send>>;
 
@@ -188,11 +560,27 @@ impl HttpServerFactory for AxumHttpServerFactory {
     where
         RF: SupergraphServiceFactory,
     {
+        let inherited_listener = self.inherited_listener.lock().unwrap().take();
         Box::pin(async move {
             let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
             let listen_address = configuration.server.listen.clone();
 
+            let admin_server =
+                admin::serve(configuration.clone(), service_factory.runtime_state()).await?;
             let router = make_axum_router(service_factory, &configuration, plugin_handlers)?;
+            // every connection holds a sender, so the receiver is notified once all of them
+            // are closed
+            let (drain_sender, mut drain_receiver) = mpsc::channel::<()>(1);
+
+            let listener = match (listener, inherited_listener) {
+                (Some(listener), _) => Some(listener),
+                (None, Some(inherited_listener)) => Some(
+                    inherited_listener
+                        .into_listener(&listen_address)
+                        .map_err(ApolloRouterError::ServerCreationError)?,
+                ),
+                (None, None) => None,
+            };
 
             // if we received a TCP listener, reuse it, otherwise create a new one
             #[cfg_attr(not(unix), allow(unused_mut))]
@@ -215,11 +603,13 @@ impl HttpServerFactory for AxumHttpServerFactory {
                 .local_addr()
                 .map_err(ApolloRouterError::ServerCreationError)?;
 
-            tracing::info!(
-                "GraphQL endpoint exposed at {}{} 🚀",
-                actual_listen_address,
-                configuration.server.graphql_path
-            );
+            for graphql_path in configuration.server.graphql_paths() {
+                tracing::info!(
+                    "GraphQL endpoint exposed at {}{} 🚀",
+                    actual_listen_address,
+                    graphql_path
+                );
+            }
             // this server reproduces most of hyper::server::Server's behaviour
             // we select over the stop_listen_receiver channel and the listener's
             // accept future. If the channel received something or the sender
@@ -239,6 +629,7 @@ impl HttpServerFactory for AxumHttpServerFactory {
                         res = listener.accept() => {
                             let app = router.clone();
                             let connection_shutdown = connection_shutdown.clone();
+                            let drain_sender = drain_sender.clone();
 
                             match res {
                                 Ok(res) => {
@@ -248,6 +639,7 @@ impl HttpServerFactory for AxumHttpServerFactory {
                                     }
 
                                     tokio::task::spawn(async move {
+                                        let _drain_sender = drain_sender;
                                         match res {
                                             NetworkStream::Tcp(stream) => {
                                                 stream
@@ -382,6 +774,9 @@ impl HttpServerFactory for AxumHttpServerFactory {
                 // the server loop, tell the currently active connections to stop
                 // then return the TCP listen socket
                 connection_shutdown.notify_waiters();
+                if let Some(admin_server) = admin_server {
+                    admin_server.shutdown().await;
+                }
                 listener
             };
 
@@ -389,10 +784,16 @@ impl HttpServerFactory for AxumHttpServerFactory {
             let server_future = tokio::task::spawn(server)
                 .map_err(|_| ApolloRouterError::HttpServerLifecycleError)
                 .boxed();
+            let connections_drained = async move {
+                let _ = drain_receiver.recv().await;
+            }
+            .boxed();
 
             Ok(HttpServerHandle::new(
                 shutdown_sender,
                 server_future,
+                connections_drained,
+                configuration.server.drain_timeout,
                 actual_listen_address,
             ))
         })
@@ -421,16 +822,17 @@ async fn custom_plugin_handler(
         .map_err(|err| err.to_string())
 }
 
-async fn handle_get(
+async fn handle_get<RF>(
     Host(host): Host,
-    service: BoxService<
-        http::Request<graphql::Request>,
-        http::Response<BoxStream<'static, graphql::Response>>,
-        BoxError,
-    >,
+    service_factory: RF,
     http_request: Request<Body>,
     display_landing_page: bool,
-) -> impl IntoResponse {
+    mode: GraphqlOverHttp,
+    serialization: Arc<ResponseSerialization>,
+) -> impl IntoResponse
+where
+    RF: SupergraphServiceFactory,
+{
     if prefers_html(http_request.headers()) && display_landing_page {
         return display_home_page().into_response();
     }
@@ -443,7 +845,7 @@ async fn handle_get(
         let mut http_request = http_request.map(|_| request);
         *http_request.uri_mut() = Uri::from_str(&format!("http://{}{}", host, http_request.uri()))
             .expect("the URL is already valid because it comes from axum; qed");
-        return run_graphql_request(service, http_request)
+        return run_graphql_request(service_factory, http_request, mode, &serialization)
             .await
             .into_response();
     }
@@ -451,17 +853,42 @@ async fn handle_get(
     (StatusCode::BAD_REQUEST, "Invalid Graphql request").into_response()
 }
 
-async fn handle_post(
+async fn handle_post<RF>(
     Host(host): Host,
     OriginalUri(uri): OriginalUri,
-    Json(request): Json<graphql::Request>,
-    service: BoxService<
-        http::Request<graphql::Request>,
-        http::Response<BoxStream<'static, graphql::Response>>,
-        BoxError,
-    >,
-    header_map: HeaderMap,
-) -> impl IntoResponse {
+    http_request: Request<Body>,
+    service_factory: RF,
+    mode: GraphqlOverHttp,
+    serialization: Arc<ResponseSerialization>,
+) -> impl IntoResponse
+where
+    RF: SupergraphServiceFactory,
+{
+    let mut request_parts = RequestParts::new(http_request);
+    let request = if has_graphql_media_type(request_parts.headers()) {
+        match raw_graphql_request(&mut request_parts).await {
+            Ok(request) => request,
+            Err(response) => return response,
+        }
+    } else {
+        match (
+            Json::<graphql::Request>::from_request(&mut request_parts).await,
+            mode,
+        ) {
+            (Ok(Json(request)), _) => request,
+            (Err(rejection), GraphqlOverHttp::Legacy) => return rejection.into_response(),
+            (Err(rejection), GraphqlOverHttp::Strict) => {
+                return graphql_over_http::rejection_response(rejection)
+            }
+        }
+    };
+    let header_map = std::mem::take(request_parts.headers_mut());
+    if mode == GraphqlOverHttp::Strict && !graphql_over_http::has_utf8_charset(&header_map) {
+        return graphql_over_http::request_error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "the body of POST requests must be encoded in UTF-8",
+        );
+    }
     let mut http_request = Request::post(
         Uri::from_str(&format!("http://{}{}", host, uri))
             .expect("the URL is already valid because it comes from axum; qed"),
@@ -470,18 +897,68 @@ async fn handle_post(
     .expect("body has already been parsed; qed");
     *http_request.headers_mut() = header_map;
 
-    run_graphql_request(service, http_request)
+    run_graphql_request(service_factory, http_request, mode, &serialization)
         .await
         .into_response()
 }
 
+/// The request of an `application/graphql` POST request: its body is the query, and the other
+/// parameters are in the query string, like for GET requests.
+async fn raw_graphql_request(
+    request_parts: &mut RequestParts<Body>,
+) -> Result<graphql::Request, Response> {
+    let query = String::from_request(request_parts)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    let mut request = match request_parts.uri().query() {
+        Some(parameters) => graphql::Request::from_urlencoded_query(parameters.to_string())
+            .map_err(|e| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid Graphql request parameters: {}", e),
+                )
+                    .into_response()
+            })?,
+        None => graphql::Request::default(),
+    };
+    request.query = Some(query);
+    Ok(request)
+}
+
 fn display_home_page() -> Html<Bytes> {
     let html = Bytes::from_static(include_bytes!("../resources/index.html"));
     Html(html)
 }
 
-async fn health_check() -> impl IntoResponse {
-    Json(json!({ "status": "pass" }))
+/// The liveness of the router, or its readiness with the `ready` parameter: the router is not
+/// ready while a subgraph fails its active health checks, or a plugin is not ready.
+async fn health_check<RF>(
+    Extension(service_factory): Extension<RF>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse
+where
+    RF: SupergraphServiceFactory,
+{
+    let (unhealthy_subgraphs, unready_plugins) = if params.contains_key("ready") {
+        (
+            service_factory.unhealthy_subgraphs(),
+            service_factory.unready_plugins(),
+        )
+    } else {
+        (Vec::new(), Vec::new())
+    };
+    if unhealthy_subgraphs.is_empty() && unready_plugins.is_empty() {
+        (StatusCode::OK, Json(json!({ "status": "pass" })))
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({
+                "status": "fail",
+                "unhealthy_subgraphs": unhealthy_subgraphs,
+                "unready_plugins": unready_plugins,
+            })),
+        )
+    }
 }
 
 // Process the headers to make sure that `VARY` is set correctly
@@ -491,22 +968,77 @@ fn process_vary_header(headers: &mut HeaderMap<HeaderValue>) {
         headers.insert(VARY, HeaderValue::from_static("origin"));
     }
 }
-async fn run_graphql_request<RS>(
-    service: RS,
+/// A new supergraph service, ready to handle a request.
+///
+/// The services of a pipeline being replaced by a schema or configuration reload can be closed
+/// while a request is being routed to them: such a request is retried once on a new service.
+async fn ready_service<RF>(service_factory: &RF) -> Result<RF::SupergraphService, BoxError>
+where
+    RF: SupergraphServiceFactory,
+{
+    match service_factory.new_service().ready_oneshot().await {
+        Err(e) if e.is::<Closed>() || e.is::<ServiceError>() => {
+            tracing::debug!("router service closed, retrying the request: {}", e);
+            service_factory.new_service().ready_oneshot().await
+        }
+        result => result,
+    }
+}
+
+async fn run_graphql_request<RF>(
+    service_factory: RF,
     http_request: Request<graphql::Request>,
+    mode: GraphqlOverHttp,
+    serialization: &ResponseSerialization,
 ) -> impl IntoResponse
 where
-    RS: Service<
-            http::Request<graphql::Request>,
-            Response = http::Response<BoxStream<'static, graphql::Response>>,
-            Error = BoxError,
-        > + Send,
+    RF: SupergraphServiceFactory,
 {
-    match service.ready_oneshot().await {
+    let options = SerializationOptions::for_request(serialization, http_request.headers());
+    // in strict mode, the media type of the response is negotiated before executing the request
+    let media_type = match mode {
+        GraphqlOverHttp::Legacy => None,
+        GraphqlOverHttp::Strict => match ResponseMediaType::negotiate(http_request.headers()) {
+            Some(media_type) => Some(media_type),
+            None => {
+                return graphql_over_http::request_error(
+                    StatusCode::NOT_ACCEPTABLE,
+                    format!(
+                        "the responses can only be sent as {} or application/json",
+                        GRAPHQL_RESPONSE_CONTENT_TYPE
+                    ),
+                )
+            }
+        },
+    };
+    // the responses to persisted queries sent with GET requests can be revalidated by their clients
+    let revalidation = if is_revalidable(&http_request) {
+        Some(
+            http_request
+                .headers()
+                .get_all(IF_NONE_MATCH)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect::<Vec<_>>()
+                .join(","),
+        )
+    } else {
+        None
+    };
+    match ready_service(&service_factory).await {
         Ok(mut service) => {
+            let transformers = service_factory.response_body_transformers();
+            let info = RequestInfo::new(http_request.body());
             let (head, body) = http_request.into_parts();
 
-            match service.call(Request::from_parts(head, body)).await {
+            let result =
+                match panics::catch_panic(&info, service.call(Request::from_parts(head, body)))
+                    .await
+                {
+                    Some(result) => result,
+                    None => return panic_response(&info),
+                };
+            match result {
                 Err(e) => {
                     if let Some(source_err) = e.source() {
                         if source_err.is::<RateLimited>() {
@@ -515,6 +1047,9 @@ where
                         if source_err.is::<Elapsed>() {
                             return Elapsed::new().into_response();
                         }
+                        if source_err.is::<Overloaded>() {
+                            return Overloaded::new().into_response();
+                        }
                     }
                     tracing::error!("router service call failed: {}", e);
                     (
@@ -525,10 +1060,16 @@ where
                 }
                 Ok(response) => {
                     let (mut parts, mut stream) = response.into_parts();
+                    let body_size = parts.extensions.remove::<ResponseBodySize>();
 
                     process_vary_header(&mut parts.headers);
 
-                    match stream.next().await {
+                    let first = match panics::catch_panic(&info, stream.next()).await {
+                        Some(first) => first,
+                        None => return panic_response(&info),
+                    };
+                    let stream = panics::catch_stream_panic(info, stream);
+                    match first {
                         None => {
                             tracing::error!("router service is not available to process request",);
                             (
@@ -544,44 +1085,118 @@ where
                                     HeaderValue::from_static(MULTIPART_DEFER_CONTENT_TYPE),
                                 );
 
-                                // each chunk contains a response and the next delimiter, to let client parsers
+                                // each part contains a response and the next delimiter, to let client parsers
                                 // know that they can process the response right away
-                                let mut first_buf = Vec::from(
-                                    &b"\r\n--graphql\r\ncontent-type: application/json\r\n\r\n"[..],
-                                );
-                                serde_json::to_writer(&mut first_buf, &response).unwrap();
-                                first_buf.extend_from_slice(b"\r\n--graphql\r\n");
-
-                                let body = once(ready(Ok(Bytes::from(first_buf)))).chain(
-                                    stream.map(|res| {
-                                        let mut buf = Vec::from(
-                                            &b"content-type: application/json\r\n\r\n"[..],
-                                        );
-                                        serde_json::to_writer(&mut buf, &res).unwrap();
-
-                                        // the last chunk has a different end delimiter
-                                        if res.has_next.unwrap_or(false) {
-                                            buf.extend_from_slice(b"\r\n--graphql\r\n");
-                                        } else {
-                                            buf.extend_from_slice(b"\r\n--graphql--\r\n");
+                                let first = match serialize_response(
+                                    response,
+                                    options,
+                                    b"\r\n--graphql\r\ncontent-type: application/json\r\n\r\n",
+                                    b"\r\n--graphql\r\n",
+                                ) {
+                                    Ok(first) => first,
+                                    Err(e) => return serialization_error_response(e),
+                                };
+                                let next = stream.map(move |res| {
+                                    // the last part has a different end delimiter
+                                    let delimiter: &'static [u8] = if res.has_next.unwrap_or(false)
+                                    {
+                                        b"\r\n--graphql\r\n"
+                                    } else {
+                                        b"\r\n--graphql--\r\n"
+                                    };
+                                    serialize_response(
+                                        res,
+                                        options,
+                                        b"content-type: application/json\r\n\r\n",
+                                        delimiter,
+                                    )
+                                });
+                                // the status is already sent when a part can not be serialized, so
+                                // the response ends with a last part reporting the error
+                                let body = once(ready(Ok(first)))
+                                    .chain(next)
+                                    .scan(false, |failed, part| {
+                                        if *failed {
+                                            return ready(None);
                                         }
-
-                                        Ok::<_, BoxError>(buf.into())
-                                    }),
+                                        let part = part.unwrap_or_else(|e| {
+                                            tracing::error!(
+                                                "could not serialize the response: {}",
+                                                e
+                                            );
+                                            *failed = true;
+                                            SerializedResponse::Whole(Bytes::from_static(
+                                                SERIALIZATION_ERROR_PART,
+                                            ))
+                                        });
+                                        ready(Some(part.into_body()))
+                                    })
+                                    .flatten();
+                                let body = count_body(
+                                    transform_response_body(&transformers, body.boxed()),
+                                    body_size,
                                 );
 
                                 (parts, StreamBody::new(body)).into_response()
                             } else {
-                                parts.headers.insert(
-                                    CONTENT_TYPE,
-                                    HeaderValue::from_static("application/json"),
+                                match media_type {
+                                    Some(media_type) => {
+                                        parts.status =
+                                            media_type.status_code(parts.status, &response);
+                                        parts
+                                            .headers
+                                            .insert(CONTENT_TYPE, media_type.content_type());
+                                    }
+                                    None => {
+                                        parts.headers.insert(
+                                            CONTENT_TYPE,
+                                            HeaderValue::from_static("application/json"),
+                                        );
+                                    }
+                                }
+                                // the body sent is the serialized one only without transformers
+                                let revalidation = revalidation.filter(|_| {
+                                    transformers.is_empty()
+                                        && parts.status == StatusCode::OK
+                                        && response.errors.is_empty()
+                                });
+                                let body = if let Some(if_none_match) = revalidation.as_deref() {
+                                    // the ETag needs the whole body
+                                    let body =
+                                        match serialize_whole(response, options, b"", b"").await {
+                                            Ok(body) => body,
+                                            Err(e) => return serialization_error_response(e),
+                                        };
+                                    let etag = etag(&body);
+                                    parts.headers.insert(ETAG, etag.clone());
+                                    if matches_etag(if_none_match, &etag) {
+                                        parts.status = StatusCode::NOT_MODIFIED;
+                                        parts.headers.remove(CONTENT_TYPE);
+                                        if let Some(body_size) = &body_size {
+                                            body_size.report(0);
+                                        }
+                                        return (parts, ()).into_response();
+                                    }
+                                    SerializedResponse::Whole(body)
+                                } else {
+                                    match serialize_response(response, options, b"", b"") {
+                                        Ok(body) => body,
+                                        Err(e) => return serialization_error_response(e),
+                                    }
+                                };
+                                // the length of the streamed responses is not known yet
+                                if let (SerializedResponse::Whole(body), true) =
+                                    (&body, transformers.is_empty())
+                                {
+                                    parts
+                                        .headers
+                                        .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+                                }
+                                let body = count_body(
+                                    transform_response_body(&transformers, body.into_body()),
+                                    body_size,
                                 );
-                                tracing::trace_span!("serialize_response").in_scope(|| {
-                                    http_ext::Response::from(http::Response::from_parts(
-                                        parts, response,
-                                    ))
-                                    .into_response()
-                                })
+                                (parts, StreamBody::new(body)).into_response()
                             }
                         }
                     }
@@ -597,6 +1212,9 @@ where
                 if source_err.is::<Elapsed>() {
                     return Elapsed::new().into_response();
                 }
+                if source_err.is::<Overloaded>() {
+                    return Overloaded::new().into_response();
+                }
             }
 
             (
@@ -608,6 +1226,285 @@ where
     }
 }
 
+/// Apply the transformations of the plugins to the serialized body of a response.
+fn transform_response_body(
+    transformers: &[ResponseBodyTransformer],
+    body: ResponseBody,
+) -> ResponseBody {
+    transformers
+        .iter()
+        .fold(body, |body, transformer| transformer(body))
+}
+
+/// Count the bytes of a body sent to the client, for the plugins accounting for the size of the
+/// responses.
+fn count_body(body: ResponseBody, size: Option<ResponseBodySize>) -> ResponseBody {
+    match size {
+        Some(size) => size.count(body),
+        None => body,
+    }
+}
+
+/// A response serialized between a prefix and a suffix.
+enum SerializedResponse {
+    /// The whole body, serialized on the task handling the request.
+    Whole(Bytes),
+    /// The chunks of a body over [`INLINE_SERIALIZATION_LIMIT`], streamed as they are serialized
+    /// on the blocking pool.
+    Streamed(ResponseBody),
+}
+
+impl SerializedResponse {
+    fn into_body(self) -> ResponseBody {
+        match self {
+            SerializedResponse::Whole(body) => once(ready(Ok(body))).boxed(),
+            SerializedResponse::Streamed(body) => body,
+        }
+    }
+}
+
+/// Serialize a response between a prefix and a suffix, whole when it is under
+/// [`INLINE_SERIALIZATION_LIMIT`], or else in chunks sent to the client as soon as they are
+/// written. An error is only returned when nothing was sent yet.
+fn serialize_response(
+    mut response: graphql::Response,
+    options: SerializationOptions,
+    prefix: &'static [u8],
+    suffix: &'static [u8],
+) -> Result<SerializedResponse, BoxError> {
+    match serialize_inline(&mut response, options, prefix, suffix)? {
+        Some(body) => Ok(SerializedResponse::Whole(body)),
+        None => Ok(SerializedResponse::Streamed(stream_response(
+            response, options, prefix, suffix,
+        ))),
+    }
+}
+
+/// Serialize a whole response, on the blocking pool when it is over
+/// [`INLINE_SERIALIZATION_LIMIT`].
+async fn serialize_whole(
+    mut response: graphql::Response,
+    options: SerializationOptions,
+    prefix: &'static [u8],
+    suffix: &'static [u8],
+) -> Result<Bytes, BoxError> {
+    if let Some(body) = serialize_inline(&mut response, options, prefix, suffix)? {
+        return Ok(body);
+    }
+    let span = tracing::trace_span!("serialize_response");
+    tokio::task::spawn_blocking(move || -> Result<Bytes, BoxError> {
+        let _guard = span.enter();
+        let mut body = prefix.to_vec();
+        serialization::write(&mut body, &response, options)?;
+        body.extend_from_slice(suffix);
+        Ok(Bytes::from(body))
+    })
+    .await?
+}
+
+/// Serialize a response on the task handling its request, unless it is over
+/// [`INLINE_SERIALIZATION_LIMIT`]. The response is prepared for its serialization either way.
+fn serialize_inline(
+    response: &mut graphql::Response,
+    options: SerializationOptions,
+    prefix: &'static [u8],
+    suffix: &'static [u8],
+) -> Result<Option<Bytes>, serde_json::Error> {
+    serialization::prepare(response, options);
+    let mut writer = LimitedWriter {
+        buffer: prefix.to_vec(),
+        limit: INLINE_SERIALIZATION_LIMIT,
+    };
+    let result = tracing::trace_span!("serialize_response")
+        .in_scope(|| serialization::write(&mut writer, response, options));
+    match result {
+        Ok(()) => {
+            writer.buffer.extend_from_slice(suffix);
+            Ok(Some(Bytes::from(writer.buffer)))
+        }
+        // the buffer is only failing to be written over the limit
+        Err(e) if e.is_io() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Serialize a prepared response on the blocking pool, in chunks sent to the client through a
+/// bounded channel. The serialization never waits for the client: the chunks which do not fit in
+/// the channel are kept aside, and sent by an async task as the client reads the previous ones.
+fn stream_response(
+    response: graphql::Response,
+    options: SerializationOptions,
+    prefix: &'static [u8],
+    suffix: &'static [u8],
+) -> ResponseBody {
+    let (sender, receiver) = mpsc::channel(RESPONSE_CHUNKS_BUFFER);
+    let span = tracing::trace_span!("serialize_response");
+    let mut writer = ChunkWriter {
+        buffer: Vec::with_capacity(RESPONSE_CHUNK_SIZE),
+        sender: sender.clone(),
+        pending: Vec::new(),
+    };
+    tokio::task::spawn(async move {
+        let serialized = tokio::task::spawn_blocking(move || {
+            let _guard = span.enter();
+            writer.buffer.extend_from_slice(prefix);
+            let result = serialization::write(&mut writer, &response, options)
+                .map_err(io::Error::from)
+                .and_then(|()| {
+                    writer.buffer.extend_from_slice(suffix);
+                    writer.flush()
+                });
+            (result, writer.pending)
+        })
+        .await;
+        let (result, pending) = match serialized {
+            Ok(serialized) => serialized,
+            Err(e) => (Err(io::Error::new(io::ErrorKind::Other, e)), Vec::new()),
+        };
+        for chunk in pending {
+            if sender.send(Ok(chunk)).await.is_err() {
+                return;
+            }
+        }
+        // the channel is only closed when the client is gone. Otherwise the body is aborted
+        // rather than ended, so that the client does not take it for a whole response
+        if let Err(e) = result {
+            if !sender.is_closed() {
+                tracing::error!("could not serialize the response: {}", e);
+                let _ = sender.send(Err(e.into())).await;
+            }
+        }
+    });
+    ReceiverStream::new(receiver).boxed()
+}
+
+/// Writes the serialized responses in chunks of [`RESPONSE_CHUNK_SIZE`] bytes.
+struct ChunkWriter {
+    buffer: Vec<u8>,
+    sender: mpsc::Sender<Result<Bytes, BoxError>>,
+    /// The chunks which did not fit in the channel, in order.
+    pending: Vec<Bytes>,
+}
+
+impl io::Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= RESPONSE_CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::replace(
+            &mut self.buffer,
+            Vec::with_capacity(RESPONSE_CHUNK_SIZE),
+        ));
+        if !self.pending.is_empty() {
+            self.pending.push(chunk);
+            return Ok(());
+        }
+        match self.sender.try_send(Ok(chunk)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(chunk)) => {
+                self.pending.extend(chunk.ok());
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the client is gone",
+            )),
+        }
+    }
+}
+
+/// Buffers a serialized response, and fails once it is over its limit.
+struct LimitedWriter {
+    buffer: Vec<u8>,
+    limit: usize,
+}
+
+impl io::Write for LimitedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buffer.len() + buf.len() > self.limit {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the response is over the limit of the inline serialization",
+            ));
+        }
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The response to a request which response could not be serialized.
+fn serialization_error_response(error: BoxError) -> Response {
+    tracing::error!("could not serialize the response: {}", error);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "could not serialize the response",
+    )
+        .into_response()
+}
+
+/// The response to a request that panicked.
+fn panic_response(info: &RequestInfo) -> Response {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(info.panic_response()),
+    )
+        .into_response()
+}
+
+/// Whether a request is a GET request for a persisted query, which response can be cached by
+/// its client and revalidated with its `ETag`.
+fn is_revalidable(request: &Request<graphql::Request>) -> bool {
+    request.method() == Method::GET
+        && request
+            .body()
+            .extensions
+            .contains_key(graphql::PERSISTED_QUERY_EXTENSION)
+}
+
+/// A weak `ETag` of a response body. The body is compressed afterwards, so the representations
+/// sent with every content encoding share this validator, which only a weak one allows.
+fn etag(body: &[u8]) -> HeaderValue {
+    HeaderValue::from_str(&format!("W/\"{}\"", hex::encode(Sha256::digest(body))))
+        .expect("hexadecimal digests are valid header values; qed")
+}
+
+/// Whether an `If-None-Match` header matches an `ETag`, with the weak comparison required for
+/// this header.
+fn matches_etag(if_none_match: &str, etag: &HeaderValue) -> bool {
+    let opaque = |tag: &[u8]| tag.strip_prefix(b"W/").unwrap_or(tag).to_vec();
+    let etag = opaque(etag.as_bytes());
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || opaque(tag.as_bytes()) == etag)
+}
+
+/// Whether the body of a request is a bare query, sent by some CLI tools and legacy clients.
+fn has_graphql_media_type(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|media_type| {
+            media_type
+                .trim()
+                .eq_ignore_ascii_case("application/graphql")
+        })
+        .unwrap_or(false)
+}
+
 fn prefers_html(headers: &HeaderMap) -> bool {
     let text_html = MediaType::new(TEXT, HTML);
 
@@ -740,6 +1637,7 @@ mod tests {
     use std::str::FromStr;
 
     use async_compression::tokio::write::GzipEncoder;
+    use futures::stream::BoxStream;
     use http::header::ACCEPT_ENCODING;
     use http::header::CONTENT_TYPE;
     use http::header::{self};
@@ -761,6 +1659,7 @@ mod tests {
 
     use super::*;
     use crate::configuration::Cors;
+    use crate::http_ext;
     use crate::json_ext::Path;
     use crate::services::new_service::NewService;
     use crate::services::transport;
@@ -1203,6 +2102,10 @@ mod tests {
             response.headers().get(header::CONTENT_TYPE),
             Some(&HeaderValue::from_static("application/json"))
         );
+        assert_eq!(
+            response.content_length(),
+            Some(serde_json::to_vec(&expected_response).unwrap().len() as u64)
+        );
 
         assert_eq!(
             response.json::<graphql::Response>().await.unwrap(),
@@ -1454,11 +2357,183 @@ mod tests {
                 .error_for_status()
                 .unwrap();
 
-            assert_eq!(
-                response.json::<graphql::Response>().await.unwrap(),
-                expected_response,
-            );
-        }
+            assert_eq!(
+                response.json::<graphql::Response>().await.unwrap(),
+                expected_response,
+            );
+        }
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn response_with_additional_endpoints() -> Result<(), ApolloRouterError> {
+        let expected_response = graphql::Response::builder()
+            .data(json!({"response": "yay"}))
+            .build();
+        let example_response = expected_response.clone();
+        let mut expectations = MockSupergraphService::new();
+        expectations
+            .expect_service_call()
+            .times(3)
+            .returning(move |_| {
+                let example_response = example_response.clone();
+                Ok(http_ext::from_response_to_stream(
+                    http::Response::builder()
+                        .status(200)
+                        .body(example_response)
+                        .unwrap(),
+                ))
+            });
+        let conf = Configuration::builder()
+            .server(
+                crate::configuration::Server::builder()
+                    .listen(SocketAddr::from_str("127.0.0.1:0").unwrap())
+                    .graphql_path(String::from("/graphql"))
+                    .additional_graphql_paths(vec![
+                        String::from("/api/:version/graphql"),
+                        String::from("/legacy/*"),
+                    ])
+                    .build(),
+            )
+            .build();
+        let (server, client) = init_with_config(expectations, conf, HashMap::new()).await;
+        for url in &[
+            format!("{}/graphql", server.listen_address()),
+            format!("{}/api/v2/graphql", server.listen_address()),
+            format!("{}/legacy/graphql", server.listen_address()),
+        ] {
+            let response = client
+                .post(url.as_str())
+                .body(json!({ "query": "query" }).to_string())
+                .send()
+                .await
+                .unwrap()
+                .error_for_status()
+                .unwrap();
+
+            assert_eq!(
+                response.json::<graphql::Response>().await.unwrap(),
+                expected_response,
+            );
+        }
+
+        let response = client
+            .post(format!("{}/other", server.listen_address()))
+            .body(json!({ "query": "query" }).to_string())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn response_with_extra_routes() -> Result<(), ApolloRouterError> {
+        let backend = axum::Server::bind(&SocketAddr::from_str("127.0.0.1:0").unwrap()).serve(
+            Router::new()
+                .route(
+                    "/api/*path",
+                    any(|uri: Uri, headers: HeaderMap| async move {
+                        format!(
+                            "backend: {} {:?} {:?}",
+                            uri,
+                            headers.get("authorization"),
+                            headers.get("x-client")
+                        )
+                    }),
+                )
+                .route(
+                    "/slow/*path",
+                    any(|| async {
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                        "too late"
+                    }),
+                )
+                .into_make_service(),
+        );
+        let backend_address = backend.local_addr();
+        tokio::spawn(backend);
+
+        let mut favicon = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut favicon, b"icon").unwrap();
+
+        let conf = Configuration::builder()
+            .server(
+                crate::configuration::Server::builder()
+                    .listen(SocketAddr::from_str("127.0.0.1:0").unwrap())
+                    .routes(
+                        serde_json::from_value(json!([
+                            { "path": "/.well-known/security.txt", "serve": { "body": "Contact: security@example.com" } },
+                            { "path": "/favicon.ico", "serve": { "file": favicon.path() }, "content_type": "image/x-icon" },
+                            { "path": "/api/*", "serve": { "proxy": format!("http://{}", backend_address) } },
+                            { "path": "/slow/*", "serve": { "proxy": format!("http://{}", backend_address) }, "timeout": "100ms" },
+                        ]))
+                        .unwrap(),
+                    )
+                    .build(),
+            )
+            .build();
+        let (server, client) =
+            init_with_config(MockSupergraphService::new(), conf, HashMap::new()).await;
+
+        let response = client
+            .get(format!(
+                "{}/.well-known/security.txt",
+                server.listen_address()
+            ))
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(
+            response.text().await.unwrap(),
+            "Contact: security@example.com"
+        );
+
+        let response = client
+            .get(format!("{}/favicon.ico", server.listen_address()))
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "image/x-icon"
+        );
+        assert_eq!(response.bytes().await.unwrap().as_ref(), b"icon");
+
+        let response = client
+            .post(format!("{}/api/users?page=2", server.listen_address()))
+            .header("authorization", "Bearer secret")
+            .header("x-client", "web")
+            .header("connection", "keep-alive, x-client")
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+        // neither the credentials nor the headers of the connection are forwarded
+        assert_eq!(
+            response.text().await.unwrap(),
+            "backend: /api/users?page=2 None None"
+        );
+
+        let response = client
+            .get(format!("{}/slow/query", server.listen_address()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
 
         server.shutdown().await?;
         Ok(())
@@ -1583,6 +2658,216 @@ mod tests {
         server.shutdown().await
     }
 
+    #[tokio::test]
+    async fn it_accepts_raw_graphql_post_bodies() -> Result<(), ApolloRouterError> {
+        let query = "query Me { me { name } }";
+        let expected_response = graphql::Response::builder()
+            .data(json!({"response": "yay"}))
+            .build();
+        let example_response = expected_response.clone();
+
+        let mut expectations = MockSupergraphService::new();
+        expectations
+            .expect_service_call()
+            .times(1)
+            .withf(move |req| {
+                assert_eq!(req.body().query.as_deref().unwrap(), query);
+                assert_eq!(req.body().operation_name.as_deref().unwrap(), "Me");
+                assert_eq!(
+                    req.body().variables.get("id"),
+                    Some(&serde_json_bytes::Value::from(1))
+                );
+                true
+            })
+            .returning(move |_| {
+                let example_response = example_response.clone();
+                Ok(http_ext::from_response_to_stream(
+                    http::Response::builder()
+                        .status(200)
+                        .body(example_response)
+                        .unwrap(),
+                ))
+            });
+        let (server, client) = init(expectations).await;
+        let url = format!("{}/", server.listen_address());
+
+        let response = client
+            .post(url.as_str())
+            .query(&[("operationName", "Me"), ("variables", r#"{"id":1}"#)])
+            .header(CONTENT_TYPE, "application/graphql")
+            .body(query)
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+
+        assert_eq!(
+            response.json::<graphql::Response>().await.unwrap(),
+            expected_response,
+        );
+
+        server.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn it_revalidates_persisted_queries_with_etags() -> Result<(), ApolloRouterError> {
+        let example_response = graphql::Response::builder()
+            .data(json!({"response": "yay"}))
+            .build();
+        let mut expectations = MockSupergraphService::new();
+        expectations
+            .expect_service_call()
+            .times(3)
+            .returning(move |_| {
+                let example_response = example_response.clone();
+                Ok(http_ext::from_response_to_stream(
+                    http::Response::builder()
+                        .status(200)
+                        .body(example_response)
+                        .unwrap(),
+                ))
+            });
+        let (server, client) = init(expectations).await;
+        let url = format!("{}/", server.listen_address());
+        let extensions = json!({
+            "persistedQuery": {
+                "version": 1,
+                "sha256Hash": "ecf4edb46db40b5132295c0291d62fb65d6759a9eedfa4d5d612dd5ec54a6b38"
+            }
+        })
+        .to_string();
+        let get = |if_none_match: Option<&str>| {
+            let mut request = client
+                .get(url.as_str())
+                .query(&[("extensions", extensions.as_str())]);
+            if let Some(if_none_match) = if_none_match {
+                request = request.header(IF_NONE_MATCH, if_none_match);
+            }
+            request.send()
+        };
+
+        let response = get(None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .expect("the response should have an etag")
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let response = get(Some(&format!("\"other\", {}", etag))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers().get(ETAG).unwrap(), etag.as_str());
+        assert!(response.bytes().await.unwrap().is_empty());
+
+        let response = get(Some("\"other\"")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.json::<graphql::Response>().await.unwrap(),
+            graphql::Response::builder()
+                .data(json!({"response": "yay"}))
+                .build()
+        );
+
+        server.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn it_sends_weak_etags_for_compressed_responses() -> Result<(), ApolloRouterError> {
+        let example_response = graphql::Response::builder()
+            .data(json!({"response": "yay"}))
+            .build();
+        let mut expectations = MockSupergraphService::new();
+        expectations
+            .expect_service_call()
+            .times(3)
+            .returning(move |_| {
+                let example_response = example_response.clone();
+                Ok(http_ext::from_response_to_stream(
+                    http::Response::builder()
+                        .status(200)
+                        .body(example_response)
+                        .unwrap(),
+                ))
+            });
+        let (server, client) = init(expectations).await;
+        let url = format!("{}/", server.listen_address());
+        let extensions = json!({
+            "persistedQuery": {
+                "version": 1,
+                "sha256Hash": "ecf4edb46db40b5132295c0291d62fb65d6759a9eedfa4d5d612dd5ec54a6b38"
+            }
+        })
+        .to_string();
+        let get = |accept_encoding: &str, if_none_match: Option<&str>| {
+            let mut request = client
+                .get(url.as_str())
+                .query(&[("extensions", extensions.as_str())])
+                .header(ACCEPT_ENCODING, accept_encoding);
+            if let Some(if_none_match) = if_none_match {
+                request = request.header(IF_NONE_MATCH, if_none_match);
+            }
+            request.send()
+        };
+
+        let identity = get("identity", None).await.unwrap();
+        let gzip = get("gzip", None).await.unwrap();
+        assert_eq!(gzip.status(), StatusCode::OK);
+        assert_eq!(gzip.headers().get(CONTENT_ENCODING).unwrap(), "gzip");
+        assert!(identity.headers().get(CONTENT_ENCODING).is_none());
+        // the representations share a validator, which must be weak
+        let etag = gzip.headers().get(ETAG).unwrap().to_str().unwrap();
+        assert!(etag.starts_with("W/\""));
+        assert_eq!(identity.headers().get(ETAG).unwrap(), etag);
+
+        let response = get("gzip", Some(etag)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers().get(ETAG).unwrap(), etag);
+
+        server.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn it_streams_the_large_responses() {
+        // the second response is over the limit of the inline serialization
+        for items in [10, 10_000] {
+            let response = graphql::Response::builder()
+                .data(json!({ "items": vec!["an item"; items] }))
+                .build();
+            let expected = [
+                &b"prefix"[..],
+                &serde_json::to_vec(&response).unwrap(),
+                &b"suffix"[..],
+            ]
+            .concat();
+
+            let body =
+                serialize_response(response.clone(), Default::default(), b"prefix", b"suffix")
+                    .unwrap();
+            assert_eq!(matches!(body, SerializedResponse::Whole(_)), items == 10);
+            let chunks = body.into_body().try_collect::<Vec<_>>().await.unwrap();
+            assert_eq!(chunks.concat(), expected);
+
+            let body = serialize_whole(response, Default::default(), b"prefix", b"suffix")
+                .await
+                .unwrap();
+            assert_eq!(body, expected);
+        }
+    }
+
+    #[test]
+    fn it_compares_etags_weakly() {
+        let etag = HeaderValue::from_static("W/\"abc\"");
+        assert!(matches_etag("\"abc\"", &etag));
+        assert!(matches_etag("W/\"abc\"", &etag));
+        assert!(matches_etag("\"def\", W/\"abc\"", &etag));
+        assert!(matches_etag("*", &etag));
+        assert!(!matches_etag("\"def\"", &etag));
+        assert!(!matches_etag("", &etag));
+    }
+
     #[tokio::test]
     async fn response_failure() -> Result<(), ApolloRouterError> {
         let mut expectations = MockSupergraphService::new();
@@ -1631,6 +2916,50 @@ mod tests {
         server.shutdown().await
     }
 
+    #[tokio::test]
+    async fn it_answers_with_the_allowed_methods() -> Result<(), ApolloRouterError> {
+        let expectations = MockSupergraphService::new();
+        let conf = Configuration::builder()
+            .cors(Cors::builder().build())
+            .server(
+                crate::configuration::Server::builder()
+                    .listen(SocketAddr::from_str("127.0.0.1:0").unwrap())
+                    .build(),
+            )
+            .build();
+        let (server, client) = init_with_config(expectations, conf, HashMap::new()).await;
+        let url = format!("{}/", server.listen_address());
+
+        let response = client
+            .request(Method::OPTIONS, url.as_str())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_header!(
+            &response,
+            ALLOW,
+            vec!["GET, HEAD, POST, OPTIONS"],
+            "Incorrect allow header"
+        );
+
+        let response = client
+            .request(Method::DELETE, url.as_str())
+            .header(ORIGIN, "https://studio.apollographql.com")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_header!(
+            &response,
+            ALLOW,
+            vec!["GET, HEAD, POST, OPTIONS"],
+            "Incorrect allow header"
+        );
+
+        server.shutdown().await
+    }
+
     #[tokio::test]
     async fn cors_preflight() -> Result<(), ApolloRouterError> {
         let expectations = MockSupergraphService::new();
@@ -1857,6 +3186,111 @@ Content-Type: application/json\r
         server.shutdown().await
     }
 
+    #[tokio::test]
+    async fn it_complies_with_the_graphql_over_http_specification() -> Result<(), ApolloRouterError>
+    {
+        let mut expectations = MockSupergraphService::new();
+        expectations.expect_service_call().returning(|request| {
+            // the router answers request errors with a 400 status code
+            let (status, response) = match request.body().query.as_deref() {
+                Some("invalid") => (
+                    StatusCode::BAD_REQUEST,
+                    graphql::Response::builder()
+                        .errors(vec![graphql::Error::builder()
+                            .message("syntax error")
+                            .build()])
+                        .build(),
+                ),
+                _ => (
+                    StatusCode::OK,
+                    graphql::Response::builder()
+                        .data(json!({ "me": "Ada" }))
+                        .build(),
+                ),
+            };
+            Ok(http_ext::from_response_to_stream(
+                http::Response::builder()
+                    .status(status)
+                    .body(response)
+                    .unwrap(),
+            ))
+        });
+        let conf = Configuration::builder()
+            .server(
+                crate::configuration::Server::builder()
+                    .listen(SocketAddr::from_str("127.0.0.1:0").unwrap())
+                    .graphql_over_http(GraphqlOverHttp::Strict)
+                    .build(),
+            )
+            .build();
+        let (server, client) = init_with_config(expectations, conf, HashMap::new()).await;
+        let url = format!("{}/", server.listen_address());
+        let post = |query: &str, accept: &str| {
+            client
+                .post(url.as_str())
+                .header(ACCEPT, accept)
+                .body(json!({ "query": query }).to_string())
+                .send()
+        };
+
+        // the media type of the responses is negotiated
+        for (accept, content_type) in [
+            (
+                "application/graphql-response+json, application/json;q=0.9",
+                GRAPHQL_RESPONSE_CONTENT_TYPE,
+            ),
+            ("application/json", "application/json"),
+            ("*/*", "application/json"),
+        ] {
+            let response = post("{ me }", accept).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers().get(CONTENT_TYPE).unwrap(),
+                content_type,
+                "{}",
+                accept
+            );
+        }
+        let response = post("{ me }", "text/html").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_ACCEPTABLE);
+
+        // request errors get a 400 status code with application/graphql-response+json only
+        let response = post("invalid", "application/graphql-response+json")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = post("invalid", "application/json").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.json::<graphql::Response>().await.unwrap().errors[0].message,
+            "syntax error"
+        );
+
+        // the requests that are not well-formed are rejected
+        let response = client
+            .post(url.as_str())
+            .header(CONTENT_TYPE, "application/json; charset=iso-8859-1")
+            .body(json!({ "query": "{ me }" }).to_string())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        for body in ["{ me }", r#"{ "query": 1 }"#] {
+            let response = client.post(url.as_str()).body(body).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", body);
+            assert_eq!(
+                response.json::<graphql::Response>().await.unwrap().errors[0]
+                    .extensions
+                    .get("code"),
+                Some(&"BAD_REQUEST".into())
+            );
+        }
+        let response = client.put(url.as_str()).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        server.shutdown().await
+    }
+
     #[test(tokio::test)]
     async fn it_doesnt_display_disabled_home_page() -> Result<(), ApolloRouterError> {
         let expectations = MockSupergraphService::new();
@@ -2306,6 +3740,93 @@ Content-Type: application/json\r
         server.shutdown().await
     }
 
+    #[derive(Clone)]
+    struct ReloadingSupergraphServiceFactory {
+        inner: MockSupergraphServiceType,
+        created: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl NewService<http::Request<graphql::Request>> for ReloadingSupergraphServiceFactory {
+        type Service =
+            tower::buffer::Buffer<MockSupergraphServiceType, http::Request<graphql::Request>>;
+
+        fn new_service(&self) -> Self::Service {
+            let (service, worker) = tower::buffer::Buffer::pair(self.inner.clone(), 1);
+            // the first service is closed, as if its pipeline had been replaced
+            if self
+                .created
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+                > 0
+            {
+                tokio::spawn(worker);
+            }
+            service
+        }
+    }
+
+    impl SupergraphServiceFactory for ReloadingSupergraphServiceFactory {
+        type SupergraphService =
+            tower::buffer::Buffer<MockSupergraphServiceType, http::Request<graphql::Request>>;
+
+        type Future = <<ReloadingSupergraphServiceFactory as NewService<
+            http::Request<graphql::Request>,
+        >>::Service as Service<http::Request<graphql::Request>>>::Future;
+
+        fn custom_endpoints(&self) -> HashMap<String, Handler> {
+            HashMap::new()
+        }
+    }
+
+    #[tokio::test]
+    async fn it_retries_requests_on_closed_services() {
+        let (service, _handle) = tower_test::mock::spawn();
+        let service_factory = ReloadingSupergraphServiceFactory {
+            inner: service.into_inner(),
+            created: Default::default(),
+        };
+
+        assert!(ready_service(&service_factory).await.is_ok());
+        assert_eq!(
+            service_factory
+                .created
+                .load(std::sync::atomic::Ordering::SeqCst),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn it_transforms_response_bodies_in_order() {
+        let transformers: Vec<ResponseBodyTransformer> = vec![
+            Arc::new(|body: ResponseBody| {
+                body.map_ok(|chunk| {
+                    Bytes::from(String::from_utf8_lossy(&chunk).replace("internal", "public"))
+                })
+                .boxed()
+            }),
+            Arc::new(|body: ResponseBody| {
+                body.map_ok(|chunk| Bytes::from(String::from_utf8_lossy(&chunk).to_uppercase()))
+                    .boxed()
+            }),
+        ];
+        let body = stream::iter(vec![
+            Ok(Bytes::from_static(b"{\"url\":\"http://internal\"}")),
+            Ok(Bytes::from_static(b"{\"hasNext\":false}")),
+        ])
+        .boxed();
+
+        let chunks: Vec<Bytes> = transform_response_body(&transformers, body)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            chunks,
+            vec![
+                Bytes::from_static(b"{\"URL\":\"HTTP://PUBLIC\"}"),
+                Bytes::from_static(b"{\"HASNEXT\":FALSE}"),
+            ]
+        );
+    }
+
     // Test Vary processing
 
     #[test]
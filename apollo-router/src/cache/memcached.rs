@@ -0,0 +1,408 @@
+//! [`KeyValueStorage`] in Memcached, through its text protocol.
+//!
+//! Memcached can't run scripts, so the counters are checked and then incremented one by one:
+//! concurrent requests can exceed the limits of a quota by a few operations.
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use sha2::Digest;
+use sha2::Sha256;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufStream;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::sync::MutexGuard;
+use tower::BoxError;
+
+use super::storage::Counters;
+use super::storage::Increment;
+use super::storage::KeyValueStorage;
+use crate::configuration::Storage;
+
+/// Longest key accepted by Memcached.
+const MAX_KEY_LENGTH: usize = 250;
+
+/// Longest relative expiration time, longer ones being read by Memcached as UNIX timestamps.
+const MAX_RELATIVE_EXPIRATION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// [`KeyValueStorage`] in a Memcached server, through a pool of connections.
+pub(crate) struct MemcachedStorage {
+    addr: String,
+    timeout: Duration,
+    /// The connections, none once they failed until they are opened again.
+    connections: Vec<Mutex<Option<Connection>>>,
+    next: AtomicUsize,
+}
+
+impl MemcachedStorage {
+    pub(crate) async fn new(url: &str, config: &Storage) -> Result<Self, BoxError> {
+        let url = url::Url::parse(url)?;
+        if url.scheme() != "memcached" {
+            return Err(format!("'{}' is not a memcached:// URL", url).into());
+        }
+        let addr = format!(
+            "{}:{}",
+            url.host_str().ok_or("the Memcached URL has no host")?,
+            url.port().unwrap_or(11211)
+        );
+        let mut connections = Vec::with_capacity(config.pool_size.get());
+        for _ in 0..config.pool_size.get() {
+            let connection =
+                tokio::time::timeout(config.timeout, Connection::open(&addr)).await??;
+            connections.push(Mutex::new(Some(connection)));
+        }
+        Ok(Self {
+            addr,
+            timeout: config.timeout,
+            connections,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// The next connection of the pool, in a round robin, opened again if it failed.
+    ///
+    /// The connection is taken out of the pool while it is used, and only put back by
+    /// [`Checkout::release`] once its reply was read: a connection dropped in the middle of an
+    /// operation, e.g. by a timeout, is not reused.
+    async fn checkout(&self) -> Result<Checkout<'_>, BoxError> {
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut slot = self.connections[next % self.connections.len()].lock().await;
+        let connection = match slot.take() {
+            Some(connection) => connection,
+            None => Connection::open(&self.addr).await?,
+        };
+        Ok(Checkout { slot, connection })
+    }
+
+    /// The counters of the increments, incremented unless one of them already reached its limit.
+    async fn increment_counters(&self, increments: &[Increment]) -> Result<Counters, BoxError> {
+        let mut checkout = self.checkout().await?;
+        let connection = &mut checkout.connection;
+        let keys: Vec<String> = increments
+            .iter()
+            .map(|increment| increment.key.clone())
+            .collect();
+        let mut values: Vec<u64> = connection
+            .get(&keys)
+            .await?
+            .into_iter()
+            .map(super::storage::counter)
+            .collect();
+        let incremented = increments
+            .iter()
+            .zip(&values)
+            .all(|(increment, value)| increment.limit.map(|limit| *value < limit).unwrap_or(true));
+        if incremented {
+            for (increment, value) in increments.iter().zip(values.iter_mut()) {
+                // the counter expires after its time to live from its first increment
+                connection
+                    .store("add", &increment.key, b"0", Some(increment.ttl))
+                    .await?;
+                *value = connection.incr(&increment.key, increment.value).await?;
+            }
+        }
+        checkout.release();
+        Ok(Counters {
+            values,
+            incremented,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl KeyValueStorage for MemcachedStorage {
+    async fn get(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>, BoxError> {
+        tokio::time::timeout(self.timeout, async {
+            let mut checkout = self.checkout().await?;
+            let values = checkout.connection.get(keys).await?;
+            checkout.release();
+            Ok(values)
+        })
+        .await?
+    }
+
+    async fn insert(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<(), BoxError> {
+        tokio::time::timeout(self.timeout, async {
+            let mut checkout = self.checkout().await?;
+            checkout.connection.store("set", &key, &value, ttl).await?;
+            checkout.release();
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn increment(&self, increments: &[Increment]) -> Result<Counters, BoxError> {
+        tokio::time::timeout(self.timeout, self.increment_counters(increments)).await?
+    }
+}
+
+/// A connection taken out of the pool.
+struct Checkout<'a> {
+    slot: MutexGuard<'a, Option<Connection>>,
+    connection: Connection,
+}
+
+impl Checkout<'_> {
+    /// Put the connection back in the pool.
+    fn release(mut self) {
+        *self.slot = Some(self.connection);
+    }
+}
+
+struct Connection {
+    stream: BufStream<TcpStream>,
+}
+
+impl Connection {
+    async fn open(addr: &str) -> Result<Self, BoxError> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream: BufStream::new(stream),
+        })
+    }
+
+    /// The values of the keys, with a `get` command.
+    async fn get(&mut self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>, BoxError> {
+        let keys: Vec<String> = keys.iter().map(|key| memcached_key(key)).collect();
+        self.stream
+            .write_all(format!("get {}\r\n", keys.join(" ")).as_bytes())
+            .await?;
+        self.stream.flush().await?;
+
+        let mut values = vec![None; keys.len()];
+        loop {
+            let line = self.read_line().await?;
+            if line == "END" {
+                return Ok(values);
+            }
+            // VALUE <key> <flags> <bytes>
+            let mut parts = line.split(' ');
+            let (key, length) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                (Some("VALUE"), Some(key), Some(_flags), Some(length)) => {
+                    (key.to_string(), length.parse::<usize>()?)
+                }
+                _ => return Err(reply_error(&line)),
+            };
+            let mut value = vec![0; length + 2];
+            self.stream.read_exact(&mut value).await?;
+            value.truncate(length);
+            for (position, _) in keys.iter().enumerate().filter(|(_, k)| **k == key) {
+                values[position] = Some(value.clone());
+            }
+        }
+    }
+
+    /// Store a value with a `set` or `add` command, `add` leaving the existing values as they are.
+    async fn store(
+        &mut self,
+        command: &str,
+        key: &str,
+        value: &[u8],
+        ttl: Option<Duration>,
+    ) -> Result<(), BoxError> {
+        let header = format!(
+            "{} {} 0 {} {}\r\n",
+            command,
+            memcached_key(key),
+            expiration(ttl),
+            value.len()
+        );
+        self.stream.write_all(header.as_bytes()).await?;
+        self.stream.write_all(value).await?;
+        self.stream.write_all(b"\r\n").await?;
+        self.stream.flush().await?;
+        match self.read_line().await?.as_str() {
+            "STORED" | "NOT_STORED" => Ok(()),
+            line => Err(reply_error(line)),
+        }
+    }
+
+    /// Increment a counter with an `incr` command, returning its new value.
+    async fn incr(&mut self, key: &str, value: u64) -> Result<u64, BoxError> {
+        self.stream
+            .write_all(format!("incr {} {}\r\n", memcached_key(key), value).as_bytes())
+            .await?;
+        self.stream.flush().await?;
+        let line = self.read_line().await?;
+        line.parse().map_err(|_| reply_error(&line))
+    }
+
+    async fn read_line(&mut self) -> Result<String, BoxError> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err("the Memcached server closed the connection".into());
+        }
+        Ok(line.trim_end_matches("\r\n").to_string())
+    }
+}
+
+/// A key accepted by Memcached: the keys too long or containing whitespace or control characters
+/// are replaced with their hash.
+fn memcached_key(key: &str) -> String {
+    if key.len() <= MAX_KEY_LENGTH && !key.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        key.to_string()
+    } else {
+        format!(
+            "apollo_router:sha256:{}",
+            hex::encode(Sha256::digest(key.as_bytes()))
+        )
+    }
+}
+
+/// The expiration time of a value, relative, or as a UNIX timestamp when it is too far away.
+fn expiration(ttl: Option<Duration>) -> u64 {
+    match ttl {
+        None => 0,
+        Some(ttl) if ttl <= MAX_RELATIVE_EXPIRATION => ttl.as_secs().max(1),
+        Some(ttl) => (SystemTime::now() + ttl)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    }
+}
+
+fn reply_error(line: &str) -> BoxError {
+    format!("unexpected reply from the Memcached server: '{}'", line).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_hashes_the_keys_memcached_does_not_accept() {
+        assert_eq!(
+            memcached_key("apollo_router:apq:ecf4"),
+            "apollo_router:apq:ecf4"
+        );
+        let key = memcached_key("apollo_router:quota:{web app}:operations:2022-10");
+        assert!(key.starts_with("apollo_router:sha256:"));
+        assert_eq!(key.len(), "apollo_router:sha256:".len() + 64);
+        assert!(memcached_key(&"a".repeat(251)).starts_with("apollo_router:sha256:"));
+    }
+
+    #[test]
+    fn it_sends_the_long_expirations_as_timestamps() {
+        assert_eq!(expiration(None), 0);
+        assert_eq!(expiration(Some(Duration::from_millis(10))), 1);
+        assert_eq!(expiration(Some(Duration::from_secs(3600))), 3600);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!(expiration(Some(MAX_RELATIVE_EXPIRATION * 2)) > now);
+    }
+
+    #[tokio::test]
+    async fn it_stores_values_and_counters_in_memcached() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::task::spawn(fake_memcached(listener));
+
+        let storage = MemcachedStorage::new(&format!("memcached://{}", addr), &Storage::default())
+            .await
+            .unwrap();
+        storage
+            .insert("value".to_string(), b"query".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            storage
+                .get(&["value".to_string(), "missing".to_string()])
+                .await
+                .unwrap(),
+            vec![Some(b"query".to_vec()), None]
+        );
+
+        let increment = Increment {
+            key: "counter".to_string(),
+            value: 2,
+            ttl: Duration::from_secs(60),
+            limit: Some(3),
+        };
+        let counters = storage.increment(&[increment.clone()]).await.unwrap();
+        assert_eq!((counters.values, counters.incremented), (vec![2], true));
+        let counters = storage.increment(&[increment.clone()]).await.unwrap();
+        assert_eq!((counters.values, counters.incremented), (vec![4], true));
+        let counters = storage.increment(&[increment]).await.unwrap();
+        assert_eq!((counters.values, counters.incremented), (vec![4], false));
+    }
+
+    /// A Memcached server answering the commands of the storage.
+    async fn fake_memcached(listener: tokio::net::TcpListener) {
+        let values = std::sync::Arc::new(std::sync::Mutex::new(std::collections::HashMap::<
+            String,
+            Vec<u8>,
+        >::new()));
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::task::spawn(fake_memcached_connection(stream, values.clone()));
+        }
+    }
+
+    async fn fake_memcached_connection(
+        stream: TcpStream,
+        values: std::sync::Arc<std::sync::Mutex<std::collections::HashMap<String, Vec<u8>>>>,
+    ) {
+        let mut stream = BufStream::new(stream);
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                return;
+            }
+            let parts: Vec<&str> = line.trim_end().split(' ').collect();
+            let mut value = Vec::new();
+            if let ["set" | "add", _, _, _, length] = parts.as_slice() {
+                value = vec![0; length.parse::<usize>().unwrap() + 2];
+                stream.read_exact(&mut value).await.unwrap();
+                value.truncate(value.len() - 2);
+            }
+            let reply = {
+                let mut values = values.lock().unwrap();
+                match parts.as_slice() {
+                    ["get", keys @ ..] => {
+                        let mut reply = Vec::new();
+                        for key in keys {
+                            if let Some(value) = values.get(*key) {
+                                reply
+                                    .extend(format!("VALUE {} 0 {}\r\n", key, value.len()).bytes());
+                                reply.extend(value);
+                                reply.extend(b"\r\n");
+                            }
+                        }
+                        reply.extend(b"END\r\n");
+                        reply
+                    }
+                    [command @ ("set" | "add"), key, _flags, _expiration, _length] => {
+                        if *command == "set" || !values.contains_key(*key) {
+                            values.insert(key.to_string(), value);
+                        }
+                        b"STORED\r\n".to_vec()
+                    }
+                    ["incr", key, value] => {
+                        let counter = values.get_mut(*key).unwrap();
+                        let incremented = String::from_utf8_lossy(counter).parse::<u64>().unwrap()
+                            + value.parse::<u64>().unwrap();
+                        *counter = incremented.to_string().into_bytes();
+                        format!("{}\r\n", incremented).into_bytes()
+                    }
+                    _ => b"ERROR\r\n".to_vec(),
+                }
+            };
+            stream.write_all(&reply).await.unwrap();
+            stream.flush().await.unwrap();
+        }
+    }
+}
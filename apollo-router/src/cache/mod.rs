@@ -2,17 +2,40 @@ use std::collections::HashMap;
 use std::hash::Hash;
 use std::sync::Arc;
 
+use serde::Serialize;
 use tokio::sync::broadcast;
 use tokio::sync::oneshot;
 use tokio::sync::Mutex;
 
 use self::storage::CacheStorage;
 
+pub(crate) mod memcached;
+pub(crate) mod partitioned;
+pub(crate) mod ring;
 pub(crate) mod storage;
 
 type WaitMap<K, V> = Arc<Mutex<HashMap<K, broadcast::Sender<V>>>>;
 pub(crate) const DEFAULT_CACHE_CAPACITY: usize = 512;
 
+/// Point in time statistics of a cache
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct CacheStats {
+    /// maximum number of entries
+    pub(crate) capacity: usize,
+    /// current number of entries
+    pub(crate) size: usize,
+    /// number of lookups that found a value
+    pub(crate) hits: u64,
+    /// number of lookups that did not find a value
+    pub(crate) misses: u64,
+}
+
+/// Type erased access to the statistics of a cache, whatever its key and value types
+#[async_trait::async_trait]
+pub(crate) trait CacheStatistics: Send + Sync {
+    async fn stats(&self) -> CacheStats;
+}
+
 /// Cache implementation with query deduplication
 #[derive(Clone)]
 pub(crate) struct DeduplicatingCache<K: Clone + Send + Eq + Hash, V: Clone> {
@@ -100,12 +123,27 @@ where
         self.storage.insert(key, value.clone()).await;
     }
 
+    pub(crate) async fn contains_key(&self, key: &K) -> bool {
+        self.storage.contains_key(key).await
+    }
+
     pub(crate) async fn remove_wait(&self, key: &K) {
         let mut locked_wait_map = self.wait_map.lock().await;
         let _ = locked_wait_map.remove(key);
     }
 }
 
+#[async_trait::async_trait]
+impl<K, V> CacheStatistics for DeduplicatingCache<K, V>
+where
+    K: Clone + Send + Sync + Eq + Hash + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    async fn stats(&self) -> CacheStats {
+        self.storage.stats().await
+    }
+}
+
 pub(crate) struct Entry<K: Clone + Send + Eq + Hash, V: Clone + Send> {
     inner: EntryInner<K, V>,
 }
@@ -182,6 +220,8 @@ mod tests {
     use mockall::mock;
     use test_log::test;
 
+    use super::CacheStatistics;
+    use super::CacheStats;
     use super::DeduplicatingCache;
 
     #[tokio::test]
@@ -213,6 +253,25 @@ mod tests {
         assert_eq!(cache.storage.len().await, 13);
     }
 
+    #[test(tokio::test)]
+    async fn it_should_count_hits_and_misses() {
+        let cache: DeduplicatingCache<usize, usize> = DeduplicatingCache::with_capacity(2).await;
+
+        cache.get(&1).await.insert(1).await;
+        cache.get(&1).await.get().await.unwrap();
+        cache.get(&2).await.insert(2).await;
+
+        assert_eq!(
+            cache.stats().await,
+            CacheStats {
+                capacity: 2,
+                size: 2,
+                hits: 1,
+                misses: 2,
+            }
+        );
+    }
+
     mock! {
         ResolveValue {
             async fn retrieve(&self, key: usize) -> usize;
@@ -0,0 +1,213 @@
+//! Caches partitioned by tenant.
+//!
+//! In multi-tenant deployments, the requests of a tenant only read and evict the entries of its
+//! own partition, so that a tenant can not pollute the caches of the others. The capacity of the
+//! cache is split between the partitions of the tenants and the shared one, and the partitions of
+//! the least recently seen tenants are dropped past the maximum number of tenants.
+
+use std::hash::Hash;
+use std::num::NonZeroUsize;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use dashmap::DashMap;
+
+use super::CacheStatistics;
+use super::CacheStats;
+use super::DeduplicatingCache;
+
+/// Key of the tenant of a request in its context.
+pub(crate) const TENANT_CONTEXT_KEY: &str = "apollo_router::tenant";
+
+#[derive(Clone)]
+pub(crate) struct PartitionedCache<K: Clone + Send + Eq + Hash, V: Clone> {
+    /// The partition of the requests without tenant.
+    shared: DeduplicatingCache<K, V>,
+    tenants: Option<Arc<Tenants<K, V>>>,
+}
+
+struct Tenants<K: Clone + Send + Eq + Hash, V: Clone> {
+    partitions: DashMap<String, Partition<K, V>>,
+    max_tenants: usize,
+    /// Capacity of each partition.
+    capacity: usize,
+    /// Counts the lookups, ordering the partitions by when their tenant was last seen.
+    clock: AtomicU64,
+}
+
+struct Partition<K: Clone + Send + Eq + Hash, V: Clone> {
+    cache: DeduplicatingCache<K, V>,
+    last_seen: AtomicU64,
+}
+
+impl<K, V> From<DeduplicatingCache<K, V>> for PartitionedCache<K, V>
+where
+    K: Clone + Send + Eq + Hash,
+    V: Clone,
+{
+    fn from(shared: DeduplicatingCache<K, V>) -> Self {
+        Self {
+            shared,
+            tenants: None,
+        }
+    }
+}
+
+impl<K, V> PartitionedCache<K, V>
+where
+    K: Clone + Send + Sync + Eq + Hash + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    /// Give each tenant its own partition, the capacity of the cache being split evenly between
+    /// the partitions of the tenants and the shared one.
+    pub(crate) async fn with_tenants(mut self, max_tenants: NonZeroUsize) -> Self {
+        let capacity = (self.shared.stats().await.capacity / (max_tenants.get() + 1)).max(1);
+        self.shared = DeduplicatingCache::with_capacity(capacity).await;
+        self.tenants = Some(Arc::new(Tenants {
+            partitions: DashMap::new(),
+            max_tenants: max_tenants.get(),
+            capacity,
+            clock: AtomicU64::new(0),
+        }));
+        self
+    }
+
+    /// The partition of a tenant, the shared one for the requests without tenant or when the
+    /// caches are not partitioned.
+    pub(crate) async fn partition(&self, tenant: Option<&str>) -> DeduplicatingCache<K, V> {
+        let (tenants, tenant) = match (&self.tenants, tenant) {
+            (Some(tenants), Some(tenant)) => (tenants, tenant),
+            _ => return self.shared.clone(),
+        };
+        let now = tenants.clock.fetch_add(1, Ordering::Relaxed);
+        if let Some(partition) = tenants.partitions.get(tenant) {
+            partition.last_seen.store(now, Ordering::Relaxed);
+            return partition.cache.clone();
+        }
+
+        let cache = DeduplicatingCache::with_capacity(tenants.capacity).await;
+        tenants.evict();
+        tenants
+            .partitions
+            .entry(tenant.to_string())
+            .or_insert_with(|| Partition {
+                cache,
+                last_seen: AtomicU64::new(now),
+            })
+            .cache
+            .clone()
+    }
+
+    /// Whether any partition has a value for the key.
+    pub(crate) async fn contains_key(&self, key: &K) -> bool {
+        if self.shared.contains_key(key).await {
+            return true;
+        }
+        for partition in self.partitions() {
+            if partition.contains_key(key).await {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// The partitions of the tenants.
+    fn partitions(&self) -> Vec<DeduplicatingCache<K, V>> {
+        match &self.tenants {
+            Some(tenants) => tenants
+                .partitions
+                .iter()
+                .map(|partition| partition.cache.clone())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl<K, V> Tenants<K, V>
+where
+    K: Clone + Send + Eq + Hash,
+    V: Clone,
+{
+    /// Drop the partitions of the least recently seen tenants, to make room for a new one.
+    fn evict(&self) {
+        while self.partitions.len() >= self.max_tenants {
+            let oldest = self
+                .partitions
+                .iter()
+                .min_by_key(|partition| partition.last_seen.load(Ordering::Relaxed))
+                .map(|partition| partition.key().clone());
+            match oldest {
+                Some(tenant) => {
+                    self.partitions.remove(&tenant);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// The statistics of all the partitions.
+#[async_trait::async_trait]
+impl<K, V> CacheStatistics for PartitionedCache<K, V>
+where
+    K: Clone + Send + Sync + Eq + Hash + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    async fn stats(&self) -> CacheStats {
+        let mut stats = self.shared.stats().await;
+        for partition in self.partitions() {
+            let partition = partition.stats().await;
+            stats.capacity += partition.capacity;
+            stats.size += partition.size;
+            stats.hits += partition.hits;
+            stats.misses += partition.misses;
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_partitions_the_entries_by_tenant() {
+        let cache: PartitionedCache<String, String> =
+            PartitionedCache::from(DeduplicatingCache::with_capacity(4).await)
+                .with_tenants(NonZeroUsize::new(1).unwrap())
+                .await;
+
+        cache
+            .partition(Some("a"))
+            .await
+            .insert("key".to_string(), "a".to_string())
+            .await;
+        assert!(cache.contains_key(&"key".to_string()).await);
+        assert!(
+            !cache
+                .partition(None)
+                .await
+                .contains_key(&"key".to_string())
+                .await
+        );
+        assert_eq!(
+            cache
+                .partition(Some("a"))
+                .await
+                .get(&"key".to_string())
+                .await
+                .get()
+                .await
+                .ok(),
+            Some("a".to_string())
+        );
+
+        // the partition of the least recently seen tenant is dropped
+        cache.partition(Some("b")).await;
+        assert!(!cache.contains_key(&"key".to_string()).await);
+        // the capacity is split between the partition of the tenant and the shared one
+        assert_eq!(cache.stats().await.capacity, 4);
+    }
+}
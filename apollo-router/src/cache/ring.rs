@@ -0,0 +1,113 @@
+//! Consistent hashing of the storage keys over shards.
+//!
+//! Every shard is placed at many points of a ring of 64 bits hashes, and a key belongs to the
+//! shard of the first point following its hash. Adding or removing a shard only moves the keys
+//! of its own points, and the keys are balanced evenly over the shards. Like in a Redis Cluster,
+//! only the hash tag of a key is hashed when it has one, e.g. `client` in `quota:{client}:daily`,
+//! so that the keys updated together are on the same shard.
+
+use sha2::Digest;
+use sha2::Sha256;
+
+/// Points of every shard on the ring.
+const VIRTUAL_NODES: usize = 160;
+
+#[derive(Clone, Debug)]
+pub(crate) struct HashRing {
+    /// Hashes of the points with their shard, sorted by hash.
+    points: Vec<(u64, usize)>,
+}
+
+impl HashRing {
+    /// The ring of these shards, which must not be empty. The shards are identified by their name,
+    /// so that all the routers using the same shards agree on the shard of every key.
+    pub(crate) fn new<S: AsRef<str>>(shards: &[S]) -> Self {
+        assert!(!shards.is_empty(), "a hash ring needs at least one shard");
+        let mut points = Vec::with_capacity(shards.len() * VIRTUAL_NODES);
+        for (shard, name) in shards.iter().enumerate() {
+            for node in 0..VIRTUAL_NODES {
+                points.push((hash(&format!("{}#{}", name.as_ref(), node)), shard));
+            }
+        }
+        points.sort_unstable();
+        Self { points }
+    }
+
+    /// The index of the shard of a key.
+    pub(crate) fn shard(&self, key: &str) -> usize {
+        let hash = hash(hash_tag(key));
+        let position = self.points.partition_point(|(point, _)| *point < hash);
+        self.points[position % self.points.len()].1
+    }
+}
+
+/// The part of a key that is hashed: its hash tag, between the first `{` and the next `}`, when
+/// it is not empty, or else the whole key.
+fn hash_tag(key: &str) -> &str {
+    key.find('{')
+        .and_then(|start| {
+            let tag = &key[start + 1..];
+            tag.find('}').map(|end| &tag[..end])
+        })
+        .filter(|tag| !tag.is_empty())
+        .unwrap_or(key)
+}
+
+fn hash(value: &str) -> u64 {
+    let digest = Sha256::digest(value.as_bytes());
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> impl Iterator<Item = String> {
+        (0..10_000).map(|key| format!("apollo_router:apq:{}", key))
+    }
+
+    #[test]
+    fn it_balances_the_keys_evenly() {
+        let ring = HashRing::new(&["redis-1", "redis-2", "redis-3"]);
+        let mut counts = [0; 3];
+        for key in keys() {
+            counts[ring.shard(&key)] += 1;
+        }
+        for count in counts {
+            assert!((2_700..4_000).contains(&count), "unbalanced: {:?}", counts);
+        }
+    }
+
+    #[test]
+    fn it_only_moves_the_keys_of_a_new_shard() {
+        let ring = HashRing::new(&["redis-1", "redis-2", "redis-3"]);
+        let grown = HashRing::new(&["redis-1", "redis-2", "redis-3", "redis-4"]);
+        let mut moved = 0;
+        for key in keys() {
+            let (before, after) = (ring.shard(&key), grown.shard(&key));
+            if before != after {
+                assert_eq!(after, 3);
+                moved += 1;
+            }
+        }
+        assert!((2_000..3_000).contains(&moved), "moved: {}", moved);
+    }
+
+    #[test]
+    fn it_hashes_the_hash_tags() {
+        assert_eq!(hash_tag("apollo_router:quota:{acme}:daily"), "acme");
+        assert_eq!(
+            hash_tag("apollo_router:quota:{}:daily"),
+            "apollo_router:quota:{}:daily"
+        );
+        assert_eq!(hash_tag("apollo_router:apq:ecf4"), "apollo_router:apq:ecf4");
+
+        let ring = HashRing::new(&["redis-1", "redis-2", "redis-3"]);
+        assert_eq!(
+            ring.shard("apollo_router:quota:{acme}:requests:daily"),
+            ring.shard("apollo_router:quota:{acme}:cost:monthly")
+        );
+    }
+}
@@ -0,0 +1,319 @@
+//! Canary evaluation of the new configurations and schemas.
+//!
+//! When the router reloads, the canary operations of the configuration are sent through the new
+//! pipeline before it receives any traffic. The reload is only applied if they all succeed:
+//! otherwise the previous pipeline keeps serving the requests, and a `canary_failed` webhook event
+//! is sent.
+//!
+//! The canary requests go through a pipeline without the quotas and the plugins in
+//! [`BYPASSED_PLUGINS`], so that they are not accounted, reported or mirrored like the requests of
+//! the clients.
+//!
+//! The subgraphs can answer the canary operations with mocked responses, carried by the canary
+//! requests in their HTTP extensions so that client requests can never use them.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use displaydoc::Display;
+use futures::StreamExt;
+use http::header::HeaderName;
+use http::HeaderValue;
+use serde_json_bytes::json;
+use thiserror::Error;
+use tower::ServiceExt;
+
+use crate::configuration::Canary;
+use crate::configuration::CanaryOperation;
+use crate::configuration::SubgraphMock;
+use crate::graphql;
+use crate::json_ext::Object;
+use crate::json_ext::Value;
+use crate::router_factory::SupergraphServiceFactory;
+
+/// The plugins the canary requests do not go through.
+const BYPASSED_PLUGINS: &[&str] = &["apollo.telemetry", "apollo.mirroring"];
+
+/// Whether the canary requests bypass a plugin.
+pub(crate) fn bypasses(plugin: &str) -> bool {
+    BYPASSED_PLUGINS.contains(&plugin)
+}
+
+/// Mocked responses of the subgraphs to a canary request, by subgraph name.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SubgraphMocks(Arc<HashMap<String, Mock>>);
+
+/// Mocked responses of a subgraph.
+#[derive(Debug)]
+struct Mock {
+    response: Option<graphql::Response>,
+    entities: HashMap<String, Object>,
+}
+
+impl SubgraphMocks {
+    fn new(mocks: HashMap<String, Mock>) -> Self {
+        Self(Arc::new(mocks))
+    }
+
+    pub(crate) fn parse(mocks: &HashMap<String, SubgraphMock>) -> Result<Self, CanaryError> {
+        let mut parsed = HashMap::new();
+        for (subgraph, mock) in mocks {
+            let invalid = |e: serde_json::Error| CanaryError::InvalidMock {
+                subgraph: subgraph.clone(),
+                reason: e.to_string(),
+            };
+            let response = match &mock.response {
+                Some(response) => Some(serde_json::from_value(response.clone()).map_err(invalid)?),
+                None => None,
+            };
+            let mut entities = HashMap::new();
+            for (type_name, fields) in &mock.entities {
+                let fields = serde_json::from_value(serde_json::Value::Object(fields.clone()))
+                    .map_err(invalid)?;
+                entities.insert(type_name.clone(), fields);
+            }
+            parsed.insert(subgraph.clone(), Mock { response, entities });
+        }
+        Ok(Self::new(parsed))
+    }
+
+    /// The mocked response of a subgraph to a fetch, if it is mocked: the entity fetches are
+    /// answered with an entity per representation, and the root fetches with the mocked
+    /// response.
+    pub(crate) fn respond(
+        &self,
+        subgraph: &str,
+        request: &graphql::Request,
+    ) -> Option<graphql::Response> {
+        let mock = self.0.get(subgraph)?;
+        let response = match request.variables.get("representations") {
+            Some(Value::Array(representations)) => mock.entities(representations),
+            _ => mock
+                .response
+                .clone()
+                .ok_or_else(|| "no mocked response to the root fetches".to_string()),
+        };
+        Some(response.unwrap_or_else(|reason| {
+            graphql::Response::builder()
+                .error(
+                    graphql::Error::builder()
+                        .message(format!("mocked subgraph '{}': {}", subgraph, reason))
+                        .build(),
+                )
+                .build()
+        }))
+    }
+}
+
+impl Mock {
+    fn entities(&self, representations: &[Value]) -> Result<graphql::Response, String> {
+        let entities = representations
+            .iter()
+            .map(|representation| -> Result<Value, String> {
+                let mut entity = representation
+                    .as_object()
+                    .cloned()
+                    .ok_or("entity representations must be objects")?;
+                let type_name = entity
+                    .get("__typename")
+                    .and_then(|type_name| type_name.as_str())
+                    .ok_or("entity representations must have a __typename")?;
+                let fields = self
+                    .entities
+                    .get(type_name)
+                    .ok_or_else(|| format!("no mocked '{}' entity", type_name))?;
+                entity.extend(fields.clone());
+                Ok(Value::Object(entity))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(graphql::Response::builder()
+            .data(json!({ "_entities": entities }))
+            .build())
+    }
+}
+
+/// Error of the canary evaluation of a new pipeline.
+#[derive(Debug, Display, Error)]
+pub(crate) enum CanaryError {
+    /// invalid mocked response of subgraph '{subgraph}': {reason}
+    InvalidMock {
+        /// The subgraph of the mocked response.
+        subgraph: String,
+        /// Why it is invalid.
+        reason: String,
+    },
+
+    /// canary operation '{operation}' failed: {reason}
+    Failed {
+        /// The name of the failed operation.
+        operation: String,
+        /// Why it failed.
+        reason: String,
+    },
+}
+
+impl CanaryError {
+    /// The name of the failed operation, if an operation failed.
+    pub(crate) fn operation(&self) -> Option<&str> {
+        match self {
+            CanaryError::InvalidMock { .. } => None,
+            CanaryError::Failed { operation, .. } => Some(operation),
+        }
+    }
+}
+
+/// Send the canary operations through a new pipeline, failing on the first one that fails.
+pub(crate) async fn evaluate<F: SupergraphServiceFactory>(
+    canary: &Canary,
+    factory: &F,
+) -> Result<(), CanaryError> {
+    let mocks = SubgraphMocks::parse(&canary.subgraph_mocks)?;
+
+    for operation in &canary.operations {
+        let failed = |reason: String| CanaryError::Failed {
+            operation: operation.name.clone(),
+            reason,
+        };
+        let request = request(operation, mocks.clone()).map_err(failed)?;
+        let response = tokio::time::timeout(canary.timeout, async {
+            let (parts, mut body) = factory
+                .canary_service()
+                .oneshot(request)
+                .await
+                .map_err(|e| e.to_string())?
+                .into_parts();
+            Ok::<_, String>((parts.status, body.next().await))
+        })
+        .await
+        .map_err(|_| failed(format!("no response after {:?}", canary.timeout)))?
+        .map_err(failed)?;
+
+        match response {
+            (status, _) if !status.is_success() => {
+                return Err(failed(format!("status code {}", status.as_u16())))
+            }
+            (_, None) => return Err(failed("empty response".to_string())),
+            (_, Some(response)) if !response.errors.is_empty() => {
+                return Err(failed(
+                    response
+                        .errors
+                        .iter()
+                        .map(|error| error.message.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ))
+            }
+            _ => tracing::debug!("canary operation '{}' succeeded", operation.name),
+        }
+    }
+    Ok(())
+}
+
+fn request(
+    operation: &CanaryOperation,
+    mocks: SubgraphMocks,
+) -> Result<http::Request<graphql::Request>, String> {
+    let mut request = http::Request::builder()
+        .method(http::Method::POST)
+        .body(
+            graphql::Request::builder()
+                .query(operation.query.clone())
+                .and_operation_name(operation.operation_name.clone())
+                .variables(
+                    operation
+                        .variables
+                        .iter()
+                        .map(|(name, value)| (name.as_str().into(), value.clone().into()))
+                        .collect(),
+                )
+                .build(),
+        )
+        .map_err(|e| e.to_string())?;
+    for (name, value) in &operation.headers {
+        request.headers_mut().insert(
+            HeaderName::try_from(name.as_str()).map_err(|e| e.to_string())?,
+            HeaderValue::try_from(value.as_str()).map_err(|e| e.to_string())?,
+        );
+    }
+    request.extensions_mut().insert(mocks);
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mocks() -> SubgraphMocks {
+        let mocks: HashMap<String, SubgraphMock> = serde_json::from_value(serde_json::json!({
+            "accounts": {
+                "response": { "data": { "me": { "__typename": "User", "id": "1" } } },
+                "entities": { "User": { "name": "Ada" } },
+            },
+        }))
+        .unwrap();
+        SubgraphMocks::parse(&mocks).unwrap()
+    }
+
+    #[test]
+    fn it_builds_the_canary_requests() {
+        let operation: CanaryOperation = serde_json::from_value(serde_json::json!({
+            "name": "me",
+            "query": "query Me($id: ID!) { user(id: $id) { name } }",
+            "variables": { "id": "1" },
+            "headers": { "x-canary": "true" },
+        }))
+        .unwrap();
+
+        let request = request(&operation, mocks()).unwrap();
+        assert_eq!(request.headers()["x-canary"], "true");
+        assert_eq!(request.body().variables.get("id"), Some(&json!("1")));
+        assert!(request.extensions().get::<SubgraphMocks>().is_some());
+    }
+
+    #[test]
+    fn it_answers_each_fetch_with_the_mocks() {
+        let mocks = mocks();
+        let root = graphql::Request::builder()
+            .query("{ me { __typename id } }")
+            .build();
+        assert!(mocks.respond("products", &root).is_none());
+        assert_eq!(
+            mocks.respond("accounts", &root).unwrap().data,
+            Some(json!({ "me": { "__typename": "User", "id": "1" } }))
+        );
+
+        let entities = |representations| {
+            graphql::Request::builder()
+                .query("query($representations: [_Any!]!) { _entities(representations: $representations) { ... on User { name } } }")
+                .variable("representations", representations)
+                .build()
+        };
+        let response = mocks
+            .respond(
+                "accounts",
+                &entities(json!([
+                    { "__typename": "User", "id": "1" },
+                    { "__typename": "User", "id": "2" },
+                ])),
+            )
+            .unwrap();
+        assert_eq!(
+            response.data,
+            Some(json!({ "_entities": [
+                { "__typename": "User", "id": "1", "name": "Ada" },
+                { "__typename": "User", "id": "2", "name": "Ada" },
+            ] }))
+        );
+
+        let response = mocks
+            .respond(
+                "accounts",
+                &entities(json!([{ "__typename": "Product", "upc": "1" }])),
+            )
+            .unwrap();
+        assert_eq!(
+            response.errors[0].message,
+            "mocked subgraph 'accounts': no mocked 'Product' entity"
+        );
+    }
+}
@@ -0,0 +1,202 @@
+//! Startup self-test of the router, e.g. to gate a rollout in CI.
+//!
+//! The configuration and the supergraph are loaded and every plugin is initialized as on startup,
+//! then the subgraphs can be pinged and sample operations planned, without running a server.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use displaydoc::Display;
+use serde::Serialize;
+use thiserror::Error;
+use tower::BoxError;
+
+use crate::configuration::Configuration;
+use crate::error::SchemaError;
+use crate::plan_diff;
+use crate::plan_diff::BrokenOperation;
+use crate::plan_diff::Operation;
+use crate::plan_diff::PlanDiffError;
+use crate::plugins::override_url::OverrideSubgraphUrl;
+use crate::plugins::traffic_shaping::probe;
+use crate::plugins::traffic_shaping::Probe;
+use crate::router_factory::SupergraphServiceConfigurator;
+use crate::router_factory::YamlSupergraphServiceFactory;
+use crate::services::subgraph_service::SubgraphService;
+use crate::Schema;
+
+/// How long a subgraph has to answer its ping.
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Errors preventing the router from starting.
+#[derive(Error, Debug, Display)]
+pub(crate) enum CheckError {
+    /// invalid supergraph: {0}
+    InvalidSchema(SchemaError),
+    /// could not initialize the plugins: {0}
+    Plugins(BoxError),
+    /// could not plan the operations: {0}
+    Planning(PlanDiffError),
+}
+
+/// Outcome of the self-test.
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct CheckReport {
+    /// The pinged subgraphs.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) subgraphs: Vec<SubgraphPing>,
+    /// Number of sample operations planned.
+    pub(crate) planned: usize,
+    /// Sample operations that can not be planned.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) failed: Vec<BrokenOperation>,
+}
+
+impl CheckReport {
+    /// Number of unreachable subgraphs and failed operations.
+    pub(crate) fn failures(&self) -> usize {
+        self.subgraphs
+            .iter()
+            .filter(|subgraph| subgraph.error.is_some())
+            .count()
+            + self.failed.len()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct SubgraphPing {
+    pub(crate) name: String,
+    pub(crate) url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+/// Initialize the router as on startup, then ping the subgraphs if asked to, and plan the sample
+/// operations.
+pub(crate) async fn check(
+    configuration: Configuration,
+    sdl: &str,
+    operations: Vec<Operation>,
+    ping_subgraphs: bool,
+) -> Result<CheckReport, CheckError> {
+    let schema = Arc::new(Schema::parse(sdl, &configuration).map_err(CheckError::InvalidSchema)?);
+    let configuration = Arc::new(configuration);
+    YamlSupergraphServiceFactory::default()
+        .create(configuration.clone(), schema.clone(), None, None)
+        .await
+        .map_err(CheckError::Plugins)?;
+
+    let mut report = CheckReport::default();
+    if ping_subgraphs {
+        report.subgraphs = ping(&configuration, &schema).await;
+    }
+    if !operations.is_empty() {
+        let planned = plan_diff::plan_operations(configuration.as_ref().clone(), sdl, operations)
+            .await
+            .map_err(CheckError::Planning)?;
+        report.planned = planned.len();
+        report.failed = planned
+            .into_iter()
+            .filter(|operation| !operation.errors.is_empty())
+            .map(|operation| BrokenOperation {
+                operation: operation.operation,
+                errors: operation.errors,
+            })
+            .collect();
+    }
+    Ok(report)
+}
+
+/// Send a `{ __typename }` query to every subgraph, at the URL overridden by the configuration if
+/// any.
+async fn ping(configuration: &Configuration, schema: &Schema) -> Vec<SubgraphPing> {
+    futures::future::join_all(schema.subgraphs().map(|(name, url)| {
+        let url = OverrideSubgraphUrl::get_configuration_url(configuration, name)
+            .map(|url| url.to_string())
+            .unwrap_or_else(|| url.to_string());
+        async move {
+            let service = SubgraphService::new(name.as_str());
+            let error = match url::Url::parse(&url) {
+                Ok(parsed) => match tokio::time::timeout(
+                    PING_TIMEOUT,
+                    probe(&service, Probe::Query, name, &parsed),
+                )
+                .await
+                {
+                    Ok(result) => result.err().map(|e| e.to_string()),
+                    Err(e) => Some(e.to_string()),
+                },
+                Err(e) => Some(e.to_string()),
+            };
+            SubgraphPing {
+                name: name.clone(),
+                url,
+                error,
+            }
+        }
+    }))
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA: &str = include_str!("query_planner/testdata/schema.graphql");
+
+    #[tokio::test]
+    async fn it_reports_operations_that_can_not_be_planned() {
+        let report = check(
+            Configuration::default(),
+            SCHEMA,
+            vec![
+                Operation {
+                    id: "me.graphql".to_string(),
+                    query: "{ me { name { first } } }".to_string(),
+                    operation_name: None,
+                },
+                Operation {
+                    id: "invalid.graphql".to_string(),
+                    query: "{ unknown }".to_string(),
+                    operation_name: None,
+                },
+            ],
+            false,
+        )
+        .await
+        .unwrap();
+
+        assert!(report.subgraphs.is_empty());
+        assert_eq!(report.planned, 2);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].operation, "invalid.graphql");
+        assert_eq!(report.failures(), 1);
+    }
+
+    #[tokio::test]
+    async fn it_pings_the_overridden_subgraph_urls() {
+        let configuration: Configuration = "override_subgraph_url:\n  books: http://127.0.0.1:1\n"
+            .parse()
+            .unwrap();
+        let report = check(configuration, SCHEMA, Vec::new(), true)
+            .await
+            .unwrap();
+
+        let books = report
+            .subgraphs
+            .iter()
+            .find(|subgraph| subgraph.name == "books")
+            .unwrap();
+        assert_eq!(books.url, "http://127.0.0.1:1/");
+        assert!(books.error.is_some());
+        assert!(report.failures() > 0);
+    }
+
+    #[tokio::test]
+    async fn it_fails_on_invalid_supergraphs() {
+        assert!(matches!(
+            check(Configuration::default(), "invalid", Vec::new(), false).await,
+            Err(CheckError::InvalidSchema(_))
+        ));
+    }
+}
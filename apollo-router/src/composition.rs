@@ -0,0 +1,279 @@
+//! Local composition of a supergraph from a list of subgraphs.
+//!
+//! The subgraphs are described in a `supergraph.yaml` file, in the format used by
+//! `rover supergraph compose`:
+//!
+//! ```yaml
+//! federation_version: 2
+//! subgraphs:
+//!   products:
+//!     routing_url: http://localhost:4001
+//!     schema:
+//!       file: ./products.graphql
+//!   reviews:
+//!     routing_url: http://localhost:4002
+//!     schema:
+//!       subgraph_url: http://localhost:4002
+//! ```
+//!
+//! Composition is delegated to `rover`, which must be installed.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use displaydoc::Display;
+use futures::prelude::*;
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::process::Command;
+use tokio_stream::wrappers::IntervalStream;
+use tower::BoxError;
+
+/// Environment variable used to set the path of the `rover` binary.
+const ROVER_PATH_ENV: &str = "APOLLO_ROVER_PATH";
+/// Maximum duration of the introspection of a subgraph, when polling for schema changes.
+const INTROSPECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Composition errors.
+#[derive(Error, Debug, Display)]
+pub(crate) enum CompositionError {
+    /// could not read the supergraph configuration at '{0}': {1}
+    ReadConfig(PathBuf, std::io::Error),
+    /// invalid supergraph configuration: {0}
+    InvalidConfig(serde_yaml::Error),
+    /// could not run rover, make sure it is installed (https://www.apollographql.com/docs/rover/getting-started): {0}
+    Rover(std::io::Error),
+    /// composition failed: {0}
+    Failed(String),
+}
+
+#[derive(Debug, Deserialize)]
+struct SupergraphConfig {
+    #[serde(default)]
+    subgraphs: BTreeMap<String, SubgraphConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphConfig {
+    schema: SubgraphSchema,
+}
+
+/// Only local files and introspected subgraphs need to be known by the router, other sources are
+/// handled by rover.
+#[derive(Debug, Deserialize)]
+struct SubgraphSchema {
+    file: Option<PathBuf>,
+    subgraph_url: Option<url::Url>,
+}
+
+impl SupergraphConfig {
+    fn read(path: &Path) -> Result<Self, CompositionError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| CompositionError::ReadConfig(path.to_path_buf(), e))?;
+        serde_yaml::from_str(&contents).map_err(CompositionError::InvalidConfig)
+    }
+
+    /// The subgraph schema files, relative paths being resolved from the configuration directory.
+    fn schema_files(&self, config_path: &Path) -> Vec<PathBuf> {
+        let directory = config_path.parent().unwrap_or_else(|| Path::new(""));
+        self.subgraphs
+            .values()
+            .filter_map(|subgraph| subgraph.schema.file.as_ref())
+            .map(|file| directory.join(file))
+            .collect()
+    }
+
+    fn subgraph_urls(&self) -> Vec<url::Url> {
+        self.subgraphs
+            .values()
+            .filter_map(|subgraph| subgraph.schema.subgraph_url.clone())
+            .collect()
+    }
+}
+
+/// Compose the supergraph described by the configuration at `path`.
+pub(crate) async fn compose(path: &Path) -> Result<String, CompositionError> {
+    // validate the configuration first, rover errors are not as helpful
+    SupergraphConfig::read(path)?;
+
+    let rover = std::env::var(ROVER_PATH_ENV).unwrap_or_else(|_| "rover".to_string());
+    let output = Command::new(rover)
+        .args([
+            "supergraph",
+            "compose",
+            "--elv2-license",
+            "accept",
+            "--config",
+        ])
+        .arg(path)
+        .output()
+        .await
+        .map_err(CompositionError::Rover)?;
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(CompositionError::Failed(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ))
+    }
+}
+
+/// Fetch the schema of a running subgraph.
+async fn introspect(
+    client: &reqwest::Client,
+    url: url::Url,
+    timeout: Duration,
+) -> Result<String, BoxError> {
+    let response: serde_json::Value = client
+        .post(url)
+        .json(&serde_json::json!({ "query": "query { _service { sdl } }" }))
+        .timeout(timeout)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    response
+        .pointer("/data/_service/sdl")
+        .and_then(|sdl| sdl.as_str())
+        .map(|sdl| sdl.to_string())
+        .ok_or_else(|| "the subgraph did not return its schema".into())
+}
+
+/// Creates a stream of events sent when the schema of an introspected subgraph changes.
+///
+/// Unreachable subgraphs are treated as having no schema, so the supergraph is composed again
+/// when they come back.
+fn subgraph_changes(path: PathBuf, interval: Duration) -> impl Stream<Item = ()> {
+    let client = reqwest::Client::new();
+    // a subgraph that does not answer before the next poll is unreachable
+    let timeout = interval.min(INTROSPECTION_TIMEOUT);
+    let mut previous: Option<Vec<Option<String>>> = None;
+    IntervalStream::new(tokio::time::interval(interval))
+        .then(move |_| {
+            let client = client.clone();
+            let path = path.clone();
+            async move {
+                // read every time, so that subgraphs added to the configuration are introspected
+                let urls = SupergraphConfig::read(&path)
+                    .map(|config| config.subgraph_urls())
+                    .unwrap_or_default();
+                let client = &client;
+                future::join_all(urls.into_iter().map(|url| async move {
+                    match introspect(client, url.clone(), timeout).await {
+                        Ok(sdl) => Some(sdl),
+                        Err(e) => {
+                            tracing::debug!("could not introspect the subgraph at {}: {}", url, e);
+                            None
+                        }
+                    }
+                }))
+                .await
+            }
+        })
+        .filter_map(move |schemas| {
+            let changed = matches!(&previous, Some(previous) if previous != &schemas);
+            previous = Some(schemas);
+            future::ready(changed.then(|| ()))
+        })
+}
+
+/// Creates a stream of supergraphs composed from the configuration at `path`.
+///
+/// When watching, the supergraph is composed again every time the configuration or one of the
+/// subgraph schema files changes. With a `poll_interval`, it is also composed again when the
+/// schema of an introspected subgraph changes. Compositions that fail are logged and skipped, so
+/// the router keeps the last valid supergraph.
+pub(crate) fn stream(
+    path: PathBuf,
+    watch: bool,
+    delay: Option<Duration>,
+    poll_interval: Option<Duration>,
+) -> impl Stream<Item = String> {
+    let changes = if watch {
+        let mut paths = vec![path.clone()];
+        match SupergraphConfig::read(&path) {
+            Ok(config) => paths.extend(config.schema_files(&path)),
+            Err(e) => tracing::error!("{}", e),
+        }
+        // every watcher sends an event when it starts, only one is needed to compose
+        let watchers = paths
+            .into_iter()
+            .map(|path| crate::files::watch(path, delay).skip(1).boxed())
+            .collect::<Vec<_>>();
+        stream::once(future::ready(()))
+            .chain(stream::select_all(watchers))
+            .boxed()
+    } else {
+        stream::once(future::ready(())).boxed()
+    };
+    let changes = match poll_interval {
+        Some(interval) => stream::select(changes, subgraph_changes(path.clone(), interval)).boxed(),
+        None => changes,
+    };
+
+    changes
+        .then(move |_| {
+            let path = path.clone();
+            async move { compose(&path).await }
+        })
+        .filter_map(|result| {
+            future::ready(match result {
+                Ok(supergraph) => Some(supergraph),
+                Err(e) => {
+                    tracing::error!("could not compose the supergraph: {}", e);
+                    None
+                }
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_resolves_schema_files() {
+        let config: SupergraphConfig = serde_yaml::from_str(
+            r#"
+federation_version: 2
+subgraphs:
+  products:
+    routing_url: http://localhost:4001
+    schema:
+      file: ./products.graphql
+  reviews:
+    routing_url: http://localhost:4002
+    schema:
+      subgraph_url: http://localhost:4002
+  users:
+    schema:
+      file: /schemas/users.graphql
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.subgraph_urls(),
+            vec![url::Url::parse("http://localhost:4002").unwrap()]
+        );
+        assert_eq!(
+            config.schema_files(Path::new("/dev/supergraph.yaml")),
+            vec![
+                PathBuf::from("/dev/./products.graphql"),
+                PathBuf::from("/schemas/users.graphql")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn it_reports_missing_configuration() {
+        let error = compose(Path::new("/does/not/exist.yaml"))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, CompositionError::ReadConfig(_, _)));
+    }
+}
@@ -0,0 +1,787 @@
+//! Secrets referenced by the configuration, resolved from secrets providers.
+//!
+//! Configuration values can reference secrets, replaced with their value when the configuration
+//! is loaded, so that they are never written in the configuration files or in the environment:
+//!
+//! * `${vault:<path>#<key>}`: a key of a HashiCorp Vault secret, read with the `VAULT_ADDR`,
+//!   `VAULT_TOKEN` and optionally `VAULT_NAMESPACE` environment variables,
+//! * `${awssm:<secret id>}` or `${awssm:<secret id>#<key>}`: an AWS Secrets Manager secret, or a key
+//!   of a JSON secret, read in `AWS_REGION` (unless the secret id is an ARN) with the credentials
+//!   of the environment variables, or of the role of the pod, of the ECS task or of the instance.
+//!
+//! The secrets are fetched before the configuration is validated, and cached by the configuration
+//! source until their lease is due for renewal: renewable Vault leases are extended, other secrets
+//! are fetched again. When a secret is fetched with a new value, the configuration is reloaded.
+//! Only the secrets referenced by the last loaded configuration are kept and renewed.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use futures::prelude::*;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio_stream::wrappers::IntervalStream;
+use tower::BoxError;
+
+use crate::plugins::authentication::sigv4::SigV4Config;
+
+/// Time a value is cached for, unless the secrets provider sets a lease duration.
+const DEFAULT_TTL: Duration = Duration::from_secs(300);
+/// Interval between the checks for the values due for renewal.
+const RENEWAL_INTERVAL: Duration = Duration::from_secs(30);
+/// Timeout of the requests to the secrets providers.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Timeout of the requests to the instance metadata service, which is only reachable on EC2.
+const IMDS_TIMEOUT: Duration = Duration::from_secs(2);
+/// Temporary AWS credentials are fetched again this long before they expire.
+const CREDENTIALS_EXPIRY_MARGIN: Duration = Duration::from_secs(300);
+/// Base URL of the EC2 instance metadata service.
+const IMDS_URL: &str = "http://169.254.169.254/latest";
+/// Base URL of the ECS credentials endpoint, for `AWS_CONTAINER_CREDENTIALS_RELATIVE_URI`.
+const ECS_CREDENTIALS_URL: &str = "http://169.254.170.2";
+
+static REFERENCE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\$\{(vault|awssm):([^}]+)\}").expect("valid regex"));
+
+/// The secrets of a configuration source, fetched when its configurations are loaded and renewed
+/// until the source is dropped.
+#[derive(Clone, Default)]
+pub(crate) struct Secrets {
+    cache: Arc<Mutex<HashMap<String, Cached>>>,
+    /// The AWS credentials of the role of the router, cached until they are about to expire.
+    aws_credentials: Arc<tokio::sync::Mutex<Option<AwsCredentials>>>,
+}
+
+/// A secret fetched from its provider.
+#[derive(Debug, PartialEq)]
+struct Secret {
+    value: String,
+    ttl: Duration,
+    /// The id of the Vault lease of a dynamic secret, when it is renewable.
+    lease_id: Option<String>,
+}
+
+struct Cached {
+    value: String,
+    /// The value is renewed at two thirds of its lease, before it expires.
+    renew_at: Instant,
+    lease_id: Option<String>,
+}
+
+impl Cached {
+    fn new(secret: Secret) -> Self {
+        Cached {
+            value: secret.value,
+            renew_at: Instant::now() + secret.ttl * 2 / 3,
+            lease_id: secret.lease_id,
+        }
+    }
+}
+
+/// Whether a configuration value references secrets.
+pub(crate) fn has_references(value: &str) -> bool {
+    REFERENCE.is_match(value)
+}
+
+impl Secrets {
+    /// Fetch the secrets referenced by the values of a configuration, unless they are cached. A
+    /// secret due for renewal keeps its last value if it cannot be fetched again. Once they are
+    /// all fetched, the secrets which are no longer referenced are dropped.
+    pub(crate) async fn fetch_references(&self, raw_yaml: &str) -> Result<(), (String, BoxError)> {
+        // an invalid configuration is reported by its validation
+        let configuration: Value = match serde_yaml::from_str(raw_yaml) {
+            Ok(configuration) => configuration,
+            Err(_) => return Ok(()),
+        };
+        let mut references = Vec::new();
+        collect_references(&configuration, &mut references);
+        let missing: Vec<String> = {
+            let cache = self.cache.lock().expect("lock poisoned");
+            let now = Instant::now();
+            references
+                .iter()
+                .filter(|reference| {
+                    cache
+                        .get(*reference)
+                        .map(|cached| cached.renew_at <= now)
+                        .unwrap_or(true)
+                })
+                .cloned()
+                .collect()
+        };
+
+        if !missing.is_empty() {
+            let client = client().map_err(|e| (missing[0].clone(), e))?;
+            let fetched = future::join_all(
+                missing
+                    .iter()
+                    .map(|reference| fetch(&client, &self.aws_credentials, reference)),
+            )
+            .await;
+            let mut cache = self.cache.lock().expect("lock poisoned");
+            for (reference, fetched) in missing.into_iter().zip(fetched) {
+                match fetched {
+                    Ok(secret) => {
+                        cache.insert(reference, Cached::new(secret));
+                    }
+                    Err(e) if cache.contains_key(&reference) => {
+                        tracing::warn!(
+                            "could not renew {}, using its last value: {}",
+                            reference,
+                            e
+                        );
+                    }
+                    Err(e) => return Err((reference, e)),
+                }
+            }
+        }
+
+        // the secrets of the previous configurations are not renewed anymore
+        self.cache
+            .lock()
+            .expect("lock poisoned")
+            .retain(|reference, _| references.contains(reference));
+        Ok(())
+    }
+
+    /// Replace the secret references of a configuration value with their fetched value, and the
+    /// rest of the value with `expand_env`. The values of the secrets are never expanded.
+    pub(crate) fn expand(
+        &self,
+        value: &str,
+        expand_env: impl Fn(&str) -> String,
+    ) -> Result<String, (String, BoxError)> {
+        let cache = self.cache.lock().expect("lock poisoned");
+        let mut expanded = String::new();
+        let mut last = 0;
+        for reference in REFERENCE.find_iter(value) {
+            let cached = cache.get(reference.as_str()).ok_or_else(|| {
+                (
+                    reference.as_str().to_string(),
+                    BoxError::from("the secret was not fetched"),
+                )
+            })?;
+            expanded.push_str(&expand_env(&value[last..reference.start()]));
+            expanded.push_str(&cached.value);
+            last = reference.end();
+        }
+        expanded.push_str(&expand_env(&value[last..]));
+        Ok(expanded)
+    }
+
+    /// Creates a stream of events sent when a cached secret is fetched again with a new value.
+    /// The stream never terminates and must be dropped to stop renewing the secrets.
+    pub(crate) fn changes(&self) -> impl Stream<Item = ()> {
+        let secrets = self.clone();
+        IntervalStream::new(tokio::time::interval(RENEWAL_INTERVAL)).filter_map(move |_| {
+            let secrets = secrets.clone();
+            async move { secrets.renew().await.then(|| ()) }
+        })
+    }
+
+    /// Renew the cached secrets due for renewal: the renewable Vault leases are extended, the
+    /// other secrets, and the leases which cannot be extended, are fetched again. Returns whether
+    /// a value changed.
+    async fn renew(&self) -> bool {
+        let due: Vec<(String, String, Option<String>)> = {
+            let cache = self.cache.lock().expect("lock poisoned");
+            let now = Instant::now();
+            cache
+                .iter()
+                .filter(|(_, cached)| cached.renew_at <= now)
+                .map(|(reference, cached)| {
+                    (
+                        reference.clone(),
+                        cached.value.clone(),
+                        cached.lease_id.clone(),
+                    )
+                })
+                .collect()
+        };
+        if due.is_empty() {
+            return false;
+        }
+        let client = match client() {
+            Ok(client) => client,
+            Err(e) => {
+                tracing::warn!("could not renew the secrets: {}", e);
+                return false;
+            }
+        };
+
+        let mut changed = false;
+        for (reference, previous, lease_id) in due {
+            if let Some(lease_id) = lease_id {
+                match renew_vault_lease(&client, &lease_id).await {
+                    Ok(ttl) => {
+                        if let Some(cached) = self
+                            .cache
+                            .lock()
+                            .expect("lock poisoned")
+                            .get_mut(&reference)
+                        {
+                            cached.renew_at = Instant::now() + ttl * 2 / 3;
+                        }
+                        continue;
+                    }
+                    Err(e) => tracing::debug!(
+                        "could not extend the lease of {}, fetching it again: {}",
+                        reference,
+                        e
+                    ),
+                }
+            }
+            match fetch(&client, &self.aws_credentials, &reference).await {
+                Ok(secret) => {
+                    let mut cache = self.cache.lock().expect("lock poisoned");
+                    // a secret dropped by a reload in the meantime is not cached again
+                    if let Some(cached) = cache.get_mut(&reference) {
+                        changed |= secret.value != previous;
+                        *cached = Cached::new(secret);
+                    }
+                }
+                Err(e) => tracing::warn!("could not renew {}: {}", reference, e),
+            }
+        }
+        if changed {
+            tracing::info!("secrets were renewed with new values, reloading the configuration");
+        }
+        changed
+    }
+}
+
+fn collect_references(value: &Value, references: &mut Vec<String>) {
+    match value {
+        Value::String(value) => {
+            for reference in REFERENCE.find_iter(value) {
+                if !references.iter().any(|known| known == reference.as_str()) {
+                    references.push(reference.as_str().to_string());
+                }
+            }
+        }
+        Value::Array(a) => a.iter().for_each(|v| collect_references(v, references)),
+        Value::Object(o) => o.values().for_each(|v| collect_references(v, references)),
+        _ => {}
+    }
+}
+
+fn client() -> Result<reqwest::Client, BoxError> {
+    Ok(reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()?)
+}
+
+/// The AWS credentials cached by a configuration source.
+type CachedAwsCredentials = tokio::sync::Mutex<Option<AwsCredentials>>;
+
+/// Fetch a secret from its provider.
+async fn fetch(
+    client: &reqwest::Client,
+    aws_credentials: &CachedAwsCredentials,
+    reference: &str,
+) -> Result<Secret, BoxError> {
+    let captures = REFERENCE
+        .captures(reference)
+        .ok_or("invalid secret reference")?;
+    match &captures[1] {
+        "vault" => fetch_from_vault(client, &captures[2]).await,
+        "awssm" => fetch_from_aws(client, aws_credentials, &captures[2]).await,
+        provider => Err(format!("unknown secrets provider '{}'", provider).into()),
+    }
+}
+
+fn env(name: &str) -> Result<String, BoxError> {
+    std::env::var(name).map_err(|_| format!("{} is not set", name).into())
+}
+
+/// The key of a secret id, written `<id>#<key>`.
+fn split_key(id: &str) -> (&str, Option<&str>) {
+    match id.rsplit_once('#') {
+        Some((id, key)) => (id, Some(key)),
+        None => (id, None),
+    }
+}
+
+/// A request to the Vault API, authenticated with `VAULT_TOKEN`.
+fn vault_request(
+    client: &reqwest::Client,
+    method: reqwest::Method,
+    path: &str,
+) -> Result<reqwest::RequestBuilder, BoxError> {
+    let url = format!(
+        "{}/v1/{}",
+        env("VAULT_ADDR")?.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    );
+    let mut request = client
+        .request(method, url)
+        .header("X-Vault-Token", env("VAULT_TOKEN")?);
+    if let Ok(namespace) = std::env::var("VAULT_NAMESPACE") {
+        request = request.header("X-Vault-Namespace", namespace);
+    }
+    Ok(request)
+}
+
+/// Read a key of a Vault secret, from the KV version 2 or version 1 secrets engines, or from a
+/// secrets engine generating dynamic secrets.
+async fn fetch_from_vault(client: &reqwest::Client, id: &str) -> Result<Secret, BoxError> {
+    let (path, key) = split_key(id);
+    let key = key.ok_or("vault secrets are referenced as `${vault:<path>#<key>}`")?;
+    let response: Value = vault_request(client, reqwest::Method::GET, path)?
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    vault_secret(&response, key)
+}
+
+fn vault_secret(response: &Value, key: &str) -> Result<Secret, BoxError> {
+    let data = response
+        .pointer("/data/data")
+        .filter(|data| data.is_object())
+        .or_else(|| response.get("data"))
+        .ok_or("the vault response has no data")?;
+    let value = string(data.get(key).ok_or("key not found in the vault secret")?);
+    let lease_id = response
+        .get("lease_id")
+        .and_then(Value::as_str)
+        .filter(|lease_id| !lease_id.is_empty())
+        .filter(|_| {
+            response
+                .get("renewable")
+                .and_then(Value::as_bool)
+                .unwrap_or(false)
+        })
+        .map(str::to_string);
+    Ok(Secret {
+        value,
+        ttl: lease_duration(response).unwrap_or(DEFAULT_TTL),
+        lease_id,
+    })
+}
+
+fn lease_duration(response: &Value) -> Option<Duration> {
+    response
+        .get("lease_duration")
+        .and_then(Value::as_u64)
+        .filter(|seconds| *seconds > 0)
+        .map(Duration::from_secs)
+}
+
+/// Extend a Vault lease, returning its new duration.
+async fn renew_vault_lease(client: &reqwest::Client, lease_id: &str) -> Result<Duration, BoxError> {
+    let response: Value = vault_request(client, reqwest::Method::PUT, "sys/leases/renew")?
+        .json(&serde_json::json!({ "lease_id": lease_id }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    lease_duration(&response).ok_or_else(|| "the lease reached its maximum duration".into())
+}
+
+/// Read an AWS Secrets Manager secret, or a key of a JSON secret.
+async fn fetch_from_aws(
+    client: &reqwest::Client,
+    aws_credentials: &CachedAwsCredentials,
+    id: &str,
+) -> Result<Secret, BoxError> {
+    let (secret_id, key) = split_key(id);
+    // ARNs are written `arn:aws:secretsmanager:<region>:<account>:secret:<name>`
+    let region = match secret_id.split(':').nth(3) {
+        Some(region) if secret_id.starts_with("arn:") => region.to_string(),
+        _ => env("AWS_REGION")?,
+    };
+    let credentials = role_or_environment_credentials(client, aws_credentials, &region).await?;
+    let config = SigV4Config {
+        region: region.clone(),
+        service: "secretsmanager".to_string(),
+        access_key_id: credentials.access_key_id,
+        secret_access_key: credentials.secret_access_key,
+        session_token: credentials.session_token,
+    };
+
+    let payload = serde_json::to_vec(&serde_json::json!({ "SecretId": secret_id }))?;
+    let mut request =
+        http::Request::post(format!("https://secretsmanager.{}.amazonaws.com/", region))
+            .header("X-Amz-Target", "secretsmanager.GetSecretValue")
+            .header(http::header::CONTENT_TYPE, "application/x-amz-json-1.1")
+            .body(payload)?;
+    config.sign(&mut request)?;
+
+    let response: Value = client
+        .execute(request.try_into()?)
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let secret = response
+        .get("SecretString")
+        .and_then(Value::as_str)
+        .ok_or("the AWS secret has no string value")?;
+    let value = match key {
+        Some(key) => {
+            let secret: Value = serde_json::from_str(secret)?;
+            string(secret.get(key).ok_or("key not found in the AWS secret")?)
+        }
+        None => secret.to_string(),
+    };
+    Ok(Secret {
+        value,
+        ttl: DEFAULT_TTL,
+        lease_id: None,
+    })
+}
+
+/// AWS credentials, static or temporary.
+#[derive(Clone, Debug, PartialEq)]
+struct AwsCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+    expires_at: Option<SystemTime>,
+}
+
+/// Temporary credentials, as returned by the ECS and instance metadata endpoints.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct RoleCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    token: String,
+    expiration: String,
+}
+
+impl TryFrom<RoleCredentials> for AwsCredentials {
+    type Error = BoxError;
+
+    fn try_from(credentials: RoleCredentials) -> Result<Self, Self::Error> {
+        Ok(AwsCredentials {
+            access_key_id: credentials.access_key_id,
+            secret_access_key: credentials.secret_access_key,
+            session_token: Some(credentials.token),
+            expires_at: Some(humantime::parse_rfc3339_weak(&credentials.expiration)?),
+        })
+    }
+}
+
+/// The AWS credentials of the router, from the first of:
+///
+/// * the `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN` environment
+///   variables,
+/// * the role of the Kubernetes service account (IRSA), with `AWS_WEB_IDENTITY_TOKEN_FILE` and
+///   `AWS_ROLE_ARN`,
+/// * the role of the ECS task, with `AWS_CONTAINER_CREDENTIALS_RELATIVE_URI` or
+///   `AWS_CONTAINER_CREDENTIALS_FULL_URI`,
+/// * the role of the EC2 instance, from the instance metadata service.
+async fn role_or_environment_credentials(
+    client: &reqwest::Client,
+    cached: &CachedAwsCredentials,
+    region: &str,
+) -> Result<AwsCredentials, BoxError> {
+    if let (Ok(access_key_id), Ok(secret_access_key)) = (
+        std::env::var("AWS_ACCESS_KEY_ID"),
+        std::env::var("AWS_SECRET_ACCESS_KEY"),
+    ) {
+        return Ok(AwsCredentials {
+            access_key_id,
+            secret_access_key,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+            expires_at: None,
+        });
+    }
+
+    // the lock is held while fetching, so that concurrent fetches share the credentials
+    let mut cached = cached.lock().await;
+    if let Some(credentials) = cached.as_ref() {
+        let fresh = credentials
+            .expires_at
+            .map(|expires_at| expires_at > SystemTime::now() + CREDENTIALS_EXPIRY_MARGIN)
+            .unwrap_or(true);
+        if fresh {
+            return Ok(credentials.clone());
+        }
+    }
+    let credentials = if let Ok(token_file) = std::env::var("AWS_WEB_IDENTITY_TOKEN_FILE") {
+        web_identity_credentials(client, region, &token_file).await?
+    } else if let Ok(uri) = std::env::var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI") {
+        role_credentials(client.get(format!("{}{}", ECS_CREDENTIALS_URL, uri))).await?
+    } else if let Ok(uri) = std::env::var("AWS_CONTAINER_CREDENTIALS_FULL_URI") {
+        let mut request = client.get(uri);
+        if let Ok(token) = std::env::var("AWS_CONTAINER_AUTHORIZATION_TOKEN") {
+            request = request.header(http::header::AUTHORIZATION, token);
+        }
+        role_credentials(request).await?
+    } else {
+        instance_credentials(client).await.map_err(|e| {
+            format!(
+                "no AWS credentials in the environment, and none from the instance metadata: {}",
+                e
+            )
+        })?
+    };
+    *cached = Some(credentials.clone());
+    Ok(credentials)
+}
+
+async fn role_credentials(request: reqwest::RequestBuilder) -> Result<AwsCredentials, BoxError> {
+    let credentials: RoleCredentials = request.send().await?.error_for_status()?.json().await?;
+    credentials.try_into()
+}
+
+/// Assume the role of the Kubernetes service account, with its projected token.
+async fn web_identity_credentials(
+    client: &reqwest::Client,
+    region: &str,
+    token_file: &str,
+) -> Result<AwsCredentials, BoxError> {
+    // the token is rotated by the kubelet, it is read again every time
+    let token = tokio::fs::read_to_string(token_file).await?;
+    let role_arn = env("AWS_ROLE_ARN")?;
+    let session_name =
+        std::env::var("AWS_ROLE_SESSION_NAME").unwrap_or_else(|_| "apollo-router".to_string());
+    let response = client
+        .post(format!("https://sts.{}.amazonaws.com/", region))
+        .form(&[
+            ("Action", "AssumeRoleWithWebIdentity"),
+            ("Version", "2011-06-15"),
+            ("RoleArn", role_arn.as_str()),
+            ("RoleSessionName", session_name.as_str()),
+            ("WebIdentityToken", token.trim()),
+        ])
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    sts_credentials(&response)
+}
+
+fn sts_credentials(response: &str) -> Result<AwsCredentials, BoxError> {
+    Ok(AwsCredentials {
+        access_key_id: xml_text(response, "AccessKeyId")?.to_string(),
+        secret_access_key: xml_text(response, "SecretAccessKey")?.to_string(),
+        session_token: Some(xml_text(response, "SessionToken")?.to_string()),
+        expires_at: Some(humantime::parse_rfc3339_weak(xml_text(
+            response,
+            "Expiration",
+        )?)?),
+    })
+}
+
+/// The text of the first element with this name, enough for the fixed responses of STS.
+fn xml_text<'a>(document: &'a str, name: &str) -> Result<&'a str, BoxError> {
+    let missing = || format!("no {} in the STS response", name);
+    let open = format!("<{}>", name);
+    let start = document.find(&open).ok_or_else(missing)? + open.len();
+    let end = document[start..]
+        .find(&format!("</{}>", name))
+        .ok_or_else(missing)?;
+    Ok(&document[start..start + end])
+}
+
+/// The credentials of the role of the EC2 instance, from the instance metadata service (IMDSv2).
+async fn instance_credentials(client: &reqwest::Client) -> Result<AwsCredentials, BoxError> {
+    let token = client
+        .put(format!("{}/api/token", IMDS_URL))
+        .header("X-aws-ec2-metadata-token-ttl-seconds", "21600")
+        .timeout(IMDS_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let url = format!("{}/meta-data/iam/security-credentials/", IMDS_URL);
+    let roles = client
+        .get(&url)
+        .header("X-aws-ec2-metadata-token", &token)
+        .timeout(IMDS_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let role = roles
+        .lines()
+        .next()
+        .filter(|role| !role.is_empty())
+        .ok_or("the instance has no role")?;
+    role_credentials(
+        client
+            .get(format!("{}{}", url, role))
+            .header("X-aws-ec2-metadata-token", &token)
+            .timeout(IMDS_TIMEOUT),
+    )
+    .await
+}
+
+fn string(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn cached(reference: &str, value: &str) -> Secrets {
+        let secrets = Secrets::default();
+        secrets.cache.lock().unwrap().insert(
+            reference.to_string(),
+            Cached::new(Secret {
+                value: value.to_string(),
+                ttl: DEFAULT_TTL,
+                lease_id: None,
+            }),
+        );
+        secrets
+    }
+
+    #[test]
+    fn it_expands_the_cached_secrets() {
+        let secrets = cached(
+            "${vault:secret/data/router#apollo_key}",
+            "service:${GRAPH}:key",
+        );
+        // the environment variables are expanded around the secrets, not in their values
+        let expand_env = |value: &str| value.replace("${ENV}", "env");
+        assert_eq!(
+            secrets
+                .expand("${ENV}: ${vault:secret/data/router#apollo_key}", expand_env)
+                .unwrap(),
+            "env: service:${GRAPH}:key"
+        );
+        assert_eq!(secrets.expand("${ENV}", expand_env).unwrap(), "env");
+
+        let (reference, _) = secrets
+            .expand("${vault:secret/data/router}", expand_env)
+            .unwrap_err();
+        assert_eq!(reference, "${vault:secret/data/router}");
+    }
+
+    #[tokio::test]
+    async fn it_drops_the_secrets_no_longer_referenced() {
+        let secrets = cached("${vault:secret/data/router#apollo_key}", "key");
+        secrets
+            .fetch_references(
+                "telemetry:\n  apollo:\n    api_key: \"${vault:secret/data/router#apollo_key}\"\n",
+            )
+            .await
+            .unwrap();
+        assert_eq!(secrets.cache.lock().unwrap().len(), 1);
+
+        // the configuration reloaded without the secret stops renewing it
+        secrets
+            .fetch_references("server:\n  listen: 127.0.0.1:4000\n")
+            .await
+            .unwrap();
+        assert!(secrets.cache.lock().unwrap().is_empty());
+        assert!(!secrets.renew().await);
+    }
+
+    #[test]
+    fn it_collects_the_references_of_the_values() {
+        let configuration = json!({
+            "headers": [
+                { "value": "Bearer ${awssm:accounts#token}" },
+                { "value": "${vault:secret/router#key} ${awssm:accounts#token}" },
+            ],
+            "${vault:secret/keys#ignored}": 4000,
+        });
+        let mut references = Vec::new();
+        collect_references(&configuration, &mut references);
+        assert_eq!(
+            references,
+            vec!["${awssm:accounts#token}", "${vault:secret/router#key}"]
+        );
+    }
+
+    #[test]
+    fn it_reads_the_values_of_the_vault_secrets() {
+        let kv2 = json!({
+            "lease_duration": 0,
+            "data": { "data": { "apollo_key": "service:graph:key" }, "metadata": {} },
+        });
+        assert_eq!(
+            vault_secret(&kv2, "apollo_key").unwrap(),
+            Secret {
+                value: "service:graph:key".to_string(),
+                ttl: DEFAULT_TTL,
+                lease_id: None,
+            }
+        );
+
+        let kv1 = json!({ "lease_id": "", "lease_duration": 60, "data": { "port": 4000 } });
+        assert_eq!(
+            vault_secret(&kv1, "port").unwrap(),
+            Secret {
+                value: "4000".to_string(),
+                ttl: Duration::from_secs(60),
+                lease_id: None,
+            }
+        );
+        assert!(vault_secret(&kv1, "apollo_key").is_err());
+
+        let dynamic = json!({
+            "lease_id": "database/creds/router/abcd",
+            "renewable": true,
+            "lease_duration": 3600,
+            "data": { "username": "router", "password": "1234" },
+        });
+        assert_eq!(
+            vault_secret(&dynamic, "password").unwrap(),
+            Secret {
+                value: "1234".to_string(),
+                ttl: Duration::from_secs(3600),
+                lease_id: Some("database/creds/router/abcd".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn it_reads_the_credentials_of_the_sts_responses() {
+        let response = r#"<AssumeRoleWithWebIdentityResponse xmlns="https://sts.amazonaws.com/doc/2011-06-15/">
+  <AssumeRoleWithWebIdentityResult>
+    <Credentials>
+      <AccessKeyId>ASIAEXAMPLE</AccessKeyId>
+      <SecretAccessKey>secret/key+example</SecretAccessKey>
+      <SessionToken>session=token</SessionToken>
+      <Expiration>2022-09-01T12:00:00Z</Expiration>
+    </Credentials>
+  </AssumeRoleWithWebIdentityResult>
+</AssumeRoleWithWebIdentityResponse>"#;
+        assert_eq!(
+            sts_credentials(response).unwrap(),
+            AwsCredentials {
+                access_key_id: "ASIAEXAMPLE".to_string(),
+                secret_access_key: "secret/key+example".to_string(),
+                session_token: Some("session=token".to_string()),
+                expires_at: Some(humantime::parse_rfc3339("2022-09-01T12:00:00Z").unwrap()),
+            }
+        );
+        assert!(sts_credentials("<ErrorResponse/>").is_err());
+    }
+
+    #[test]
+    fn it_splits_the_keys_of_the_secret_ids() {
+        assert_eq!(
+            split_key("arn:aws:secretsmanager:us-east-1:123:secret:router#jwt"),
+            (
+                "arn:aws:secretsmanager:us-east-1:123:secret:router",
+                Some("jwt")
+            )
+        );
+        assert_eq!(split_key("router"), ("router", None));
+    }
+}
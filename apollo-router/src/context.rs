@@ -2,13 +2,24 @@
 //!
 //! Router plugins accept a mutable [`Context`] when invoked and this contains a DashMap which
 //! allows additional data to be passed back and forth along the request invocation pipeline.
+//!
+//! The entries can be accessed by name, or through a [`ContextKey`] which namespaces the name
+//! and fixes the type of the value, so that plugins don't step on each other's entries.
 
+use std::fmt;
+use std::marker::PhantomData;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
 
+use dashmap::mapref::entry::Entry;
 use dashmap::mapref::multiple::RefMulti;
 use dashmap::mapref::multiple::RefMutMulti;
 use dashmap::DashMap;
+use once_cell::sync::OnceCell;
+use serde::de::DeserializeOwned;
 use serde::Serialize;
+use tokio::sync::watch;
 use tower::BoxError;
 
 use crate::json_ext::Value;
@@ -16,6 +27,113 @@ use crate::json_ext::Value;
 /// Holds [`Context`] entries.
 pub(crate) type Entries = Arc<DashMap<String, Value>>;
 
+/// Holds the senders notifying the subscribers of the changes of [`Context`] entries, created
+/// with the first subscription.
+type Watchers = Arc<OnceCell<DashMap<String, watch::Sender<Option<Value>>>>>;
+
+/// Holds the typed values of the router for a request, which plugins do not see.
+type Extensions = Arc<Mutex<http::Extensions>>;
+
+/// A typed key of a [`Context`] entry, in the namespace of its owner, usually a plugin.
+///
+/// The entry is named `<namespace>::<name>`, so it can still be accessed by name, from a Rhai
+/// script for example. Keys are created with the [`context_key!`](crate::context_key) macro.
+///
+/// ```
+/// use apollo_router::context_key;
+/// use apollo_router::ContextKey;
+///
+/// const ATTEMPTS: ContextKey<u32> = context_key!("my_plugin", "attempts");
+/// ```
+pub struct ContextKey<V> {
+    namespace: &'static str,
+    name: &'static str,
+    value: PhantomData<fn() -> V>,
+}
+
+impl<V> ContextKey<V> {
+    /// Create a key from its namespace and its full name, which the
+    /// [`context_key!`](crate::context_key) macro builds.
+    #[doc(hidden)]
+    pub const fn from_parts(namespace: &'static str, name: &'static str) -> Self {
+        Self {
+            namespace,
+            name,
+            value: PhantomData,
+        }
+    }
+
+    /// The namespace of the entry.
+    pub fn namespace(&self) -> &'static str {
+        self.namespace
+    }
+
+    /// The full name of the entry, `<namespace>::<name>`.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// Create the [`ContextKey`] of the `<namespace>::<name>` entry.
+#[macro_export]
+macro_rules! context_key {
+    ($namespace: literal, $name: literal) => {
+        $crate::ContextKey::from_parts($namespace, concat!($namespace, "::", $name))
+    };
+}
+
+impl<V> Clone for ContextKey<V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<V> Copy for ContextKey<V> {}
+
+impl<V> fmt::Debug for ContextKey<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ContextKey").field(&self.name()).finish()
+    }
+}
+
+impl<V> From<ContextKey<V>> for String {
+    fn from(key: ContextKey<V>) -> Self {
+        key.name().to_string()
+    }
+}
+
+/// A subscription to the changes of a [`Context`] entry.
+///
+/// Only the last value is kept: a subscriber which is slower than the changes misses the
+/// intermediate values.
+#[derive(Debug)]
+pub struct ContextSubscription<V> {
+    receiver: watch::Receiver<Option<Value>>,
+    value: PhantomData<fn() -> V>,
+}
+
+impl<V: DeserializeOwned> ContextSubscription<V> {
+    /// The current value of the entry.
+    pub fn current(&self) -> Result<Option<V>, BoxError> {
+        deserialize(self.receiver.borrow().clone())
+    }
+
+    /// Wait for the next change of the entry, and return its new value.
+    ///
+    /// Fails if the context was dropped.
+    pub async fn changed(&mut self) -> Result<Option<V>, BoxError> {
+        self.receiver.changed().await?;
+        self.current()
+    }
+}
+
+fn deserialize<V: DeserializeOwned>(value: Option<Value>) -> Result<Option<V>, BoxError> {
+    value
+        .map(serde_json_bytes::from_value)
+        .transpose()
+        .map_err(|e| e.into())
+}
+
 /// A map of arbitrary JSON values, for use by plugins.
 ///
 /// Context makes use of [`DashMap`] under the hood which tries to handle concurrency
@@ -30,6 +148,8 @@ pub(crate) type Entries = Arc<DashMap<String, Value>>;
 pub struct Context {
     // Allows adding custom entries to the context.
     entries: Entries,
+    watchers: Watchers,
+    extensions: Extensions,
 }
 
 impl Context {
@@ -37,8 +157,17 @@ impl Context {
     pub fn new() -> Self {
         Context {
             entries: Default::default(),
+            watchers: Default::default(),
+            extensions: Default::default(),
         }
     }
+
+    /// The typed values of the router for this request.
+    pub(crate) fn extensions(&self) -> MutexGuard<'_, http::Extensions> {
+        self.extensions
+            .lock()
+            .expect("the extensions of the context are never poisoned; qed")
+    }
 }
 
 impl Context {
@@ -70,12 +199,7 @@ impl Context {
         V: for<'de> serde::Deserialize<'de> + Serialize,
     {
         match serde_json_bytes::to_value(value) {
-            Ok(value) => self
-                .entries
-                .insert(key.into(), value)
-                .map(|v| serde_json_bytes::from_value(v))
-                .transpose()
-                .map_err(|e| e.into()),
+            Ok(value) => deserialize(self.insert_json_value(key, value)),
             Err(e) => Err(e.into()),
         }
     }
@@ -87,7 +211,26 @@ impl Context {
     where
         K: Into<String>,
     {
-        self.entries.insert(key.into(), value)
+        // the subscribers are notified while the entry is locked, so in the order of the changes
+        match self.entries.entry(key.into()) {
+            Entry::Occupied(mut entry) => {
+                let previous = entry.insert(value);
+                self.notify(entry.key(), entry.get());
+                Some(previous)
+            }
+            Entry::Vacant(entry) => {
+                let entry = entry.insert(value);
+                self.notify(entry.key(), entry.value());
+                None
+            }
+        }
+    }
+
+    /// Notify the subscribers of an entry, if any, of its new value.
+    fn notify(&self, key: &str, value: &Value) {
+        if let Some(watcher) = self.watchers.get().and_then(|watchers| watchers.get(key)) {
+            watcher.send_replace(Some(value.clone()));
+        }
     }
 
     /// Get a json value from the context using the provided key.
@@ -115,26 +258,76 @@ impl Context {
         K: Into<String>,
         V: for<'de> serde::Deserialize<'de> + Serialize + Default,
     {
-        let key = key.into();
-        self.entries
-            .entry(key.clone())
+        let mut entry = self
+            .entries
+            .entry(key.into())
             .or_try_insert_with(|| serde_json_bytes::to_value::<V>(Default::default()))?;
-        let mut result = Ok(());
+        let value = serde_json_bytes::from_value(entry.value().clone())?;
+        *entry.value_mut() = serde_json_bytes::to_value((upsert)(value))?;
+        // the entry is still locked, so the subscribers are notified in the order of the changes
+        self.notify(entry.key(), entry.value());
+        Ok(())
+    }
+
+    /// Get the value of a typed entry.
+    pub fn get_typed<V>(&self, key: ContextKey<V>) -> Result<Option<V>, BoxError>
+    where
+        V: DeserializeOwned,
+    {
         self.entries
-            .alter(&key, |_, v| match serde_json_bytes::from_value(v.clone()) {
-                Ok(value) => match serde_json_bytes::to_value((upsert)(value)) {
-                    Ok(value) => value,
-                    Err(e) => {
-                        result = Err(e);
-                        v
-                    }
-                },
-                Err(e) => {
-                    result = Err(e);
-                    v
-                }
-            });
-        result.map_err(|e| e.into())
+            .get(key.name())
+            .map(|v| serde_json_bytes::from_value(v.value().clone()))
+            .transpose()
+            .map_err(|e| e.into())
+    }
+
+    /// Insert the value of a typed entry.
+    ///
+    /// Semantics: the same as [`Context::insert`].
+    pub fn insert_typed<V>(&self, key: ContextKey<V>, value: V) -> Result<Option<V>, BoxError>
+    where
+        V: DeserializeOwned + Serialize,
+    {
+        self.insert(key, value)
+    }
+
+    /// Upsert the value of a typed entry.
+    ///
+    /// Semantics: the same as [`Context::upsert`].
+    pub fn upsert_typed<V>(
+        &self,
+        key: ContextKey<V>,
+        upsert: impl Fn(V) -> V,
+    ) -> Result<(), BoxError>
+    where
+        V: DeserializeOwned + Serialize + Default,
+    {
+        self.upsert(key, upsert)
+    }
+
+    /// Subscribe to the changes of a typed entry.
+    ///
+    /// The subscribers are notified of the values inserted or upserted, but not of the changes
+    /// made while iterating mutably over the entries.
+    pub fn subscribe<V>(&self, key: ContextKey<V>) -> ContextSubscription<V> {
+        // the entry is locked while subscribing, so that its changes are notified after its
+        // current value. The entries are always locked before the watchers
+        let entry = self.entries.entry(key.name().to_string());
+        let current = match &entry {
+            Entry::Occupied(entry) => Some(entry.get().clone()),
+            Entry::Vacant(_) => None,
+        };
+        let receiver = self
+            .watchers
+            .get_or_init(Default::default)
+            .entry(key.name().to_string())
+            .or_insert_with(|| watch::channel(current).0)
+            .subscribe();
+        drop(entry);
+        ContextSubscription {
+            receiver,
+            value: PhantomData,
+        }
     }
 
     /// Iterate over the entries.
@@ -156,7 +349,9 @@ impl Default for Context {
 
 #[cfg(test)]
 mod test {
+    use crate::context_key;
     use crate::Context;
+    use crate::ContextKey;
 
     #[test]
     fn test_context_insert() {
@@ -219,4 +414,46 @@ mod test {
         assert_eq!(c.get("one").unwrap(), Some(2));
         assert_eq!(c.get("two").unwrap(), Some(3));
     }
+
+    const ATTEMPTS: ContextKey<usize> = context_key!("my_plugin", "attempts");
+    const OTHER_ATTEMPTS: ContextKey<String> = context_key!("other_plugin", "attempts");
+
+    #[test]
+    fn it_namespaces_the_typed_entries() {
+        let c = Context::new();
+        assert_eq!(c.get_typed(ATTEMPTS).unwrap(), None);
+        assert!(c.insert_typed(ATTEMPTS, 1).is_ok());
+        assert!(c.insert_typed(OTHER_ATTEMPTS, "one".to_string()).is_ok());
+        assert!(c.upsert_typed(ATTEMPTS, |v| v + 1).is_ok());
+        assert_eq!(c.get_typed(ATTEMPTS).unwrap(), Some(2));
+        assert_eq!(c.get_typed(OTHER_ATTEMPTS).unwrap().as_deref(), Some("one"));
+        assert_eq!(c.get::<_, usize>("my_plugin::attempts").unwrap(), Some(2));
+        assert_eq!(ATTEMPTS.namespace(), "my_plugin");
+        assert_eq!(ATTEMPTS.name(), "my_plugin::attempts");
+    }
+
+    #[tokio::test]
+    async fn it_notifies_the_subscribers_of_the_changes() {
+        let c = Context::new();
+        assert!(c.insert_typed(ATTEMPTS, 1).is_ok());
+        let mut subscription = c.subscribe(ATTEMPTS);
+        assert_eq!(subscription.current().unwrap(), Some(1));
+
+        let writer = c.clone();
+        tokio::spawn(async move {
+            writer.insert_typed(ATTEMPTS, 2).unwrap();
+        });
+        assert_eq!(subscription.changed().await.unwrap(), Some(2));
+
+        assert!(c.upsert_typed(ATTEMPTS, |v| v + 1).is_ok());
+        assert_eq!(subscription.changed().await.unwrap(), Some(3));
+
+        // the other entries don't notify the subscribers
+        assert!(c.insert_typed(OTHER_ATTEMPTS, "one".to_string()).is_ok());
+        assert!(c.insert("my_plugin::attempts", 4).is_ok());
+        assert_eq!(subscription.changed().await.unwrap(), Some(4));
+
+        drop(c);
+        assert!(subscription.changed().await.is_err());
+    }
 }
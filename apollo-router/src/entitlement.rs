@@ -0,0 +1,379 @@
+//! Entitlements of the router to its commercial features.
+//!
+//! The license is a JWT set in the `APOLLO_ROUTER_LICENSE` environment variable, signed with one
+//! of the public keys of the `licensing` configuration, and read every time a configuration is
+//! loaded. Its claims set two grace periods: past `warnAt` the router warns on every reload that
+//! uses a commercial feature, and past `haltAt` it refuses to load the configurations using them,
+//! the running pipeline being kept. Without a license, or with an invalid or expired one, the
+//! configurations using commercial features are refused too.
+//!
+//! The entitlement is only enforced when `licensing` is configured. Otherwise, its state is
+//! `unenforced` and the configurations are loaded whatever their features.
+//!
+//! The state of the entitlement is reported by the `apollo_router_entitlement_state` metric and
+//! by the `/entitlement` endpoint of the admin API.
+
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use displaydoc::Display;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::configuration::Configuration;
+use crate::configuration::Licensing;
+use crate::jwt;
+use crate::jwt::Jwk;
+
+/// Environment variable holding the license.
+const LICENSE_ENV: &str = "APOLLO_ROUTER_LICENSE";
+
+/// Entitlement errors.
+#[derive(Error, Debug, Display)]
+pub(crate) enum EntitlementError {
+    /// invalid license: {0}
+    InvalidLicense(String),
+    /// the commercial features {0:?} need a license, set in APOLLO_ROUTER_LICENSE
+    Unlicensed(Vec<&'static str>),
+    /// the license expired, the commercial features {0:?} cannot be used anymore
+    Halted(Vec<&'static str>),
+}
+
+/// State of the entitlement.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EntitlementState {
+    /// The licensing is not configured, the entitlement is not enforced.
+    Unenforced,
+    /// No license was provided.
+    Unlicensed,
+    /// The license could not be read.
+    Invalid,
+    /// The license is valid.
+    Entitled,
+    /// Past the `warnAt` claim of the license.
+    Warning,
+    /// Past the `haltAt` claim of the license.
+    Halted,
+}
+
+impl EntitlementState {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            EntitlementState::Unenforced => "unenforced",
+            EntitlementState::Unlicensed => "unlicensed",
+            EntitlementState::Invalid => "invalid",
+            EntitlementState::Entitled => "entitled",
+            EntitlementState::Warning => "warning",
+            EntitlementState::Halted => "halted",
+        }
+    }
+}
+
+/// Claims of the license.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Claims {
+    /// The licensee.
+    sub: String,
+    /// Start of the warning period, in seconds since the UNIX epoch.
+    warn_at: u64,
+    /// Start of the halt period, in seconds since the UNIX epoch.
+    halt_at: u64,
+}
+
+/// The entitlement of the router, as read from its license.
+#[derive(Clone, Debug)]
+pub(crate) struct Entitlement {
+    /// Whether the licensing is configured.
+    enforced: bool,
+    license: Result<Option<Claims>, String>,
+}
+
+/// The entitlement state reported by the admin API.
+#[derive(Debug, Serialize)]
+pub(crate) struct EntitlementReport {
+    state: EntitlementState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    licensee: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warn_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    halt_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    commercial_features: Vec<&'static str>,
+}
+
+/// A commercial feature, and whether a configuration uses it.
+struct Feature {
+    name: &'static str,
+    used: fn(&Configuration) -> bool,
+}
+
+const COMMERCIAL_FEATURES: &[Feature] = &[
+    Feature {
+        name: "contracts",
+        used: |configuration| !configuration.contracts.is_empty(),
+    },
+    Feature {
+        name: "quotas",
+        used: |configuration| configuration.quotas.is_some(),
+    },
+    Feature {
+        name: "data_masking",
+        used: |configuration| configuration.data_masking.is_some(),
+    },
+    Feature {
+        name: "canary",
+        used: |configuration| configuration.canary.is_some(),
+    },
+];
+
+/// The commercial features used by a configuration.
+pub(crate) fn commercial_features(configuration: &Configuration) -> Vec<&'static str> {
+    COMMERCIAL_FEATURES
+        .iter()
+        .filter(|feature| (feature.used)(configuration))
+        .map(|feature| feature.name)
+        .collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Entitlement {
+    /// The entitlement of the license set in the environment, verified as configured.
+    pub(crate) fn load(licensing: Option<&Licensing>) -> Self {
+        Entitlement::new(std::env::var(LICENSE_ENV).ok().as_deref(), licensing)
+    }
+
+    fn new(license: Option<&str>, licensing: Option<&Licensing>) -> Self {
+        let licensing = match licensing {
+            Some(licensing) => licensing,
+            None => {
+                return Entitlement {
+                    enforced: false,
+                    license: Ok(None),
+                }
+            }
+        };
+        Entitlement {
+            enforced: true,
+            license: license
+                .map(str::trim)
+                .filter(|license| !license.is_empty())
+                .map(|license| Self::decode(license, licensing))
+                .transpose(),
+        }
+    }
+
+    /// Read the claims of the license, a JWT issued for the router and signed with one of the
+    /// public keys. The secret keys are never used, since they could sign licenses too.
+    fn decode(license: &str, licensing: &Licensing) -> Result<Claims, String> {
+        let keys: Vec<Jwk> = licensing
+            .keys
+            .iter()
+            .filter(|key| key.kty == "RSA" || key.kty == "EC")
+            .cloned()
+            .collect();
+        let claims = jwt::verify(license, &keys, &licensing.issuer, &licensing.audience)
+            .map_err(|e| e.to_string())?;
+        let claims: Claims = serde_json::from_value(serde_json::Value::Object(claims))
+            .map_err(|e| format!("could not read the claims: {}", e))?;
+        if claims.halt_at < claims.warn_at {
+            return Err("the halt period starts before the warning period".to_string());
+        }
+        Ok(claims)
+    }
+
+    /// The state of the entitlement at a time, in seconds since the UNIX epoch.
+    pub(crate) fn state_at(&self, now: u64) -> EntitlementState {
+        if !self.enforced {
+            return EntitlementState::Unenforced;
+        }
+        match &self.license {
+            Ok(None) => EntitlementState::Unlicensed,
+            Err(_) => EntitlementState::Invalid,
+            Ok(Some(claims)) if now >= claims.halt_at => EntitlementState::Halted,
+            Ok(Some(claims)) if now >= claims.warn_at => EntitlementState::Warning,
+            Ok(Some(_)) => EntitlementState::Entitled,
+        }
+    }
+
+    pub(crate) fn state(&self) -> EntitlementState {
+        self.state_at(now())
+    }
+
+    /// Check that a configuration can be loaded, warning about the commercial features it uses
+    /// during the warning period.
+    pub(crate) fn check(&self, configuration: &Configuration) -> Result<(), EntitlementError> {
+        self.check_at(configuration, now())
+    }
+
+    fn check_at(&self, configuration: &Configuration, now: u64) -> Result<(), EntitlementError> {
+        let features = commercial_features(configuration);
+        if features.is_empty() {
+            return Ok(());
+        }
+        match (self.state_at(now), &self.license) {
+            (EntitlementState::Unenforced, _) | (EntitlementState::Entitled, _) => Ok(()),
+            (EntitlementState::Unlicensed, _) => Err(EntitlementError::Unlicensed(features)),
+            (EntitlementState::Warning, Ok(Some(claims))) => {
+                tracing::warn!(
+                    "the license expired, the commercial features {:?} will not be loaded anymore in {}s",
+                    features,
+                    claims.halt_at.saturating_sub(now)
+                );
+                Ok(())
+            }
+            (EntitlementState::Invalid, Err(e)) => Err(EntitlementError::InvalidLicense(e.clone())),
+            _ => Err(EntitlementError::Halted(features)),
+        }
+    }
+
+    /// The entitlement state, with the commercial features used by a configuration.
+    pub(crate) fn report(&self, configuration: &Configuration) -> EntitlementReport {
+        let claims = self.license.as_ref().ok().and_then(Option::as_ref);
+        EntitlementReport {
+            state: self.state(),
+            licensee: claims.map(|claims| claims.sub.clone()),
+            warn_at: claims.map(|claims| claims.warn_at),
+            halt_at: claims.map(|claims| claims.halt_at),
+            error: self.license.as_ref().err().cloned(),
+            commercial_features: commercial_features(configuration),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::jwt::test::ec_signed_token;
+    use crate::jwt::test::signed_token;
+
+    fn licensing(key: Jwk) -> Licensing {
+        Licensing {
+            issuer: "https://licensor".to_string(),
+            audience: "router".to_string(),
+            keys: vec![key],
+        }
+    }
+
+    fn license(claims: serde_json::Value) -> serde_json::Value {
+        let mut license = json!({ "iss": "https://licensor", "aud": "router", "sub": "acme" });
+        license
+            .as_object_mut()
+            .unwrap()
+            .extend(claims.as_object().unwrap().clone());
+        license
+    }
+
+    fn entitlement(claims: serde_json::Value) -> Entitlement {
+        let (license, key) = ec_signed_token(license(claims));
+        Entitlement::new(Some(&license), Some(&licensing(key)))
+    }
+
+    fn configuration() -> Configuration {
+        serde_json::from_value(json!({ "canary": { "operations": [] } })).unwrap()
+    }
+
+    #[test]
+    fn it_goes_through_the_grace_periods() {
+        let entitled = entitlement(json!({ "warnAt": 1000, "haltAt": 2000 }));
+        assert_eq!(entitled.state_at(999), EntitlementState::Entitled);
+        assert_eq!(entitled.state_at(1000), EntitlementState::Warning);
+        assert_eq!(entitled.state_at(2000), EntitlementState::Halted);
+
+        assert!(entitled.check_at(&configuration(), 1500).is_ok());
+        assert!(matches!(
+            entitled.check_at(&configuration(), 2500),
+            Err(EntitlementError::Halted(features)) if features == vec!["canary"]
+        ));
+        assert!(entitled.check_at(&Configuration::default(), 2500).is_ok());
+    }
+
+    #[test]
+    fn it_is_not_enforced_without_licensing() {
+        let (license, _) = ec_signed_token(license(json!({ "warnAt": 0, "haltAt": 0 })));
+        for license in [None, Some("not a license"), Some(license.as_str())] {
+            let entitlement = Entitlement::new(license, None);
+            assert_eq!(entitlement.state_at(0), EntitlementState::Unenforced);
+            assert!(entitlement.check_at(&configuration(), 0).is_ok());
+        }
+    }
+
+    #[test]
+    fn it_reads_the_licenses() {
+        let (_, key) = ec_signed_token(json!({}));
+        let unlicensed = Entitlement::new(None, Some(&licensing(key.clone())));
+        assert_eq!(unlicensed.state_at(0), EntitlementState::Unlicensed);
+        assert_eq!(
+            Entitlement::new(Some(" "), Some(&licensing(key.clone()))).state_at(0),
+            EntitlementState::Unlicensed
+        );
+        assert!(matches!(
+            unlicensed.check_at(&configuration(), 0),
+            Err(EntitlementError::Unlicensed(features)) if features == vec!["canary"]
+        ));
+        assert!(unlicensed.check_at(&Configuration::default(), 0).is_ok());
+
+        let invalid = Entitlement::new(Some("not a license"), Some(&licensing(key)));
+        assert_eq!(invalid.state_at(0), EntitlementState::Invalid);
+        assert!(matches!(
+            invalid.check_at(&configuration(), 0),
+            Err(EntitlementError::InvalidLicense(_))
+        ));
+        assert_eq!(
+            entitlement(json!({ "warnAt": 2, "haltAt": 1 })).state_at(0),
+            EntitlementState::Invalid
+        );
+    }
+
+    #[test]
+    fn it_checks_the_validity_of_the_licenses() {
+        let future = 9999999999u64;
+        assert_eq!(
+            entitlement(json!({ "warnAt": future, "haltAt": future })).state(),
+            EntitlementState::Entitled
+        );
+        // whatever their grace periods
+        assert_eq!(
+            entitlement(json!({ "exp": 1, "warnAt": future, "haltAt": future })).state(),
+            EntitlementState::Invalid
+        );
+        assert_eq!(
+            entitlement(json!({ "nbf": future, "warnAt": future, "haltAt": future })).state(),
+            EntitlementState::Invalid
+        );
+        assert_eq!(
+            entitlement(json!({ "aud": "other", "warnAt": future, "haltAt": future })).state(),
+            EntitlementState::Invalid
+        );
+    }
+
+    #[test]
+    fn it_verifies_the_signatures_of_the_licenses() {
+        let claims = license(json!({ "warnAt": 9999999999u64, "haltAt": 9999999999u64 }));
+        let (license, _) = ec_signed_token(claims.clone());
+        let (_, other) = ec_signed_token(json!({}));
+        assert_eq!(
+            Entitlement::new(Some(&license), Some(&licensing(other))).state_at(0),
+            EntitlementState::Invalid
+        );
+
+        // a secret key could sign licenses too
+        let (license, secret) = signed_token(claims);
+        assert_eq!(
+            Entitlement::new(Some(&license), Some(&licensing(secret))).state_at(0),
+            EntitlementState::Invalid
+        );
+    }
+}
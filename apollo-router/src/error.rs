@@ -1,7 +1,10 @@
 //! Router errors.
 use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
 
 use displaydoc::Display;
+use http::StatusCode;
 use miette::Diagnostic;
 use miette::NamedSource;
 use miette::Report;
@@ -22,7 +25,9 @@ pub(crate) use crate::graphql::Error;
 use crate::graphql::Response;
 use crate::json_ext::Path;
 use crate::json_ext::Value;
+use crate::plugins::traffic_shaping::Elapsed;
 use crate::spec::SpecError;
+use crate::spec::ValidationError;
 
 /// Error types for execution.
 ///
@@ -40,10 +45,14 @@ pub(crate) enum FetchError {
         service: String,
     },
 
-    /// invalid type for variable: '{name}'
+    /// {message}
     ValidationInvalidTypeVariable {
         /// Name of the variable.
         name: String,
+
+        /// Description of the error, used as the GraphQL error message.
+        #[serde(skip)]
+        message: String,
     },
 
     /// query could not be planned: {reason}
@@ -90,6 +99,67 @@ pub(crate) enum FetchError {
         reason: String,
     },
 
+    /// could not connect to '{service}': {reason}
+    SubrequestConnectError {
+        /// The service that could not be reached.
+        service: String,
+
+        /// The reason the connection failed.
+        reason: String,
+    },
+
+    /// service '{service}' responded with status {status}: {reason}
+    SubrequestHttpStatusError {
+        /// The service that responded with an error status.
+        service: String,
+
+        /// The HTTP status code of the response.
+        status: u16,
+
+        /// The reason the response was rejected.
+        reason: String,
+
+        /// The delay of the `Retry-After` header of the response.
+        #[serde(skip)]
+        retry_after: Option<Duration>,
+    },
+
+    /// request deadline exceeded while fetching from '{service}'
+    SubrequestDeadlineExceeded {
+        /// The service that was cancelled.
+        service: String,
+    },
+
+    /// service '{service}' is unavailable: its circuit breaker is open
+    SubrequestCircuitOpen {
+        /// The service that was skipped.
+        service: String,
+    },
+
+    /// request size limit of {limit} bytes exceeded while fetching from '{service}'
+    SubrequestSizeLimitExceeded {
+        /// The service that was aborted.
+        service: String,
+        /// The maximum size of the request, in bytes.
+        limit: u64,
+    },
+
+    /// service '{service}' did not return {missing} of the {representations} entities requested
+    SubrequestMissingEntities {
+        /// The service that did not return the entities.
+        service: String,
+        /// The number of entities missing or null.
+        missing: usize,
+        /// The number of entities requested.
+        representations: usize,
+    },
+
+    /// service '{service}' did not return the entity
+    SubrequestMissingEntity {
+        /// The service that did not return the entity.
+        service: String,
+    },
+
     /// subquery requires field '{field}' but it was not found in the current response
     ExecutionFieldNotFound {
         /// The field that is not found.
@@ -111,15 +181,100 @@ pub(crate) enum FetchError {
 }
 
 impl FetchError {
+    /// The code of the GraphQL errors of this kind.
+    pub(crate) fn code(&self) -> ErrorCode {
+        match self {
+            FetchError::ValidationInvalidTypeVariable { .. } => ErrorCode::BadUserInput,
+            FetchError::ValidationUnknownServiceError { .. }
+            | FetchError::ValidationPlanningError { .. } => ErrorCode::GraphqlValidationFailed,
+            FetchError::SubrequestNoResponse { .. }
+            | FetchError::SubrequestMalformedResponse { .. }
+            | FetchError::SubrequestUnexpectedPatchResponse { .. }
+            | FetchError::SubrequestHttpError { .. }
+            | FetchError::SubrequestConnectError { .. }
+            | FetchError::SubrequestHttpStatusError { .. }
+            | FetchError::SubrequestDeadlineExceeded { .. }
+            | FetchError::SubrequestCircuitOpen { .. }
+            | FetchError::SubrequestSizeLimitExceeded { .. }
+            | FetchError::SubrequestMissingEntities { .. }
+            | FetchError::SubrequestMissingEntity { .. }
+            | FetchError::CompressionError { .. } => ErrorCode::SubrequestFailed,
+            FetchError::MalformedResponse { .. }
+            | FetchError::ExecutionFieldNotFound { .. }
+            | FetchError::ExecutionInvalidContent { .. }
+            | FetchError::ExecutionPathNotFound { .. } => ErrorCode::InternalServerError,
+        }
+    }
+
+    /// The kind of the error, for the metrics.
+    pub(crate) fn kind(&self) -> FetchErrorKind {
+        match self {
+            FetchError::SubrequestConnectError { .. } => FetchErrorKind::Connect,
+            FetchError::SubrequestDeadlineExceeded { .. } => FetchErrorKind::Timeout,
+            FetchError::SubrequestHttpStatusError { .. } => FetchErrorKind::HttpStatus,
+            FetchError::SubrequestMalformedResponse { .. }
+            | FetchError::SubrequestUnexpectedPatchResponse { .. }
+            | FetchError::MalformedResponse { .. } => FetchErrorKind::Deserialization,
+            _ => FetchErrorKind::Other,
+        }
+    }
+
+    /// Whether the request may succeed if it is sent again: the connection errors and the
+    /// statuses of overloaded or unavailable subgraphs. The deadline of the client request is
+    /// not retryable.
+    pub(crate) fn is_retryable(&self) -> bool {
+        match self {
+            FetchError::SubrequestConnectError { .. } => true,
+            FetchError::SubrequestHttpStatusError { status, .. } => StatusCode::from_u16(*status)
+                .map(is_retryable_status)
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    /// The delay the subgraph asked to wait before sending the request again.
+    pub(crate) fn retry_after(&self) -> Option<Duration> {
+        match self {
+            FetchError::SubrequestHttpStatusError { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// Convert the error of a subgraph service to a fetch error, keeping the fetch errors as is.
+    pub(crate) fn from_service_error(service: &str, error: BoxError) -> Self {
+        match error.downcast::<FetchError>() {
+            Ok(fetch_error) => *fetch_error,
+            Err(error) => FetchError::SubrequestHttpError {
+                service: service.to_string(),
+                reason: error.to_string(),
+            },
+        }
+    }
+
+    /// Convert an error of the HTTP client to a fetch error.
+    pub(crate) fn from_hyper_error(service: &str, error: &hyper::Error) -> Self {
+        if error.is_connect() {
+            FetchError::SubrequestConnectError {
+                service: service.to_string(),
+                reason: error.to_string(),
+            }
+        } else {
+            FetchError::SubrequestHttpError {
+                service: service.to_string(),
+                reason: error.to_string(),
+            }
+        }
+    }
+
     /// Convert the fetch error to a GraphQL error.
     pub(crate) fn to_graphql_error(&self, path: Option<Path>) -> Error {
         let value: Value = serde_json::to_value(self).unwrap().into();
-        Error {
-            message: self.to_string(),
-            locations: Default::default(),
-            path,
-            extensions: value.as_object().unwrap().to_owned(),
-        }
+        Error::builder()
+            .message(self.to_string())
+            .and_path(path)
+            .code(self.code())
+            .extensions(value.as_object().unwrap().to_owned())
+            .build()
     }
 
     /// Convert the error to an appropriate response.
@@ -131,6 +286,160 @@ impl FetchError {
     }
 }
 
+/// The codes of the errors sent by the router, in the `code` extension of the GraphQL errors.
+///
+/// The codes are stable, so that clients can handle the errors without matching their messages.
+/// Plugins can send errors with their own codes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[non_exhaustive]
+pub enum ErrorCode {
+    /// The query is not syntactically valid GraphQL.
+    GraphqlParseFailed,
+    /// The query is not valid against the schema, or could not be planned.
+    GraphqlValidationFailed,
+    /// The variables of the query are missing or do not match their type.
+    BadUserInput,
+    /// The HTTP request is not a valid GraphQL request.
+    BadRequest,
+    /// The hash of a persisted query is not known by the router.
+    PersistedQueryNotFound,
+    /// The automatic persisted queries are disabled.
+    PersistedQueryNotSupported,
+    /// The client is not allowed to register persisted queries.
+    PersistedQueryClientNotAllowed,
+    /// The operation is not in the safelist of persisted queries.
+    PersistedQueryNotInSafelist,
+    /// An introspection query exceeds the introspection limits.
+    IntrospectionLimitExceeded,
+    /// The client reached one of its quotas.
+    QuotaExceeded,
+    /// A field of the response was masked for the client.
+    FieldMasked,
+    /// A value of the response does not match its type in the schema.
+    ResponseValidationFailed,
+    /// A request to a subgraph failed.
+    SubrequestFailed,
+    /// The router failed to handle the request.
+    InternalServerError,
+}
+
+impl ErrorCode {
+    /// The code, as sent in the `code` extension.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::GraphqlParseFailed => "GRAPHQL_PARSE_FAILED",
+            ErrorCode::GraphqlValidationFailed => "GRAPHQL_VALIDATION_FAILED",
+            ErrorCode::BadUserInput => "BAD_USER_INPUT",
+            ErrorCode::BadRequest => "BAD_REQUEST",
+            ErrorCode::PersistedQueryNotFound => "PERSISTED_QUERY_NOT_FOUND",
+            ErrorCode::PersistedQueryNotSupported => "PERSISTED_QUERY_NOT_SUPPORTED",
+            ErrorCode::PersistedQueryClientNotAllowed => "PERSISTED_QUERY_CLIENT_NOT_ALLOWED",
+            ErrorCode::PersistedQueryNotInSafelist => "PERSISTED_QUERY_NOT_IN_SAFELIST",
+            ErrorCode::IntrospectionLimitExceeded => "INTROSPECTION_LIMIT_EXCEEDED",
+            ErrorCode::QuotaExceeded => "QUOTA_EXCEEDED",
+            ErrorCode::FieldMasked => "FIELD_MASKED",
+            ErrorCode::ResponseValidationFailed => "RESPONSE_VALIDATION_FAILED",
+            ErrorCode::SubrequestFailed => "SUBREQUEST_FAILED",
+            ErrorCode::InternalServerError => "INTERNAL_SERVER_ERROR",
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Kinds of the failures of the subgraph requests.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum FetchErrorKind {
+    /// The subgraph could not be reached.
+    Connect,
+    /// The subgraph did not respond in time.
+    Timeout,
+    /// The subgraph responded with an error status and no GraphQL response.
+    HttpStatus,
+    /// The response of the subgraph could not be deserialized.
+    Deserialization,
+    /// The subgraph responded with GraphQL errors.
+    Graphql,
+    /// Any other failure.
+    Other,
+}
+
+impl FetchErrorKind {
+    /// The kind of the error of a subgraph service.
+    pub(crate) fn of(error: &BoxError) -> Self {
+        if let Some(fetch_error) = error
+            .downcast_ref::<FetchError>()
+            .or_else(|| error.source().and_then(|e| e.downcast_ref::<FetchError>()))
+        {
+            fetch_error.kind()
+        } else if is_timeout(error) {
+            FetchErrorKind::Timeout
+        } else {
+            FetchErrorKind::Other
+        }
+    }
+
+    /// The kind, as a metrics label.
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            FetchErrorKind::Connect => "connect",
+            FetchErrorKind::Timeout => "timeout",
+            FetchErrorKind::HttpStatus => "http_status",
+            FetchErrorKind::Deserialization => "deserialization",
+            FetchErrorKind::Graphql => "graphql",
+            FetchErrorKind::Other => "other",
+        }
+    }
+}
+
+/// Whether the status of a subgraph response tells the subgraph is overloaded or unavailable,
+/// so that the request may succeed later.
+pub(crate) fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Whether the request to a subgraph may succeed if it is sent again: it failed with a
+/// retryable fetch error, or did not get a response before its timeout.
+pub(crate) fn is_retryable_error(error: &BoxError) -> bool {
+    match error.downcast_ref::<FetchError>() {
+        Some(fetch_error) => fetch_error.is_retryable(),
+        None => is_timeout(error),
+    }
+}
+
+fn is_timeout(error: &BoxError) -> bool {
+    error.is::<Elapsed>() || error.is::<tower::timeout::error::Elapsed>()
+}
+
+/// The delay of the `Retry-After` header of a subgraph response, in seconds or as a date.
+pub(crate) fn retry_after(headers: &http::HeaderMap) -> Option<Duration> {
+    let value = headers
+        .get(http::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => Some(
+            httpdate::parse_http_date(value)
+                .ok()?
+                .duration_since(SystemTime::now())
+                .unwrap_or_default(),
+        ),
+    }
+}
+
 /// A location in the request that triggered a graphql error.
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -168,6 +477,18 @@ impl From<BoxError> for CacheResolverError {
 pub(crate) enum ServiceBuildError {
     /// couldn't build Router Service: {0}
     QueryPlannerError(QueryPlannerError),
+    /// couldn't build contract '{0}': {1}
+    Contract(String, String),
+    /// couldn't build the query planner for override labels [{0}]: {1}
+    ProgressiveOverride(String, String),
+    /// invalid maintenance response: {0}
+    Maintenance(String),
+    /// couldn't poll the operation registry: {0}
+    OperationRegistry(String),
+    /// invalid surrogate key header: {0}
+    SurrogateKeys(String),
+    /// couldn't verify the tokens of the clients: {0}
+    ClientIdentification(String),
 }
 
 /// Error types for QueryPlanner
@@ -177,7 +498,7 @@ pub(crate) enum QueryPlannerError {
     SchemaValidationErrors(PlannerErrors),
 
     /// couldn't plan query: {0}
-    PlanningErrors(PlanErrors),
+    PlanningErrors(PlanErrors, Vec<ValidationError>),
 
     /// query planning panicked: {0}
     JoinError(Arc<JoinError>),
@@ -199,6 +520,19 @@ pub(crate) enum QueryPlannerError {
 
     /// introspection error: {0}
     Introspection(IntrospectionError),
+
+    /// {0}
+    IntrospectionLimit(IntrospectionLimitError),
+}
+
+/// Error types for the introspection queries exceeding the introspection limits
+#[derive(Error, Debug, Display, Clone, PartialEq, Eq)]
+pub(crate) enum IntrospectionLimitError {
+    /// the introspection query nests the `fields`, `interfaces`, `possibleTypes` and `inputFields` fields {0} times, more than the limit of {1}
+    MaxDepth(usize, usize),
+
+    /// the introspection response is longer than the limit of {0} bytes
+    MaxSize(usize),
 }
 
 #[derive(Clone, Debug, Error)]
@@ -224,12 +558,6 @@ impl From<Vec<PlannerError>> for QueryPlannerError {
     }
 }
 
-impl From<PlanErrors> for QueryPlannerError {
-    fn from(errors: PlanErrors) -> Self {
-        QueryPlannerError::PlanningErrors(errors)
-    }
-}
-
 impl From<JoinError> for QueryPlannerError {
     fn from(err: JoinError) -> Self {
         QueryPlannerError::JoinError(Arc::new(err))
@@ -266,6 +594,8 @@ pub(crate) enum SchemaError {
     Parse(ParseErrors),
     /// Api error(s): {0}
     Api(String),
+    /// Contract error: {0}
+    Contract(String),
 }
 
 /// Collection of schema parsing errors.
@@ -309,3 +639,151 @@ impl ParseErrors {
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+
+    use super::*;
+
+    #[test]
+    fn it_builds_errors_with_a_code() {
+        let error = Error::builder()
+            .message("Variable \"$id\" of required type \"ID!\" was not provided.")
+            .location(Location { line: 1, column: 9 })
+            .path(Path::from("user"))
+            .extension("code", "OVERRIDDEN")
+            .extension("name", "id")
+            .code(ErrorCode::BadUserInput)
+            .build();
+        assert_eq!(error.code(), Some("BAD_USER_INPUT"));
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            serde_json::json!({
+                "message": "Variable \"$id\" of required type \"ID!\" was not provided.",
+                "locations": [{ "line": 1, "column": 9 }],
+                "path": ["user"],
+                "extensions": { "code": "BAD_USER_INPUT", "name": "id" },
+            })
+        );
+        assert_eq!(
+            serde_json::to_string(&error.extensions).unwrap(),
+            r#"{"code":"BAD_USER_INPUT","name":"id"}"#
+        );
+        assert_eq!(Error::builder().message("failed").build().code(), None);
+    }
+
+    #[test]
+    fn it_adds_a_code_to_the_fetch_errors() {
+        let error = FetchError::ValidationInvalidTypeVariable {
+            name: "id".to_string(),
+            message: "invalid variable".to_string(),
+        }
+        .to_graphql_error(None);
+        assert_eq!(
+            Value::Object(error.extensions),
+            json!({ "code": "BAD_USER_INPUT", "type": "ValidationInvalidTypeVariable", "name": "id" })
+        );
+        assert_eq!(
+            FetchError::SubrequestHttpError {
+                service: "products".to_string(),
+                reason: "connection refused".to_string(),
+            }
+            .to_graphql_error(None)
+            .code(),
+            Some(ErrorCode::SubrequestFailed.as_str())
+        );
+        assert_eq!(
+            serde_json::to_value(ErrorCode::GraphqlParseFailed).unwrap(),
+            serde_json::json!("GRAPHQL_PARSE_FAILED")
+        );
+    }
+
+    #[test]
+    fn it_tells_the_retryable_fetch_errors() {
+        let connect = FetchError::SubrequestConnectError {
+            service: "products".to_string(),
+            reason: "connection refused".to_string(),
+        };
+        assert_eq!(connect.kind(), FetchErrorKind::Connect);
+        assert!(connect.is_retryable());
+
+        let unavailable = FetchError::SubrequestHttpStatusError {
+            service: "products".to_string(),
+            status: 503,
+            reason: "service unavailable".to_string(),
+            retry_after: Some(Duration::from_secs(1)),
+        };
+        assert_eq!(unavailable.kind(), FetchErrorKind::HttpStatus);
+        assert!(unavailable.is_retryable());
+        let not_found = FetchError::SubrequestHttpStatusError {
+            service: "products".to_string(),
+            status: 404,
+            reason: "not found".to_string(),
+            retry_after: None,
+        };
+        assert!(!not_found.is_retryable());
+        assert_eq!(unavailable.retry_after(), Some(Duration::from_secs(1)));
+        assert_eq!(not_found.retry_after(), None);
+
+        let deadline = FetchError::SubrequestDeadlineExceeded {
+            service: "products".to_string(),
+        };
+        assert_eq!(deadline.kind(), FetchErrorKind::Timeout);
+        assert!(!deadline.is_retryable());
+
+        let malformed = FetchError::SubrequestMalformedResponse {
+            service: "products".to_string(),
+            reason: "expected value".to_string(),
+        };
+        assert_eq!(malformed.kind(), FetchErrorKind::Deserialization);
+        assert!(!malformed.is_retryable());
+    }
+
+    #[test]
+    fn it_converts_the_service_errors() {
+        let error: BoxError = Box::new(FetchError::SubrequestConnectError {
+            service: "products".to_string(),
+            reason: "connection refused".to_string(),
+        });
+        assert_eq!(FetchErrorKind::of(&error), FetchErrorKind::Connect);
+        assert!(matches!(
+            FetchError::from_service_error("products", error),
+            FetchError::SubrequestConnectError { .. }
+        ));
+
+        let error: BoxError = Box::new(tower::timeout::error::Elapsed::new());
+        assert_eq!(FetchErrorKind::of(&error), FetchErrorKind::Timeout);
+        assert!(is_retryable_error(&error));
+        // the clients see the same message as before
+        assert_eq!(
+            FetchError::from_service_error("products", error).to_string(),
+            "HTTP fetch failed from 'products': request timed out"
+        );
+
+        let error: BoxError = "broken pipe".into();
+        assert_eq!(FetchErrorKind::of(&error), FetchErrorKind::Other);
+        assert_eq!(
+            FetchError::from_service_error("products", error).to_string(),
+            "HTTP fetch failed from 'products': broken pipe"
+        );
+    }
+
+    #[test]
+    fn it_reads_the_retry_after_header() {
+        let mut headers = http::HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(http::header::RETRY_AFTER, "120".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(3600));
+        headers.insert(http::header::RETRY_AFTER, date.parse().unwrap());
+        assert!(retry_after(&headers).unwrap() > Duration::from_secs(3500));
+        headers.insert(
+            http::header::RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+        headers.insert(http::header::RETRY_AFTER, "soon".parse().unwrap());
+        assert_eq!(retry_after(&headers), None);
+    }
+}
@@ -4,7 +4,9 @@ use std::env;
 use std::ffi::OsStr;
 use std::ffi::OsString;
 use std::fmt;
+use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Mutex;
 use std::time::Duration;
 
 use anyhow::anyhow;
@@ -13,25 +15,99 @@ use anyhow::Result;
 use clap::AppSettings;
 use clap::CommandFactory;
 use clap::Parser;
+use clap::Subcommand;
 use directories::ProjectDirs;
+use futures::StreamExt;
+use once_cell::sync::Lazy;
 use once_cell::sync::OnceCell;
+use tower::BoxError;
 use tracing::dispatcher::with_default;
 use tracing::dispatcher::Dispatch;
 use tracing::instrument::WithSubscriber;
+use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
+use tracing_subscriber::reload;
 use tracing_subscriber::EnvFilter;
+use tracing_subscriber::Registry;
 use url::ParseError;
 use url::Url;
 
+use crate::check;
 use crate::configuration::generate_config_schema;
 use crate::configuration::Configuration;
 use crate::configuration::ConfigurationError;
+use crate::http_server_factory::InheritedListener;
+use crate::kubernetes;
+use crate::kubernetes::ObjectReference;
+use crate::panics;
+use crate::plan_diff;
+use crate::plugins::telemetry::scrubbing::ScrubbedStdout;
+use crate::plugins::telemetry::scrubbing::ScrubbingHandle;
+use crate::router::ApolloRouterError;
 use crate::router::ConfigurationSource;
 use crate::router::RouterHttpServer;
 use crate::router::SchemaSource;
 use crate::router::ShutdownSource;
+use crate::supervisor;
+#[cfg(unix)]
+use crate::systemd;
+
+/// In development mode, the interval between two introspections of the subgraphs.
+const DEV_POLL_INTERVAL: Duration = Duration::from_secs(5);
 
 pub(crate) static GLOBAL_ENV_FILTER: OnceCell<String> = OnceCell::new();
 
+/// Failure of the router after it started serving, a supervised worker is restarted on it.
+#[derive(Debug, thiserror::Error)]
+#[error(transparent)]
+struct RuntimeFailure(ApolloRouterError);
+
+/// Handles on the log filters of the subscribers installed by the router, so that the log level
+/// can be changed at runtime.
+static LOG_FILTER_HANDLES: Lazy<Mutex<Vec<reload::Handle<EnvFilter, Registry>>>> =
+    Lazy::new(Default::default);
+
+/// Log level set through [`set_log_level`], if any.
+static CURRENT_LOG_LEVEL: Lazy<Mutex<Option<String>>> = Lazy::new(Default::default);
+
+/// Create a log filter layer that will follow the changes made through [`set_log_level`].
+pub(crate) fn reloadable_log_filter(
+    log_level: &str,
+) -> Result<reload::Layer<EnvFilter, Registry>, BoxError> {
+    let (filter, handle) = reload::Layer::new(EnvFilter::try_new(log_level)?);
+    LOG_FILTER_HANDLES
+        .lock()
+        .expect("lock poisoned")
+        .push(handle);
+    Ok(filter)
+}
+
+/// Replace the log filter of every subscriber installed by the router.
+pub(crate) fn set_log_level(log_level: &str) -> Result<(), BoxError> {
+    // parse the filter first so that an invalid one does not leave subscribers half updated
+    EnvFilter::try_new(log_level)?;
+    LOG_FILTER_HANDLES
+        .lock()
+        .expect("lock poisoned")
+        // subscribers that were dropped can not be reloaded anymore
+        .retain(|handle| {
+            EnvFilter::try_new(log_level)
+                .map(|filter| handle.reload(filter).is_ok())
+                .unwrap_or(true)
+        });
+    *CURRENT_LOG_LEVEL.lock().expect("lock poisoned") = Some(log_level.to_string());
+    Ok(())
+}
+
+/// The log level currently in use.
+pub(crate) fn current_log_level() -> String {
+    CURRENT_LOG_LEVEL
+        .lock()
+        .expect("lock poisoned")
+        .clone()
+        .or_else(|| GLOBAL_ENV_FILTER.get().cloned())
+        .unwrap_or_else(|| "info".to_string())
+}
+
 /// Options for the router
 #[derive(Parser, Debug)]
 #[clap(
@@ -71,10 +147,63 @@ pub(crate) struct Opt {
     )]
     supergraph_path: Option<PathBuf>,
 
+    /// Compose the supergraph from the subgraphs listed in this file, for local development.
+    /// Uses the `rover supergraph compose` configuration format, and requires rover.
+    #[clap(
+        long = "supergraph-config",
+        parse(from_os_str),
+        env = "APOLLO_ROUTER_SUPERGRAPH_CONFIG_PATH",
+        conflicts_with = "supergraph-path"
+    )]
+    supergraph_config_path: Option<PathBuf>,
+
+    /// ConfigMap or Secret key holding the configuration, watched through the Kubernetes API:
+    /// `configmap:[<namespace>/]<name>/<key>` or `secret:[<namespace>/]<name>/<key>`.
+    #[clap(
+        long = "kubernetes-config",
+        env = "APOLLO_ROUTER_KUBERNETES_CONFIG",
+        conflicts_with = "config-path"
+    )]
+    kubernetes_config: Option<ObjectReference>,
+
+    /// ConfigMap or Secret key holding the supergraph schema, watched through the Kubernetes API:
+    /// `configmap:[<namespace>/]<name>/<key>` or `secret:[<namespace>/]<name>/<key>`.
+    #[clap(
+        long = "kubernetes-supergraph",
+        env = "APOLLO_ROUTER_KUBERNETES_SUPERGRAPH",
+        conflicts_with_all = &["supergraph-path", "supergraph-config-path"]
+    )]
+    kubernetes_supergraph: Option<ObjectReference>,
+
+    /// Development mode: compose the supergraph from `--supergraph-config`, introspect the
+    /// subgraphs listed with a `subgraph_url` for changes, reload files automatically, and enable
+    /// the landing page, introspection and subgraph errors.
+    #[clap(long, env = "APOLLO_ROUTER_DEV", requires = "supergraph-config-path")]
+    dev: bool,
+
+    /// Supervision mode: run the router in a worker process, restarted with an exponential
+    /// backoff when it crashes.
+    #[clap(long, env = "APOLLO_ROUTER_SUPERVISE")]
+    supervise: bool,
+
     /// Prints the configuration schema.
     #[clap(long)]
     schema: bool,
 
+    /// Plan the operations of this file, or of the `.graphql` files of this directory, with both
+    /// the `--supergraph` and the `--candidate-supergraph` schemas, print the operations whose plan
+    /// changed or broke, and exit. Exits with an error if an operation broke.
+    #[clap(
+        long = "diff-plans",
+        parse(from_os_str),
+        requires_all = &["supergraph-path", "candidate-supergraph-path"]
+    )]
+    diff_plans_path: Option<PathBuf>,
+
+    /// Candidate supergraph schema compared with `--supergraph` by `--diff-plans`.
+    #[clap(long = "candidate-supergraph", parse(from_os_str))]
+    candidate_supergraph_path: Option<PathBuf>,
+
     /// Your Apollo key.
     #[clap(skip = std::env::var("APOLLO_KEY").ok())]
     apollo_key: Option<String>,
@@ -95,6 +224,49 @@ pub(crate) struct Opt {
     /// Display version and exit.
     #[clap(parse(from_flag), long, short = 'V')]
     pub(crate) version: bool,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the query plans of operations, in JSON, and exit. Exits with an error if an operation
+    /// can not be planned.
+    Plan {
+        /// Supergraph schema the operations are planned with.
+        #[clap(long = "schema", alias = "supergraph", parse(from_os_str))]
+        schema_path: PathBuf,
+
+        /// The operations of this file, or of the `.graphql` files of this directory.
+        #[clap(long = "query", parse(from_os_str))]
+        query_path: PathBuf,
+
+        /// Configuration location relative to the project directory.
+        #[clap(short, long = "config", parse(from_os_str))]
+        config_path: Option<PathBuf>,
+    },
+    /// Load the configuration and the supergraph, initialize every plugin, and exit, e.g. to gate
+    /// a rollout in CI. Exits with an error if the router can not start, if a subgraph does not
+    /// answer with `--ping-subgraphs`, or if a sample operation can not be planned.
+    Check {
+        /// Supergraph schema loaded by the router.
+        #[clap(long = "schema", alias = "supergraph", parse(from_os_str))]
+        schema_path: PathBuf,
+
+        /// Configuration location relative to the project directory.
+        #[clap(short, long = "config", parse(from_os_str))]
+        config_path: Option<PathBuf>,
+
+        /// Sample operations planned with the supergraph: this file, or the `.graphql` files of
+        /// this directory.
+        #[clap(long = "query", parse(from_os_str))]
+        query_path: Option<PathBuf>,
+
+        /// Send a `{ __typename }` query to every subgraph.
+        #[clap(long)]
+        ping_subgraphs: bool,
+    },
 }
 
 /// Wrapper so that structop can display the default config path in the help message.
@@ -138,6 +310,13 @@ impl fmt::Display for ProjectDir {
 ///
 /// Refer to the examples if you would like to see how to run your own router with plugins.
 pub fn main() -> Result<()> {
+    // the environment is only changed safely before the runtime starts its threads
+    #[cfg(unix)]
+    let inherited_listener =
+        systemd::take_activated_listener().context("could not use the socket passed by systemd")?;
+    #[cfg(not(unix))]
+    let inherited_listener = None;
+
     let mut builder = tokio::runtime::Builder::new_multi_thread();
     builder.enable_all();
     if let Some(nb) = std::env::var("APOLLO_ROUTER_NUM_CORES")
@@ -147,7 +326,7 @@ pub fn main() -> Result<()> {
         builder.worker_threads(nb);
     }
     let runtime = builder.build()?;
-    runtime.block_on(Executable::builder().start())
+    runtime.block_on(Executable::start_with_listener(None, inherited_listener))
 }
 
 /// Entry point into creating a router executable.
@@ -176,6 +355,15 @@ impl Executable {
     ///
     #[builder(entry = "builder", exit = "start", visibility = "pub")]
     async fn start(shutdown: Option<ShutdownSource>) -> Result<()> {
+        Self::start_with_listener(shutdown, None).await
+    }
+
+    /// Start like [`Executable::start`], serving on the listener inherited by the router process
+    /// if there is one.
+    async fn start_with_listener(
+        shutdown: Option<ShutdownSource>,
+        inherited_listener: Option<InheritedListener>,
+    ) -> Result<()> {
         let opt = Opt::parse();
 
         if opt.version {
@@ -185,45 +373,93 @@ impl Executable {
 
         copy_args_to_env();
 
+        match &opt.command {
+            Some(Command::Plan {
+                schema_path,
+                query_path,
+                config_path,
+            }) => return plan(schema_path, query_path, config_path).await,
+            Some(Command::Check {
+                schema_path,
+                config_path,
+                query_path,
+                ping_subgraphs,
+            }) => return self_check(schema_path, config_path, query_path, *ping_subgraphs).await,
+            None => {}
+        }
+
         if opt.schema {
             let schema = generate_config_schema();
             println!("{}", serde_json::to_string_pretty(&schema)?);
             return Ok(());
         }
 
-        let builder = tracing_subscriber::fmt::fmt().with_env_filter(
-            EnvFilter::try_new(&opt.log_level).context("could not parse log configuration")?,
+        if let Some(operations_path) = &opt.diff_plans_path {
+            return diff_plans(&opt, operations_path).await;
+        }
+
+        let registry = Registry::default().with(
+            reloadable_log_filter(&opt.log_level)
+                .map_err(|e| anyhow!(e))
+                .context("could not parse log configuration")?,
         );
 
         let dispatcher = if atty::is(atty::Stream::Stdout) {
-            Dispatch::new(builder.finish())
+            Dispatch::new(
+                registry.with(
+                    tracing_subscriber::fmt::layer()
+                        .with_writer(ScrubbedStdout::new(ScrubbingHandle::router())),
+                ),
+            )
         } else {
-            Dispatch::new(builder.json().finish())
+            Dispatch::new(
+                registry.with(
+                    tracing_subscriber::fmt::layer()
+                        .json()
+                        .with_writer(ScrubbedStdout::new(ScrubbingHandle::router())),
+                ),
+            )
         };
 
         GLOBAL_ENV_FILTER.set(opt.log_level.clone()).expect(
             "failed setting the global env filter. THe start() function should only be called once",
         );
 
+        // the worker processes are started with the same arguments, and run the router
+        if opt.supervise && supervisor::worker_restarts().is_none() {
+            return supervisor::supervise(shutdown.unwrap_or(ShutdownSource::CtrlC))
+                .with_subscriber(dispatcher)
+                .await
+                .map_err(Into::into);
+        }
+
         // The dispatcher we created is passed explicitely here to make sure we display the logs
         // in the initialization pahse and in the state machine code, before a global subscriber
         // is set using the configuration file
-        Self::inner_start(shutdown, opt, dispatcher.clone())
+        let result = Self::inner_start(shutdown, opt, dispatcher.clone(), inherited_listener)
             .with_subscriber(dispatcher)
-            .await
+            .await;
+        // the supervisor does not restart the workers which could not start, but restarts the
+        // ones which failed once they were serving
+        if let (Err(e), Some(_)) = (&result, supervisor::worker_restarts()) {
+            if !e.is::<RuntimeFailure>() {
+                eprintln!("{}", e);
+                std::process::exit(supervisor::STARTUP_FAILURE_EXIT_CODE);
+            }
+        }
+        result
     }
 
     async fn inner_start(
         shutdown: Option<ShutdownSource>,
         opt: Opt,
         dispatcher: Dispatch,
+        inherited_listener: Option<InheritedListener>,
     ) -> Result<()> {
         let current_directory = std::env::current_dir()?;
 
-        let configuration = opt
-            .config_path
-            .as_ref()
-            .map(|path| {
+        let configuration = match (&opt.config_path, &opt.kubernetes_config) {
+            (Some(path), _) => {
                 let path = if path.is_relative() {
                     current_directory.join(path)
                 } else {
@@ -232,15 +468,51 @@ impl Executable {
 
                 ConfigurationSource::File {
                     path,
-                    watch: opt.hot_reload,
+                    watch: opt.hot_reload || opt.dev,
                     delay: None,
                 }
-            })
-            .unwrap_or_else(|| Configuration::builder().build().into());
+            }
+            (None, Some(reference)) => ConfigurationSource::Stream(
+                kubernetes::watch_configuration(reference.clone())?.boxed(),
+            ),
+            (None, None) => Configuration::builder().build().into(),
+        };
+        let configuration = if opt.dev {
+            configuration.with_dev_mode()
+        } else {
+            configuration
+        };
 
         let apollo_router_msg = format!("Apollo Router v{} // (c) Apollo Graph, Inc. // Licensed as ELv2 (https://go.apollo.dev/elv2)", std::env!("CARGO_PKG_VERSION"));
-        let schema = match (opt.supergraph_path, opt.apollo_key) {
-            (Some(supergraph_path), _) => {
+        let schema = match (
+            opt.supergraph_path,
+            opt.supergraph_config_path,
+            opt.apollo_key,
+            opt.kubernetes_supergraph,
+        ) {
+            (_, _, _, Some(reference)) => {
+                tracing::info!("{apollo_router_msg}");
+                setup_panic_handler(dispatcher.clone());
+
+                SchemaSource::Stream(kubernetes::watch(reference)?.boxed())
+            }
+            (_, Some(supergraph_config_path), _, _) => {
+                tracing::info!("{apollo_router_msg}");
+                setup_panic_handler(dispatcher.clone());
+
+                let supergraph_config_path = if supergraph_config_path.is_relative() {
+                    current_directory.join(supergraph_config_path)
+                } else {
+                    supergraph_config_path
+                };
+                SchemaSource::Subgraphs {
+                    path: supergraph_config_path,
+                    watch: opt.hot_reload || opt.dev,
+                    delay: None,
+                    poll_interval: opt.dev.then(|| DEV_POLL_INTERVAL),
+                }
+            }
+            (Some(supergraph_path), _, _, _) => {
                 tracing::info!("{apollo_router_msg}");
                 setup_panic_handler(dispatcher.clone());
 
@@ -255,7 +527,7 @@ impl Executable {
                     delay: None,
                 }
             }
-            (None, Some(apollo_key)) => {
+            (None, None, Some(apollo_key), _) => {
                 tracing::info!("{apollo_router_msg}");
 
                 let apollo_graph_ref = opt.apollo_graph_ref.ok_or_else(||anyhow!("cannot fetch the supergraph from Apollo Studio without setting the APOLLO_GRAPH_REF environment variable"))?;
@@ -294,6 +566,11 @@ impl Executable {
 
       $ ./router --supergraph <file_path>
 
+  * Compose the supergraph from a list of subgraphs with the
+    '--supergraph-config' option (requires rover):
+
+      $ ./router --supergraph-config <file_path>
+
   * Fetch a registered schema from Apollo Studio by setting
     these environment variables:
 
@@ -317,13 +594,19 @@ impl Executable {
             }
         };
 
-        let router = RouterHttpServer::builder()
-            .configuration(configuration)
-            .schema(schema)
-            .shutdown(shutdown.unwrap_or(ShutdownSource::CtrlC))
-            .start();
+        let router = RouterHttpServer::start_with_listener(
+            schema,
+            Some(configuration),
+            Some(shutdown.unwrap_or(ShutdownSource::CtrlC)),
+            inherited_listener,
+        );
+        // resolves once the router first serves requests, or once it stopped without serving
+        let started = router.listen_address().await.is_ok();
         if let Err(err) = router.await {
             tracing::error!("{}", err);
+            if started {
+                return Err(RuntimeFailure(err).into());
+            }
             return Err(err.into());
         }
         Ok(())
@@ -340,6 +623,10 @@ fn setup_panic_handler(dispatcher: Dispatch) {
     }
     std::panic::set_hook(Box::new(move |e| {
         with_default(&dispatcher, || {
+            // the panic of a request is answered with an error, the router keeps running
+            if panics::report_request_panic(e) {
+                return;
+            }
             if show_backtraces {
                 let backtrace = backtrace::Backtrace::new();
                 tracing::error!("{}\n{:?}", e, backtrace)
@@ -353,6 +640,95 @@ fn setup_panic_handler(dispatcher: Dispatch) {
     }));
 }
 
+/// Compare the query plans of the operations between the base and candidate supergraphs.
+async fn diff_plans(opt: &Opt, operations_path: &Path) -> Result<()> {
+    let read_schema = |path: &Option<PathBuf>| -> Result<String> {
+        let path = path
+            .as_ref()
+            .ok_or_else(|| anyhow!("--diff-plans requires two supergraphs"))?;
+        std::fs::read_to_string(path)
+            .with_context(|| format!("could not read the supergraph at {}", path.display()))
+    };
+    let base = read_schema(&opt.supergraph_path)?;
+    let candidate = read_schema(&opt.candidate_supergraph_path)?;
+    let configuration = read_configuration(&opt.config_path)?;
+
+    let operations = plan_diff::read_operations(operations_path)?;
+    let report = plan_diff::diff_plans(configuration, &base, &candidate, operations).await?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    if report.broken.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{} operation(s) can not be planned with the candidate supergraph",
+            report.broken.len()
+        ))
+    }
+}
+
+/// Print the query plans of the operations.
+async fn plan(
+    schema_path: &Path,
+    operations_path: &Path,
+    config_path: &Option<PathBuf>,
+) -> Result<()> {
+    let schema = std::fs::read_to_string(schema_path)
+        .with_context(|| format!("could not read the supergraph at {}", schema_path.display()))?;
+    let configuration = read_configuration(config_path)?;
+
+    let operations = plan_diff::read_operations(operations_path)?;
+    let planned = plan_diff::plan_operations(configuration, &schema, operations).await?;
+    println!("{}", serde_json::to_string_pretty(&planned)?);
+
+    let failed = planned
+        .iter()
+        .filter(|operation| !operation.errors.is_empty())
+        .count();
+    if failed == 0 {
+        Ok(())
+    } else {
+        Err(anyhow!("{} operation(s) can not be planned", failed))
+    }
+}
+
+/// Check that the router can start, and that it can reach the subgraphs and plan the sample
+/// operations.
+async fn self_check(
+    schema_path: &Path,
+    config_path: &Option<PathBuf>,
+    operations_path: &Option<PathBuf>,
+    ping_subgraphs: bool,
+) -> Result<()> {
+    let schema = std::fs::read_to_string(schema_path)
+        .with_context(|| format!("could not read the supergraph at {}", schema_path.display()))?;
+    let configuration = read_configuration(config_path)?;
+    let operations = match operations_path {
+        Some(path) => plan_diff::read_operations(path)?,
+        None => Vec::new(),
+    };
+
+    let report = check::check(configuration, &schema, operations, ping_subgraphs).await?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    let failures = report.failures();
+    if failures == 0 {
+        Ok(())
+    } else {
+        Err(anyhow!("{} check(s) failed", failures))
+    }
+}
+
+fn read_configuration(path: &Option<PathBuf>) -> Result<Configuration> {
+    match path {
+        Some(path) => std::fs::read_to_string(path)
+            .with_context(|| format!("could not read the configuration at {}", path.display()))?
+            .parse::<Configuration>()
+            .context("invalid configuration"),
+        None => Ok(Configuration::default()),
+    }
+}
+
 fn copy_args_to_env() {
     // Copy all the args to env.
     // This way, Clap is still responsible for the definitive view of what the current options are.
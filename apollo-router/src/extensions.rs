@@ -0,0 +1,340 @@
+//! Typed accessors of the well-known extensions of the GraphQL requests and responses.
+//!
+//! The extensions are still stored as JSON in [`Request::extensions`] and
+//! [`Response::extensions`], so that the unknown ones are forwarded as is. The accessors read
+//! them in place, without deserializing them, and return `None` if they are missing or malformed.
+//! The optional fields of an extension are `None` when they are malformed.
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::json_ext::Object;
+use crate::json_ext::Value;
+use crate::request::Request;
+use crate::response::Response;
+
+/// Name of the request extension of the automatic persisted queries.
+pub const PERSISTED_QUERY_EXTENSION: &str = "persistedQuery";
+/// Name of the request extension identifying the client library.
+pub const CLIENT_LIBRARY_EXTENSION: &str = "clientLibrary";
+/// Name of the request extension with which clients request the `fail_fast` partial results
+/// policy.
+pub const STRICT_EXTENSION: &str = "strict";
+/// Name of the response extension with the timings of the request.
+pub const TRACING_EXTENSION: &str = "tracing";
+
+/// The `persistedQuery` extension of the requests sending the hash of their query.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PersistedQuery {
+    /// The version of the protocol, `1`.
+    pub version: u64,
+
+    /// The SHA-256 hash of the query, in hexadecimal.
+    #[serde(rename = "sha256Hash")]
+    pub sha256_hash: String,
+}
+
+impl PersistedQuery {
+    /// A persisted query of the version of the protocol supported by the router.
+    pub fn new(sha256_hash: impl Into<String>) -> Self {
+        Self {
+            version: 1,
+            sha256_hash: sha256_hash.into(),
+        }
+    }
+
+    /// Read the extension from its JSON value.
+    pub(crate) fn from_extension(value: &Value) -> Option<Self> {
+        let object = value.as_object()?;
+        Some(Self {
+            version: object.get("version")?.as_u64()?,
+            sha256_hash: string(object.get("sha256Hash"))?,
+        })
+    }
+}
+
+/// The `clientLibrary` extension identifying the client of a request.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ClientLibrary {
+    /// The name of the client.
+    pub name: String,
+
+    /// The version of the client.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub version: Option<String>,
+}
+
+impl ClientLibrary {
+    /// A client library of the given name and version.
+    pub fn new(name: impl Into<String>, version: Option<String>) -> Self {
+        Self {
+            name: name.into(),
+            version,
+        }
+    }
+
+    /// Read the extension from its JSON value.
+    pub(crate) fn from_extension(value: &Value) -> Option<Self> {
+        let object = value.as_object()?;
+        Some(Self {
+            name: string(object.get("name"))?,
+            version: string(object.get("version")),
+        })
+    }
+}
+
+/// The `tracing` extension of the responses, with the timings of the request in nanoseconds.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Tracing {
+    /// The version of the format, `1`.
+    pub version: u64,
+
+    /// The start of the request, in RFC 3339 format.
+    pub start_time: String,
+
+    /// The end of the request, in RFC 3339 format.
+    pub end_time: String,
+
+    /// The duration of the request.
+    pub duration: u64,
+
+    /// The planning of the query, including its parsing and validation.
+    #[serde(default)]
+    pub planning: Option<Timing>,
+
+    /// The fetches from the subgraphs.
+    #[serde(default)]
+    pub subgraphs: Vec<SubgraphTiming>,
+}
+
+/// A step of a request, its start being relative to the start of the request.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Timing {
+    /// The start of the step.
+    pub start_offset: u64,
+
+    /// The duration of the step.
+    pub duration: u64,
+}
+
+/// A fetch from a subgraph, its start being relative to the start of the request.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubgraphTiming {
+    /// The name of the subgraph.
+    pub name: String,
+
+    /// The start of the fetch.
+    pub start_offset: u64,
+
+    /// The duration of the fetch.
+    pub duration: u64,
+}
+
+impl Tracing {
+    /// Read the extension from its JSON value.
+    pub(crate) fn from_extension(value: &Value) -> Option<Self> {
+        let object = value.as_object()?;
+        let number = |name: &str| object.get(name)?.as_u64();
+        Some(Self {
+            version: number("version")?,
+            start_time: string(object.get("startTime"))?,
+            end_time: string(object.get("endTime"))?,
+            duration: number("duration")?,
+            planning: object.get("planning").and_then(Timing::from_extension),
+            subgraphs: object
+                .get("subgraphs")
+                .and_then(Value::as_array)
+                .map(|subgraphs| {
+                    subgraphs
+                        .iter()
+                        .filter_map(SubgraphTiming::from_extension)
+                        .collect()
+                })
+                .unwrap_or_default(),
+        })
+    }
+}
+
+impl Timing {
+    fn from_extension(value: &Value) -> Option<Self> {
+        let object = value.as_object()?;
+        Some(Self {
+            start_offset: object.get("startOffset")?.as_u64()?,
+            duration: object.get("duration")?.as_u64()?,
+        })
+    }
+}
+
+impl SubgraphTiming {
+    fn from_extension(value: &Value) -> Option<Self> {
+        let object = value.as_object()?;
+        Some(Self {
+            name: string(object.get("name"))?,
+            start_offset: object.get("startOffset")?.as_u64()?,
+            duration: object.get("duration")?.as_u64()?,
+        })
+    }
+}
+
+fn string(value: Option<&Value>) -> Option<String> {
+    value?.as_str().map(str::to_string)
+}
+
+fn set<T: Serialize>(extensions: &mut Object, name: &'static str, extension: &T) {
+    extensions.insert(
+        name,
+        serde_json_bytes::to_value(extension).expect("extensions are serializable; qed"),
+    );
+}
+
+impl Request {
+    /// The `persistedQuery` extension, if any.
+    pub fn persisted_query(&self) -> Option<PersistedQuery> {
+        self.extensions
+            .get(PERSISTED_QUERY_EXTENSION)
+            .and_then(PersistedQuery::from_extension)
+    }
+
+    /// Replace the `persistedQuery` extension.
+    pub fn set_persisted_query(&mut self, persisted_query: &PersistedQuery) {
+        set(
+            &mut self.extensions,
+            PERSISTED_QUERY_EXTENSION,
+            persisted_query,
+        )
+    }
+
+    /// The `clientLibrary` extension, if any.
+    pub fn client_library(&self) -> Option<ClientLibrary> {
+        self.extensions
+            .get(CLIENT_LIBRARY_EXTENSION)
+            .and_then(ClientLibrary::from_extension)
+    }
+
+    /// Replace the `clientLibrary` extension.
+    pub fn set_client_library(&mut self, client_library: &ClientLibrary) {
+        set(
+            &mut self.extensions,
+            CLIENT_LIBRARY_EXTENSION,
+            client_library,
+        )
+    }
+
+    /// The `strict` extension, if any: whether the client requested the `fail_fast` partial
+    /// results policy.
+    pub fn strict(&self) -> Option<bool> {
+        self.extensions.get(STRICT_EXTENSION)?.as_bool()
+    }
+}
+
+impl Response {
+    /// The `tracing` extension, if any.
+    pub fn tracing(&self) -> Option<Tracing> {
+        self.extensions
+            .get(TRACING_EXTENSION)
+            .and_then(Tracing::from_extension)
+    }
+
+    /// Replace the `tracing` extension.
+    pub fn set_tracing(&mut self, tracing: &Tracing) {
+        set(&mut self.extensions, TRACING_EXTENSION, tracing)
+    }
+}
+
+/// Add the typed extensions given to a request builder to its other extensions.
+pub(crate) fn with_request_extensions(
+    mut extensions: Object,
+    persisted_query: Option<PersistedQuery>,
+    client_library: Option<ClientLibrary>,
+) -> Object {
+    if let Some(persisted_query) = persisted_query {
+        set(&mut extensions, PERSISTED_QUERY_EXTENSION, &persisted_query);
+    }
+    if let Some(client_library) = client_library {
+        set(&mut extensions, CLIENT_LIBRARY_EXTENSION, &client_library);
+    }
+    extensions
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+
+    use super::*;
+
+    #[test]
+    fn it_reads_and_writes_the_request_extensions() {
+        let request = Request::builder()
+            .query("{ me { name } }")
+            .persisted_query(PersistedQuery::new("ecf4edb4"))
+            .client_library(ClientLibrary::new("ios", Some("2.1.0".to_string())))
+            .extension("other", json!(true))
+            .build();
+        assert_eq!(
+            serde_json_bytes::Value::Object(request.extensions.clone()),
+            json!({
+                "other": true,
+                "persistedQuery": { "version": 1, "sha256Hash": "ecf4edb4" },
+                "clientLibrary": { "name": "ios", "version": "2.1.0" },
+            })
+        );
+        assert_eq!(
+            request.persisted_query(),
+            Some(PersistedQuery::new("ecf4edb4"))
+        );
+        assert_eq!(
+            request.client_library().unwrap().version.as_deref(),
+            Some("2.1.0")
+        );
+
+        let mut request = Request::builder()
+            .extension("persistedQuery", json!({ "version": "one" }))
+            .build();
+        assert_eq!(request.persisted_query(), None);
+        assert_eq!(request.client_library(), None);
+        request.set_client_library(&ClientLibrary::new("web", None));
+        assert_eq!(
+            request.extensions.get("clientLibrary"),
+            Some(&json!({ "name": "web" }))
+        );
+
+        // a malformed optional field is ignored
+        let request = Request::builder()
+            .extension("clientLibrary", json!({ "name": "web", "version": 2 }))
+            .build();
+        assert_eq!(
+            request.client_library(),
+            Some(ClientLibrary::new("web", None))
+        );
+    }
+
+    #[test]
+    fn it_reads_and_writes_the_tracing_extension() {
+        let mut response = Response::builder().build();
+        assert_eq!(response.tracing(), None);
+        let tracing = Tracing {
+            version: 1,
+            start_time: "2022-09-21T10:00:00.000Z".to_string(),
+            end_time: "2022-09-21T10:00:00.100Z".to_string(),
+            duration: 100_000_000,
+            planning: Some(Timing {
+                start_offset: 0,
+                duration: 10_000_000,
+            }),
+            subgraphs: vec![SubgraphTiming {
+                name: "products".to_string(),
+                start_offset: 20_000_000,
+                duration: 50_000_000,
+            }],
+        };
+        response.set_tracing(&tracing);
+        assert_eq!(
+            response.extensions.get("tracing").unwrap()["subgraphs"][0]["startOffset"],
+            json!(20_000_000)
+        );
+        assert_eq!(response.tracing(), Some(tracing));
+    }
+}
@@ -58,6 +58,19 @@ pub(crate) fn watch(path: PathBuf, delay: Option<Duration>) -> impl Stream<Item
         .boxed()
 }
 
+/// Creates a stream of events whenever the process receives a SIGHUP signal. The stream never
+/// terminates.
+#[cfg(unix)]
+pub(crate) fn sighup() -> impl Stream<Item = ()> {
+    match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(mut signal) => stream::poll_fn(move |cx| signal.poll_recv(cx)).boxed(),
+        Err(e) => {
+            tracing::error!("failed to install SIGHUP signal handler: {}", e);
+            stream::pending().boxed()
+        }
+    }
+}
+
 #[cfg(test)]
 pub(crate) mod tests {
     use std::env::temp_dir;
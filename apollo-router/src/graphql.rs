@@ -10,8 +10,18 @@ use serde_json_bytes::ByteString;
 use serde_json_bytes::Map as JsonMap;
 use serde_json_bytes::Value;
 
+pub use crate::error::ErrorCode;
 use crate::error::FetchError;
 pub use crate::error::Location;
+pub use crate::extensions::ClientLibrary;
+pub use crate::extensions::PersistedQuery;
+pub use crate::extensions::SubgraphTiming;
+pub use crate::extensions::Timing;
+pub use crate::extensions::Tracing;
+pub use crate::extensions::CLIENT_LIBRARY_EXTENSION;
+pub use crate::extensions::PERSISTED_QUERY_EXTENSION;
+pub use crate::extensions::STRICT_EXTENSION;
+pub use crate::extensions::TRACING_EXTENSION;
 use crate::json_ext::Object;
 use crate::json_ext::Path;
 pub use crate::json_ext::Path as JsonPath;
@@ -41,14 +51,26 @@ pub struct Error {
 
 #[buildstructor::buildstructor]
 impl Error {
+    /// The `code` is added to the extensions, before the other ones.
     #[builder(visibility = "pub")]
     fn new(
         message: String,
         locations: Vec<Location>,
         path: Option<Path>,
+        code: Option<ErrorCode>,
         // Skip the `Object` type alias in order to use buildstructor’s map special-casing
         extensions: JsonMap<ByteString, Value>,
     ) -> Self {
+        let extensions = match code {
+            Some(code) => std::iter::once(("code".into(), code.as_str().into()))
+                .chain(
+                    extensions
+                        .into_iter()
+                        .filter(|(key, _)| key.as_str() != "code"),
+                )
+                .collect(),
+            None => extensions,
+        };
         Self {
             message,
             locations,
@@ -57,6 +79,11 @@ impl Error {
         }
     }
 
+    /// The `code` extension of the error, if any: an [`ErrorCode`] for the errors of the router.
+    pub fn code(&self) -> Option<&str> {
+        self.extensions.get("code").and_then(|code| code.as_str())
+    }
+
     pub(crate) fn from_value(service_name: &str, value: Value) -> Result<Error, FetchError> {
         let mut object =
             ensure_object!(value).map_err(|error| FetchError::SubrequestMalformedResponse {
@@ -0,0 +1,267 @@
+//! Compliance with the [GraphQL-over-HTTP specification](https://graphql.github.io/graphql-over-http/draft/).
+//!
+//! In `strict` mode, the media type of the responses is negotiated with the `Accept` header of the
+//! requests, between `application/graphql-response+json` and `application/json`, and the status
+//! code of the responses follows the semantics of this media type: the request errors preventing
+//! the execution get a 400 status code with the former, and a 200 one with the latter. The media
+//! type of the POST requests is validated as well.
+use std::cmp::Ordering;
+
+use axum::extract::rejection::JsonRejection;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum::Json;
+use http::header::ACCEPT;
+use http::header::CONTENT_TYPE;
+use http::HeaderMap;
+use http::HeaderValue;
+use http::StatusCode;
+
+use crate::graphql;
+
+/// Media type of the GraphQL responses defined by the specification.
+pub(crate) const GRAPHQL_RESPONSE_CONTENT_TYPE: &str = "application/graphql-response+json";
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// The media type of the responses to a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ResponseMediaType {
+    GraphqlResponseJson,
+    Json,
+}
+
+impl ResponseMediaType {
+    /// The media type preferred by a request, or none if it accepts neither of them. Requests
+    /// without an `Accept` header get `application/json`, like the clients predating the
+    /// specification expect.
+    pub(crate) fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        let accepted = accepted_media_types(headers);
+        if accepted.is_empty() {
+            return Some(ResponseMediaType::Json);
+        }
+        let mut preferred: Option<(Self, f32)> = None;
+        for (media_type, quality) in accepted {
+            let media_type = match media_type.as_str() {
+                GRAPHQL_RESPONSE_CONTENT_TYPE => ResponseMediaType::GraphqlResponseJson,
+                // the non deferred responses to the clients accepting multipart responses are
+                // not sent as multipart
+                JSON_CONTENT_TYPE | "application/*" | "*/*" | "multipart/mixed" => {
+                    ResponseMediaType::Json
+                }
+                _ => continue,
+            };
+            if quality <= 0.0 {
+                continue;
+            }
+            // on equal quality, the media type of the specification wins
+            let better = match preferred {
+                None => true,
+                Some((preferred_type, preferred_quality)) => {
+                    match quality.partial_cmp(&preferred_quality) {
+                        Some(Ordering::Greater) => true,
+                        Some(Ordering::Equal) => {
+                            media_type == ResponseMediaType::GraphqlResponseJson
+                                && preferred_type == ResponseMediaType::Json
+                        }
+                        _ => false,
+                    }
+                }
+            };
+            if better {
+                preferred = Some((media_type, quality));
+            }
+        }
+        preferred.map(|(media_type, _)| media_type)
+    }
+
+    pub(crate) fn content_type(self) -> HeaderValue {
+        HeaderValue::from_static(match self {
+            ResponseMediaType::GraphqlResponseJson => GRAPHQL_RESPONSE_CONTENT_TYPE,
+            ResponseMediaType::Json => JSON_CONTENT_TYPE,
+        })
+    }
+
+    /// The status code of a response sent with this media type, instead of the one set by the
+    /// router.
+    pub(crate) fn status_code(
+        self,
+        status: StatusCode,
+        response: &graphql::Response,
+    ) -> StatusCode {
+        match self {
+            // every response to a well-formed request gets a 200 status code
+            ResponseMediaType::Json if status == StatusCode::BAD_REQUEST => StatusCode::OK,
+            // a response without data is a request error
+            ResponseMediaType::GraphqlResponseJson
+                if status.is_success() && response.data.is_none() =>
+            {
+                StatusCode::BAD_REQUEST
+            }
+            _ => status,
+        }
+    }
+}
+
+/// The media types of the `Accept` header, lowercased, with their quality.
+fn accepted_media_types(headers: &HeaderMap) -> Vec<(String, f32)> {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|media_range| {
+            let mut parameters = media_range.split(';');
+            let media_type = parameters.next()?.trim().to_ascii_lowercase();
+            if media_type.is_empty() {
+                return None;
+            }
+            let quality = parameters
+                .filter_map(|parameter| parameter.trim().strip_prefix("q="))
+                .find_map(|quality| quality.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((media_type, quality))
+        })
+        .collect()
+}
+
+/// Whether the charset of a POST request, if any, is UTF-8, the only one allowed by the
+/// specification.
+pub(crate) fn has_utf8_charset(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|content_type| {
+            content_type
+                .split(';')
+                .skip(1)
+                .filter_map(|parameter| {
+                    let (name, value) = parameter.split_once('=')?;
+                    name.trim()
+                        .eq_ignore_ascii_case("charset")
+                        .then(|| value.trim().trim_matches('"'))
+                })
+                .all(|charset| charset.eq_ignore_ascii_case("utf-8"))
+        })
+        .unwrap_or(true)
+}
+
+/// The response to a request that is not a well-formed GraphQL-over-HTTP request.
+pub(crate) fn request_error(status: StatusCode, message: impl Into<String>) -> Response {
+    (
+        status,
+        [(CONTENT_TYPE, HeaderValue::from_static(JSON_CONTENT_TYPE))],
+        Json(
+            graphql::Response::builder()
+                .errors(vec![graphql::Error::builder()
+                    .message(message.into())
+                    .code(graphql::ErrorCode::BadRequest)
+                    .build()])
+                .build(),
+        ),
+    )
+        .into_response()
+}
+
+/// The response to a POST request which body could not be read as a GraphQL request.
+pub(crate) fn rejection_response(rejection: JsonRejection) -> Response {
+    match rejection {
+        JsonRejection::MissingJsonContentType(_) => request_error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "the body of POST requests must be sent with the application/json or application/graphql media type",
+        ),
+        JsonRejection::JsonDataError(e) => request_error(StatusCode::BAD_REQUEST, e.to_string()),
+        JsonRejection::JsonSyntaxError(e) => request_error(StatusCode::BAD_REQUEST, e.to_string()),
+        rejection => rejection.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn it_negotiates_the_media_type_of_responses() {
+        assert_eq!(
+            ResponseMediaType::negotiate(&HeaderMap::new()),
+            Some(ResponseMediaType::Json)
+        );
+        assert_eq!(
+            ResponseMediaType::negotiate(&accept(
+                "application/graphql-response+json, application/json"
+            )),
+            Some(ResponseMediaType::GraphqlResponseJson)
+        );
+        assert_eq!(
+            ResponseMediaType::negotiate(&accept(
+                "application/graphql-response+json;q=0.9, application/json"
+            )),
+            Some(ResponseMediaType::Json)
+        );
+        assert_eq!(
+            ResponseMediaType::negotiate(&accept("*/*")),
+            Some(ResponseMediaType::Json)
+        );
+        assert_eq!(ResponseMediaType::negotiate(&accept("text/html")), None);
+        assert_eq!(
+            ResponseMediaType::negotiate(&accept("application/json;q=0")),
+            None
+        );
+    }
+
+    #[test]
+    fn it_sets_the_status_code_of_the_media_type() {
+        let request_error = graphql::Response::builder()
+            .errors(vec![graphql::Error::builder()
+                .message("syntax error")
+                .build()])
+            .build();
+        let field_error = graphql::Response::builder()
+            .data(serde_json_bytes::Value::Null)
+            .errors(vec![graphql::Error::builder()
+                .message("subgraph error")
+                .build()])
+            .build();
+
+        let json = ResponseMediaType::Json;
+        assert_eq!(
+            json.status_code(StatusCode::BAD_REQUEST, &request_error),
+            StatusCode::OK
+        );
+        assert_eq!(
+            json.status_code(StatusCode::TOO_MANY_REQUESTS, &request_error),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        let graphql_response_json = ResponseMediaType::GraphqlResponseJson;
+        assert_eq!(
+            graphql_response_json.status_code(StatusCode::OK, &request_error),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            graphql_response_json.status_code(StatusCode::OK, &field_error),
+            StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn it_only_allows_the_utf8_charset() {
+        let content_type = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, HeaderValue::from_str(value).unwrap());
+            headers
+        };
+        assert!(has_utf8_charset(&content_type("application/json")));
+        assert!(has_utf8_charset(&content_type(
+            "application/json; charset=UTF-8"
+        )));
+        assert!(!has_utf8_charset(&content_type(
+            "application/json; charset=iso-8859-1"
+        )));
+    }
+}
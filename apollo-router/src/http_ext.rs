@@ -8,15 +8,18 @@ use std::cmp::PartialEq;
 use std::hash::Hash;
 use std::ops::Deref;
 use std::ops::DerefMut;
+use std::sync::Arc;
 
 use axum::body::boxed;
 use axum::response::IntoResponse;
 use bytes::Bytes;
+use futures::StreamExt;
 use http::header;
 use http::HeaderValue;
 use multimap::MultiMap;
 
 use crate::graphql;
+use crate::plugin::ResponseBody;
 
 /// Delayed-fallibility wrapper for conversion to [`http::header::HeaderName`].
 ///
@@ -383,6 +386,51 @@ impl<T: PartialEq> PartialEq for Request<T> {
 
 impl<T: Eq> Eq for Request<T> {}
 
+/// Notified of the size of the body of a response once it is sent, or once the client is gone.
+///
+/// Inserted in the extensions of a response, it lets the plugins account for the size of the body
+/// serialized by the HTTP server, without serializing the response themselves.
+#[derive(Clone)]
+pub(crate) struct ResponseBodySize(Arc<dyn Fn(u64) + Send + Sync>);
+
+impl ResponseBodySize {
+    pub(crate) fn new(report: impl Fn(u64) + Send + Sync + 'static) -> Self {
+        ResponseBodySize(Arc::new(report))
+    }
+
+    pub(crate) fn report(&self, bytes: u64) {
+        (self.0)(bytes)
+    }
+
+    /// Count the bytes of a body, reporting them once it is dropped.
+    pub(crate) fn count(self, body: ResponseBody) -> ResponseBody {
+        struct Counter {
+            size: ResponseBodySize,
+            bytes: u64,
+        }
+
+        impl Drop for Counter {
+            fn drop(&mut self) {
+                self.size.report(self.bytes);
+            }
+        }
+
+        let mut counter = Counter {
+            size: self,
+            bytes: 0,
+        };
+        body.map(move |chunk| {
+            // borrowed as a whole so that the closure owns the counter, reporting once dropped
+            let counter = &mut counter;
+            if let Ok(chunk) = &chunk {
+                counter.bytes += chunk.len() as u64;
+            }
+            chunk
+        })
+        .boxed()
+    }
+}
+
 /// Wrap an http Response.
 #[derive(Debug, Default)]
 pub(crate) struct Response<T> {
@@ -395,7 +443,6 @@ pub(crate) fn from_response_to_stream(
 ) -> http::Response<futures::stream::BoxStream<'static, graphql::Response>> {
     use futures::future::ready;
     use futures::stream::once;
-    use futures::StreamExt;
 
     http.map(|body| once(ready(body)).boxed())
 }
@@ -496,4 +543,33 @@ mod test {
         assert_eq!(request.method(), Method::POST);
         assert_eq!(request.body(), &"test");
     }
+
+    #[tokio::test]
+    async fn it_reports_the_size_of_the_body_once_it_is_dropped() {
+        use std::sync::atomic::AtomicU64;
+        use std::sync::atomic::Ordering;
+        use std::sync::Arc;
+
+        use bytes::Bytes;
+        use futures::StreamExt;
+
+        use crate::http_ext::ResponseBodySize;
+
+        let reported = Arc::new(AtomicU64::new(u64::MAX));
+        let size = {
+            let reported = reported.clone();
+            ResponseBodySize::new(move |bytes| reported.store(bytes, Ordering::SeqCst))
+        };
+        let mut body = size.count(
+            futures::stream::iter(vec![
+                Ok(Bytes::from_static(b"{\"data\"")),
+                Ok(Bytes::from_static(b":{}}")),
+            ])
+            .boxed(),
+        );
+        while body.next().await.is_some() {}
+        assert_eq!(reported.load(Ordering::SeqCst), u64::MAX);
+        drop(body);
+        assert_eq!(reported.load(Ordering::SeqCst), 11);
+    }
 }
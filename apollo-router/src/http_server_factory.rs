@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
 
 use derivative::Derivative;
 use futures::channel::oneshot;
@@ -44,6 +45,13 @@ pub(crate) struct HttpServerHandle {
     #[derivative(Debug = "ignore")]
     server_future: Pin<Box<dyn Future<Output = Result<Listener, ApolloRouterError>> + Send>>,
 
+    /// Future to wait on for the open connections to finish their requests
+    #[derivative(Debug = "ignore")]
+    connections_drained: Pin<Box<dyn Future<Output = ()> + Send>>,
+
+    /// How long the open connections have to finish their requests once the server stops
+    drain_timeout: Duration,
+
     /// The listen address that the server is actually listening on.
     /// If the socket address specified port zero the OS will assign a random free port.
     listen_address: ListenAddr,
@@ -53,11 +61,15 @@ impl HttpServerHandle {
     pub(crate) fn new(
         shutdown_sender: oneshot::Sender<()>,
         server_future: Pin<Box<dyn Future<Output = Result<Listener, ApolloRouterError>> + Send>>,
+        connections_drained: Pin<Box<dyn Future<Output = ()> + Send>>,
+        drain_timeout: Duration,
         listen_address: ListenAddr,
     ) -> Self {
         Self {
             shutdown_sender,
             server_future,
+            connections_drained,
+            drain_timeout,
             listen_address,
         }
     }
@@ -67,6 +79,12 @@ impl HttpServerHandle {
             tracing::error!("Failed to notify http thread of shutdown")
         };
         let _listener = self.server_future.await?;
+        if tokio::time::timeout(self.drain_timeout, self.connections_drained)
+            .await
+            .is_err()
+        {
+            tracing::warn!("some connections were still open after the drain timeout");
+        }
         #[cfg(unix)]
         {
             if let ListenAddr::UnixSocket(path) = self.listen_address {
@@ -99,6 +117,19 @@ impl HttpServerHandle {
         let listener = self.server_future.await;
         tracing::debug!("previous server stopped");
 
+        // the connections of the previous server finish their in flight requests with the
+        // previous pipeline, while the new server handles the new connections
+        let connections_drained = self.connections_drained;
+        let drain_timeout = self.drain_timeout;
+        tokio::spawn(async move {
+            match tokio::time::timeout(drain_timeout, connections_drained).await {
+                Ok(()) => tracing::debug!("previous server drained"),
+                Err(_) => tracing::warn!(
+                    "some connections of the previous server were still open after the drain timeout"
+                ),
+            }
+        });
+
         // we keep the TCP listener if it is compatible with the new configuration
         let listener = if self.listen_address != configuration.server.listen {
             None
@@ -136,6 +167,39 @@ pub(crate) enum Listener {
     Unix(tokio::net::UnixListener),
 }
 
+/// A listener inherited from the process starting the router, like the socket passed by systemd
+/// socket activation, created before the runtime.
+#[derive(Debug)]
+pub(crate) enum InheritedListener {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+impl InheritedListener {
+    /// The listener of the server, if the inherited socket matches the kind of its address.
+    pub(crate) fn into_listener(self, listen_address: &ListenAddr) -> std::io::Result<Listener> {
+        match (self, listen_address) {
+            (InheritedListener::Tcp(listener), ListenAddr::SocketAddr(_)) => {
+                listener.set_nonblocking(true)?;
+                Ok(Listener::Tcp(tokio::net::TcpListener::from_std(listener)?))
+            }
+            #[cfg(unix)]
+            (InheritedListener::Unix(listener), ListenAddr::UnixSocket(_)) => {
+                listener.set_nonblocking(true)?;
+                Ok(Listener::Unix(tokio::net::UnixListener::from_std(
+                    listener,
+                )?))
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "the inherited socket does not match the listen address, a TCP or a Unix socket",
+            )),
+        }
+    }
+}
+
 pub(crate) enum NetworkStream {
     Tcp(tokio::net::TcpStream),
     #[cfg(unix)]
@@ -190,6 +254,8 @@ mod tests {
         HttpServerHandle::new(
             shutdown_sender,
             futures::future::ready(Ok(listener)).boxed(),
+            futures::future::ready(()).boxed(),
+            Duration::from_secs(60),
             SocketAddr::from_str("127.0.0.1:0").unwrap().into(),
         )
         .shutdown()
@@ -212,6 +278,8 @@ mod tests {
         HttpServerHandle::new(
             shutdown_sender,
             futures::future::ready(Ok(listener)).boxed(),
+            futures::future::ready(()).boxed(),
+            Duration::from_secs(60),
             ListenAddr::UnixSocket(sock),
         )
         .shutdown()
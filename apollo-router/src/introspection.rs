@@ -1,21 +1,563 @@
-#[cfg(test)]
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io;
 
+use apollo_parser::ast;
 use router_bridge::introspect;
 use router_bridge::introspect::IntrospectionError;
 use router_bridge::planner::IncrementalDeliverySupport;
 use router_bridge::planner::QueryPlannerConfig;
+use serde_json_bytes::ByteString;
 
 use crate::cache::storage::CacheStorage;
+use crate::configuration::IntrospectionLimits;
+use crate::error::IntrospectionLimitError;
+use crate::error::QueryPlannerError;
 use crate::graphql::Response;
+use crate::json_ext::Object;
+use crate::json_ext::Value;
 use crate::Configuration;
 
 const DEFAULT_INTROSPECTION_CACHE_CAPACITY: usize = 5;
 
+/// Fragments, e.g. cyclic ones, nested deeper than this are introspected by the query planner.
+const MAX_DEPTH: usize = 64;
+
+/// The introspection of the whole schema, the types being referenced by name only.
+const SCHEMA_INTROSPECTION_QUERY: &str = r#"
+query SchemaIntrospection {
+  __schema {
+    description
+    queryType { name }
+    mutationType { name }
+    subscriptionType { name }
+    types { ...FullType }
+    directives { name description isRepeatable locations args { ...InputValue } }
+  }
+}
+
+fragment FullType on __Type {
+  kind
+  name
+  description
+  fields(includeDeprecated: true) {
+    name
+    description
+    args { ...InputValue }
+    type { ...TypeRef }
+    isDeprecated
+    deprecationReason
+  }
+  inputFields { ...InputValue }
+  interfaces { ...TypeRef }
+  enumValues(includeDeprecated: true) { name description isDeprecated deprecationReason }
+  possibleTypes { ...TypeRef }
+  ofType { name }
+}
+
+fragment InputValue on __InputValue {
+  name
+  description
+  type { ...TypeRef }
+  defaultValue
+}
+
+fragment TypeRef on __Type {
+  kind
+  name
+  ofType { kind name ofType { kind name ofType { kind name ofType { kind name ofType { kind name
+  ofType { kind name ofType { kind name ofType { kind name } } } } } } } }
+}
+"#;
+
+/// The fields of `__Type`, null for the list and non null wrapping types except `kind` and `ofType`.
+const TYPE_FIELDS: &[&str] = &[
+    "kind",
+    "name",
+    "description",
+    "fields",
+    "interfaces",
+    "possibleTypes",
+    "enumValues",
+    "inputFields",
+    "ofType",
+];
+
+/// The fields of `__Type` listing other types, which the introspection limits restrict the
+/// nesting of.
+const LIST_FIELDS: &[&str] = &["fields", "interfaces", "possibleTypes", "inputFields"];
+
 /// A cache containing our well known introspection queries.
 pub(crate) struct Introspection {
     cache: CacheStorage<String, Response>,
     defer_support: bool,
+    limits: Option<IntrospectionLimits>,
+    schema: Option<SchemaIntrospection>,
+}
+
+/// The introspection of the whole schema, which introspection queries are answered from by
+/// selecting their fields.
+struct SchemaIntrospection {
+    schema: Object,
+    types: HashMap<String, Object>,
+}
+
+/// The introspection types.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IntrospectionType {
+    Schema,
+    Type,
+    Field,
+    InputValue,
+    EnumValue,
+    Directive,
+}
+
+impl IntrospectionType {
+    fn name(self) -> &'static str {
+        match self {
+            IntrospectionType::Schema => "__Schema",
+            IntrospectionType::Type => "__Type",
+            IntrospectionType::Field => "__Field",
+            IntrospectionType::InputValue => "__InputValue",
+            IntrospectionType::EnumValue => "__EnumValue",
+            IntrospectionType::Directive => "__Directive",
+        }
+    }
+
+    /// The introspection type of a field, none for the scalars and enums.
+    fn field_type(self, field: &str) -> Option<IntrospectionType> {
+        match (self, field) {
+            (
+                IntrospectionType::Schema,
+                "types" | "queryType" | "mutationType" | "subscriptionType",
+            ) => Some(IntrospectionType::Type),
+            (IntrospectionType::Schema, "directives") => Some(IntrospectionType::Directive),
+            (IntrospectionType::Type, "fields") => Some(IntrospectionType::Field),
+            (IntrospectionType::Type, "interfaces" | "possibleTypes" | "ofType") => {
+                Some(IntrospectionType::Type)
+            }
+            (IntrospectionType::Type, "enumValues") => Some(IntrospectionType::EnumValue),
+            (IntrospectionType::Type, "inputFields")
+            | (IntrospectionType::Field | IntrospectionType::Directive, "args") => {
+                Some(IntrospectionType::InputValue)
+            }
+            (IntrospectionType::Field | IntrospectionType::InputValue, "type") => {
+                Some(IntrospectionType::Type)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl SchemaIntrospection {
+    fn new(data: Value) -> Option<Self> {
+        let schema = match data {
+            Value::Object(mut data) => match data.remove("__schema") {
+                Some(Value::Object(schema)) => schema,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let types = match schema.get("types") {
+            Some(Value::Array(types)) => types
+                .iter()
+                .filter_map(|ty| {
+                    let ty = ty.as_object()?;
+                    Some((ty.get("name")?.as_str()?.to_string(), ty.clone()))
+                })
+                .collect(),
+            _ => return None,
+        };
+        Some(Self { schema, types })
+    }
+
+    /// The response of an introspection query, or none if it must be introspected by the query
+    /// planner, e.g. because it uses variables or directives.
+    fn execute(&self, query: &str) -> Option<Response> {
+        let parser = apollo_parser::Parser::new(query);
+        let tree = parser.parse();
+        if tree.errors().next().is_some() {
+            return None;
+        }
+
+        let mut operations = Vec::new();
+        let mut fragments = HashMap::new();
+        for definition in tree.document().definitions() {
+            match definition {
+                ast::Definition::OperationDefinition(operation) => operations.push(operation),
+                ast::Definition::FragmentDefinition(fragment) => {
+                    let name = fragment.fragment_name()?.name()?.text().to_string();
+                    fragments.insert(name, fragment);
+                }
+                _ => return None,
+            }
+        }
+        let operation = match operations.as_slice() {
+            [operation] => operation,
+            _ => return None,
+        };
+        let is_query = operation
+            .operation_type()
+            .map(|ty| ty.query_token().is_some())
+            .unwrap_or(true);
+        if !is_query || operation.directives().is_some() {
+            return None;
+        }
+
+        let selector = Selector {
+            introspection: self,
+            fragments: &fragments,
+        };
+        let mut data = Object::new();
+        for selection in operation.selection_set()?.selections() {
+            let field = match selection {
+                ast::Selection::Field(field) if field.directives().is_none() => field,
+                _ => return None,
+            };
+            let name = field.name()?.text().to_string();
+            let value = match (name.as_str(), arguments(&field)?.as_slice()) {
+                ("__schema", []) => selector.value(
+                    &Value::Object(self.schema.clone()),
+                    IntrospectionType::Schema,
+                    field.selection_set()?,
+                    0,
+                )?,
+                ("__type", [(argument, ast::Value::StringValue(type_name))])
+                    if argument == "name" =>
+                {
+                    match self.types.get(&Into::<String>::into(type_name.clone())) {
+                        Some(ty) => selector.value(
+                            &Value::Object(ty.clone()),
+                            IntrospectionType::Type,
+                            field.selection_set()?,
+                            0,
+                        )?,
+                        None => Value::Null,
+                    }
+                }
+                ("__typename", []) => self
+                    .schema
+                    .get("queryType")?
+                    .as_object()?
+                    .get("name")?
+                    .clone(),
+                _ => return None,
+            };
+            merge(&mut data, response_key(&field)?, value);
+        }
+
+        Some(Response::builder().data(Value::Object(data)).build())
+    }
+}
+
+/// The selection of the fields of an introspection query.
+struct Selector<'a> {
+    introspection: &'a SchemaIntrospection,
+    fragments: &'a HashMap<String, ast::FragmentDefinition>,
+}
+
+impl Selector<'_> {
+    fn value(
+        &self,
+        value: &Value,
+        ty: IntrospectionType,
+        selection_set: ast::SelectionSet,
+        depth: usize,
+    ) -> Option<Value> {
+        match value {
+            Value::Null => Some(Value::Null),
+            Value::Array(values) => values
+                .iter()
+                .map(|value| self.value(value, ty, selection_set.clone(), depth))
+                .collect::<Option<Vec<_>>>()
+                .map(Value::Array),
+            Value::Object(object) => {
+                // the types are referenced by name, except the list and non null wrapping types
+                let named_type = if ty == IntrospectionType::Type {
+                    object
+                        .get("name")
+                        .and_then(|name| name.as_str())
+                        .and_then(|name| self.introspection.types.get(name))
+                } else {
+                    None
+                };
+                let mut output = Object::new();
+                self.select(
+                    named_type.unwrap_or(object),
+                    ty,
+                    selection_set,
+                    &mut output,
+                    depth,
+                )?;
+                Some(Value::Object(output))
+            }
+            _ => None,
+        }
+    }
+
+    fn select(
+        &self,
+        object: &Object,
+        ty: IntrospectionType,
+        selection_set: ast::SelectionSet,
+        output: &mut Object,
+        depth: usize,
+    ) -> Option<()> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        for selection in selection_set.selections() {
+            match selection {
+                ast::Selection::Field(field) => {
+                    if field.directives().is_some() {
+                        return None;
+                    }
+                    let name = field.name()?.text().to_string();
+                    let value = if name == "__typename" {
+                        Value::String(ty.name().into())
+                    } else {
+                        let value = field_value(object, ty, &name, arguments(&field)?)?;
+                        match (ty.field_type(&name), field.selection_set()) {
+                            (Some(field_type), Some(selection_set)) => {
+                                self.value(&value, field_type, selection_set, depth + 1)?
+                            }
+                            (None, None) => value,
+                            _ => return None,
+                        }
+                    };
+                    merge(output, response_key(&field)?, value);
+                }
+                ast::Selection::InlineFragment(fragment) => {
+                    if fragment.directives().is_some() {
+                        return None;
+                    }
+                    if applies(fragment.type_condition(), ty)? {
+                        self.select(object, ty, fragment.selection_set()?, output, depth + 1)?;
+                    }
+                }
+                ast::Selection::FragmentSpread(spread) => {
+                    if spread.directives().is_some() {
+                        return None;
+                    }
+                    let name = spread.fragment_name()?.name()?.text().to_string();
+                    let fragment = self.fragments.get(&name)?;
+                    if fragment.directives().is_some() {
+                        return None;
+                    }
+                    if applies(fragment.type_condition(), ty)? {
+                        self.select(object, ty, fragment.selection_set()?, output, depth + 1)?;
+                    }
+                }
+            }
+        }
+        Some(())
+    }
+}
+
+/// The value of a field, before the selection of its subfields.
+fn field_value(
+    object: &Object,
+    ty: IntrospectionType,
+    name: &str,
+    arguments: Vec<(String, ast::Value)>,
+) -> Option<Value> {
+    let value = match object.get(name) {
+        Some(value) => value.clone(),
+        // the fields of the wrapping types that do not apply to them
+        None if ty == IntrospectionType::Type && TYPE_FIELDS.contains(&name) => Value::Null,
+        None => return None,
+    };
+
+    match (ty, name) {
+        (IntrospectionType::Type, "fields" | "enumValues") => {
+            let include_deprecated = match arguments.as_slice() {
+                [] => false,
+                [(argument, ast::Value::BooleanValue(value))]
+                    if argument == "includeDeprecated" =>
+                {
+                    value.true_token().is_some()
+                }
+                _ => return None,
+            };
+            match value {
+                // the schema introspection includes the deprecated fields and enum values
+                Value::Array(values) if !include_deprecated => Some(Value::Array(
+                    values
+                        .into_iter()
+                        .filter(|value| {
+                            value
+                                .as_object()
+                                .and_then(|value| value.get("isDeprecated"))
+                                != Some(&Value::Bool(true))
+                        })
+                        .collect(),
+                )),
+                value => Some(value),
+            }
+        }
+        _ if arguments.is_empty() => Some(value),
+        _ => None,
+    }
+}
+
+fn arguments(field: &ast::Field) -> Option<Vec<(String, ast::Value)>> {
+    match field.arguments() {
+        Some(arguments) => arguments
+            .arguments()
+            .map(|argument| Some((argument.name()?.text().to_string(), argument.value()?)))
+            .collect(),
+        None => Some(Vec::new()),
+    }
+}
+
+fn response_key(field: &ast::Field) -> Option<String> {
+    match field.alias() {
+        Some(alias) => Some(alias.name()?.text().to_string()),
+        None => Some(field.name()?.text().to_string()),
+    }
+}
+
+/// Whether a fragment applies to an introspection type, which it always does without a type
+/// condition, or none if its type condition can not be parsed.
+fn applies(type_condition: Option<ast::TypeCondition>, ty: IntrospectionType) -> Option<bool> {
+    let type_condition = match type_condition {
+        Some(type_condition) => type_condition.named_type()?.name()?.text().to_string(),
+        None => return Some(true),
+    };
+    Some(type_condition == ty.name())
+}
+
+/// The deepest nesting of the fields listing types in a query, 0 if it can not be parsed.
+fn list_depth(query: &str) -> usize {
+    let tree = apollo_parser::Parser::new(query).parse();
+    let mut operations = Vec::new();
+    let mut fragments = HashMap::new();
+    for definition in tree.document().definitions() {
+        match definition {
+            ast::Definition::OperationDefinition(operation) => operations.push(operation),
+            ast::Definition::FragmentDefinition(fragment) => {
+                if let Some(name) = fragment.fragment_name().and_then(|name| name.name()) {
+                    fragments.insert(name.text().to_string(), fragment);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut depths = DepthCounter {
+        fragments: &fragments,
+        depths: HashMap::new(),
+        visiting: HashSet::new(),
+    };
+    operations
+        .iter()
+        .filter_map(|operation| operation.selection_set())
+        .map(|selection_set| depths.selection_set(selection_set))
+        .max()
+        .unwrap_or_default()
+}
+
+/// Counts the nesting of the fields listing types, the depth of each fragment being counted once.
+struct DepthCounter<'a> {
+    fragments: &'a HashMap<String, ast::FragmentDefinition>,
+    depths: HashMap<String, usize>,
+    visiting: HashSet<String>,
+}
+
+impl DepthCounter<'_> {
+    fn selection_set(&mut self, selection_set: ast::SelectionSet) -> usize {
+        selection_set
+            .selections()
+            .map(|selection| match selection {
+                ast::Selection::Field(field) => {
+                    let depth = field
+                        .selection_set()
+                        .map(|selection_set| self.selection_set(selection_set))
+                        .unwrap_or_default();
+                    match field.name() {
+                        Some(name) if LIST_FIELDS.contains(&name.text().as_str()) => depth + 1,
+                        _ => depth,
+                    }
+                }
+                ast::Selection::InlineFragment(fragment) => fragment
+                    .selection_set()
+                    .map(|selection_set| self.selection_set(selection_set))
+                    .unwrap_or_default(),
+                ast::Selection::FragmentSpread(spread) => spread
+                    .fragment_name()
+                    .and_then(|name| name.name())
+                    .map(|name| self.fragment(name.text().to_string()))
+                    .unwrap_or_default(),
+            })
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn fragment(&mut self, name: String) -> usize {
+        if let Some(depth) = self.depths.get(&name) {
+            return *depth;
+        }
+        // cyclic fragments are rejected by the validation of the query
+        if !self.visiting.insert(name.clone()) {
+            return 0;
+        }
+        let depth = self
+            .fragments
+            .get(&name)
+            .and_then(|fragment| fragment.selection_set())
+            .map(|selection_set| self.selection_set(selection_set))
+            .unwrap_or_default();
+        self.visiting.remove(&name);
+        self.depths.insert(name, depth);
+        depth
+    }
+}
+
+/// Insert a value in a response, merging the fields selected several times.
+fn merge(output: &mut Object, key: String, value: Value) {
+    match output.get_mut(key.as_str()) {
+        Some(existing) => merge_value(existing, value),
+        None => {
+            output.insert(ByteString::from(key), value);
+        }
+    }
+}
+
+fn merge_value(existing: &mut Value, value: Value) {
+    match (existing, value) {
+        (Value::Object(existing), Value::Object(value)) => {
+            for (key, value) in value {
+                merge(existing, key.as_str().to_string(), value);
+            }
+        }
+        (Value::Array(existing), Value::Array(values)) if existing.len() == values.len() => {
+            for (existing, value) in existing.iter_mut().zip(values) {
+                merge_value(existing, value);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Counts the bytes of a serialized response, without writing them, until they exceed a limit.
+struct SizeCounter {
+    size: usize,
+    max_size: usize,
+}
+
+impl io::Write for SizeCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.size += buf.len();
+        if self.size > self.max_size {
+            return Err(io::ErrorKind::Other.into());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 impl Introspection {
@@ -23,9 +565,28 @@ impl Introspection {
         Self {
             cache: CacheStorage::new(capacity).await,
             defer_support: configuration.server.experimental_defer_support,
+            limits: configuration
+                .server
+                .experimental_introspection_limits
+                .clone(),
+            schema: None,
         }
     }
 
+    /// Pre-compute the introspection of the whole schema, which the introspection queries are
+    /// answered from.
+    pub(crate) fn with_schema(mut self, schema_sdl: &str) -> Self {
+        match self
+            .introspect(schema_sdl, SCHEMA_INTROSPECTION_QUERY.to_string())
+            .map(|response| response.data.and_then(SchemaIntrospection::new))
+        {
+            Ok(Some(schema)) => self.schema = Some(schema),
+            Ok(None) => tracing::warn!("could not pre-compute the schema introspection"),
+            Err(e) => tracing::warn!("could not pre-compute the schema introspection: {}", e),
+        }
+        self
+    }
+
     pub(crate) async fn new(configuration: &Configuration) -> Self {
         Self::with_capacity(configuration, DEFAULT_INTROSPECTION_CACHE_CAPACITY).await
     }
@@ -48,15 +609,59 @@ impl Introspection {
         &self,
         schema_sdl: &str,
         query: String,
-    ) -> Result<Response, IntrospectionError> {
+    ) -> Result<Response, QueryPlannerError> {
+        // the cached responses are within the limits
         if let Some(response) = self.cache.get(&query).await {
             return Ok(response);
         }
 
-        // Do the introspection query and cache it
+        if let Some(limits) = &self.limits {
+            let depth = list_depth(&query);
+            if depth > limits.max_depth {
+                return Err(QueryPlannerError::IntrospectionLimit(
+                    IntrospectionLimitError::MaxDepth(depth, limits.max_depth),
+                ));
+            }
+        }
+
+        // Answer the query from the schema introspection, or do the introspection query, and
+        // cache it
+        let response = match self
+            .schema
+            .as_ref()
+            .and_then(|schema| schema.execute(&query))
+        {
+            Some(response) => response,
+            None => self
+                .introspect(schema_sdl, query.clone())
+                .map_err(QueryPlannerError::Introspection)?,
+        };
+        self.check_size(&response)?;
+
+        self.cache.insert(query, response.clone()).await;
+
+        Ok(response)
+    }
+
+    fn check_size(&self, response: &Response) -> Result<(), QueryPlannerError> {
+        let max_size = match &self.limits {
+            Some(limits) => limits.max_size,
+            None => return Ok(()),
+        };
+        // the serialization stops as soon as the response is over the limit
+        let mut counter = SizeCounter { size: 0, max_size };
+        if serde_json::to_writer(&mut counter, response).is_err() && counter.size > max_size {
+            return Err(QueryPlannerError::IntrospectionLimit(
+                IntrospectionLimitError::MaxSize(max_size),
+            ));
+        }
+        Ok(())
+    }
+
+    fn introspect(&self, schema_sdl: &str, query: String) -> Result<Response, IntrospectionError> {
         let mut response = introspect::batch_introspect(
             schema_sdl,
-            vec![query.to_owned()],
+            vec![query],
             QueryPlannerConfig {
                 incremental_delivery: Some(IncrementalDeliverySupport {
                     enable_defer: Some(self.defer_support),
@@ -83,16 +688,14 @@ impl Introspection {
                 .into(),
             })?;
 
-        let response = Response::builder().data(introspection_result).build();
-
-        self.cache.insert(query, response.clone()).await;
-
-        Ok(response)
+        Ok(Response::builder().data(introspection_result).build())
     }
 }
 
 #[cfg(test)]
 mod introspection_tests {
+    use serde_json_bytes::json;
+
     use super::*;
 
     #[tokio::test]
@@ -115,4 +718,274 @@ mod introspection_tests {
                 .unwrap()
         );
     }
+
+    fn schema_introspection() -> SchemaIntrospection {
+        let string = |name: &str| json!({ "kind": "SCALAR", "name": name });
+        let field = |name: &str, ty: Value, deprecated: bool| {
+            json!({
+                "name": name,
+                "description": null,
+                "args": [],
+                "type": ty,
+                "isDeprecated": deprecated,
+                "deprecationReason": if deprecated { json!("unused") } else { Value::Null },
+            })
+        };
+        let object = |name: &str, fields: Vec<Value>| {
+            json!({
+                "kind": "OBJECT",
+                "name": name,
+                "description": null,
+                "fields": fields,
+                "inputFields": null,
+                "interfaces": [],
+                "enumValues": null,
+                "possibleTypes": null,
+                "ofType": null,
+            })
+        };
+        SchemaIntrospection::new(json!({
+            "__schema": {
+                "description": null,
+                "queryType": { "name": "Query" },
+                "mutationType": null,
+                "subscriptionType": null,
+                "types": [
+                    object("Query", vec![
+                        field("me", json!({ "kind": "OBJECT", "name": "User", "ofType": null }), false),
+                        field("user", json!({ "kind": "OBJECT", "name": "User", "ofType": null }), true),
+                    ]),
+                    object("User", vec![
+                        field("name", json!({ "kind": "NON_NULL", "name": null, "ofType": string("String") }), false),
+                    ]),
+                ],
+                "directives": [],
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn it_answers_from_the_schema_introspection() {
+        let introspection = schema_introspection();
+
+        let response = introspection
+            .execute("{ __schema { queryType { name fields { name } } } }")
+            .unwrap();
+        assert_eq!(
+            response.data,
+            Some(json!({
+                "__schema": { "queryType": { "name": "Query", "fields": [{ "name": "me" }] } }
+            }))
+        );
+
+        let response = introspection
+            .execute(
+                r#"
+                query {
+                  user: __type(name: "User") { ...Fields }
+                  __type(name: "Query") {
+                    fields(includeDeprecated: true) { name isDeprecated type { kind name } }
+                  }
+                }
+                fragment Fields on __Type {
+                  fields { name type { kind description ofType { name __typename } } }
+                }
+                "#,
+            )
+            .unwrap();
+        assert_eq!(
+            response.data,
+            Some(json!({
+                "user": {
+                    "fields": [{
+                        "name": "name",
+                        "type": {
+                            "kind": "NON_NULL",
+                            "description": null,
+                            "ofType": { "name": "String", "__typename": "__Type" }
+                        }
+                    }]
+                },
+                "__type": {
+                    "fields": [
+                        { "name": "me", "isDeprecated": false, "type": { "kind": "OBJECT", "name": "User" } },
+                        { "name": "user", "isDeprecated": true, "type": { "kind": "OBJECT", "name": "User" } }
+                    ]
+                }
+            }))
+        );
+    }
+
+    #[test]
+    fn it_counts_the_nesting_of_the_fields_listing_types() {
+        assert_eq!(list_depth("{ __schema { queryType { name } } }"), 0);
+        assert_eq!(
+            list_depth(
+                r#"
+                { __type(name: "Query") { ...Fields interfaces { name } } }
+                fragment Fields on __Type { fields { type { ...Recursive } } }
+                fragment Recursive on __Type { possibleTypes { ...Recursive } }
+                "#
+            ),
+            2
+        );
+        assert_eq!(
+            list_depth(
+                r#"
+                { __schema { types { fields { type { fields { type { fields { name } } } } } } } }
+                "#
+            ),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn it_rejects_the_queries_exceeding_the_introspection_limits() {
+        let configuration = Configuration::builder()
+            .server(
+                crate::configuration::Server::builder()
+                    .introspection_limits(IntrospectionLimits {
+                        max_depth: 1,
+                        max_size: 100,
+                    })
+                    .build(),
+            )
+            .build();
+        let mut introspection = Introspection::new(&configuration).await;
+        introspection.schema = Some(schema_introspection());
+
+        assert!(matches!(
+            introspection
+                .execute(
+                    " ",
+                    "{ __schema { types { fields { type { fields { name } } } } } }".to_string()
+                )
+                .await,
+            Err(QueryPlannerError::IntrospectionLimit(
+                IntrospectionLimitError::MaxDepth(2, 1)
+            ))
+        ));
+        assert!(introspection
+            .execute(" ", "{ __schema { queryType { name } } }".to_string())
+            .await
+            .is_ok());
+        assert!(matches!(
+            introspection
+                .execute(
+                    " ",
+                    "{ __schema { types { name kind description fields { name } } } }".to_string()
+                )
+                .await,
+            Err(QueryPlannerError::IntrospectionLimit(
+                IntrospectionLimitError::MaxSize(100)
+            ))
+        ));
+
+        // the introspection queries are not limited by default
+        let mut introspection = Introspection::new(&Configuration::default()).await;
+        introspection.schema = Some(schema_introspection());
+        assert!(introspection
+            .execute(
+                " ",
+                "{ __schema { types { fields { type { fields { name } } } } } }".to_string()
+            )
+            .await
+            .is_ok());
+    }
+
+    /// The introspection query sent by GraphiQL.
+    const GRAPHIQL_INTROSPECTION_QUERY: &str = r#"
+    query IntrospectionQuery {
+      __schema {
+        queryType { name }
+        mutationType { name }
+        subscriptionType { name }
+        types { ...FullType }
+        directives { name description locations args { ...InputValue } }
+      }
+    }
+    fragment FullType on __Type {
+      kind
+      name
+      description
+      fields(includeDeprecated: true) {
+        name
+        description
+        args { ...InputValue }
+        type { ...TypeRef }
+        isDeprecated
+        deprecationReason
+      }
+      inputFields { ...InputValue }
+      interfaces { ...TypeRef }
+      enumValues(includeDeprecated: true) { name description isDeprecated deprecationReason }
+      possibleTypes { ...TypeRef }
+    }
+    fragment InputValue on __InputValue {
+      name
+      description
+      type { ...TypeRef }
+      defaultValue
+    }
+    fragment TypeRef on __Type {
+      kind
+      name
+      ofType { kind name ofType { kind name ofType { kind name ofType { kind name
+      ofType { kind name ofType { kind name ofType { kind name ofType { kind name } } } } } } } }
+    }
+    "#;
+
+    #[tokio::test]
+    async fn it_answers_like_the_query_planner_on_a_supergraph() {
+        let schema = include_str!("testdata/starstuff@current.graphql");
+        let introspection = Introspection::new(&Configuration::default())
+            .await
+            .with_schema(schema);
+        let queries = [
+            GRAPHIQL_INTROSPECTION_QUERY,
+            "{ __schema { queryType { name } mutationType { name } subscriptionType { name } } }",
+            r#"{ product: __type(name: "Product") { name kind fields { name args { name } } } }"#,
+            r#"{ __type(name: "Unknown") { name } }"#,
+            r#"
+            query {
+              t: __type(name: "Query") { ...Fields fields { description } }
+              __typename
+            }
+            fragment Fields on __Type {
+              __typename
+              fields(includeDeprecated: false) {
+                name
+                type { ... on __Type { kind name ofType { kind name } } }
+              }
+            }
+            "#,
+            "{ __schema { directives { name locations isRepeatable args { name defaultValue } } } }",
+        ];
+
+        for query in queries {
+            let cached = introspection
+                .schema
+                .as_ref()
+                .expect("the schema introspection must be pre-computed")
+                .execute(query)
+                .unwrap_or_else(|| panic!("{} must be answered from the cache", query));
+            let introspected = introspection.introspect(schema, query.to_string()).unwrap();
+            assert_eq!(cached, introspected, "{}", query);
+        }
+    }
+
+    #[test]
+    fn it_leaves_the_queries_using_variables_or_directives_to_the_query_planner() {
+        let introspection = schema_introspection();
+        assert!(introspection
+            .execute("query($name: String!) { __type(name: $name) { name } }")
+            .is_none());
+        assert!(introspection
+            .execute("query($skip: Boolean!) { __schema { types @skip(if: $skip) { name } } }")
+            .is_none());
+        assert!(introspection
+            .execute("{ __type(name: \"Query\") { unknown } }")
+            .is_none());
+    }
 }
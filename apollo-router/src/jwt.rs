@@ -0,0 +1,410 @@
+//! Verification of JSON Web Tokens.
+//!
+//! The signature of a token is verified with a key of a JSON Web Key Set: the key with the `kid`
+//! of the token, or else the keys matching its algorithm, in turn. The RSA (`RS256`, `RS384`,
+//! `RS512`), ECDSA (`ES256`, `ES384`) and HMAC (`HS256`, `HS384`, `HS512`) algorithms are
+//! supported, and the `exp`, `nbf`, `iss` and `aud` claims are checked once the signature is
+//! verified.
+
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use displaydoc::Display;
+use ring::hmac;
+use ring::signature;
+use ring::signature::RsaPublicKeyComponents;
+use ring::signature::UnparsedPublicKey;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+
+/// Claims of a token.
+pub(crate) type Claims = serde_json::Map<String, serde_json::Value>;
+
+/// Leeway of the `exp` and `nbf` claims, in seconds, for the clocks that drift.
+const LEEWAY: u64 = 60;
+
+/// Errors of the verification of a token.
+#[derive(Error, Debug, Display, Clone, PartialEq, Eq)]
+pub(crate) enum JwtError {
+    /// the token is malformed: {0}
+    Malformed(&'static str),
+    /// the algorithm '{0}' is not supported
+    UnsupportedAlgorithm(String),
+    /// no key verifies the signature of the token
+    InvalidSignature,
+    /// the token expired
+    Expired,
+    /// the token is not valid yet
+    NotYetValid,
+    /// the token is not issued by '{0}'
+    InvalidIssuer(String),
+    /// the token is not intended for '{0}'
+    InvalidAudience(String),
+}
+
+/// A JSON Web Key.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+pub(crate) struct Jwk {
+    /// Type of the key: `RSA`, `EC` or `oct`.
+    pub(crate) kty: String,
+    /// Identifier of the key, matched with the `kid` of the tokens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) kid: Option<String>,
+    /// Algorithm of the key, any algorithm of its type if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) alg: Option<String>,
+    /// Modulus of an RSA key, in base64url.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) n: Option<String>,
+    /// Exponent of an RSA key, in base64url.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) e: Option<String>,
+    /// Curve of an EC key: `P-256` or `P-384`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) crv: Option<String>,
+    /// Coordinates of an EC key, in base64url.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) x: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) y: Option<String>,
+    /// Secret of an HMAC key, in base64url.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) k: Option<String>,
+}
+
+/// A JSON Web Key Set.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct JwkSet {
+    pub(crate) keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    #[serde(default)]
+    kid: Option<String>,
+}
+
+/// The claims of a token, once its signature is verified with one of the keys and it is issued by
+/// `issuer` for `audience`.
+pub(crate) fn verify(
+    token: &str,
+    keys: &[Jwk],
+    issuer: &str,
+    audience: &str,
+) -> Result<Claims, JwtError> {
+    let claims = verify_at(token, keys, now())?;
+    check_recipient(&claims, issuer, audience)?;
+    Ok(claims)
+}
+
+/// Checks that a token is issued by `issuer` and intended for `audience`: its `aud` claim is
+/// either a string or an array of strings.
+fn check_recipient(claims: &Claims, issuer: &str, audience: &str) -> Result<(), JwtError> {
+    if claims.get("iss").and_then(serde_json::Value::as_str) != Some(issuer) {
+        return Err(JwtError::InvalidIssuer(issuer.to_string()));
+    }
+    let intended = match claims.get("aud") {
+        Some(serde_json::Value::String(aud)) => aud == audience,
+        Some(serde_json::Value::Array(auds)) => auds.iter().any(|aud| aud == audience),
+        _ => false,
+    };
+    if !intended {
+        return Err(JwtError::InvalidAudience(audience.to_string()));
+    }
+    Ok(())
+}
+
+fn verify_at(token: &str, keys: &[Jwk], now: u64) -> Result<Claims, JwtError> {
+    let token = token.trim();
+    let mut parts = token.split('.');
+    let (header, payload, signature) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(header), Some(payload), Some(signature)) if parts.next().is_none() => {
+            (header, payload, signature)
+        }
+        _ => return Err(JwtError::Malformed("it does not have three parts")),
+    };
+    let header: Header = serde_json::from_slice(&decode(header)?)
+        .map_err(|_| JwtError::Malformed("the header is not valid"))?;
+    let signature = decode(signature)?;
+    // the header and the payload are signed as they were encoded
+    let message = &token[..token.rfind('.').unwrap_or_default()];
+
+    let verified = keys
+        .iter()
+        .filter(|key| match (&header.kid, &key.kid) {
+            (Some(kid), Some(key_kid)) => kid == key_kid,
+            _ => true,
+        })
+        .filter(|key| key.alg.as_deref().map_or(true, |alg| alg == header.alg))
+        .map(|key| verify_signature(&header.alg, key, message.as_bytes(), &signature))
+        .collect::<Result<Vec<bool>, JwtError>>()?
+        .into_iter()
+        .any(|verified| verified);
+    if !verified {
+        return Err(JwtError::InvalidSignature);
+    }
+
+    let claims: Claims = serde_json::from_slice(&decode(payload)?)
+        .map_err(|_| JwtError::Malformed("the claims are not a JSON object"))?;
+    let time = |claim: &str| claims.get(claim).and_then(serde_json::Value::as_u64);
+    if matches!(time("exp"), Some(exp) if now > exp.saturating_add(LEEWAY)) {
+        return Err(JwtError::Expired);
+    }
+    if matches!(time("nbf"), Some(nbf) if nbf > now.saturating_add(LEEWAY)) {
+        return Err(JwtError::NotYetValid);
+    }
+    Ok(claims)
+}
+
+/// Whether a key of the type of the algorithm verifies the signature. The keys of other types
+/// never do.
+fn verify_signature(
+    alg: &str,
+    key: &Jwk,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<bool, JwtError> {
+    let verified = match (alg, key.kty.as_str()) {
+        ("RS256" | "RS384" | "RS512", "RSA") => {
+            let parameters = match alg {
+                "RS256" => &signature::RSA_PKCS1_2048_8192_SHA256,
+                "RS384" => &signature::RSA_PKCS1_2048_8192_SHA384,
+                _ => &signature::RSA_PKCS1_2048_8192_SHA512,
+            };
+            let (n, e) = match (key_part(&key.n), key_part(&key.e)) {
+                (Some(n), Some(e)) => (n, e),
+                _ => return Ok(false),
+            };
+            RsaPublicKeyComponents { n, e }
+                .verify(parameters, message, signature)
+                .is_ok()
+        }
+        ("ES256" | "ES384", "EC") => {
+            let (algorithm, curve) = match alg {
+                "ES256" => (&signature::ECDSA_P256_SHA256_FIXED, "P-256"),
+                _ => (&signature::ECDSA_P384_SHA384_FIXED, "P-384"),
+            };
+            let (x, y) = match (key_part(&key.x), key_part(&key.y)) {
+                (Some(x), Some(y)) if key.crv.as_deref() == Some(curve) => (x, y),
+                _ => return Ok(false),
+            };
+            // uncompressed point
+            let mut public_key = Vec::with_capacity(1 + x.len() + y.len());
+            public_key.push(4);
+            public_key.extend_from_slice(&x);
+            public_key.extend_from_slice(&y);
+            UnparsedPublicKey::new(algorithm, public_key)
+                .verify(message, signature)
+                .is_ok()
+        }
+        ("HS256" | "HS384" | "HS512", "oct") => {
+            let algorithm = match alg {
+                "HS256" => hmac::HMAC_SHA256,
+                "HS384" => hmac::HMAC_SHA384,
+                _ => hmac::HMAC_SHA512,
+            };
+            let secret = match key_part(&key.k) {
+                Some(secret) => secret,
+                None => return Ok(false),
+            };
+            hmac::verify(&hmac::Key::new(algorithm, &secret), message, signature).is_ok()
+        }
+        ("RS256" | "RS384" | "RS512" | "ES256" | "ES384" | "HS256" | "HS384" | "HS512", _) => false,
+        (alg, _) => return Err(JwtError::UnsupportedAlgorithm(alg.to_string())),
+    };
+    Ok(verified)
+}
+
+fn key_part(part: &Option<String>) -> Option<Vec<u8>> {
+    base64::decode_config(part.as_ref()?, base64::URL_SAFE_NO_PAD).ok()
+}
+
+fn decode(part: &str) -> Result<Vec<u8>, JwtError> {
+    base64::decode_config(part, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| JwtError::Malformed("a part is not base64url encoded"))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use ring::rand::SystemRandom;
+    use ring::signature::EcdsaKeyPair;
+    use ring::signature::KeyPair;
+
+    use super::*;
+
+    /// A token signed with HMAC-SHA256, and the key verifying it.
+    pub(crate) fn signed_token(claims: serde_json::Value) -> (String, Jwk) {
+        let secret = b"test secret of the router";
+        let message = format!(
+            "{}.{}",
+            base64::encode_config(r#"{"alg":"HS256","kid":"test"}"#, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(claims.to_string(), base64::URL_SAFE_NO_PAD)
+        );
+        let tag = hmac::sign(
+            &hmac::Key::new(hmac::HMAC_SHA256, secret),
+            message.as_bytes(),
+        );
+        let key = Jwk {
+            kty: "oct".to_string(),
+            kid: Some("test".to_string()),
+            k: Some(base64::encode_config(secret, base64::URL_SAFE_NO_PAD)),
+            ..Default::default()
+        };
+        (
+            format!(
+                "{}.{}",
+                message,
+                base64::encode_config(tag.as_ref(), base64::URL_SAFE_NO_PAD)
+            ),
+            key,
+        )
+    }
+
+    /// A token signed with ECDSA P-256 by a new key pair, and the public key verifying it.
+    pub(crate) fn ec_signed_token(claims: serde_json::Value) -> (String, Jwk) {
+        let rng = SystemRandom::new();
+        let algorithm = &signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(algorithm, &rng).unwrap();
+        let key_pair = EcdsaKeyPair::from_pkcs8(algorithm, pkcs8.as_ref()).unwrap();
+        let message = format!(
+            "{}.{}",
+            base64::encode_config(r#"{"alg":"ES256","kid":"test"}"#, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(claims.to_string(), base64::URL_SAFE_NO_PAD)
+        );
+        let signature = key_pair.sign(&rng, message.as_bytes()).unwrap();
+        // uncompressed point
+        let public_key = key_pair.public_key().as_ref();
+        let key = Jwk {
+            kty: "EC".to_string(),
+            kid: Some("test".to_string()),
+            crv: Some("P-256".to_string()),
+            x: Some(base64::encode_config(
+                &public_key[1..33],
+                base64::URL_SAFE_NO_PAD,
+            )),
+            y: Some(base64::encode_config(
+                &public_key[33..],
+                base64::URL_SAFE_NO_PAD,
+            )),
+            ..Default::default()
+        };
+        (
+            format!(
+                "{}.{}",
+                message,
+                base64::encode_config(signature.as_ref(), base64::URL_SAFE_NO_PAD)
+            ),
+            key,
+        )
+    }
+
+    #[test]
+    fn it_verifies_the_signature() {
+        let (token, key) = signed_token(serde_json::json!({ "sub": "web", "exp": 1000 }));
+        let claims = verify_at(&token, &[key.clone()], 900).unwrap();
+        assert_eq!(claims["sub"], "web");
+
+        let other = Jwk {
+            k: Some(base64::encode_config(b"other", base64::URL_SAFE_NO_PAD)),
+            ..key.clone()
+        };
+        assert_eq!(
+            verify_at(&token, &[other], 900),
+            Err(JwtError::InvalidSignature)
+        );
+        assert_eq!(verify_at(&token, &[], 900), Err(JwtError::InvalidSignature));
+
+        // the claims can not be changed
+        let mut parts: Vec<&str> = token.split('.').collect();
+        let forged = base64::encode_config(r#"{"sub":"admin"}"#, base64::URL_SAFE_NO_PAD);
+        parts[1] = &forged;
+        assert_eq!(
+            verify_at(&parts.join("."), &[key], 900),
+            Err(JwtError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn it_verifies_the_ec_signatures() {
+        let (token, key) = ec_signed_token(serde_json::json!({ "sub": "web" }));
+        assert_eq!(verify_at(&token, &[key], 0).unwrap()["sub"], "web");
+
+        let (_, other) = ec_signed_token(serde_json::json!({}));
+        assert_eq!(
+            verify_at(&token, &[other], 0),
+            Err(JwtError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn it_checks_the_validity_period() {
+        let (token, key) = signed_token(serde_json::json!({ "nbf": 1000, "exp": 2000 }));
+        assert!(verify_at(&token, &[key.clone()], 1500).is_ok());
+        assert_eq!(
+            verify_at(&token, &[key.clone()], 2000 + LEEWAY + 1),
+            Err(JwtError::Expired)
+        );
+        assert_eq!(
+            verify_at(&token, &[key], 1000 - LEEWAY - 1),
+            Err(JwtError::NotYetValid)
+        );
+    }
+
+    #[test]
+    fn it_saturates_the_leeway() {
+        let (token, key) = signed_token(serde_json::json!({ "nbf": u64::MAX, "exp": u64::MAX }));
+        assert!(verify_at(&token, &[key.clone()], u64::MAX).is_ok());
+        assert!(verify_at(&token, &[key], u64::MAX - LEEWAY - 1).is_err());
+    }
+
+    #[test]
+    fn it_checks_the_issuer_and_the_audience() {
+        let claims = |claims: serde_json::Value| claims.as_object().unwrap().clone();
+        let check = |value| check_recipient(&claims(value), "https://auth", "router");
+        assert!(check(serde_json::json!({ "iss": "https://auth", "aud": "router" })).is_ok());
+        assert!(
+            check(serde_json::json!({ "iss": "https://auth", "aud": ["web", "router"] })).is_ok()
+        );
+        assert_eq!(
+            check(serde_json::json!({ "iss": "https://other", "aud": "router" })),
+            Err(JwtError::InvalidIssuer("https://auth".to_string()))
+        );
+        assert_eq!(
+            check(serde_json::json!({ "aud": "router" })),
+            Err(JwtError::InvalidIssuer("https://auth".to_string()))
+        );
+        assert_eq!(
+            check(serde_json::json!({ "iss": "https://auth", "aud": ["web"] })),
+            Err(JwtError::InvalidAudience("router".to_string()))
+        );
+        assert_eq!(
+            check(serde_json::json!({ "iss": "https://auth" })),
+            Err(JwtError::InvalidAudience("router".to_string()))
+        );
+    }
+
+    #[test]
+    fn it_rejects_unsigned_tokens() {
+        let header = base64::encode_config(r#"{"alg":"none"}"#, base64::URL_SAFE_NO_PAD);
+        let payload = base64::encode_config(r#"{"sub":"web"}"#, base64::URL_SAFE_NO_PAD);
+        let (_, key) = signed_token(serde_json::json!({}));
+        assert_eq!(
+            verify_at(&format!("{}.{}.", header, payload), &[key.clone()], 0),
+            Err(JwtError::UnsupportedAlgorithm("none".to_string()))
+        );
+        assert!(matches!(
+            verify_at("not a token", &[key], 0),
+            Err(JwtError::Malformed(_))
+        ));
+    }
+}
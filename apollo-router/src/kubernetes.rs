@@ -0,0 +1,469 @@
+//! Configuration and supergraph schema loaded from Kubernetes ConfigMaps and Secrets.
+//!
+//! The objects are watched through the Kubernetes API with the service account of the pod, so
+//! their updates are applied as soon as they are made, instead of waiting for the kubelet to sync
+//! the mounted volumes. The service account needs the `get` and `watch` permissions on them.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use displaydoc::Display;
+use futures::channel::mpsc;
+use futures::prelude::*;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::configuration::load_configuration;
+use crate::configuration::secrets::Secrets;
+use crate::configuration::Configuration;
+
+/// Directory of the credentials of the pod's service account.
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+/// Duration of each watch request, after which the object is watched again.
+const WATCH_TIMEOUT: Duration = Duration::from_secs(300);
+/// Delay before watching the object again after an error.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Timeout of the requests reading an object, and of the connections to the API server.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Kubernetes errors.
+#[derive(Error, Debug, Display)]
+pub(crate) enum KubernetesError {
+    /// invalid object reference '{0}', expected `configmap:[<namespace>/]<name>/<key>` or `secret:[<namespace>/]<name>/<key>`
+    InvalidReference(String),
+    /// not running in a Kubernetes pod: {0}
+    NotInCluster(String),
+    /// could not read the service account credentials: {0}
+    ServiceAccount(std::io::Error),
+    /// Kubernetes API error: {0}
+    Api(String),
+    /// key not found in {0}
+    MissingKey(ObjectReference),
+}
+
+/// The kind of a Kubernetes object holding a configuration or a schema.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ObjectKind {
+    ConfigMap,
+    Secret,
+}
+
+/// A key of a ConfigMap or of a Secret, written `configmap:[<namespace>/]<name>/<key>` or
+/// `secret:[<namespace>/]<name>/<key>`. The namespace defaults to the one of the pod.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ObjectReference {
+    kind: ObjectKind,
+    namespace: Option<String>,
+    name: String,
+    key: String,
+}
+
+impl FromStr for ObjectReference {
+    type Err = KubernetesError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || KubernetesError::InvalidReference(s.to_string());
+        let (kind, path) = s.split_once(':').ok_or_else(invalid)?;
+        let kind = match kind.to_ascii_lowercase().as_str() {
+            "configmap" => ObjectKind::ConfigMap,
+            "secret" => ObjectKind::Secret,
+            _ => return Err(invalid()),
+        };
+        let parts: Vec<&str> = path.split('/').collect();
+        if parts.iter().any(|part| part.is_empty()) {
+            return Err(invalid());
+        }
+        let (namespace, name, key) = match parts.as_slice() {
+            [name, key] => (None, name, key),
+            [namespace, name, key] => (Some(namespace.to_string()), name, key),
+            _ => return Err(invalid()),
+        };
+        Ok(ObjectReference {
+            kind,
+            namespace,
+            name: name.to_string(),
+            key: key.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for ObjectReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            ObjectKind::ConfigMap => "configmap",
+            ObjectKind::Secret => "secret",
+        };
+        match &self.namespace {
+            Some(namespace) => write!(f, "{}:{}/{}/{}", kind, namespace, self.name, self.key),
+            None => write!(f, "{}:{}/{}", kind, self.name, self.key),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Object {
+    metadata: Metadata,
+    #[serde(default)]
+    data: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Metadata {
+    resource_version: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Status {
+    code: Option<u16>,
+    message: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", content = "object", rename_all = "UPPERCASE")]
+enum WatchEvent {
+    Added(Object),
+    Modified(Object),
+    Deleted(Object),
+    Bookmark(Object),
+    Error(Status),
+}
+
+impl ObjectReference {
+    /// The value of the key in the object, decoded for Secrets.
+    fn value(&self, object: &Object) -> Result<String, KubernetesError> {
+        let value = object
+            .data
+            .get(&self.key)
+            .ok_or_else(|| KubernetesError::MissingKey(self.clone()))?;
+        match self.kind {
+            ObjectKind::ConfigMap => Ok(value.clone()),
+            ObjectKind::Secret => base64::decode(value)
+                .ok()
+                .and_then(|value| String::from_utf8(value).ok())
+                .ok_or_else(|| KubernetesError::Api(format!("invalid data in {}", self))),
+        }
+    }
+}
+
+/// Client of the Kubernetes API, with the credentials of the pod's service account.
+struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    /// The token is read again for each request, since the kubelet rotates bound tokens.
+    token_path: PathBuf,
+    namespace: String,
+}
+
+impl Client {
+    fn in_cluster() -> Result<Self, KubernetesError> {
+        let env = |name: &str| {
+            std::env::var(name)
+                .map_err(|_| KubernetesError::NotInCluster(format!("{} is not set", name)))
+        };
+        let host = env("KUBERNETES_SERVICE_HOST")?;
+        let port = env("KUBERNETES_SERVICE_PORT")?;
+        let read = |file: &str| {
+            std::fs::read(Path::new(SERVICE_ACCOUNT_DIR).join(file))
+                .map_err(KubernetesError::ServiceAccount)
+        };
+        let certificate = reqwest::Certificate::from_pem(&read("ca.crt")?)
+            .map_err(|e| KubernetesError::NotInCluster(e.to_string()))?;
+        let http = reqwest::Client::builder()
+            .add_root_certificate(certificate)
+            .connect_timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| KubernetesError::NotInCluster(e.to_string()))?;
+
+        // the token is checked now, so that a pod without it fails to start
+        let token_path = Path::new(SERVICE_ACCOUNT_DIR).join("token");
+        read("token")?;
+
+        Ok(Client {
+            http,
+            // IPv6 addresses must be enclosed in brackets
+            base_url: if host.contains(':') {
+                format!("https://[{}]:{}", host, port)
+            } else {
+                format!("https://{}:{}", host, port)
+            },
+            token_path,
+            namespace: String::from_utf8_lossy(&read("namespace")?)
+                .trim()
+                .to_string(),
+        })
+    }
+
+    fn objects_url(&self, reference: &ObjectReference) -> String {
+        format!(
+            "{}/api/v1/namespaces/{}/{}",
+            self.base_url,
+            reference.namespace.as_ref().unwrap_or(&self.namespace),
+            match reference.kind {
+                ObjectKind::ConfigMap => "configmaps",
+                ObjectKind::Secret => "secrets",
+            }
+        )
+    }
+
+    async fn send(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, KubernetesError> {
+        let token = tokio::fs::read_to_string(&self.token_path)
+            .await
+            .map_err(KubernetesError::ServiceAccount)?;
+        request
+            .bearer_auth(token.trim())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| KubernetesError::Api(e.to_string()))
+    }
+
+    async fn get(&self, reference: &ObjectReference) -> Result<Object, KubernetesError> {
+        let url = format!("{}/{}", self.objects_url(reference), reference.name);
+        self.send(self.http.get(url).timeout(REQUEST_TIMEOUT))
+            .await?
+            .json()
+            .await
+            .map_err(|e| KubernetesError::Api(e.to_string()))
+    }
+
+    /// The events of the object from a resource version, until the watch request times out.
+    async fn watch(
+        &self,
+        reference: &ObjectReference,
+        resource_version: &str,
+    ) -> Result<impl Stream<Item = Result<WatchEvent, KubernetesError>>, KubernetesError> {
+        let field_selector = format!("metadata.name={}", reference.name);
+        let timeout = WATCH_TIMEOUT.as_secs().to_string();
+        // the API server ends the watch after its timeout, the request gets some leeway on top
+        let request = self
+            .http
+            .get(self.objects_url(reference))
+            .timeout(WATCH_TIMEOUT + REQUEST_TIMEOUT)
+            .query(&[
+                ("watch", "true"),
+                ("allowWatchBookmarks", "true"),
+                ("fieldSelector", field_selector.as_str()),
+                ("resourceVersion", resource_version),
+                ("timeoutSeconds", timeout.as_str()),
+            ]);
+        let body = self.send(request).await?.bytes_stream();
+
+        // the events are sent as JSON objects, one per line
+        let mut buffer = Vec::new();
+        Ok(body
+            .map(move |chunk| {
+                let chunk = chunk.map_err(|e| KubernetesError::Api(e.to_string()))?;
+                buffer.extend_from_slice(&chunk);
+                let mut lines = Vec::new();
+                while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..=end).collect();
+                    lines.push(line);
+                }
+                Ok::<_, KubernetesError>(lines)
+            })
+            .map_ok(|lines| {
+                stream::iter(lines.into_iter().map(|line| {
+                    serde_json::from_slice(&line).map_err(|e| KubernetesError::Api(e.to_string()))
+                }))
+            })
+            .try_flatten())
+    }
+}
+
+/// Send the value of the key when it changes, until the receiver is dropped or the watch ends.
+async fn watch_changes(
+    client: &Client,
+    reference: &ObjectReference,
+    previous: &mut Option<String>,
+    sender: &mut mpsc::Sender<String>,
+) -> Result<(), KubernetesError> {
+    let mut changed = |object: &Object| -> Result<Option<String>, KubernetesError> {
+        let value = reference.value(object)?;
+        if previous.as_ref() == Some(&value) {
+            return Ok(None);
+        }
+        *previous = Some(value.clone());
+        Ok(Some(value))
+    };
+
+    let object = client.get(reference).await?;
+    if let Some(value) = changed(&object)? {
+        if sender.send(value).await.is_err() {
+            return Ok(());
+        }
+    }
+    let mut resource_version = object.metadata.resource_version.unwrap_or_default();
+
+    let events = client.watch(reference, &resource_version).await?;
+    tokio::pin!(events);
+    while let Some(event) = events.next().await {
+        let object = match event? {
+            WatchEvent::Added(object) | WatchEvent::Modified(object) => object,
+            WatchEvent::Bookmark(object) => {
+                resource_version = object.metadata.resource_version.unwrap_or(resource_version);
+                continue;
+            }
+            WatchEvent::Deleted(_) => {
+                tracing::warn!("{} was deleted, the router keeps its last value", reference);
+                continue;
+            }
+            WatchEvent::Error(status) => {
+                // the resource version expired, the object is read again
+                if status.code == Some(410) {
+                    return Ok(());
+                }
+                return Err(KubernetesError::Api(status.message.unwrap_or_default()));
+            }
+        };
+        resource_version = object
+            .metadata
+            .resource_version
+            .clone()
+            .unwrap_or(resource_version);
+        match changed(&object) {
+            Ok(Some(value)) => {
+                tracing::info!("{} changed", reference);
+                if sender.send(value).await.is_err() {
+                    return Ok(());
+                }
+            }
+            Ok(None) => {}
+            Err(e) => tracing::error!("{}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Creates a stream of the values of a ConfigMap or Secret key: its current value, then every new
+/// value. The stream never terminates and must be dropped to finish watching.
+///
+/// Fails if the router does not run in a Kubernetes pod with a service account.
+pub(crate) fn watch(
+    reference: ObjectReference,
+) -> Result<impl Stream<Item = String>, KubernetesError> {
+    let client = Client::in_cluster()?;
+    let (mut sender, receiver) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let mut previous = None;
+        while !sender.is_closed() {
+            if let Err(e) = watch_changes(&client, &reference, &mut previous, &mut sender).await {
+                tracing::error!("cannot watch {}: {}", reference, e);
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+/// Creates a stream of the configurations of a ConfigMap or Secret key, also loaded again when
+/// their secrets are renewed with new values. Invalid configurations are logged and skipped, so
+/// the router keeps the last valid one.
+pub(crate) fn watch_configuration(
+    reference: ObjectReference,
+) -> Result<impl Stream<Item = Configuration>, KubernetesError> {
+    // the secrets are scoped to the watch, and renewed until the stream is dropped
+    let secrets = Secrets::default();
+    let updates = stream::select(watch(reference)?.map(Some), secrets.changes().map(|_| None));
+    let mut last = None;
+    Ok(updates.filter_map(move |update| {
+        if update.is_some() {
+            last = update;
+        }
+        let configuration = last.clone();
+        let secrets = secrets.clone();
+        async move {
+            match load_configuration(&configuration?, &secrets).await {
+                Ok(configuration) => Some(configuration),
+                Err(e) => {
+                    tracing::error!("{}", e);
+                    None
+                }
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn it_parses_the_object_references() {
+        assert_eq!(
+            "configmap:router/router.yaml"
+                .parse::<ObjectReference>()
+                .unwrap(),
+            ObjectReference {
+                kind: ObjectKind::ConfigMap,
+                namespace: None,
+                name: "router".to_string(),
+                key: "router.yaml".to_string(),
+            }
+        );
+        let secret: ObjectReference = "secret:graphs/supergraph/schema.graphql".parse().unwrap();
+        assert_eq!(secret.kind, ObjectKind::Secret);
+        assert_eq!(secret.namespace.as_deref(), Some("graphs"));
+        assert_eq!(
+            secret.to_string(),
+            "secret:graphs/supergraph/schema.graphql"
+        );
+
+        assert!("router/router.yaml".parse::<ObjectReference>().is_err());
+        assert!("volume:router/router.yaml"
+            .parse::<ObjectReference>()
+            .is_err());
+        assert!("configmap:router".parse::<ObjectReference>().is_err());
+        assert!("configmap:router//router.yaml"
+            .parse::<ObjectReference>()
+            .is_err());
+    }
+
+    #[test]
+    fn it_reads_the_values_of_the_watch_events() {
+        let event: WatchEvent = serde_json::from_value(json!({
+            "type": "MODIFIED",
+            "object": {
+                "kind": "Secret",
+                "metadata": { "name": "supergraph", "resourceVersion": "42" },
+                "data": { "schema.graphql": base64::encode("schema { query: Query }") },
+            },
+        }))
+        .unwrap();
+        let object = match event {
+            WatchEvent::Modified(object) => object,
+            _ => panic!("expected a modification"),
+        };
+        assert_eq!(object.metadata.resource_version.as_deref(), Some("42"));
+
+        let secret: ObjectReference = "secret:supergraph/schema.graphql".parse().unwrap();
+        assert_eq!(secret.value(&object).unwrap(), "schema { query: Query }");
+        let missing: ObjectReference = "secret:supergraph/other.graphql".parse().unwrap();
+        assert!(matches!(
+            missing.value(&object),
+            Err(KubernetesError::MissingKey(_))
+        ));
+
+        let event: WatchEvent = serde_json::from_value(json!({
+            "type": "ERROR",
+            "object": { "kind": "Status", "code": 410, "message": "too old resource version" },
+        }))
+        .unwrap();
+        assert!(matches!(
+            event,
+            WatchEvent::Error(Status {
+                code: Some(410),
+                ..
+            })
+        ));
+    }
+}
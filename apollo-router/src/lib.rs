@@ -47,39 +47,64 @@ mod json_ext;
 #[macro_use]
 pub mod plugin;
 
+mod admin;
 mod axum_http_server_factory;
 mod cache;
+mod canary;
+mod check;
+mod composition;
 mod configuration;
 mod context;
+mod entitlement;
 mod error;
 mod executable;
+mod extensions;
 mod files;
 pub mod graphql;
+mod graphql_over_http;
 mod http_ext;
 mod http_server_factory;
 mod introspection;
+mod jwt;
+mod kubernetes;
 pub mod layers;
+mod load_shedding;
+mod memory;
+mod operation_registry;
+mod panics;
+mod plan_diff;
 mod plugins;
 mod query_planner;
 mod request;
 mod response;
 mod router;
+mod router_builder;
 mod router_factory;
+mod serialization;
 pub mod services;
+pub mod signature;
 mod spec;
 mod state_machine;
+mod supervisor;
+#[cfg(unix)]
+mod systemd;
 mod test_harness;
+mod webhooks;
 
 pub use crate::configuration::Configuration;
 pub use crate::configuration::ListenAddr;
 pub use crate::context::Context;
+pub use crate::context::ContextKey;
+pub use crate::context::ContextSubscription;
 pub use crate::executable::main;
 pub use crate::executable::Executable;
+pub use crate::memory::AccountingAllocator;
 pub use crate::router::ApolloRouterError;
 pub use crate::router::ConfigurationSource;
 pub use crate::router::RouterHttpServer;
 pub use crate::router::SchemaSource;
 pub use crate::router::ShutdownSource;
+pub use crate::router_builder::RouterBuilder;
 pub use crate::test_harness::TestHarness;
 
 /// Not part of the public API
@@ -0,0 +1,284 @@
+//! Adaptive load shedding of the requests received by a listener.
+//!
+//! The number of requests processed at the same time is limited, and the limit follows an AIMD
+//! control loop: it grows by one request every `limit` requests completing within the latency
+//! target, and is multiplied by the backoff ratio each time a request exceeds it. Requests above
+//! the limit are rejected right away with a 503 status code and a `Retry-After` header, so that
+//! an overloaded router keeps serving the requests it accepted within the latency target.
+//!
+//! Requests are also rejected while the memory usage of the router is above its soft limit, unless
+//! no other request is in flight: the memory is then not used by the requests, and rejecting them
+//! would not lower it.
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use http::header::RETRY_AFTER;
+use http::HeaderValue;
+use http::Request;
+
+use crate::configuration::LoadShedding;
+use crate::configuration::Memory;
+use crate::memory::memory_usage;
+
+/// Shared state of the load shedding of a listener.
+#[derive(Debug)]
+pub(crate) struct LoadShedder {
+    config: LoadShedding,
+    limit: Mutex<f64>,
+    in_flight: AtomicUsize,
+}
+
+impl LoadShedder {
+    pub(crate) fn new(mut config: LoadShedding) -> Self {
+        // with a limit of zero, no request would ever complete to raise it again
+        config.min_limit = config.min_limit.max(1);
+        let limit = config
+            .initial_limit
+            .clamp(config.min_limit, config.max_limit.max(config.min_limit));
+        LoadShedder {
+            config,
+            limit: Mutex::new(limit as f64),
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    /// The current concurrency limit.
+    pub(crate) fn limit(&self) -> usize {
+        *self.limit.lock().expect("lock poisoned") as usize
+    }
+
+    /// Reserve a slot for a request, unless the concurrency limit is reached.
+    fn try_acquire(self: &Arc<Self>) -> Option<InFlight> {
+        let limit = self.limit();
+        self.in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |in_flight| {
+                (in_flight < limit).then(|| in_flight + 1)
+            })
+            .ok()
+            .map(|_| InFlight {
+                shedder: self.clone(),
+            })
+    }
+
+    /// Adapt the concurrency limit to the latency of a completed request.
+    fn record_latency(&self, latency: Duration) {
+        let mut limit = self.limit.lock().expect("lock poisoned");
+        let max_limit = self.config.max_limit.max(self.config.min_limit) as f64;
+        *limit = if latency > self.config.latency_target {
+            (*limit * self.config.backoff_ratio).max(self.config.min_limit as f64)
+        } else {
+            (*limit + 1.0 / *limit).min(max_limit)
+        };
+    }
+
+    fn rejection(&self) -> Response {
+        rejection(self.config.retry_after)
+    }
+}
+
+fn rejection(retry_after: Duration) -> Response {
+    let mut response = (
+        StatusCode::SERVICE_UNAVAILABLE,
+        "the router is overloaded, try again later",
+    )
+        .into_response();
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(retry_after.as_secs().max(1)));
+    response
+}
+
+/// A request counted in the requests in flight until it is dropped.
+struct InFlight {
+    shedder: Arc<LoadShedder>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.shedder.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Middleware rejecting the requests above the concurrency limit.
+pub(crate) async fn shed_load<B>(
+    shedder: Arc<LoadShedder>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    match shedder.try_acquire() {
+        Some(_in_flight) => {
+            let start = Instant::now();
+            let response = next.run(request).await;
+            shedder.record_latency(start.elapsed());
+            response
+        }
+        None => {
+            tracing::debug!(
+                "concurrency limit of {} requests reached, rejecting the request",
+                shedder.limit()
+            );
+            shedder.rejection()
+        }
+    }
+}
+
+/// Shared state of the soft memory limit of a listener.
+#[derive(Debug)]
+pub(crate) struct MemoryShedder {
+    config: Memory,
+    in_flight: AtomicUsize,
+}
+
+impl MemoryShedder {
+    pub(crate) fn new(config: Memory) -> Self {
+        MemoryShedder {
+            config,
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    /// Count a request in flight, unless the memory usage is above the soft limit while other
+    /// requests are in flight.
+    fn try_acquire(self: &Arc<Self>) -> Option<MemoryInFlight> {
+        let others = self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let in_flight = MemoryInFlight {
+            shedder: self.clone(),
+        };
+        match memory_usage() {
+            Some(usage) if others > 0 && usage > self.config.soft_limit => {
+                tracing::debug!(
+                    "memory usage of {} bytes above the soft limit of {} bytes, rejecting the request",
+                    usage,
+                    self.config.soft_limit
+                );
+                None
+            }
+            _ => Some(in_flight),
+        }
+    }
+}
+
+/// A request counted in the requests in flight of a [`MemoryShedder`] until it is dropped.
+struct MemoryInFlight {
+    shedder: Arc<MemoryShedder>,
+}
+
+impl Drop for MemoryInFlight {
+    fn drop(&mut self) {
+        self.shedder.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Middleware rejecting the requests while the memory usage is above the soft limit.
+pub(crate) async fn shed_memory<B>(
+    shedder: Arc<MemoryShedder>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    match shedder.try_acquire() {
+        Some(_in_flight) => next.run(request).await,
+        None => rejection(shedder.config.retry_after),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::middleware;
+    use axum::routing::get;
+    use axum::Router;
+    use hyper::Body;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn shedder() -> Arc<LoadShedder> {
+        Arc::new(LoadShedder::new(LoadShedding {
+            initial_limit: 2,
+            min_limit: 1,
+            max_limit: 3,
+            latency_target: Duration::from_millis(100),
+            ..Default::default()
+        }))
+    }
+
+    #[test]
+    fn it_rejects_requests_above_the_limit() {
+        let shedder = shedder();
+        let first = shedder.try_acquire();
+        let second = shedder.try_acquire();
+        assert!(first.is_some());
+        assert!(second.is_some());
+        assert!(shedder.try_acquire().is_none());
+
+        drop(first);
+        assert!(shedder.try_acquire().is_some());
+
+        let response = shedder.rejection();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "1");
+    }
+
+    #[test]
+    fn it_adapts_the_limit_to_the_latency() {
+        let shedder = shedder();
+
+        // additive increase while within the latency target, up to the maximum
+        shedder.record_latency(Duration::from_millis(10));
+        shedder.record_latency(Duration::from_millis(10));
+        assert_eq!(shedder.limit(), 2);
+        shedder.record_latency(Duration::from_millis(10));
+        assert_eq!(shedder.limit(), 3);
+        for _ in 0..10 {
+            shedder.record_latency(Duration::from_millis(10));
+        }
+        assert_eq!(shedder.limit(), 3);
+
+        // multiplicative decrease when the latency target is exceeded, down to the minimum
+        shedder.record_latency(Duration::from_millis(200));
+        assert_eq!(shedder.limit(), 2);
+        for _ in 0..10 {
+            shedder.record_latency(Duration::from_millis(200));
+        }
+        assert_eq!(shedder.limit(), 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn it_rejects_requests_above_the_memory_limit() {
+        let shedder = |soft_limit| {
+            Arc::new(MemoryShedder::new(Memory {
+                soft_limit,
+                retry_after: Duration::from_secs(2),
+            }))
+        };
+        let router = |shedder: Arc<MemoryShedder>| {
+            Router::new()
+                .route("/", get(|| async { "ok" }))
+                .layer(middleware::from_fn(move |request, next| {
+                    shed_memory(shedder.clone(), request, next)
+                }))
+        };
+        let request = || Request::get("/").body(Body::empty()).unwrap();
+
+        let response = router(shedder(u64::MAX)).oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // the only request in flight is served whatever the memory usage
+        let above = shedder(1);
+        let response = router(above.clone()).oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let _other = above.try_acquire().unwrap();
+        let response = router(above.clone()).oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "2");
+    }
+}
@@ -0,0 +1,153 @@
+//! Memory usage of the router.
+//!
+//! The resident memory is read from `/proc` on Linux, and the heap usage is tracked when the
+//! [`AccountingAllocator`] is the global allocator of a custom binary. Both are exported as
+//! metrics, and the soft memory limit sheds the requests while the memory usage is above it.
+use std::alloc::GlobalAlloc;
+use std::alloc::Layout;
+use std::alloc::System;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+
+/// Minimum delay between two reads of the resident memory, which are too slow for every request.
+const RESIDENT_MEMORY_SAMPLING_MILLIS: u64 = 100;
+
+/// Bytes allocated through the [`AccountingAllocator`].
+static HEAP: AtomicUsize = AtomicUsize::new(0);
+
+static START: Lazy<Instant> = Lazy::new(Instant::now);
+static RESIDENT: AtomicU64 = AtomicU64::new(0);
+/// When the resident memory was last read, in milliseconds since [`START`].
+static RESIDENT_SAMPLED_AT: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Global allocator tracking the heap usage of the router, on top of the system allocator.
+///
+/// ```no_run
+/// #[global_allocator]
+/// static ALLOCATOR: apollo_router::AccountingAllocator = apollo_router::AccountingAllocator;
+///
+/// fn main() -> anyhow::Result<()> {
+///     apollo_router::main()
+/// }
+/// ```
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AccountingAllocator;
+
+unsafe impl GlobalAlloc for AccountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            HEAP.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            HEAP.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        HEAP.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            if new_size > layout.size() {
+                HEAP.fetch_add(new_size - layout.size(), Ordering::Relaxed);
+            } else {
+                HEAP.fetch_sub(layout.size() - new_size, Ordering::Relaxed);
+            }
+        }
+        new_ptr
+    }
+}
+
+/// Bytes allocated on the heap, if the [`AccountingAllocator`] is the global allocator.
+pub(crate) fn heap_usage() -> Option<u64> {
+    // every program allocates before reaching this point
+    match HEAP.load(Ordering::Relaxed) {
+        0 => None,
+        heap => Some(heap as u64),
+    }
+}
+
+/// Resident memory of the process, in bytes, on Linux.
+#[cfg(target_os = "linux")]
+pub(crate) fn resident_memory() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // Safety: sysconf has no preconditions
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    (page_size > 0).then(|| pages * page_size as u64)
+}
+
+/// Resident memory of the process, unavailable on the other platforms.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn resident_memory() -> Option<u64> {
+    None
+}
+
+/// Memory usage compared with the soft memory limit: the heap usage if it is tracked, since it goes
+/// down as soon as the memory is freed, otherwise the resident memory, read again at most every
+/// 100ms.
+pub(crate) fn memory_usage() -> Option<u64> {
+    if let Some(heap) = heap_usage() {
+        return Some(heap);
+    }
+    let now = START.elapsed().as_millis() as u64;
+    let sampled_at = RESIDENT_SAMPLED_AT.load(Ordering::Relaxed);
+    let outdated =
+        sampled_at == u64::MAX || now.saturating_sub(sampled_at) >= RESIDENT_MEMORY_SAMPLING_MILLIS;
+    // only one of the concurrent requests reads the resident memory again
+    if outdated
+        && RESIDENT_SAMPLED_AT
+            .compare_exchange(sampled_at, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+    {
+        if let Some(resident) = resident_memory() {
+            RESIDENT.store(resident, Ordering::Relaxed);
+        }
+    }
+    match RESIDENT.load(Ordering::Relaxed) {
+        0 => None,
+        resident => Some(resident),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_tracks_the_heap_usage() {
+        // the accounting allocator is not the global allocator of the tests
+        assert_eq!(heap_usage(), None);
+
+        let layout = Layout::from_size_align(1024, 8).unwrap();
+        unsafe {
+            let ptr = AccountingAllocator.alloc(layout);
+            assert_eq!(heap_usage(), Some(1024));
+            let ptr = AccountingAllocator.realloc(ptr, layout, 2048);
+            assert_eq!(heap_usage(), Some(2048));
+            AccountingAllocator.dealloc(ptr, Layout::from_size_align(2048, 8).unwrap());
+        }
+        assert_eq!(heap_usage(), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn it_reads_the_resident_memory() {
+        assert!(resident_memory().unwrap() > 0);
+        assert!(memory_usage().unwrap() > 0);
+    }
+}
@@ -0,0 +1,440 @@
+//! Persisted query manifests fetched from the Apollo operation registry.
+//!
+//! The registry is polled through Apollo uplink with the `APOLLO_KEY` and `APOLLO_GRAPH_REF` of
+//! the graph. Each new manifest is registered and activated in the [`PersistedQueryRegistry`]
+//! under the id the registry gave it, and written to the cache file if one is configured: when the
+//! router starts, the cached manifest is activated until the registry answers, so that it does
+//! not depend on the registry being available to serve the persisted queries.
+//!
+//! The registry is asked for the manifests after the last one it sent, which is not necessarily
+//! the active one: the most recent manifest wins, so a manifest pushed through the admin API stays
+//! active until the registry publishes a new one.
+
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::json;
+use serde_json::Value;
+use tokio::task::JoinHandle;
+use tower::BoxError;
+use url::Url;
+
+use crate::configuration::OperationRegistry;
+use crate::services::layers::persisted_queries::Manifest;
+use crate::services::layers::persisted_queries::ManifestOperation;
+use crate::services::layers::persisted_queries::PersistedQueryRegistry;
+use crate::services::layers::persisted_queries::RegistrationError;
+
+const GCP_URL: &str = "https://uplink.api.apollographql.com/";
+const AWS_URL: &str = "https://aws.uplink.api.apollographql.com/";
+/// Timeout of the requests to uplink and of the downloads of the manifests.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Format of the manifests of the operation registry.
+const MANIFEST_FORMAT: &str = "apollo-persisted-query-manifest";
+/// Maximum size of a chunk of a manifest.
+const MAX_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+const QUERY: &str = r#"query PersistedQueriesManifestQuery($apiKey: String!, $graphRef: String!, $ifAfterId: ID) {
+  persistedQueries(ref: $graphRef, apiKey: $apiKey, ifAfterId: $ifAfterId) {
+    __typename
+    ... on PersistedQueriesResult {
+      id
+      minDelaySeconds
+      chunks {
+        id
+        urls
+      }
+    }
+    ... on Unchanged {
+      id
+      minDelaySeconds
+    }
+    ... on FetchError {
+      code
+      message
+    }
+  }
+}"#;
+
+/// Answer of uplink to the `persistedQueries` query.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "__typename")]
+enum PersistedQueriesResponse {
+    #[serde(rename_all = "camelCase")]
+    PersistedQueriesResult {
+        id: String,
+        min_delay_seconds: f64,
+        /// The manifest is split in chunks, `null` if no persisted query list is linked to the
+        /// variant of the graph.
+        chunks: Option<Vec<Chunk>>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Unchanged {
+        min_delay_seconds: f64,
+    },
+    FetchError {
+        code: String,
+        message: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct Chunk {
+    id: String,
+    /// Mirrors of the chunk, tried in turn.
+    urls: Vec<String>,
+}
+
+/// A chunk of a manifest of the operation registry.
+#[derive(Debug, Deserialize)]
+struct ChunkManifest {
+    format: String,
+    version: u64,
+    operations: Vec<ChunkOperation>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChunkOperation {
+    id: String,
+    body: String,
+}
+
+/// What the registry answered.
+enum Fetched {
+    Manifest(Manifest),
+    Unchanged,
+}
+
+/// Polling of the operation registry, running until it is dropped.
+pub(crate) struct OperationRegistryPoller {
+    task: JoinHandle<()>,
+}
+
+impl OperationRegistryPoller {
+    pub(crate) fn spawn(
+        configuration: OperationRegistry,
+        registry: PersistedQueryRegistry,
+    ) -> Result<Self, BoxError> {
+        let api_key = std::env::var("APOLLO_KEY")
+            .map_err(|_| "the operation registry needs the APOLLO_KEY environment variable")?;
+        let graph_ref = std::env::var("APOLLO_GRAPH_REF").map_err(|_| {
+            "the operation registry needs the APOLLO_GRAPH_REF environment variable"
+        })?;
+        let urls = if configuration.urls.is_empty() {
+            vec![Url::parse(GCP_URL)?, Url::parse(AWS_URL)?]
+        } else {
+            configuration.urls.clone()
+        };
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+
+        if let Some(path) = &configuration.cache_path {
+            // the manifests of the previous configurations are still active on reloads
+            if registry.status().active.is_none() && path.exists() {
+                match load_cached(path, &registry) {
+                    Ok(version) => tracing::info!(
+                        "activated the cached persisted query manifest '{}'",
+                        version
+                    ),
+                    Err(e) => tracing::warn!(
+                        "could not activate the cached persisted query manifest: {}",
+                        e
+                    ),
+                }
+            }
+        }
+
+        let task = tokio::spawn(async move {
+            let mut current_url = 0;
+            loop {
+                let last_version = registry.operation_registry_version();
+                let delay = match fetch(
+                    &client,
+                    &urls[current_url],
+                    &api_key,
+                    &graph_ref,
+                    last_version.as_deref(),
+                )
+                .await
+                {
+                    // a manifest sent again is not activated again over the manifests pushed
+                    // through the admin API since
+                    Ok((Fetched::Manifest(manifest), delay))
+                        if last_version.as_deref() == Some(manifest.version.as_str()) =>
+                    {
+                        tracing::trace!("the persisted query manifest did not change");
+                        delay
+                    }
+                    Ok((Fetched::Manifest(manifest), delay)) => {
+                        let version = manifest.version.clone();
+                        registry.set_operation_registry_version(version.clone());
+                        if let Some(path) = &configuration.cache_path {
+                            if let Err(e) = write_cached(path, &manifest).await {
+                                tracing::warn!(
+                                    "could not cache the persisted query manifest: {}",
+                                    e
+                                );
+                            }
+                        }
+                        match activate(&registry, manifest) {
+                            Ok(()) => tracing::info!(
+                                "activated the persisted query manifest '{}' of the operation registry",
+                                version
+                            ),
+                            Err(e) => tracing::error!(
+                                "could not activate the persisted query manifest '{}': {}",
+                                version,
+                                e
+                            ),
+                        }
+                        delay
+                    }
+                    Ok((Fetched::Unchanged, delay)) => {
+                        tracing::trace!("the persisted query manifest did not change");
+                        delay
+                    }
+                    Err(e) => {
+                        tracing::warn!(
+                            "could not fetch the persisted query manifest from {}: {}",
+                            urls[current_url],
+                            e
+                        );
+                        current_url = (current_url + 1) % urls.len();
+                        Duration::ZERO
+                    }
+                };
+                tokio::time::sleep(delay.max(configuration.poll_interval)).await;
+            }
+        });
+        Ok(Self { task })
+    }
+}
+
+impl Drop for OperationRegistryPoller {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Register a manifest and activate it, or activate it again if it was registered already.
+fn activate(
+    registry: &PersistedQueryRegistry,
+    manifest: Manifest,
+) -> Result<(), RegistrationError> {
+    match registry.register(Manifest {
+        activate: true,
+        ..manifest
+    }) {
+        Err(RegistrationError::VersionExists(version)) => registry.activate(&version),
+        result => result,
+    }
+}
+
+fn load_cached(path: &Path, registry: &PersistedQueryRegistry) -> Result<String, BoxError> {
+    let manifest: Manifest = serde_json::from_slice(&std::fs::read(path)?)?;
+    let version = manifest.version.clone();
+    activate(registry, manifest)?;
+    registry.set_operation_registry_version(version.clone());
+    Ok(version)
+}
+
+/// Write the manifest to the cache file, through a temporary file so that a crash never leaves
+/// a truncated manifest.
+async fn write_cached(path: &Path, manifest: &Manifest) -> Result<(), BoxError> {
+    let temporary = path.with_extension("tmp");
+    tokio::fs::write(&temporary, serde_json::to_vec(manifest)?).await?;
+    tokio::fs::rename(&temporary, path).await?;
+    Ok(())
+}
+
+/// Fetch the manifest of the graph if it changed since the last version fetched, with the delay
+/// the registry asks for before the next poll.
+async fn fetch(
+    client: &reqwest::Client,
+    url: &Url,
+    api_key: &str,
+    graph_ref: &str,
+    last_version: Option<&str>,
+) -> Result<(Fetched, Duration), BoxError> {
+    let response: Value = client
+        .post(url.clone())
+        .json(&json!({
+            "query": QUERY,
+            "operationName": "PersistedQueriesManifestQuery",
+            "variables": {
+                "apiKey": api_key,
+                "graphRef": graph_ref,
+                "ifAfterId": last_version,
+            },
+        }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let response = response
+        .pointer("/data/persistedQueries")
+        .cloned()
+        .ok_or_else(|| format!("unexpected uplink response: {}", response))?;
+
+    match serde_json::from_value(response)? {
+        PersistedQueriesResponse::PersistedQueriesResult {
+            id,
+            min_delay_seconds,
+            chunks,
+        } => {
+            let mut operations = Vec::new();
+            for chunk in chunks.unwrap_or_default() {
+                operations.extend(fetch_chunk(client, &chunk).await?);
+            }
+            let manifest = Manifest {
+                version: id,
+                operations,
+                activate: true,
+            };
+            Ok((Fetched::Manifest(manifest), delay(min_delay_seconds)))
+        }
+        PersistedQueriesResponse::Unchanged { min_delay_seconds } => {
+            Ok((Fetched::Unchanged, delay(min_delay_seconds)))
+        }
+        PersistedQueriesResponse::FetchError { code, message } => {
+            Err(format!("{}: {}", code, message).into())
+        }
+    }
+}
+
+/// Download the operations of a chunk, from the first mirror answering.
+async fn fetch_chunk(
+    client: &reqwest::Client,
+    chunk: &Chunk,
+) -> Result<Vec<ManifestOperation>, BoxError> {
+    let mut error: BoxError = format!("chunk '{}' has no URL", chunk.id).into();
+    for url in &chunk.urls {
+        match download(client, url).await {
+            Ok(manifest) => {
+                return Ok(manifest
+                    .operations
+                    .into_iter()
+                    .map(|operation| ManifestOperation {
+                        id: operation.id,
+                        body: operation.body,
+                        clients: Vec::new(),
+                    })
+                    .collect())
+            }
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+async fn download(client: &reqwest::Client, url: &str) -> Result<ChunkManifest, BoxError> {
+    let too_large = || format!("the chunk is larger than {} bytes", MAX_CHUNK_SIZE);
+    let mut response = client.get(url).send().await?.error_for_status()?;
+    if matches!(response.content_length(), Some(length) if length > MAX_CHUNK_SIZE as u64) {
+        return Err(too_large().into());
+    }
+    let mut body = Vec::new();
+    while let Some(bytes) = response.chunk().await? {
+        if body.len() + bytes.len() > MAX_CHUNK_SIZE {
+            return Err(too_large().into());
+        }
+        body.extend_from_slice(&bytes);
+    }
+    let manifest: ChunkManifest = serde_json::from_slice(&body)?;
+    if manifest.format != MANIFEST_FORMAT || manifest.version != 1 {
+        return Err(format!(
+            "unsupported manifest format '{}' version {}",
+            manifest.format, manifest.version
+        )
+        .into());
+    }
+    Ok(manifest)
+}
+
+fn delay(seconds: f64) -> Duration {
+    if seconds.is_finite() && seconds > 0.0 {
+        Duration::from_secs_f64(seconds)
+    } else {
+        Duration::ZERO
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const HASH: &str = "ecf4edb46db40b5132295c0291d62fb65d6759a9eedfa4d5d612dd5ec54a6b38";
+
+    #[test]
+    fn it_reads_the_uplink_responses() {
+        let response: PersistedQueriesResponse = serde_json::from_value(json!({
+            "__typename": "PersistedQueriesResult",
+            "id": "pq-1",
+            "minDelaySeconds": 60.0,
+            "chunks": [{ "id": "chunk-1", "urls": ["https://storage/chunk-1.json"] }],
+        }))
+        .unwrap();
+        assert!(matches!(
+            response,
+            PersistedQueriesResponse::PersistedQueriesResult { id, chunks: Some(chunks), .. }
+                if id == "pq-1" && chunks[0].urls.len() == 1
+        ));
+
+        let response: PersistedQueriesResponse = serde_json::from_value(json!({
+            "__typename": "Unchanged",
+            "id": "pq-1",
+            "minDelaySeconds": 30,
+        }))
+        .unwrap();
+        assert!(matches!(
+            response,
+            PersistedQueriesResponse::Unchanged { .. }
+        ));
+        assert_eq!(delay(30.0), Duration::from_secs(30));
+        assert_eq!(delay(-1.0), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn it_activates_the_cached_manifests() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("manifest.json");
+        write_cached(
+            &path,
+            &Manifest {
+                version: "pq-1".to_string(),
+                operations: vec![ManifestOperation {
+                    id: HASH.to_string(),
+                    body: "{__typename}".to_string(),
+                    clients: Vec::new(),
+                }],
+                activate: true,
+            },
+        )
+        .await
+        .unwrap();
+
+        let registry = PersistedQueryRegistry::default();
+        assert_eq!(load_cached(&path, &registry).unwrap(), "pq-1");
+        assert_eq!(registry.get(HASH).as_deref(), Some("{__typename}"));
+        assert_eq!(
+            registry.operation_registry_version().as_deref(),
+            Some("pq-1")
+        );
+
+        // a manifest fetched again is activated again
+        registry
+            .register(Manifest {
+                version: "v2".to_string(),
+                operations: Vec::new(),
+                activate: true,
+            })
+            .unwrap();
+        assert_eq!(load_cached(&path, &registry).unwrap(), "pq-1");
+        assert_eq!(registry.status().active.as_deref(), Some("pq-1"));
+    }
+}
@@ -0,0 +1,477 @@
+//! Panics of the requests, answered with an error instead of closing the connection.
+//!
+//! A request is handled with a correlation ID: when it panics, the panic is logged with this ID,
+//! and the client receives a 500 GraphQL error carrying it. When `crash_reports` is configured,
+//! a report with the backtrace and the operation hash is also written, named after the ID.
+//!
+//! The services behind a [`Buffer`] are called from the task of the buffer: they are wrapped with
+//! [`CatchPanicBufferLayer`], so that a panic fails the call instead of the whole buffer.
+//!
+//! Any other panic, and a panic on a poisoned lock, still stops the router.
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::panic::AssertUnwindSafe;
+use std::panic::PanicInfo;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::sync::RwLock;
+use std::task::Context;
+use std::task::Poll;
+use std::time::SystemTime;
+
+use futures::future::Either;
+use futures::future::ErrInto;
+use futures::future::Ready;
+use futures::prelude::*;
+use futures::stream::BoxStream;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tower::buffer::Buffer;
+use tower::BoxError;
+use tower::Layer;
+use tower::Service;
+
+use crate::configuration::CrashReports;
+use crate::graphql;
+use crate::layers::DEFAULT_BUFFER_SIZE;
+
+/// Extension of the errors carrying the correlation ID of the panic.
+const CORRELATION_ID_EXTENSION: &str = "correlationId";
+
+/// Reports waiting to be written, beyond which the reports are dropped.
+const PENDING_REPORTS: usize = 16;
+
+static CRASH_REPORTS: Lazy<RwLock<Option<CrashReports>>> = Lazy::new(Default::default);
+
+/// The reports are written by a thread of their own, so that the panicking task does not wait
+/// for the disk, and the backtraces are resolved there.
+static REPORT_WRITER: Lazy<mpsc::SyncSender<PendingReport>> = Lazy::new(|| {
+    let (sender, receiver) = mpsc::sync_channel::<PendingReport>(PENDING_REPORTS);
+    std::thread::Builder::new()
+        .name("crash-reports".to_string())
+        .spawn(move || {
+            for pending in receiver {
+                pending.write();
+            }
+        })
+        .expect("could not spawn the crash reports thread");
+    sender
+});
+
+// the task-local is initialized with a constant, which clippy takes for a shared one
+#[allow(clippy::declare_interior_mutable_const)]
+mod request {
+    tokio::task_local! {
+        /// The request handled by the current task.
+        pub(super) static REQUEST: super::RequestInfo;
+    }
+}
+
+thread_local! {
+    /// Whether the current thread is calling a service behind a [`CatchPanicBufferLayer`].
+    static CALLING_BUFFERED_SERVICE: Cell<bool> = Cell::new(false);
+}
+
+/// Use the crash reports configuration of the current configuration.
+pub(crate) fn configure(config: Option<&CrashReports>) {
+    *CRASH_REPORTS.write().expect("lock poisoned") = config.cloned();
+}
+
+/// What is known about the request that panicked.
+#[derive(Debug, Clone)]
+pub(crate) struct RequestInfo {
+    correlation_id: String,
+    operation_hash: Option<String>,
+    operation_name: Option<String>,
+}
+
+impl RequestInfo {
+    pub(crate) fn new(request: &graphql::Request) -> Self {
+        Self {
+            correlation_id: uuid::Uuid::new_v4().to_string(),
+            operation_hash: request
+                .query
+                .as_ref()
+                .map(|query| hex::encode(Sha256::digest(query.as_bytes()))),
+            operation_name: request.operation_name.clone(),
+        }
+    }
+
+    /// The response sent instead of the one of the request that panicked.
+    pub(crate) fn panic_response(&self) -> graphql::Response {
+        graphql::Response::builder()
+            .errors(vec![graphql::Error::builder()
+                .message("internal server error")
+                .code(graphql::ErrorCode::InternalServerError)
+                .extension(CORRELATION_ID_EXTENSION, self.correlation_id.clone())
+                .build()])
+            .build()
+    }
+}
+
+/// Run the future of a request, returning `None` if it panicked.
+pub(crate) async fn catch_panic<F>(info: &RequestInfo, future: F) -> Option<F::Output>
+where
+    F: Future,
+{
+    request::REQUEST
+        .scope(info.clone(), AssertUnwindSafe(future).catch_unwind())
+        .await
+        .ok()
+}
+
+/// The responses of a request, ending with the error of [`RequestInfo::panic_response`] if one
+/// of them panicked.
+pub(crate) fn catch_stream_panic(
+    info: RequestInfo,
+    stream: BoxStream<'static, graphql::Response>,
+) -> BoxStream<'static, graphql::Response> {
+    CatchPanicStream {
+        info,
+        stream: Some(stream),
+    }
+    .boxed()
+}
+
+struct CatchPanicStream {
+    info: RequestInfo,
+    /// Dropped after a panic.
+    stream: Option<BoxStream<'static, graphql::Response>>,
+}
+
+impl Stream for CatchPanicStream {
+    type Item = graphql::Response;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let stream = match this.stream.as_mut() {
+            Some(stream) => stream,
+            None => return Poll::Ready(None),
+        };
+        let info = this.info.clone();
+        let polled = request::REQUEST.sync_scope(info, || {
+            std::panic::catch_unwind(AssertUnwindSafe(|| stream.poll_next_unpin(cx)))
+        });
+        match polled {
+            Ok(poll) => poll,
+            Err(_) => {
+                this.stream = None;
+                let mut response = this.info.panic_response();
+                // the client may be waiting for the end of a deferred response
+                response.has_next = Some(false);
+                Poll::Ready(Some(response))
+            }
+        }
+    }
+}
+
+/// Layer of a [`Buffer`] whose service fails the calls that panic, instead of stopping the task of
+/// the buffer and with it every later call.
+pub(crate) struct CatchPanicBufferLayer<Request> {
+    bound: usize,
+    _request: PhantomData<fn(Request)>,
+}
+
+impl<Request> CatchPanicBufferLayer<Request> {
+    pub(crate) fn new() -> Self {
+        Self {
+            bound: DEFAULT_BUFFER_SIZE,
+            _request: PhantomData,
+        }
+    }
+}
+
+impl<S, Request> Layer<S> for CatchPanicBufferLayer<Request>
+where
+    S: Service<Request> + Send + 'static,
+    S::Response: Send,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    Request: Send + 'static,
+{
+    type Service = Buffer<CatchPanic<S>, Request>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Buffer::new(CatchPanic { inner: service }, self.bound)
+    }
+}
+
+/// Service failing the calls that panic, see [`CatchPanicBufferLayer`].
+///
+/// Only the synchronous part of the call runs in the task of the buffer: the response future is
+/// awaited by the task of the request.
+pub(crate) struct CatchPanic<S> {
+    inner: S,
+}
+
+impl<S, Request> Service<Request> for CatchPanic<S>
+where
+    S: Service<Request>,
+    S::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = Either<ErrInto<S::Future, BoxError>, Ready<Result<S::Response, BoxError>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let inner = &mut self.inner;
+        match catch_buffered_panic(|| inner.poll_ready(cx)) {
+            Ok(poll) => poll.map_err(Into::into),
+            Err(error) => Poll::Ready(Err(error)),
+        }
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let inner = &mut self.inner;
+        match catch_buffered_panic(|| inner.call(request)) {
+            Ok(future) => Either::Left(future.err_into()),
+            Err(error) => Either::Right(future::ready(Err(error))),
+        }
+    }
+}
+
+fn catch_buffered_panic<T>(f: impl FnOnce() -> T) -> Result<T, BoxError> {
+    CALLING_BUFFERED_SERVICE.with(|calling| calling.set(true));
+    let result = std::panic::catch_unwind(AssertUnwindSafe(f));
+    CALLING_BUFFERED_SERVICE.with(|calling| calling.set(false));
+    result.map_err(|_| "service panicked".into())
+}
+
+/// The content of a crash report.
+#[derive(Debug, Serialize)]
+struct CrashReport {
+    correlation_id: String,
+    /// Seconds since the UNIX epoch.
+    timestamp: u64,
+    message: String,
+    location: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    operation_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    operation_name: Option<String>,
+    backtrace: String,
+}
+
+/// A crash report sent to the writer thread, with its backtrace not resolved yet.
+struct PendingReport {
+    config: CrashReports,
+    report: CrashReport,
+    backtrace: backtrace::Backtrace,
+}
+
+impl PendingReport {
+    fn write(mut self) {
+        self.backtrace.resolve();
+        self.report.backtrace = format!("{:?}", self.backtrace);
+        if let Err(e) = write_report(&self.config.path, self.config.max_reports, &self.report) {
+            tracing::error!(
+                "could not write the crash report in {}: {}",
+                self.config.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Log a panic that happened while handling a request, and write its crash report if configured.
+///
+/// Returns `false` if the panic did not happen while handling a request, or if it happened on a
+/// lock poisoned by an earlier panic, in which case the router can not recover from it.
+pub(crate) fn report_request_panic(panic: &PanicInfo) -> bool {
+    let message = panic_message(panic);
+    // the state the lock protects was left inconsistent by the earlier panic
+    if message.contains("PoisonError") {
+        return false;
+    }
+    let info = match request::REQUEST.try_with(Clone::clone) {
+        Ok(info) => info,
+        Err(_) => {
+            if CALLING_BUFFERED_SERVICE.with(Cell::get) {
+                tracing::error!("buffered service panicked: {}", panic);
+                return true;
+            }
+            return false;
+        }
+    };
+    tracing::error!(
+        correlation_id = %info.correlation_id,
+        operation_hash = info.operation_hash.as_deref().unwrap_or_default(),
+        "request panicked: {}",
+        panic
+    );
+
+    let config = match CRASH_REPORTS.read() {
+        Ok(config) => match config.as_ref() {
+            Some(config) => config.clone(),
+            None => return true,
+        },
+        Err(_) => return true,
+    };
+    let pending = PendingReport {
+        config,
+        report: CrashReport {
+            correlation_id: info.correlation_id,
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default(),
+            message,
+            location: panic.location().map(|location| location.to_string()),
+            operation_hash: info.operation_hash,
+            operation_name: info.operation_name,
+            backtrace: String::new(),
+        },
+        backtrace: backtrace::Backtrace::new_unresolved(),
+    };
+    if REPORT_WRITER.try_send(pending).is_err() {
+        tracing::warn!("too many crash reports are being written, the report was dropped");
+    }
+    true
+}
+
+fn panic_message(panic: &PanicInfo) -> String {
+    let payload = panic.payload();
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_default()
+}
+
+/// Write a report, unless the directory already holds `max_reports` of them.
+fn write_report(directory: &Path, max_reports: usize, report: &CrashReport) -> std::io::Result<()> {
+    std::fs::create_dir_all(directory)?;
+    let reports = std::fs::read_dir(directory)?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().extension().map_or(false, |ext| ext == "json"))
+        .count();
+    if reports >= max_reports {
+        tracing::warn!(
+            "{} already holds {} crash reports, the report was not written",
+            directory.display(),
+            reports
+        );
+        return Ok(());
+    }
+    std::fs::write(
+        directory.join(format!("{}.json", report.correlation_id)),
+        serde_json::to_vec_pretty(report)?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn request_info() -> RequestInfo {
+        RequestInfo::new(
+            &graphql::Request::builder()
+                .query("{ me { id } }".to_string())
+                .build(),
+        )
+    }
+
+    #[tokio::test]
+    async fn it_catches_the_panics_of_requests() {
+        let info = request_info();
+        assert_eq!(catch_panic(&info, async { 1 }).await, Some(1));
+        assert_eq!(
+            catch_panic(&info, async {
+                panic!("request panicked");
+            })
+            .await,
+            None::<()>
+        );
+
+        let response = info.panic_response();
+        assert_eq!(
+            response.errors[0].extensions.get(CORRELATION_ID_EXTENSION),
+            Some(&info.correlation_id.as_str().into())
+        );
+    }
+
+    #[tokio::test]
+    async fn it_ends_response_streams_that_panic() {
+        let info = request_info();
+        let stream = stream::iter(vec![
+            graphql::Response::builder().has_next(true).build(),
+            graphql::Response::builder().has_next(true).build(),
+        ])
+        .enumerate()
+        .map(|(index, response)| {
+            if index == 1 {
+                panic!("deferred response panicked");
+            }
+            response
+        })
+        .boxed();
+
+        let responses: Vec<_> = catch_stream_panic(info.clone(), stream).collect().await;
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[1].has_next, Some(false));
+        assert_eq!(
+            responses[1].errors[0]
+                .extensions
+                .get(CORRELATION_ID_EXTENSION),
+            Some(&info.correlation_id.as_str().into())
+        );
+    }
+
+    #[test]
+    fn it_writes_crash_reports() {
+        let directory = tempfile::tempdir().unwrap();
+        let info = request_info();
+        let report = CrashReport {
+            correlation_id: info.correlation_id.clone(),
+            timestamp: 0,
+            message: "request panicked".to_string(),
+            location: None,
+            operation_hash: info.operation_hash.clone(),
+            operation_name: None,
+            backtrace: String::new(),
+        };
+        write_report(directory.path(), 1, &report).unwrap();
+
+        let written: serde_json::Value = serde_json::from_slice(
+            &std::fs::read(
+                directory
+                    .path()
+                    .join(format!("{}.json", info.correlation_id)),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(written["message"], "request panicked");
+        assert_eq!(
+            written["operation_hash"],
+            hex::encode(Sha256::digest("{ me { id } }".as_bytes()))
+        );
+
+        let other = CrashReport {
+            correlation_id: "other".to_string(),
+            ..report
+        };
+        write_report(directory.path(), 1, &other).unwrap();
+        assert!(!directory.path().join("other.json").exists());
+    }
+
+    #[tokio::test]
+    async fn it_fails_the_buffered_calls_that_panic() {
+        let panicking = tower::service_fn(|panics: bool| {
+            if panics {
+                panic!("call panicked");
+            }
+            future::ready(Ok::<_, BoxError>(panics))
+        });
+        let mut panicking = CatchPanicBufferLayer::new().layer(panicking);
+
+        assert!(panicking.ready().await.unwrap().call(true).await.is_err());
+        // the buffer keeps serving the calls after a panic
+        assert!(!panicking.ready().await.unwrap().call(false).await.unwrap());
+    }
+}
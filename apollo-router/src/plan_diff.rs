@@ -0,0 +1,365 @@
+//! Query plans of a corpus of operations, and their comparison between two supergraphs.
+//!
+//! The plans of operations can be printed without running a server, e.g. to check them in CI.
+//!
+//! Before rolling out a new supergraph, the operations sent by clients can be planned with both
+//! the current supergraph and the candidate one. An operation whose plan changed fetches its data
+//! differently from the subgraphs, and an operation that can not be planned anymore selects fields
+//! that the candidate supergraph broke.
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use apollo_parser::ast;
+use displaydoc::Display;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::configuration::Configuration;
+use crate::error::QueryPlannerError;
+use crate::error::SchemaError;
+use crate::query_planner::BridgeQueryPlanner;
+use crate::query_planner::QueryKey;
+use crate::services::QueryPlannerContent;
+use crate::Schema;
+
+/// Errors when comparing query plans.
+#[derive(Error, Debug, Display)]
+pub(crate) enum PlanDiffError {
+    /// could not read the operations at '{0}': {1}
+    ReadOperations(PathBuf, std::io::Error),
+    /// invalid {0}: {1}
+    InvalidSchema(&'static str, SchemaError),
+    /// could not create the query planner of the {0}: {1}
+    Planner(&'static str, QueryPlannerError),
+}
+
+/// An operation of the corpus.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Operation {
+    /// Identifies the operation in the report: its file, followed by its name when the file
+    /// contains several operations.
+    pub(crate) id: String,
+    pub(crate) query: String,
+    pub(crate) operation_name: Option<String>,
+}
+
+/// Outcome of the comparison.
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct PlanDiffReport {
+    /// Number of operations planned the same way with both supergraphs.
+    pub(crate) unchanged: usize,
+    /// Operations planned differently by the candidate supergraph.
+    pub(crate) changed: Vec<ChangedPlan>,
+    /// Operations that the candidate supergraph can not plan anymore.
+    pub(crate) broken: Vec<BrokenOperation>,
+    /// Operations that only the candidate supergraph can plan, with their errors with the base
+    /// one.
+    pub(crate) fixed: Vec<BrokenOperation>,
+    /// Operations that can not be planned with either supergraph.
+    pub(crate) invalid: Vec<BrokenOperation>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ChangedPlan {
+    pub(crate) operation: String,
+    pub(crate) base: String,
+    pub(crate) candidate: String,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct BrokenOperation {
+    pub(crate) operation: String,
+    pub(crate) errors: Vec<String>,
+}
+
+/// The plan of an operation, or its planning errors.
+#[derive(Debug, Serialize)]
+pub(crate) struct PlannedOperation {
+    pub(crate) operation: String,
+    /// The plan, in the format of the `apolloQueryPlan` response extension. Introspection queries
+    /// have none, the router answering them whatever the subgraphs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) plan: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) errors: Vec<String>,
+}
+
+/// Read the operations of a `.graphql` file, or of all the `.graphql` and `.gql` files of a
+/// directory.
+pub(crate) fn read_operations(path: &Path) -> Result<Vec<Operation>, PlanDiffError> {
+    let read_error = |e| PlanDiffError::ReadOperations(path.to_path_buf(), e);
+    let mut files = if path.is_dir() {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(path).map_err(read_error)? {
+            let file = entry.map_err(read_error)?.path();
+            if matches!(
+                file.extension().and_then(|extension| extension.to_str()),
+                Some("graphql") | Some("gql")
+            ) {
+                files.push(file);
+            }
+        }
+        files
+    } else {
+        vec![path.to_path_buf()]
+    };
+    files.sort();
+
+    let mut operations = Vec::new();
+    for file in files {
+        let query = std::fs::read_to_string(&file)
+            .map_err(|e| PlanDiffError::ReadOperations(file.clone(), e))?;
+        let id = file
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        operations.extend(split_operations(id, query));
+    }
+    Ok(operations)
+}
+
+/// One operation per named operation of a document containing several of them.
+fn split_operations(id: String, query: String) -> Vec<Operation> {
+    let names: Vec<String> = apollo_parser::Parser::new(&query)
+        .parse()
+        .document()
+        .definitions()
+        .filter_map(|definition| match definition {
+            ast::Definition::OperationDefinition(operation) => Some(
+                operation
+                    .name()
+                    .map(|name| name.text().to_string())
+                    .unwrap_or_default(),
+            ),
+            _ => None,
+        })
+        .collect();
+
+    if names.len() < 2 {
+        return vec![Operation {
+            id,
+            query,
+            operation_name: None,
+        }];
+    }
+    names
+        .into_iter()
+        .map(|name| Operation {
+            id: format!("{}#{}", id, name),
+            query: query.clone(),
+            operation_name: Some(name),
+        })
+        .collect()
+}
+
+/// Plan every operation with both supergraphs and report the differences.
+pub(crate) async fn diff_plans(
+    configuration: Configuration,
+    base: &str,
+    candidate: &str,
+    operations: Vec<Operation>,
+) -> Result<PlanDiffReport, PlanDiffError> {
+    let configuration = Arc::new(configuration);
+    let base = planner("base supergraph", base, configuration.clone()).await?;
+    let candidate = planner("candidate supergraph", candidate, configuration).await?;
+
+    let mut report = PlanDiffReport::default();
+    for operation in operations {
+        let key = (operation.query, operation.operation_name);
+        let base = plan(&base, key.clone()).await;
+        let candidate = plan(&candidate, key).await;
+        match (base, candidate) {
+            (Ok(base), Ok(candidate)) if base == candidate => report.unchanged += 1,
+            (Ok(base), Ok(candidate)) => report.changed.push(ChangedPlan {
+                operation: operation.id,
+                base,
+                candidate,
+            }),
+            (Ok(_), Err(errors)) => report.broken.push(BrokenOperation {
+                operation: operation.id,
+                errors,
+            }),
+            (Err(errors), Ok(_)) => report.fixed.push(BrokenOperation {
+                operation: operation.id,
+                errors,
+            }),
+            (Err(_), Err(errors)) => report.invalid.push(BrokenOperation {
+                operation: operation.id,
+                errors,
+            }),
+        }
+    }
+    Ok(report)
+}
+
+/// Plan every operation with the supergraph.
+pub(crate) async fn plan_operations(
+    configuration: Configuration,
+    schema: &str,
+    operations: Vec<Operation>,
+) -> Result<Vec<PlannedOperation>, PlanDiffError> {
+    let planner = planner("supergraph", schema, Arc::new(configuration)).await?;
+
+    let mut planned = Vec::with_capacity(operations.len());
+    for operation in operations {
+        let (plan, errors) = match planner
+            .get((operation.query, operation.operation_name))
+            .await
+        {
+            Ok(QueryPlannerContent::Plan { plan, .. }) => (
+                Some(serde_json::json!({
+                    "object": { "kind": "QueryPlan", "node": &plan.root },
+                    "text": &plan.formatted_query_plan,
+                })),
+                Vec::new(),
+            ),
+            Ok(_) => (None, Vec::new()),
+            Err(e) => (None, planning_errors(e)),
+        };
+        planned.push(PlannedOperation {
+            operation: operation.id,
+            plan,
+            errors,
+        });
+    }
+    Ok(planned)
+}
+
+async fn planner(
+    name: &'static str,
+    sdl: &str,
+    configuration: Arc<Configuration>,
+) -> Result<BridgeQueryPlanner, PlanDiffError> {
+    let schema =
+        Schema::parse(sdl, &configuration).map_err(|e| PlanDiffError::InvalidSchema(name, e))?;
+    BridgeQueryPlanner::new(Arc::new(schema), None, configuration)
+        .await
+        .map_err(|e| PlanDiffError::Planner(name, e))
+}
+
+/// The formatted plan of an operation, or its planning errors.
+async fn plan(planner: &BridgeQueryPlanner, key: QueryKey) -> Result<String, Vec<String>> {
+    match planner.get(key).await {
+        Ok(QueryPlannerContent::Plan { plan, .. }) => Ok(plan
+            .formatted_query_plan
+            .clone()
+            .unwrap_or_else(|| format!("{:#?}", plan.root))),
+        // introspection queries are answered by the router whatever the subgraphs
+        Ok(_) => Ok(String::new()),
+        Err(e) => Err(planning_errors(e)),
+    }
+}
+
+fn planning_errors(error: QueryPlannerError) -> Vec<String> {
+    match error {
+        QueryPlannerError::PlanningErrors(errors, _) => errors
+            .errors
+            .iter()
+            .map(|error| error.message.clone().unwrap_or_default())
+            .collect(),
+        e => vec![e.to_string()],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA: &str = include_str!("query_planner/testdata/schema.graphql");
+
+    fn operation(id: &str, query: &str) -> Operation {
+        Operation {
+            id: id.to_string(),
+            query: query.to_string(),
+            operation_name: None,
+        }
+    }
+
+    #[test]
+    fn it_splits_documents_with_several_operations() {
+        let operations = split_operations(
+            "me.graphql".to_string(),
+            "query A { me { id } } query B { me { name { first } } }".to_string(),
+        );
+        assert_eq!(
+            operations
+                .iter()
+                .map(|operation| (operation.id.as_str(), operation.operation_name.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![("me.graphql#A", Some("A")), ("me.graphql#B", Some("B"))]
+        );
+
+        let operations = split_operations("me.graphql".to_string(), "{ me { id } }".to_string());
+        assert_eq!(operations, vec![operation("me.graphql", "{ me { id } }")]);
+    }
+
+    #[tokio::test]
+    async fn it_reports_changed_and_broken_operations() {
+        // `inStock` moves from the inventory subgraph to the books one, `isCheckedOut` is removed
+        // and `isAvailable` is added
+        let candidate = SCHEMA.replace(
+            "inStock: Boolean @join__field(graph: INVENTORY)\nisCheckedOut: Boolean @join__field(graph: INVENTORY)\n",
+            "inStock: Boolean @join__field(graph: BOOKS)\nisAvailable: Boolean @join__field(graph: BOOKS)\n",
+        );
+        assert_ne!(candidate, SCHEMA);
+
+        let report = diff_plans(
+            Configuration::default(),
+            SCHEMA,
+            &candidate,
+            vec![
+                operation("me.graphql", "{ me { name { first } } }"),
+                operation("book.graphql", r#"{ book(isbn: "1") { title inStock } }"#),
+                operation(
+                    "checkout.graphql",
+                    r#"{ book(isbn: "1") { isCheckedOut } }"#,
+                ),
+                operation(
+                    "available.graphql",
+                    r#"{ book(isbn: "1") { isAvailable } }"#,
+                ),
+                operation("invalid.graphql", "{ unknown }"),
+            ],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.unchanged, 1);
+        assert_eq!(report.changed.len(), 1);
+        assert_eq!(report.changed[0].operation, "book.graphql");
+        assert_ne!(report.changed[0].base, report.changed[0].candidate);
+        assert_eq!(report.broken.len(), 1);
+        assert_eq!(report.broken[0].operation, "checkout.graphql");
+        assert!(!report.broken[0].errors.is_empty());
+        assert_eq!(report.fixed.len(), 1);
+        assert_eq!(report.fixed[0].operation, "available.graphql");
+        assert!(!report.fixed[0].errors.is_empty());
+        assert_eq!(report.invalid.len(), 1);
+        assert_eq!(report.invalid[0].operation, "invalid.graphql");
+    }
+
+    #[tokio::test]
+    async fn it_plans_operations() {
+        let planned = plan_operations(
+            Configuration::default(),
+            SCHEMA,
+            vec![
+                operation("me.graphql", "{ me { name { first } } }"),
+                operation("invalid.graphql", "{ unknown }"),
+            ],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(planned.len(), 2);
+        let plan = planned[0].plan.as_ref().expect("missing plan");
+        assert_eq!(plan["object"]["kind"], "QueryPlan");
+        assert_eq!(plan["object"]["node"]["kind"], "Fetch");
+        assert!(plan["text"].as_str().unwrap().starts_with("QueryPlan"));
+        assert!(planned[0].errors.is_empty());
+        assert!(planned[1].plan.is_none());
+        assert!(!planned[1].errors.is_empty());
+    }
+}
@@ -0,0 +1,144 @@
+//! Metrics exporters provided by plugins.
+//!
+//! The telemetry plugin records the router metrics with the meter providers of the exporters
+//! configured under `telemetry.metrics`. Besides the built-in Prometheus and OTLP exporters,
+//! plugins can provide their own, e.g. to push metrics to StatsD, by implementing
+//! [`MetricsExporter`] and registering it with [`register_metrics_exporter!`]. A registered
+//! exporter is configured under `telemetry.metrics.custom.<name>`.
+//!
+//! [`register_metrics_exporter!`]: crate::register_metrics_exporter
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use opentelemetry::metrics::MeterProvider;
+use opentelemetry::sdk::Resource;
+use schemars::gen::SchemaGenerator;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use tower::BoxError;
+
+use crate::services::transport;
+
+/// Initialise details for a metrics exporter.
+#[non_exhaustive]
+pub struct MetricsExporterInit<T> {
+    /// Configuration
+    pub config: T,
+    /// The resource describing the router, e.g. its `service.name`, to attach to the metrics.
+    pub resource: Resource,
+}
+
+/// An exporter sending the router metrics to a monitoring system.
+///
+/// The exporter is kept alive as long as the router uses its configuration, so that it can own
+/// the tasks pushing the metrics, and is dropped when the configuration is reloaded.
+pub trait MetricsExporter: Send + Sync + 'static {
+    /// The configuration of the exporter.
+    type Config: JsonSchema + DeserializeOwned + Send;
+
+    /// Create the exporter.
+    fn new(init: MetricsExporterInit<Self::Config>) -> Result<Self, BoxError>
+    where
+        Self: Sized;
+
+    /// The meter provider the router metrics are recorded with.
+    fn meter_provider(&self) -> Arc<dyn MeterProvider + Send + Sync + 'static>;
+
+    /// An endpoint serving the metrics, for exporters that are scraped. It is accessible via
+    /// `/plugins/apollo.telemetry/<name>`.
+    fn custom_endpoint(&self) -> Option<transport::BoxService> {
+        None
+    }
+}
+
+/// Object safe version of [`MetricsExporter`], implemented by all the exporters.
+pub(crate) trait DynMetricsExporter: Send + Sync + 'static {
+    fn meter_provider(&self) -> Arc<dyn MeterProvider + Send + Sync + 'static>;
+
+    fn custom_endpoint(&self) -> Option<transport::BoxService>;
+}
+
+impl<T> DynMetricsExporter for T
+where
+    T: MetricsExporter,
+{
+    fn meter_provider(&self) -> Arc<dyn MeterProvider + Send + Sync + 'static> {
+        MetricsExporter::meter_provider(self)
+    }
+
+    fn custom_endpoint(&self) -> Option<transport::BoxService> {
+        MetricsExporter::custom_endpoint(self)
+    }
+}
+
+type InstanceFactory =
+    fn(&serde_json::Value, Resource) -> Result<Box<dyn DynMetricsExporter>, BoxError>;
+
+type SchemaFactory = fn(&mut SchemaGenerator) -> schemars::schema::Schema;
+
+/// Factories for metrics exporter schema and configuration.
+#[derive(Clone)]
+pub(crate) struct MetricsExporterFactory {
+    instance_factory: InstanceFactory,
+    schema_factory: SchemaFactory,
+}
+
+impl MetricsExporterFactory {
+    pub(crate) fn new<E: MetricsExporter>() -> Self {
+        MetricsExporterFactory {
+            instance_factory: |configuration, resource| {
+                let config = serde_json::from_value(configuration.clone())?;
+                let exporter = E::new(MetricsExporterInit { config, resource })?;
+                Ok(Box::new(exporter) as Box<dyn DynMetricsExporter>)
+            },
+            schema_factory: |gen| gen.subschema_for::<<E as MetricsExporter>::Config>(),
+        }
+    }
+
+    pub(crate) fn create_instance(
+        &self,
+        configuration: &serde_json::Value,
+        resource: Resource,
+    ) -> Result<Box<dyn DynMetricsExporter>, BoxError> {
+        (self.instance_factory)(configuration, resource)
+    }
+
+    pub(crate) fn create_schema(&self, gen: &mut SchemaGenerator) -> schemars::schema::Schema {
+        (self.schema_factory)(gen)
+    }
+}
+
+static METRICS_EXPORTER_REGISTRY: Lazy<Mutex<HashMap<String, MetricsExporterFactory>>> =
+    Lazy::new(|| {
+        let m = HashMap::new();
+        Mutex::new(m)
+    });
+
+/// Register a metrics exporter factory.
+pub fn register_metrics_exporter<E: MetricsExporter>(name: String) {
+    METRICS_EXPORTER_REGISTRY
+        .lock()
+        .expect("Lock poisoned")
+        .insert(name, MetricsExporterFactory::new::<E>());
+}
+
+/// Get a copy of the registered metrics exporter factories.
+pub(crate) fn metrics_exporters() -> HashMap<String, MetricsExporterFactory> {
+    METRICS_EXPORTER_REGISTRY
+        .lock()
+        .expect("Lock poisoned")
+        .clone()
+}
+
+/// Register a metrics exporter with a name, configured under `telemetry.metrics.custom.<name>`.
+#[macro_export]
+macro_rules! register_metrics_exporter {
+    ($name: literal, $exporter_type: ident) => {
+        $crate::_private::startup::on_startup! {
+            $crate::plugin::metrics::register_metrics_exporter::<$exporter_type>($name.to_string());
+        }
+    };
+}
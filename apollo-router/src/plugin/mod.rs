@@ -14,7 +14,10 @@
 //! processing. At each stage a [`Service`] is provided which provides an appropriate
 //! mechanism for interacting with the request and response.
 
+pub mod metrics;
 pub mod serde;
+pub mod spans;
+pub mod surrogate_keys;
 #[macro_use]
 pub mod test;
 
@@ -24,31 +27,107 @@ use std::sync::Arc;
 use std::sync::Mutex;
 use std::task::Context;
 use std::task::Poll;
+use std::time::Duration;
 
 use ::serde::de::DeserializeOwned;
 use ::serde::Deserialize;
 use async_trait::async_trait;
+use bytes::Bytes;
 use futures::future::BoxFuture;
+use futures::stream::BoxStream;
 use once_cell::sync::Lazy;
 use schemars::gen::SchemaGenerator;
+use schemars::schema::SchemaObject;
+use schemars::schema::SubschemaValidation;
 use schemars::JsonSchema;
-use tower::buffer::future::ResponseFuture;
 use tower::buffer::Buffer;
+use tower::load_shed::LoadShed;
 use tower::BoxError;
 use tower::Service;
 use tower::ServiceBuilder;
 
-use crate::layers::ServiceBuilderExt;
+use crate::cache::storage::KeyValueStorage;
+use crate::graphql;
+use crate::panics::CatchPanic;
+use crate::panics::CatchPanicBufferLayer;
+use crate::plugins::telemetry::metrics::PluginMetrics;
+use crate::plugins::traffic_shaping::SubgraphHealth;
 use crate::services::execution;
+use crate::services::execution::QueryPlan;
 use crate::services::subgraph;
 use crate::services::supergraph;
 use crate::transport;
-
-type InstanceFactory =
-    fn(&serde_json::Value, Arc<String>) -> BoxFuture<Result<Box<dyn DynPlugin>, BoxError>>;
+use crate::webhooks::Notifier;
+use crate::Schema;
+
+type InstanceFactory = fn(
+    &serde_json::Value,
+    Arc<String>,
+    PluginMetrics,
+    Option<Notifier>,
+    SubgraphHealth,
+    Option<Arc<dyn KeyValueStorage>>,
+) -> BoxFuture<Result<Box<dyn DynPlugin>, BoxError>>;
 
 type SchemaFactory = fn(&mut SchemaGenerator) -> schemars::schema::Schema;
 
+type DefaultsFactory = fn() -> Option<serde_json::Value>;
+
+type ValidationFactory = fn(&serde_json::Value) -> Result<(), BoxError>;
+
+/// The default maximum duration of [`Plugin::startup`].
+pub const DEFAULT_STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The serialized body of a response, as sent to the client: a JSON object, or the parts of a
+/// multipart response for deferred responses.
+pub type ResponseBody = BoxStream<'static, Result<Bytes, BoxError>>;
+
+/// A transformation of the serialized response bodies, see [`Plugin::response_body_transformer`].
+pub type ResponseBodyTransformer = Arc<dyn Fn(ResponseBody) -> ResponseBody + Send + Sync>;
+
+/// A change of the supergraph schema, passed to [`Plugin::schema_changed`].
+///
+/// Types and fields are compared in the API schema, and named by their schema coordinate: `Type`
+/// or `Type.field`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SchemaChange {
+    /// SHA-256 hash of the previous supergraph schema
+    pub previous_hash: String,
+    /// SHA-256 hash of the new supergraph schema
+    pub hash: String,
+    /// Types and fields added by the new schema
+    pub added: Vec<String>,
+    /// Types and fields removed by the new schema
+    pub removed: Vec<String>,
+    /// Fields whose type changed
+    pub changed: Vec<String>,
+}
+
+impl SchemaChange {
+    pub(crate) fn new(previous: &Schema, schema: &Schema) -> Self {
+        let previous_coordinates = previous.api_schema().coordinates();
+        let coordinates = schema.api_schema().coordinates();
+        let mut change = SchemaChange {
+            previous_hash: previous.schema_id.clone().unwrap_or_default(),
+            hash: schema.schema_id.clone().unwrap_or_default(),
+            ..Default::default()
+        };
+        for (coordinate, ty) in &coordinates {
+            match previous_coordinates.get(coordinate) {
+                None => change.added.push(coordinate.clone()),
+                Some(previous_ty) if previous_ty != ty => change.changed.push(coordinate.clone()),
+                Some(_) => {}
+            }
+        }
+        change.removed = previous_coordinates
+            .into_keys()
+            .filter(|coordinate| !coordinates.contains_key(coordinate))
+            .collect();
+        change
+    }
+}
+
 /// Initialise details for a plugin
 #[non_exhaustive]
 pub struct PluginInit<T> {
@@ -56,6 +135,14 @@ pub struct PluginInit<T> {
     pub config: T,
     /// Router Supergraph Schema (schema definition language)
     pub supergraph_sdl: Arc<String>,
+    /// Metrics of the plugins of the pipeline
+    pub(crate) metrics: PluginMetrics,
+    /// Webhooks notified of the events of the pipeline
+    pub(crate) webhooks: Option<Notifier>,
+    /// Health checks of the subgraphs of the pipeline
+    pub(crate) subgraph_health: SubgraphHealth,
+    /// Storage shared with the other routers, if configured
+    pub(crate) storage: Option<Arc<dyn KeyValueStorage>>,
 }
 
 impl<T> PluginInit<T>
@@ -67,6 +154,10 @@ where
         PluginInit {
             config,
             supergraph_sdl,
+            metrics: Default::default(),
+            webhooks: None,
+            subgraph_health: Default::default(),
+            storage: None,
         }
     }
 
@@ -82,8 +173,36 @@ where
         Ok(PluginInit {
             config,
             supergraph_sdl,
+            metrics: Default::default(),
+            webhooks: None,
+            subgraph_health: Default::default(),
+            storage: None,
         })
     }
+
+    /// Export the metrics registered by the plugin with the ones of the pipeline.
+    pub(crate) fn with_metrics(mut self, metrics: PluginMetrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Notify the webhooks of the pipeline of the events of the plugin.
+    pub(crate) fn with_webhooks(mut self, webhooks: Option<Notifier>) -> Self {
+        self.webhooks = webhooks;
+        self
+    }
+
+    /// Check the health of the subgraphs with the health checks of the pipeline.
+    pub(crate) fn with_subgraph_health(mut self, subgraph_health: SubgraphHealth) -> Self {
+        self.subgraph_health = subgraph_health;
+        self
+    }
+
+    /// Share the counters of the plugin with the other routers through the configured storage.
+    pub(crate) fn with_storage(mut self, storage: Option<Arc<dyn KeyValueStorage>>) -> Self {
+        self.storage = storage;
+        self
+    }
 }
 
 /// Factories for plugin schema and configuration.
@@ -91,16 +210,81 @@ where
 pub(crate) struct PluginFactory {
     instance_factory: InstanceFactory,
     schema_factory: SchemaFactory,
+    defaults_factory: DefaultsFactory,
+    validation_factory: ValidationFactory,
     pub(crate) type_id: TypeId,
+    /// The version of the crate registering the plugin, exposed by the admin API.
+    pub(crate) version: Option<&'static str>,
 }
 
 impl PluginFactory {
+    pub(crate) fn new<P: Plugin>() -> Self {
+        PluginFactory {
+            instance_factory: |configuration,
+                               schema,
+                               metrics,
+                               webhooks,
+                               subgraph_health,
+                               storage| {
+                Box::pin(async move {
+                    let init = PluginInit::try_new(with_defaults::<P>(configuration), schema)?
+                        .with_metrics(metrics)
+                        .with_webhooks(webhooks)
+                        .with_subgraph_health(subgraph_health)
+                        .with_storage(storage);
+                    let plugin = P::new(init).await?;
+                    Ok(Box::new(plugin) as Box<dyn DynPlugin>)
+                })
+            },
+            schema_factory: schema_with_defaults::<P>,
+            defaults_factory: P::config_defaults,
+            validation_factory: |configuration| {
+                serde_json::from_value::<P::Config>(with_defaults::<P>(configuration))?;
+                Ok(())
+            },
+            type_id: TypeId::of::<P>(),
+            version: None,
+        }
+    }
+
+    #[cfg(test)]
     pub(crate) async fn create_instance(
         &self,
         configuration: &serde_json::Value,
         supergraph_sdl: Arc<String>,
     ) -> Result<Box<dyn DynPlugin>, BoxError> {
-        (self.instance_factory)(configuration, supergraph_sdl).await
+        self.create_instance_in_pipeline(
+            configuration,
+            supergraph_sdl,
+            Default::default(),
+            None,
+            Default::default(),
+            None,
+        )
+        .await
+    }
+
+    /// Create an instance of the plugin, exporting its metrics with the ones of the pipeline,
+    /// notifying its webhooks, checking the health of its subgraphs and sharing its counters
+    /// through the storage.
+    pub(crate) async fn create_instance_in_pipeline(
+        &self,
+        configuration: &serde_json::Value,
+        supergraph_sdl: Arc<String>,
+        metrics: PluginMetrics,
+        webhooks: Option<Notifier>,
+        subgraph_health: SubgraphHealth,
+        storage: Option<Arc<dyn KeyValueStorage>>,
+    ) -> Result<Box<dyn DynPlugin>, BoxError> {
+        (self.instance_factory)(
+            configuration,
+            supergraph_sdl,
+            metrics,
+            webhooks,
+            subgraph_health,
+            storage,
+        )
+        .await
     }
 
     #[cfg(test)]
@@ -108,12 +292,78 @@ impl PluginFactory {
         &self,
         configuration: &serde_json::Value,
     ) -> Result<Box<dyn DynPlugin>, BoxError> {
-        (self.instance_factory)(configuration, Default::default()).await
+        self.create_instance(configuration, Default::default())
+            .await
     }
 
     pub(crate) fn create_schema(&self, gen: &mut SchemaGenerator) -> schemars::schema::Schema {
         (self.schema_factory)(gen)
     }
+
+    /// The configuration with the default values of the plugin.
+    pub(crate) fn with_defaults(&self, configuration: serde_json::Value) -> serde_json::Value {
+        match (self.defaults_factory)() {
+            Some(defaults) => merge_defaults(configuration, defaults),
+            None => configuration,
+        }
+    }
+
+    /// Check that the configuration, with the default values of the plugin, can be deserialized.
+    pub(crate) fn validate(&self, configuration: &serde_json::Value) -> Result<(), BoxError> {
+        (self.validation_factory)(configuration)
+    }
+}
+
+/// Add the default values missing from a configuration. The objects are merged recursively, and
+/// a null configuration, like an empty YAML section, takes all the defaults.
+fn merge_defaults(
+    configuration: serde_json::Value,
+    defaults: serde_json::Value,
+) -> serde_json::Value {
+    match (configuration, defaults) {
+        (serde_json::Value::Null, defaults) => defaults,
+        (serde_json::Value::Object(mut configuration), serde_json::Value::Object(defaults)) => {
+            for (key, default) in defaults {
+                let value = match configuration.remove(&key) {
+                    Some(value) => merge_defaults(value, default),
+                    None => default,
+                };
+                configuration.insert(key, value);
+            }
+            serde_json::Value::Object(configuration)
+        }
+        (configuration, _) => configuration,
+    }
+}
+
+fn with_defaults<P: Plugin>(configuration: &serde_json::Value) -> serde_json::Value {
+    match P::config_defaults() {
+        Some(defaults) => merge_defaults(configuration.clone(), defaults),
+        None => configuration.clone(),
+    }
+}
+
+/// The schema of the configuration of a plugin, documenting its default values.
+fn schema_with_defaults<P: Plugin>(gen: &mut SchemaGenerator) -> schemars::schema::Schema {
+    let schema = P::config_schema(gen);
+    let defaults = match P::config_defaults() {
+        Some(defaults) => defaults,
+        None => return schema,
+    };
+    let mut schema = match schema {
+        // the other keywords are ignored next to a reference, so it is wrapped
+        schemars::schema::Schema::Object(object) if object.is_ref() => SchemaObject {
+            subschemas: Some(Box::new(SubschemaValidation {
+                all_of: Some(vec![schemars::schema::Schema::Object(object)]),
+                ..Default::default()
+            })),
+            ..Default::default()
+        },
+        schemars::schema::Schema::Object(object) => object,
+        schema => return schema,
+    };
+    schema.metadata().default = Some(defaults);
+    schemars::schema::Schema::Object(schema)
 }
 
 static PLUGIN_REGISTRY: Lazy<Mutex<HashMap<String, PluginFactory>>> = Lazy::new(|| {
@@ -123,21 +373,21 @@ static PLUGIN_REGISTRY: Lazy<Mutex<HashMap<String, PluginFactory>>> = Lazy::new(
 
 /// Register a plugin factory.
 pub fn register_plugin<P: Plugin>(name: String) {
-    let plugin_factory = PluginFactory {
-        instance_factory: |configuration, schema| {
-            Box::pin(async move {
-                let init = PluginInit::try_new(configuration.clone(), schema)?;
-                let plugin = P::new(init).await?;
-                Ok(Box::new(plugin) as Box<dyn DynPlugin>)
-            })
-        },
-        schema_factory: |gen| gen.subschema_for::<<P as Plugin>::Config>(),
-        type_id: TypeId::of::<P>(),
-    };
     PLUGIN_REGISTRY
         .lock()
         .expect("Lock poisoned")
-        .insert(name, plugin_factory);
+        .insert(name, PluginFactory::new::<P>());
+}
+
+/// Register a plugin factory with the version of the crate registering it.
+#[doc(hidden)]
+pub fn register_plugin_with_version<P: Plugin>(name: String, version: &'static str) {
+    let mut factory = PluginFactory::new::<P>();
+    factory.version = Some(version);
+    PLUGIN_REGISTRY
+        .lock()
+        .expect("Lock poisoned")
+        .insert(name, factory);
 }
 
 /// Get a copy of the registered plugin factories.
@@ -145,6 +395,19 @@ pub(crate) fn plugins() -> HashMap<String, PluginFactory> {
     PLUGIN_REGISTRY.lock().expect("Lock poisoned").clone()
 }
 
+/// The name of an extra plugin added programmatically: its registered name if it has one, so that
+/// it gets the custom endpoints of a registered plugin, or else a name derived from its position.
+pub(crate) fn extra_plugin_name<P: Plugin>(index: usize) -> String {
+    let type_id = TypeId::of::<P>();
+    match plugins()
+        .iter()
+        .find(|(_name, factory)| factory.type_id == type_id)
+    {
+        Some((name, _factory)) => name.clone(),
+        None => format!("extra_plugins.{}.{}", index, std::any::type_name::<P>()),
+    }
+}
+
 /// All router plugins must implement the Plugin trait.
 ///
 /// This trait defines lifecycle hooks that enable hooking into Apollo Router services.
@@ -162,6 +425,27 @@ pub trait Plugin: Send + Sync + 'static {
     /// and passed to [`Plugin::new`] as part of [`PluginInit`].
     type Config: JsonSchema + DeserializeOwned + Send;
 
+    /// The JSON schema of the configuration of the plugin, added to the schema of the router
+    /// configuration, which validates the configuration on startup and reload.
+    /// By default it is generated from [`Plugin::Config`]: override it to refine the generated
+    /// schema, with patterns or examples for instance.
+    fn config_schema(gen: &mut SchemaGenerator) -> schemars::schema::Schema
+    where
+        Self: Sized,
+    {
+        gen.subschema_for::<Self::Config>()
+    }
+
+    /// The default values of the configuration of the plugin, merged into its configuration
+    /// before it is validated and passed to [`Plugin::new`]: the configured values take
+    /// precedence, and the objects are merged recursively.
+    fn config_defaults() -> Option<serde_json::Value>
+    where
+        Self: Sized,
+    {
+        None
+    }
+
     /// This is invoked once after the router starts and compiled-in
     /// plugins are registered.
     async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError>
@@ -181,6 +465,17 @@ pub trait Plugin: Send + Sync + 'static {
         service
     }
 
+    /// This is invoked with the query plan of each request, before its execution and before the `execution_service` of the plugins.
+    /// Define `check_query_plan` to enforce a policy on the subgraphs a client can reach (for example, to block the plans fetching from a subgraph for some clients), or to annotate the context for the other plugins.
+    /// Returning an error vetoes the execution: the error is sent to the client with a 403 status code.
+    fn check_query_plan(
+        &self,
+        _query_plan: &QueryPlan,
+        _context: &crate::Context,
+    ) -> Result<(), graphql::Error> {
+        Ok(())
+    }
+
     /// This service handles communication between the Apollo Router and your subgraphs.
     /// Define `subgraph_service` to configure this communication (for example, to dynamically add headers to pass to a subgraph).
     /// The `_subgraph_name` parameter is useful if you need to apply a customization only specific subgraphs.
@@ -198,6 +493,60 @@ pub trait Plugin: Send + Sync + 'static {
         None
     }
 
+    /// The `response_body_transformer` method lets you transform the serialized body of the responses, after execution and before compression (for example, to strip fields, inject an extension or rewrite URLs).
+    /// The body is transformed as a stream of bytes, so that each part of a deferred response is transformed as soon as it is sent.
+    /// Like the services, the bodies go through the plugins in reverse order: the transformation of the first plugin is applied last.
+    fn response_body_transformer(&self) -> Option<ResponseBodyTransformer> {
+        None
+    }
+
+    /// This is invoked when the router reloads with a different supergraph schema, on the plugin
+    /// instance created for the new schema, before the router starts serving it.
+    /// Define `schema_changed` to invalidate data derived from the previous schema, or to notify other systems.
+    /// Returning an error rejects the new schema: the router keeps serving the previous one.
+    async fn schema_changed(&self, _change: &SchemaChange) -> Result<(), BoxError> {
+        Ok(())
+    }
+
+    /// This is invoked when the router reloads its configuration or its schema, on the instance of
+    /// the plugin serving the previous ones, before creating a new instance with [`Plugin::new`].
+    /// Define `reconfigure` to create the new instance from the previous one, keeping its state (for example, its caches or connections) while applying the new configuration.
+    /// Returning `None`, as by default, creates the new instance from scratch.
+    async fn reconfigure(&self, _init: PluginInit<Self::Config>) -> Result<Option<Self>, BoxError>
+    where
+        Self: Sized,
+    {
+        Ok(None)
+    }
+
+    /// This is invoked once the plugin is created, and the startup of the plugins it depends on
+    /// is complete, before the router serves requests with it.
+    /// Define `startup` if your plugin needs async work before handling requests (for example, to fetch a JWKS, warm a cache or open connections).
+    /// Returning an error, or not completing within [`Plugin::startup_timeout`], fails the start or the reload of the router.
+    async fn startup(&self) -> Result<(), BoxError> {
+        Ok(())
+    }
+
+    /// The maximum duration of [`Plugin::startup`] and of [`Plugin::rollback`], [`DEFAULT_STARTUP_TIMEOUT`] by default.
+    fn startup_timeout(&self) -> Duration {
+        DEFAULT_STARTUP_TIMEOUT
+    }
+
+    /// This is invoked when the start or the reload of the router fails after the [`Plugin::startup`] of the plugin (for example, when another plugin fails to start or a canary operation fails), before the instance is dropped without serving any request.
+    /// Define `rollback` to undo the side effects of `startup` (for example, to deregister from a service registry).
+    async fn rollback(&self) {}
+
+    /// The names of the plugins whose startup must complete before the startup of this one, like `apollo.telemetry` or `my_group.my_plugin`.
+    /// The plugins which are not configured are ignored.
+    fn depends_on(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+
+    /// Whether the plugin can handle requests. The readiness check of the router fails while a plugin is not ready (for example, while a connection is lost).
+    fn is_ready(&self) -> bool {
+        true
+    }
+
     /// Return the name of the plugin.
     fn name(&self) -> &'static str
     where
@@ -228,6 +577,14 @@ pub(crate) trait DynPlugin: Send + Sync + 'static {
     /// Define `execution_service` if your customization includes logic to govern execution (for example, if you want to block a particular query based on a policy decision).
     fn execution_service(&self, service: execution::BoxService) -> execution::BoxService;
 
+    /// This is invoked with the query plan of each request, before its execution.
+    /// Returning an error vetoes the execution.
+    fn check_query_plan(
+        &self,
+        query_plan: &QueryPlan,
+        context: &crate::Context,
+    ) -> Result<(), graphql::Error>;
+
     /// This service handles communication between the Apollo Router and your subgraphs.
     /// Define `subgraph_service` to configure this communication (for example, to dynamically add headers to pass to a subgraph).
     /// The `_subgraph_name` parameter is useful if you need to apply a customization only on specific subgraphs.
@@ -241,6 +598,41 @@ pub(crate) trait DynPlugin: Send + Sync + 'static {
     /// For now it's only accessible for official `apollo.` plugins and for `experimental.`. This endpoint will be accessible via `/plugins/group.plugin_name`
     fn custom_endpoint(&self) -> Option<transport::BoxService>;
 
+    /// The `response_body_transformer` method lets you transform the serialized body of the responses, after execution and before compression.
+    fn response_body_transformer(&self) -> Option<ResponseBodyTransformer>;
+
+    /// This is invoked when the router reloads with a different supergraph schema.
+    /// Returning an error rejects the new schema.
+    async fn schema_changed(&self, change: &SchemaChange) -> Result<(), BoxError>;
+
+    /// This is invoked when the router reloads, on the instance of the plugin serving the
+    /// previous configuration and schema. Returns the new instance, or `None` if it must be
+    /// created from scratch.
+    async fn reconfigure(
+        &self,
+        configuration: &serde_json::Value,
+        supergraph_sdl: Arc<String>,
+        metrics: PluginMetrics,
+        webhooks: Option<Notifier>,
+        subgraph_health: SubgraphHealth,
+        storage: Option<Arc<dyn KeyValueStorage>>,
+    ) -> Result<Option<Box<dyn DynPlugin>>, BoxError>;
+
+    /// This is invoked once the plugin is created, after the startup of the plugins it depends on.
+    async fn startup(&self) -> Result<(), BoxError>;
+
+    /// The maximum duration of the startup.
+    fn startup_timeout(&self) -> Duration;
+
+    /// This is invoked when the start or the reload of the router fails after the startup.
+    async fn rollback(&self);
+
+    /// The names of the plugins whose startup must complete before the startup of this one.
+    fn depends_on(&self) -> Vec<&'static str>;
+
+    /// Whether the plugin can handle requests.
+    fn is_ready(&self) -> bool;
+
     /// Return the name of the plugin.
     fn name(&self) -> &'static str;
 }
@@ -259,6 +651,14 @@ where
         self.execution_service(service)
     }
 
+    fn check_query_plan(
+        &self,
+        query_plan: &QueryPlan,
+        context: &crate::Context,
+    ) -> Result<(), graphql::Error> {
+        self.check_query_plan(query_plan, context)
+    }
+
     fn subgraph_service(&self, name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
         self.subgraph_service(name, service)
     }
@@ -267,6 +667,54 @@ where
         self.custom_endpoint()
     }
 
+    fn response_body_transformer(&self) -> Option<ResponseBodyTransformer> {
+        self.response_body_transformer()
+    }
+
+    async fn schema_changed(&self, change: &SchemaChange) -> Result<(), BoxError> {
+        self.schema_changed(change).await
+    }
+
+    async fn reconfigure(
+        &self,
+        configuration: &serde_json::Value,
+        supergraph_sdl: Arc<String>,
+        metrics: PluginMetrics,
+        webhooks: Option<Notifier>,
+        subgraph_health: SubgraphHealth,
+        storage: Option<Arc<dyn KeyValueStorage>>,
+    ) -> Result<Option<Box<dyn DynPlugin>>, BoxError> {
+        let init = PluginInit::try_new(with_defaults::<T>(configuration), supergraph_sdl)?
+            .with_metrics(metrics)
+            .with_webhooks(webhooks)
+            .with_subgraph_health(subgraph_health)
+            .with_storage(storage);
+        Ok(self
+            .reconfigure(init)
+            .await?
+            .map(|plugin| Box::new(plugin) as Box<dyn DynPlugin>))
+    }
+
+    async fn startup(&self) -> Result<(), BoxError> {
+        self.startup().await
+    }
+
+    fn startup_timeout(&self) -> Duration {
+        self.startup_timeout()
+    }
+
+    async fn rollback(&self) {
+        self.rollback().await
+    }
+
+    fn depends_on(&self) -> Vec<&'static str> {
+        self.depends_on()
+    }
+
+    fn is_ready(&self) -> bool {
+        self.is_ready()
+    }
+
     fn name(&self) -> &'static str {
         self.name()
     }
@@ -286,7 +734,10 @@ macro_rules! register_plugin {
                 format!("{}.{}", $group, $name)
             };
 
-            $crate::plugin::register_plugin::<$plugin_type>(qualified_name);
+            $crate::plugin::register_plugin_with_version::<$plugin_type>(
+                qualified_name,
+                env!("CARGO_PKG_VERSION"),
+            );
         }
     };
 }
@@ -294,13 +745,17 @@ macro_rules! register_plugin {
 /// Handler represents a [`Plugin`] endpoint.
 #[derive(Clone)]
 pub(crate) struct Handler {
-    service: Buffer<transport::BoxService, transport::Request>,
+    service: LoadShed<Buffer<CatchPanic<transport::BoxService>, transport::Request>>,
 }
 
 impl Handler {
     pub(crate) fn new(service: transport::BoxService) -> Self {
         Self {
-            service: ServiceBuilder::new().buffered().service(service),
+            // the requests are rejected when the buffer is full instead of waiting
+            service: ServiceBuilder::new()
+                .load_shed()
+                .layer(CatchPanicBufferLayer::new())
+                .service(service),
         }
     }
 }
@@ -308,7 +763,11 @@ impl Handler {
 impl Service<transport::Request> for Handler {
     type Response = transport::Response;
     type Error = BoxError;
-    type Future = ResponseFuture<BoxFuture<'static, Result<Self::Response, Self::Error>>>;
+    type Future = tower::load_shed::future::ResponseFuture<
+        tower::buffer::future::ResponseFuture<
+            <CatchPanic<transport::BoxService> as Service<transport::Request>>::Future,
+        >,
+    >;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         self.service.poll_ready(cx)
@@ -324,3 +783,95 @@ impl From<transport::BoxService> for Handler {
         Self::new(original)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize, JsonSchema)]
+    #[serde(deny_unknown_fields)]
+    struct Conf {
+        name: String,
+        retries: u32,
+        backoff: Backoff,
+    }
+
+    #[derive(Debug, Deserialize, JsonSchema)]
+    struct Backoff {
+        min_ms: u64,
+        max_ms: u64,
+    }
+
+    struct WithDefaults;
+
+    #[async_trait]
+    impl Plugin for WithDefaults {
+        type Config = Conf;
+
+        async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+            let Conf {
+                name,
+                retries,
+                backoff,
+            } = init.config;
+            if backoff.min_ms > backoff.max_ms {
+                return Err(format!("{}: invalid backoff for {} retries", name, retries).into());
+            }
+            Ok(WithDefaults)
+        }
+
+        fn config_defaults() -> Option<serde_json::Value> {
+            Some(json!({ "retries": 3, "backoff": { "min_ms": 10, "max_ms": 1000 } }))
+        }
+    }
+
+    #[test]
+    fn it_merges_the_default_configuration() {
+        let factory = PluginFactory::new::<WithDefaults>();
+        assert_eq!(
+            factory.with_defaults(json!({ "name": "a", "backoff": { "max_ms": 500 } })),
+            json!({ "name": "a", "retries": 3, "backoff": { "min_ms": 10, "max_ms": 500 } })
+        );
+        assert_eq!(
+            factory.with_defaults(serde_json::Value::Null),
+            json!({ "retries": 3, "backoff": { "min_ms": 10, "max_ms": 1000 } })
+        );
+    }
+
+    #[tokio::test]
+    async fn it_validates_the_configuration_with_its_defaults() {
+        let factory = PluginFactory::new::<WithDefaults>();
+        assert!(factory.validate(&json!({ "name": "a" })).is_ok());
+        let error = factory
+            .validate(&json!({ "name": "a", "retries": "many" }))
+            .unwrap_err();
+        assert!(error.to_string().contains("invalid type"));
+        assert!(factory.validate(&json!({ "retries": 1 })).is_err());
+
+        // the defaults are merged before the plugin is created as well
+        assert!(factory
+            .create_instance_without_schema(&json!({ "name": "a", "backoff": { "max_ms": 500 } }))
+            .await
+            .is_ok());
+        assert!(factory
+            .create_instance_without_schema(&json!({ "name": "a", "backoff": { "max_ms": 5 } }))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn it_documents_the_defaults_in_the_schema() {
+        let mut gen = SchemaGenerator::default();
+        let schema =
+            serde_json::to_value(PluginFactory::new::<WithDefaults>().create_schema(&mut gen))
+                .unwrap();
+        assert_eq!(
+            schema["default"],
+            json!({ "retries": 3, "backoff": { "min_ms": 10, "max_ms": 1000 } })
+        );
+        // the generated schema is still used for the validation
+        assert_eq!(schema["allOf"][0]["$ref"], json!("#/definitions/Conf"));
+    }
+}
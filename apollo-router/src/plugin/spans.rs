@@ -0,0 +1,137 @@
+//! Customization of the spans the router creates for the stages of the pipeline.
+//!
+//! The telemetry plugin creates a span for every stage a request goes through. Plugins can
+//! rename these spans, add attributes to them or suppress them, e.g. to follow the semantic
+//! conventions of your organization, by implementing [`SpanCustomizer`] and registering it with
+//! [`register_span_customizer!`]. Registered customizers are applied in the order of their names.
+//!
+//! [`register_span_customizer!`]: crate::register_span_customizer
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use opentelemetry::Key;
+use opentelemetry::KeyValue;
+use opentelemetry::Value;
+
+use crate::Context;
+
+/// A stage of the pipeline the router creates a span for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SpanStage {
+    /// The processing of a request, from its reception to its response (`supergraph`).
+    Supergraph,
+    /// The execution of the query plan of a request (`execution`).
+    Execution,
+    /// A request to a subgraph (`subgraph`).
+    Subgraph,
+}
+
+/// A span about to be created, as customized so far.
+#[derive(Debug)]
+pub struct SpanCustomization<'a> {
+    stage: SpanStage,
+    subgraph_name: Option<&'a str>,
+    context: &'a Context,
+    pub(crate) name: String,
+    pub(crate) attributes: Vec<KeyValue>,
+    pub(crate) suppressed: bool,
+}
+
+impl<'a> SpanCustomization<'a> {
+    pub(crate) fn new(
+        stage: SpanStage,
+        name: &str,
+        subgraph_name: Option<&'a str>,
+        context: &'a Context,
+    ) -> Self {
+        SpanCustomization {
+            stage,
+            subgraph_name,
+            context,
+            name: name.to_string(),
+            attributes: Vec::new(),
+            suppressed: false,
+        }
+    }
+
+    /// The stage of the pipeline the span is created for.
+    pub fn stage(&self) -> SpanStage {
+        self.stage
+    }
+
+    /// The name of the subgraph, for the spans of the subgraph requests.
+    pub fn subgraph_name(&self) -> Option<&'a str> {
+        self.subgraph_name
+    }
+
+    /// The context of the request.
+    pub fn context(&self) -> &'a Context {
+        self.context
+    }
+
+    /// The name of the span.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Rename the span.
+    pub fn set_name(&mut self, name: impl Into<String>) {
+        self.name = name.into();
+    }
+
+    /// Add an attribute to the span.
+    pub fn set_attribute(&mut self, key: impl Into<Key>, value: impl Into<Value>) {
+        self.attributes.push(KeyValue::new(key, value));
+    }
+
+    /// Do not create the span. The spans of the next stages become children of the parent of
+    /// the suppressed span.
+    pub fn suppress(&mut self) {
+        self.suppressed = true;
+    }
+}
+
+/// Customizes the spans the router creates for the stages of the pipeline.
+pub trait SpanCustomizer: Send + Sync + 'static {
+    /// Customize a span before it is created.
+    fn customize(&self, span: &mut SpanCustomization<'_>);
+}
+
+static SPAN_CUSTOMIZER_REGISTRY: Lazy<Mutex<HashMap<String, Arc<dyn SpanCustomizer>>>> =
+    Lazy::new(|| {
+        let m = HashMap::new();
+        Mutex::new(m)
+    });
+
+/// Register a span customizer.
+pub fn register_span_customizer<C: SpanCustomizer + Default>(name: String) {
+    SPAN_CUSTOMIZER_REGISTRY
+        .lock()
+        .expect("Lock poisoned")
+        .insert(name, Arc::new(C::default()));
+}
+
+/// Get the registered span customizers, in the order of their names.
+pub(crate) fn span_customizers() -> Vec<Arc<dyn SpanCustomizer>> {
+    let registry = SPAN_CUSTOMIZER_REGISTRY.lock().expect("Lock poisoned");
+    let mut customizers: Vec<_> = registry.iter().collect();
+    customizers.sort_by(|(a, _), (b, _)| a.cmp(b));
+    customizers
+        .into_iter()
+        .map(|(_, customizer)| customizer.clone())
+        .collect()
+}
+
+/// Register a span customizer with a name. The customizer must implement `Default`.
+#[macro_export]
+macro_rules! register_span_customizer {
+    ($name: literal, $customizer_type: ident) => {
+        $crate::_private::startup::on_startup! {
+            $crate::plugin::spans::register_span_customizer::<$customizer_type>($name.to_string());
+        }
+    };
+}
@@ -0,0 +1,42 @@
+//! Surrogate keys of the responses, for the CDNs purging their cached responses by key.
+//!
+//! When `surrogate_keys` is configured, the router sends the keys of the types and of the entities
+//! of a response in a header, e.g. `Surrogate-Key` for Fastly or `Cache-Tag` for Cloudflare, so
+//! that the cached responses can be purged when these entities change. Plugins can add their own
+//! keys to the response of a request with [`add_surrogate_key`], until its first part is sent.
+
+use crate::context_key;
+use crate::Context;
+use crate::ContextKey;
+
+/// The surrogate keys added by plugins, in the order they were added.
+pub(crate) const SURROGATE_KEYS_CONTEXT_KEY: ContextKey<Vec<String>> =
+    context_key!("apollo::surrogate_keys", "keys");
+
+/// Add a surrogate key to the response of a request.
+///
+/// ```
+/// use apollo_router::plugin::surrogate_keys::add_surrogate_key;
+/// use apollo_router::Context;
+///
+/// let context = Context::new();
+/// add_surrogate_key(&context, "tenant:acme");
+/// ```
+pub fn add_surrogate_key(context: &Context, key: impl Into<String>) {
+    let key = key.into();
+    let _ = context.upsert_typed(SURROGATE_KEYS_CONTEXT_KEY, |mut keys| {
+        if !keys.contains(&key) {
+            keys.push(key.clone());
+        }
+        keys
+    });
+}
+
+/// The surrogate keys added to the response of a request by plugins.
+pub fn surrogate_keys(context: &Context) -> Vec<String> {
+    context
+        .get_typed(SURROGATE_KEYS_CONTEXT_KEY)
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
@@ -0,0 +1,197 @@
+//! Authentication of the requests sent to subgraphs.
+//!
+//! Each subgraph can be configured with its own credentials:
+//! * `aws_sig_v4` signs requests for AWS hosted subgraphs (AppSync, Lambda function URLs, ...).
+//!   The subgraph service signs them right before sending them, after compression and the
+//!   other changes made by the plugins.
+//! * `oauth2_client_credentials` fetches an access token from an authorization server and sends
+//!   it as a bearer token. Tokens are cached and refreshed before they expire.
+
+mod oauth2;
+pub(crate) mod sigv4;
+
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+
+use futures::FutureExt;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tower::BoxError;
+use tower::ServiceBuilder;
+use tower::ServiceExt;
+
+use self::oauth2::OAuth2Config;
+use self::oauth2::TokenProvider;
+use self::sigv4::SigV4Config;
+use crate::layers::ServiceBuilderExt;
+use crate::panics::CatchPanicBufferLayer;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::register_plugin;
+use crate::services::subgraph;
+use crate::Configuration;
+use crate::SubgraphRequest;
+
+register_plugin!("apollo", "subgraph_authentication", SubgraphAuthentication);
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum AuthConfig {
+    /// Sign requests with AWS Signature Version 4
+    AwsSigV4(SigV4Config),
+    /// Send an access token obtained with the OAuth2 client credentials flow
+    Oauth2ClientCredentials(OAuth2Config),
+}
+
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Authentication configuration, per subgraph name
+    #[serde(default)]
+    subgraphs: HashMap<String, AuthConfig>,
+}
+
+pub(crate) struct SubgraphAuthentication {
+    token_providers: HashMap<String, TokenProvider>,
+}
+
+impl SubgraphAuthentication {
+    /// AWS Signature Version 4 credentials of a subgraph, if it has some.
+    pub(crate) fn get_configuration_sigv4(
+        configuration: &Configuration,
+        subgraph: &str,
+    ) -> Option<SigV4Config> {
+        let mut config = configuration
+            .plugin_configuration("apollo.subgraph_authentication")
+            .and_then(|conf| serde_json::from_value::<Config>(conf).ok())?;
+        match config.subgraphs.remove(subgraph)? {
+            AuthConfig::AwsSigV4(config) => Some(config),
+            AuthConfig::Oauth2ClientCredentials(_) => None,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for SubgraphAuthentication {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        // SigV4 credentials are used by the subgraph service, see `get_configuration_sigv4`
+        let mut token_providers = HashMap::new();
+        for (name, config) in init.config.subgraphs {
+            if let AuthConfig::Oauth2ClientCredentials(config) = config {
+                token_providers.insert(name, TokenProvider::new(config)?);
+            }
+        }
+        Ok(SubgraphAuthentication { token_providers })
+    }
+
+    fn subgraph_service(&self, name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+        match self.token_providers.get(name).cloned() {
+            Some(provider) => ServiceBuilder::new()
+                .checkpoint_async(move |mut request: SubgraphRequest| {
+                    let provider = provider.clone();
+                    async move {
+                        provider.authorize(&mut request).await?;
+                        Ok(ControlFlow::Continue(request))
+                    }
+                    .boxed()
+                })
+                // the requests are rejected when the buffer is full instead of waiting
+                .load_shed()
+                .layer(CatchPanicBufferLayer::new())
+                .service(service)
+                .boxed(),
+            None => service,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::AUTHORIZATION;
+    use serde_json::json;
+    use serde_json::Value;
+    use tower::util::BoxService;
+    use tower::Service;
+    use tower::ServiceExt;
+
+    use super::SubgraphAuthentication;
+    use crate::plugin::test::MockSubgraphService;
+    use crate::plugin::DynPlugin;
+    use crate::Configuration;
+    use crate::SubgraphRequest;
+    use crate::SubgraphResponse;
+
+    async fn plugin(config: Value) -> Box<dyn DynPlugin> {
+        crate::plugin::plugins()
+            .get("apollo.subgraph_authentication")
+            .expect("Plugin not found")
+            .create_instance(&config, Default::default())
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn it_gives_the_sigv4_credentials_to_the_subgraph_service() {
+        let configuration: Configuration = serde_json::from_value(json!({
+            "subgraph_authentication": {
+                "subgraphs": {
+                    "products": {
+                        "aws_sig_v4": {
+                            "region": "us-east-1",
+                            "service": "lambda",
+                            "access_key_id": "AKIDEXAMPLE",
+                            "secret_access_key": "secret"
+                        }
+                    },
+                    "reviews": {
+                        "oauth2_client_credentials": {
+                            "token_url": "http://127.0.0.1/token",
+                            "client_id": "client",
+                            "client_secret": "secret"
+                        }
+                    }
+                }
+            }
+        }))
+        .unwrap();
+
+        let config =
+            SubgraphAuthentication::get_configuration_sigv4(&configuration, "products").unwrap();
+        assert_eq!(config.access_key_id, "AKIDEXAMPLE");
+        assert!(
+            SubgraphAuthentication::get_configuration_sigv4(&configuration, "reviews").is_none()
+        );
+        assert!(
+            SubgraphAuthentication::get_configuration_sigv4(&configuration, "accounts").is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn it_ignores_other_subgraphs() {
+        let plugin = plugin(json!({})).await;
+
+        let mut mock_service = MockSubgraphService::new();
+        mock_service
+            .expect_call()
+            .withf(|req| req.subgraph_request.headers().get(AUTHORIZATION).is_none())
+            .times(1)
+            .returning(move |req: SubgraphRequest| {
+                Ok(SubgraphResponse::fake_builder()
+                    .context(req.context)
+                    .build())
+            });
+
+        let mut subgraph_service =
+            plugin.subgraph_service("reviews", BoxService::new(mock_service));
+
+        subgraph_service
+            .ready()
+            .await
+            .unwrap()
+            .call(SubgraphRequest::fake_builder().build())
+            .await
+            .unwrap();
+    }
+}
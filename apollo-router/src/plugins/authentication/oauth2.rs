@@ -0,0 +1,243 @@
+//! OAuth2 client credentials flow for subgraph requests.
+//!
+//! See <https://www.rfc-editor.org/rfc/rfc6749#section-4.4>
+
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use http::header::AUTHORIZATION;
+use http::HeaderValue;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use tower::BoxError;
+
+use crate::SubgraphRequest;
+
+/// Tokens are refreshed this long before they expire, so that they are not rejected in flight.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+/// Subgraph requests wait for the token, so an unresponsive authorization server must not hold
+/// them forever.
+const TOKEN_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Credentials used to fetch an access token for a subgraph.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct OAuth2Config {
+    /// URL of the token endpoint of the authorization server.
+    pub(crate) token_url: url::Url,
+    /// Client id.
+    pub(crate) client_id: String,
+    /// Client secret.
+    pub(crate) client_secret: String,
+    /// Scopes to request.
+    #[serde(default)]
+    pub(crate) scopes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+#[derive(Debug)]
+struct CachedToken {
+    header: HeaderValue,
+    expires_at: Option<Instant>,
+}
+
+impl CachedToken {
+    fn is_valid(&self, now: Instant) -> bool {
+        match self.expires_at {
+            Some(expires_at) => now + EXPIRY_MARGIN < expires_at,
+            None => true,
+        }
+    }
+}
+
+/// Fetches access tokens and caches them until they are about to expire.
+///
+/// It is shared by all requests sent to the same subgraph, so only one token request is in flight
+/// at a time.
+#[derive(Clone)]
+pub(crate) struct TokenProvider {
+    config: OAuth2Config,
+    client: reqwest::Client,
+    token: Arc<Mutex<Option<CachedToken>>>,
+}
+
+impl TokenProvider {
+    pub(crate) fn new(config: OAuth2Config) -> Result<Self, BoxError> {
+        Ok(Self {
+            config,
+            client: reqwest::Client::builder()
+                .timeout(TOKEN_REQUEST_TIMEOUT)
+                .build()?,
+            token: Default::default(),
+        })
+    }
+
+    /// Add a bearer token to the request, fetching a new one if needed.
+    pub(crate) async fn authorize(&self, request: &mut SubgraphRequest) -> Result<(), BoxError> {
+        let header = self.header().await?;
+        request
+            .subgraph_request
+            .headers_mut()
+            .insert(AUTHORIZATION, header);
+        Ok(())
+    }
+
+    async fn header(&self) -> Result<HeaderValue, BoxError> {
+        let mut token = self.token.lock().await;
+        if let Some(cached) = token.as_ref() {
+            if cached.is_valid(Instant::now()) {
+                return Ok(cached.header.clone());
+            }
+        }
+
+        let requested_at = Instant::now();
+        let response = self.fetch().await?;
+        let cached = CachedToken {
+            header: HeaderValue::from_str(&format!("Bearer {}", response.access_token))?,
+            expires_at: response
+                .expires_in
+                .map(|expires_in| requested_at + Duration::from_secs(expires_in)),
+        };
+        let header = cached.header.clone();
+        *token = Some(cached);
+        Ok(header)
+    }
+
+    async fn fetch(&self) -> Result<TokenResponse, BoxError> {
+        let mut form = vec![
+            ("grant_type", "client_credentials".to_string()),
+            ("client_id", self.config.client_id.clone()),
+            ("client_secret", self.config.client_secret.clone()),
+        ];
+        if !self.config.scopes.is_empty() {
+            form.push(("scope", self.config.scopes.join(" ")));
+        }
+
+        let response = self
+            .client
+            .post(self.config.token_url.clone())
+            .form(&form)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "could not fetch an access token from {}: {}",
+                self.config.token_url,
+                response.status()
+            )
+            .into());
+        }
+        Ok(response.json().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    use axum::routing::post;
+    use axum::Form;
+    use axum::Json;
+    use axum::Router;
+    use serde_json::json;
+    use serde_json::Value;
+
+    use super::*;
+
+    async fn token_server(expires_in: u64) -> (url::Url, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let router = Router::new().route(
+            "/token",
+            post(move |Form(form): Form<Vec<(String, String)>>| {
+                let count = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                async move {
+                    assert!(form.contains(&("grant_type".into(), "client_credentials".into())));
+                    assert!(form.contains(&("scope".into(), "read write".into())));
+                    Json::<Value>(json!({
+                        "access_token": format!("token{}", count),
+                        "token_type": "Bearer",
+                        "expires_in": expires_in,
+                    }))
+                }
+            }),
+        );
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(
+            hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(router.into_make_service()),
+        );
+        (format!("http://{}/token", address).parse().unwrap(), calls)
+    }
+
+    fn provider(token_url: url::Url) -> TokenProvider {
+        TokenProvider::new(OAuth2Config {
+            token_url,
+            client_id: "client".to_string(),
+            client_secret: "secret".to_string(),
+            scopes: vec!["read".to_string(), "write".to_string()],
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_caches_tokens() {
+        let (token_url, calls) = token_server(3600).await;
+        let provider = provider(token_url);
+
+        let mut request = SubgraphRequest::fake_builder().build();
+        provider.authorize(&mut request).await.unwrap();
+        provider.authorize(&mut request).await.unwrap();
+
+        assert_eq!(
+            request
+                .subgraph_request
+                .headers()
+                .get(AUTHORIZATION)
+                .unwrap(),
+            "Bearer token1"
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn it_refreshes_expiring_tokens() {
+        // the token expires within the margin, so it is never reused
+        let (token_url, calls) = token_server(10).await;
+        let provider = provider(token_url);
+
+        let mut request = SubgraphRequest::fake_builder().build();
+        provider.authorize(&mut request).await.unwrap();
+        provider.authorize(&mut request).await.unwrap();
+
+        assert_eq!(
+            request
+                .subgraph_request
+                .headers()
+                .get(AUTHORIZATION)
+                .unwrap(),
+            "Bearer token2"
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn tokens_without_expiry_stay_valid() {
+        let token = CachedToken {
+            header: HeaderValue::from_static("Bearer token"),
+            expires_at: None,
+        };
+        assert!(token.is_valid(Instant::now() + Duration::from_secs(86400)));
+    }
+}
@@ -0,0 +1,262 @@
+//! AWS Signature Version 4 signing of subgraph requests.
+//!
+//! See <https://docs.aws.amazon.com/general/latest/gr/sigv4_signing.html>
+
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use hmac::Hmac;
+use hmac::Mac;
+use http::header::AUTHORIZATION;
+use http::header::HOST;
+use http::HeaderValue;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tower::BoxError;
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+const X_AMZ_DATE: &str = "x-amz-date";
+const X_AMZ_SECURITY_TOKEN: &str = "x-amz-security-token";
+
+/// Credentials used to sign requests sent to an AWS hosted subgraph.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SigV4Config {
+    /// AWS region of the subgraph, e.g. `us-east-1`.
+    pub(crate) region: String,
+    /// AWS service name, e.g. `appsync` or `lambda`.
+    pub(crate) service: String,
+    /// Access key id.
+    pub(crate) access_key_id: String,
+    /// Secret access key.
+    pub(crate) secret_access_key: String,
+    /// Session token, when using temporary credentials.
+    pub(crate) session_token: Option<String>,
+}
+
+impl SigV4Config {
+    /// Add the `Authorization` header (and the headers it covers) to an HTTP request.
+    ///
+    /// The body must be the exact bytes sent over the wire, after compression, so the subgraph
+    /// service signs its requests right before sending them.
+    pub(crate) fn sign<B: AsRef<[u8]>>(
+        &self,
+        request: &mut http::Request<B>,
+    ) -> Result<(), BoxError> {
+        self.sign_at(request, SystemTime::now())
+    }
+
+    pub(crate) fn sign_at<B: AsRef<[u8]>>(
+        &self,
+        request: &mut http::Request<B>,
+        now: SystemTime,
+    ) -> Result<(), BoxError> {
+        let payload_hash = hex::encode(Sha256::digest(request.body().as_ref()));
+        let timestamp = format_timestamp(now)?;
+        let date = &timestamp[..8];
+
+        let uri = request.uri().clone();
+        let host = uri
+            .authority()
+            .ok_or("cannot sign a request without a host")?
+            .to_string();
+
+        let headers = request.headers_mut();
+        headers.insert(HOST, HeaderValue::from_str(&host)?);
+        headers.insert(X_AMZ_DATE, HeaderValue::from_str(&timestamp)?);
+        if let Some(session_token) = &self.session_token {
+            headers.insert(X_AMZ_SECURITY_TOKEN, HeaderValue::from_str(session_token)?);
+        }
+
+        // Signed headers must be sorted by name
+        let mut signed = vec![("host", host.as_str()), (X_AMZ_DATE, timestamp.as_str())];
+        if let Some(session_token) = &self.session_token {
+            signed.push((X_AMZ_SECURITY_TOKEN, session_token.as_str()));
+        }
+        let signed_headers = signed
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = signed
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+            .collect();
+
+        let canonical_request = [
+            request.method().as_str(),
+            &canonical_uri(uri.path()),
+            &canonical_query(uri.query().unwrap_or_default()),
+            &canonical_headers,
+            &signed_headers,
+            &payload_hash,
+        ]
+        .join("\n");
+
+        let scope = format!("{}/{}/{}/aws4_request", date, self.region, self.service);
+        let string_to_sign = [
+            ALGORITHM,
+            &timestamp,
+            &scope,
+            &hex::encode(Sha256::digest(canonical_request.as_bytes())),
+        ]
+        .join("\n");
+
+        let mut key = hmac_sha256(
+            format!("AWS4{}", self.secret_access_key).as_bytes(),
+            date.as_bytes(),
+        );
+        for part in [self.region.as_str(), self.service.as_str(), "aws4_request"] {
+            key = hmac_sha256(&key, part.as_bytes());
+        }
+        let signature = hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()));
+
+        let authorization = format!(
+            "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+            ALGORITHM, self.access_key_id, scope, signed_headers, signature
+        );
+        request
+            .headers_mut()
+            .insert(AUTHORIZATION, HeaderValue::from_str(&authorization)?);
+
+        Ok(())
+    }
+}
+
+fn canonical_uri(path: &str) -> String {
+    if path.is_empty() {
+        return "/".to_string();
+    }
+    path.split('/')
+        .map(|segment| urlencoding::encode(segment).into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn canonical_query(query: &str) -> String {
+    let mut pairs: Vec<(String, String)> = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (
+                urlencoding::encode(&urlencoding::decode(key).unwrap_or_default()).into_owned(),
+                urlencoding::encode(&urlencoding::decode(value).unwrap_or_default()).into_owned(),
+            )
+        })
+        .collect();
+    pairs.sort();
+    pairs
+        .into_iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Format a time as `YYYYMMDD'T'HHMMSS'Z'`: the basic format of its RFC 3339 timestamp.
+fn format_timestamp(time: SystemTime) -> Result<String, BoxError> {
+    time.duration_since(UNIX_EPOCH)?;
+    Ok(humantime::format_rfc3339_seconds(time)
+        .to_string()
+        .chars()
+        .filter(|c| *c != '-' && *c != ':')
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http::Method;
+
+    use super::*;
+
+    fn config() -> SigV4Config {
+        SigV4Config {
+            region: "us-east-1".to_string(),
+            service: "service".to_string(),
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        }
+    }
+
+    #[test]
+    fn it_formats_timestamps() {
+        let time = UNIX_EPOCH + Duration::from_secs(1440938160);
+        assert_eq!(format_timestamp(time).unwrap(), "20150830T123600Z");
+        assert_eq!(format_timestamp(UNIX_EPOCH).unwrap(), "19700101T000000Z");
+    }
+
+    #[test]
+    fn it_builds_canonical_uris_and_queries() {
+        assert_eq!(canonical_uri(""), "/");
+        assert_eq!(canonical_uri("/graphql"), "/graphql");
+        assert_eq!(canonical_uri("/a b/c"), "/a%20b/c");
+        assert_eq!(canonical_query("b=2&a=1&c"), "a=1&b=2&c=");
+    }
+
+    fn sign(method: Method, uri: &str, payload: &'static [u8]) -> http::Request<&'static [u8]> {
+        let mut request = http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(payload)
+            .unwrap();
+        config()
+            .sign_at(&mut request, UNIX_EPOCH + Duration::from_secs(1440938160))
+            .unwrap();
+        request
+    }
+
+    #[test]
+    fn it_matches_the_aws_test_suite() {
+        // `get-vanilla`, `post-vanilla` and `get-vanilla-query-order-key-case` from the AWS SigV4
+        // test suite
+        let request = sign(Method::GET, "https://example.amazonaws.com/", b"");
+        let headers = request.headers();
+        assert_eq!(headers.get(HOST).unwrap(), "example.amazonaws.com");
+        assert_eq!(headers.get(X_AMZ_DATE).unwrap(), "20150830T123600Z");
+        assert_eq!(
+            headers.get(AUTHORIZATION).unwrap(),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, SignedHeaders=host;x-amz-date, Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+
+        let request = sign(Method::POST, "https://example.amazonaws.com/", b"");
+        assert_eq!(
+            request.headers().get(AUTHORIZATION).unwrap(),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, SignedHeaders=host;x-amz-date, Signature=5da7c1a2acd57cee7505fc6676e4e544621c30862966e37dddb68e92efbe5d6b"
+        );
+
+        let request = sign(
+            Method::GET,
+            "https://example.amazonaws.com/?Param2=value2&Param1=value1",
+            b"",
+        );
+        assert_eq!(
+            request.headers().get(AUTHORIZATION).unwrap(),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, SignedHeaders=host;x-amz-date, Signature=b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500"
+        );
+    }
+
+    #[test]
+    fn it_signs_the_payload() {
+        let empty = sign(Method::POST, "https://example.amazonaws.com/", b"");
+        let payload = sign(
+            Method::POST,
+            "https://example.amazonaws.com/",
+            br#"{"query":"{ me }"}"#,
+        );
+        assert_ne!(
+            empty.headers().get(AUTHORIZATION),
+            payload.headers().get(AUTHORIZATION)
+        );
+    }
+}
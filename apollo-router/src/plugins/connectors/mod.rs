@@ -0,0 +1,714 @@
+//! REST connectors: subgraphs served by the router from plain REST endpoints.
+//!
+//! The subgraph is still declared in the supergraph, but the router does not send it GraphQL
+//! requests. Instead, each root field and entity type of the subgraph is mapped to an endpoint,
+//! and the JSON responses are shaped after the selection set of the subgraph operation.
+
+mod operation;
+mod template;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::stream;
+use futures::StreamExt;
+use http::header::ACCEPT;
+use http::HeaderValue;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tower::BoxError;
+use tower::ServiceExt;
+
+use self::operation::collect_fields;
+use self::operation::Field;
+use self::operation::Selection;
+use self::template::PathTemplate;
+use crate::graphql::Error;
+use crate::graphql::ErrorCode;
+use crate::json_ext::Object;
+use crate::json_ext::Path;
+use crate::json_ext::Value;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::register_plugin;
+use crate::services::subgraph;
+use crate::Schema;
+use crate::SubgraphRequest;
+use crate::SubgraphResponse;
+
+register_plugin!("apollo", "connectors", Connectors);
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 16;
+
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// REST connectors, per subgraph name
+    #[serde(default)]
+    subgraphs: HashMap<String, ConnectorConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct ConnectorConfig {
+    /// Base URL of the REST API
+    base_url: url::Url,
+    /// Endpoints serving root fields, keyed by `Type.field` (e.g. `Query.user`)
+    #[serde(default)]
+    fields: HashMap<String, EndpointConfig>,
+    /// Endpoints serving entities, keyed by type name
+    #[serde(default)]
+    entities: HashMap<String, EndpointConfig>,
+    /// JSON property of each field, keyed by type name then field name. Nested properties are
+    /// separated by dots. Fields that are not listed use the property of the same name.
+    #[serde(default)]
+    mappings: HashMap<String, HashMap<String, String>>,
+    /// Timeout of the requests to the REST API (default: 30s)
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    timeout: Option<Duration>,
+    /// Maximum number of concurrent requests to fetch the entities of a subgraph request
+    /// (default: 16)
+    max_concurrent_requests: Option<usize>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct EndpointConfig {
+    /// Path appended to the base URL. `{name}` is replaced by the argument (for root fields) or
+    /// the key field (for entities) of the same name
+    path: String,
+    /// HTTP method
+    #[serde(default)]
+    method: Method,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+enum Method {
+    Get,
+    Post,
+    Put,
+    Patch,
+    Delete,
+}
+
+impl Default for Method {
+    fn default() -> Self {
+        Method::Get
+    }
+}
+
+impl From<Method> for http::Method {
+    fn from(method: Method) -> Self {
+        match method {
+            Method::Get => http::Method::GET,
+            Method::Post => http::Method::POST,
+            Method::Put => http::Method::PUT,
+            Method::Patch => http::Method::PATCH,
+            Method::Delete => http::Method::DELETE,
+        }
+    }
+}
+
+struct Endpoint {
+    path: PathTemplate,
+    method: Method,
+}
+
+impl TryFrom<EndpointConfig> for Endpoint {
+    type Error = BoxError;
+
+    fn try_from(config: EndpointConfig) -> Result<Self, Self::Error> {
+        Ok(Endpoint {
+            path: PathTemplate::parse(&config.path)?,
+            method: config.method,
+        })
+    }
+}
+
+struct Connector {
+    base_url: String,
+    fields: HashMap<String, Endpoint>,
+    entities: HashMap<String, Endpoint>,
+    mappings: HashMap<String, HashMap<String, String>>,
+    timeout: Duration,
+    max_concurrent_requests: usize,
+}
+
+impl TryFrom<ConnectorConfig> for Connector {
+    type Error = BoxError;
+
+    fn try_from(config: ConnectorConfig) -> Result<Self, Self::Error> {
+        let fields = config
+            .fields
+            .into_iter()
+            .map(|(coordinate, endpoint)| {
+                if !coordinate.contains('.') {
+                    return Err(format!(
+                        "invalid field '{}', fields must be written as 'Type.field'",
+                        coordinate
+                    )
+                    .into());
+                }
+                Ok((coordinate, endpoint.try_into()?))
+            })
+            .collect::<Result<_, BoxError>>()?;
+        let entities = config
+            .entities
+            .into_iter()
+            .map(|(type_name, endpoint)| Ok((type_name, endpoint.try_into()?)))
+            .collect::<Result<_, BoxError>>()?;
+        let max_concurrent_requests = config
+            .max_concurrent_requests
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_REQUESTS);
+        if max_concurrent_requests == 0 {
+            return Err("'max_concurrent_requests' must be greater than 0".into());
+        }
+
+        Ok(Connector {
+            base_url: config.base_url.as_str().trim_end_matches('/').to_string(),
+            fields,
+            entities,
+            mappings: config.mappings,
+            timeout: config.timeout.unwrap_or(DEFAULT_TIMEOUT),
+            max_concurrent_requests,
+        })
+    }
+}
+
+struct Connectors {
+    connectors: HashMap<String, Arc<Connector>>,
+    schema: Option<Arc<Schema>>,
+    client: reqwest::Client,
+}
+
+#[async_trait::async_trait]
+impl Plugin for Connectors {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        let connectors = init
+            .config
+            .subgraphs
+            .into_iter()
+            .map(|(name, config)| {
+                let connector = Connector::try_from(config)
+                    .map_err(|e| format!("invalid connector for subgraph '{}': {}", name, e))?;
+                Ok((name, Arc::new(connector)))
+            })
+            .collect::<Result<HashMap<_, _>, BoxError>>()?;
+
+        let schema = if connectors.is_empty() {
+            None
+        } else {
+            let schema = Schema::parse(&init.supergraph_sdl, &Default::default())?;
+            for name in connectors.keys() {
+                if !schema.subgraphs().any(|(subgraph, _)| subgraph == name) {
+                    return Err(format!("connector for unknown subgraph '{}'", name).into());
+                }
+            }
+            Some(Arc::new(schema))
+        };
+
+        Ok(Connectors {
+            connectors,
+            schema,
+            client: reqwest::Client::new(),
+        })
+    }
+
+    fn subgraph_service(&self, name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+        match (self.connectors.get(name), &self.schema) {
+            (Some(connector), Some(schema)) => ConnectorService {
+                name: name.to_string(),
+                connector: connector.clone(),
+                schema: schema.clone(),
+                client: self.client.clone(),
+            }
+            .boxed(),
+            _ => service,
+        }
+    }
+}
+
+/// Replaces the subgraph service of a subgraph served by a connector.
+#[derive(Clone)]
+struct ConnectorService {
+    name: String,
+    connector: Arc<Connector>,
+    schema: Arc<Schema>,
+    client: reqwest::Client,
+}
+
+impl tower::Service<SubgraphRequest> for ConnectorService {
+    type Response = SubgraphResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: SubgraphRequest) -> Self::Future {
+        let service = self.clone();
+        Box::pin(async move { service.execute(request).await })
+    }
+}
+
+impl ConnectorService {
+    async fn execute(&self, request: SubgraphRequest) -> Result<SubgraphResponse, BoxError> {
+        let body = request.subgraph_request.body();
+        let query = body
+            .query
+            .as_deref()
+            .ok_or_else(|| format!("missing query in request to subgraph '{}'", self.name))?;
+        let selections = operation::parse(query, &body.variables)?;
+        let root_type = self
+            .schema
+            .root_operation_name(request.operation_kind)
+            .to_string();
+
+        let mut data = Object::new();
+        let mut errors = Vec::new();
+        for field in collect_fields(&selections, &root_type) {
+            let value = match field.name.as_str() {
+                "__typename" => Value::String(root_type.clone().into()),
+                "_entities" => self.entities(field, &mut errors).await,
+                _ => self
+                    .root_field(&root_type, field)
+                    .await
+                    .unwrap_or_else(|error| {
+                        errors.push(self.error(error, Path::from(field.response_key())));
+                        Value::Null
+                    }),
+            };
+            data.insert(field.response_key(), value);
+        }
+
+        Ok(SubgraphResponse::builder()
+            .data(Value::Object(data))
+            .errors(errors)
+            .extensions(Object::new())
+            .context(request.context)
+            .build())
+    }
+
+    async fn root_field(&self, root_type: &str, field: &Field) -> Result<Value, BoxError> {
+        let coordinate = format!("{}.{}", root_type, field.name);
+        let endpoint = self
+            .connector
+            .fields
+            .get(&coordinate)
+            .ok_or_else(|| format!("no endpoint is configured for '{}'", coordinate))?;
+        let response = self.fetch(endpoint, &field.arguments).await?;
+        Ok(self.project(
+            &response,
+            self.field_type(root_type, &field.name),
+            &field.selections,
+        ))
+    }
+
+    async fn entities(&self, field: &Field, errors: &mut Vec<Error>) -> Value {
+        let representations = match field.arguments.get("representations") {
+            Some(Value::Array(representations)) => representations.as_slice(),
+            _ => &[],
+        };
+
+        // in order, with a bounded number of requests in flight
+        let fetches: Vec<_> = representations
+            .iter()
+            .map(|representation| self.entity(representation, field))
+            .collect();
+        let results: Vec<_> = stream::iter(fetches)
+            .buffered(self.connector.max_concurrent_requests)
+            .collect()
+            .await;
+
+        Value::Array(
+            results
+                .into_iter()
+                .enumerate()
+                .map(|(index, result)| {
+                    result.unwrap_or_else(|error| {
+                        errors.push(self.error(
+                            error,
+                            Path::from(format!("{}/{}", field.response_key(), index)),
+                        ));
+                        Value::Null
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    async fn entity(&self, representation: &Value, field: &Field) -> Result<Value, BoxError> {
+        let representation = representation
+            .as_object()
+            .ok_or("entity representations must be objects")?;
+        let type_name = representation
+            .get("__typename")
+            .and_then(|type_name| type_name.as_str())
+            .ok_or("entity representations must have a __typename")?;
+        let endpoint = self
+            .connector
+            .entities
+            .get(type_name)
+            .ok_or_else(|| format!("no endpoint is configured for entity '{}'", type_name))?;
+        let response = self.fetch(endpoint, representation).await?;
+        Ok(self.project(&response, Some(type_name), &field.selections))
+    }
+
+    async fn fetch(&self, endpoint: &Endpoint, values: &Object) -> Result<Value, BoxError> {
+        let mut url = url::Url::parse(&format!(
+            "{}{}",
+            self.connector.base_url,
+            endpoint.path.expand(values)?
+        ))?;
+
+        // values that are not used in the path are sent as query parameters or in the body
+        let parameters = endpoint.path.parameters();
+        let remaining: Object = values
+            .iter()
+            .filter(|(name, _)| {
+                name.as_str() != "__typename" && !parameters.contains(name.as_str())
+            })
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+
+        let request = match endpoint.method {
+            Method::Get => {
+                for (name, value) in remaining.iter() {
+                    let values = match value {
+                        Value::Array(values) => values.iter().collect(),
+                        value => vec![value],
+                    };
+                    for value in values {
+                        match value {
+                            Value::Null => {}
+                            Value::String(s) => {
+                                url.query_pairs_mut().append_pair(name.as_str(), s.as_str());
+                            }
+                            value => {
+                                url.query_pairs_mut()
+                                    .append_pair(name.as_str(), &serde_json::to_string(value)?);
+                            }
+                        }
+                    }
+                }
+                self.client.get(url.clone())
+            }
+            method => self
+                .client
+                .request(method.into(), url.clone())
+                .json(&remaining),
+        };
+
+        let response = request
+            .header(ACCEPT, HeaderValue::from_static("application/json"))
+            .timeout(self.connector.timeout)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(format!("{} returned {}", url, response.status()).into());
+        }
+        let bytes = response.bytes().await?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Shape a JSON value after a selection set, following the configured mappings.
+    fn project(&self, value: &Value, type_name: Option<&str>, selections: &[Selection]) -> Value {
+        match value {
+            Value::Array(values) => Value::Array(
+                values
+                    .iter()
+                    .map(|value| self.project(value, type_name, selections))
+                    .collect(),
+            ),
+            Value::Object(object) => {
+                let type_name = type_name.unwrap_or_default();
+                let mappings = self.connector.mappings.get(type_name);
+                let mut output = Object::new();
+                for field in collect_fields(selections, type_name) {
+                    let value = if field.name == "__typename" {
+                        Value::String(type_name.to_string().into())
+                    } else {
+                        let property = mappings
+                            .and_then(|mappings| mappings.get(&field.name))
+                            .unwrap_or(&field.name);
+                        let value = lookup(object, property).cloned().unwrap_or_default();
+                        if field.selections.is_empty() {
+                            value
+                        } else {
+                            self.project(
+                                &value,
+                                self.field_type(type_name, &field.name),
+                                &field.selections,
+                            )
+                        }
+                    };
+                    output.insert(field.response_key(), value);
+                }
+                Value::Object(output)
+            }
+            value => value.clone(),
+        }
+    }
+
+    fn field_type(&self, type_name: &str, field_name: &str) -> Option<&str> {
+        self.schema
+            .object_types
+            .get(type_name)
+            .and_then(|object_type| object_type.field(field_name))
+            .and_then(|field_type| field_type.inner_type_name())
+    }
+
+    fn error(&self, error: BoxError, path: Path) -> Error {
+        tracing::error!(subgraph = %self.name, "connector request failed: {}", error);
+        Error::builder()
+            .message(format!(
+                "request to subgraph '{}' failed: {}",
+                self.name, error
+            ))
+            .path(path)
+            .code(ErrorCode::SubrequestFailed)
+            .build()
+    }
+}
+
+/// Find a (dot separated) property in a JSON object.
+fn lookup<'a>(object: &'a Object, property: &str) -> Option<&'a Value> {
+    let mut keys = property.split('.');
+    let mut value = object.get(keys.next()?)?;
+    for key in keys {
+        value = value.as_object()?.get(key)?;
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::extract::Path as UrlPath;
+    use axum::extract::Query;
+    use axum::routing::get;
+    use axum::Json;
+    use axum::Router;
+    use serde_json::json;
+    use tower::util::BoxService;
+    use tower::Service;
+
+    use super::*;
+    use crate::plugin::test::MockSubgraphService;
+    use crate::plugin::DynPlugin;
+    use crate::query_planner::fetch::OperationKind;
+
+    async fn rest_api() -> String {
+        let router = Router::new()
+            .route(
+                "/users/me",
+                get(|| async {
+                    Json(json!({
+                        "id": "1",
+                        "full_name": "Ada Lovelace",
+                        "profile": { "login": "ada" }
+                    }))
+                }),
+            )
+            .route(
+                "/users/:id",
+                get(
+                    |UrlPath(id): UrlPath<String>, Query(query): Query<HashMap<String, String>>| async move {
+                        if id == "404" {
+                            return Err(http::StatusCode::NOT_FOUND);
+                        }
+                        if id == "slow" {
+                            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                        }
+                        Ok(Json(json!({
+                            "id": id,
+                            "full_name": format!("User {}", id),
+                            "profile": { "login": query.get("suffix").cloned().unwrap_or_default() }
+                        })))
+                    },
+                ),
+            );
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(
+            hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(router.into_make_service()),
+        );
+        format!("http://{}", address)
+    }
+
+    async fn plugin(base_url: &str) -> Box<dyn DynPlugin> {
+        crate::plugin::plugins()
+            .get("apollo.connectors")
+            .expect("Plugin not found")
+            .create_instance(
+                &json!({
+                    "subgraphs": {
+                        "accounts": {
+                            "base_url": base_url,
+                            "timeout": "500ms",
+                            "fields": {
+                                "Query.me": { "path": "/users/me" }
+                            },
+                            "entities": {
+                                "User": { "path": "/users/{id}" }
+                            },
+                            "mappings": {
+                                "User": {
+                                    "name": "full_name",
+                                    "username": "profile.login"
+                                }
+                            }
+                        }
+                    }
+                }),
+                Arc::new(include_str!("../../testdata/supergraph.graphql").to_string()),
+            )
+            .await
+            .unwrap()
+    }
+
+    async fn call(
+        plugin: &dyn DynPlugin,
+        query: &str,
+        variables: serde_json_bytes::Value,
+    ) -> crate::graphql::Response {
+        // the mock has no expectations: the subgraph itself must not be called
+        let mut service =
+            plugin.subgraph_service("accounts", BoxService::new(MockSubgraphService::new()));
+        let request = SubgraphRequest::fake_builder()
+            .subgraph_request(
+                http::Request::builder()
+                    .body(
+                        crate::graphql::Request::builder()
+                            .query(query)
+                            .variables(variables.as_object().unwrap().clone())
+                            .build(),
+                    )
+                    .unwrap(),
+            )
+            .operation_kind(OperationKind::Query)
+            .build();
+        service
+            .ready()
+            .await
+            .unwrap()
+            .call(request)
+            .await
+            .unwrap()
+            .response
+            .into_body()
+    }
+
+    #[tokio::test]
+    async fn it_serves_root_fields() {
+        let base_url = rest_api().await;
+        let plugin = plugin(&base_url).await;
+
+        let response = call(
+            plugin.as_ref(),
+            "{ me { __typename id name username } }",
+            serde_json_bytes::json!({}),
+        )
+        .await;
+
+        assert_eq!(
+            response.data,
+            Some(serde_json_bytes::json!({
+                "me": { "__typename": "User", "id": "1", "name": "Ada Lovelace", "username": "ada" }
+            }))
+        );
+        assert!(response.errors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_serves_entities() {
+        let base_url = rest_api().await;
+        let plugin = plugin(&base_url).await;
+
+        let response = call(
+            plugin.as_ref(),
+            "query($representations: [_Any!]!) { _entities(representations: $representations) { ... on User { name username } } }",
+            serde_json_bytes::json!({
+                "representations": [
+                    { "__typename": "User", "id": "2", "suffix": "x" },
+                    { "__typename": "User", "id": "404" }
+                ]
+            }),
+        )
+        .await;
+
+        assert_eq!(
+            response.data,
+            Some(serde_json_bytes::json!({
+                "_entities": [
+                    { "name": "User 2", "username": "x" },
+                    null
+                ]
+            }))
+        );
+        assert_eq!(response.errors.len(), 1);
+        assert_eq!(response.errors[0].path, Some(Path::from("_entities/1")));
+    }
+
+    #[tokio::test]
+    async fn it_times_out_slow_endpoints() {
+        let base_url = rest_api().await;
+        let plugin = plugin(&base_url).await;
+
+        let response = call(
+            plugin.as_ref(),
+            "query($representations: [_Any!]!) { _entities(representations: $representations) { ... on User { name } } }",
+            serde_json_bytes::json!({
+                "representations": [
+                    { "__typename": "User", "id": "slow" },
+                    { "__typename": "User", "id": "3" }
+                ]
+            }),
+        )
+        .await;
+
+        assert_eq!(
+            response.data,
+            Some(serde_json_bytes::json!({
+                "_entities": [null, { "name": "User 3" }]
+            }))
+        );
+        assert_eq!(response.errors.len(), 1);
+        assert_eq!(response.errors[0].path, Some(Path::from("_entities/0")));
+    }
+
+    #[tokio::test]
+    async fn it_rejects_unknown_subgraphs() {
+        let error = crate::plugin::plugins()
+            .get("apollo.connectors")
+            .expect("Plugin not found")
+            .create_instance(
+                &json!({
+                    "subgraphs": {
+                        "unknown": { "base_url": "http://localhost" }
+                    }
+                }),
+                Arc::new(include_str!("../../testdata/supergraph.graphql").to_string()),
+            )
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "connector for unknown subgraph 'unknown'"
+        );
+    }
+}
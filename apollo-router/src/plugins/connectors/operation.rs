@@ -0,0 +1,273 @@
+//! Minimal representation of the operations sent by the query planner to a subgraph.
+//!
+//! Unlike [`crate::spec::Query`], this keeps field arguments, and does not need the subgraph
+//! fields (like `_entities`) to be present in the supergraph schema.
+
+use std::collections::HashMap;
+
+use apollo_parser::ast;
+use tower::BoxError;
+
+use crate::json_ext::Object;
+use crate::json_ext::Value;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Field {
+    pub(crate) alias: Option<String>,
+    pub(crate) name: String,
+    pub(crate) arguments: Object,
+    pub(crate) selections: Vec<Selection>,
+}
+
+impl Field {
+    pub(crate) fn response_key(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Selection {
+    Field(Field),
+    /// Inline fragments and fragment spreads, with their type condition
+    Fragment {
+        type_condition: Option<String>,
+        selections: Vec<Selection>,
+    },
+}
+
+/// Fields selected on an object of type `type_name`, with fragments applied.
+pub(crate) fn collect_fields<'a>(selections: &'a [Selection], type_name: &str) -> Vec<&'a Field> {
+    let mut fields = Vec::new();
+    for selection in selections {
+        match selection {
+            Selection::Field(field) => fields.push(field),
+            Selection::Fragment {
+                type_condition,
+                selections,
+            } => {
+                if type_condition
+                    .as_deref()
+                    .map(|condition| condition == type_name)
+                    .unwrap_or(true)
+                {
+                    fields.extend(collect_fields(selections, type_name));
+                }
+            }
+        }
+    }
+    fields
+}
+
+/// Parse the root selection set of the first operation of `query`.
+pub(crate) fn parse(query: &str, variables: &Object) -> Result<Vec<Selection>, BoxError> {
+    let tree = apollo_parser::Parser::new(query).parse();
+    if let Some(error) = tree.errors().next() {
+        return Err(format!("could not parse the subgraph operation: {:?}", error).into());
+    }
+    let document = tree.document();
+
+    let fragments: HashMap<String, ast::FragmentDefinition> = document
+        .definitions()
+        .filter_map(|definition| match definition {
+            ast::Definition::FragmentDefinition(fragment) => {
+                let name = fragment.fragment_name()?.name()?.text().to_string();
+                Some((name, fragment))
+            }
+            _ => None,
+        })
+        .collect();
+
+    let operation = document
+        .definitions()
+        .find_map(|definition| match definition {
+            ast::Definition::OperationDefinition(operation) => Some(operation),
+            _ => None,
+        })
+        .ok_or("the subgraph request does not contain an operation")?;
+
+    let parser = SelectionParser {
+        fragments: &fragments,
+        variables,
+    };
+    parser.selection_set(operation.selection_set())
+}
+
+struct SelectionParser<'a> {
+    fragments: &'a HashMap<String, ast::FragmentDefinition>,
+    variables: &'a Object,
+}
+
+impl SelectionParser<'_> {
+    fn selection_set(
+        &self,
+        selection_set: Option<ast::SelectionSet>,
+    ) -> Result<Vec<Selection>, BoxError> {
+        let selection_set = match selection_set {
+            Some(selection_set) => selection_set,
+            None => return Ok(Vec::new()),
+        };
+
+        selection_set
+            .selections()
+            .map(|selection| self.selection(selection))
+            .collect()
+    }
+
+    fn selection(&self, selection: ast::Selection) -> Result<Selection, BoxError> {
+        match selection {
+            ast::Selection::Field(field) => {
+                let name = field
+                    .name()
+                    .ok_or("the node Name is not optional in the spec")?
+                    .text()
+                    .to_string();
+                let alias = field
+                    .alias()
+                    .and_then(|alias| alias.name())
+                    .map(|name| name.text().to_string());
+                let mut arguments = Object::new();
+                for argument in field
+                    .arguments()
+                    .into_iter()
+                    .flat_map(|arguments| arguments.arguments())
+                {
+                    if let (Some(name), Some(value)) = (argument.name(), argument.value()) {
+                        arguments.insert(name.text().to_string(), self.value(&value)?);
+                    }
+                }
+
+                Ok(Selection::Field(Field {
+                    alias,
+                    name,
+                    arguments,
+                    selections: self.selection_set(field.selection_set())?,
+                }))
+            }
+            ast::Selection::InlineFragment(fragment) => Ok(Selection::Fragment {
+                type_condition: type_condition(fragment.type_condition()),
+                selections: self.selection_set(fragment.selection_set())?,
+            }),
+            ast::Selection::FragmentSpread(spread) => {
+                let name = spread
+                    .fragment_name()
+                    .and_then(|name| name.name())
+                    .map(|name| name.text().to_string())
+                    .unwrap_or_default();
+                let fragment = self
+                    .fragments
+                    .get(&name)
+                    .ok_or_else(|| format!("unknown fragment '{}'", name))?;
+                Ok(Selection::Fragment {
+                    type_condition: type_condition(fragment.type_condition()),
+                    selections: self.selection_set(fragment.selection_set())?,
+                })
+            }
+        }
+    }
+
+    fn value(&self, value: &ast::Value) -> Result<Value, BoxError> {
+        Ok(match value {
+            ast::Value::Variable(variable) => {
+                let name = variable
+                    .name()
+                    .map(|name| name.text().to_string())
+                    .unwrap_or_default();
+                self.variables
+                    .get(name.as_str())
+                    .cloned()
+                    .unwrap_or_default()
+            }
+            ast::Value::StringValue(s) => Into::<String>::into(s.clone()).into(),
+            ast::Value::FloatValue(f) => f
+                .to_string()
+                .parse::<f64>()
+                .map_err(|_| "invalid float value")?
+                .into(),
+            ast::Value::IntValue(i) => i
+                .to_string()
+                .parse::<i64>()
+                .map_err(|_| "invalid int value")?
+                .into(),
+            ast::Value::BooleanValue(b) => b.true_token().is_some().into(),
+            ast::Value::NullValue(_) => Value::Null,
+            ast::Value::EnumValue(e) => e
+                .name()
+                .map(|name| name.text().to_string())
+                .unwrap_or_default()
+                .into(),
+            ast::Value::ListValue(list) => Value::Array(
+                list.values()
+                    .map(|value| self.value(&value))
+                    .collect::<Result<_, _>>()?,
+            ),
+            ast::Value::ObjectValue(object) => {
+                let mut fields = Object::new();
+                for field in object.object_fields() {
+                    if let (Some(name), Some(value)) = (field.name(), field.value()) {
+                        fields.insert(name.text().to_string(), self.value(&value)?);
+                    }
+                }
+                Value::Object(fields)
+            }
+        })
+    }
+}
+
+fn type_condition(condition: Option<ast::TypeCondition>) -> Option<String> {
+    condition
+        .and_then(|condition| condition.named_type())
+        .and_then(|named_type| named_type.name())
+        .map(|name| name.text().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+
+    use super::*;
+
+    #[test]
+    fn it_resolves_arguments() {
+        let variables = json!({"id": "1"});
+        let selections = parse(
+            r#"query($id: ID!) { user(id: $id, format: SHORT, tags: ["a"]) { name } }"#,
+            variables.as_object().unwrap(),
+        )
+        .unwrap();
+
+        let fields = collect_fields(&selections, "Query");
+        assert_eq!(fields.len(), 1);
+        assert_eq!(fields[0].name, "user");
+        assert_eq!(
+            Value::Object(fields[0].arguments.clone()),
+            json!({"id": "1", "format": "SHORT", "tags": ["a"]})
+        );
+    }
+
+    #[test]
+    fn it_applies_fragments() {
+        let selections = parse(
+            r#"query($representations: [_Any!]!) {
+                _entities(representations: $representations) {
+                    ... on User { name }
+                    ... on Product { upc }
+                    ...Common
+                }
+            }
+            fragment Common on User { id: identifier }"#,
+            &Object::new(),
+        )
+        .unwrap();
+
+        let entities = collect_fields(&selections, "Query")[0];
+        let user_fields = collect_fields(&entities.selections, "User")
+            .into_iter()
+            .map(|field| field.response_key())
+            .collect::<Vec<_>>();
+        assert_eq!(user_fields, vec!["name", "id"]);
+        assert_eq!(
+            collect_fields(&entities.selections, "User")[1].name,
+            "identifier"
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! URL path templates, e.g. `/users/{id}/posts`.
+
+use std::collections::HashSet;
+
+use tower::BoxError;
+
+use crate::json_ext::Object;
+use crate::json_ext::Value;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Parameter(String),
+}
+
+/// A path where `{name}` placeholders are replaced by values when a request is made.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct PathTemplate {
+    segments: Vec<Segment>,
+}
+
+impl PathTemplate {
+    pub(crate) fn parse(template: &str) -> Result<Self, BoxError> {
+        let mut segments = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .map(|end| start + end)
+                .ok_or_else(|| format!("unclosed '{{' in path template '{}'", template))?;
+            let name = rest[start + 1..end].trim();
+            if name.is_empty() || name.contains('{') {
+                return Err(format!("invalid parameter in path template '{}'", template).into());
+            }
+            segments.push(Segment::Parameter(name.to_string()));
+            rest = &rest[end + 1..];
+        }
+        if rest.contains('}') {
+            return Err(format!("unopened '}}' in path template '{}'", template).into());
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+
+        Ok(Self { segments })
+    }
+
+    /// Names of the parameters used in the template.
+    pub(crate) fn parameters(&self) -> HashSet<&str> {
+        self.segments
+            .iter()
+            .filter_map(|segment| match segment {
+                Segment::Parameter(name) => Some(name.as_str()),
+                Segment::Literal(_) => None,
+            })
+            .collect()
+    }
+
+    /// Replace the parameters with the (URL encoded) values of the same name.
+    pub(crate) fn expand(&self, values: &Object) -> Result<String, BoxError> {
+        let mut path = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => path.push_str(literal),
+                Segment::Parameter(name) => {
+                    let value = match values.get(name.as_str()) {
+                        Some(Value::String(s)) => s.as_str().to_string(),
+                        Some(Value::Number(n)) => n.to_string(),
+                        Some(Value::Bool(b)) => b.to_string(),
+                        _ => return Err(format!("no value for parameter '{}'", name).into()),
+                    };
+                    path.push_str(&urlencoding::encode(&value));
+                }
+            }
+        }
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+
+    use super::*;
+
+    fn object(value: Value) -> Object {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn it_expands_parameters() {
+        let template = PathTemplate::parse("/users/{id}/posts/{ post }").unwrap();
+        assert_eq!(template.parameters(), HashSet::from(["id", "post"]));
+        assert_eq!(
+            template
+                .expand(&object(json!({"id": "a b", "post": 3})))
+                .unwrap(),
+            "/users/a%20b/posts/3"
+        );
+    }
+
+    #[test]
+    fn it_rejects_missing_values() {
+        let template = PathTemplate::parse("/users/{id}").unwrap();
+        assert!(template.expand(&object(json!({ "id": null }))).is_err());
+        assert!(template.expand(&object(json!({}))).is_err());
+    }
+
+    #[test]
+    fn it_rejects_invalid_templates() {
+        assert!(PathTemplate::parse("/users/{id").is_err());
+        assert!(PathTemplate::parse("/users/id}").is_err());
+        assert!(PathTemplate::parse("/users/{}").is_err());
+        assert!(PathTemplate::parse("/users/{{id}}").is_err());
+    }
+}
@@ -10,6 +10,7 @@ use tower::BoxError;
 use tower::ServiceBuilder;
 use tower::ServiceExt;
 
+use crate::graphql::ErrorCode;
 use crate::layers::ServiceBuilderExt;
 use crate::plugin::Plugin;
 use crate::plugin::PluginInit;
@@ -118,7 +119,9 @@ impl Plugin for Csrf {
                                 or provide one of the following headers: {}", 
                                 NON_PREFLIGHTED_CONTENT_TYPES.join(", "),
                                 required_headers.join(", ")
-                            )).build();
+                            ))
+                            .code(ErrorCode::BadRequest)
+                            .build();
                         let res = SupergraphResponse::builder()
                             .error(error)
                             .status_code(StatusCode::BAD_REQUEST)
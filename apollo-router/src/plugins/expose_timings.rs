@@ -0,0 +1,246 @@
+//! Timings of the planning and of the subgraph fetches of a request, in its response extensions.
+//!
+//! When enabled, the requests with the `Apollo-Expose-Timings: true` header get a `tracing`
+//! response extension, in a simplified variant of the Apollo tracing format.
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use http::HeaderValue;
+use tower::BoxError;
+use tower::ServiceExt as TowerServiceExt;
+
+use crate::graphql::SubgraphTiming;
+use crate::graphql::Timing;
+use crate::graphql::Tracing;
+use crate::layers::ServiceExt;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::register_plugin;
+use crate::services::execution;
+use crate::services::subgraph;
+use crate::services::supergraph;
+use crate::Context;
+
+const EXPOSE_TIMINGS_HEADER_NAME: &str = "Apollo-Expose-Timings";
+const START_CONTEXT_KEY: &str = "experimental::expose_timings.start";
+const PLANNING_CONTEXT_KEY: &str = "experimental::expose_timings.planning";
+const SUBGRAPHS_CONTEXT_KEY: &str = "experimental::expose_timings.subgraphs";
+
+#[derive(Debug, Clone)]
+struct ExposeTimings {
+    enabled: bool,
+}
+
+fn nanos(duration: Duration) -> u64 {
+    duration.as_nanos() as u64
+}
+
+/// The `tracing` extension of a request that exposes its timings.
+fn tracing_extension(context: &Context) -> Option<Tracing> {
+    let start: SystemTime = context.get(START_CONTEXT_KEY).ok().flatten()?;
+    let end = SystemTime::now();
+    let planning: Option<Duration> = context.get(PLANNING_CONTEXT_KEY).ok().flatten();
+    let subgraphs: Vec<SubgraphTiming> = context
+        .get(SUBGRAPHS_CONTEXT_KEY)
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+
+    Some(Tracing {
+        version: 1,
+        start_time: humantime::format_rfc3339_millis(start).to_string(),
+        end_time: humantime::format_rfc3339_millis(end).to_string(),
+        duration: nanos(end.duration_since(start).unwrap_or_default()),
+        // planning, including the parsing and validation of the query, starts with the request
+        planning: planning.map(|planning| Timing {
+            start_offset: 0,
+            duration: nanos(planning),
+        }),
+        subgraphs,
+    })
+}
+
+#[async_trait::async_trait]
+impl Plugin for ExposeTimings {
+    type Config = bool;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        Ok(ExposeTimings {
+            enabled: init.config,
+        })
+    }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        if !self.enabled {
+            return service;
+        }
+        service
+            .map_future_with_request_data(
+                |req: &supergraph::Request| {
+                    let is_enabled = req
+                        .originating_request
+                        .headers()
+                        .get(EXPOSE_TIMINGS_HEADER_NAME)
+                        == Some(&HeaderValue::from_static("true"));
+                    if is_enabled {
+                        req.context
+                            .insert(START_CONTEXT_KEY, SystemTime::now())
+                            .unwrap();
+                    }
+                    is_enabled
+                },
+                |is_enabled: bool, f| async move {
+                    let res: supergraph::ServiceResult = f.await;
+                    match res {
+                        Ok(res) if is_enabled => {
+                            let context = res.context.clone();
+                            Ok(res.map_stream(move |mut response| {
+                                // the timings are complete once the last part is sent
+                                if !response.has_next.unwrap_or(false) {
+                                    if let Some(tracing) = tracing_extension(&context) {
+                                        response.set_tracing(&tracing);
+                                    }
+                                }
+                                response
+                            }))
+                        }
+                        res => res,
+                    }
+                },
+            )
+            .boxed()
+    }
+
+    fn execution_service(&self, service: execution::BoxService) -> execution::BoxService {
+        if !self.enabled {
+            return service;
+        }
+        service
+            .map_request(|req: execution::Request| {
+                if let Ok(Some(start)) = req.context.get::<_, SystemTime>(START_CONTEXT_KEY) {
+                    let planning = SystemTime::now().duration_since(start).unwrap_or_default();
+                    req.context.insert(PLANNING_CONTEXT_KEY, planning).unwrap();
+                }
+                req
+            })
+            .boxed()
+    }
+
+    fn subgraph_service(&self, name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+        if !self.enabled {
+            return service;
+        }
+        let name = name.to_string();
+        service
+            .map_future_with_request_data(
+                |req: &subgraph::Request| {
+                    req.context
+                        .get::<_, SystemTime>(START_CONTEXT_KEY)
+                        .ok()
+                        .flatten()
+                        .map(|start| (req.context.clone(), start))
+                },
+                move |request_start: Option<(Context, SystemTime)>, f| {
+                    let name = name.clone();
+                    let fetch_start = SystemTime::now();
+                    let instant = Instant::now();
+                    async move {
+                        let res: subgraph::ServiceResult = f.await;
+                        if let Some((context, start)) = request_start {
+                            let timing = SubgraphTiming {
+                                name,
+                                start_offset: nanos(
+                                    fetch_start.duration_since(start).unwrap_or_default(),
+                                ),
+                                duration: nanos(instant.elapsed()),
+                            };
+                            let _ = context.upsert(
+                                SUBGRAPHS_CONTEXT_KEY,
+                                |mut timings: Vec<SubgraphTiming>| {
+                                    timings.push(timing.clone());
+                                    timings
+                                },
+                            );
+                        }
+                        res
+                    }
+                },
+            )
+            .boxed()
+    }
+}
+
+register_plugin!("experimental", "expose_timings", ExposeTimings);
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::TestHarness;
+
+    async fn execute(expose_timings: bool, header: bool) -> crate::graphql::Response {
+        let request = if header {
+            supergraph::Request::fake_builder()
+                .query("query {topProducts{name}}")
+                .header(EXPOSE_TIMINGS_HEADER_NAME, "true")
+                .build()
+        } else {
+            supergraph::Request::fake_builder()
+                .query("query {topProducts{name}}")
+                .build()
+        };
+        TestHarness::builder()
+            .configuration_json(serde_json::json!({
+                "plugins": { "experimental.expose_timings": expose_timings }
+            }))
+            .unwrap()
+            .build()
+            .await
+            .unwrap()
+            .oneshot(request.unwrap())
+            .await
+            .unwrap()
+            .next_response()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_exposes_the_timings() {
+        let response = execute(true, true).await;
+        let tracing = response
+            .extensions
+            .get("tracing")
+            .and_then(|tracing| tracing.as_object())
+            .expect("missing timings");
+        assert_eq!(tracing.get("version"), Some(&json!(1)));
+        assert!(tracing.get("duration").unwrap().as_u64().is_some());
+        assert!(tracing
+            .get("planning")
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .contains_key("duration"));
+        let subgraphs = tracing.get("subgraphs").unwrap().as_array().unwrap();
+        assert_eq!(subgraphs.len(), 1);
+        assert_eq!(
+            subgraphs[0].as_object().unwrap().get("name"),
+            Some(&json!("products"))
+        );
+    }
+
+    #[tokio::test]
+    async fn it_exposes_the_timings_only_when_requested() {
+        assert!(!execute(true, false)
+            .await
+            .extensions
+            .contains_key("tracing"));
+        assert!(!execute(false, true)
+            .await
+            .extensions
+            .contains_key("tracing"));
+    }
+}
@@ -6,6 +6,7 @@ use tower::ServiceBuilder;
 use tower::ServiceExt;
 
 use crate::error::Error;
+use crate::error::ErrorCode;
 use crate::json_ext::Object;
 use crate::layers::ServiceBuilderExt;
 use crate::plugin::Plugin;
@@ -35,12 +36,10 @@ impl Plugin for ForbidMutations {
             ServiceBuilder::new()
                 .checkpoint(|req: ExecutionRequest| {
                     if req.query_plan.contains_mutations() {
-                        let error = Error {
-                            message: "Mutations are forbidden".to_string(),
-                            locations: Default::default(),
-                            path: Default::default(),
-                            extensions: Default::default(),
-                        };
+                        let error = Error::builder()
+                            .message("Mutations are forbidden")
+                            .code(ErrorCode::BadRequest)
+                            .build();
                         let res = ExecutionResponse::builder()
                             .error(error)
                             .extensions(Object::new())
@@ -104,12 +103,10 @@ mod forbid_http_get_mutations_tests {
 
     #[tokio::test]
     async fn it_doesnt_let_mutations_pass_through() {
-        let expected_error = Error {
-            message: "Mutations are forbidden".to_string(),
-            locations: Default::default(),
-            path: Default::default(),
-            extensions: Default::default(),
-        };
+        let expected_error = Error::builder()
+            .message("Mutations are forbidden")
+            .code(ErrorCode::BadRequest)
+            .build();
         let expected_status = StatusCode::BAD_REQUEST;
 
         let service_stack = ForbidMutations::new(PluginInit::new(true, Default::default()))
@@ -550,6 +550,7 @@ mod test {
                 .header(CONTENT_TYPE, "graphql")
                 .body(Request::builder().query("query").build())
                 .expect("expecting valid request"),
+            subgraph_name: String::from("test"),
             operation_kind: OperationKind::Query,
             context: Context::new(),
         }
@@ -7,6 +7,7 @@ use tower::BoxError;
 use tower::ServiceExt;
 
 use crate::error::Error as SubgraphError;
+use crate::json_ext::Value;
 use crate::plugin::Plugin;
 use crate::plugin::PluginInit;
 use crate::register_plugin;
@@ -35,6 +36,9 @@ struct Config {
     all: bool,
     #[serde(default)]
     subgraphs: HashMap<String, bool>,
+    /// Extension set to the name of the subgraph in the errors it returns, when they are
+    /// included, e.g. `service`. The errors are left as is without it
+    service_extension: Option<String>,
 }
 
 struct IncludeSubgraphErrors {
@@ -77,6 +81,17 @@ impl Plugin for IncludeSubgraphErrors {
                 })
                 .boxed();
         }
+        if let Some(extension) = self.config.service_extension.clone() {
+            let name = Value::String(name.to_string().into());
+            return service
+                .map_response(move |mut response: SubgraphResponse| {
+                    for error in &mut response.response.body_mut().errors {
+                        error.extensions.insert(extension.as_str(), name.clone());
+                    }
+                    response
+                })
+                .boxed();
+        }
         service
     }
 }
@@ -106,6 +121,10 @@ mod test {
         serde_json::from_str(r#"{"data": {"topProducts":null}, "errors":[{"message": "couldn't find mock for query", "locations": [], "path": null, "extensions": { "test": "value" }}]}"#).unwrap()
     });
 
+    static ATTRIBUTED_PRODUCT_RESPONSE: Lazy<Response> = Lazy::new(|| {
+        serde_json::from_str(r#"{"data": {"topProducts":null}, "errors":[{"message": "couldn't find mock for query", "locations": [], "path": null, "extensions": { "test": "value", "service": "products" }}]}"#).unwrap()
+    });
+
     static REDACTED_PRODUCT_RESPONSE: Lazy<Response> = Lazy::new(|| {
         serde_json::from_str(r#"{"data": {"topProducts":null}, "errors":[{"message": "Subgraph errors redacted", "locations": [], "path": null, "extensions": {}}]}"#).unwrap()
     });
@@ -244,6 +263,16 @@ mod test {
         execute_router_test(ERROR_PRODUCT_QUERY, &*UNREDACTED_PRODUCT_RESPONSE, router).await;
     }
 
+    #[tokio::test]
+    async fn it_sets_the_service_extension() {
+        let plugin = get_redacting_plugin(
+            &serde_json::json!({ "all": true, "service_extension": "service" }),
+        )
+        .await;
+        let router = build_mock_router(plugin).await;
+        execute_router_test(ERROR_PRODUCT_QUERY, &*ATTRIBUTED_PRODUCT_RESPONSE, router).await;
+    }
+
     #[tokio::test]
     async fn it_does_not_redact_all_implicit_redact_product_explict_allow_for_product_query() {
         // Build a redacting plugin
@@ -0,0 +1,599 @@
+//! Mirroring of client requests to a shadow graph.
+//!
+//! A share of the requests is sent again, in the background, to a shadow graph such as a
+//! candidate router version or a router using staging subgraphs. The responses of the shadow graph
+//! are discarded, and can be compared with the responses sent to the clients. The credentials of
+//! the clients and the configured headers are not sent to the shadow graph, or only the allowed
+//! headers are, and the number of mirrored requests in flight is bounded. The number of mirrored requests, failures and mismatches is exported with the metrics
+//! of the router.
+
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use apollo_parser::ast;
+use http::header;
+use http::header::HeaderName;
+use http::HeaderMap;
+use opentelemetry::metrics::ObserverResult;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tokio::sync::oneshot;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
+use tower::BoxError;
+use tower::ServiceBuilder;
+use tower::ServiceExt;
+use tracing::Instrument;
+
+use crate::error::ConfigurationError;
+use crate::graphql;
+use crate::layers::ServiceBuilderExt;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::plugins::telemetry::metrics::PluginMetrics;
+use crate::register_plugin;
+use crate::services::supergraph;
+use crate::SupergraphRequest;
+use crate::SupergraphResponse;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_IN_FLIGHT: usize = 100;
+/// Headers of the client requests never sent to the shadow graph, unless they are forwarded
+/// explicitly.
+const CREDENTIAL_HEADERS: [HeaderName; 3] = [
+    header::AUTHORIZATION,
+    header::PROXY_AUTHORIZATION,
+    header::COOKIE,
+];
+
+/// Request mirroring configuration.
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct MirroringConfig {
+    /// URL of the shadow graph receiving the mirrored requests
+    url: url::Url,
+    /// Percentage of the requests mirrored, from 0 to 100
+    #[serde(default = "default_percentage")]
+    percentage: f64,
+    /// Mirror the mutations too. They are not mirrored by default, as the shadow graph would apply
+    /// them a second time
+    #[serde(default)]
+    include_mutations: bool,
+    /// Compare the responses of the shadow graph with the responses sent to the clients, and count
+    /// the mismatches
+    #[serde(default)]
+    diff: bool,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Timeout of the mirrored requests (defaults to 30s)
+    timeout: Option<Duration>,
+    /// Maximum number of mirrored requests in flight (defaults to 100). The requests selected
+    /// while this many are in flight are not mirrored
+    max_in_flight: Option<usize>,
+    /// Headers of the client requests not sent to the shadow graph, along with the
+    /// `authorization`, `proxy-authorization` and `cookie` headers
+    #[serde(default)]
+    remove_headers: Vec<String>,
+    /// Only send these headers of the client requests to the shadow graph, instead of all the
+    /// headers except the removed ones
+    #[serde(default)]
+    forward_headers: Option<Vec<String>>,
+}
+
+fn default_percentage() -> f64 {
+    100.0
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    mirrored: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+    mismatches: AtomicU64,
+}
+
+impl Stats {
+    fn register(stats: &Arc<Stats>, metrics: &PluginMetrics) {
+        let stats = stats.clone();
+        metrics.register_observers(move |meter| {
+            #[allow(clippy::type_complexity)]
+            let counters: [(&'static str, &'static str, fn(&Stats) -> &AtomicU64); 4] = [
+                (
+                    "apollo_router_mirrored_requests_total",
+                    "Requests sent to the shadow graph",
+                    |stats| &stats.mirrored,
+                ),
+                (
+                    "apollo_router_mirrored_requests_dropped_total",
+                    "Requests not mirrored because too many mirrored requests were in flight",
+                    |stats| &stats.dropped,
+                ),
+                (
+                    "apollo_router_mirrored_requests_failed_total",
+                    "Mirrored requests without a valid response from the shadow graph",
+                    |stats| &stats.failed,
+                ),
+                (
+                    "apollo_router_mirrored_requests_mismatches_total",
+                    "Responses of the shadow graph different from the responses sent to the clients",
+                    |stats| &stats.mismatches,
+                ),
+            ];
+            for (name, description, counter) in counters {
+                let stats = stats.clone();
+                meter
+                    .u64_sum_observer(name, move |result: ObserverResult<u64>| {
+                        result.observe(counter(&stats).load(Ordering::Relaxed), &[])
+                    })
+                    .with_description(description)
+                    .init();
+            }
+        });
+    }
+}
+
+#[derive(Debug)]
+struct Mirror {
+    config: MirroringConfig,
+    client: reqwest::Client,
+    /// Requests considered for mirroring, used to spread the mirrored ones.
+    requests: AtomicU64,
+    in_flight: Arc<Semaphore>,
+    stats: Arc<Stats>,
+    /// Headers not sent to the shadow graph, if they are not allowlisted.
+    removed: Vec<HeaderName>,
+    /// Only headers sent to the shadow graph, if set.
+    forwarded: Option<Vec<HeaderName>>,
+}
+
+impl Mirror {
+    /// Whether the next request is mirrored, so that the configured percentage of the requests is.
+    fn sample(&self) -> bool {
+        let n = self.requests.fetch_add(1, Ordering::Relaxed) as f64;
+        let percentage = self.config.percentage / 100.0;
+        ((n + 1.0) * percentage).floor() > (n * percentage).floor()
+    }
+
+    /// The request to mirror, if it is selected. The requests are sampled before their operation
+    /// is parsed to skip the mutations, so that only the sampled ones are parsed.
+    fn select(&self, request: &SupergraphRequest) -> Option<MirroredRequest> {
+        if !self.sample() {
+            return None;
+        }
+        let body = request.originating_request.body();
+        if !self.config.include_mutations && is_mutation(body) {
+            return None;
+        }
+        let permit = match self.in_flight.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+
+        Some(MirroredRequest {
+            headers: self.headers(request.originating_request.headers()),
+            body: body.clone(),
+            _permit: permit,
+        })
+    }
+
+    /// The headers of a client request sent to the shadow graph.
+    fn headers(&self, client: &HeaderMap) -> HeaderMap {
+        let mut headers = match &self.forwarded {
+            Some(forwarded) => {
+                let mut headers = HeaderMap::new();
+                for name in forwarded {
+                    for value in client.get_all(name) {
+                        headers.append(name.clone(), value.clone());
+                    }
+                }
+                headers
+            }
+            None => {
+                let mut headers = client.clone();
+                for name in &self.removed {
+                    headers.remove(name);
+                }
+                headers
+            }
+        };
+        headers.remove(header::HOST);
+        headers.remove(header::CONTENT_LENGTH);
+        headers
+    }
+
+    /// Send the request to the shadow graph, and compare its response with the first response
+    /// sent to the client if it is given.
+    async fn send(
+        &self,
+        request: MirroredRequest,
+        primary: Option<oneshot::Receiver<graphql::Response>>,
+    ) {
+        self.stats.mirrored.fetch_add(1, Ordering::Relaxed);
+        let shadow = match self
+            .client
+            .post(self.config.url.clone())
+            .headers(request.headers)
+            .json(&request.body)
+            .send()
+            .await
+        {
+            Ok(response) => response.bytes().await,
+            Err(e) => Err(e),
+        };
+        let shadow = match shadow {
+            Ok(shadow) => shadow,
+            Err(e) => {
+                self.stats.failed.fetch_add(1, Ordering::Relaxed);
+                tracing::debug!("mirrored request failed: {}", e);
+                return;
+            }
+        };
+
+        let primary = match primary {
+            Some(primary) => primary,
+            None => return,
+        };
+        // the client response stream was dropped before its first response
+        let primary = match primary.await {
+            Ok(primary) => primary,
+            Err(_) => return,
+        };
+        match serde_json::from_slice::<graphql::Response>(&shadow) {
+            Ok(shadow) => {
+                if shadow.data != primary.data
+                    || shadow.errors.is_empty() != primary.errors.is_empty()
+                {
+                    self.stats.mismatches.fetch_add(1, Ordering::Relaxed);
+                    tracing::debug!(
+                        "mirrored response mismatch for operation {:?}",
+                        request.body.operation_name
+                    );
+                }
+            }
+            Err(e) => {
+                self.stats.failed.fetch_add(1, Ordering::Relaxed);
+                tracing::debug!("mirrored request returned an invalid response: {}", e);
+            }
+        }
+    }
+}
+
+struct MirroredRequest {
+    headers: HeaderMap,
+    body: graphql::Request,
+    /// Released once the mirrored request completes.
+    _permit: OwnedSemaphorePermit,
+}
+
+/// Whether the operation of the request is a mutation.
+fn is_mutation(request: &graphql::Request) -> bool {
+    let query = match &request.query {
+        Some(query) => query,
+        None => return false,
+    };
+    let tree = apollo_parser::Parser::new(query).parse();
+    tree.document().definitions().any(|definition| {
+        if let ast::Definition::OperationDefinition(operation) = definition {
+            let selected = match (&request.operation_name, operation.name()) {
+                (Some(operation_name), Some(name)) => name.text() == *operation_name,
+                (Some(_), None) => false,
+                (None, _) => true,
+            };
+            selected
+                && operation
+                    .operation_type()
+                    .and_then(|operation_type| operation_type.mutation_token())
+                    .is_some()
+        } else {
+            false
+        }
+    })
+}
+
+#[derive(Debug)]
+struct Mirroring {
+    mirror: Arc<Mirror>,
+}
+
+#[async_trait::async_trait]
+impl Plugin for Mirroring {
+    type Config = MirroringConfig;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        if !(0.0..=100.0).contains(&init.config.percentage) {
+            return Err(ConfigurationError::InvalidConfiguration {
+                message: "bad configuration for mirroring plugin",
+                error: "the percentage of mirrored requests must be between 0 and 100".to_string(),
+            }
+            .into());
+        }
+        let header_names = |names: &[String]| {
+            names
+                .iter()
+                .map(|name| {
+                    HeaderName::from_bytes(name.as_bytes()).map_err(|_| {
+                        ConfigurationError::InvalidConfiguration {
+                            message: "bad configuration for mirroring plugin",
+                            error: format!("'{}' is not a valid header name", name),
+                        }
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let mut removed = CREDENTIAL_HEADERS.to_vec();
+        removed.extend(header_names(&init.config.remove_headers)?);
+        let forwarded = match &init.config.forward_headers {
+            Some(names) => Some(header_names(names)?),
+            None => None,
+        };
+        let client = reqwest::Client::builder()
+            .timeout(init.config.timeout.unwrap_or(DEFAULT_TIMEOUT))
+            .build()?;
+        let in_flight = Arc::new(Semaphore::new(
+            init.config.max_in_flight.unwrap_or(DEFAULT_MAX_IN_FLIGHT),
+        ));
+        let stats = Arc::new(Stats::default());
+        Stats::register(&stats, &init.metrics);
+
+        Ok(Mirroring {
+            mirror: Arc::new(Mirror {
+                config: init.config,
+                client,
+                requests: AtomicU64::new(0),
+                in_flight,
+                stats,
+                removed,
+                forwarded,
+            }),
+        })
+    }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        let mirror = self.mirror.clone();
+        let select = self.mirror.clone();
+        ServiceBuilder::new()
+            .map_future_with_request_data(
+                move |req: &SupergraphRequest| select.select(req),
+                move |request: Option<MirroredRequest>, fut| {
+                    let mirror = mirror.clone();
+                    async move {
+                        let response: SupergraphResponse = fut.await?;
+                        let request = match request {
+                            Some(request) => request,
+                            None => return Ok(response),
+                        };
+
+                        let (response, primary) = if mirror.config.diff {
+                            let (sender, receiver) = oneshot::channel();
+                            let mut sender = Some(sender);
+                            let response = response.map_stream(move |response| {
+                                if let Some(sender) = sender.take() {
+                                    let _ = sender.send(response.clone());
+                                }
+                                response
+                            });
+                            (response, Some(receiver))
+                        } else {
+                            (response, None)
+                        };
+                        tokio::spawn(
+                            async move { mirror.send(request, primary).await }.in_current_span(),
+                        );
+
+                        Ok(response)
+                    }
+                },
+            )
+            .service(service)
+            .boxed()
+    }
+}
+
+register_plugin!("apollo", "mirroring", Mirroring);
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+
+    use axum::routing::post;
+    use axum::Json;
+    use axum::Router;
+    use serde_json::json;
+
+    use super::*;
+    use crate::plugin::test::MockSupergraphService;
+
+    fn config(url: &str) -> MirroringConfig {
+        serde_json::from_value(json!({ "url": url, "diff": true })).unwrap()
+    }
+
+    async fn mirroring(config: MirroringConfig) -> Mirroring {
+        Mirroring::new(PluginInit::new(config, Default::default()))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_mirrors_the_configured_percentage() {
+        let mut quarter = config("http://localhost:4000");
+        quarter.percentage = 25.0;
+        let mirror = mirroring(quarter).await.mirror;
+        let mirrored = (0..100).filter(|_| mirror.sample()).count();
+        assert_eq!(mirrored, 25);
+
+        let mut invalid = config("http://localhost:4000");
+        invalid.percentage = 120.0;
+        assert!(Mirroring::new(PluginInit::new(invalid, Default::default()))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn it_detects_mutations() {
+        let request = |query: &str, operation_name: Option<&str>| {
+            graphql::Request::builder()
+                .query(query.to_string())
+                .and_operation_name(operation_name.map(str::to_string))
+                .build()
+        };
+        assert!(is_mutation(&request("mutation { createProduct }", None)));
+        assert!(!is_mutation(&request("{ topProducts { upc } }", None)));
+        assert!(is_mutation(&request(
+            "query A { me { id } } mutation B { createProduct }",
+            Some("B")
+        )));
+        assert!(!is_mutation(&request(
+            "query A { me { id } } mutation B { createProduct }",
+            Some("A")
+        )));
+    }
+
+    #[tokio::test]
+    async fn it_counts_mismatches() {
+        // shadow graph answering with a different response
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let shadow_received = received.clone();
+        let app = Router::new().route(
+            "/",
+            post(
+                move |headers: HeaderMap, Json(body): Json<serde_json::Value>| {
+                    shadow_received.lock().unwrap().push((headers, body));
+                    async { Json(json!({ "data": { "test": 5678 } })) }
+                },
+            ),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(
+            hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let mut mock_service = MockSupergraphService::new();
+        mock_service.expect_call().times(1).returning(|_| {
+            Ok(SupergraphResponse::fake_builder()
+                .data(json!({ "test": 1234 }))
+                .build()
+                .unwrap())
+        });
+        let mirroring = mirroring(config(&url)).await;
+        let service = mirroring.supergraph_service(mock_service.boxed());
+
+        let mut response = service
+            .oneshot(
+                SupergraphRequest::fake_builder()
+                    .query("{ test }")
+                    .header("authorization", "Bearer secret")
+                    .header("cookie", "session=secret")
+                    .header("x-client", "web")
+                    .build()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            response.next_response().await.unwrap().data,
+            Some(json!({ "test": 1234 }).into())
+        );
+
+        for _ in 0..50 {
+            if mirroring.mirror.stats.mismatches.load(Ordering::Relaxed) == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(mirroring.mirror.stats.mirrored.load(Ordering::Relaxed), 1);
+        assert_eq!(mirroring.mirror.stats.mismatches.load(Ordering::Relaxed), 1);
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let (headers, body) = &received[0];
+        assert_eq!(body, &json!({ "query": "{ test }" }));
+        // the credentials of the client are not sent to the shadow graph
+        assert!(headers.get("authorization").is_none());
+        assert!(headers.get("cookie").is_none());
+        assert_eq!(headers.get("x-client").unwrap(), "web");
+    }
+
+    #[tokio::test]
+    async fn it_only_parses_the_sampled_requests() {
+        let mut config = config("http://localhost:4000");
+        config.percentage = 50.0;
+        let mirroring = mirroring(config).await;
+        let request = |query: &str| {
+            SupergraphRequest::fake_builder()
+                .query(query)
+                .build()
+                .unwrap()
+        };
+
+        // the mutations are skipped once sampled, and count in the sampled requests
+        assert!(mirroring.mirror.select(&request("{ test }")).is_none());
+        assert!(mirroring
+            .mirror
+            .select(&request("mutation { createProduct }"))
+            .is_none());
+        assert!(mirroring.mirror.select(&request("{ test }")).is_none());
+        assert!(mirroring.mirror.select(&request("{ test }")).is_some());
+    }
+
+    #[tokio::test]
+    async fn it_filters_the_mirrored_headers() {
+        let client: HeaderMap = [
+            ("host", "router"),
+            ("authorization", "Bearer secret"),
+            ("x-api-key", "secret"),
+            ("x-client", "web"),
+            ("x-trace", "1234"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+        .collect();
+        let names = |headers: HeaderMap| {
+            let mut names: Vec<String> = headers.keys().map(|name| name.to_string()).collect();
+            names.sort();
+            names
+        };
+
+        let mut removing = config("http://localhost:4000");
+        removing.remove_headers = vec!["X-Api-Key".to_string()];
+        let mirror = mirroring(removing).await.mirror;
+        assert_eq!(names(mirror.headers(&client)), ["x-client", "x-trace"]);
+
+        let mut forwarding = config("http://localhost:4000");
+        forwarding.forward_headers = Some(vec!["x-client".to_string(), "host".to_string()]);
+        let mirror = mirroring(forwarding).await.mirror;
+        assert_eq!(names(mirror.headers(&client)), ["x-client"]);
+
+        let mut invalid = config("http://localhost:4000");
+        invalid.remove_headers = vec!["not a header".to_string()];
+        assert!(Mirroring::new(PluginInit::new(invalid, Default::default()))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn it_bounds_the_mirrored_requests_in_flight() {
+        let mut config = config("http://localhost:4000");
+        config.max_in_flight = Some(1);
+        let mirroring = mirroring(config).await;
+        let request = SupergraphRequest::fake_builder()
+            .query("{ test }")
+            .build()
+            .unwrap();
+
+        let in_flight = mirroring.mirror.select(&request);
+        assert!(in_flight.is_some());
+        assert!(mirroring.mirror.select(&request).is_none());
+        assert_eq!(mirroring.mirror.stats.dropped.load(Ordering::Relaxed), 1);
+
+        drop(in_flight);
+        assert!(mirroring.mirror.select(&request).is_some());
+    }
+}
@@ -2,11 +2,15 @@
 //!
 //! These plugins are compiled into the router and configured via YAML configuration.
 
+pub(crate) mod authentication;
+mod connectors;
 pub(crate) mod csrf;
 mod expose_query_plan;
+mod expose_timings;
 mod forbid_mutations;
 mod headers;
 mod include_subgraph_errors;
+mod mirroring;
 pub(crate) mod override_url;
 pub(crate) mod rhai;
 pub(crate) mod telemetry;
@@ -7,6 +7,7 @@ use http::Uri;
 use tower::BoxError;
 use tower::ServiceExt;
 
+use crate::configuration::Configuration;
 use crate::plugin::Plugin;
 use crate::plugin::PluginInit;
 use crate::register_plugin;
@@ -14,10 +15,23 @@ use crate::services::subgraph;
 use crate::SubgraphRequest;
 
 #[derive(Debug, Clone)]
-struct OverrideSubgraphUrl {
+pub(crate) struct OverrideSubgraphUrl {
     urls: HashMap<String, Uri>,
 }
 
+impl OverrideSubgraphUrl {
+    /// The URL this plugin sends the requests of a subgraph to, if the configuration overrides it.
+    pub(crate) fn get_configuration_url(
+        configuration: &Configuration,
+        subgraph_name: &str,
+    ) -> Option<url::Url> {
+        configuration
+            .plugin_configuration("apollo.override_subgraph_url")
+            .and_then(|config| serde_json::from_value::<<Self as Plugin>::Config>(config).ok())
+            .and_then(|mut urls| urls.remove(subgraph_name))
+    }
+}
+
 #[async_trait::async_trait]
 impl Plugin for OverrideSubgraphUrl {
     type Config = HashMap<String, url::Url>;
@@ -1,11 +1,16 @@
 //! Configuration for apollo telemetry.
 // This entire file is license key functionality
+use std::collections::HashMap;
+
 use http::header::HeaderName;
 use schemars::JsonSchema;
 use serde::Deserialize;
+use serde::Serialize;
+use tower::BoxError;
 use url::Url;
 
 use crate::plugin::serde::deserialize_header_name;
+use crate::plugins::telemetry::disk_buffer::DiskBufferConfig;
 
 #[derive(Debug, Clone, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
@@ -35,12 +40,113 @@ pub(crate) struct Config {
     )]
     pub(crate) client_version_header: HeaderName,
 
+    /// Send the trace of every operation to Studio instead of its statistics, so that the
+    /// operations can be inspected one by one.
+    #[serde(default)]
+    pub(crate) send_traces: bool,
+
+    /// The share of the operations that are traced when `send_traces` is enabled, between 0 and 1.
+    /// The other operations are reported with statistics.
+    #[serde(default = "trace_sampling_rate_default")]
+    pub(crate) trace_sampling_rate: f64,
+
+    /// The sampling rate of the operations with these names, overriding `trace_sampling_rate`.
+    #[serde(default)]
+    pub(crate) trace_sampling_overrides: HashMap<String, f64>,
+
+    /// The variable values sent in the traces. By default, no variable value is sent.
+    #[serde(default)]
+    pub(crate) send_variable_values: ForwardValues,
+
+    /// The request headers sent in the traces. By default, no header is sent. The `authorization`,
+    /// `cookie` and `set-cookie` headers are never sent.
+    #[serde(default)]
+    pub(crate) send_headers: ForwardHeaders,
+
+    /// Keep the reports and traces that could not be sent to Studio on disk, to send them once it
+    /// is reachable again, including after a restart.
+    #[serde(default)]
+    pub(crate) disk_buffer: Option<DiskBufferConfig>,
+
+    /// Whether the reports are sent through the Spaceport embedded in the router.
+    #[schemars(skip)]
+    #[serde(skip)]
+    pub(crate) embedded_spaceport: bool,
+
+    /// Whether the timestamps are normalized, from `experimental_deterministic`.
+    #[schemars(skip)]
+    #[serde(skip)]
+    pub(crate) deterministic: bool,
+
     // This'll get overridden if a user tries to set it.
     // The purpose is to allow is to pass this in to the plugin.
     #[schemars(skip)]
     pub(crate) schema_id: String,
 }
 
+/// Variable values sent in the traces.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ForwardValues {
+    /// Send no variable value.
+    None,
+    /// Send all the variable values.
+    All,
+    /// Send the values of these variables only.
+    Only(Vec<String>),
+}
+
+impl Default for ForwardValues {
+    fn default() -> Self {
+        ForwardValues::None
+    }
+}
+
+/// Request headers sent in the traces.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ForwardHeaders {
+    /// Send no header.
+    None,
+    /// Send all the headers.
+    All,
+    /// Send these headers only.
+    Only(Vec<String>),
+    /// Send all the headers except these ones.
+    Except(Vec<String>),
+}
+
+impl Default for ForwardHeaders {
+    fn default() -> Self {
+        ForwardHeaders::None
+    }
+}
+
+impl Config {
+    /// The share of the operations with this name that are traced.
+    pub(crate) fn sampling_rate_of(&self, operation_name: Option<&str>) -> f64 {
+        operation_name
+            .and_then(|name| self.trace_sampling_overrides.get(name))
+            .copied()
+            .unwrap_or(self.trace_sampling_rate)
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), BoxError> {
+        let rates = std::iter::once(&self.trace_sampling_rate)
+            .chain(self.trace_sampling_overrides.values());
+        for rate in rates {
+            if !(0.0..=1.0).contains(rate) {
+                return Err(format!(
+                    "invalid trace sampling rate {}: it must be between 0 and 1",
+                    rate
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
+
 fn apollo_key() -> Option<String> {
     std::env::var("APOLLO_KEY").ok()
 }
@@ -49,6 +155,10 @@ fn apollo_graph_reference() -> Option<String> {
     std::env::var("APOLLO_GRAPH_REF").ok()
 }
 
+fn trace_sampling_rate_default() -> f64 {
+    1.0
+}
+
 fn client_name_header_default_str() -> &'static str {
     "apollographql-client-name"
 }
@@ -73,6 +183,14 @@ impl Default for Config {
             apollo_graph_ref: None,
             client_name_header: client_name_header_default(),
             client_version_header: client_version_header_default(),
+            send_traces: false,
+            trace_sampling_rate: trace_sampling_rate_default(),
+            trace_sampling_overrides: HashMap::new(),
+            send_variable_values: ForwardValues::default(),
+            send_headers: ForwardHeaders::default(),
+            disk_buffer: None,
+            embedded_spaceport: false,
+            deterministic: false,
             schema_id: "<no_schema_id>".to_string(),
         }
     }
@@ -1,8 +1,13 @@
 //! Configuration for the telemetry plugin.
 use std::borrow::Cow;
 use std::collections::BTreeMap;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 
 use opentelemetry::sdk::Resource;
+use opentelemetry::trace::IdGenerator;
+use opentelemetry::trace::SpanId;
+use opentelemetry::trace::TraceId;
 use opentelemetry::Array;
 use opentelemetry::KeyValue;
 use opentelemetry::Value;
@@ -43,7 +48,34 @@ pub struct Conf {
     #[allow(dead_code)]
     pub(crate) metrics: Option<Metrics>,
     pub(crate) tracing: Option<Tracing>,
+    pub(crate) logs: Option<logs::Config>,
     pub(crate) apollo: Option<apollo::Config>,
+    pub(crate) scrubbing: Option<scrubbing::Config>,
+    /// Normalize timestamps, durations and generated trace and span ids so that emitted telemetry
+    /// is stable across runs. This is intended for snapshot tests and must not be used in
+    /// production.
+    #[serde(default)]
+    pub(crate) experimental_deterministic: bool,
+}
+
+impl Conf {
+    /// The duration to report for an operation that started at `start`.
+    pub(crate) fn elapsed(&self, start: Instant) -> Duration {
+        if self.experimental_deterministic {
+            DETERMINISTIC_DURATION
+        } else {
+            start.elapsed()
+        }
+    }
+}
+
+/// The time to report for an event happening now.
+pub(crate) fn now(deterministic: bool) -> SystemTime {
+    if deterministic {
+        DETERMINISTIC_TIME
+    } else {
+        SystemTime::now()
+    }
 }
 
 #[derive(Clone, Default, Debug, Deserialize, JsonSchema)]
@@ -53,6 +85,8 @@ pub(crate) struct Metrics {
     pub(crate) common: Option<MetricsCommon>,
     pub(crate) otlp: Option<otlp::Config>,
     pub(crate) prometheus: Option<metrics::prometheus::Config>,
+    /// Metrics exporters provided by plugins, by name
+    pub(crate) custom: Option<metrics::custom::Config>,
 }
 
 #[derive(Clone, Default, Debug, Deserialize, JsonSchema)]
@@ -60,6 +94,14 @@ pub(crate) struct Metrics {
 pub(crate) struct MetricsCommon {
     /// Configuration to add custom labels/attributes to metrics
     pub(crate) attributes: Option<MetricsAttributesConf>,
+    /// Configuration to label the router metrics with the operation and the client of the requests
+    pub(crate) operations: Option<metrics::operation::OperationAttributesConf>,
+    /// Configuration of the histograms recording the latencies
+    #[serde(default)]
+    pub(crate) histograms: metrics::histogram::HistogramConf,
+    /// Record the size of the responses, and their numbers of entities and null fields
+    #[serde(default)]
+    pub(crate) response_size: bool,
     #[serde(default)]
     /// Resources
     pub(crate) resources: HashMap<String, String>,
@@ -265,6 +307,27 @@ impl From<&Trace> for opentelemetry::sdk::trace::Config {
     }
 }
 
+/// Generates sequential trace and span ids, starting at 1.
+///
+/// Used in deterministic mode so that ids in exported traces do not change between test runs.
+#[derive(Debug, Default)]
+pub(crate) struct DeterministicIdGenerator {
+    trace_id: AtomicU64,
+    span_id: AtomicU64,
+}
+
+impl IdGenerator for DeterministicIdGenerator {
+    fn new_trace_id(&self) -> TraceId {
+        let id = self.trace_id.fetch_add(1, Ordering::Relaxed) as u128 + 1;
+        TraceId::from_bytes(id.to_be_bytes())
+    }
+
+    fn new_span_id(&self) -> SpanId {
+        let id = self.span_id.fetch_add(1, Ordering::Relaxed) + 1;
+        SpanId::from_bytes(id.to_be_bytes())
+    }
+}
+
 fn parent_based(sampler: opentelemetry::sdk::trace::Sampler) -> opentelemetry::sdk::trace::Sampler {
     opentelemetry::sdk::trace::Sampler::ParentBased(Box::new(sampler))
 }
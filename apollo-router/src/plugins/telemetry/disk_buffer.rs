@@ -0,0 +1,367 @@
+//! Records kept on disk until they can be exported.
+//!
+//! Every record is a file of the buffer directory, written to a temporary file then renamed so
+//! that a crash never leaves a partial record, and checked against its SHA-256 digest when it is
+//! read. The names of the records start with their creation time, so that they are read in order.
+//!
+//! The directory is listed when the buffer is first used, and again once all the records it knows
+//! of were exported, to pick up the ones written by the other buffers sharing the directory, e.g.
+//! the one of the previous configuration. A record is claimed by renaming it before it is
+//! exported, so that only one buffer exports it, and a claim left by a crash is given back after
+//! a minute.
+
+use std::collections::VecDeque;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+const MAGIC: &[u8] = b"ROUTERB1";
+const HEADER_SIZE: usize = MAGIC.len() + 32;
+const RECORD_EXTENSION: &str = "record";
+const TEMPORARY_EXTENSION: &str = "tmp";
+const CLAIMED_EXTENSION: &str = "claimed";
+/// Temporary files and claims older than this were left by a crash.
+const STALE_FILE: Duration = Duration::from_secs(60);
+
+/// Configuration of a disk buffer.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DiskBufferConfig {
+    /// Directory of the buffer, created if it does not exist.
+    pub(crate) path: PathBuf,
+
+    /// Maximum size of the buffer in bytes, the oldest records being dropped to make room for
+    /// the new ones.
+    /// default: 67108864
+    #[serde(default = "default_max_size")]
+    pub(crate) max_size: u64,
+}
+
+fn default_max_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+/// A record claimed from the buffer, until it is removed or released.
+#[derive(Debug)]
+pub(crate) struct Record {
+    path: PathBuf,
+    claimed: PathBuf,
+    size: u64,
+    pub(crate) payload: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct DiskBuffer {
+    path: PathBuf,
+    max_size: u64,
+    index: Arc<Mutex<Index>>,
+}
+
+/// The records of the directory known to a buffer, oldest first.
+#[derive(Debug, Default)]
+struct Index {
+    loaded: bool,
+    records: VecDeque<(PathBuf, u64)>,
+    size: u64,
+}
+
+impl DiskBuffer {
+    pub(crate) fn new(config: &DiskBufferConfig) -> Self {
+        Self {
+            path: config.path.clone(),
+            max_size: config.max_size,
+            index: Default::default(),
+        }
+    }
+
+    /// Add a record at the end of the buffer.
+    pub(crate) async fn push(&self, payload: &[u8]) -> io::Result<()> {
+        let size = (HEADER_SIZE + payload.len()) as u64;
+        if size > self.max_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "the record of {} bytes does not fit in the buffer of {} bytes",
+                    size, self.max_size
+                ),
+            ));
+        }
+        let mut index = self.index.lock().await;
+        if !index.loaded {
+            self.load(&mut index).await?;
+        }
+        tokio::fs::create_dir_all(&self.path).await?;
+
+        let mut dropped = 0;
+        while index.size + size > self.max_size {
+            match index.records.pop_front() {
+                Some((path, record_size)) => {
+                    index.size -= record_size;
+                    remove(&path).await?;
+                    dropped += 1;
+                }
+                None => break,
+            }
+        }
+        if dropped > 0 {
+            tracing::warn!(
+                "the telemetry buffer {} is full, {} records were dropped",
+                self.path.display(),
+                dropped
+            );
+        }
+
+        let name = format!(
+            "{:020}-{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+            uuid::Uuid::new_v4().simple()
+        );
+        let temporary = self.path.join(&name).with_extension(TEMPORARY_EXTENSION);
+        let mut file = tokio::fs::File::create(&temporary).await?;
+        file.write_all(MAGIC).await?;
+        file.write_all(&Sha256::digest(payload)).await?;
+        file.write_all(payload).await?;
+        file.sync_all().await?;
+        let path = self.path.join(name).with_extension(RECORD_EXTENSION);
+        tokio::fs::rename(&temporary, &path).await?;
+        index.records.push_back((path, size));
+        index.size += size;
+        Ok(())
+    }
+
+    /// Claim the oldest record of the buffer, the corrupted ones being dropped.
+    pub(crate) async fn first(&self) -> io::Result<Option<Record>> {
+        let mut index = self.index.lock().await;
+        if index.records.is_empty() {
+            self.load(&mut index).await?;
+        }
+        while let Some((path, size)) = index.records.pop_front() {
+            index.size -= size;
+            let claimed = path.with_extension(format!("{}.{}", now(), CLAIMED_EXTENSION));
+            match tokio::fs::rename(&path, &claimed).await {
+                Ok(()) => {}
+                // claimed by another buffer of the directory
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    index.records.push_front((path, size));
+                    index.size += size;
+                    return Err(e);
+                }
+            }
+            let content = tokio::fs::read(&claimed).await?;
+            if content.len() >= HEADER_SIZE
+                && content.starts_with(MAGIC)
+                && content[MAGIC.len()..HEADER_SIZE] == Sha256::digest(&content[HEADER_SIZE..])[..]
+            {
+                return Ok(Some(Record {
+                    path,
+                    claimed,
+                    size,
+                    payload: content[HEADER_SIZE..].to_vec(),
+                }));
+            }
+            tracing::warn!("dropping the corrupted telemetry record {}", path.display());
+            remove(&claimed).await?;
+        }
+        Ok(None)
+    }
+
+    /// Remove a record once it was exported.
+    pub(crate) async fn remove(&self, record: Record) -> io::Result<()> {
+        remove(&record.claimed).await
+    }
+
+    /// Give back a record which could not be exported, to export it first next time.
+    pub(crate) async fn release(&self, record: Record) -> io::Result<()> {
+        let mut index = self.index.lock().await;
+        tokio::fs::rename(&record.claimed, &record.path).await?;
+        index.records.push_front((record.path, record.size));
+        index.size += record.size;
+        Ok(())
+    }
+
+    /// The number of records known to the buffer.
+    pub(crate) async fn count(&self) -> io::Result<usize> {
+        let mut index = self.index.lock().await;
+        if !index.loaded {
+            self.load(&mut index).await?;
+        }
+        Ok(index.records.len())
+    }
+
+    /// List the records of the directory, giving back the stale claims.
+    async fn load(&self, index: &mut Index) -> io::Result<()> {
+        let mut records = Vec::new();
+        let mut entries = match tokio::fs::read_dir(&self.path).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                *index = Index {
+                    loaded: true,
+                    ..Default::default()
+                };
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let metadata = match entry.metadata().await {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some(RECORD_EXTENSION) => records.push((path, metadata.len())),
+                Some(TEMPORARY_EXTENSION) => {
+                    let stale = metadata
+                        .modified()
+                        .ok()
+                        .and_then(|modified| modified.elapsed().ok())
+                        .map_or(false, |age| age > STALE_FILE);
+                    if stale {
+                        remove(&path).await?;
+                    }
+                }
+                Some(CLAIMED_EXTENSION) => {
+                    // the claims are named after the record and the time they were made
+                    let claim = path.with_extension("");
+                    let stale = claim
+                        .extension()
+                        .and_then(|claimed_at| claimed_at.to_str()?.parse::<u64>().ok())
+                        .map_or(false, |claimed_at| {
+                            now().saturating_sub(claimed_at) > STALE_FILE.as_secs()
+                        });
+                    if stale {
+                        let record = claim.with_extension(RECORD_EXTENSION);
+                        match tokio::fs::rename(&path, &record).await {
+                            Ok(()) => records.push((record, metadata.len())),
+                            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                            Err(e) => return Err(e),
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        records.sort();
+        index.size = records.iter().map(|(_, size)| size).sum();
+        index.records = records.into();
+        index.loaded = true;
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+async fn remove(path: &Path) -> io::Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disk_buffer(path: &Path, max_size: u64) -> DiskBuffer {
+        DiskBuffer::new(&DiskBufferConfig {
+            path: path.join("buffer"),
+            max_size,
+        })
+    }
+
+    async fn drain(buffer: &DiskBuffer) -> Vec<Vec<u8>> {
+        let mut payloads = Vec::new();
+        while let Some(record) = buffer.first().await.unwrap() {
+            payloads.push(record.payload.clone());
+            buffer.remove(record).await.unwrap();
+        }
+        payloads
+    }
+
+    #[tokio::test]
+    async fn it_keeps_the_records_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let buffer = disk_buffer(dir.path(), default_max_size());
+        assert!(buffer.first().await.unwrap().is_none());
+        for payload in [&b"one"[..], b"two", b"three"] {
+            buffer.push(payload).await.unwrap();
+        }
+        assert_eq!(buffer.count().await.unwrap(), 3);
+
+        // the records are read by the buffers of the next configurations and restarts
+        let next = disk_buffer(dir.path(), default_max_size());
+        assert_eq!(
+            drain(&next).await,
+            vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()]
+        );
+        assert!(buffer.first().await.unwrap().is_none());
+        assert_eq!(buffer.count().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn it_exports_each_record_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let buffer = disk_buffer(dir.path(), default_max_size());
+        let other = disk_buffer(dir.path(), default_max_size());
+        buffer.push(b"one").await.unwrap();
+        buffer.push(b"two").await.unwrap();
+
+        let one = buffer.first().await.unwrap().unwrap();
+        let two = other.first().await.unwrap().unwrap();
+        assert_eq!(one.payload, b"one");
+        assert_eq!(two.payload, b"two");
+        assert!(other.first().await.unwrap().is_none());
+
+        other.release(two).await.unwrap();
+        buffer.remove(one).await.unwrap();
+        assert_eq!(drain(&buffer).await, vec![b"two".to_vec()]);
+        assert!(other.first().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn it_drops_the_corrupted_and_oldest_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let buffer = disk_buffer(dir.path(), 3 * (HEADER_SIZE as u64 + 3));
+        for payload in [&b"one"[..], b"two", b"six"] {
+            buffer.push(payload).await.unwrap();
+        }
+        let (first, _) = buffer.index.lock().await.records[0].clone();
+        let mut content = std::fs::read(&first).unwrap();
+        content[HEADER_SIZE] = b'O';
+        std::fs::write(&first, content).unwrap();
+        std::fs::write(dir.path().join("buffer/partial.tmp"), b"ROUTER").unwrap();
+
+        assert_eq!(drain(&buffer).await, vec![b"two".to_vec(), b"six".to_vec()]);
+
+        for payload in [&b"one"[..], b"two", b"six", b"ten"] {
+            buffer.push(payload).await.unwrap();
+        }
+        assert!(buffer.push(&[0; 100]).await.is_err());
+        assert_eq!(
+            drain(&buffer).await,
+            vec![b"two".to_vec(), b"six".to_vec(), b"ten".to_vec()]
+        );
+    }
+}
@@ -0,0 +1,448 @@
+//! Export of the router logs with OpenTelemetry.
+//!
+//! The tracing events of the router are exported as OTLP log records, correlated with the trace
+//! and span they were emitted in. Records are sent in batches, in the background, to the
+//! collector of the active pipeline, using OTLP/HTTP with the JSON encoding. Records are dropped
+//! when the collector can not keep up.
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::sync::Weak;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use http::header::CONTENT_TYPE;
+use opentelemetry::trace::SpanId;
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::trace::TraceId;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::json;
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tower::BoxError;
+use tracing::field::Field;
+use tracing::field::Visit;
+use tracing::Dispatch;
+use tracing::Event;
+use tracing::Level;
+use tracing::Subscriber;
+use tracing_futures::WithSubscriber;
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+use url::Url;
+
+use crate::plugins::telemetry::scrubbing::ScrubbingHandle;
+
+/// Maximum number of records waiting to be exported.
+const MAX_QUEUE_SIZE: usize = 2048;
+/// Maximum number of records sent in one request.
+const MAX_BATCH_SIZE: usize = 512;
+/// Delay between two exports of the waiting records.
+const SCHEDULED_DELAY: Duration = Duration::from_secs(1);
+/// Timeout of the export requests.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+/// Export of the router logs
+pub(crate) struct Config {
+    /// Export of the logs to an OpenTelemetry collector, using OTLP/HTTP
+    pub(crate) otlp: Option<OtlpConfig>,
+    /// Log of the operations slower than a threshold, with the size of their data
+    pub(crate) slow_operations: Option<SlowOperations>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+/// Log of the slow operations
+pub(crate) struct SlowOperations {
+    /// Duration above which an operation is logged, once its last response part is sent
+    #[serde(with = "humantime_serde")]
+    #[schemars(with = "String")]
+    pub(crate) threshold: Duration,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+/// Export of the logs to an OpenTelemetry collector
+pub(crate) struct OtlpConfig {
+    /// The logs endpoint of the collector
+    #[serde(default = "default_endpoint")]
+    pub(crate) endpoint: Url,
+    /// Headers sent with the export requests, e.g. to authenticate with the collector
+    #[serde(default)]
+    pub(crate) headers: HashMap<String, String>,
+    /// Minimum level of the exported logs
+    #[serde(default)]
+    pub(crate) level: LogLevel,
+}
+
+fn default_endpoint() -> Url {
+    Url::parse("http://localhost:4318/v1/logs").expect("valid url")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+/// Level of the logs
+pub(crate) enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Default for LogLevel {
+    fn default() -> Self {
+        LogLevel::Info
+    }
+}
+
+impl From<LogLevel> for Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => Level::ERROR,
+            LogLevel::Warn => Level::WARN,
+            LogLevel::Info => Level::INFO,
+            LogLevel::Debug => Level::DEBUG,
+            LogLevel::Trace => Level::TRACE,
+        }
+    }
+}
+
+/// A tracing event, as exported.
+#[derive(Debug, Clone)]
+pub(crate) struct LogRecord {
+    time: SystemTime,
+    level: Level,
+    target: String,
+    body: String,
+    attributes: Vec<(String, String)>,
+    trace_id: Option<TraceId>,
+    span_id: Option<SpanId>,
+}
+
+impl LogRecord {
+    fn to_json(&self) -> Value {
+        let mut attributes = vec![json!({
+            "key": "target",
+            "value": { "stringValue": self.target }
+        })];
+        attributes.extend(self.attributes.iter().map(|(key, value)| {
+            json!({
+                "key": key,
+                "value": { "stringValue": value }
+            })
+        }));
+        let mut record = json!({
+            "timeUnixNano": self
+                .time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos()
+                .to_string(),
+            "severityNumber": severity_number(&self.level),
+            "severityText": self.level.as_str(),
+            "body": { "stringValue": self.body },
+            "attributes": attributes,
+        });
+        // OTLP/JSON encodes the ids as hexadecimal strings
+        if let Some(trace_id) = self.trace_id {
+            record["traceId"] = format!("{:032x}", trace_id).into();
+        }
+        if let Some(span_id) = self.span_id {
+            record["spanId"] = format!("{:016x}", span_id).into();
+        }
+        record
+    }
+}
+
+fn severity_number(level: &Level) -> u8 {
+    match *level {
+        Level::TRACE => 1,
+        Level::DEBUG => 5,
+        Level::INFO => 9,
+        Level::WARN => 13,
+        Level::ERROR => 17,
+    }
+}
+
+/// The body of an export request.
+fn export_request(resource: &[(String, String)], records: &[LogRecord]) -> Value {
+    json!({
+        "resourceLogs": [{
+            "resource": {
+                "attributes": resource
+                    .iter()
+                    .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+                    .collect::<Vec<_>>(),
+            },
+            "scopeLogs": [{
+                "scope": { "name": "apollo-router", "version": env!("CARGO_PKG_VERSION") },
+                "logRecords": records.iter().map(LogRecord::to_json).collect::<Vec<_>>(),
+            }],
+        }],
+    })
+}
+
+/// Sender of the records to the export task of a pipeline.
+///
+/// The task stops once the records waiting to be exported are sent, when the exporter is dropped
+/// with its pipeline.
+#[derive(Debug)]
+pub(crate) struct LogsExporter {
+    level: Level,
+    sender: mpsc::Sender<LogRecord>,
+}
+
+impl LogsExporter {
+    pub(crate) fn spawn(
+        config: &OtlpConfig,
+        resource: Vec<(String, String)>,
+    ) -> Result<Self, BoxError> {
+        let client = reqwest::Client::builder().timeout(EXPORT_TIMEOUT).build()?;
+        let mut headers = http::HeaderMap::new();
+        for (name, value) in &config.headers {
+            headers.insert(
+                http::header::HeaderName::try_from(name.as_str())?,
+                http::HeaderValue::try_from(value.as_str())?,
+            );
+        }
+        let endpoint = config.endpoint.clone();
+        let (sender, mut receiver) = mpsc::channel::<LogRecord>(MAX_QUEUE_SIZE);
+        // the export task runs without subscriber, but its errors are still logged
+        let dispatch = tracing::dispatcher::get_default(Dispatch::clone);
+
+        let export = move |records: Vec<LogRecord>| {
+            let dispatch = dispatch.clone();
+            let request = client
+                .post(endpoint.clone())
+                .headers(headers.clone())
+                .header(CONTENT_TYPE, "application/json")
+                .json(&export_request(&resource, &records));
+            async move {
+                let result = match request.send().await {
+                    Ok(response) => response.error_for_status().map(|_| ()),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    // the events of this module are not exported, see `LogsLayer::on_event`
+                    tracing::dispatcher::with_default(&dispatch, || {
+                        tracing::warn!("could not export {} log records: {}", records.len(), e)
+                    });
+                }
+            }
+        };
+
+        tokio::spawn(
+            async move {
+                let mut interval = tokio::time::interval(SCHEDULED_DELAY);
+                let mut batch = Vec::new();
+                loop {
+                    tokio::select! {
+                        record = receiver.recv() => match record {
+                            Some(record) => {
+                                batch.push(record);
+                                if batch.len() >= MAX_BATCH_SIZE {
+                                    export(std::mem::take(&mut batch)).await;
+                                }
+                            }
+                            None => break,
+                        },
+                        _ = interval.tick() => {
+                            if !batch.is_empty() {
+                                export(std::mem::take(&mut batch)).await;
+                            }
+                        }
+                    }
+                }
+                if !batch.is_empty() {
+                    export(batch).await;
+                }
+            }
+            // the events of the HTTP client must not be exported in turn
+            .with_subscriber(Dispatch::none()),
+        );
+
+        Ok(LogsExporter {
+            level: config.level.into(),
+            sender,
+        })
+    }
+}
+
+/// Handle of the layer of the router subscriber, to change the exporter it uses.
+///
+/// The layer only holds a weak reference to the exporter of the active pipeline, which owns it.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LogsHandle(Arc<RwLock<Weak<LogsExporter>>>);
+
+impl LogsHandle {
+    /// Export the events with the exporter of the pipeline being activated, or stop exporting them.
+    /// Returns the exporter of the previous pipeline, to restore it if the activation is rolled back.
+    pub(crate) fn activate(&self, exporter: Option<&Arc<LogsExporter>>) -> Weak<LogsExporter> {
+        std::mem::replace(
+            &mut *self.0.write().expect("lock poisoned"),
+            exporter.map(Arc::downgrade).unwrap_or_default(),
+        )
+    }
+
+    /// Export the events with the exporter of the previous pipeline again.
+    pub(crate) fn restore(&self, exporter: Weak<LogsExporter>) {
+        *self.0.write().expect("lock poisoned") = exporter;
+    }
+}
+
+/// Layer of the router subscriber exporting the events with the exporter of the active pipeline.
+pub(crate) struct LogsLayer {
+    handle: LogsHandle,
+    scrubbing: ScrubbingHandle,
+}
+
+impl LogsLayer {
+    pub(crate) fn new(handle: LogsHandle, scrubbing: ScrubbingHandle) -> Self {
+        LogsLayer { handle, scrubbing }
+    }
+}
+
+impl<S> Layer<S> for LogsLayer
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        // the export errors would be exported in turn
+        if event.metadata().target() == module_path!() {
+            return;
+        }
+        let exporter = match self.handle.0.read().expect("lock poisoned").upgrade() {
+            Some(exporter) if *event.metadata().level() <= exporter.level => exporter,
+            _ => return,
+        };
+
+        let mut visitor = RecordVisitor::default();
+        event.record(&mut visitor);
+
+        let (trace_id, span_id) = match ctx.event_span(event) {
+            Some(span) => match span.extensions().get::<OtelData>() {
+                Some(data) => (
+                    data.builder.trace_id.or_else(|| {
+                        let parent = data.parent_cx.span();
+                        let span_context = parent.span_context();
+                        span_context.is_valid().then(|| span_context.trace_id())
+                    }),
+                    data.builder.span_id,
+                ),
+                None => (None, None),
+            },
+            None => (None, None),
+        };
+
+        if let Some(scrubber) = self.scrubbing.current() {
+            visitor.message = scrubber.scrub(&visitor.message).into_owned();
+            for (_, value) in &mut visitor.attributes {
+                *value = scrubber.scrub(value).into_owned();
+            }
+        }
+
+        let record = LogRecord {
+            time: SystemTime::now(),
+            level: *event.metadata().level(),
+            target: event.metadata().target().to_string(),
+            body: visitor.message,
+            attributes: visitor.attributes,
+            trace_id,
+            span_id,
+        };
+        // the record is dropped when the queue is full
+        let _ = exporter.sender.try_send(record);
+    }
+}
+
+#[derive(Default)]
+struct RecordVisitor {
+    message: String,
+    attributes: Vec<(String, String)>,
+}
+
+impl Visit for RecordVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.attributes
+                .push((field.name().to_string(), value.to_string()));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        } else {
+            self.attributes
+                .push((field.name().to_string(), format!("{:?}", value)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::trace::TracerProvider;
+    use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
+
+    use super::*;
+
+    #[test]
+    fn it_exports_the_events_with_their_span() {
+        let (sender, mut receiver) = mpsc::channel(10);
+        let exporter = Arc::new(LogsExporter {
+            level: Level::INFO,
+            sender,
+        });
+        let handle = LogsHandle::default();
+        handle.activate(Some(&exporter));
+        let layer = LogsLayer::new(handle.clone(), ScrubbingHandle::default());
+        let tracer = opentelemetry::sdk::trace::TracerProvider::builder()
+            .build()
+            .tracer("test");
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer))
+            .with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!(subgraph = "products", "could not reach the subgraph");
+            let span = tracing::info_span!("request");
+            let _guard = span.enter();
+            tracing::error!("request failed");
+            tracing::debug!("not exported");
+            handle.activate(None);
+            tracing::error!("not exported either");
+        });
+
+        let record = receiver.try_recv().unwrap();
+        assert_eq!(record.level, Level::WARN);
+        assert_eq!(record.body, "could not reach the subgraph");
+        assert_eq!(
+            record.attributes,
+            vec![("subgraph".to_string(), "products".to_string())]
+        );
+        assert!(record.trace_id.is_none());
+
+        let record = receiver.try_recv().unwrap();
+        assert_eq!(record.body, "request failed");
+        assert!(record.trace_id.is_some());
+        assert!(record.span_id.is_some());
+        let json = record.to_json();
+        assert_eq!(json["severityNumber"], 17);
+        assert_eq!(json["severityText"], "ERROR");
+        assert_eq!(json["traceId"].as_str().unwrap().len(), 32);
+        assert_eq!(json["spanId"].as_str().unwrap().len(), 16);
+
+        assert!(receiver.try_recv().is_err());
+    }
+}
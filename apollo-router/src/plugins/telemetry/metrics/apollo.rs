@@ -1,10 +1,15 @@
 //! Apollo metrics
 // With regards to ELv2 licensing, this entire file is license key functionality
 use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicI64;
+use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::time::Duration;
+use std::time::Instant;
 
-use apollo_spaceport::ReportHeader;
+use apollo_spaceport::server::ReportSpaceport;
+use apollo_spaceport::Message;
 use apollo_spaceport::Reporter;
 use apollo_spaceport::ReporterError;
 use async_trait::async_trait;
@@ -13,6 +18,7 @@ use deadpool::managed::Pool;
 use deadpool::Runtime;
 use futures::channel::mpsc;
 use futures::stream::StreamExt;
+use opentelemetry::metrics::ObserverResult;
 use studio::Report;
 use studio::SingleReport;
 use sys_info::hostname;
@@ -20,35 +26,148 @@ use tower::BoxError;
 use url::Url;
 
 use crate::plugins::telemetry::apollo::Config;
+use crate::plugins::telemetry::config::now;
 use crate::plugins::telemetry::config::MetricsCommon;
+use crate::plugins::telemetry::disk_buffer::DiskBuffer;
+use crate::plugins::telemetry::metrics::AggregateMeterProvider;
 use crate::plugins::telemetry::metrics::MetricsBuilder;
 use crate::plugins::telemetry::metrics::MetricsConfigurator;
 
 mod duration_histogram;
 pub(crate) mod studio;
+pub(crate) mod trace;
 
 const DEFAULT_QUEUE_SIZE: usize = 65_536;
+/// Interval between the attempts to send the buffered reports.
+const BUFFER_SEND_INTERVAL: Duration = Duration::from_secs(10);
+const BUFFER_SEND_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Clone)]
 pub(crate) enum Sender {
     Noop,
-    Spaceport(mpsc::Sender<SingleReport>),
+    Spaceport(mpsc::Sender<SingleReport>, Arc<ReporterStats>),
 }
 
 impl Sender {
     pub(crate) fn send(&self, metrics: SingleReport) {
         match &self {
             Sender::Noop => {}
-            Sender::Spaceport(channel) => {
+            Sender::Spaceport(channel, stats) => {
                 if let Err(err) = channel.to_owned().try_send(metrics) {
+                    stats.dropped.fetch_add(1, Ordering::Relaxed);
                     tracing::warn!(
                         "could not send metrics to spaceport, metric will be dropped: {}",
                         err
                     );
+                } else {
+                    stats.queued.fetch_add(1, Ordering::Relaxed);
                 }
             }
         }
     }
+
+    /// Expose the health of the reporting pipeline with the router metrics.
+    pub(crate) fn register_metrics(&self, meter_provider: &AggregateMeterProvider) {
+        if let Sender::Spaceport(_, stats) = self {
+            stats.register(meter_provider);
+        }
+    }
+}
+
+/// Health of the pipeline sending the reports to Spaceport.
+#[derive(Debug, Default)]
+pub(crate) struct ReporterStats {
+    /// Reports waiting to be aggregated and sent.
+    queued: AtomicI64,
+    /// Reports dropped because the queue was full or closed.
+    dropped: AtomicU64,
+    /// Reports that could not be submitted to Spaceport.
+    export_errors: AtomicU64,
+    /// Reports kept on disk until Studio is reachable again.
+    buffered: AtomicU64,
+    /// Connections to Spaceport, updated whenever a report is sent.
+    pool_size: AtomicU64,
+    /// Idle connections to Spaceport, negative when reports are waiting for a connection.
+    pool_available: AtomicI64,
+    pool_wait_micros: AtomicU64,
+}
+
+impl ReporterStats {
+    fn register(self: &Arc<Self>, meter_provider: &AggregateMeterProvider) {
+        let stats = self.clone();
+        meter_provider
+            .meter("apollo/router", None)
+            .register_observers(move |meter| {
+                let observe = |load: fn(&ReporterStats) -> i64| {
+                    let stats = stats.clone();
+                    move |result: ObserverResult<i64>| result.observe(load(&stats), &[])
+                };
+                let observe_total = |load: fn(&ReporterStats) -> u64| {
+                    let stats = stats.clone();
+                    move |result: ObserverResult<u64>| result.observe(load(&stats), &[])
+                };
+                meter
+                    .i64_value_observer(
+                        "apollo_router_studio_reports_queued",
+                        observe(|stats| stats.queued.load(Ordering::Relaxed)),
+                    )
+                    .with_description("Reports waiting to be sent to Apollo Studio.")
+                    .init();
+                meter
+                    .u64_sum_observer(
+                        "apollo_router_studio_reports_dropped_total",
+                        observe_total(|stats| stats.dropped.load(Ordering::Relaxed)),
+                    )
+                    .with_description(
+                        "Reports to Apollo Studio dropped because the queue was full or closed.",
+                    )
+                    .init();
+                meter
+                    .u64_sum_observer(
+                        "apollo_router_studio_export_errors_total",
+                        observe_total(|stats| stats.export_errors.load(Ordering::Relaxed)),
+                    )
+                    .with_description("Reports that could not be submitted to Spaceport.")
+                    .init();
+                meter
+                    .i64_value_observer(
+                        "apollo_router_studio_reports_buffered",
+                        observe(|stats| stats.buffered.load(Ordering::Relaxed) as i64),
+                    )
+                    .with_description("Reports kept on disk until Apollo Studio is reachable.")
+                    .init();
+                meter
+                    .i64_value_observer(
+                        "apollo_router_studio_pool_size",
+                        observe(|stats| stats.pool_size.load(Ordering::Relaxed) as i64),
+                    )
+                    .with_description("Connections to Spaceport.")
+                    .init();
+                meter
+                    .i64_value_observer(
+                        "apollo_router_studio_pool_available",
+                        observe(|stats| stats.pool_available.load(Ordering::Relaxed)),
+                    )
+                    .with_description(
+                        "Idle connections to Spaceport, negative when reports wait for a connection.",
+                    )
+                    .init();
+                let stats = stats.clone();
+                meter
+                    .f64_sum_observer(
+                        "apollo_router_studio_pool_wait_seconds_total",
+                        move |result| {
+                            result.observe(
+                                stats.pool_wait_micros.load(Ordering::Relaxed) as f64
+                                    / 1_000_000.0,
+                                &[],
+                            )
+                        },
+                    )
+                    .with_description("Time spent waiting for a connection to Spaceport.")
+                    .init();
+            });
+    }
 }
 
 impl Default for Sender {
@@ -71,12 +190,37 @@ impl MetricsConfigurator for Config {
                 apollo_key: Some(key),
                 apollo_graph_ref: Some(reference),
                 schema_id,
+                disk_buffer,
+                embedded_spaceport,
+                deterministic,
                 ..
             } => {
                 if !ENABLED.swap(true, Ordering::Relaxed) {
                     tracing::info!("Apollo Studio usage reporting is enabled. See https://go.apollo.dev/o/data for details");
                 }
-                let exporter = ApolloMetricsExporter::new(endpoint, key, reference, schema_id)?;
+                let buffer = match disk_buffer {
+                    Some(config) => Some(ReportBuffer {
+                        buffer: DiskBuffer::new(config),
+                        client: if *embedded_spaceport {
+                            Some(
+                                reqwest::Client::builder()
+                                    .timeout(BUFFER_SEND_TIMEOUT)
+                                    .build()?,
+                            )
+                        } else {
+                            None
+                        },
+                    }),
+                    None => None,
+                };
+                let exporter = ApolloMetricsExporter::new(
+                    endpoint,
+                    key,
+                    reference,
+                    schema_id,
+                    buffer,
+                    *deterministic,
+                )?;
 
                 builder
                     .with_apollo_metrics_collector(exporter.provider())
@@ -115,6 +259,16 @@ fn get_uname() -> Result<String, std::io::Error> {
 
 struct ApolloMetricsExporter {
     tx: mpsc::Sender<SingleReport>,
+    stats: Arc<ReporterStats>,
+}
+
+/// Reports kept on disk until they can be sent.
+#[derive(Clone)]
+struct ReportBuffer {
+    buffer: DiskBuffer,
+    /// Client sending the buffered reports to Studio when Spaceport is embedded, since it only
+    /// finds out that Studio is unreachable after accepting a report.
+    client: Option<reqwest::Client>,
 }
 
 impl ApolloMetricsExporter {
@@ -123,6 +277,8 @@ impl ApolloMetricsExporter {
         apollo_key: &str,
         apollo_graph_ref: &str,
         schema_id: &str,
+        buffer: Option<ReportBuffer>,
+        deterministic: bool,
     ) -> Result<ApolloMetricsExporter, BoxError> {
         let apollo_key = apollo_key.to_string();
         // Desired behavior:
@@ -130,6 +286,8 @@ impl ApolloMetricsExporter {
         // * If we cannot connect to spaceport metrics are discarded and a warning raised.
         // * When the stream of metrics finishes we terminate the thread.
         // * If the exporter is dropped the remaining records are flushed.
+        // * With a disk buffer, the reports that cannot be sent are written to disk, and sent by
+        //   another thread once spaceport is reachable again.
         let (tx, mut rx) = mpsc::channel::<SingleReport>(DEFAULT_QUEUE_SIZE);
 
         let header = apollo_spaceport::ReportHeader {
@@ -157,8 +315,23 @@ impl ApolloMetricsExporter {
         .build()
         .unwrap();
 
+        let stats: Arc<ReporterStats> = Default::default();
+        let task_stats = stats.clone();
+
+        // This is the thread that sends the buffered reports
+        let buffer_task = buffer.clone().map(|buffer| {
+            tokio::spawn(Self::send_buffered(
+                pool.clone(),
+                stats.clone(),
+                apollo_key.clone(),
+                buffer,
+            ))
+        });
+        let buffer = buffer.map(|buffer| buffer.buffer);
+
         // This is the thread that actually sends metrics
         tokio::spawn(async move {
+            let stats = task_stats;
             let timeout = tokio::time::interval(Duration::from_secs(5));
             let mut report = Report::default();
             tokio::pin!(timeout);
@@ -167,39 +340,145 @@ impl ApolloMetricsExporter {
                 tokio::select! {
                     single_report = rx.next() => {
                         if let Some(r) = single_report {
+                            stats.queued.fetch_sub(1, Ordering::Relaxed);
                             report += r;
                         } else {
                             break;
                         }
                        },
                     _ = timeout.tick() => {
-                        Self::send_report(&pool, &apollo_key, &header, std::mem::take(&mut report)).await;
+                        let report = std::mem::take(&mut report).into_report(header.clone(), now(deterministic));
+                        Self::send_report(&pool, &stats, &apollo_key, buffer.as_ref(), report).await;
                     }
                 };
             }
 
-            Self::send_report(&pool, &apollo_key, &header, report).await;
+            let report = report.into_report(header, now(deterministic));
+            Self::send_report(&pool, &stats, &apollo_key, buffer.as_ref(), report).await;
+            if let Some(buffer_task) = buffer_task {
+                buffer_task.abort();
+            }
         });
-        Ok(ApolloMetricsExporter { tx })
+        Ok(ApolloMetricsExporter { tx, stats })
     }
 
     pub(crate) fn provider(&self) -> Sender {
-        Sender::Spaceport(self.tx.clone())
+        Sender::Spaceport(self.tx.clone(), self.stats.clone())
     }
 
     async fn send_report(
         pool: &Pool<ReporterManager>,
+        stats: &ReporterStats,
         apollo_key: &str,
-        header: &ReportHeader,
-        report: Report,
+        buffer: Option<&DiskBuffer>,
+        report: apollo_spaceport::Report,
     ) {
+        let status = pool.status();
+        stats.pool_size.store(status.size as u64, Ordering::Relaxed);
+        stats
+            .pool_available
+            .store(status.available as i64, Ordering::Relaxed);
         if report.operation_count == 0 {
             return;
         }
+        let encoded = buffer.map(|_| report.encode_to_vec());
+        if !Self::submit(pool, stats, apollo_key, report).await {
+            if let (Some(buffer), Some(encoded)) = (buffer, encoded) {
+                if let Err(e) = buffer.push(&encoded).await {
+                    tracing::warn!("could not write the report to the disk buffer: {}", e);
+                }
+                Self::update_buffered(stats, buffer).await;
+            }
+        }
+    }
+
+    /// Send the buffered reports, oldest first, stopping at the first one that cannot be sent
+    /// until the next attempt.
+    async fn send_buffered(
+        pool: Pool<ReporterManager>,
+        stats: Arc<ReporterStats>,
+        apollo_key: String,
+        buffer: ReportBuffer,
+    ) {
+        let mut interval = tokio::time::interval(BUFFER_SEND_INTERVAL);
+        loop {
+            interval.tick().await;
+            loop {
+                let record = match buffer.buffer.first().await {
+                    Ok(Some(record)) => record,
+                    Ok(None) => break,
+                    Err(e) => {
+                        tracing::warn!("could not read the disk buffer: {}", e);
+                        break;
+                    }
+                };
+                let report = match apollo_spaceport::Report::decode(record.payload.as_slice()) {
+                    Ok(report) => report,
+                    Err(e) => {
+                        tracing::warn!("dropping a buffered record which is not a report: {}", e);
+                        if let Err(e) = buffer.buffer.remove(record).await {
+                            tracing::warn!("could not remove a report from the disk buffer: {}", e);
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                // with embedded spaceport, the reports are sent to Studio directly since
+                // spaceport accepts them before finding out whether Studio is reachable
+                let sent = match &buffer.client {
+                    Some(client) => {
+                        match ReportSpaceport::transfer(client, &apollo_key, &report).await {
+                            Ok(_) => true,
+                            Err(e) => {
+                                stats.export_errors.fetch_add(1, Ordering::Relaxed);
+                                tracing::warn!("failed to submit a buffered report: {}", e);
+                                false
+                            }
+                        }
+                    }
+                    None => tokio::time::timeout(
+                        BUFFER_SEND_TIMEOUT,
+                        Self::submit(&pool, &stats, &apollo_key, report),
+                    )
+                    .await
+                    .unwrap_or(false),
+                };
+                let result = if sent {
+                    buffer.buffer.remove(record).await
+                } else {
+                    buffer.buffer.release(record).await
+                };
+                if let Err(e) = result {
+                    tracing::warn!("could not update the disk buffer: {}", e);
+                }
+                if !sent {
+                    break;
+                }
+            }
+            Self::update_buffered(&stats, &buffer.buffer).await;
+        }
+    }
 
-        match pool.get().await {
+    async fn update_buffered(stats: &ReporterStats, buffer: &DiskBuffer) {
+        if let Ok(len) = buffer.count().await {
+            stats.buffered.store(len as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Submit a report to spaceport, returning whether it was accepted.
+    async fn submit(
+        pool: &Pool<ReporterManager>,
+        stats: &ReporterStats,
+        apollo_key: &str,
+        report: apollo_spaceport::Report,
+    ) -> bool {
+        let start = Instant::now();
+        let reporter = pool.get().await;
+        stats
+            .pool_wait_micros
+            .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        match reporter {
             Ok(mut reporter) => {
-                let report = report.into_report(header.clone());
                 match reporter
                     .submit(apollo_spaceport::ReporterRequest {
                         apollo_key: apollo_key.to_string(),
@@ -207,19 +486,23 @@ impl ApolloMetricsExporter {
                     })
                     .await
                 {
-                    Ok(_) => {}
+                    Ok(_) => true,
                     Err(e) => {
+                        stats.export_errors.fetch_add(1, Ordering::Relaxed);
                         tracing::warn!("failed to submit stats to spaceport: {}", e);
+                        false
                     }
-                };
+                }
             }
             Err(err) => {
+                stats.export_errors.fetch_add(1, Ordering::Relaxed);
                 tracing::warn!(
                     "stats discarded as unable to get connection to spaceport: {}",
                     err
                 );
+                false
             }
-        };
+        }
     }
 }
 
@@ -269,6 +552,7 @@ mod test {
             client_name_header: HeaderName::from_static("name_header"),
             client_version_header: HeaderName::from_static("version_header"),
             schema_id: "schema_sha".to_string(),
+            ..Default::default()
         })
         .await?;
         assert!(matches!(plugin.apollo_metrics_sender, Sender::Noop));
@@ -278,10 +562,26 @@ mod test {
     #[tokio::test(flavor = "multi_thread")]
     async fn apollo_metrics_enabled() -> Result<(), BoxError> {
         let plugin = create_plugin().await?;
-        assert!(matches!(plugin.apollo_metrics_sender, Sender::Spaceport(_)));
+        assert!(matches!(
+            plugin.apollo_metrics_sender,
+            Sender::Spaceport(_, _)
+        ));
         Ok(())
     }
 
+    #[test]
+    fn apollo_metrics_pipeline_health() {
+        let (tx, rx) = futures::channel::mpsc::channel(100);
+        let stats: Arc<ReporterStats> = Default::default();
+        let sender = Sender::Spaceport(tx, stats.clone());
+        sender.send(SingleReport::default());
+        assert_eq!(stats.queued.load(Ordering::Relaxed), 1);
+
+        drop(rx);
+        sender.send(SingleReport::default());
+        assert_eq!(stats.dropped.load(Ordering::Relaxed), 1);
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn apollo_metrics_single_operation() -> Result<(), BoxError> {
         let query = "query {topProducts{name}}";
@@ -355,7 +655,7 @@ mod test {
         let mut plugin = create_plugin().await?;
         // Replace the apollo metrics sender so we can test metrics collection.
         let (tx, rx) = futures::channel::mpsc::channel(100);
-        plugin.apollo_metrics_sender = Sender::Spaceport(tx);
+        plugin.apollo_metrics_sender = Sender::Spaceport(tx, Default::default());
         TestHarness::builder()
             .extra_plugin(plugin)
             .build()
@@ -375,17 +675,7 @@ mod test {
             .await
             .unwrap();
 
-        let results = rx
-            .collect::<Vec<_>>()
-            .await
-            .into_iter()
-            .map(|mut m| {
-                m.traces_and_stats.iter_mut().for_each(|(_k, v)| {
-                    v.stats_with_context.query_latency_stats.latency = Duration::from_millis(100)
-                });
-                m
-            })
-            .collect();
+        let results = rx.collect::<Vec<_>>().await;
         Ok(results)
     }
 
@@ -397,6 +687,7 @@ mod test {
             client_name_header: HeaderName::from_static("name_header"),
             client_version_header: HeaderName::from_static("version_header"),
             schema_id: "schema_sha".to_string(),
+            ..Default::default()
         })
     }
 
@@ -407,7 +698,10 @@ mod test {
             config::Conf {
                 metrics: None,
                 tracing: None,
+                logs: None,
                 apollo: Some(apollo_config),
+                scrubbing: None,
+                experimental_deterministic: true,
             },
             Default::default(),
         ))
@@ -6,6 +6,7 @@ use std::time::SystemTime;
 use apollo_spaceport::ReferencedFieldsForType;
 use apollo_spaceport::ReportHeader;
 use apollo_spaceport::StatsContext;
+use apollo_spaceport::Trace;
 use itertools::Itertools;
 use serde::Serialize;
 
@@ -21,10 +22,14 @@ impl Report {
         aggregated_report
     }
 
-    pub(crate) fn into_report(self, header: ReportHeader) -> apollo_spaceport::Report {
+    pub(crate) fn into_report(
+        self,
+        header: ReportHeader,
+        end_time: SystemTime,
+    ) -> apollo_spaceport::Report {
         let mut report = apollo_spaceport::Report {
             header: Some(header),
-            end_time: Some(SystemTime::now().into()),
+            end_time: Some(end_time.into()),
             operation_count: self.operation_count,
             ..Default::default()
         };
@@ -46,6 +51,9 @@ pub(crate) struct SingleReport {
 pub(crate) struct SingleTracesAndStats {
     pub(crate) stats_with_context: SingleContextualizedStats,
     pub(crate) referenced_fields_by_type: HashMap<String, ReferencedFieldsForType>,
+    /// The trace of the operation, sent instead of its statistics.
+    #[serde(skip)]
+    pub(crate) trace: Option<Trace>,
 }
 
 #[derive(Default, Debug, Serialize)]
@@ -113,14 +121,22 @@ pub(crate) struct TracesAndStats {
     #[serde(with = "vectorize")]
     pub(crate) stats_with_context: HashMap<StatsContext, ContextualizedStats>,
     pub(crate) referenced_fields_by_type: HashMap<String, ReferencedFieldsForType>,
+    #[serde(skip)]
+    pub(crate) traces: Vec<Trace>,
 }
 
 impl AddAssign<SingleTracesAndStats> for TracesAndStats {
     fn add_assign(&mut self, stats: SingleTracesAndStats) {
-        *self
-            .stats_with_context
-            .entry(stats.stats_with_context.context.clone())
-            .or_default() += stats.stats_with_context;
+        // Studio aggregates the traces into the statistics itself
+        match stats.trace {
+            Some(trace) => self.traces.push(trace),
+            None => {
+                *self
+                    .stats_with_context
+                    .entry(stats.stats_with_context.context.clone())
+                    .or_default() += stats.stats_with_context
+            }
+        }
 
         // No merging required here because references fields by type will always be the same for each stats report key.
         self.referenced_fields_by_type = stats.referenced_fields_by_type;
@@ -249,6 +265,7 @@ impl From<TracesAndStats> for apollo_spaceport::TracesAndStats {
         Self {
             stats_with_context: stats.stats_with_context.into_values().map_into().collect(),
             referenced_fields_by_type: stats.referenced_fields_by_type,
+            trace: stats.traces,
             ..Default::default()
         }
     }
@@ -373,6 +390,35 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_traces_are_not_aggregated() {
+        let mut traced = create_test_metric("client_1", "version_1", "report_key_1");
+        traced
+            .traces_and_stats
+            .get_mut("report_key_1")
+            .unwrap()
+            .trace = Some(Trace::default());
+        let metric = create_test_metric("client_1", "version_1", "report_key_1");
+        let aggregated_metrics = Report::new(vec![traced, metric]);
+        assert_eq!(aggregated_metrics.operation_count, 2);
+
+        let traces_and_stats: apollo_spaceport::TracesAndStats = aggregated_metrics
+            .traces_per_query
+            .into_values()
+            .next()
+            .unwrap()
+            .into();
+        assert_eq!(traces_and_stats.trace.len(), 1);
+        assert_eq!(
+            traces_and_stats.stats_with_context[0]
+                .query_latency_stats
+                .as_ref()
+                .unwrap()
+                .request_count,
+            1
+        );
+    }
+
     fn create_test_metric(
         client_name: &str,
         client_version: &str,
@@ -452,6 +498,7 @@ mod test {
                             is_interface: false,
                         },
                     )]),
+                    trace: None,
                 },
             )]),
         }
@@ -0,0 +1,384 @@
+//! Traces of the operations sent to Studio.
+// With regards to ELv2 licensing, this entire file is license key functionality
+use std::collections::HashMap;
+use std::time::Duration;
+
+use apollo_spaceport::trace::http::Method;
+use apollo_spaceport::trace::http::Values;
+use apollo_spaceport::trace::Details;
+use apollo_spaceport::trace::Error as TraceError;
+use apollo_spaceport::trace::Http;
+use apollo_spaceport::trace::Location as TraceLocation;
+use apollo_spaceport::trace::Node;
+use apollo_spaceport::Trace;
+use rand::Rng;
+
+use crate::graphql::Error;
+use crate::json_ext::Object;
+use crate::plugins::telemetry::apollo::Config;
+use crate::plugins::telemetry::apollo::ForwardHeaders;
+use crate::plugins::telemetry::apollo::ForwardValues;
+use crate::plugins::telemetry::config::now;
+use crate::plugins::telemetry::scrubbing::Scrubber;
+use crate::SupergraphRequest;
+
+/// Headers that are never sent to Studio, as they contain credentials.
+const REDACTED_HEADERS: [&str; 3] = ["authorization", "cookie", "set-cookie"];
+
+/// The parts of a request that are kept for its trace, redacted as configured.
+#[derive(Debug, Clone)]
+pub(crate) struct TraceRequest {
+    start_time: std::time::SystemTime,
+    method: Method,
+    headers: HashMap<String, Values>,
+    details: Details,
+    /// Errors of the responses, with their messages scrubbed.
+    errors: Vec<TraceError>,
+    /// Number of operations this trace stands for, the inverse of its sampling rate.
+    field_execution_weight: f64,
+}
+
+impl TraceRequest {
+    /// The request of the trace, if the operation is sampled.
+    pub(crate) fn sample(
+        config: &Config,
+        request: &SupergraphRequest,
+        scrubber: Option<&Scrubber>,
+    ) -> Option<Self> {
+        let operation_name = request.originating_request.body().operation_name.as_deref();
+        let rate = config.sampling_rate_of(operation_name);
+        if rate <= 0.0 || rand::thread_rng().gen::<f64>() >= rate {
+            return None;
+        }
+        let mut trace_request = Self::new(config, request, scrubber);
+        trace_request.field_execution_weight = 1.0 / rate;
+        Some(trace_request)
+    }
+
+    fn new(config: &Config, request: &SupergraphRequest, scrubber: Option<&Scrubber>) -> Self {
+        let http_request = &request.originating_request;
+
+        let mut headers: HashMap<String, Values> = HashMap::new();
+        for (name, value) in http_request.headers() {
+            let name = name.as_str();
+            let forwarded = match &config.send_headers {
+                ForwardHeaders::None => false,
+                ForwardHeaders::All => true,
+                ForwardHeaders::Only(names) => names.iter().any(|n| n.eq_ignore_ascii_case(name)),
+                ForwardHeaders::Except(names) => {
+                    !names.iter().any(|n| n.eq_ignore_ascii_case(name))
+                }
+            };
+            if forwarded && !REDACTED_HEADERS.contains(&name) {
+                let value = value.to_str().unwrap_or_default();
+                headers
+                    .entry(name.to_string())
+                    .or_default()
+                    .value
+                    .push(scrub(scrubber, value));
+            }
+        }
+
+        let mut variables: Object = http_request.body().variables.clone();
+        if let Some(scrubber) = scrubber {
+            scrubber.scrub_variables(&mut variables);
+        }
+
+        // variables that are not sent are still listed, with an empty value
+        let variables_json = match &config.send_variable_values {
+            ForwardValues::None => HashMap::new(),
+            ForwardValues::All | ForwardValues::Only(_) => variables
+                .iter()
+                .map(|(name, value)| {
+                    let name = name.as_str().to_string();
+                    let sent = match &config.send_variable_values {
+                        ForwardValues::Only(names) => names.contains(&name),
+                        _ => true,
+                    };
+                    let value = if sent {
+                        serde_json::to_string(value).unwrap_or_default()
+                    } else {
+                        String::new()
+                    };
+                    (name, value)
+                })
+                .collect(),
+        };
+
+        TraceRequest {
+            start_time: now(config.deterministic),
+            method: method(http_request.method()),
+            headers,
+            details: Details {
+                variables_json,
+                operation_name: scrub(
+                    scrubber,
+                    http_request
+                        .body()
+                        .operation_name
+                        .as_deref()
+                        .unwrap_or_default(),
+                ),
+            },
+            errors: Vec::new(),
+            field_execution_weight: 1.0,
+        }
+    }
+
+    /// Keep the errors of a response part, scrubbed as they may contain personal data.
+    pub(crate) fn record_errors(&mut self, errors: &[Error], scrubber: Option<&Scrubber>) {
+        self.errors.extend(errors.iter().map(|error| {
+            TraceError {
+                message: scrub(scrubber, &error.message),
+                location: error
+                    .locations
+                    .iter()
+                    .map(|location| TraceLocation {
+                        line: location.line.max(0) as u32,
+                        column: location.column.max(0) as u32,
+                    })
+                    .collect(),
+                ..Default::default()
+            }
+        }));
+    }
+
+    /// The trace of the operation, once it completed.
+    pub(crate) fn into_trace(
+        self,
+        duration: Duration,
+        client_name: String,
+        client_version: String,
+        persisted_query_hit: bool,
+    ) -> Trace {
+        Trace {
+            start_time: Some(self.start_time.into()),
+            end_time: Some((self.start_time + duration).into()),
+            duration_ns: duration.as_nanos() as u64,
+            root: Some(Node {
+                error: self.errors,
+                ..Default::default()
+            }),
+            details: Some(self.details),
+            client_name,
+            client_version,
+            http: Some(Http {
+                method: self.method.into(),
+                request_headers: self.headers,
+                ..Default::default()
+            }),
+            persisted_query_hit,
+            field_execution_weight: self.field_execution_weight,
+            ..Default::default()
+        }
+    }
+}
+
+fn scrub(scrubber: Option<&Scrubber>, text: &str) -> String {
+    match scrubber {
+        Some(scrubber) => scrubber.scrub(text).into_owned(),
+        None => text.to_string(),
+    }
+}
+
+fn method(method: &http::Method) -> Method {
+    match *method {
+        http::Method::OPTIONS => Method::Options,
+        http::Method::GET => Method::Get,
+        http::Method::HEAD => Method::Head,
+        http::Method::POST => Method::Post,
+        http::Method::PUT => Method::Put,
+        http::Method::DELETE => Method::Delete,
+        http::Method::TRACE => Method::Trace,
+        http::Method::CONNECT => Method::Connect,
+        http::Method::PATCH => Method::Patch,
+        _ => Method::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+
+    use super::*;
+
+    fn trace_with(mut config: serde_json::Value) -> Trace {
+        config["schema_id"] = "abc".into();
+        let config: Config = serde_json::from_value(config).unwrap();
+        let request = SupergraphRequest::fake_builder()
+            .query("query Me($id: ID, $password: String) { me(id: $id) { name } }")
+            .operation_name("Me")
+            .variable("id", json!("1"))
+            .variable("password", json!("secret"))
+            .header("x-client", "web")
+            .header("x-secret", "secret")
+            .header("authorization", "Bearer secret")
+            .method(http::Method::POST)
+            .build()
+            .unwrap();
+        TraceRequest::new(&config, &request, None).into_trace(
+            Duration::from_millis(10),
+            "client".to_string(),
+            "1.0".to_string(),
+            false,
+        )
+    }
+
+    #[test]
+    fn it_samples_the_traces() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "schema_id": "abc",
+            "send_traces": true,
+            "trace_sampling_rate": 0.0,
+            "trace_sampling_overrides": { "Checkout": 1.0, "Me": 0.5 }
+        }))
+        .unwrap();
+        let request = |operation_name: &str| {
+            SupergraphRequest::fake_builder()
+                .query("query Me { me { name } }")
+                .operation_name(operation_name)
+                .build()
+                .unwrap()
+        };
+
+        assert!(TraceRequest::sample(&config, &request("Other"), None).is_none());
+        let trace_request = TraceRequest::sample(&config, &request("Checkout"), None).unwrap();
+        assert_eq!(trace_request.field_execution_weight, 1.0);
+
+        let sampled: Vec<TraceRequest> = (0..1000)
+            .filter_map(|_| TraceRequest::sample(&config, &request("Me"), None))
+            .collect();
+        assert!(sampled.len() > 350 && sampled.len() < 650);
+        assert!(sampled
+            .into_iter()
+            .all(|trace_request| trace_request.field_execution_weight == 2.0));
+
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "schema_id": "abc",
+            "trace_sampling_overrides": { "Me": 2.0 }
+        }))
+        .unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn it_normalizes_the_traces_in_deterministic_mode() {
+        let mut config: Config = serde_json::from_value(
+            serde_json::json!({ "schema_id": "abc", "send_headers": "all" }),
+        )
+        .unwrap();
+        config.deterministic = true;
+        let request = SupergraphRequest::fake_builder()
+            .query("query Me { me { name } }")
+            .header("x-client", "web")
+            .build()
+            .unwrap();
+        let trace = || {
+            TraceRequest::new(&config, &request, None).into_trace(
+                Duration::from_millis(100),
+                "client".to_string(),
+                "1.0".to_string(),
+                false,
+            )
+        };
+
+        let first = trace();
+        std::thread::sleep(Duration::from_millis(5));
+        let second = trace();
+        assert_eq!(first, second);
+        assert_eq!(
+            first.start_time,
+            Some(std::time::SystemTime::UNIX_EPOCH.into())
+        );
+    }
+
+    fn header_names(trace: &Trace) -> Vec<&str> {
+        let mut names: Vec<&str> = trace
+            .http
+            .as_ref()
+            .unwrap()
+            .request_headers
+            .keys()
+            .map(String::as_str)
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    #[test]
+    fn it_sends_no_variables_and_headers_by_default() {
+        let trace = trace_with(serde_json::json!({}));
+        let details = trace.details.as_ref().unwrap();
+        assert_eq!(details.operation_name, "Me");
+        assert!(details.variables_json.is_empty());
+        assert!(header_names(&trace).is_empty());
+        assert_eq!(trace.duration_ns, 10_000_000);
+        assert_eq!(trace.http.as_ref().unwrap().method, Method::Post as i32);
+    }
+
+    #[test]
+    fn it_redacts_the_variables_that_are_not_allowed() {
+        let trace = trace_with(serde_json::json!({ "send_variable_values": { "only": ["id"] } }));
+        let variables = &trace.details.as_ref().unwrap().variables_json;
+        assert_eq!(variables["id"], "\"1\"");
+        assert_eq!(variables["password"], "");
+
+        let trace = trace_with(serde_json::json!({ "send_variable_values": "all" }));
+        let variables = &trace.details.as_ref().unwrap().variables_json;
+        assert_eq!(variables["password"], "\"secret\"");
+    }
+
+    #[test]
+    fn it_strips_the_configured_headers() {
+        let trace = trace_with(serde_json::json!({ "send_headers": { "except": ["X-Secret"] } }));
+        assert_eq!(header_names(&trace), vec!["content-type", "x-client"]);
+        assert_eq!(
+            trace.http.as_ref().unwrap().request_headers["x-client"].value,
+            vec!["web".to_string()]
+        );
+
+        let trace = trace_with(serde_json::json!({ "send_headers": { "only": ["x-secret"] } }));
+        assert_eq!(header_names(&trace), vec!["x-secret"]);
+
+        let trace = trace_with(serde_json::json!({ "send_headers": "all" }));
+        assert!(!header_names(&trace).contains(&"authorization"));
+    }
+
+    #[test]
+    fn it_scrubs_the_errors() {
+        let config: Config =
+            serde_json::from_value(serde_json::json!({ "schema_id": "abc" })).unwrap();
+        let scrubber = Scrubber::new(&crate::plugins::telemetry::scrubbing::Config {
+            patterns: vec![r"[\w.+-]+@[\w-]+\.[\w.]+".to_string()],
+            variables: Vec::new(),
+            replacement: "[REDACTED]".to_string(),
+        })
+        .unwrap();
+        let request = SupergraphRequest::fake_builder()
+            .query("query Me { me { name } }")
+            .build()
+            .unwrap();
+        let mut trace_request = TraceRequest::new(&config, &request, Some(&scrubber));
+        trace_request.record_errors(
+            &[Error::builder()
+                .message("no user ada@example.com")
+                .locations(vec![crate::graphql::Location {
+                    line: 1,
+                    column: 12,
+                }])
+                .build()],
+            Some(&scrubber),
+        );
+        let trace = trace_request.into_trace(
+            Duration::from_millis(10),
+            "client".to_string(),
+            "1.0".to_string(),
+            false,
+        );
+
+        let errors = &trace.root.as_ref().unwrap().error;
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "no user [REDACTED]");
+        assert_eq!(errors[0].location[0].column, 12);
+    }
+}
@@ -0,0 +1,154 @@
+//! Metrics exporters registered by plugins.
+use std::collections::HashMap;
+
+use opentelemetry::sdk::Resource;
+use opentelemetry::KeyValue;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::ObjectValidation;
+use schemars::schema::Schema;
+use schemars::schema::SchemaObject;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::Map;
+use serde_json::Value;
+use tower::BoxError;
+
+use crate::plugin::metrics::metrics_exporters;
+use crate::plugin::metrics::MetricsExporterFactory;
+use crate::plugins::telemetry::config::MetricsCommon;
+use crate::plugins::telemetry::metrics::MetricsBuilder;
+use crate::plugins::telemetry::metrics::MetricsConfigurator;
+
+/// The configuration of the metrics exporters registered by plugins, by exporter name.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub(crate) struct Config {
+    pub(crate) exporters: Map<String, Value>,
+}
+
+impl JsonSchema for Config {
+    fn schema_name() -> String {
+        stringify!(CustomMetricsExporters).to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        // The schema lists the exporters that have been registered at compile time.
+        let mut exporters: Vec<_> = metrics_exporters().into_iter().collect();
+        exporters.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let properties = exporters
+            .into_iter()
+            .map(|(name, factory)| (name, factory.create_schema(gen)))
+            .collect();
+
+        Schema::Object(SchemaObject {
+            object: Some(Box::new(ObjectValidation {
+                properties,
+                additional_properties: Some(Box::new(Schema::Bool(false))),
+                ..Default::default()
+            })),
+            ..Default::default()
+        })
+    }
+}
+
+impl MetricsConfigurator for Config {
+    fn apply(
+        &self,
+        builder: MetricsBuilder,
+        metrics_config: &MetricsCommon,
+    ) -> Result<MetricsBuilder, BoxError> {
+        self.apply_with(&metrics_exporters(), builder, metrics_config)
+    }
+}
+
+impl Config {
+    fn apply_with(
+        &self,
+        factories: &HashMap<String, MetricsExporterFactory>,
+        mut builder: MetricsBuilder,
+        metrics_config: &MetricsCommon,
+    ) -> Result<MetricsBuilder, BoxError> {
+        for (name, config) in &self.exporters {
+            let factory = factories
+                .get(name)
+                .ok_or_else(|| format!("unknown metrics exporter '{}'", name))?;
+            tracing::debug!("configuring the {} metrics exporter", name);
+            let exporter = factory
+                .create_instance(
+                    config,
+                    Resource::new(
+                        metrics_config
+                            .resources
+                            .clone()
+                            .into_iter()
+                            .map(|(k, v)| KeyValue::new(k, v)),
+                    ),
+                )
+                .map_err(|e| format!("could not create the {} metrics exporter: {}", name, e))?;
+
+            if let Some(endpoint) = exporter.custom_endpoint() {
+                builder = builder.with_custom_endpoint(&format!("/{}", name), endpoint);
+            }
+            builder = builder.with_shared_meter_provider(exporter.meter_provider());
+            builder = builder.with_exporter(exporter);
+        }
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use opentelemetry::metrics::noop::NoopMeterProvider;
+    use opentelemetry::metrics::MeterProvider;
+
+    use super::*;
+    use crate::plugin::metrics::MetricsExporter;
+    use crate::plugin::metrics::MetricsExporterInit;
+
+    #[derive(Deserialize, JsonSchema)]
+    struct TestExporterConfig {
+        prefix: String,
+    }
+
+    struct TestExporter;
+
+    impl MetricsExporter for TestExporter {
+        type Config = TestExporterConfig;
+
+        fn new(init: MetricsExporterInit<Self::Config>) -> Result<Self, BoxError> {
+            if init.config.prefix.is_empty() {
+                return Err("the prefix must not be empty".into());
+            }
+            Ok(TestExporter)
+        }
+
+        fn meter_provider(&self) -> Arc<dyn MeterProvider + Send + Sync + 'static> {
+            Arc::new(NoopMeterProvider::new())
+        }
+    }
+
+    fn apply(config: serde_json::Value) -> Result<MetricsBuilder, BoxError> {
+        let factories = HashMap::from([(
+            "test.statsd".to_string(),
+            MetricsExporterFactory::new::<TestExporter>(),
+        )]);
+        let config: Config = serde_json::from_value(config).unwrap();
+        config.apply_with(
+            &factories,
+            MetricsBuilder::default(),
+            &MetricsCommon::default(),
+        )
+    }
+
+    #[test]
+    fn it_creates_the_registered_exporters() {
+        let mut builder = apply(serde_json::json!({ "test.statsd": { "prefix": "router" } }))
+            .expect("the exporter is registered");
+        assert_eq!(builder.exporters().len(), 1);
+
+        assert!(apply(serde_json::json!({ "test.statsd": { "prefix": "" } })).is_err());
+        assert!(apply(serde_json::json!({ "unknown": {} })).is_err());
+    }
+}
@@ -0,0 +1,151 @@
+//! Histograms recording the latencies of the requests.
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use tower::BoxError;
+
+/// Boundaries of the latency buckets, in milliseconds, when none are configured.
+const DEFAULT_BOUNDARIES_MILLIS: [f64; 12] = [
+    1.0, 5.0, 15.0, 50.0, 100.0, 200.0, 300.0, 400.0, 500.0, 1000.0, 5000.0, 10000.0,
+];
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+/// Configuration of the histograms recording the latencies
+pub(crate) struct HistogramConf {
+    /// Boundaries of the histogram buckets, in the unit of the latencies
+    pub(crate) buckets: Option<Buckets>,
+    /// Unit of the latencies
+    #[serde(default)]
+    pub(crate) unit: TimeUnit,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+/// Boundaries of the histogram buckets
+pub(crate) enum Buckets {
+    /// Explicit boundaries, in increasing order
+    Explicit(Vec<f64>),
+    /// Boundaries growing exponentially
+    Exponential(ExponentialBuckets),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+/// Boundaries growing exponentially, each boundary being the previous one times the factor
+pub(crate) struct ExponentialBuckets {
+    /// The first boundary
+    pub(crate) start: f64,
+    /// The factor between two consecutive boundaries
+    pub(crate) factor: f64,
+    /// The number of boundaries
+    pub(crate) count: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+/// Unit of the latencies
+pub(crate) enum TimeUnit {
+    /// Latencies in seconds
+    Seconds,
+    /// Latencies in milliseconds
+    Milliseconds,
+}
+
+impl Default for TimeUnit {
+    fn default() -> Self {
+        TimeUnit::Seconds
+    }
+}
+
+impl TimeUnit {
+    /// The name of a latency instrument, suffixed with its unit.
+    pub(crate) fn instrument_name(&self, name: &str) -> String {
+        match self {
+            TimeUnit::Seconds => format!("{}_seconds", name),
+            TimeUnit::Milliseconds => format!("{}_milliseconds", name),
+        }
+    }
+
+    pub(crate) fn value_of(&self, duration: Duration) -> f64 {
+        match self {
+            TimeUnit::Seconds => duration.as_secs_f64(),
+            TimeUnit::Milliseconds => duration.as_nanos() as f64 / 1_000_000.0,
+        }
+    }
+}
+
+impl HistogramConf {
+    /// The configured bucket boundaries, or the default ones in the configured unit.
+    pub(crate) fn boundaries(&self) -> Result<Vec<f64>, BoxError> {
+        match &self.buckets {
+            None => Ok(DEFAULT_BOUNDARIES_MILLIS
+                .iter()
+                .map(|boundary| match self.unit {
+                    TimeUnit::Seconds => boundary / 1000.0,
+                    TimeUnit::Milliseconds => *boundary,
+                })
+                .collect()),
+            Some(Buckets::Explicit(boundaries)) => {
+                if boundaries.is_empty() {
+                    return Err("histogram buckets must not be empty".into());
+                }
+                if boundaries.windows(2).any(|pair| pair[0] >= pair[1]) {
+                    return Err("histogram buckets must be in increasing order".into());
+                }
+                Ok(boundaries.clone())
+            }
+            Some(Buckets::Exponential(buckets)) => Ok(prometheus::exponential_buckets(
+                buckets.start,
+                buckets.factor,
+                buckets.count,
+            )?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boundaries(config: serde_json::Value) -> Result<Vec<f64>, BoxError> {
+        serde_json::from_value::<HistogramConf>(config)
+            .unwrap()
+            .boundaries()
+    }
+
+    #[test]
+    fn it_computes_the_bucket_boundaries() {
+        assert_eq!(
+            boundaries(serde_json::json!({})).unwrap(),
+            vec![0.001, 0.005, 0.015, 0.05, 0.1, 0.2, 0.3, 0.4, 0.5, 1.0, 5.0, 10.0]
+        );
+        assert_eq!(
+            boundaries(serde_json::json!({ "unit": "milliseconds" })).unwrap()[..3],
+            [1.0, 5.0, 15.0]
+        );
+        assert_eq!(
+            boundaries(serde_json::json!({ "buckets": { "explicit": [0.5, 1.0, 2.0] } })).unwrap(),
+            vec![0.5, 1.0, 2.0]
+        );
+        assert_eq!(
+            boundaries(serde_json::json!({
+                "buckets": { "exponential": { "start": 1.0, "factor": 2.0, "count": 4 } }
+            }))
+            .unwrap(),
+            vec![1.0, 2.0, 4.0, 8.0]
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_buckets() {
+        assert!(boundaries(serde_json::json!({ "buckets": { "explicit": [] } })).is_err());
+        assert!(boundaries(serde_json::json!({ "buckets": { "explicit": [1.0, 0.5] } })).is_err());
+        assert!(boundaries(serde_json::json!({
+            "buckets": { "exponential": { "start": 1.0, "factor": 1.0, "count": 4 } }
+        }))
+        .is_err());
+    }
+}
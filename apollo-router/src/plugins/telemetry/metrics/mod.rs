@@ -1,6 +1,8 @@
 use std::any::Any;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
 
 use ::serde::Deserialize;
 use access_json::JSONQuery;
@@ -13,6 +15,8 @@ use opentelemetry::metrics::Counter;
 use opentelemetry::metrics::Meter;
 use opentelemetry::metrics::MeterProvider;
 use opentelemetry::metrics::Number;
+use opentelemetry::metrics::ObserverResult;
+use opentelemetry::metrics::UpDownCounter;
 use opentelemetry::metrics::ValueRecorder;
 use opentelemetry::KeyValue;
 use regex::Regex;
@@ -21,21 +25,30 @@ use serde::Serialize;
 use serde_json::Value;
 use tower::BoxError;
 
+use crate::entitlement::Entitlement;
 use crate::error::FetchError;
 use crate::graphql::Request;
+use crate::memory::heap_usage;
+use crate::memory::resident_memory;
 use crate::plugin::serde::deserialize_header_name;
 use crate::plugin::serde::deserialize_json_query;
 use crate::plugin::serde::deserialize_regex;
 use crate::plugin::Handler;
 use crate::plugins::telemetry::config::MetricsCommon;
 use crate::plugins::telemetry::metrics::apollo::Sender;
+use crate::plugins::telemetry::metrics::histogram::TimeUnit;
 use crate::services::transport;
 use crate::services::SupergraphResponse;
+use crate::supervisor;
 use crate::Context;
 
 pub(crate) mod apollo;
+pub(crate) mod custom;
+pub(crate) mod histogram;
+pub(crate) mod operation;
 pub(crate) mod otlp;
 pub(crate) mod prometheus;
+pub(crate) mod response_size;
 
 pub(crate) type MetricsExporterHandle = Box<dyn Any + Send + Sync + 'static>;
 
@@ -493,6 +506,14 @@ impl MetricsBuilder {
         self
     }
 
+    fn with_shared_meter_provider(
+        mut self,
+        meter_provider: Arc<dyn MeterProvider + Send + Sync + 'static>,
+    ) -> Self {
+        self.meter_providers.push(meter_provider);
+        self
+    }
+
     fn with_custom_endpoint(mut self, path: &str, endpoint: transport::BoxService) -> Self {
         self.custom_endpoints
             .insert(path.to_string(), Handler::new(endpoint));
@@ -518,10 +539,24 @@ pub(crate) struct BasicMetrics {
     pub(crate) http_requests_total: AggregateCounter<u64>,
     pub(crate) http_requests_error_total: AggregateCounter<u64>,
     pub(crate) http_requests_duration: AggregateValueRecorder<f64>,
+    pub(crate) http_requests_deadline_exceeded_total: AggregateCounter<u64>,
+    pub(crate) http_requests_cancelled_total: AggregateCounter<u64>,
+    pub(crate) http_requests_panicked_total: AggregateCounter<u64>,
+    pub(crate) http_requests_in_flight: AggregateUpDownCounter<i64>,
+    pub(crate) http_requests_queued: AggregateUpDownCounter<i64>,
+    pub(crate) http_response_size_bytes_total: AggregateCounter<u64>,
+    pub(crate) http_response_entities_total: AggregateCounter<u64>,
+    pub(crate) http_response_null_fields_total: AggregateCounter<u64>,
+    pub(crate) http_request_size_bytes: AggregateValueRecorder<u64>,
+    pub(crate) http_requests_size_limit_exceeded_total: AggregateCounter<u64>,
+    duration_unit: TimeUnit,
 }
 
 impl BasicMetrics {
-    pub(crate) fn new(meter_provider: &AggregateMeterProvider) -> BasicMetrics {
+    pub(crate) fn new(
+        meter_provider: &AggregateMeterProvider,
+        duration_unit: TimeUnit,
+    ) -> BasicMetrics {
         let meter = meter_provider.meter("apollo/router", None);
         BasicMetrics {
             http_requests_total: meter.build_counter(|m| {
@@ -535,12 +570,191 @@ impl BasicMetrics {
                     .init()
             }),
             http_requests_duration: meter.build_value_recorder(|m| {
-                m.f64_value_recorder("http_request_duration_seconds")
+                m.f64_value_recorder(duration_unit.instrument_name("http_request_duration"))
                     .with_description("Total number of HTTP requests made.")
                     .init()
             }),
+            http_requests_deadline_exceeded_total: meter.build_counter(|m| {
+                m.u64_counter("http_requests_deadline_exceeded_total")
+                    .with_description(
+                        "Total number of HTTP requests that reached their deadline before all subgraph fetches completed.",
+                    )
+                    .init()
+            }),
+            http_requests_cancelled_total: meter.build_counter(|m| {
+                m.u64_counter("http_requests_cancelled_total")
+                    .with_description(
+                        "Total number of HTTP requests cancelled before their response was sent, usually because the client disconnected.",
+                    )
+                    .init()
+            }),
+            http_requests_panicked_total: meter.build_counter(|m| {
+                m.u64_counter("http_requests_panicked_total")
+                    .with_description(
+                        "Total number of HTTP requests answered with an error because they panicked.",
+                    )
+                    .init()
+            }),
+            http_requests_in_flight: meter.build_up_down_counter(|m| {
+                m.i64_up_down_counter("http_requests_in_flight")
+                    .with_description(
+                        "Number of requests being processed by each stage (supergraph, execution, subgraph).",
+                    )
+                    .init()
+            }),
+            http_requests_queued: meter.build_up_down_counter(|m| {
+                m.i64_up_down_counter("http_requests_queued")
+                    .with_description(
+                        "Number of requests waiting in each stage (supergraph, execution, subgraph) before reaching its service, e.g. in buffers or for a concurrency limit.",
+                    )
+                    .init()
+            }),
+            http_response_size_bytes_total: meter.build_counter(|m| {
+                m.u64_counter("http_response_size_bytes_total")
+                    .with_description("Total size of the responses sent, in bytes.")
+                    .init()
+            }),
+            http_response_entities_total: meter.build_counter(|m| {
+                m.u64_counter("http_response_entities_total")
+                    .with_description("Total number of entities in the responses sent.")
+                    .init()
+            }),
+            http_response_null_fields_total: meter.build_counter(|m| {
+                m.u64_counter("http_response_null_fields_total")
+                    .with_description("Total number of null fields in the responses sent.")
+                    .init()
+            }),
+            http_request_size_bytes: meter.build_value_recorder(|m| {
+                m.u64_value_recorder("http_request_size_bytes")
+                    .with_description(
+                        "Approximate size of the data held by each request: its body, the responses of its subgraphs and its response.",
+                    )
+                    .init()
+            }),
+            http_requests_size_limit_exceeded_total: meter.build_counter(|m| {
+                m.u64_counter("http_requests_size_limit_exceeded_total")
+                    .with_description(
+                        "Total number of HTTP requests whose subgraph fetches were aborted because they held too much data.",
+                    )
+                    .init()
+            }),
+            duration_unit,
         }
     }
+
+    /// Record the duration of a request, in the configured unit.
+    pub(crate) fn record_duration(&self, duration: Duration, attributes: &[KeyValue]) {
+        self.http_requests_duration
+            .record(self.duration_unit.value_of(duration), attributes);
+    }
+}
+
+/// Register the memory usage of the router, read when the metrics are collected. The heap usage is
+/// only known when the accounting allocator is the global allocator.
+pub(crate) fn register_memory_metrics(meter_provider: &AggregateMeterProvider) {
+    meter_provider
+        .meter("apollo/router", None)
+        .register_observers(|meter| {
+            meter
+                .i64_value_observer(
+                    "apollo_router_memory_resident_bytes",
+                    |result: ObserverResult<i64>| {
+                        if let Some(resident) = resident_memory() {
+                            result.observe(resident as i64, &[])
+                        }
+                    },
+                )
+                .with_description("Resident memory of the router, in bytes.")
+                .init();
+            meter
+                .i64_value_observer(
+                    "apollo_router_memory_heap_bytes",
+                    |result: ObserverResult<i64>| {
+                        if let Some(heap) = heap_usage() {
+                            result.observe(heap as i64, &[])
+                        }
+                    },
+                )
+                .with_description("Memory allocated on the heap by the router, in bytes.")
+                .init();
+        });
+}
+
+/// Register the number of times the router worker was restarted, when it is supervised.
+pub(crate) fn register_worker_metrics(meter_provider: &AggregateMeterProvider) {
+    let restarts = match supervisor::worker_restarts() {
+        Some(restarts) => restarts,
+        None => return,
+    };
+    meter_provider
+        .meter("apollo/router", None)
+        .register_observers(move |meter| {
+            meter
+                .u64_sum_observer(
+                    "apollo_router_worker_restarts_total",
+                    move |result: ObserverResult<u64>| result.observe(restarts, &[]),
+                )
+                .with_description(
+                    "Total number of times the router worker was restarted by the supervisor.",
+                )
+                .init();
+        });
+}
+
+/// Register the state of the entitlement a pipeline was loaded with.
+pub(crate) fn register_entitlement_metrics(metrics: &PluginMetrics, entitlement: Entitlement) {
+    metrics.register_observers(move |meter| {
+        let entitlement = entitlement.clone();
+        meter
+            .u64_value_observer(
+                "apollo_router_entitlement_state",
+                move |result: ObserverResult<u64>| {
+                    let state = entitlement.state();
+                    result.observe(1, &[KeyValue::new("state", state.as_str())])
+                },
+            )
+            .with_description(
+                "State of the license of the router: unenforced, unlicensed, invalid, entitled, warning or halted.",
+            )
+            .init();
+    });
+}
+
+/// The metrics of the plugins of a pipeline, exported with the meter provider of its telemetry
+/// plugin. The observers registered before the telemetry plugin is created are registered once it
+/// is.
+#[derive(Clone, Default)]
+pub(crate) struct PluginMetrics(Arc<Mutex<PluginMetricsState>>);
+
+type RegisterObservers = Box<dyn Fn(&Meter) + Send + Sync>;
+
+#[derive(Default)]
+struct PluginMetricsState {
+    meter_provider: Option<AggregateMeterProvider>,
+    pending: Vec<RegisterObservers>,
+}
+
+impl PluginMetrics {
+    /// Register observers, their values being read when the metrics are collected.
+    pub(crate) fn register_observers(&self, register: impl Fn(&Meter) + Send + Sync + 'static) {
+        let mut state = self.0.lock().expect("lock poisoned");
+        match &state.meter_provider {
+            Some(meter_provider) => meter_provider
+                .meter("apollo/router", None)
+                .register_observers(register),
+            None => state.pending.push(Box::new(register)),
+        }
+    }
+
+    /// Export the metrics of the plugins with the meter provider of the telemetry plugin.
+    pub(crate) fn set_meter_provider(&self, meter_provider: &AggregateMeterProvider) {
+        let mut state = self.0.lock().expect("lock poisoned");
+        let meter = meter_provider.meter("apollo/router", None);
+        for register in state.pending.drain(..) {
+            meter.register_observers(register);
+        }
+        state.meter_provider = Some(meter_provider.clone());
+    }
 }
 
 #[derive(Clone, Default)]
@@ -578,10 +792,25 @@ impl AggregateMeter {
 
     pub(crate) fn build_value_recorder<T: Into<Number> + Copy>(
         &self,
-        build: fn(&Meter) -> ValueRecorder<T>,
+        build: impl Fn(&Meter) -> ValueRecorder<T>,
     ) -> AggregateValueRecorder<T> {
         AggregateValueRecorder(self.0.iter().map(|m| build(m)).collect())
     }
+
+    pub(crate) fn build_up_down_counter<T: Into<Number> + Copy>(
+        &self,
+        build: fn(&Meter) -> UpDownCounter<T>,
+    ) -> AggregateUpDownCounter<T> {
+        AggregateUpDownCounter(self.0.iter().map(|m| build(m)).collect())
+    }
+
+    /// Register observers with every meter, their values being read when the metrics are
+    /// collected.
+    pub(crate) fn register_observers(&self, register: impl Fn(&Meter)) {
+        for meter in &self.0 {
+            register(meter)
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -597,6 +826,19 @@ where
     }
 }
 
+#[derive(Clone)]
+pub(crate) struct AggregateUpDownCounter<T: Into<Number> + Copy>(Vec<UpDownCounter<T>>);
+impl<T> AggregateUpDownCounter<T>
+where
+    T: Into<Number> + Copy,
+{
+    pub(crate) fn add(&self, value: T, attributes: &[KeyValue]) {
+        for counter in &self.0 {
+            counter.add(value, attributes)
+        }
+    }
+}
+
 #[derive(Clone)]
 pub(crate) struct AggregateValueRecorder<T: Into<Number> + Copy>(Vec<ValueRecorder<T>>);
 impl<T> AggregateValueRecorder<T>
@@ -0,0 +1,172 @@
+//! Labels of the router metrics identifying the operation and the client of the requests.
+//!
+//! Operation and client names are chosen by the clients, so the number of distinct values of
+//! these labels is bounded: the values that are not allowed, or seen once the maximum number of
+//! values is reached, are replaced by `other`.
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+/// The label value replacing the values over the cardinality limit.
+const OTHER: &str = "other";
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+/// Configuration to label the router metrics with the operation name, and the client name and version
+pub(crate) struct OperationAttributesConf {
+    /// Label the metrics with the client name and version as well
+    #[serde(default)]
+    pub(crate) client: bool,
+    /// Operation names the metrics are labelled with, the other operations being labelled as `other`
+    pub(crate) allow_list: Option<Vec<String>>,
+    /// Maximum number of distinct values of each label, the values seen once it is reached being labelled as `other`
+    #[serde(default = "default_max_values")]
+    pub(crate) max_values: usize,
+}
+
+impl Default for OperationAttributesConf {
+    fn default() -> Self {
+        OperationAttributesConf {
+            client: false,
+            allow_list: None,
+            max_values: default_max_values(),
+        }
+    }
+}
+
+fn default_max_values() -> usize {
+    100
+}
+
+/// Bounds the number of distinct values of a label.
+#[derive(Debug)]
+struct CardinalityLimiter {
+    allow_list: Option<HashSet<String>>,
+    max_values: usize,
+    seen: Mutex<HashSet<String>>,
+}
+
+impl CardinalityLimiter {
+    fn new(allow_list: Option<&Vec<String>>, max_values: usize) -> Self {
+        CardinalityLimiter {
+            allow_list: allow_list.map(|names| names.iter().cloned().collect()),
+            max_values,
+            seen: Default::default(),
+        }
+    }
+
+    fn limit(&self, value: &str) -> String {
+        if let Some(allow_list) = &self.allow_list {
+            if !allow_list.contains(value) {
+                return OTHER.to_string();
+            }
+        }
+        let mut seen = self.seen.lock().expect("lock poisoned");
+        if seen.contains(value) {
+            return value.to_string();
+        }
+        if seen.len() >= self.max_values {
+            return OTHER.to_string();
+        }
+        seen.insert(value.to_string());
+        value.to_string()
+    }
+}
+
+/// The operation and client labels of a router, keeping track of the values in use.
+#[derive(Debug)]
+pub(crate) struct OperationAttributes {
+    operation_name: CardinalityLimiter,
+    client: Option<(CardinalityLimiter, CardinalityLimiter)>,
+}
+
+impl OperationAttributes {
+    pub(crate) fn new(config: &OperationAttributesConf) -> Self {
+        OperationAttributes {
+            operation_name: CardinalityLimiter::new(config.allow_list.as_ref(), config.max_values),
+            client: config.client.then(|| {
+                (
+                    CardinalityLimiter::new(None, config.max_values),
+                    CardinalityLimiter::new(None, config.max_values),
+                )
+            }),
+        }
+    }
+
+    pub(crate) fn get_attributes(
+        &self,
+        operation_name: Option<&str>,
+        client_name: &str,
+        client_version: &str,
+    ) -> HashMap<String, String> {
+        let mut attributes = HashMap::new();
+        if let Some(operation_name) = operation_name {
+            attributes.insert(
+                "operation_name".to_string(),
+                self.operation_name.limit(operation_name),
+            );
+        }
+        if let Some((client_names, client_versions)) = &self.client {
+            attributes.insert("client_name".to_string(), client_names.limit(client_name));
+            attributes.insert(
+                "client_version".to_string(),
+                client_versions.limit(client_version),
+            );
+        }
+        attributes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes(config: serde_json::Value) -> OperationAttributes {
+        OperationAttributes::new(&serde_json::from_value(config).unwrap())
+    }
+
+    #[test]
+    fn it_labels_the_operations_over_the_limit_as_other() {
+        let attributes = attributes(serde_json::json!({ "max_values": 2 }));
+        let operation_name =
+            |name| attributes.get_attributes(Some(name), "", "")["operation_name"].clone();
+
+        assert_eq!(operation_name("GetUser"), "GetUser");
+        assert_eq!(operation_name("GetProducts"), "GetProducts");
+        assert_eq!(operation_name("GetReviews"), "other");
+        assert_eq!(operation_name("GetUser"), "GetUser");
+        assert!(!attributes
+            .get_attributes(None, "web", "1.0")
+            .contains_key("operation_name"));
+    }
+
+    #[test]
+    fn it_labels_the_operations_by_default() {
+        let attributes = OperationAttributes::new(&Default::default());
+        let labels = attributes.get_attributes(Some("GetUser"), "web", "1.0");
+        assert_eq!(labels["operation_name"], "GetUser");
+        assert!(!labels.contains_key("client_name"));
+    }
+
+    #[test]
+    fn it_labels_the_operations_not_allowed_as_other() {
+        let attributes = attributes(serde_json::json!({
+            "allow_list": ["GetUser"],
+            "client": true,
+            "max_values": 1
+        }));
+
+        let labels = attributes.get_attributes(Some("GetProducts"), "web", "1.0");
+        assert_eq!(labels["operation_name"], "other");
+        assert_eq!(labels["client_name"], "web");
+        assert_eq!(labels["client_version"], "1.0");
+
+        let labels = attributes.get_attributes(Some("GetUser"), "ios", "1.0");
+        assert_eq!(labels["operation_name"], "GetUser");
+        assert_eq!(labels["client_name"], "other");
+        assert_eq!(labels["client_version"], "1.0");
+    }
+}
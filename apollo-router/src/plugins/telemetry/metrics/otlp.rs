@@ -40,13 +40,23 @@ impl MetricsConfigurator for super::super::otlp::Config {
         mut builder: MetricsBuilder,
         metrics_config: &MetricsCommon,
     ) -> Result<MetricsBuilder, BoxError> {
+        if self.disk_buffer.is_some() {
+            return Err("otlp metric export does not support disk_buffer".into());
+        }
         let exporter: MetricExporterBuilder = self.exporter()?;
+        // latencies are aggregated in histograms only when their buckets are configured
+        let selector = match &metrics_config.histograms.buckets {
+            Some(_) => {
+                selectors::simple::Selector::Histogram(metrics_config.histograms.boundaries()?)
+            }
+            None => selectors::simple::Selector::Exact,
+        };
         match exporter.exporter {
             Some(exporter) => {
                 let exporter = opentelemetry_otlp::new_pipeline()
                     .metrics(tokio::spawn, delayed_interval)
                     .with_exporter(exporter)
-                    .with_aggregator_selector(selectors::simple::Selector::Exact)
+                    .with_aggregator_selector(selector)
                     .with_resource(
                         metrics_config
                             .resources
@@ -33,9 +33,7 @@ impl MetricsConfigurator for Config {
     ) -> Result<MetricsBuilder, BoxError> {
         if self.enabled {
             let exporter = opentelemetry_prometheus::exporter()
-                .with_default_histogram_boundaries(vec![
-                    0.001, 0.005, 0.015, 0.05, 0.1, 0.2, 0.3, 0.4, 0.5, 1.0, 5.0, 10.0,
-                ])
+                .with_default_histogram_boundaries(metrics_config.histograms.boundaries()?)
                 .with_resource(Resource::new(
                     metrics_config
                         .resources
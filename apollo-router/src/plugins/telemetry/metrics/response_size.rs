@@ -0,0 +1,91 @@
+//! Size of the responses, and their numbers of entities and null fields.
+//!
+//! The entities and null fields are counted over all the parts of a deferred response, and the
+//! bytes by the HTTP server as it serializes them, so that responses are not serialized twice.
+//! They are recorded once the response is sent.
+use opentelemetry::KeyValue;
+
+use crate::graphql::Response;
+use crate::json_ext::Value;
+use crate::plugins::telemetry::metrics::BasicMetrics;
+
+#[derive(Debug, Default)]
+pub(crate) struct ResponseSize {
+    entities: u64,
+    null_fields: u64,
+}
+
+impl ResponseSize {
+    /// Account for a part of the response.
+    pub(crate) fn add(&mut self, response: &Response) {
+        // the root of the data is not an entity, and deferred parts complete entities that were
+        // already counted in a previous part
+        let data = response
+            .data
+            .iter()
+            .chain(response.incremental.iter().filter_map(|i| i.data.as_ref()));
+        for data in data {
+            if let Value::Object(fields) = data {
+                fields.values().for_each(|value| self.count(value));
+            }
+        }
+    }
+
+    fn count(&mut self, value: &Value) {
+        match value {
+            Value::Null => self.null_fields += 1,
+            Value::Object(fields) => {
+                self.entities += 1;
+                fields.values().for_each(|value| self.count(value));
+            }
+            Value::Array(values) => values.iter().for_each(|value| self.count(value)),
+            _ => {}
+        }
+    }
+
+    /// Record the size of the response, with the bytes of its serialized body.
+    pub(crate) fn record(&self, bytes: u64, metrics: &BasicMetrics, attributes: &[KeyValue]) {
+        metrics
+            .http_response_size_bytes_total
+            .add(bytes, attributes);
+        metrics
+            .http_response_entities_total
+            .add(self.entities, attributes);
+        metrics
+            .http_response_null_fields_total
+            .add(self.null_fields, attributes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+
+    use super::*;
+    use crate::graphql::IncrementalResponse;
+
+    #[test]
+    fn it_counts_the_entities_and_null_fields() {
+        let mut size = ResponseSize::default();
+        let response = Response::builder()
+            .data(json!({
+                "me": { "id": "a", "name": null, "friends": [{ "id": "b" }, null] },
+                "topProducts": null
+            }))
+            .build();
+        size.add(&response);
+        assert_eq!(size.entities, 2);
+        assert_eq!(size.null_fields, 3);
+
+        size.add(
+            &Response::builder()
+                .incremental(vec![IncrementalResponse::builder()
+                    .data(json!({ "reviews": [{ "body": null }] }))
+                    .path(crate::json_ext::Path::from("me"))
+                    .build()])
+                .build(),
+        );
+        assert_eq!(size.entities, 3);
+        assert_eq!(size.null_fields, 4);
+    }
+}
@@ -6,19 +6,24 @@ use std::fmt;
 use std::sync::atomic::AtomicU8;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
 use std::time::Duration;
 use std::time::Instant;
+use std::time::SystemTime;
 
 use ::tracing::info_span;
 use ::tracing::subscriber::set_global_default;
 use ::tracing::Span;
 use ::tracing::Subscriber;
 use apollo_spaceport::server::ReportSpaceport;
+use apollo_spaceport::Message;
+use apollo_spaceport::ReporterRequest;
 use apollo_spaceport::StatsContext;
 use futures::future::BoxFuture;
 use futures::FutureExt;
 use futures::StreamExt;
-use http::HeaderValue;
+use http::header::HeaderName;
 use http::StatusCode;
 use metrics::apollo::Sender;
 use once_cell::sync::OnceCell;
@@ -39,38 +44,65 @@ use tower::ServiceBuilder;
 use tower::ServiceExt;
 use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
 use tracing_subscriber::registry::LookupSpan;
-use tracing_subscriber::EnvFilter;
 use tracing_subscriber::Registry;
 use url::Url;
 
 use self::config::Conf;
 use self::metrics::AttributesForwardConf;
 use self::metrics::MetricsAttributesConf;
-use crate::executable::GLOBAL_ENV_FILTER;
+use crate::error::FetchErrorKind;
+use crate::executable::current_log_level;
+use crate::executable::reloadable_log_filter;
+use crate::http_ext::ResponseBodySize;
 use crate::layers::ServiceBuilderExt;
+use crate::plugin::spans::span_customizers;
+use crate::plugin::spans::SpanCustomization;
+use crate::plugin::spans::SpanCustomizer;
+use crate::plugin::spans::SpanStage;
 use crate::plugin::Handler;
 use crate::plugin::Plugin;
 use crate::plugin::PluginInit;
 use crate::plugins::telemetry::config::MetricsCommon;
 use crate::plugins::telemetry::config::Trace;
+use crate::plugins::telemetry::disk_buffer::DiskBuffer;
+use crate::plugins::telemetry::logs::LogsExporter;
+use crate::plugins::telemetry::logs::LogsHandle;
+use crate::plugins::telemetry::logs::LogsLayer;
+use crate::plugins::telemetry::logs::SlowOperations;
 use crate::plugins::telemetry::metrics::apollo::studio::SingleContextualizedStats;
 use crate::plugins::telemetry::metrics::apollo::studio::SingleQueryLatencyStats;
 use crate::plugins::telemetry::metrics::apollo::studio::SingleReport;
 use crate::plugins::telemetry::metrics::apollo::studio::SingleTracesAndStats;
+use crate::plugins::telemetry::metrics::apollo::trace::TraceRequest;
+use crate::plugins::telemetry::metrics::histogram::TimeUnit;
+use crate::plugins::telemetry::metrics::operation::OperationAttributes;
+use crate::plugins::telemetry::metrics::response_size::ResponseSize;
 use crate::plugins::telemetry::metrics::AggregateMeterProvider;
+use crate::plugins::telemetry::metrics::AggregateUpDownCounter;
 use crate::plugins::telemetry::metrics::BasicMetrics;
 use crate::plugins::telemetry::metrics::MetricsBuilder;
 use crate::plugins::telemetry::metrics::MetricsConfigurator;
 use crate::plugins::telemetry::metrics::MetricsExporterHandle;
+use crate::plugins::telemetry::scrubbing::ScrubbedStdout;
+use crate::plugins::telemetry::scrubbing::Scrubber;
+use crate::plugins::telemetry::scrubbing::ScrubbingHandle;
+use crate::plugins::telemetry::scrubbing::ScrubbingLayer;
+use crate::plugins::telemetry::tracing::customized_span;
 use crate::plugins::telemetry::tracing::TracingConfigurator;
+use crate::plugins::traffic_shaping::TIMED_OUT_CONTEXT_KEY;
+use crate::query_planner::DEADLINE_EXCEEDED_CONTEXT_KEY;
 use crate::query_planner::USAGE_REPORTING;
 use crate::register_plugin;
 use crate::services::execution;
+use crate::services::layers::client_identification::ClientInfo;
+use crate::services::request_size::RequestSize;
+use crate::services::request_size::REQUEST_BYTES_EXCEEDED_CONTEXT_KEY;
 use crate::services::subgraph;
 use crate::services::supergraph;
 use crate::services::transport;
 use crate::Context;
 use crate::ExecutionRequest;
+use crate::ExecutionResponse;
 use crate::SubgraphRequest;
 use crate::SubgraphResponse;
 use crate::SupergraphRequest;
@@ -78,8 +110,11 @@ use crate::SupergraphResponse;
 
 pub(crate) mod apollo;
 pub(crate) mod config;
-mod metrics;
+mod disk_buffer;
+mod logs;
+pub(crate) mod metrics;
 mod otlp;
+pub(crate) mod scrubbing;
 mod tracing;
 
 static SUPERGRAPH_SPAN_NAME: &str = "supergraph";
@@ -89,10 +124,17 @@ const ATTRIBUTES: &str = "apollo_telemetry::metrics_attributes";
 const SUBGRAPH_ATTRIBUTES: &str = "apollo_telemetry::subgraph_metrics_attributes";
 pub(crate) static STUDIO_EXCLUDE: &str = "apollo_telemetry::studio::exclude";
 const DEFAULT_SERVICE_NAME: &str = "apollo-router";
+/// Duration reported for every operation when deterministic mode is enabled.
+const DETERMINISTIC_DURATION: Duration = Duration::from_millis(100);
+/// Time reported for every event when deterministic mode is enabled.
+const DETERMINISTIC_TIME: SystemTime = SystemTime::UNIX_EPOCH;
 
-static TELEMETRY_LOADED: OnceCell<bool> = OnceCell::new();
+static TELEMETRY_LOADED: OnceCell<LogsHandle> = OnceCell::new();
 static TELEMETRY_REFCOUNT: AtomicU8 = AtomicU8::new(0);
 
+/// The logs exporter and the scrubbing rules of an active telemetry.
+type Activation = (Weak<LogsExporter>, Option<Arc<Scrubber>>);
+
 #[doc(hidden)] // Only public for integration tests
 pub struct Telemetry {
     config: config::Conf,
@@ -104,6 +146,14 @@ pub struct Telemetry {
     custom_endpoints: HashMap<String, Handler>,
     spaceport_shutdown: Option<futures::channel::oneshot::Sender<()>>,
     apollo_metrics_sender: metrics::apollo::Sender,
+    operation_attributes: Arc<OperationAttributes>,
+    span_customizers: Vec<Arc<dyn SpanCustomizer>>,
+    logs: LogsHandle,
+    logs_exporter: Option<Arc<LogsExporter>>,
+    scrubbing: ScrubbingHandle,
+    scrubber: Option<Arc<Scrubber>>,
+    /// The exporter and the scrubbing rules active before the startup, restored on rollback.
+    previous_activation: Mutex<Option<Activation>>,
 }
 
 #[derive(Debug)]
@@ -160,46 +210,115 @@ impl Plugin for Telemetry {
     type Config = config::Conf;
 
     async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
-        Self::new_common::<Registry>(init.config, None).await
+        let telemetry = Self::new_common::<Registry>(init.config, None).await?;
+        init.metrics.set_meter_provider(&telemetry.meter_provider);
+        Ok(telemetry)
+    }
+
+    async fn startup(&self) -> Result<(), BoxError> {
+        let logs_exporter = self.logs.activate(self.logs_exporter.as_ref());
+        let scrubber = self.scrubbing.activate(self.scrubber.as_ref());
+        *self.previous_activation.lock().expect("lock poisoned") = Some((logs_exporter, scrubber));
+        Ok(())
+    }
+
+    async fn rollback(&self) {
+        let previous = self
+            .previous_activation
+            .lock()
+            .expect("lock poisoned")
+            .take();
+        if let Some((logs_exporter, scrubber)) = previous {
+            self.logs.restore(logs_exporter);
+            self.scrubbing.activate(scrubber.as_ref());
+        }
     }
 
     fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
         let metrics_sender = self.apollo_metrics_sender.clone();
-        let metrics = BasicMetrics::new(&self.meter_provider);
+        let metrics = BasicMetrics::new(&self.meter_provider, self.duration_unit());
+        let queued_metrics = metrics.clone();
         let config = Arc::new(self.config.clone());
         let config_map_res = config.clone();
+        let reports_enabled = !matches!(metrics_sender, Sender::Noop);
+        let operation_attributes = self.operation_attributes.clone();
+        let record_response_size = self
+            .config
+            .metrics
+            .as_ref()
+            .and_then(|m| m.common.as_ref())
+            .map(|c| c.response_size)
+            .unwrap_or_default();
+        let slow_operations = self
+            .config
+            .logs
+            .as_ref()
+            .and_then(|logs| logs.slow_operations.clone());
+        let log_slow_operations = slow_operations.is_some();
+        let scrubber = self.scrubber.clone();
+        let scrubber_map_res = scrubber.clone();
         ServiceBuilder::new()
             .instrument(Self::supergraph_service_span(
                 config.apollo.clone().unwrap_or_default(),
+                self.span_customizers.clone(),
             ))
             .map_future_with_request_data(
                 move |req: &SupergraphRequest| {
-                    Self::populate_context(config.clone(), req);
-                    req.context.clone()
+                    Self::populate_context(
+                        config.clone(),
+                        &operation_attributes,
+                        scrubber.as_deref(),
+                        req,
+                    );
+                    let trace_request = match &config.apollo {
+                        Some(apollo) if apollo.send_traces && reports_enabled => {
+                            TraceRequest::sample(apollo, req, scrubber.as_deref())
+                        }
+                        _ => None,
+                    };
+                    // only kept for the slow operations log
+                    let operation_name = log_slow_operations
+                        .then(|| req.originating_request.body().operation_name.clone())
+                        .flatten();
+                    (req.context.clone(), trace_request, operation_name)
                 },
-                move |ctx: Context, fut| {
+                move |(ctx, trace_request, operation_name): (
+                    Context,
+                    Option<TraceRequest>,
+                    Option<String>,
+                ),
+                      fut| {
                     let config = config_map_res.clone();
+                    let scrubber = scrubber_map_res.clone();
+                    let slow_operations = slow_operations.clone();
                     let metrics = metrics.clone();
                     let sender = metrics_sender.clone();
                     let start = Instant::now();
+                    let in_flight =
+                        StageGuard::new(&metrics.http_requests_in_flight, "supergraph", Vec::new());
                     async move {
+                        let _in_flight = in_flight;
+                        let mut cancellation_guard =
+                            CancellationGuard::new(metrics.clone(), ctx.clone());
                         let mut result: Result<SupergraphResponse, BoxError> = fut.await;
                         result = Self::update_metrics(
                             config.clone(),
                             ctx.clone(),
                             metrics.clone(),
                             result,
-                            start.elapsed(),
+                            config.elapsed(start),
                         )
                         .await;
                         match result {
                             Err(e) => {
+                                cancellation_guard.completed();
                                 if !matches!(sender, Sender::Noop) {
                                     Self::update_apollo_metrics(
                                         &ctx,
                                         sender,
                                         true,
-                                        start.elapsed(),
+                                        config.elapsed(start),
+                                        trace_request,
                                     );
                                 }
                                 let mut metric_attrs = Vec::new();
@@ -223,28 +342,74 @@ impl Plugin for Telemetry {
 
                                 Err(e)
                             }
-                            Ok(router_response) => {
+                            Ok(mut router_response) => {
                                 let mut has_errors =
                                     !router_response.response.status().is_success();
+                                let response_size = record_response_size.then(|| {
+                                    let response_size =
+                                        Arc::new(Mutex::new(ResponseSize::default()));
+                                    // the bytes are counted by the HTTP server as it sends them
+                                    let recorded = response_size.clone();
+                                    let metrics = metrics.clone();
+                                    let ctx = ctx.clone();
+                                    router_response.response.extensions_mut().insert(
+                                        ResponseBodySize::new(move |bytes| {
+                                            recorded.lock().expect("lock poisoned").record(
+                                                bytes,
+                                                &metrics,
+                                                &Self::metric_attributes(&ctx),
+                                            )
+                                        }),
+                                    );
+                                    response_size
+                                });
                                 Ok(router_response.map(move |response_stream| {
                                     let sender = sender.clone();
                                     let ctx = ctx.clone();
+                                    let config = config.clone();
+                                    let metrics = metrics.clone();
+                                    let mut trace_request = trace_request;
 
                                     response_stream
                                         .map(move |response| {
                                             if !response.errors.is_empty() {
                                                 has_errors = true;
+                                                if let Some(trace_request) = &mut trace_request {
+                                                    trace_request.record_errors(
+                                                        &response.errors,
+                                                        scrubber.as_deref(),
+                                                    );
+                                                }
+                                            }
+                                            if let Some(response_size) = &response_size {
+                                                response_size
+                                                    .lock()
+                                                    .expect("lock poisoned")
+                                                    .add(&response);
                                             }
 
-                                            if !response.has_next.unwrap_or(false)
-                                                && !matches!(sender, Sender::Noop)
-                                            {
-                                                Self::update_apollo_metrics(
-                                                    &ctx,
-                                                    sender.clone(),
-                                                    has_errors,
-                                                    start.elapsed(),
-                                                );
+                                            if !response.has_next.unwrap_or(false) {
+                                                cancellation_guard.completed();
+                                                let size =
+                                                    Self::record_request_size(&metrics, &ctx);
+                                                if let Some(slow_operations) = &slow_operations {
+                                                    Self::log_slow_operation(
+                                                        slow_operations,
+                                                        operation_name.as_deref(),
+                                                        &ctx,
+                                                        config.elapsed(start),
+                                                        size,
+                                                    );
+                                                }
+                                                if !matches!(sender, Sender::Noop) {
+                                                    Self::update_apollo_metrics(
+                                                        &ctx,
+                                                        sender.clone(),
+                                                        has_errors,
+                                                        config.elapsed(start),
+                                                        trace_request.take(),
+                                                    );
+                                                }
                                             }
                                             response
                                         })
@@ -255,11 +420,22 @@ impl Plugin for Telemetry {
                     }
                 },
             )
+            .map_request(move |mut req: SupergraphRequest| {
+                req.originating_request.extensions_mut().insert(Queued::new(
+                    &queued_metrics,
+                    "supergraph",
+                    Vec::new(),
+                ));
+                req
+            })
             .service(service)
             .boxed()
     }
 
     fn execution_service(&self, service: execution::BoxService) -> execution::BoxService {
+        let metrics = BasicMetrics::new(&self.meter_provider, self.duration_unit());
+        let queued_metrics = metrics.clone();
+        let span_customizers = self.span_customizers.clone();
         ServiceBuilder::new()
             .instrument(move |req: &ExecutionRequest| {
                 let query = req
@@ -274,20 +450,49 @@ impl Plugin for Telemetry {
                     .operation_name
                     .clone()
                     .unwrap_or_default();
-                info_span!("execution",
-                    graphql.document = query.as_str(),
-                    graphql.operation.name = operation_name.as_str(),
-                    "otel.kind" = %SpanKind::Internal
+                customized_span(
+                    &span_customizers,
+                    SpanCustomization::new(SpanStage::Execution, "execution", None, &req.context),
+                    || {
+                        info_span!("execution",
+                            graphql.document = query.as_str(),
+                            graphql.operation.name = operation_name.as_str(),
+                            "otel.kind" = %SpanKind::Internal
+                        )
+                    },
                 )
             })
+            .map_future_with_request_data(
+                |_: &ExecutionRequest| (),
+                move |_, fut: BoxFuture<'static, Result<ExecutionResponse, BoxError>>| {
+                    let in_flight =
+                        StageGuard::new(&metrics.http_requests_in_flight, "execution", Vec::new());
+                    async move {
+                        let _in_flight = in_flight;
+                        fut.await
+                    }
+                },
+            )
+            .map_request(move |mut req: ExecutionRequest| {
+                req.originating_request.extensions_mut().insert(Queued::new(
+                    &queued_metrics,
+                    "execution",
+                    Vec::new(),
+                ));
+                req
+            })
             .service(service)
             .boxed()
     }
 
     fn subgraph_service(&self, name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
-        let metrics = BasicMetrics::new(&self.meter_provider);
+        let metrics = BasicMetrics::new(&self.meter_provider, self.duration_unit());
+        let queued_metrics = metrics.clone();
+        let config = Arc::new(self.config.clone());
         let subgraph_attribute = KeyValue::new("subgraph", name.to_string());
+        let queued_attribute = subgraph_attribute.clone();
         let name = name.to_owned();
+        let span_customizers = self.span_customizers.clone();
         let subgraph_metrics = Arc::new(
             self.config
                 .metrics
@@ -368,11 +573,22 @@ impl Plugin for Telemetry {
                     .clone()
                     .unwrap_or_default();
 
-                info_span!("subgraph",
-                    name = name.as_str(),
-                    graphql.document = query.as_str(),
-                    graphql.operation.name = operation_name.as_str(),
-                    "otel.kind" = %SpanKind::Internal,
+                customized_span(
+                    &span_customizers,
+                    SpanCustomization::new(
+                        SpanStage::Subgraph,
+                        "subgraph",
+                        Some(name.as_str()),
+                        &req.context,
+                    ),
+                    || {
+                        info_span!("subgraph",
+                            name = name.as_str(),
+                            graphql.document = query.as_str(),
+                            graphql.operation.name = operation_name.as_str(),
+                            "otel.kind" = %SpanKind::Internal,
+                        )
+                    },
                 )
             })
             .map_future_with_request_data(
@@ -401,9 +617,16 @@ impl Plugin for Telemetry {
                     let metrics = metrics.clone();
                     let subgraph_attribute = subgraph_attribute.clone();
                     let subgraph_metrics = subgraph_metrics.clone();
+                    let config = config.clone();
+                    let in_flight = StageGuard::new(
+                        &metrics.http_requests_in_flight,
+                        "subgraph",
+                        vec![subgraph_attribute.clone()],
+                    );
                     // Using Instant because it is guaranteed to be monotonically increasing.
                     let now = Instant::now();
                     f.map(move |r: Result<SubgraphResponse, BoxError>| {
+                        drop(in_flight);
                         let subgraph_metrics_conf = subgraph_metrics.clone();
                         let mut metric_attrs = context
                             .get::<_, HashMap<String, String>>(SUBGRAPH_ATTRIBUTES)
@@ -435,6 +658,12 @@ impl Plugin for Telemetry {
                                     "status",
                                     response.response.status().as_u16().to_string(),
                                 ));
+                                if !response.response.body().errors.is_empty() {
+                                    metric_attrs.push(KeyValue::new(
+                                        "error_kind",
+                                        FetchErrorKind::Graphql.as_str(),
+                                    ));
+                                }
 
                                 // Fill attributes from response
                                 if let Some(subgraph_attributes_conf) = &*subgraph_metrics_conf {
@@ -452,6 +681,10 @@ impl Plugin for Telemetry {
                                 metrics.http_requests_total.add(1, &metric_attrs);
                             }
                             Err(err) => {
+                                metric_attrs.push(KeyValue::new(
+                                    "error_kind",
+                                    FetchErrorKind::of(err).as_str(),
+                                ));
                                 // Fill attributes from error
                                 if let Some(subgraph_attributes_conf) = &*subgraph_metrics_conf {
                                     metric_attrs.extend(
@@ -465,13 +698,19 @@ impl Plugin for Telemetry {
                                 metrics.http_requests_error_total.add(1, &metric_attrs);
                             }
                         }
-                        metrics
-                            .http_requests_duration
-                            .record(now.elapsed().as_secs_f64(), &metric_attrs);
+                        metrics.record_duration(config.elapsed(now), &metric_attrs);
                         r
                     })
                 },
             )
+            .map_request(move |mut req: SubgraphRequest| {
+                req.subgraph_request.extensions_mut().insert(Queued::new(
+                    &queued_metrics,
+                    "subgraph",
+                    vec![queued_attribute.clone()],
+                ));
+                req
+            })
             .service(service)
             .boxed()
     }
@@ -524,11 +763,14 @@ impl Telemetry {
     where
         S: Subscriber + Send + Sync + for<'span> LookupSpan<'span>,
     {
+        let deterministic = config.experimental_deterministic;
         // Apollo config is special because we enable tracing if some env variables are present.
         let apollo = config
             .apollo
             .as_mut()
             .expect("telemetry apollo config must be present");
+        apollo.validate()?;
+        apollo.deterministic = deterministic;
 
         // If we have key and graph ref but no endpoint we start embedded spaceport
         let (spaceport, shutdown_tx) = match apollo {
@@ -540,11 +782,40 @@ impl Telemetry {
             } => {
                 ::tracing::debug!("starting Spaceport");
                 let (shutdown_tx, shutdown_rx) = futures::channel::oneshot::channel();
-                let report_spaceport = ReportSpaceport::new(
-                    "127.0.0.1:0".parse()?,
-                    Some(Box::pin(shutdown_rx.map(|_| ()))),
-                )
-                .await?;
+                // the reports which could not be transferred to Studio are kept on disk
+                let failed_reports = apollo.disk_buffer.as_ref().map(|config| {
+                    let buffer = DiskBuffer::new(config);
+                    let (tx, mut rx) = tokio::sync::mpsc::channel::<ReporterRequest>(1024);
+                    tokio::spawn(async move {
+                        while let Some(request) = rx.recv().await {
+                            if let Some(report) = request.report {
+                                if let Err(e) = buffer.push(&report.encode_to_vec()).await {
+                                    ::tracing::warn!(
+                                        "could not write the report to the disk buffer: {}",
+                                        e
+                                    );
+                                }
+                            }
+                        }
+                    });
+                    tx
+                });
+                let address = "127.0.0.1:0".parse()?;
+                let report_spaceport = match failed_reports {
+                    Some(failed_reports) => {
+                        ReportSpaceport::with_failed_reports(
+                            address,
+                            Some(Box::pin(shutdown_rx.map(|_| ()))),
+                            failed_reports,
+                        )
+                        .await?
+                    }
+                    None => {
+                        ReportSpaceport::new(address, Some(Box::pin(shutdown_rx.map(|_| ()))))
+                            .await?
+                    }
+                };
+                apollo.embedded_spaceport = true;
                 // Now that the port is known update the config
                 apollo.endpoint = Some(Url::parse(&format!(
                     "https://{}",
@@ -563,8 +834,9 @@ impl Telemetry {
         let mut builder = Self::create_metrics_exporters(&config)?;
 
         // the global tracer and subscriber initialization step must be performed only once
-        TELEMETRY_LOADED.get_or_try_init::<_, BoxError>(|| {
-            use anyhow::Context;
+        let scrubbing = ScrubbingHandle::router();
+        let logs = TELEMETRY_LOADED.get_or_try_init::<_, BoxError>(|| {
+            let logs = LogsHandle::default();
             let tracer_provider = Self::create_tracer_provider(&config)?;
 
             let tracer = tracer_provider.versioned_tracer(
@@ -578,46 +850,95 @@ impl Telemetry {
                 .expect("otel error handler lock poisoned, fatal");
             global::set_text_map_propagator(Self::create_propagator(&config));
 
-            let log_level = GLOBAL_ENV_FILTER
-                .get()
-                .map(|s| s.as_str())
-                .unwrap_or("info");
-
-            let sub_builder = tracing_subscriber::fmt::fmt().with_env_filter(
-                EnvFilter::try_new(log_level).context("could not parse log configuration")?,
-            );
+            let log_level = current_log_level();
 
             if let Some(sub) = subscriber {
                 let telemetry = tracing_opentelemetry::layer().with_tracer(tracer);
-                let subscriber = sub.with(telemetry);
+                let subscriber = sub
+                    .with(ScrubbingLayer::new(scrubbing.clone()))
+                    .with(telemetry)
+                    .with(LogsLayer::new(logs.clone(), scrubbing.clone()));
                 if let Err(e) = set_global_default(subscriber) {
                     ::tracing::error!("cannot set global subscriber: {:?}", e);
                 }
             } else if atty::is(atty::Stream::Stdout) {
                 let telemetry = tracing_opentelemetry::layer().with_tracer(tracer);
 
-                let subscriber = sub_builder.finish().with(telemetry);
+                let subscriber = Registry::default()
+                    .with(reloadable_log_filter(&log_level)?)
+                    .with(
+                        tracing_subscriber::fmt::layer()
+                            .with_writer(ScrubbedStdout::new(scrubbing.clone())),
+                    )
+                    .with(ScrubbingLayer::new(scrubbing.clone()))
+                    .with(telemetry)
+                    .with(LogsLayer::new(logs.clone(), scrubbing.clone()));
                 if let Err(e) = set_global_default(subscriber) {
                     ::tracing::error!("cannot set global subscriber: {:?}", e);
                 }
             } else {
                 let telemetry = tracing_opentelemetry::layer().with_tracer(tracer);
 
-                let subscriber = sub_builder.json().finish().with(telemetry);
+                let subscriber = Registry::default()
+                    .with(reloadable_log_filter(&log_level)?)
+                    .with(
+                        tracing_subscriber::fmt::layer()
+                            .json()
+                            .with_writer(ScrubbedStdout::new(scrubbing.clone())),
+                    )
+                    .with(ScrubbingLayer::new(scrubbing.clone()))
+                    .with(telemetry)
+                    .with(LogsLayer::new(logs.clone(), scrubbing.clone()));
                 if let Err(e) = set_global_default(subscriber) {
                     ::tracing::error!("cannot set global subscriber: {:?}", e);
                 }
             };
 
-            Ok(true)
+            Ok(logs)
         })?;
 
+        // the rules are used by the subscriber once the pipeline is started
+        let scrubber = match &config.scrubbing {
+            Some(scrubbing) => Some(Arc::new(Scrubber::new(scrubbing)?)),
+            None => None,
+        };
+        // the events are exported with this exporter once the pipeline is started
+        let logs_exporter = match config.logs.as_ref().and_then(|logs| logs.otlp.as_ref()) {
+            Some(otlp) => Some(Arc::new(LogsExporter::spawn(
+                otlp,
+                Self::create_logs_resource(&config),
+            )?)),
+            None => None,
+        };
+
+        let meter_provider = builder.meter_provider();
+        let apollo_metrics_sender = builder.apollo_metrics_provider();
+        apollo_metrics_sender.register_metrics(&meter_provider);
+        metrics::register_memory_metrics(&meter_provider);
+        metrics::register_worker_metrics(&meter_provider);
+        // the operation name label is bounded even when the operations are not configured
+        let operation_attributes = Arc::new(OperationAttributes::new(
+            &config
+                .metrics
+                .as_ref()
+                .and_then(|m| m.common.as_ref())
+                .and_then(|c| c.operations.clone())
+                .unwrap_or_default(),
+        ));
+
         let plugin = Ok(Telemetry {
             spaceport_shutdown: shutdown_tx,
             custom_endpoints: builder.custom_endpoints(),
             _metrics_exporters: builder.exporters(),
-            meter_provider: builder.meter_provider(),
-            apollo_metrics_sender: builder.apollo_metrics_provider(),
+            meter_provider,
+            apollo_metrics_sender,
+            operation_attributes,
+            span_customizers: span_customizers(),
+            logs: logs.clone(),
+            logs_exporter,
+            scrubbing,
+            scrubber,
+            previous_activation: Default::default(),
             config,
         });
 
@@ -642,6 +963,15 @@ impl Telemetry {
         plugin
     }
 
+    fn duration_unit(&self) -> TimeUnit {
+        self.config
+            .metrics
+            .as_ref()
+            .and_then(|m| m.common.as_ref())
+            .map(|c| c.histograms.unit)
+            .unwrap_or_default()
+    }
+
     fn create_propagator(config: &config::Conf) -> TextMapCompositePropagator {
         let propagation = config
             .clone()
@@ -676,8 +1006,13 @@ impl Telemetry {
     ) -> Result<opentelemetry::sdk::trace::TracerProvider, BoxError> {
         let tracing_config = config.tracing.clone().unwrap_or_default();
         let trace_config = &tracing_config.trace_config.unwrap_or_default();
+        let mut sdk_trace_config: opentelemetry::sdk::trace::Config = trace_config.into();
+        if config.experimental_deterministic {
+            sdk_trace_config =
+                sdk_trace_config.with_id_generator(config::DeterministicIdGenerator::default());
+        }
         let mut builder =
-            opentelemetry::sdk::trace::TracerProvider::builder().with_config(trace_config.into());
+            opentelemetry::sdk::trace::TracerProvider::builder().with_config(sdk_trace_config);
 
         builder = setup_tracing(builder, &tracing_config.jaeger, trace_config)?;
         builder = setup_tracing(builder, &tracing_config.zipkin, trace_config)?;
@@ -690,6 +1025,32 @@ impl Telemetry {
         Ok(tracer_provider)
     }
 
+    fn create_logs_resource(config: &config::Conf) -> Vec<(String, String)> {
+        // the logs are attributed to the same service as the traces they are correlated with
+        let trace_config = config
+            .tracing
+            .as_ref()
+            .and_then(|tracing| tracing.trace_config.clone())
+            .unwrap_or_default();
+        let mut resource = vec![(
+            opentelemetry_semantic_conventions::resource::SERVICE_NAME
+                .as_str()
+                .to_string(),
+            trace_config
+                .service_name
+                .unwrap_or_else(|| "router".to_string()),
+        )];
+        if let Some(service_namespace) = trace_config.service_namespace {
+            resource.push((
+                opentelemetry_semantic_conventions::resource::SERVICE_NAMESPACE
+                    .as_str()
+                    .to_string(),
+                service_namespace,
+            ));
+        }
+        resource
+    }
+
     fn create_metrics_exporters(config: &config::Conf) -> Result<MetricsBuilder, BoxError> {
         let metrics_config = config.metrics.clone().unwrap_or_default();
         let metrics_common_config = &mut metrics_config.common.unwrap_or_default();
@@ -710,6 +1071,7 @@ impl Telemetry {
         builder =
             setup_metrics_exporter(builder, &metrics_config.prometheus, metrics_common_config)?;
         builder = setup_metrics_exporter(builder, &metrics_config.otlp, metrics_common_config)?;
+        builder = setup_metrics_exporter(builder, &metrics_config.custom, metrics_common_config)?;
         Ok(builder)
     }
 
@@ -729,37 +1091,41 @@ impl Telemetry {
 
     fn supergraph_service_span(
         config: apollo::Config,
+        span_customizers: Vec<Arc<dyn SpanCustomizer>>,
     ) -> impl Fn(&SupergraphRequest) -> Span + Clone {
         let client_name_header = config.client_name_header;
         let client_version_header = config.client_version_header;
 
         move |request: &SupergraphRequest| {
             let http_request = &request.originating_request;
-            let headers = http_request.headers();
             let query = http_request.body().query.clone().unwrap_or_default();
             let operation_name = http_request
                 .body()
                 .operation_name
                 .clone()
                 .unwrap_or_default();
-            let client_name = headers
-                .get(&client_name_header)
-                .cloned()
-                .unwrap_or_else(|| HeaderValue::from_static(""));
-            let client_version = headers
-                .get(&client_version_header)
-                .cloned()
-                .unwrap_or_else(|| HeaderValue::from_static(""));
-            let span = info_span!(
-                SUPERGRAPH_SPAN_NAME,
-                graphql.document = query.as_str(),
-                // TODO add graphql.operation.type
-                graphql.operation.name = operation_name.as_str(),
-                client_name = client_name.to_str().unwrap_or_default(),
-                client_version = client_version.to_str().unwrap_or_default(),
-                "otel.kind" = %SpanKind::Internal
-            );
-            span
+            let (client_name, client_version) =
+                Self::client(request, &client_name_header, &client_version_header);
+            customized_span(
+                &span_customizers,
+                SpanCustomization::new(
+                    SpanStage::Supergraph,
+                    SUPERGRAPH_SPAN_NAME,
+                    None,
+                    &request.context,
+                ),
+                || {
+                    info_span!(
+                        SUPERGRAPH_SPAN_NAME,
+                        graphql.document = query.as_str(),
+                        // TODO add graphql.operation.type
+                        graphql.operation.name = operation_name.as_str(),
+                        client_name = client_name.as_str(),
+                        client_version = client_version.as_str(),
+                        "otel.kind" = %SpanKind::Internal
+                    )
+                },
+            )
         }
     }
 
@@ -768,6 +1134,7 @@ impl Telemetry {
         sender: Sender,
         has_errors: bool,
         duration: Duration,
+        trace_request: Option<TraceRequest>,
     ) {
         let metrics = if let Some(usage_reporting) = context
             .get::<_, UsageReporting>(USAGE_REPORTING)
@@ -788,6 +1155,22 @@ impl Telemetry {
                     ..Default::default()
                 }
             } else {
+                let client_name: String = context
+                    .get(CLIENT_NAME)
+                    .unwrap_or_default()
+                    .unwrap_or_default();
+                let client_version: String = context
+                    .get(CLIENT_VERSION)
+                    .unwrap_or_default()
+                    .unwrap_or_default();
+                let trace = trace_request.map(|trace_request| {
+                    trace_request.into_trace(
+                        duration,
+                        client_name.clone(),
+                        client_version.clone(),
+                        persisted_query_hit.unwrap_or_default(),
+                    )
+                });
                 metrics::apollo::studio::SingleReport {
                     operation_count,
                     traces_and_stats: HashMap::from([(
@@ -795,14 +1178,8 @@ impl Telemetry {
                         SingleTracesAndStats {
                             stats_with_context: SingleContextualizedStats {
                                 context: StatsContext {
-                                    client_name: context
-                                        .get(CLIENT_NAME)
-                                        .unwrap_or_default()
-                                        .unwrap_or_default(),
-                                    client_version: context
-                                        .get(CLIENT_VERSION)
-                                        .unwrap_or_default()
-                                        .unwrap_or_default(),
+                                    client_name,
+                                    client_version,
                                 },
                                 query_latency_stats: SingleQueryLatencyStats {
                                     latency: duration,
@@ -817,6 +1194,7 @@ impl Telemetry {
                                 .into_iter()
                                 .map(|(k, v)| (k, convert(v)))
                                 .collect(),
+                            trace,
                         },
                     )]),
                 }
@@ -831,6 +1209,56 @@ impl Telemetry {
         sender.send(metrics);
     }
 
+    /// Record the size of the data held by a request, once its last response part is sent.
+    fn record_request_size(metrics: &BasicMetrics, context: &Context) -> RequestSize {
+        let attributes = Self::metric_attributes(context);
+        let size = RequestSize::from_context(context);
+        metrics
+            .http_request_size_bytes
+            .record(size.total(), &attributes);
+        ::tracing::debug!(
+            request_bytes = size.request,
+            subgraph_response_bytes = size.subgraph_responses,
+            response_bytes = size.response,
+            "size of the request"
+        );
+        if context
+            .get::<_, bool>(REQUEST_BYTES_EXCEEDED_CONTEXT_KEY)
+            .ok()
+            .flatten()
+            .unwrap_or_default()
+        {
+            metrics
+                .http_requests_size_limit_exceeded_total
+                .add(1, &attributes);
+        }
+        size
+    }
+
+    /// Log an operation that took longer than the threshold of the slow operations log.
+    fn log_slow_operation(
+        slow_operations: &SlowOperations,
+        operation_name: Option<&str>,
+        context: &Context,
+        duration: Duration,
+        size: RequestSize,
+    ) {
+        if duration < slow_operations.threshold {
+            return;
+        }
+        let client_name: Option<String> = context.get(CLIENT_NAME).ok().flatten();
+        ::tracing::warn!(
+            target: "apollo_router::slow_operations",
+            operation_name = operation_name.unwrap_or_default(),
+            client_name = client_name.as_deref().unwrap_or_default(),
+            duration_ms = duration.as_millis() as u64,
+            request_bytes = size.request,
+            subgraph_response_bytes = size.subgraph_responses,
+            response_bytes = size.response,
+            "slow operation"
+        );
+    }
+
     async fn update_metrics(
         config: Arc<Conf>,
         context: Context,
@@ -838,17 +1266,17 @@ impl Telemetry {
         result: Result<SupergraphResponse, BoxError>,
         request_duration: Duration,
     ) -> Result<SupergraphResponse, BoxError> {
-        let mut metric_attrs = context
-            .get::<_, HashMap<String, String>>(ATTRIBUTES)
+        let mut metric_attrs = Self::metric_attributes(&context);
+        if context
+            .get::<_, bool>(DEADLINE_EXCEEDED_CONTEXT_KEY)
             .ok()
             .flatten()
-            .map(|attrs| {
-                attrs
-                    .into_iter()
-                    .map(|(attr_name, attr_value)| KeyValue::new(attr_name, attr_value))
-                    .collect::<Vec<KeyValue>>()
-            })
-            .unwrap_or_default();
+            .unwrap_or_default()
+        {
+            metrics
+                .http_requests_deadline_exceeded_total
+                .add(1, &metric_attrs);
+        }
         let res = match result {
             Ok(response) => {
                 metric_attrs.push(KeyValue::new(
@@ -884,46 +1312,76 @@ impl Telemetry {
                 Err(err)
             }
         };
-        metrics
-            .http_requests_duration
-            .record(request_duration.as_secs_f64(), &metric_attrs);
+        metrics.record_duration(request_duration, &metric_attrs);
 
         res
     }
 
-    fn populate_context(config: Arc<Conf>, req: &SupergraphRequest) {
-        let apollo_config = config.apollo.clone().unwrap_or_default();
-        let context = &req.context;
-        let http_request = &req.originating_request;
-        let headers = http_request.headers();
-        let client_name_header = &apollo_config.client_name_header;
-        let client_version_header = &apollo_config.client_version_header;
-        let _ = context.insert(
-            CLIENT_NAME,
-            headers
-                .get(client_name_header)
-                .cloned()
-                .unwrap_or_else(|| HeaderValue::from_static(""))
-                .to_str()
-                .unwrap_or_default()
-                .to_string(),
-        );
-        let _ = context.insert(
-            CLIENT_VERSION,
+    /// The attributes of the metrics of a request, populated in its context.
+    fn metric_attributes(context: &Context) -> Vec<KeyValue> {
+        context
+            .get::<_, HashMap<String, String>>(ATTRIBUTES)
+            .ok()
+            .flatten()
+            .map(|attrs| {
+                attrs
+                    .into_iter()
+                    .map(|(attr_name, attr_value)| KeyValue::new(attr_name, attr_value))
+                    .collect::<Vec<KeyValue>>()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The name and version of the client identified for a request, or else read from the client
+    /// awareness headers.
+    fn client(
+        req: &SupergraphRequest,
+        client_name_header: &HeaderName,
+        client_version_header: &HeaderName,
+    ) -> (String, String) {
+        let client = ClientInfo::from_context(&req.context);
+        let headers = req.originating_request.headers();
+        let header = |name: &HeaderName| {
             headers
-                .get(client_version_header)
-                .cloned()
-                .unwrap_or_else(|| HeaderValue::from_static(""))
-                .to_str()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
                 .unwrap_or_default()
-                .to_string(),
+                .to_string()
+        };
+        match client.name {
+            Some(name) => (name, client.version.unwrap_or_default()),
+            None => (header(client_name_header), header(client_version_header)),
+        }
+    }
+
+    /// Keep the client and the metric attributes of a request in its context, scrubbed as they
+    /// are sent to Studio and to the metrics exporters.
+    fn populate_context(
+        config: Arc<Conf>,
+        operation_attributes: &OperationAttributes,
+        scrubber: Option<&Scrubber>,
+        req: &SupergraphRequest,
+    ) {
+        let apollo_config = config.apollo.clone().unwrap_or_default();
+        let context = &req.context;
+        let scrub = |value: String| match scrubber {
+            Some(scrubber) => scrubber.scrub(&value).into_owned(),
+            None => value,
+        };
+        let (client_name, client_version) = Self::client(
+            req,
+            &apollo_config.client_name_header,
+            &apollo_config.client_version_header,
         );
+        let (client_name, client_version) = (scrub(client_name), scrub(client_version));
         if let Some(metrics_conf) = &config.metrics {
             // List of custom attributes for metrics
             let mut attributes: HashMap<String, String> = HashMap::new();
-            if let Some(operation_name) = &req.originating_request.body().operation_name {
-                attributes.insert("operation_name".to_string(), operation_name.clone());
-            }
+            attributes.extend(operation_attributes.get_attributes(
+                req.originating_request.body().operation_name.as_deref(),
+                &client_name,
+                &client_version,
+            ));
 
             if let Some(router_attributes_conf) = metrics_conf
                 .common
@@ -931,15 +1389,110 @@ impl Telemetry {
                 .and_then(|c| c.attributes.as_ref())
                 .and_then(|a| a.router.as_ref())
             {
-                attributes.extend(
-                    router_attributes_conf
-                        .get_attributes_from_request(headers, req.originating_request.body()),
-                );
+                attributes.extend(router_attributes_conf.get_attributes_from_request(
+                    req.originating_request.headers(),
+                    req.originating_request.body(),
+                ));
                 attributes.extend(router_attributes_conf.get_attributes_from_context(context));
             }
+            if scrubber.is_some() {
+                attributes = attributes
+                    .into_iter()
+                    .map(|(name, value)| (name, scrub(value)))
+                    .collect();
+            }
 
             let _ = context.insert(ATTRIBUTES, attributes);
         }
+        let _ = context.insert(CLIENT_NAME, client_name);
+        let _ = context.insert(CLIENT_VERSION, client_version);
+    }
+}
+
+/// Counts a request in a gauge of its stage until it is dropped.
+struct StageGuard {
+    gauge: AggregateUpDownCounter<i64>,
+    attributes: Vec<KeyValue>,
+}
+
+impl StageGuard {
+    fn new(
+        gauge: &AggregateUpDownCounter<i64>,
+        stage: &'static str,
+        mut attributes: Vec<KeyValue>,
+    ) -> Self {
+        attributes.push(KeyValue::new("stage", stage));
+        gauge.add(1, &attributes);
+        StageGuard {
+            gauge: gauge.clone(),
+            attributes,
+        }
+    }
+}
+
+impl Drop for StageGuard {
+    fn drop(&mut self) {
+        self.gauge.add(-1, &self.attributes);
+    }
+}
+
+/// Counts a request in the requests queued in its stage, from the telemetry layer of the stage to
+/// its service, e.g. while it waits in buffers or for a concurrency limit.
+///
+/// Added to the extensions of the HTTP request of the stage, and removed by the service of the
+/// stage when it gets the request. A request rejected or cancelled before is dropped with it.
+pub(crate) struct Queued(StageGuard);
+
+impl Queued {
+    fn new(metrics: &BasicMetrics, stage: &'static str, attributes: Vec<KeyValue>) -> Self {
+        Queued(StageGuard::new(
+            &metrics.http_requests_queued,
+            stage,
+            attributes,
+        ))
+    }
+}
+
+/// Counts the request as cancelled if it is dropped before its last response was produced,
+/// which happens when the client disconnects, or as panicked if it is dropped by a panic.
+///
+/// Requests dropped because the router timeout elapsed are not cancellations.
+struct CancellationGuard {
+    metrics: BasicMetrics,
+    context: Context,
+    completed: bool,
+}
+
+impl CancellationGuard {
+    fn new(metrics: BasicMetrics, context: Context) -> Self {
+        CancellationGuard {
+            metrics,
+            context,
+            completed: false,
+        }
+    }
+
+    fn completed(&mut self) {
+        self.completed = true;
+    }
+}
+
+impl Drop for CancellationGuard {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.metrics.http_requests_panicked_total.add(1, &[]);
+        } else if !self.completed {
+            let timed_out = self
+                .context
+                .get::<_, bool>(TIMED_OUT_CONTEXT_KEY)
+                .ok()
+                .flatten()
+                .unwrap_or_default();
+            if !timed_out {
+                ::tracing::debug!("request cancelled before its response was sent");
+                self.metrics.http_requests_cancelled_total.add(1, &[]);
+            }
+        }
     }
 }
 
@@ -984,15 +1537,18 @@ register_plugin!("apollo", "telemetry", Telemetry);
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
+    use std::time::Duration;
 
     use http::StatusCode;
     use serde_json::Value;
     use serde_json_bytes::json;
     use serde_json_bytes::ByteString;
     use tower::util::BoxService;
+    use tower::BoxError;
     use tower::Service;
     use tower::ServiceExt;
 
+    use super::Queued;
     use crate::error::FetchError;
     use crate::graphql::Error;
     use crate::graphql::Request;
@@ -1001,8 +1557,10 @@ mod tests {
     use crate::plugin::test::MockSubgraphService;
     use crate::plugin::test::MockSupergraphService;
     use crate::plugin::DynPlugin;
+    use crate::plugins::traffic_shaping::TIMED_OUT_CONTEXT_KEY;
     use crate::services::SubgraphRequest;
     use crate::services::SubgraphResponse;
+    use crate::Context;
     use crate::SupergraphRequest;
     use crate::SupergraphResponse;
 
@@ -1187,6 +1745,8 @@ mod tests {
             .expect_call()
             .times(1)
             .returning(move |req: SubgraphRequest| {
+                // the request is queued until it reaches the subgraph service
+                assert!(req.subgraph_request.extensions().get::<Queued>().is_some());
                 let mut extension = Object::new();
                 extension.insert(
                     serde_json_bytes::ByteString::from("status"),
@@ -1399,7 +1959,7 @@ mod tests {
         assert_eq!(resp.status(), StatusCode::OK);
         let body = hyper::body::to_bytes(resp.body_mut()).await.unwrap();
         let prom_metrics = String::from_utf8_lossy(&body);
-        assert!(prom_metrics.contains(r#"http_requests_error_total{message="cannot contact the subgraph",service_name="apollo-router",subgraph="my_subgraph_name_error",subgraph_error_extended_type="SubrequestHttpError"} 1"#));
+        assert!(prom_metrics.contains(r#"http_requests_error_total{error_kind="other",message="cannot contact the subgraph",service_name="apollo-router",subgraph="my_subgraph_name_error",subgraph_error_extended_type="SubrequestHttpError"} 1"#));
         assert!(prom_metrics.contains(r#"http_requests_total{another_test="my_default_value",my_value="2",myname="label_value",renamed_value="my_value_set",service_name="apollo-router",status="200",x_custom="coming_from_header"} 1"#));
         assert!(prom_metrics.contains(r#"http_request_duration_seconds_count{another_test="my_default_value",my_value="2",myname="label_value",renamed_value="my_value_set",service_name="apollo-router",status="200",x_custom="coming_from_header"}"#));
         assert!(prom_metrics.contains(r#"http_request_duration_seconds_bucket{another_test="my_default_value",my_value="2",myname="label_value",renamed_value="my_value_set",service_name="apollo-router",status="200",x_custom="coming_from_header",le="0.001"}"#));
@@ -1415,6 +1975,145 @@ mod tests {
         assert!(prom_metrics.contains(r#"http_request_duration_seconds_bucket{another_test="my_default_value",my_value="2",myname="label_value",renamed_value="my_value_set",service_name="apollo-router",status="200",x_custom="coming_from_header",le="+Inf"}"#));
         assert!(prom_metrics.contains(r#"http_request_duration_seconds_count{another_test="my_default_value",my_value="2",myname="label_value",renamed_value="my_value_set",service_name="apollo-router",status="200",x_custom="coming_from_header"}"#));
         assert!(prom_metrics.contains(r#"http_request_duration_seconds_sum{another_test="my_default_value",my_value="2",myname="label_value",renamed_value="my_value_set",service_name="apollo-router",status="200",x_custom="coming_from_header"}"#));
-        assert!(prom_metrics.contains(r#"http_request_duration_seconds_bucket{error="INTERNAL_SERVER_ERROR",my_key="my_custom_attribute_from_context",query_from_request="query { test }",service_name="apollo-router",status="200",subgraph="my_subgraph_name",unknown_data="default_value",le="1"}"#));
+        assert!(prom_metrics.contains(r#"http_request_duration_seconds_bucket{error="INTERNAL_SERVER_ERROR",error_kind="graphql",my_key="my_custom_attribute_from_context",query_from_request="query { test }",service_name="apollo-router",status="200",subgraph="my_subgraph_name",unknown_data="default_value",le="1"}"#));
+    }
+
+    async fn prometheus_plugin() -> Box<dyn DynPlugin> {
+        crate::plugin::plugins()
+            .get("apollo.telemetry")
+            .expect("Plugin not found")
+            .create_instance(
+                &serde_json::json!({
+                    "apollo": {"schema_id": "abc"},
+                    "metrics": {"prometheus": {"enabled": true}}
+                }),
+                Default::default(),
+            )
+            .await
+            .unwrap()
+    }
+
+    /// The value of the `http_requests_cancelled_total` counter, if it was incremented.
+    async fn cancelled_total(plugin: &dyn DynPlugin) -> Option<String> {
+        let request =
+            http::Request::get("http://localhost:4000/plugins/apollo.telemetry/prometheus")
+                .body(Default::default())
+                .unwrap();
+        let handler = plugin.custom_endpoint().unwrap();
+        let mut response = handler.oneshot(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.body_mut()).await.unwrap();
+        String::from_utf8_lossy(&body)
+            .lines()
+            .find(|line| line.starts_with("http_requests_cancelled_total"))
+            .and_then(|line| line.rsplit(' ').next())
+            .map(str::to_string)
+    }
+
+    fn pending_service() -> BoxService<SupergraphRequest, SupergraphResponse, BoxError> {
+        BoxService::new(tower::service_fn(|_request: SupergraphRequest| {
+            futures::future::pending::<Result<SupergraphResponse, BoxError>>()
+        }))
+    }
+
+    fn responding_service() -> BoxService<SupergraphRequest, SupergraphResponse, BoxError> {
+        let mut mock_service = MockSupergraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .returning(|req: SupergraphRequest| {
+                Ok(SupergraphResponse::fake_builder()
+                    .context(req.context)
+                    .build()
+                    .unwrap())
+            });
+        BoxService::new(mock_service)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn it_counts_the_requests_cancelled_by_the_client() {
+        let plugin = prometheus_plugin().await;
+
+        // the client disconnects while the request is executed
+        let mut response = Box::pin(
+            plugin
+                .supergraph_service(pending_service())
+                .oneshot(SupergraphRequest::fake_builder().build().unwrap()),
+        );
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), &mut response)
+                .await
+                .is_err()
+        );
+        drop(response);
+
+        // the client disconnects before reading the response
+        let response = plugin
+            .supergraph_service(responding_service())
+            .oneshot(SupergraphRequest::fake_builder().build().unwrap())
+            .await
+            .unwrap();
+        drop(response);
+
+        assert_eq!(cancelled_total(&*plugin).await.as_deref(), Some("2"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn it_does_not_count_the_completed_or_timed_out_requests_as_cancelled() {
+        let plugin = prometheus_plugin().await;
+
+        // the whole response is read
+        let mut response = plugin
+            .supergraph_service(responding_service())
+            .oneshot(SupergraphRequest::fake_builder().build().unwrap())
+            .await
+            .unwrap();
+        assert!(response.next_response().await.is_some());
+        drop(response);
+
+        // the router timeout elapses while the request is executed
+        let context = Context::new();
+        let mut response = Box::pin(
+            plugin.supergraph_service(pending_service()).oneshot(
+                SupergraphRequest::fake_builder()
+                    .context(context.clone())
+                    .build()
+                    .unwrap(),
+            ),
+        );
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), &mut response)
+                .await
+                .is_err()
+        );
+        // the timeout layer marks the request before dropping it
+        context.insert(TIMED_OUT_CONTEXT_KEY, true).unwrap();
+        drop(response);
+
+        assert_eq!(cancelled_total(&*plugin).await, None);
+    }
+
+    #[test]
+    fn deterministic_ids_are_sequential() {
+        use opentelemetry::trace::IdGenerator;
+        use opentelemetry::trace::SpanId;
+        use opentelemetry::trace::TraceId;
+
+        let generator = super::config::DeterministicIdGenerator::default();
+        assert_eq!(
+            generator.new_trace_id(),
+            TraceId::from_bytes(1u128.to_be_bytes())
+        );
+        assert_eq!(
+            generator.new_trace_id(),
+            TraceId::from_bytes(2u128.to_be_bytes())
+        );
+        assert_eq!(
+            generator.new_span_id(),
+            SpanId::from_bytes(1u64.to_be_bytes())
+        );
+        assert_eq!(
+            generator.new_span_id(),
+            SpanId::from_bytes(2u64.to_be_bytes())
+        );
     }
 }
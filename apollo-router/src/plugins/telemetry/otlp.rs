@@ -20,6 +20,7 @@ use url::Url;
 
 use crate::configuration::ConfigurationError;
 use crate::plugins::telemetry::config::GenericWith;
+use crate::plugins::telemetry::disk_buffer::DiskBufferConfig;
 use crate::plugins::telemetry::tracing::parse_url_for_endpoint;
 
 #[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
@@ -35,6 +36,11 @@ pub(crate) struct Config {
     pub(crate) timeout: Option<Duration>,
     pub(crate) grpc: Option<GrpcExporter>,
     pub(crate) http: Option<HttpExporter>,
+
+    /// Keep the spans that could not be exported on disk, to export them once the collector is
+    /// reachable again, including after a restart. Only supported by the trace exporter.
+    #[serde(default)]
+    pub(crate) disk_buffer: Option<DiskBufferConfig>,
 }
 
 impl Config {
@@ -0,0 +1,491 @@
+//! Scrubbing of the personal data emitted by the telemetry.
+//!
+//! The data matching the configured patterns is replaced in the attributes and events of the
+//! spans, in the logs written to the standard output or exported, and in the traces sent to
+//! Studio. The configured variables are replaced in the traces sent to Studio before the patterns
+//! are applied to the other variables.
+//!
+//! The rules belong to a pipeline and are only used by the layers of the router subscriber once
+//! it is started, so the logs of a running pipeline are not scrubbed with rules it did not load.
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::io;
+use std::io::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use once_cell::sync::Lazy;
+use opentelemetry::KeyValue;
+use regex::NoExpand;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tower::BoxError;
+use tracing::span::Id;
+use tracing::Subscriber;
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::json_ext::Object;
+use crate::json_ext::Value;
+
+/// The handle shared by the subscribers of the router, which outlive the pipelines.
+static ROUTER_HANDLE: Lazy<ScrubbingHandle> = Lazy::new(ScrubbingHandle::default);
+/// Identifiers of the handles, so that the cache of a thread never mixes them up.
+static NEXT_HANDLE_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static CACHED: RefCell<Cached> = RefCell::new(Cached::default());
+}
+
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+/// Scrubbing of the personal data emitted by the telemetry
+pub(crate) struct Config {
+    /// Regular expressions matching the data to scrub, e.g. emails or authentication tokens
+    #[serde(default)]
+    pub(crate) patterns: Vec<String>,
+    /// Variables scrubbed in the traces sent to Studio, as paths like `$.input.password`. `*`
+    /// matches any field, and lists are traversed
+    #[serde(default)]
+    pub(crate) variables: Vec<String>,
+    /// Text replacing the scrubbed data
+    #[serde(default = "default_replacement")]
+    pub(crate) replacement: String,
+}
+
+fn default_replacement() -> String {
+    "[REDACTED]".to_string()
+}
+
+/// The compiled scrubbing rules of a configuration.
+#[derive(Debug)]
+pub(crate) struct Scrubber {
+    patterns: Vec<Regex>,
+    variables: Vec<Vec<String>>,
+    replacement: String,
+}
+
+impl Scrubber {
+    pub(crate) fn new(config: &Config) -> Result<Self, BoxError> {
+        let patterns = config
+            .patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern)
+                    .map_err(|e| format!("invalid scrubbing pattern '{}': {}", pattern, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let variables = config
+            .variables
+            .iter()
+            .map(|path| {
+                let selector: Vec<String> = path
+                    .strip_prefix("$.")
+                    .unwrap_or(path)
+                    .split('.')
+                    .map(str::to_string)
+                    .collect();
+                if selector.iter().any(String::is_empty) {
+                    return Err(format!("invalid scrubbed variable path '{}'", path));
+                }
+                Ok(selector)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            patterns,
+            variables,
+            replacement: config.replacement.clone(),
+        })
+    }
+
+    /// The text with the data matching the patterns replaced.
+    pub(crate) fn scrub<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for pattern in &self.patterns {
+            let scrubbed = match pattern.replace_all(&text, NoExpand(&self.replacement)) {
+                Cow::Owned(scrubbed) => Some(scrubbed),
+                Cow::Borrowed(_) => None,
+            };
+            if let Some(scrubbed) = scrubbed {
+                text = Cow::Owned(scrubbed);
+            }
+        }
+        text
+    }
+
+    /// Scrub the string values of OpenTelemetry attributes.
+    pub(crate) fn scrub_key_values(&self, key_values: &mut [KeyValue]) {
+        for key_value in key_values {
+            let scrubbed = match &key_value.value {
+                opentelemetry::Value::String(value) => match self.scrub(value) {
+                    Cow::Owned(scrubbed) => Some(scrubbed),
+                    Cow::Borrowed(_) => None,
+                },
+                _ => None,
+            };
+            if let Some(scrubbed) = scrubbed {
+                key_value.value = opentelemetry::Value::String(scrubbed.into());
+            }
+        }
+    }
+
+    /// Scrub the variables of a request: the configured ones are replaced, and the patterns are
+    /// applied to the strings of the others.
+    pub(crate) fn scrub_variables(&self, variables: &mut Object) {
+        for selector in &self.variables {
+            self.replace_selected_fields(variables, selector);
+        }
+        for (_, value) in variables.iter_mut() {
+            self.scrub_value(value);
+        }
+    }
+
+    fn replace_selected_fields(&self, fields: &mut Object, selector: &[String]) {
+        let (name, rest) = match selector.split_first() {
+            Some(split) => split,
+            None => return,
+        };
+        for (field_name, field) in fields.iter_mut() {
+            if name != "*" && name != field_name.as_str() {
+                continue;
+            }
+            if rest.is_empty() {
+                *field = Value::String(self.replacement.as_str().into());
+            } else {
+                self.replace_selected(field, rest);
+            }
+        }
+    }
+
+    fn replace_selected(&self, value: &mut Value, selector: &[String]) {
+        match value {
+            Value::Array(values) => {
+                for value in values {
+                    self.replace_selected(value, selector);
+                }
+            }
+            Value::Object(fields) => self.replace_selected_fields(fields, selector),
+            _ => {}
+        }
+    }
+
+    fn scrub_value(&self, value: &mut Value) {
+        match value {
+            Value::String(string) => {
+                let scrubbed = match self.scrub(string.as_str()) {
+                    Cow::Owned(scrubbed) => Some(scrubbed),
+                    Cow::Borrowed(_) => None,
+                };
+                if let Some(scrubbed) = scrubbed {
+                    *value = Value::String(scrubbed.into());
+                }
+            }
+            Value::Array(values) => {
+                for value in values {
+                    self.scrub_value(value);
+                }
+            }
+            Value::Object(fields) => {
+                for (_, field) in fields.iter_mut() {
+                    self.scrub_value(field);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Handle of the layers of a subscriber, to change the rules they scrub with.
+///
+/// The rules are read from a cache of the current thread, refreshed when the generation of the
+/// handle changes, so that writing a log line does not take a lock.
+#[derive(Clone, Debug)]
+pub(crate) struct ScrubbingHandle(Arc<HandleInner>);
+
+#[derive(Debug)]
+struct HandleInner {
+    id: u64,
+    generation: AtomicU64,
+    scrubber: RwLock<Option<Arc<Scrubber>>>,
+}
+
+#[derive(Default)]
+struct Cached {
+    handle: u64,
+    generation: u64,
+    scrubber: Option<Arc<Scrubber>>,
+}
+
+impl Default for ScrubbingHandle {
+    fn default() -> Self {
+        ScrubbingHandle(Arc::new(HandleInner {
+            id: NEXT_HANDLE_ID.fetch_add(1, Ordering::Relaxed),
+            generation: AtomicU64::new(0),
+            scrubber: RwLock::new(None),
+        }))
+    }
+}
+
+impl ScrubbingHandle {
+    /// The handle of the subscribers of the router.
+    pub(crate) fn router() -> Self {
+        ROUTER_HANDLE.clone()
+    }
+
+    /// Scrub with the rules of the pipeline being activated, or stop scrubbing. Returns the rules
+    /// of the previous pipeline, to restore them if the activation is rolled back.
+    pub(crate) fn activate(&self, scrubber: Option<&Arc<Scrubber>>) -> Option<Arc<Scrubber>> {
+        let previous = std::mem::replace(
+            &mut *self.0.scrubber.write().expect("lock poisoned"),
+            scrubber.cloned(),
+        );
+        // bumped after the rules are written, so a cache refreshed before still refreshes again
+        self.0.generation.fetch_add(1, Ordering::Release);
+        previous
+    }
+
+    /// The rules of the active pipeline.
+    pub(crate) fn current(&self) -> Option<Arc<Scrubber>> {
+        let generation = self.0.generation.load(Ordering::Acquire);
+        CACHED.with(|cached| {
+            let mut cached = cached.borrow_mut();
+            if cached.handle != self.0.id || cached.generation != generation {
+                *cached = Cached {
+                    handle: self.0.id,
+                    generation,
+                    scrubber: self.0.scrubber.read().expect("lock poisoned").clone(),
+                };
+            }
+            cached.scrubber.clone()
+        })
+    }
+}
+
+/// Layer of the router subscriber scrubbing the spans before they are exported.
+///
+/// It must be added before the OpenTelemetry layer, which exports the spans when they close.
+pub(crate) struct ScrubbingLayer {
+    handle: ScrubbingHandle,
+}
+
+impl ScrubbingLayer {
+    pub(crate) fn new(handle: ScrubbingHandle) -> Self {
+        ScrubbingLayer { handle }
+    }
+}
+
+impl<S> Layer<S> for ScrubbingLayer
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let scrubber = match self.handle.current() {
+            Some(scrubber) => scrubber,
+            None => return,
+        };
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut extensions = span.extensions_mut();
+        let data = match extensions.get_mut::<OtelData>() {
+            Some(data) => data,
+            None => return,
+        };
+        if let Some(attributes) = data.builder.attributes.as_mut() {
+            scrubber.scrub_key_values(attributes);
+        }
+        if let Some(events) = data.builder.events.as_mut() {
+            for event in events {
+                event.name = scrubber.scrub(&event.name).into_owned().into();
+                scrubber.scrub_key_values(&mut event.attributes);
+            }
+        }
+    }
+}
+
+/// The standard output of the logs, scrubbed with the rules of the active pipeline.
+#[derive(Debug, Clone)]
+pub(crate) struct ScrubbedStdout {
+    handle: ScrubbingHandle,
+}
+
+impl ScrubbedStdout {
+    pub(crate) fn new(handle: ScrubbingHandle) -> Self {
+        ScrubbedStdout { handle }
+    }
+}
+
+impl<'a> MakeWriter<'a> for ScrubbedStdout {
+    type Writer = ScrubbedStdoutWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        ScrubbedStdoutWriter {
+            buffer: Vec::new(),
+            scrubber: self.handle.current(),
+        }
+    }
+}
+
+/// Writer of a log line, scrubbed and written to the standard output once complete.
+pub(crate) struct ScrubbedStdoutWriter {
+    buffer: Vec<u8>,
+    scrubber: Option<Arc<Scrubber>>,
+}
+
+impl Write for ScrubbedStdoutWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ScrubbedStdoutWriter {
+    fn drop(&mut self) {
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        let _ = match &self.scrubber {
+            Some(scrubber) => stdout.write_all(
+                scrubber
+                    .scrub(&String::from_utf8_lossy(&self.buffer))
+                    .as_bytes(),
+            ),
+            None => stdout.write_all(&self.buffer),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use opentelemetry::trace::TracerProvider;
+    use serde_json_bytes::json;
+    use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
+
+    use super::*;
+
+    fn scrubber() -> Scrubber {
+        Scrubber::new(&Config {
+            patterns: vec![
+                r"[\w.+-]+@[\w-]+\.[\w.]+".to_string(),
+                r"Bearer \S+".to_string(),
+            ],
+            variables: vec!["$.input.password".to_string(), "users.*.phone".to_string()],
+            replacement: default_replacement(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn it_scrubs_the_patterns() {
+        let scrubber = scrubber();
+        assert_eq!(
+            scrubber.scrub("user ada@example.com sent Bearer abc.def"),
+            "user [REDACTED] sent [REDACTED]"
+        );
+        assert!(matches!(scrubber.scrub("nothing here"), Cow::Borrowed(_)));
+
+        assert!(Scrubber::new(&Config {
+            patterns: vec!["(".to_string()],
+            ..Default::default()
+        })
+        .is_err());
+        assert!(Scrubber::new(&Config {
+            variables: vec!["$.input..password".to_string()],
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn it_scrubs_the_variables() {
+        let mut variables = json!({
+            "input": { "name": "Ada", "password": "secret" },
+            "users": [
+                { "contact": { "phone": "555-0100", "email": "ada@example.com" } },
+                { "contact": { "phone": "555-0101" } }
+            ]
+        })
+        .as_object()
+        .unwrap()
+        .clone();
+        scrubber().scrub_variables(&mut variables);
+        assert_eq!(
+            Value::Object(variables),
+            json!({
+                "input": { "name": "Ada", "password": "[REDACTED]" },
+                "users": [
+                    { "contact": { "phone": "[REDACTED]", "email": "[REDACTED]" } },
+                    { "contact": { "phone": "[REDACTED]" } }
+                ]
+            })
+        );
+    }
+
+    /// Keeps the attributes of the spans as they are exported.
+    struct Capture(Arc<Mutex<Vec<KeyValue>>>);
+
+    impl<S> Layer<S> for Capture
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+            let span = ctx.span(&id).unwrap();
+            let extensions = span.extensions();
+            let data = extensions.get::<OtelData>().unwrap();
+            self.0
+                .lock()
+                .unwrap()
+                .extend(data.builder.attributes.clone().unwrap_or_default());
+        }
+    }
+
+    #[test]
+    fn it_scrubs_with_the_active_rules() {
+        let handle = ScrubbingHandle::default();
+        assert!(handle.current().is_none());
+        let scrubber = Arc::new(scrubber());
+        handle.activate(Some(&scrubber));
+        assert!(Arc::ptr_eq(&handle.current().unwrap(), &scrubber));
+
+        // another handle is not confused with the cached one
+        assert!(ScrubbingHandle::default().current().is_none());
+        assert!(handle.current().is_some());
+
+        handle.activate(None);
+        assert!(handle.current().is_none());
+    }
+
+    #[test]
+    fn it_scrubs_the_spans() {
+        let handle = ScrubbingHandle::default();
+        handle.activate(Some(&Arc::new(scrubber())));
+        let captured = Arc::new(Mutex::new(Vec::new()));
+        let tracer = opentelemetry::sdk::trace::TracerProvider::builder()
+            .build()
+            .tracer("test");
+        let subscriber = tracing_subscriber::registry()
+            .with(ScrubbingLayer::new(handle))
+            .with(Capture(captured.clone()))
+            .with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("request", user = "ada@example.com", id = 1).entered();
+        });
+
+        let captured = captured.lock().unwrap();
+        assert!(captured.contains(&KeyValue::new("user", "[REDACTED]")));
+        assert!(captured.contains(&KeyValue::new("id", 1_i64)));
+    }
+}
@@ -8,6 +8,7 @@ use crate::plugins::telemetry::config::Trace;
 use crate::plugins::telemetry::tracing::apollo_telemetry;
 use crate::plugins::telemetry::tracing::apollo_telemetry::SpaceportConfig;
 use crate::plugins::telemetry::tracing::apollo_telemetry::StudioGraph;
+use crate::plugins::telemetry::tracing::buffered::BufferedExporter;
 use crate::plugins::telemetry::tracing::TracingConfigurator;
 
 impl TracingConfigurator for Config {
@@ -18,6 +19,7 @@ impl TracingConfigurator for Config {
                 endpoint: Some(endpoint),
                 apollo_key: Some(key),
                 apollo_graph_ref: Some(reference),
+                disk_buffer,
                 ..
             } => {
                 tracing::debug!("configuring exporter to Spaceport");
@@ -31,7 +33,13 @@ impl TracingConfigurator for Config {
                         collector: endpoint.to_string(),
                     }))
                     .build_exporter()?;
-                builder.with_batch_exporter(exporter, opentelemetry::runtime::Tokio)
+                match disk_buffer {
+                    Some(disk_buffer) => builder.with_batch_exporter(
+                        BufferedExporter::new(exporter, disk_buffer),
+                        opentelemetry::runtime::Tokio,
+                    ),
+                    None => builder.with_batch_exporter(exporter, opentelemetry::runtime::Tokio),
+                }
             }
             _ => builder,
         })
@@ -0,0 +1,555 @@
+//! Span exporter keeping the batches it could not export on disk.
+//!
+//! The batches are written to the disk buffer when the wrapped exporter fails, and exported
+//! again by a separate task, so that the batch span processor is never held up by them.
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use opentelemetry::sdk::export::trace::ExportResult;
+use opentelemetry::sdk::export::trace::SpanData;
+use opentelemetry::sdk::export::trace::SpanExporter;
+use opentelemetry::sdk::trace::EvictedHashMap;
+use opentelemetry::sdk::trace::EvictedQueue;
+use opentelemetry::sdk::InstrumentationLibrary;
+use opentelemetry::sdk::Resource;
+use opentelemetry::trace::Event;
+use opentelemetry::trace::Link;
+use opentelemetry::trace::SpanContext;
+use opentelemetry::trace::SpanId;
+use opentelemetry::trace::SpanKind;
+use opentelemetry::trace::StatusCode;
+use opentelemetry::trace::TraceFlags;
+use opentelemetry::trace::TraceId;
+use opentelemetry::Array;
+use opentelemetry::Key;
+use opentelemetry::KeyValue;
+use opentelemetry::Value;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::plugins::telemetry::disk_buffer::DiskBuffer;
+use crate::plugins::telemetry::disk_buffer::DiskBufferConfig;
+
+/// Interval between the attempts to export the buffered batches.
+const SEND_INTERVAL: Duration = Duration::from_secs(10);
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub(crate) struct BufferedExporter<E: SpanExporter + 'static> {
+    inner: Arc<Mutex<E>>,
+    buffer: DiskBuffer,
+    task: JoinHandle<()>,
+}
+
+impl<E: SpanExporter + 'static> BufferedExporter<E> {
+    pub(crate) fn new(inner: E, config: &DiskBufferConfig) -> Self {
+        let inner = Arc::new(Mutex::new(inner));
+        let buffer = DiskBuffer::new(config);
+        let task = tokio::spawn({
+            let inner = inner.clone();
+            let buffer = buffer.clone();
+            async move {
+                let start = tokio::time::Instant::now() + SEND_INTERVAL;
+                let mut interval = tokio::time::interval_at(start, SEND_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    send_buffered(&inner, &buffer).await;
+                }
+            }
+        });
+        Self {
+            inner,
+            buffer,
+            task,
+        }
+    }
+}
+
+#[async_trait]
+impl<E: SpanExporter + 'static> SpanExporter for BufferedExporter<E> {
+    async fn export(&mut self, batch: Vec<SpanData>) -> ExportResult {
+        let retained = batch.clone();
+        let result = self.inner.lock().await.export(batch).await;
+        if let Err(error) = &result {
+            let spans: Vec<BufferedSpan> = retained.iter().map(BufferedSpan::from).collect();
+            let pushed = match serde_json::to_vec(&spans) {
+                Ok(encoded) => self.buffer.push(&encoded).await.map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            match pushed {
+                Ok(()) => {
+                    tracing::warn!(
+                        "spans kept on disk as they could not be exported: {}",
+                        error
+                    );
+                    return Ok(());
+                }
+                Err(e) => tracing::warn!("could not write the spans to the disk buffer: {}", e),
+            }
+        }
+        result
+    }
+
+    fn shutdown(&mut self) {
+        self.task.abort();
+        if let Ok(mut inner) = self.inner.try_lock() {
+            inner.shutdown();
+        }
+    }
+}
+
+impl<E: SpanExporter + 'static> Drop for BufferedExporter<E> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Export the buffered batches, oldest first, stopping at the first one that cannot be exported
+/// until the next attempt.
+async fn send_buffered<E: SpanExporter>(inner: &Mutex<E>, buffer: &DiskBuffer) {
+    loop {
+        let record = match buffer.first().await {
+            Ok(Some(record)) => record,
+            Ok(None) => return,
+            Err(e) => {
+                tracing::warn!("could not read the disk buffer: {}", e);
+                return;
+            }
+        };
+        let batch = match serde_json::from_slice::<Vec<BufferedSpan>>(&record.payload) {
+            Ok(spans) => spans.into_iter().map(SpanData::from).collect(),
+            Err(e) => {
+                tracing::warn!(
+                    "dropping a buffered record which is not a batch of spans: {}",
+                    e
+                );
+                if let Err(e) = buffer.remove(record).await {
+                    tracing::warn!("could not remove spans from the disk buffer: {}", e);
+                    return;
+                }
+                continue;
+            }
+        };
+        let exported = async { inner.lock().await.export(batch).await };
+        let exported = match tokio::time::timeout(SEND_TIMEOUT, exported).await {
+            Ok(Ok(())) => true,
+            Ok(Err(e)) => {
+                tracing::warn!("could not export the buffered spans: {}", e);
+                false
+            }
+            Err(_) => {
+                tracing::warn!("timed out exporting the buffered spans");
+                false
+            }
+        };
+        let result = if exported {
+            buffer.remove(record).await
+        } else {
+            buffer.release(record).await
+        };
+        if let Err(e) = result {
+            tracing::warn!("could not update the disk buffer: {}", e);
+        }
+        if !exported {
+            return;
+        }
+    }
+}
+
+/// A span as it is written to the disk buffer.
+#[derive(Debug, Serialize, Deserialize)]
+struct BufferedSpan {
+    trace_id: String,
+    span_id: String,
+    trace_flags: u8,
+    is_remote: bool,
+    trace_state: String,
+    parent_span_id: String,
+    span_kind: BufferedSpanKind,
+    name: String,
+    start_time: SystemTime,
+    end_time: SystemTime,
+    attributes: Vec<BufferedKeyValue>,
+    events: Vec<BufferedEvent>,
+    links: Vec<BufferedLink>,
+    status_code: BufferedStatusCode,
+    status_message: String,
+    resource: Option<Vec<BufferedKeyValue>>,
+    instrumentation_name: String,
+    instrumentation_version: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BufferedEvent {
+    name: String,
+    timestamp: SystemTime,
+    attributes: Vec<BufferedKeyValue>,
+    dropped_attributes_count: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BufferedLink {
+    trace_id: String,
+    span_id: String,
+    trace_flags: u8,
+    is_remote: bool,
+    trace_state: String,
+    attributes: Vec<BufferedKeyValue>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum BufferedSpanKind {
+    Client,
+    Server,
+    Producer,
+    Consumer,
+    Internal,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum BufferedStatusCode {
+    Unset,
+    Ok,
+    Error,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BufferedKeyValue {
+    key: String,
+    value: BufferedValue,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum BufferedValue {
+    Bool(bool),
+    I64(i64),
+    F64(f64),
+    String(String),
+    BoolArray(Vec<bool>),
+    I64Array(Vec<i64>),
+    F64Array(Vec<f64>),
+    StringArray(Vec<String>),
+}
+
+impl From<&SpanData> for BufferedSpan {
+    fn from(span: &SpanData) -> Self {
+        let context = &span.span_context;
+        Self {
+            trace_id: format!("{:032x}", context.trace_id()),
+            span_id: format!("{:016x}", context.span_id()),
+            trace_flags: context.trace_flags().to_u8(),
+            is_remote: context.is_remote(),
+            trace_state: context.trace_state().header(),
+            parent_span_id: format!("{:016x}", span.parent_span_id),
+            span_kind: match span.span_kind {
+                SpanKind::Client => BufferedSpanKind::Client,
+                SpanKind::Server => BufferedSpanKind::Server,
+                SpanKind::Producer => BufferedSpanKind::Producer,
+                SpanKind::Consumer => BufferedSpanKind::Consumer,
+                SpanKind::Internal => BufferedSpanKind::Internal,
+            },
+            name: span.name.to_string(),
+            start_time: span.start_time,
+            end_time: span.end_time,
+            attributes: span
+                .attributes
+                .iter()
+                .map(|(key, value)| BufferedKeyValue::new(key, value))
+                .collect(),
+            events: span
+                .events
+                .iter()
+                .map(|event| BufferedEvent {
+                    name: event.name.to_string(),
+                    timestamp: event.timestamp,
+                    attributes: event
+                        .attributes
+                        .iter()
+                        .map(BufferedKeyValue::from)
+                        .collect(),
+                    dropped_attributes_count: event.dropped_attributes_count,
+                })
+                .collect(),
+            links: span
+                .links
+                .iter()
+                .map(|link| BufferedLink {
+                    trace_id: format!("{:032x}", link.span_context().trace_id()),
+                    span_id: format!("{:016x}", link.span_context().span_id()),
+                    trace_flags: link.span_context().trace_flags().to_u8(),
+                    is_remote: link.span_context().is_remote(),
+                    trace_state: link.span_context().trace_state().header(),
+                    attributes: link
+                        .attributes()
+                        .iter()
+                        .map(BufferedKeyValue::from)
+                        .collect(),
+                })
+                .collect(),
+            status_code: match span.status_code {
+                StatusCode::Unset => BufferedStatusCode::Unset,
+                StatusCode::Ok => BufferedStatusCode::Ok,
+                StatusCode::Error => BufferedStatusCode::Error,
+            },
+            status_message: span.status_message.to_string(),
+            resource: span.resource.as_ref().map(|resource| {
+                resource
+                    .iter()
+                    .map(|(key, value)| BufferedKeyValue::new(key, value))
+                    .collect()
+            }),
+            instrumentation_name: span.instrumentation_lib.name.to_string(),
+            instrumentation_version: span
+                .instrumentation_lib
+                .version
+                .as_ref()
+                .map(|version| version.to_string()),
+        }
+    }
+}
+
+impl From<BufferedSpan> for SpanData {
+    fn from(span: BufferedSpan) -> Self {
+        let mut attributes =
+            EvictedHashMap::new(span.attributes.len() as u32, span.attributes.len());
+        for attribute in span.attributes {
+            attributes.insert(attribute.into());
+        }
+        let mut events = EvictedQueue::new(span.events.len() as u32);
+        events.append_vec(
+            &mut span
+                .events
+                .into_iter()
+                .map(|event| {
+                    Event::new(
+                        event.name,
+                        event.timestamp,
+                        event.attributes.into_iter().map(KeyValue::from).collect(),
+                        event.dropped_attributes_count,
+                    )
+                })
+                .collect(),
+        );
+        let mut links = EvictedQueue::new(span.links.len() as u32);
+        links.append_vec(
+            &mut span
+                .links
+                .into_iter()
+                .map(|link| {
+                    Link::new(
+                        span_context(
+                            &link.trace_id,
+                            &link.span_id,
+                            link.trace_flags,
+                            link.is_remote,
+                            &link.trace_state,
+                        ),
+                        link.attributes.into_iter().map(KeyValue::from).collect(),
+                    )
+                })
+                .collect(),
+        );
+        SpanData {
+            span_context: span_context(
+                &span.trace_id,
+                &span.span_id,
+                span.trace_flags,
+                span.is_remote,
+                &span.trace_state,
+            ),
+            parent_span_id: SpanId::from_hex(&span.parent_span_id).unwrap_or(SpanId::INVALID),
+            span_kind: match span.span_kind {
+                BufferedSpanKind::Client => SpanKind::Client,
+                BufferedSpanKind::Server => SpanKind::Server,
+                BufferedSpanKind::Producer => SpanKind::Producer,
+                BufferedSpanKind::Consumer => SpanKind::Consumer,
+                BufferedSpanKind::Internal => SpanKind::Internal,
+            },
+            name: span.name.into(),
+            start_time: span.start_time,
+            end_time: span.end_time,
+            attributes,
+            events,
+            links,
+            status_code: match span.status_code {
+                BufferedStatusCode::Unset => StatusCode::Unset,
+                BufferedStatusCode::Ok => StatusCode::Ok,
+                BufferedStatusCode::Error => StatusCode::Error,
+            },
+            status_message: span.status_message.into(),
+            resource: span
+                .resource
+                .map(|resource| Arc::new(Resource::new(resource.into_iter().map(KeyValue::from)))),
+            instrumentation_lib: InstrumentationLibrary::new(
+                Cow::from(span.instrumentation_name),
+                span.instrumentation_version.map(Cow::from),
+            ),
+        }
+    }
+}
+
+fn span_context(
+    trace_id: &str,
+    span_id: &str,
+    trace_flags: u8,
+    is_remote: bool,
+    trace_state: &str,
+) -> SpanContext {
+    SpanContext::new(
+        TraceId::from_hex(trace_id).unwrap_or(TraceId::INVALID),
+        SpanId::from_hex(span_id).unwrap_or(SpanId::INVALID),
+        TraceFlags::new(trace_flags),
+        is_remote,
+        trace_state.parse().unwrap_or_default(),
+    )
+}
+
+impl BufferedKeyValue {
+    fn new(key: &Key, value: &Value) -> Self {
+        Self {
+            key: key.as_str().to_string(),
+            value: match value {
+                Value::Bool(value) => BufferedValue::Bool(*value),
+                Value::I64(value) => BufferedValue::I64(*value),
+                Value::F64(value) => BufferedValue::F64(*value),
+                Value::String(value) => BufferedValue::String(value.to_string()),
+                Value::Array(Array::Bool(values)) => BufferedValue::BoolArray(values.clone()),
+                Value::Array(Array::I64(values)) => BufferedValue::I64Array(values.clone()),
+                Value::Array(Array::F64(values)) => BufferedValue::F64Array(values.clone()),
+                Value::Array(Array::String(values)) => BufferedValue::StringArray(
+                    values.iter().map(|value| value.to_string()).collect(),
+                ),
+            },
+        }
+    }
+}
+
+impl From<&KeyValue> for BufferedKeyValue {
+    fn from(key_value: &KeyValue) -> Self {
+        Self::new(&key_value.key, &key_value.value)
+    }
+}
+
+impl From<BufferedKeyValue> for KeyValue {
+    fn from(key_value: BufferedKeyValue) -> Self {
+        let value = match key_value.value {
+            BufferedValue::Bool(value) => Value::Bool(value),
+            BufferedValue::I64(value) => Value::I64(value),
+            BufferedValue::F64(value) => Value::F64(value),
+            BufferedValue::String(value) => Value::String(value.into()),
+            BufferedValue::BoolArray(values) => Value::Array(Array::Bool(values)),
+            BufferedValue::I64Array(values) => Value::Array(Array::I64(values)),
+            BufferedValue::F64Array(values) => Value::Array(Array::F64(values)),
+            BufferedValue::StringArray(values) => {
+                Value::Array(Array::String(values.into_iter().map(Cow::from).collect()))
+            }
+        };
+        KeyValue::new(key_value.key, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering;
+
+    use opentelemetry::trace::TraceError;
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Collector {
+        reachable: Arc<AtomicBool>,
+        exported: Arc<std::sync::Mutex<Vec<SpanData>>>,
+    }
+
+    #[async_trait]
+    impl SpanExporter for Collector {
+        async fn export(&mut self, batch: Vec<SpanData>) -> ExportResult {
+            if !self.reachable.load(Ordering::SeqCst) {
+                return Err(TraceError::from("the collector is unreachable"));
+            }
+            self.exported.lock().unwrap().extend(batch);
+            Ok(())
+        }
+    }
+
+    fn span() -> SpanData {
+        let mut attributes = EvictedHashMap::new(8, 8);
+        attributes.insert(KeyValue::new("graphql.operation.name", "me"));
+        attributes.insert(KeyValue::new("http.status_code", 200_i64));
+        attributes.insert(KeyValue::new(
+            "graphql.fields",
+            Value::Array(Array::String(vec!["me".into(), "name".into()])),
+        ));
+        SpanData {
+            span_context: span_context(
+                "0af7651916cd43dd8448eb211c80319c",
+                "b7ad6b7169203331",
+                1,
+                false,
+                "vendor=value",
+            ),
+            parent_span_id: SpanId::from_hex("00f067aa0ba902b7").unwrap(),
+            span_kind: SpanKind::Server,
+            name: "supergraph".into(),
+            start_time: SystemTime::UNIX_EPOCH + Duration::from_secs(1),
+            end_time: SystemTime::UNIX_EPOCH + Duration::from_secs(2),
+            attributes,
+            events: EvictedQueue::new(8),
+            links: EvictedQueue::new(8),
+            status_code: StatusCode::Error,
+            status_message: "failed".into(),
+            resource: Some(Arc::new(Resource::new(vec![KeyValue::new(
+                "service.name",
+                "router",
+            )]))),
+            instrumentation_lib: InstrumentationLibrary::new("apollo-router", None),
+        }
+    }
+
+    #[tokio::test]
+    async fn it_exports_the_buffered_spans_once_the_collector_is_reachable() {
+        let dir = tempfile::tempdir().unwrap();
+        let collector = Collector::default();
+        let reachable = collector.reachable.clone();
+        let exported = collector.exported.clone();
+        let mut exporter = BufferedExporter::new(
+            collector,
+            &DiskBufferConfig {
+                path: dir.path().to_path_buf(),
+                max_size: 1024 * 1024,
+            },
+        );
+
+        exporter.export(vec![span()]).await.unwrap();
+        assert_eq!(exporter.buffer.count().await.unwrap(), 1);
+        send_buffered(&exporter.inner, &exporter.buffer).await;
+        assert_eq!(exporter.buffer.count().await.unwrap(), 1);
+
+        reachable.store(true, Ordering::SeqCst);
+        send_buffered(&exporter.inner, &exporter.buffer).await;
+        assert_eq!(exporter.buffer.count().await.unwrap(), 0);
+        let exported = exported.lock().unwrap();
+        assert_eq!(exported.len(), 1);
+        let span = span();
+        assert_eq!(exported[0].span_context, span.span_context);
+        assert_eq!(exported[0].parent_span_id, span.parent_span_id);
+        assert_eq!(exported[0].name, span.name);
+        assert_eq!(exported[0].end_time, span.end_time);
+        assert_eq!(exported[0].status_code, span.status_code);
+        assert_eq!(
+            exported[0].attributes.get(&Key::new("graphql.fields")),
+            span.attributes.get(&Key::new("graphql.fields"))
+        );
+        assert_eq!(
+            exported[0].attributes.get(&Key::new("http.status_code")),
+            Some(&Value::I64(200))
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use opentelemetry::sdk::trace::Builder;
 use reqwest::Url;
 use schemars::JsonSchema;
@@ -5,12 +7,19 @@ use serde::Deserialize;
 use serde::Deserializer;
 use serde::Serialize;
 use tower::BoxError;
+use tracing::Span;
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Registry;
 use url::ParseError;
 
+use crate::plugin::spans::SpanCustomization;
+use crate::plugin::spans::SpanCustomizer;
 use crate::plugins::telemetry::config::Trace;
 
 pub(crate) mod apollo;
 pub(crate) mod apollo_telemetry;
+pub(crate) mod buffered;
 pub(crate) mod datadog;
 pub(crate) mod jaeger;
 pub(crate) mod otlp;
@@ -70,3 +79,109 @@ where
     let url = parse_url_for_endpoint(s).map_err(serde::de::Error::custom)?;
     Ok(AgentEndpoint::Url(url))
 }
+
+/// Create the span of a stage of the pipeline, as customized by the registered customizers.
+pub(crate) fn customized_span(
+    customizers: &[Arc<dyn SpanCustomizer>],
+    mut customization: SpanCustomization<'_>,
+    create: impl FnOnce() -> Span,
+) -> Span {
+    for customizer in customizers {
+        customizer.customize(&mut customization);
+    }
+    if customization.suppressed {
+        return Span::none();
+    }
+
+    let span = create();
+    let name = span.metadata().map(|metadata| metadata.name());
+    if name == Some(customization.name.as_str()) && customization.attributes.is_empty() {
+        return span;
+    }
+    // span names are static in tracing, the OpenTelemetry span is renamed instead
+    span.with_subscriber(|(id, dispatch)| {
+        if let Some(registry) = dispatch.downcast_ref::<Registry>() {
+            if let Some(span) = registry.span(id) {
+                if let Some(data) = span.extensions_mut().get_mut::<OtelData>() {
+                    data.builder.name = customization.name.into();
+                    data.builder
+                        .attributes
+                        .get_or_insert_with(Vec::new)
+                        .extend(customization.attributes);
+                }
+            }
+        }
+    });
+    span
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry::KeyValue;
+    use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
+
+    use super::*;
+    use crate::plugin::spans::SpanStage;
+    use crate::Context;
+
+    struct Conventions;
+
+    impl SpanCustomizer for Conventions {
+        fn customize(&self, span: &mut SpanCustomization<'_>) {
+            match span.stage() {
+                SpanStage::Execution => span.suppress(),
+                _ => {
+                    let name = format!("router.{}", span.name());
+                    span.set_name(name);
+                    if let Some(subgraph_name) = span.subgraph_name() {
+                        span.set_attribute("acme.subgraph", subgraph_name.to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    fn otel_data(span: &Span) -> Option<(String, Vec<KeyValue>)> {
+        span.with_subscriber(|(id, dispatch)| {
+            let registry = dispatch.downcast_ref::<Registry>()?;
+            let span = registry.span(id)?;
+            let extensions = span.extensions();
+            let data = extensions.get::<OtelData>()?;
+            Some((
+                data.builder.name.to_string(),
+                data.builder.attributes.clone().unwrap_or_default(),
+            ))
+        })
+        .flatten()
+    }
+
+    #[test]
+    fn it_customizes_the_spans() {
+        let tracer = opentelemetry::sdk::trace::TracerProvider::builder()
+            .build()
+            .tracer("test");
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        let customizers: Vec<Arc<dyn SpanCustomizer>> = vec![Arc::new(Conventions)];
+        let context = Context::new();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = customized_span(
+                &customizers,
+                SpanCustomization::new(SpanStage::Subgraph, "subgraph", Some("products"), &context),
+                || tracing::info_span!("subgraph"),
+            );
+            let (name, attributes) = otel_data(&span).unwrap();
+            assert_eq!(name, "router.subgraph");
+            assert!(attributes.contains(&KeyValue::new("acme.subgraph", "products")));
+
+            let span = customized_span(
+                &customizers,
+                SpanCustomization::new(SpanStage::Execution, "execution", None, &context),
+                || tracing::info_span!("execution"),
+            );
+            assert!(span.is_none());
+        });
+    }
+}
@@ -6,15 +6,20 @@ use opentelemetry_otlp::SpanExporterBuilder;
 use tower::BoxError;
 
 use crate::plugins::telemetry::config::Trace;
+use crate::plugins::telemetry::tracing::buffered::BufferedExporter;
 use crate::plugins::telemetry::tracing::TracingConfigurator;
 
 impl TracingConfigurator for super::super::otlp::Config {
     fn apply(&self, builder: Builder, _trace_config: &Trace) -> Result<Builder, BoxError> {
         tracing::debug!("configuring Otlp tracing");
         let exporter: SpanExporterBuilder = self.exporter()?;
-        Ok(builder.with_batch_exporter(
-            exporter.build_span_exporter()?,
-            opentelemetry::runtime::Tokio,
-        ))
+        let exporter = exporter.build_span_exporter()?;
+        Ok(match &self.disk_buffer {
+            Some(disk_buffer) => builder.with_batch_exporter(
+                BufferedExporter::new(exporter, disk_buffer),
+                opentelemetry::runtime::Tokio,
+            ),
+            None => builder.with_batch_exporter(exporter, opentelemetry::runtime::Tokio),
+        })
     }
 }
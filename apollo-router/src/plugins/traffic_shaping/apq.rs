@@ -0,0 +1,234 @@
+//! Automatic persisted queries towards the subgraphs. Implemented as a tower Layer.
+//!
+//! Requests are first sent with the SHA-256 hash of their query only, and sent again with their
+//! query when the subgraph does not know the hash yet, which registers it in the subgraph. Once a
+//! subgraph answers that it does not support persisted queries, its requests are sent as is.
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::task::Poll;
+
+use futures::future::BoxFuture;
+use sha2::Digest;
+use sha2::Sha256;
+use tower::BoxError;
+use tower::Layer;
+use tower::ServiceExt;
+
+use crate::graphql;
+use crate::http_ext;
+use crate::SubgraphRequest;
+use crate::SubgraphResponse;
+
+const PERSISTED_QUERY_NOT_FOUND: &str = "PERSISTED_QUERY_NOT_FOUND";
+const PERSISTED_QUERY_NOT_SUPPORTED: &str = "PERSISTED_QUERY_NOT_SUPPORTED";
+
+#[derive(Clone, Default)]
+pub(crate) struct SubgraphApqLayer {
+    unsupported: Arc<AtomicBool>,
+}
+
+impl<S> Layer<S> for SubgraphApqLayer
+where
+    S: tower::Service<SubgraphRequest, Response = SubgraphResponse, Error = BoxError> + Clone,
+{
+    type Service = SubgraphApqService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        SubgraphApqService {
+            service,
+            unsupported: self.unsupported.clone(),
+        }
+    }
+}
+
+pub(crate) struct SubgraphApqService<S> {
+    service: S,
+    unsupported: Arc<AtomicBool>,
+}
+
+/// The code of the persisted query error of a subgraph response, if any.
+fn persisted_query_error(response: &SubgraphResponse) -> Option<&'static str> {
+    response.response.body().errors.iter().find_map(|error| {
+        match error
+            .extensions
+            .get("code")
+            .and_then(|code| code.as_str())
+            .unwrap_or(error.message.as_str())
+        {
+            PERSISTED_QUERY_NOT_FOUND | "PersistedQueryNotFound" => Some(PERSISTED_QUERY_NOT_FOUND),
+            PERSISTED_QUERY_NOT_SUPPORTED | "PersistedQueryNotSupported" => {
+                Some(PERSISTED_QUERY_NOT_SUPPORTED)
+            }
+            _ => None,
+        }
+    })
+}
+
+impl<S> tower::Service<SubgraphRequest> for SubgraphApqService<S>
+where
+    S: tower::Service<SubgraphRequest, Response = SubgraphResponse, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    <S as tower::Service<SubgraphRequest>>::Future: Send + 'static,
+{
+    type Response = SubgraphResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut request: SubgraphRequest) -> Self::Future {
+        let service = self.service.clone();
+        let query = match request.subgraph_request.body().query.clone() {
+            Some(query) if !self.unsupported.load(Ordering::Relaxed) => query,
+            _ => return Box::pin(service.oneshot(request)),
+        };
+        let unsupported = self.unsupported.clone();
+
+        Box::pin(async move {
+            let persisted_query =
+                graphql::PersistedQuery::new(hex::encode(Sha256::digest(query.as_bytes())));
+            let mut hash_only = http_ext::Request::from(&request.subgraph_request).inner;
+            hash_only.body_mut().query = None;
+            hash_only.body_mut().set_persisted_query(&persisted_query);
+            let hash_only = SubgraphRequest::builder()
+                .originating_request(request.originating_request.clone())
+                .subgraph_request(hash_only)
+                .subgraph_name(request.subgraph_name.clone())
+                .operation_kind(request.operation_kind)
+                .context(request.context.clone())
+                .build();
+
+            let call = service.clone().oneshot(hash_only);
+            let response = call.await?;
+            match persisted_query_error(&response) {
+                None => return Ok(response),
+                Some(PERSISTED_QUERY_NOT_SUPPORTED) => {
+                    tracing::debug!("apq: the subgraph does not support persisted queries");
+                    unsupported.store(true, Ordering::Relaxed);
+                }
+                Some(_) => {
+                    // sending the query with its hash registers it in the subgraph
+                    request
+                        .subgraph_request
+                        .body_mut()
+                        .set_persisted_query(&persisted_query);
+                }
+            }
+            service.oneshot(request).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use serde_json_bytes::json;
+    use tower::Service;
+
+    use super::*;
+    use crate::error::Error;
+
+    /// A subgraph registering the persisted queries, or not supporting them.
+    fn subgraph(
+        supported: bool,
+        requests: Arc<Mutex<Vec<graphql::Request>>>,
+    ) -> impl tower::Service<
+        SubgraphRequest,
+        Response = SubgraphResponse,
+        Error = BoxError,
+        Future = BoxFuture<'static, Result<SubgraphResponse, BoxError>>,
+    > + Clone {
+        let registered = Arc::new(Mutex::new(Vec::new()));
+        tower::service_fn(move |request: SubgraphRequest| {
+            let body = request.subgraph_request.body().clone();
+            requests.lock().unwrap().push(body.clone());
+            let hash = body
+                .persisted_query()
+                .map(|persisted_query| persisted_query.sha256_hash);
+            let mut registered = registered.lock().unwrap();
+            let code = match (hash, body.query) {
+                (Some(_), _) if !supported => Some(PERSISTED_QUERY_NOT_SUPPORTED),
+                (Some(hash), Some(_)) => {
+                    registered.push(hash);
+                    None
+                }
+                (Some(hash), None) if !registered.contains(&hash) => {
+                    Some(PERSISTED_QUERY_NOT_FOUND)
+                }
+                _ => None,
+            };
+            let errors = code
+                .map(|code| Error {
+                    message: "persisted query error".to_string(),
+                    locations: Default::default(),
+                    path: Default::default(),
+                    extensions: serde_json_bytes::from_value(json!({ "code": code })).unwrap(),
+                })
+                .into_iter()
+                .collect();
+            Box::pin(async move {
+                Ok(SubgraphResponse::fake_builder()
+                    .data(json!({ "me": { "name": "Ada" } }))
+                    .errors(errors)
+                    .build())
+            }) as BoxFuture<'static, _>
+        })
+    }
+
+    async fn fetch(
+        service: &mut impl Service<SubgraphRequest, Response = SubgraphResponse, Error = BoxError>,
+    ) {
+        let response = service
+            .ready()
+            .await
+            .unwrap()
+            .call(
+                SubgraphRequest::fake_builder()
+                    .subgraph_request(
+                        http::Request::builder()
+                            .body(graphql::Request::builder().query("{me{name}}").build())
+                            .unwrap(),
+                    )
+                    .build(),
+            )
+            .await
+            .unwrap();
+        assert!(response.response.body().errors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_sends_the_query_hashes() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let mut service = SubgraphApqLayer::default().layer(subgraph(true, requests.clone()));
+        fetch(&mut service).await;
+        fetch(&mut service).await;
+
+        let requests = requests.lock().unwrap();
+        let queries: Vec<_> = requests.iter().map(|r| r.query.as_deref()).collect();
+        // the first hash is unknown to the subgraph, which registers the query sent next
+        assert_eq!(queries, vec![None, Some("{me{name}}"), None]);
+        assert!(requests
+            .iter()
+            .all(|request| request.extensions.contains_key("persistedQuery")));
+    }
+
+    #[tokio::test]
+    async fn it_stops_sending_the_query_hashes_when_unsupported() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let mut service = SubgraphApqLayer::default().layer(subgraph(false, requests.clone()));
+        fetch(&mut service).await;
+        fetch(&mut service).await;
+
+        let requests = requests.lock().unwrap();
+        let queries: Vec<_> = requests.iter().map(|r| r.query.as_deref()).collect();
+        assert_eq!(queries, vec![None, Some("{me{name}}"), Some("{me{name}}")]);
+        assert!(!requests[2].extensions.contains_key("persistedQuery"));
+    }
+}
@@ -0,0 +1,398 @@
+//! Stop sending requests to a failing subgraph.
+//!
+//! The circuit opens after a number of consecutive failed requests, a request failing when the
+//! subgraph returns an error or a 5xx status code, or does not respond before its timeout. The
+//! requests rejected by the router itself, when they are rate limited or shed, are neither failures
+//! nor successes. While it is open, requests are rejected right away. Once the reset timeout
+//! elapsed, a single trial request is sent to the subgraph: the circuit closes if it succeeds, and
+//! opens again otherwise. The circuit of a subgraph failing its active health
+//! checks is kept open until it passes them again. Webhooks are notified when the circuit opens and
+//! closes.
+
+use std::fmt;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::Serialize;
+use tower::BoxError;
+use tower::Layer;
+use tower::Service;
+
+use super::Overloaded;
+use super::RateLimited;
+use crate::webhooks;
+use crate::webhooks::Notifier;
+use crate::SubgraphRequest;
+use crate::SubgraphResponse;
+
+/// The error of the requests rejected while the circuit is open.
+#[derive(Debug, Default)]
+pub(crate) struct CircuitOpen;
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("the circuit breaker of the subgraph is open")
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+/// State of the circuit of a subgraph, as exposed by the admin API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CircuitState {
+    Closed,
+    /// The requests are rejected until the reset timeout elapses.
+    Open,
+    /// The next request is the trial deciding whether the circuit closes.
+    HalfOpen,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    consecutive_failures: u32,
+    /// When the circuit was opened, if it is open.
+    opened_at: Option<Instant>,
+    /// A trial request is being sent to the subgraph.
+    trial: bool,
+}
+
+/// Circuit breaker of a subgraph.
+///
+/// The state of the circuit is shared by all the services created from the same layer.
+#[derive(Debug, Clone)]
+pub(crate) struct CircuitBreakerLayer {
+    subgraph: Arc<String>,
+    state: Arc<Mutex<State>>,
+    failure_threshold: u32,
+    reset_timeout: Duration,
+    degrade: bool,
+    webhooks: Option<Notifier>,
+}
+
+impl CircuitBreakerLayer {
+    pub(crate) fn new(
+        subgraph: &str,
+        failure_threshold: NonZeroU32,
+        reset_timeout: Duration,
+        degrade: bool,
+        webhooks: Option<Notifier>,
+    ) -> Self {
+        CircuitBreakerLayer {
+            subgraph: Arc::new(subgraph.to_string()),
+            state: Default::default(),
+            failure_threshold: failure_threshold.get(),
+            reset_timeout,
+            degrade,
+            webhooks,
+        }
+    }
+
+    /// Whether the fetches to the subgraph are skipped while its circuit is open.
+    pub(crate) fn degrade(&self) -> bool {
+        self.degrade
+    }
+
+    /// Whether the circuit is open and the reset timeout has not elapsed yet.
+    pub(crate) fn is_open(&self) -> bool {
+        let state = self.state.lock().expect("lock poisoned");
+        state
+            .opened_at
+            .map(|opened_at| opened_at.elapsed() < self.reset_timeout)
+            .unwrap_or(false)
+    }
+
+    pub(crate) fn state(&self) -> CircuitState {
+        let state = self.state.lock().expect("lock poisoned");
+        match state.opened_at {
+            None => CircuitState::Closed,
+            Some(opened_at) if opened_at.elapsed() < self.reset_timeout => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Allow a request to be sent to the subgraph, unless the circuit is open.
+    fn try_acquire(&self) -> Result<Attempt, CircuitOpen> {
+        let mut state = self.state.lock().expect("lock poisoned");
+        match state.opened_at {
+            None => Ok(Attempt {
+                breaker: self.clone(),
+                trial: false,
+            }),
+            Some(opened_at) if opened_at.elapsed() >= self.reset_timeout && !state.trial => {
+                state.trial = true;
+                Ok(Attempt {
+                    breaker: self.clone(),
+                    trial: true,
+                })
+            }
+            Some(_) => Err(CircuitOpen),
+        }
+    }
+
+    fn record(&self, outcome: Outcome) {
+        let mut state = self.state.lock().expect("lock poisoned");
+        let was_open = state.opened_at.is_some();
+        match outcome {
+            Outcome::Success => *state = State::default(),
+            Outcome::Failure => {
+                state.consecutive_failures += 1;
+                if state.trial || state.consecutive_failures >= self.failure_threshold {
+                    state.opened_at = Some(Instant::now());
+                }
+                state.trial = false;
+            }
+            // the subgraph was not reached: the next request is the trial
+            Outcome::Rejected => state.trial = false,
+        }
+        let is_open = state.opened_at.is_some();
+        drop(state);
+        self.notify(was_open, is_open);
+    }
+
+    /// Open the circuit of a subgraph failing its health checks, or close it once it passes them
+    /// again.
+    pub(crate) fn set_healthy(&self, healthy: bool) {
+        let mut state = self.state.lock().expect("lock poisoned");
+        let was_open = state.opened_at.is_some();
+        if healthy {
+            *state = State::default();
+        } else {
+            state.opened_at = Some(Instant::now());
+        }
+        let is_open = state.opened_at.is_some();
+        drop(state);
+        self.notify(was_open, is_open);
+    }
+
+    fn notify(&self, was_open: bool, is_open: bool) {
+        let webhooks = match &self.webhooks {
+            Some(webhooks) => webhooks,
+            None => return,
+        };
+        let subgraph = self.subgraph.to_string();
+        match (was_open, is_open) {
+            (false, true) => webhooks.notify(webhooks::Event::CircuitOpened { subgraph }),
+            (true, false) => webhooks.notify(webhooks::Event::CircuitClosed { subgraph }),
+            _ => {}
+        }
+    }
+}
+
+/// How a request sent through the circuit completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Success,
+    /// The subgraph returned an error or a 5xx status code, or timed out.
+    Failure,
+    /// The router rate limited or shed the request.
+    Rejected,
+}
+
+impl Outcome {
+    fn of(response: &Result<SubgraphResponse, BoxError>) -> Self {
+        match response {
+            Ok(response) if response.response.status().is_server_error() => Outcome::Failure,
+            Ok(_) => Outcome::Success,
+            Err(error) if is_rejection(error.as_ref()) => Outcome::Rejected,
+            Err(_) => Outcome::Failure,
+        }
+    }
+}
+
+fn is_rejection(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut error = Some(error);
+    while let Some(e) = error {
+        if e.is::<RateLimited>()
+            || e.is::<Overloaded>()
+            || e.is::<tower::load_shed::error::Overloaded>()
+            || e.is::<CircuitOpen>()
+        {
+            return true;
+        }
+        error = e.source();
+    }
+    false
+}
+
+/// A request sent to the subgraph.
+struct Attempt {
+    breaker: CircuitBreakerLayer,
+    trial: bool,
+}
+
+impl Attempt {
+    fn complete(mut self, outcome: Outcome) {
+        self.trial = false;
+        self.breaker.record(outcome);
+    }
+}
+
+impl Drop for Attempt {
+    fn drop(&mut self) {
+        // a trial request cancelled before its response lets the next request be the trial
+        if self.trial {
+            self.breaker.state.lock().expect("lock poisoned").trial = false;
+        }
+    }
+}
+
+impl<S> Layer<S> for CircuitBreakerLayer {
+    type Service = CircuitBreaker<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        CircuitBreaker {
+            inner: service,
+            breaker: self.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CircuitBreaker<S> {
+    inner: S,
+    breaker: CircuitBreakerLayer,
+}
+
+impl<S> Service<SubgraphRequest> for CircuitBreaker<S>
+where
+    S: Service<SubgraphRequest, Response = SubgraphResponse>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = SubgraphResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<SubgraphResponse, BoxError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, request: SubgraphRequest) -> Self::Future {
+        let attempt = match self.breaker.try_acquire() {
+            Ok(attempt) => attempt,
+            Err(e) => {
+                tracing::trace!("circuit open; rejecting the request.");
+                return futures::future::ready(Err(e.into())).boxed();
+            }
+        };
+        let response = self.inner.call(request);
+        async move {
+            let response = response.await.map_err(Into::into);
+            attempt.complete(Outcome::of(&response));
+            response
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::plugin::test::MockSubgraphService;
+    use crate::plugins::traffic_shaping::Elapsed;
+
+    fn breaker(reset_timeout: Duration) -> CircuitBreakerLayer {
+        CircuitBreakerLayer::new(
+            "products",
+            NonZeroU32::new(2).unwrap(),
+            reset_timeout,
+            false,
+            None,
+        )
+    }
+
+    fn subgraph(status: StatusCode) -> MockSubgraphService {
+        let mut service = MockSubgraphService::new();
+        service
+            .expect_call()
+            .returning(move |_| Ok(SubgraphResponse::fake_builder().status_code(status).build()));
+        service
+    }
+
+    async fn call(breaker: &CircuitBreakerLayer, status: StatusCode) -> Result<(), BoxError> {
+        breaker
+            .layer(subgraph(status))
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn it_opens_after_consecutive_failures() {
+        let breaker = breaker(Duration::from_secs(60));
+
+        call(&breaker, StatusCode::BAD_GATEWAY).await.unwrap();
+        call(&breaker, StatusCode::OK).await.unwrap();
+        call(&breaker, StatusCode::BAD_GATEWAY).await.unwrap();
+        assert!(!breaker.is_open());
+        call(&breaker, StatusCode::BAD_GATEWAY).await.unwrap();
+        assert!(breaker.is_open());
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        let error = call(&breaker, StatusCode::OK).await.unwrap_err();
+        assert!(error.is::<CircuitOpen>());
+    }
+
+    #[tokio::test]
+    async fn it_closes_after_a_successful_trial() {
+        let breaker = breaker(Duration::ZERO);
+        call(&breaker, StatusCode::BAD_GATEWAY).await.unwrap();
+        call(&breaker, StatusCode::BAD_GATEWAY).await.unwrap();
+
+        // the reset timeout elapsed: a failed trial opens the circuit again
+        call(&breaker, StatusCode::BAD_GATEWAY).await.unwrap();
+        assert!(breaker.state.lock().unwrap().opened_at.is_some());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+
+        call(&breaker, StatusCode::OK).await.unwrap();
+        assert!(breaker.state.lock().unwrap().opened_at.is_none());
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    async fn fail(breaker: &CircuitBreakerLayer, error: fn() -> BoxError) {
+        let mut service = MockSubgraphService::new();
+        service.expect_call().returning(move |_| Err(error()));
+        breaker
+            .layer(service)
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn it_ignores_the_requests_rejected_by_the_router() {
+        let breaker = breaker(Duration::ZERO);
+        fail(&breaker, || RateLimited::new().into()).await;
+        fail(&breaker, || Overloaded::new().into()).await;
+        assert!(!breaker.is_open());
+        assert_eq!(breaker.state.lock().unwrap().consecutive_failures, 0);
+
+        // a rejected trial lets the next request be the trial
+        call(&breaker, StatusCode::BAD_GATEWAY).await.unwrap();
+        call(&breaker, StatusCode::BAD_GATEWAY).await.unwrap();
+        fail(&breaker, || RateLimited::new().into()).await;
+        assert!(!breaker.state.lock().unwrap().trial);
+        call(&breaker, StatusCode::OK).await.unwrap();
+        assert!(breaker.state.lock().unwrap().opened_at.is_none());
+    }
+
+    #[tokio::test]
+    async fn it_opens_when_the_subgraph_times_out() {
+        let breaker = breaker(Duration::from_secs(60));
+        fail(&breaker, || Elapsed::new().into()).await;
+        assert!(!breaker.is_open());
+        fail(&breaker, || Elapsed::new().into()).await;
+        assert!(breaker.is_open());
+    }
+}
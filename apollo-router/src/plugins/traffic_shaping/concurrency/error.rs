@@ -0,0 +1,32 @@
+//! Error types
+
+use std::error;
+use std::fmt;
+
+use axum::response::IntoResponse;
+use http::StatusCode;
+
+/// The concurrency limit error.
+#[derive(Debug, Default)]
+pub(crate) struct Overloaded;
+
+impl Overloaded {
+    /// Construct a new Overloaded error
+    pub(crate) fn new() -> Self {
+        Overloaded {}
+    }
+}
+
+impl fmt::Display for Overloaded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("too many requests are being processed")
+    }
+}
+
+impl IntoResponse for Overloaded {
+    fn into_response(self) -> axum::response::Response {
+        (StatusCode::SERVICE_UNAVAILABLE, self.to_string()).into_response()
+    }
+}
+
+impl error::Error for Overloaded {}
@@ -0,0 +1,61 @@
+//! Future types
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+
+use pin_project_lite::pin_project;
+use tokio::sync::OwnedSemaphorePermit;
+
+use super::Overloaded;
+
+pin_project! {
+    /// Holds the permit of the request until its response is ready, or fails right away if the
+    /// request got no permit.
+    #[derive(Debug)]
+    pub(crate) struct ResponseFuture<T> {
+        #[pin]
+        response: Option<T>,
+        permit: Option<OwnedSemaphorePermit>,
+    }
+}
+
+impl<T> ResponseFuture<T> {
+    pub(crate) fn new(response: T, permit: OwnedSemaphorePermit) -> Self {
+        ResponseFuture {
+            response: Some(response),
+            permit: Some(permit),
+        }
+    }
+
+    pub(crate) fn overloaded() -> Self {
+        ResponseFuture {
+            response: None,
+            permit: None,
+        }
+    }
+}
+
+impl<F, T, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<T, E>>,
+    E: Into<tower::BoxError>,
+{
+    type Output = Result<T, tower::BoxError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        match this.response.as_pin_mut() {
+            Some(response) => match response.poll(cx) {
+                Poll::Ready(v) => {
+                    this.permit.take();
+                    Poll::Ready(v.map_err(Into::into))
+                }
+                Poll::Pending => Poll::Pending,
+            },
+            None => Poll::Ready(Err(Overloaded::new().into())),
+        }
+    }
+}
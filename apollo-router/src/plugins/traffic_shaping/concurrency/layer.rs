@@ -0,0 +1,37 @@
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+
+use tokio::sync::Semaphore;
+use tower::Layer;
+
+use super::ConcurrencyLimit;
+
+/// Rejects the requests received while the underlying service is already processing the
+/// maximum number of requests.
+///
+/// The limit is shared by all the services created from the same layer.
+#[derive(Debug, Clone)]
+pub(crate) struct ConcurrencyLimitLayer {
+    semaphore: Arc<Semaphore>,
+}
+
+impl ConcurrencyLimitLayer {
+    /// Create new concurrency limit layer.
+    pub(crate) fn new(max: NonZeroUsize) -> Self {
+        ConcurrencyLimitLayer {
+            semaphore: Arc::new(Semaphore::new(max.get())),
+        }
+    }
+}
+
+impl<S> Layer<S> for ConcurrencyLimitLayer {
+    type Service = ConcurrencyLimit<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ConcurrencyLimit {
+            inner: service,
+            semaphore: self.semaphore.clone(),
+            permit: None,
+        }
+    }
+}
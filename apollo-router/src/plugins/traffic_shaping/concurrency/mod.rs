@@ -0,0 +1,14 @@
+//! Limit the number of requests processed concurrently.
+//!
+//! Unlike the tower concurrency limit, which waits for a request to complete before accepting a
+//! new one, the requests received when the limit is reached are rejected right away instead of
+//! being queued.
+
+mod error;
+mod future;
+mod layer;
+mod service;
+
+pub(crate) use self::error::Overloaded;
+pub(crate) use self::layer::ConcurrencyLimitLayer;
+pub(crate) use self::service::ConcurrencyLimit;
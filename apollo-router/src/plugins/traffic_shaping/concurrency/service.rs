@@ -0,0 +1,59 @@
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+
+use futures::ready;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
+use tower::Service;
+
+use super::future::ResponseFuture;
+
+#[derive(Debug)]
+pub(crate) struct ConcurrencyLimit<T> {
+    pub(crate) inner: T,
+    pub(crate) semaphore: Arc<Semaphore>,
+    /// The permit acquired in `poll_ready`, moved to the response future in `call`
+    pub(crate) permit: Option<OwnedSemaphorePermit>,
+}
+
+impl<S, Request> Service<Request> for ConcurrencyLimit<S>
+where
+    S: Service<Request>,
+    S::Error: Into<tower::BoxError>,
+{
+    type Response = S::Response;
+    type Error = tower::BoxError;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.permit.is_none() {
+            // without permit, the request is rejected when it is called: failing here would fail
+            // the buffers in front of the limit for all the following requests
+            self.permit = self.semaphore.clone().try_acquire_owned().ok();
+        }
+
+        Poll::Ready(ready!(self.inner.poll_ready(cx)).map_err(Into::into))
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        match self.permit.take() {
+            Some(permit) => ResponseFuture::new(self.inner.call(request), permit),
+            None => {
+                tracing::trace!("concurrency limit reached; rejecting the request.");
+                ResponseFuture::overloaded()
+            }
+        }
+    }
+}
+
+impl<S: Clone> Clone for ConcurrencyLimit<S> {
+    fn clone(&self) -> Self {
+        // the permit belongs to the service that acquired it
+        ConcurrencyLimit {
+            inner: self.inner.clone(),
+            semaphore: self.semaphore.clone(),
+            permit: None,
+        }
+    }
+}
@@ -0,0 +1,365 @@
+//! Active health checks of the subgraphs.
+//!
+//! Subgraphs are probed periodically, with a `{ __typename }` query or an HTTP GET request. A
+//! subgraph becomes unhealthy after a number of consecutive failed probes, and healthy again after
+//! a number of consecutive successful ones. The circuit of an unhealthy subgraph is kept open, and
+//! the readiness check of the router fails while a subgraph is unhealthy.
+
+use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use http::Uri;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::task::JoinHandle;
+use tower::BoxError;
+use tower::ServiceExt;
+
+use super::circuit_breaker::CircuitBreakerLayer;
+use super::circuit_breaker::CircuitState;
+use crate::graphql;
+use crate::query_planner::OperationKind;
+use crate::services::subgraph_service::SubgraphService;
+use crate::Context;
+use crate::SubgraphRequest;
+
+/// How a subgraph is probed: with a `{ __typename }` query, successful when answered with data
+/// and a 2xx status code, or with an HTTP GET request, successful when answered with a 2xx status
+/// code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Probe {
+    Query,
+    Http,
+}
+
+impl Default for Probe {
+    fn default() -> Self {
+        Probe::Query
+    }
+}
+
+/// Settings of the health checks of a subgraph.
+#[derive(Debug, Clone)]
+pub(crate) struct HealthCheckSettings {
+    pub(crate) probe: Probe,
+    pub(crate) url: url::Url,
+    pub(crate) interval: Duration,
+    pub(crate) timeout: Duration,
+    pub(crate) unhealthy_threshold: u32,
+    pub(crate) healthy_threshold: u32,
+}
+
+/// Consecutive probe results, the subgraph changing health once they reach a threshold.
+#[derive(Debug)]
+struct Health {
+    healthy: bool,
+    /// Consecutive results contradicting the current health.
+    consecutive: u32,
+}
+
+impl Health {
+    /// Account for a probe result, returning the new health if it changed.
+    fn record(&mut self, success: bool, settings: &HealthCheckSettings) -> Option<bool> {
+        if success == self.healthy {
+            self.consecutive = 0;
+            return None;
+        }
+        self.consecutive += 1;
+        let threshold = if self.healthy {
+            settings.unhealthy_threshold
+        } else {
+            settings.healthy_threshold
+        };
+        if self.consecutive < threshold {
+            return None;
+        }
+        self.healthy = success;
+        self.consecutive = 0;
+        Some(success)
+    }
+}
+
+/// The health checks of the subgraphs of a pipeline.
+///
+/// They are configured by the traffic shaping plugin, and started by the pipeline once its
+/// subgraph services are built, so that the probes are sent like the requests to the subgraphs.
+/// They stop when the pipeline is dropped.
+#[derive(Clone, Default)]
+pub(crate) struct SubgraphHealth {
+    checks: Arc<Mutex<HealthChecks>>,
+}
+
+#[derive(Default)]
+struct HealthChecks {
+    checks: HashMap<String, HealthCheck>,
+    tasks: Vec<JoinHandle<()>>,
+    circuit_breakers: Option<Arc<Mutex<HashMap<String, CircuitBreakerLayer>>>>,
+}
+
+impl Drop for HealthChecks {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+struct HealthCheck {
+    settings: HealthCheckSettings,
+    healthy: Arc<AtomicBool>,
+    circuit_breakers: Arc<Mutex<HashMap<String, CircuitBreakerLayer>>>,
+}
+
+impl SubgraphHealth {
+    /// Check the health of a subgraph once the pipeline starts, keeping its circuit open while it
+    /// is unhealthy.
+    pub(crate) fn check(
+        &self,
+        subgraph: &str,
+        settings: HealthCheckSettings,
+        circuit_breakers: Arc<Mutex<HashMap<String, CircuitBreakerLayer>>>,
+    ) {
+        self.checks.lock().expect("lock poisoned").checks.insert(
+            subgraph.to_string(),
+            HealthCheck {
+                settings,
+                healthy: Arc::new(AtomicBool::new(true)),
+                circuit_breakers,
+            },
+        );
+    }
+
+    /// Track the circuits of the subgraphs, whether or not they are health checked, so that their
+    /// state is exposed by the admin API.
+    pub(crate) fn track_circuits(
+        &self,
+        circuit_breakers: Arc<Mutex<HashMap<String, CircuitBreakerLayer>>>,
+    ) {
+        self.checks.lock().expect("lock poisoned").circuit_breakers = Some(circuit_breakers);
+    }
+
+    /// Whether a subgraph passes its health checks, if it has any.
+    pub(crate) fn healthy(&self, subgraph: &str) -> Option<bool> {
+        self.checks
+            .lock()
+            .expect("lock poisoned")
+            .checks
+            .get(subgraph)
+            .map(|check| check.healthy.load(Ordering::Relaxed))
+    }
+
+    /// The state of the circuit of a subgraph, if it has a circuit breaker.
+    pub(crate) fn circuit(&self, subgraph: &str) -> Option<CircuitState> {
+        let circuit_breakers = self
+            .checks
+            .lock()
+            .expect("lock poisoned")
+            .circuit_breakers
+            .clone()?;
+        let breaker = circuit_breakers
+            .lock()
+            .expect("lock poisoned")
+            .get(subgraph)
+            .cloned()?;
+        Some(breaker.state())
+    }
+
+    /// Start probing a subgraph through its service, if it has a health check.
+    pub(crate) fn start(&self, subgraph: &str, service: &SubgraphService) {
+        let mut checks = self.checks.lock().expect("lock poisoned");
+        let (settings, healthy, circuit_breakers) = match checks.checks.get(subgraph) {
+            Some(check) => (
+                check.settings.clone(),
+                check.healthy.clone(),
+                check.circuit_breakers.clone(),
+            ),
+            None => return,
+        };
+        let subgraph = subgraph.to_string();
+        let service = service.clone();
+        checks.tasks.push(tokio::spawn(async move {
+            let mut health = Health {
+                healthy: true,
+                consecutive: 0,
+            };
+            let mut interval = tokio::time::interval(settings.interval);
+            loop {
+                interval.tick().await;
+                let result = tokio::time::timeout(
+                    settings.timeout,
+                    probe(&service, settings.probe, &subgraph, &settings.url),
+                )
+                .await;
+                let success = match result {
+                    Ok(Ok(())) => true,
+                    Ok(Err(e)) => {
+                        tracing::debug!("health check of subgraph '{}' failed: {}", subgraph, e);
+                        false
+                    }
+                    Err(_) => {
+                        tracing::debug!("health check of subgraph '{}' timed out", subgraph);
+                        false
+                    }
+                };
+                let changed = health.record(success, &settings);
+                match changed {
+                    Some(true) => tracing::info!("subgraph '{}' is healthy again", subgraph),
+                    Some(false) => tracing::warn!("subgraph '{}' is unhealthy", subgraph),
+                    None => {}
+                }
+                healthy.store(health.healthy, Ordering::Relaxed);
+
+                // the circuit stays open while the subgraph is unhealthy
+                let breaker = circuit_breakers
+                    .lock()
+                    .expect("lock poisoned")
+                    .get(&subgraph)
+                    .cloned();
+                if let Some(breaker) = breaker {
+                    if !health.healthy {
+                        breaker.set_healthy(false);
+                    } else if changed.is_some() {
+                        breaker.set_healthy(true);
+                    }
+                }
+            }
+        }));
+    }
+
+    /// The subgraphs failing their health checks, in alphabetical order.
+    pub(crate) fn unhealthy(&self) -> Vec<String> {
+        let mut unhealthy: Vec<String> = self
+            .checks
+            .lock()
+            .expect("lock poisoned")
+            .checks
+            .iter()
+            .filter(|(_, check)| !check.healthy.load(Ordering::Relaxed))
+            .map(|(name, _)| name.clone())
+            .collect();
+        unhealthy.sort();
+        unhealthy
+    }
+}
+
+/// Probe a subgraph once, through its service.
+pub(crate) async fn probe(
+    service: &SubgraphService,
+    probe: Probe,
+    subgraph: &str,
+    url: &url::Url,
+) -> Result<(), BoxError> {
+    let uri: Uri = url.as_str().parse()?;
+    match probe {
+        Probe::Query => {
+            let query = graphql::Request::builder()
+                .query("{ __typename }".to_string())
+                .build();
+            let request = SubgraphRequest::builder()
+                .originating_request(Arc::new(http::Request::new(query.clone())))
+                .subgraph_request(http::Request::post(uri).body(query)?)
+                .subgraph_name(subgraph.to_string())
+                .operation_kind(OperationKind::Query)
+                .context(Context::new())
+                .build();
+            let response = service.clone().oneshot(request).await?.response;
+            if !response.status().is_success() {
+                return Err(format!("the subgraph answered with {}", response.status()).into());
+            }
+            if response
+                .body()
+                .data
+                .as_ref()
+                .map(|data| data.is_null())
+                .unwrap_or(true)
+            {
+                return Err("the subgraph answered without data".into());
+            }
+        }
+        Probe::Http => {
+            let status = service.get(uri).await?;
+            if !status.is_success() {
+                return Err(format!("the subgraph answered with {}", status).into());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use super::*;
+
+    fn settings(url: &str) -> HealthCheckSettings {
+        HealthCheckSettings {
+            probe: Probe::Http,
+            url: url.parse().unwrap(),
+            interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(100),
+            unhealthy_threshold: 2,
+            healthy_threshold: 3,
+        }
+    }
+
+    #[test]
+    fn it_changes_health_after_consecutive_results() {
+        let settings = settings("http://127.0.0.1:1");
+        let mut health = Health {
+            healthy: true,
+            consecutive: 0,
+        };
+        assert_eq!(health.record(false, &settings), None);
+        assert_eq!(health.record(true, &settings), None);
+        assert_eq!(health.record(false, &settings), None);
+        assert_eq!(health.record(false, &settings), Some(false));
+
+        assert_eq!(health.record(true, &settings), None);
+        assert_eq!(health.record(true, &settings), None);
+        assert_eq!(health.record(true, &settings), Some(true));
+    }
+
+    #[tokio::test]
+    async fn it_opens_the_circuit_of_unhealthy_subgraphs() {
+        let breaker = CircuitBreakerLayer::new(
+            "unreachable",
+            NonZeroU32::new(5).unwrap(),
+            Duration::from_secs(60),
+            false,
+            None,
+        );
+        let circuit_breakers = Arc::new(Mutex::new(
+            [("unreachable".to_string(), breaker.clone())]
+                .into_iter()
+                .collect(),
+        ));
+        let health = SubgraphHealth::default();
+        health.check(
+            "unreachable",
+            settings("http://127.0.0.1:1"),
+            circuit_breakers,
+        );
+        assert!(health.unhealthy().is_empty());
+        health.start("unreachable", &SubgraphService::new("unreachable"));
+
+        // the probes are failing once the subgraph is unhealthy
+        let mut attempts = 0;
+        while health.unhealthy().is_empty() {
+            attempts += 1;
+            assert!(attempts < 100, "the subgraph should be unhealthy");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(health.unhealthy(), vec!["unreachable".to_string()]);
+        assert!(breaker.is_open());
+
+        // the health checks of another pipeline are not affected
+        assert!(SubgraphHealth::default().unhealthy().is_empty());
+    }
+}
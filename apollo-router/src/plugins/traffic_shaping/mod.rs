@@ -4,19 +4,31 @@
 //! * Query deduplication
 //!
 //! Future functionality:
-//! * APQ (already written, but config needs to be moved here)
+//! * APQ towards the subgraphs (the APQ cache of the router is not configured here yet)
 //! * Caching
 //! * Rate limiting
 //!
 
+mod apq;
+mod circuit_breaker;
+mod concurrency;
 mod deduplication;
+mod health_check;
 mod rate;
+mod retry;
 mod timeout;
+mod traffic_split;
 
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::num::NonZeroU32;
 use std::num::NonZeroU64;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 use http::header::ACCEPT_ENCODING;
 use http::header::CONTENT_ENCODING;
@@ -27,23 +39,51 @@ use tower::BoxError;
 use tower::ServiceBuilder;
 use tower::ServiceExt;
 
+use self::apq::SubgraphApqLayer;
+use self::circuit_breaker::CircuitBreakerLayer;
+pub(crate) use self::circuit_breaker::CircuitState;
+use self::concurrency::ConcurrencyLimitLayer;
+pub(crate) use self::concurrency::Overloaded;
+pub(crate) use self::health_check::probe;
+use self::health_check::HealthCheckSettings;
+pub(crate) use self::health_check::Probe;
+pub(crate) use self::health_check::SubgraphHealth;
 use self::rate::RateLimitLayer;
 pub(crate) use self::rate::RateLimited;
+use self::retry::RetryLayer;
 pub(crate) use self::timeout::Elapsed;
 use self::timeout::TimeoutLayer;
+pub(crate) use self::timeout::TIMED_OUT_CONTEXT_KEY;
+use self::traffic_split::TrafficSplitLayer;
+use crate::cache::storage::KeyValueStorage;
 use crate::error::ConfigurationError;
-use crate::layers::ServiceBuilderExt;
+use crate::panics::CatchPanicBufferLayer;
 use crate::plugin::Plugin;
 use crate::plugin::PluginInit;
 use crate::plugins::traffic_shaping::deduplication::QueryDeduplicationLayer;
+use crate::query_planner::DEADLINE_CONTEXT_KEY;
+use crate::query_planner::DEGRADED_SUBGRAPHS_CONTEXT_KEY;
 use crate::register_plugin;
+use crate::services::request_size::MAX_REQUEST_BYTES_CONTEXT_KEY;
 use crate::services::subgraph;
 use crate::services::subgraph_service::Compression;
 use crate::services::supergraph;
+use crate::webhooks::Notifier;
 use crate::Configuration;
+use crate::Schema;
 use crate::SubgraphRequest;
 
 const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+const DEFAULT_RESET_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_UNHEALTHY_THRESHOLD: u32 = 3;
+const DEFAULT_HEALTHY_THRESHOLD: u32 = 2;
+const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+const DEFAULT_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(5);
+const DEFAULT_RETRY_MIN_PER_SEC: u32 = 10;
+const DEFAULT_RETRY_PERCENT: f32 = 0.2;
 trait Merge {
     fn merge(&self, fallback: Option<&Self>) -> Self;
 }
@@ -61,6 +101,36 @@ struct Shaping {
     #[schemars(with = "String", default)]
     /// Enable timeout for incoming requests
     timeout: Option<Duration>,
+    /// Maximum number of requests sent to the subgraph at the same time. Requests above that
+    /// limit fail right away instead of being queued
+    concurrency_limit: Option<NonZeroUsize>,
+    /// Maximum number of entity representations sent to the subgraph in a single request. Larger
+    /// entity fetches are split into several requests sent in parallel
+    entity_batch_size: Option<NonZeroUsize>,
+    /// Handling of the entities missing from the responses of the subgraph, or null: `error`
+    /// fails the fetch, `null_with_error` sets them to null with an error at their path, and
+    /// `null` sets them to null silently. Without it, a missing entity fails the fetch and a null
+    /// one is kept as is
+    missing_entities: Option<MissingEntities>,
+    /// Stop sending requests to the subgraph after consecutive failures
+    circuit_breaker: Option<CircuitBreakerConf>,
+    /// Probe the subgraph periodically, keeping its circuit open and failing the readiness check
+    /// of the router while it is unhealthy
+    health_check: Option<HealthCheckConf>,
+    /// Send the queries again when they fail with a retryable error: the subgraph could not be
+    /// reached, did not respond in time, or responded with a 429, 502, 503 or 504 status code.
+    /// Mutations are never retried
+    retry: Option<RetryConf>,
+    /// Split the requests between several endpoints of the subgraph, e.g. for canary rollouts.
+    /// Only applies to specific subgraphs
+    traffic_split: Option<TrafficSplitConf>,
+    /// Send the SHA-256 hash of the queries instead of the queries, with automatic persisted
+    /// queries, and the queries only when the subgraph does not know their hash yet
+    apq: Option<bool>,
+    /// Send the queries as GET requests, with the query and variables in the URL, when the URL is
+    /// at most that many bytes long, so that HTTP caches between the router and the subgraph can
+    /// cache them. Mutations are always sent as POST requests
+    get_max_url_length: Option<NonZeroUsize>,
 }
 
 impl Merge for Shaping {
@@ -71,11 +141,28 @@ impl Merge for Shaping {
                 deduplicate_query: self.deduplicate_query.or(fallback.deduplicate_query),
                 compression: self.compression.or(fallback.compression),
                 timeout: self.timeout.or(fallback.timeout),
+                concurrency_limit: self.concurrency_limit.or(fallback.concurrency_limit),
+                entity_batch_size: self.entity_batch_size.or(fallback.entity_batch_size),
+                missing_entities: self.missing_entities.or(fallback.missing_entities),
+                circuit_breaker: self
+                    .circuit_breaker
+                    .as_ref()
+                    .or(fallback.circuit_breaker.as_ref())
+                    .cloned(),
+                health_check: self
+                    .health_check
+                    .as_ref()
+                    .or(fallback.health_check.as_ref())
+                    .cloned(),
+                retry: self.retry.as_ref().or(fallback.retry.as_ref()).cloned(),
                 global_rate_limit: self
                     .global_rate_limit
                     .as_ref()
                     .or(fallback.global_rate_limit.as_ref())
                     .cloned(),
+                traffic_split: self.traffic_split.clone(),
+                apq: self.apq.or(fallback.apq),
+                get_max_url_length: self.get_max_url_length.or(fallback.get_max_url_length),
             },
         }
     }
@@ -90,6 +177,18 @@ struct RouterShaping {
     #[schemars(with = "String", default)]
     /// Enable timeout for incoming requests
     timeout: Option<Duration>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Execution budget of incoming requests: the subgraph fetches still running at the deadline
+    /// are cancelled, and the data fetched so far is returned with errors
+    deadline: Option<Duration>,
+    /// Maximum number of requests processed at the same time. Requests above that limit are
+    /// rejected with a 503 status code instead of being queued
+    concurrency_limit: Option<NonZeroUsize>,
+    /// Maximum approximate size of the data held by a request, in bytes: its body, the responses
+    /// of its subgraphs and its response. The subgraph fetches of the requests above that size
+    /// are aborted, and the data fetched so far is returned with errors
+    max_request_bytes: Option<NonZeroU64>,
 }
 
 #[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
@@ -107,10 +206,22 @@ pub(crate) struct Config {
     #[serde(default)]
     /// Applied on specific subgraphs
     subgraphs: HashMap<String, Shaping>,
-    /// Enable variable deduplication optimization when sending requests to subgraphs (https://github.com/apollographql/router/issues/87)
+    /// Enable variable deduplication optimization when sending requests to subgraphs (https://github.com/apollographql/router/issues/87):
+    /// identical entity representations are only sent once
     deduplicate_variables: Option<bool>,
 }
 
+/// Handling of the entities missing from the responses of a subgraph, or null: `error` fails the
+/// fetch, `null_with_error` sets them to null with an error at their path, and `null` sets them
+/// to null silently.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum MissingEntities {
+    Error,
+    NullWithError,
+    Null,
+}
+
 #[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 struct RateLimitConf {
@@ -122,6 +233,91 @@ struct RateLimitConf {
     interval: Duration,
 }
 
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct CircuitBreakerConf {
+    /// Number of consecutive failed requests opening the circuit (defaults to 5). A request fails
+    /// when the subgraph returns an error or a 5xx status code, not when the router times it out or
+    /// sheds it
+    failure_threshold: Option<NonZeroU32>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Time during which the requests are rejected once the circuit is open, before a trial
+    /// request is sent to the subgraph (defaults to 30s)
+    reset_timeout: Option<Duration>,
+    /// Skip the fetches to the subgraph while its circuit is open: the fields it resolves are set
+    /// to null with an error for each of them, and the rest of the query is still served
+    #[serde(default)]
+    degrade: bool,
+}
+
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct HealthCheckConf {
+    /// Probe sent to the subgraph: a `{ __typename }` `query` (the default), or an `http` GET
+    /// request
+    #[serde(default)]
+    probe: Probe,
+    /// URL probed instead of the URL of the subgraph, e.g. its `/health` endpoint for `http`
+    /// probes
+    url: Option<url::Url>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Interval between two probes (defaults to 10s)
+    interval: Option<Duration>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Timeout of a probe (defaults to 2s)
+    timeout: Option<Duration>,
+    /// Number of consecutive failed probes making the subgraph unhealthy (defaults to 3)
+    unhealthy_threshold: Option<NonZeroU32>,
+    /// Number of consecutive successful probes making the subgraph healthy again (defaults to 2)
+    healthy_threshold: Option<NonZeroU32>,
+}
+
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct RetryConf {
+    /// Maximum number of times a request is sent again
+    attempts: NonZeroU32,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Time waited before the first retry, doubled before each of the following ones (defaults
+    /// to 100ms). The actual delay is picked randomly between half this time and this time
+    backoff: Option<Duration>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Longest time waited before a retry (defaults to 5s). The requests are not sent again when
+    /// the `Retry-After` header of the subgraph asks to wait longer
+    max_backoff: Option<Duration>,
+    /// Number of retries per second always allowed, whatever the traffic (defaults to 10)
+    min_per_sec: Option<u32>,
+    /// Ratio of the requests of the last 10 seconds that can be retried on top of `min_per_sec`,
+    /// between 0 and 1 (defaults to 0.2)
+    retry_percent: Option<f32>,
+}
+
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct TrafficSplitConf {
+    /// Endpoints receiving the requests, instead of the URL of the subgraph
+    targets: Vec<TargetConf>,
+    /// Send the requests with the same value for this header, e.g. a client identifier, to the
+    /// same endpoint. Endpoints are picked randomly for every request otherwise
+    sticky_header: Option<String>,
+}
+
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct TargetConf {
+    /// Name of the endpoint in the metrics, e.g. `v2`
+    name: String,
+    /// URL of the endpoint
+    url: url::Url,
+    /// Share of the requests sent to the endpoint, relative to the weights of the other endpoints
+    weight: u32,
+}
+
 impl Merge for RateLimitConf {
     fn merge(&self, fallback: Option<&Self>) -> Self {
         match fallback {
@@ -140,6 +336,15 @@ pub(crate) struct TrafficShaping {
     config: Config,
     rate_limit_router: Option<RateLimitLayer>,
     rate_limit_subgraphs: Mutex<HashMap<String, RateLimitLayer>>,
+    concurrency_limit_router: Option<ConcurrencyLimitLayer>,
+    concurrency_limit_subgraphs: Mutex<HashMap<String, ConcurrencyLimitLayer>>,
+    circuit_breakers: Arc<Mutex<HashMap<String, CircuitBreakerLayer>>>,
+    apq_subgraphs: Mutex<HashMap<String, SubgraphApqLayer>>,
+    retries: Mutex<HashMap<String, RetryLayer>>,
+    traffic_splits: Arc<HashMap<String, TrafficSplitLayer>>,
+    webhooks: Option<Notifier>,
+    /// Storage of the rate limits shared with the other routers
+    storage: Option<Arc<dyn KeyValueStorage>>,
 }
 
 #[async_trait::async_trait]
@@ -165,19 +370,141 @@ impl Plugin for TrafficShaping {
                     Ok(RateLimitLayer::new(
                         router_rate_limit_conf.capacity,
                         router_rate_limit_conf.interval,
+                    )
+                    .with_storage(
+                        init.storage.clone(),
+                        "apollo_router:rate_limit:router".to_string(),
                     ))
                 }
             })
             .transpose()?;
+        if init
+            .config
+            .all
+            .as_ref()
+            .and_then(|all| all.traffic_split.as_ref())
+            .is_some()
+        {
+            return Err(ConfigurationError::InvalidConfiguration {
+                message: "bad configuration for traffic_shaping plugin",
+                error: "traffic_split can only be set on specific subgraphs".to_string(),
+            }
+            .into());
+        }
+        let invalid_retry_percent = init
+            .config
+            .all
+            .iter()
+            .chain(init.config.subgraphs.values())
+            .filter_map(|shaping| shaping.retry.as_ref()?.retry_percent)
+            .find(|retry_percent| !(0.0..=1.0).contains(retry_percent));
+        if let Some(retry_percent) = invalid_retry_percent {
+            return Err(ConfigurationError::InvalidConfiguration {
+                message: "bad configuration for traffic_shaping plugin",
+                error: format!(
+                    "retry_percent must be between 0 and 1, not {}",
+                    retry_percent
+                ),
+            }
+            .into());
+        }
+        let mut traffic_splits = HashMap::new();
+        for (name, shaping) in &init.config.subgraphs {
+            if let Some(traffic_split) = &shaping.traffic_split {
+                let layer = TrafficSplitLayer::new(
+                    name,
+                    traffic_split
+                        .targets
+                        .iter()
+                        .map(|target| (target.name.clone(), target.url.clone(), target.weight)),
+                    traffic_split.sticky_header.as_deref(),
+                )
+                .map_err(|e| ConfigurationError::InvalidConfiguration {
+                    message: "bad configuration for traffic_shaping plugin",
+                    error: format!("invalid traffic split of subgraph '{}': {}", name, e),
+                })?;
+                traffic_splits.insert(name.clone(), layer);
+            }
+        }
+        let traffic_splits = Arc::new(traffic_splits);
+        if !traffic_splits.is_empty() {
+            traffic_split::register_metrics(traffic_splits.clone(), &init.metrics);
+        }
+        let concurrency_limit_router = init
+            .config
+            .router
+            .as_ref()
+            .and_then(|r| r.concurrency_limit)
+            .map(ConcurrencyLimitLayer::new);
+
+        let circuit_breakers: Arc<Mutex<HashMap<String, CircuitBreakerLayer>>> = Default::default();
+        init.subgraph_health
+            .track_circuits(circuit_breakers.clone());
+        // the health checks are started by the pipeline, through its subgraph services
+        let has_health_checks = init
+            .config
+            .all
+            .iter()
+            .chain(init.config.subgraphs.values())
+            .any(|shaping| shaping.health_check.is_some());
+        if has_health_checks {
+            let schema = Schema::parse(&init.supergraph_sdl, &Default::default())?;
+            for (name, url) in schema.subgraphs() {
+                let health_check =
+                    Self::merge_config(init.config.all.as_ref(), init.config.subgraphs.get(name))
+                        .and_then(|shaping| shaping.health_check);
+                let health_check = match health_check {
+                    Some(health_check) => health_check,
+                    None => continue,
+                };
+                let url = match health_check.url {
+                    Some(url) => url,
+                    None => url::Url::parse(&url.to_string())?,
+                };
+                let settings = HealthCheckSettings {
+                    probe: health_check.probe,
+                    url,
+                    interval: health_check
+                        .interval
+                        .unwrap_or(DEFAULT_HEALTH_CHECK_INTERVAL),
+                    timeout: health_check.timeout.unwrap_or(DEFAULT_HEALTH_CHECK_TIMEOUT),
+                    unhealthy_threshold: health_check
+                        .unhealthy_threshold
+                        .map(NonZeroU32::get)
+                        .unwrap_or(DEFAULT_UNHEALTHY_THRESHOLD),
+                    healthy_threshold: health_check
+                        .healthy_threshold
+                        .map(NonZeroU32::get)
+                        .unwrap_or(DEFAULT_HEALTHY_THRESHOLD),
+                };
+                init.subgraph_health
+                    .check(name, settings, circuit_breakers.clone());
+            }
+        }
 
         Ok(Self {
             config: init.config,
             rate_limit_router,
             rate_limit_subgraphs: Mutex::new(HashMap::new()),
+            concurrency_limit_router,
+            concurrency_limit_subgraphs: Mutex::new(HashMap::new()),
+            circuit_breakers,
+            apq_subgraphs: Mutex::new(HashMap::new()),
+            retries: Mutex::new(HashMap::new()),
+            traffic_splits,
+            webhooks: init.webhooks,
+            storage: init.storage,
         })
     }
 
     fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        let deadline = self.config.router.as_ref().and_then(|r| r.deadline);
+        let max_request_bytes = self
+            .config
+            .router
+            .as_ref()
+            .and_then(|r| r.max_request_bytes);
+        let circuit_breakers = self.circuit_breakers.clone();
         ServiceBuilder::new()
             .layer(TimeoutLayer::new(
                 self.config
@@ -187,7 +514,51 @@ impl Plugin for TrafficShaping {
                     .unwrap_or(DEFAULT_TIMEOUT),
             ))
             .option_layer(self.rate_limit_router.clone())
+            .option_layer(self.concurrency_limit_router.clone())
             .service(service)
+            .map_request(move |req: supergraph::Request| {
+                // the deadline and maximum size overridden for the client take precedence
+                let overridden =
+                    |key: &str| req.context.get::<_, u64>(key).ok().flatten().is_some();
+                if let Some(deadline) = deadline.filter(|_| !overridden(DEADLINE_CONTEXT_KEY)) {
+                    let deadline = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        + deadline;
+                    if let Err(e) = req
+                        .context
+                        .insert(DEADLINE_CONTEXT_KEY, deadline.as_millis() as u64)
+                    {
+                        tracing::error!("could not set the request deadline: {}", e);
+                    }
+                }
+                if let Some(max_request_bytes) =
+                    max_request_bytes.filter(|_| !overridden(MAX_REQUEST_BYTES_CONTEXT_KEY))
+                {
+                    if let Err(e) = req
+                        .context
+                        .insert(MAX_REQUEST_BYTES_CONTEXT_KEY, max_request_bytes.get())
+                    {
+                        tracing::error!("could not set the maximum size of the request: {}", e);
+                    }
+                }
+                let degraded_subgraphs: Vec<String> = circuit_breakers
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|(_, breaker)| breaker.degrade() && breaker.is_open())
+                    .map(|(name, _)| name.clone())
+                    .collect();
+                if !degraded_subgraphs.is_empty() {
+                    if let Err(e) = req
+                        .context
+                        .insert(DEGRADED_SUBGRAPHS_CONTEXT_KEY, degraded_subgraphs)
+                    {
+                        tracing::error!("could not set the degraded subgraphs: {}", e);
+                    }
+                }
+                req
+            })
             .boxed()
     }
 
@@ -205,15 +576,84 @@ impl Plugin for TrafficShaping {
                     .entry(name.to_string())
                     .or_insert_with(|| {
                         RateLimitLayer::new(rate_limit_conf.capacity, rate_limit_conf.interval)
+                            .with_storage(
+                                self.storage.clone(),
+                                format!("apollo_router:rate_limit:subgraph:{}", name),
+                            )
+                    })
+                    .clone()
+            });
+            let circuit_breaker = config.circuit_breaker.as_ref().map(|circuit_breaker_conf| {
+                self.circuit_breakers
+                    .lock()
+                    .unwrap()
+                    .entry(name.to_string())
+                    .or_insert_with(|| {
+                        CircuitBreakerLayer::new(
+                            name,
+                            circuit_breaker_conf.failure_threshold.unwrap_or_else(|| {
+                                NonZeroU32::new(DEFAULT_FAILURE_THRESHOLD)
+                                    .expect("the default is not zero; qed")
+                            }),
+                            circuit_breaker_conf
+                                .reset_timeout
+                                .unwrap_or(DEFAULT_RESET_TIMEOUT),
+                            circuit_breaker_conf.degrade,
+                            self.webhooks.clone(),
+                        )
+                    })
+                    .clone()
+            });
+            let retry = config.retry.as_ref().map(|retry_conf| {
+                // the budget of the retries is shared by all the requests to the subgraph
+                self.retries
+                    .lock()
+                    .unwrap()
+                    .entry(name.to_string())
+                    .or_insert_with(|| {
+                        RetryLayer::new(
+                            name,
+                            retry_conf.attempts.get(),
+                            retry_conf.backoff.unwrap_or(DEFAULT_RETRY_BACKOFF),
+                            retry_conf.max_backoff.unwrap_or(DEFAULT_RETRY_MAX_BACKOFF),
+                            retry_conf.min_per_sec.unwrap_or(DEFAULT_RETRY_MIN_PER_SEC),
+                            retry_conf.retry_percent.unwrap_or(DEFAULT_RETRY_PERCENT),
+                        )
                     })
                     .clone()
             });
+            let concurrency_limit = config.concurrency_limit.map(|max| {
+                self.concurrency_limit_subgraphs
+                    .lock()
+                    .unwrap()
+                    .entry(name.to_string())
+                    .or_insert_with(|| ConcurrencyLimitLayer::new(max))
+                    .clone()
+            });
+            let apq = config.apq.unwrap_or_default().then(|| {
+                self.apq_subgraphs
+                    .lock()
+                    .unwrap()
+                    .entry(name.to_string())
+                    .or_default()
+                    .clone()
+            });
             ServiceBuilder::new()
                 .option_layer(config.deduplicate_query.unwrap_or_default().then(|| {
                     // Buffer is required because dedup layer requires a clone service.
+                    // The requests are rejected when it is full instead of waiting for capacity.
                     ServiceBuilder::new()
+                        .load_shed()
                         .layer(QueryDeduplicationLayer::default())
-                        .buffered()
+                        .layer(CatchPanicBufferLayer::new())
+                }))
+                .option_layer(circuit_breaker)
+                .option_layer(retry.map(|retry| {
+                    // Buffer is required because the retry layer may send a request again.
+                    ServiceBuilder::new()
+                        .load_shed()
+                        .layer(retry)
+                        .layer(CatchPanicBufferLayer::new())
                 }))
                 .layer(TimeoutLayer::new(
                     config
@@ -221,6 +661,15 @@ impl Plugin for TrafficShaping {
                     .unwrap_or(DEFAULT_TIMEOUT),
                 ))
                 .option_layer(rate_limit)
+                .option_layer(concurrency_limit)
+                .option_layer(self.traffic_splits.get(name).cloned())
+                .option_layer(apq.map(|apq| {
+                    // Buffer is required because the APQ layer may send a request twice.
+                    ServiceBuilder::new()
+                        .load_shed()
+                        .layer(apq)
+                        .layer(CatchPanicBufferLayer::new())
+                }))
                 .service(service)
                 .map_request(move |mut req: SubgraphRequest| {
                     if let Some(compression) = config.compression {
@@ -255,6 +704,62 @@ impl TrafficShaping {
             .map(|conf| conf.get("deduplicate_variables") == Some(&serde_json::Value::Bool(true)))
             .unwrap_or_default()
     }
+
+    /// Maximum number of entity representations sent in a single request, for the subgraphs
+    /// having one.
+    pub(crate) fn get_configuration_entity_batch_sizes<'a>(
+        configuration: &Configuration,
+        subgraphs: impl Iterator<Item = &'a String>,
+    ) -> BTreeMap<String, NonZeroUsize> {
+        let config = match configuration
+            .plugin_configuration("apollo.traffic_shaping")
+            .and_then(|conf| serde_json::from_value::<Config>(conf).ok())
+        {
+            Some(config) => config,
+            None => return BTreeMap::new(),
+        };
+        subgraphs
+            .filter_map(|name| {
+                Self::merge_config(config.all.as_ref(), config.subgraphs.get(name))
+                    .and_then(|shaping| shaping.entity_batch_size)
+                    .map(|size| (name.clone(), size))
+            })
+            .collect()
+    }
+
+    /// Handling of the missing entities, for the subgraphs having one.
+    pub(crate) fn get_configuration_missing_entities<'a>(
+        configuration: &Configuration,
+        subgraphs: impl Iterator<Item = &'a String>,
+    ) -> BTreeMap<String, MissingEntities> {
+        let config = match configuration
+            .plugin_configuration("apollo.traffic_shaping")
+            .and_then(|conf| serde_json::from_value::<Config>(conf).ok())
+        {
+            Some(config) => config,
+            None => return BTreeMap::new(),
+        };
+        subgraphs
+            .filter_map(|name| {
+                Self::merge_config(config.all.as_ref(), config.subgraphs.get(name))
+                    .and_then(|shaping| shaping.missing_entities)
+                    .map(|missing_entities| (name.clone(), missing_entities))
+            })
+            .collect()
+    }
+
+    /// Maximum length of the URLs of the queries sent as GET requests to a subgraph, if it has
+    /// one.
+    pub(crate) fn get_configuration_get_max_url_length(
+        configuration: &Configuration,
+        subgraph: &str,
+    ) -> Option<NonZeroUsize> {
+        let config = configuration
+            .plugin_configuration("apollo.traffic_shaping")
+            .and_then(|conf| serde_json::from_value::<Config>(conf).ok())?;
+        Self::merge_config(config.all.as_ref(), config.subgraphs.get(subgraph))
+            .and_then(|shaping| shaping.get_max_url_length)
+    }
 }
 
 register_plugin!("apollo", "traffic_shaping", TrafficShaping);
@@ -271,12 +776,19 @@ mod test {
     use tower::Service;
 
     use super::*;
+    use crate::cache::storage::Counters;
+    use crate::cache::storage::InMemoryStorage;
+    use crate::cache::storage::Increment;
     use crate::graphql::Response;
     use crate::json_ext::Object;
     use crate::plugin::test::MockSubgraph;
     use crate::plugin::test::MockSupergraphService;
     use crate::plugin::DynPlugin;
+    use crate::services::request_stages;
+    use crate::services::request_stages::RequestStages;
+    use crate::services::request_stages::Stage;
     use crate::Configuration;
+    use crate::Context;
     use crate::PluggableSupergraphServiceBuilder;
     use crate::Schema;
     use crate::SupergraphRequest;
@@ -314,16 +826,40 @@ mod test {
 
     async fn build_mock_router_with_variable_dedup_optimization(
         plugin: Box<dyn DynPlugin>,
+    ) -> BoxCloneService<SupergraphRequest, SupergraphResponse, BoxError> {
+        let account_mocks = vec![(
+            r#"{"query":"query TopProducts__accounts__3($representations:[_Any!]!){_entities(representations:$representations){...on User{name}}}","operationName":"TopProducts__accounts__3","variables":{"representations":[{"__typename":"User","id":"1"},{"__typename":"User","id":"2"}]}}"#,
+            r#"{"data":{"_entities":[{"name":"Ada Lovelace"},{"name":"Alan Turing"}]}}"#,
+        )];
+
+        build_mock_router(
+            plugin,
+            r#"
+        traffic_shaping:
+            deduplicate_variables: true
+        "#,
+            account_mocks,
+        )
+        .await
+    }
+
+    async fn build_mock_router(
+        plugin: Box<dyn DynPlugin>,
+        config: &str,
+        account_mocks: Vec<(&str, &str)>,
     ) -> BoxCloneService<SupergraphRequest, SupergraphResponse, BoxError> {
         let mut extensions = Object::new();
         extensions.insert("test", Value::String(ByteString::from("value")));
 
-        let account_mocks = vec![
-            (
-                r#"{"query":"query TopProducts__accounts__3($representations:[_Any!]!){_entities(representations:$representations){...on User{name}}}","operationName":"TopProducts__accounts__3","variables":{"representations":[{"__typename":"User","id":"1"},{"__typename":"User","id":"2"}]}}"#,
-                r#"{"data":{"_entities":[{"name":"Ada Lovelace"},{"name":"Alan Turing"}]}}"#
-            )
-        ].into_iter().map(|(query, response)| (serde_json::from_str(query).unwrap(), serde_json::from_str(response).unwrap())).collect();
+        let account_mocks = account_mocks
+            .into_iter()
+            .map(|(query, response)| {
+                (
+                    serde_json::from_str(query).unwrap(),
+                    serde_json::from_str(response).unwrap(),
+                )
+            })
+            .collect();
         let account_service = MockSubgraph::new(account_mocks);
 
         let review_mocks = vec![
@@ -352,13 +888,7 @@ mod test {
         );
         let schema: Arc<Schema> = Arc::new(Schema::parse(schema, &Default::default()).unwrap());
 
-        let config: Configuration = serde_yaml::from_str(
-            r#"
-        traffic_shaping:
-            deduplicate_variables: true
-        "#,
-        )
-        .unwrap();
+        let config: Configuration = serde_yaml::from_str(config).unwrap();
 
         let builder = PluggableSupergraphServiceBuilder::new(schema.clone())
             .with_configuration(Arc::new(config));
@@ -398,6 +928,44 @@ mod test {
         execute_router_test(VALID_QUERY, &*EXPECTED_RESPONSE, router).await;
     }
 
+    #[tokio::test]
+    async fn it_splits_entity_fetches_in_batches() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        deduplicate_variables: true
+        subgraphs:
+            accounts:
+                entity_batch_size: 1
+        "#,
+        )
+        .unwrap();
+        let plugin = get_traffic_shaping_plugin(&config).await;
+
+        let account_mocks = vec![
+            (
+                r#"{"query":"query TopProducts__accounts__3($representations:[_Any!]!){_entities(representations:$representations){...on User{name}}}","operationName":"TopProducts__accounts__3","variables":{"representations":[{"__typename":"User","id":"1"}]}}"#,
+                r#"{"data":{"_entities":[{"name":"Ada Lovelace"}]}}"#,
+            ),
+            (
+                r#"{"query":"query TopProducts__accounts__3($representations:[_Any!]!){_entities(representations:$representations){...on User{name}}}","operationName":"TopProducts__accounts__3","variables":{"representations":[{"__typename":"User","id":"2"}]}}"#,
+                r#"{"data":{"_entities":[{"name":"Alan Turing"}]}}"#,
+            ),
+        ];
+        let router = build_mock_router(
+            plugin,
+            r#"
+        traffic_shaping:
+            deduplicate_variables: true
+            subgraphs:
+                accounts:
+                    entity_batch_size: 1
+        "#,
+            account_mocks,
+        )
+        .await;
+        execute_router_test(VALID_QUERY, &*EXPECTED_RESPONSE, router).await;
+    }
+
     #[tokio::test]
     async fn it_add_correct_headers_for_compression() {
         let config = serde_yaml::from_str::<serde_json::Value>(
@@ -509,6 +1077,41 @@ mod test {
             .unwrap();
     }
 
+    #[tokio::test]
+    async fn it_only_splits_the_traffic_of_specific_subgraphs() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        subgraphs:
+            test:
+                traffic_split:
+                    targets:
+                        - name: v1
+                          url: http://test-v1/graphql
+                          weight: 1
+        "#,
+        )
+        .unwrap();
+        get_traffic_shaping_plugin(&config).await;
+
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        all:
+            traffic_split:
+                targets:
+                    - name: v1
+                      url: http://test-v1/graphql
+                      weight: 1
+        "#,
+        )
+        .unwrap();
+        assert!(crate::plugin::plugins()
+            .get("apollo.traffic_shaping")
+            .expect("Plugin not found")
+            .create_instance_without_schema(&config)
+            .await
+            .is_err());
+    }
+
     #[tokio::test]
     async fn it_rate_limit_router_requests() {
         let config = serde_yaml::from_str::<serde_json::Value>(
@@ -560,4 +1163,216 @@ mod test {
             .await
             .unwrap();
     }
+
+    async fn get_traffic_shaping_plugin_with_storage(
+        config: &serde_json::Value,
+        storage: Arc<dyn KeyValueStorage>,
+    ) -> Box<dyn DynPlugin> {
+        crate::plugin::plugins()
+            .get("apollo.traffic_shaping")
+            .expect("Plugin not found")
+            .create_instance_in_pipeline(
+                config,
+                Default::default(),
+                Default::default(),
+                None,
+                Default::default(),
+                Some(storage),
+            )
+            .await
+            .expect("Plugin not created")
+    }
+
+    fn responding_supergraph_service() -> supergraph::BoxService {
+        tower::service_fn(|_request: SupergraphRequest| async {
+            SupergraphResponse::fake_builder()
+                .data(json!({ "test": 1234_u32 }))
+                .build()
+        })
+        .boxed()
+    }
+
+    #[tokio::test]
+    async fn it_shares_the_router_rate_limit_through_the_storage() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        router:
+            global_rate_limit:
+                capacity: 1
+                interval: 1h
+        "#,
+        )
+        .unwrap();
+
+        // two routers counting their requests in the same storage
+        let storage: Arc<dyn KeyValueStorage> = Arc::new(InMemoryStorage::default());
+        let first = get_traffic_shaping_plugin_with_storage(&config, storage.clone()).await;
+        let second = get_traffic_shaping_plugin_with_storage(&config, storage).await;
+
+        assert!(first
+            .supergraph_service(responding_supergraph_service())
+            .oneshot(SupergraphRequest::fake_builder().build().unwrap())
+            .await
+            .is_ok());
+        let error = second
+            .supergraph_service(responding_supergraph_service())
+            .oneshot(SupergraphRequest::fake_builder().build().unwrap())
+            .await
+            .unwrap_err();
+        assert!(error.is::<RateLimited>());
+    }
+
+    #[tokio::test]
+    async fn it_counts_the_rate_limit_locally_when_the_storage_fails() {
+        struct FailingStorage;
+
+        #[async_trait::async_trait]
+        impl KeyValueStorage for FailingStorage {
+            async fn get(&self, _keys: &[String]) -> Result<Vec<Option<Vec<u8>>>, BoxError> {
+                Err("unavailable".into())
+            }
+
+            async fn insert(
+                &self,
+                _key: String,
+                _value: Vec<u8>,
+                _ttl: Option<Duration>,
+            ) -> Result<(), BoxError> {
+                Err("unavailable".into())
+            }
+
+            async fn increment(&self, _increments: &[Increment]) -> Result<Counters, BoxError> {
+                Err("unavailable".into())
+            }
+        }
+
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        router:
+            global_rate_limit:
+                capacity: 1
+                interval: 1h
+        "#,
+        )
+        .unwrap();
+
+        let plugin =
+            get_traffic_shaping_plugin_with_storage(&config, Arc::new(FailingStorage)).await;
+        assert!(plugin
+            .supergraph_service(responding_supergraph_service())
+            .oneshot(SupergraphRequest::fake_builder().build().unwrap())
+            .await
+            .is_ok());
+        let error = plugin
+            .supergraph_service(responding_supergraph_service())
+            .oneshot(SupergraphRequest::fake_builder().build().unwrap())
+            .await
+            .unwrap_err();
+        assert!(error.is::<RateLimited>());
+    }
+
+    #[tokio::test]
+    async fn it_reports_the_stage_of_the_router_requests_timing_out() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        router:
+            timeout: 50ms
+        "#,
+        )
+        .unwrap();
+
+        let plugin = get_traffic_shaping_plugin(&config).await;
+        let service = tower::service_fn(|request: SupergraphRequest| async move {
+            request_stages::record(&request.context, Stage::Parsing, async {}).await;
+            request_stages::record(
+                &request.context,
+                Stage::Fetch("products"),
+                tokio::time::sleep(Duration::from_secs(1)),
+            )
+            .await;
+            Ok::<_, BoxError>(SupergraphResponse::fake_builder().build().unwrap())
+        });
+
+        let context = Context::new();
+        let error = plugin
+            .supergraph_service(service.boxed())
+            .oneshot(
+                SupergraphRequest::fake_builder()
+                    .context(context.clone())
+                    .build()
+                    .unwrap(),
+            )
+            .await
+            .map(|_| ())
+            .expect_err("should be in error due to the timeout");
+        // the stages are not reported to the client
+        assert_eq!(error.to_string(), "request timed out");
+        let report = context
+            .extensions()
+            .get::<Arc<RequestStages>>()
+            .unwrap()
+            .report();
+        assert!(
+            report.starts_with("after fetch from subgraph 'products' (parsing: "),
+            "{}",
+            report
+        );
+        assert!(report.ends_with(" (cancelled))"), "{}", report);
+        // the request is not counted as cancelled by the telemetry
+        assert_eq!(
+            context.get::<_, bool>(TIMED_OUT_CONTEXT_KEY).unwrap(),
+            Some(true)
+        );
+    }
+
+    #[tokio::test]
+    async fn it_limits_concurrent_router_requests() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        router:
+            concurrency_limit: 1
+        "#,
+        )
+        .unwrap();
+
+        let plugin = get_traffic_shaping_plugin(&config).await;
+        let mut mock_service = MockSupergraphService::new();
+        mock_service.expect_clone().returning(|| {
+            let mut mock_service = MockSupergraphService::new();
+
+            mock_service.expect_call().times(0..2).returning(move |_| {
+                Ok(SupergraphResponse::fake_builder()
+                    .data(json!({ "test": 1234_u32 }))
+                    .build()
+                    .unwrap())
+            });
+            mock_service
+        });
+
+        // the first service holds the only permit once ready
+        let mut first = plugin.supergraph_service(mock_service.clone().boxed());
+        first.ready().await.unwrap();
+
+        let error = plugin
+            .supergraph_service(mock_service.clone().boxed())
+            .oneshot(SupergraphRequest::fake_builder().build().unwrap())
+            .await
+            .map(|_| ())
+            .expect_err("should be in error due to the concurrency limit");
+        assert!(error.is::<Overloaded>());
+
+        // the permit is released once the response is ready
+        let _response = first
+            .call(SupergraphRequest::fake_builder().build().unwrap())
+            .await
+            .unwrap();
+        let _response = plugin
+            .supergraph_service(mock_service.clone().boxed())
+            .oneshot(SupergraphRequest::fake_builder().build().unwrap())
+            .await
+            .unwrap()
+            .next_response()
+            .await
+            .unwrap();
+    }
 }
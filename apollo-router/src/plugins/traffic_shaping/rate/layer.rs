@@ -8,16 +8,19 @@ use std::time::UNIX_EPOCH;
 
 use tower::Layer;
 
+use super::service::SharedCounter;
 use super::Rate;
 use super::RateLimit;
+use crate::cache::storage::KeyValueStorage;
 /// Enforces a rate limit on the number of requests the underlying
 /// service can handle over a period of time.
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub(crate) struct RateLimitLayer {
     rate: Rate,
     window_start: Arc<AtomicU64>,
     previous_nb_requests: Arc<AtomicUsize>,
     current_nb_requests: Arc<AtomicUsize>,
+    shared: Option<SharedCounter>,
 }
 
 impl RateLimitLayer {
@@ -34,8 +37,20 @@ impl RateLimitLayer {
             )),
             previous_nb_requests: Arc::default(),
             current_nb_requests: Arc::new(AtomicUsize::new(1)),
+            shared: None,
         }
     }
+
+    /// Count the requests under this key of the storage shared with the other routers, if any,
+    /// instead of in this router only.
+    pub(crate) fn with_storage(
+        mut self,
+        storage: Option<Arc<dyn KeyValueStorage>>,
+        key: String,
+    ) -> Self {
+        self.shared = storage.map(|storage| SharedCounter { storage, key });
+        self
+    }
 }
 
 impl<S> Layer<S> for RateLimitLayer {
@@ -48,6 +63,9 @@ impl<S> Layer<S> for RateLimitLayer {
             window_start: self.window_start.clone(),
             previous_nb_requests: self.previous_nb_requests.clone(),
             current_nb_requests: self.current_nb_requests.clone(),
+            shared: self.shared.clone(),
+            admission: None,
+            admitted: false,
         }
     }
 }
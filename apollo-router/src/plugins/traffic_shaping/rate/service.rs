@@ -7,14 +7,18 @@ use std::task::Poll;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 
+use futures::future::BoxFuture;
 use futures::ready;
+use futures::FutureExt;
+use tower::BoxError;
 use tower::Service;
 
 use super::future::ResponseFuture;
 use super::Rate;
+use crate::cache::storage::Increment;
+use crate::cache::storage::KeyValueStorage;
 use crate::plugins::traffic_shaping::rate::error::RateLimited;
 
-#[derive(Debug)]
 pub(crate) struct RateLimit<T> {
     pub(crate) inner: T,
     pub(crate) rate: Rate,
@@ -24,27 +28,56 @@ pub(crate) struct RateLimit<T> {
     pub(crate) window_start: Arc<AtomicU64>,
     pub(crate) previous_nb_requests: Arc<AtomicUsize>,
     pub(crate) current_nb_requests: Arc<AtomicUsize>,
+    /// The counter shared with the other routers, if any, used instead of the local ones
+    pub(crate) shared: Option<SharedCounter>,
+    /// The pending check of the shared counter
+    pub(crate) admission: Option<BoxFuture<'static, Result<bool, BoxError>>>,
+    /// Whether the shared counter admitted the next request
+    pub(crate) admitted: bool,
 }
 
-impl<S, Request> Service<Request> for RateLimit<S>
-where
-    S: Service<Request>,
-    S::Error: Into<tower::BoxError>,
-{
-    type Response = S::Response;
-    type Error = tower::BoxError;
-    type Future = ResponseFuture<S::Future>;
+/// Counter of the requests of the current window, kept in the storage shared by the routers.
+#[derive(Clone)]
+pub(crate) struct SharedCounter {
+    pub(crate) storage: Arc<dyn KeyValueStorage>,
+    pub(crate) key: String,
+}
 
-    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+impl SharedCounter {
+    /// Count a request in the current window unless it already reached the capacity, returning
+    /// whether the request is admitted. The counter of a window is checked and incremented at
+    /// once, and expires with it.
+    async fn admit(self, rate: Rate) -> Result<bool, BoxError> {
+        let window = now_millis() / rate.per().as_millis() as u64;
+        let counters = self
+            .storage
+            .increment(&[Increment {
+                key: format!("{}:{}", self.key, window),
+                value: 1,
+                ttl: rate.per(),
+                limit: Some(rate.num()),
+            }])
+            .await?;
+        Ok(counters.incremented)
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time must be after EPOCH")
+        .as_millis() as u64
+}
+
+impl<T> RateLimit<T> {
+    /// Count a request with the counters of this router, returning whether it is admitted.
+    fn admit_locally(&self) -> bool {
         let time_unit = self.rate.per().as_millis() as u64;
 
         let updated =
             self.window_start
                 .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |window_start| {
-                    let duration_now = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .expect("system time must be after EPOCH")
-                        .as_millis() as u64;
+                    let duration_now = now_millis();
                     if duration_now - window_start > self.rate.per().as_millis() as u64 {
                         Some(duration_now)
                     } else {
@@ -68,16 +101,56 @@ where
             + self.current_nb_requests.load(Ordering::SeqCst);
 
         if estimated_cap as u64 > self.rate.num() {
-            tracing::trace!("rate limit exceeded; sleeping.");
-            return Poll::Ready(Err(RateLimited::new().into()));
+            return false;
         }
 
         self.current_nb_requests.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+}
+
+impl<S, Request> Service<Request> for RateLimit<S>
+where
+    S: Service<Request>,
+    S::Error: Into<tower::BoxError>,
+{
+    type Response = S::Response;
+    type Error = tower::BoxError;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if !self.admitted {
+            let admitted = match &self.shared {
+                Some(shared) => {
+                    let rate = self.rate;
+                    let admission = self
+                        .admission
+                        .get_or_insert_with(|| shared.clone().admit(rate).boxed());
+                    let admitted = ready!(admission.poll_unpin(cx));
+                    self.admission = None;
+                    admitted.unwrap_or_else(|e| {
+                        // the requests are still limited by this router while the storage is unavailable
+                        tracing::error!(
+                            "couldn't count the request in the shared rate limit, counting it locally: {}",
+                            e
+                        );
+                        self.admit_locally()
+                    })
+                }
+                None => self.admit_locally(),
+            };
+            if !admitted {
+                tracing::trace!("rate limit exceeded; sleeping.");
+                return Poll::Ready(Err(RateLimited::new().into()));
+            }
+            self.admitted = true;
+        }
 
         Poll::Ready(ready!(self.inner.poll_ready(cx)).map_err(Into::into))
     }
 
     fn call(&mut self, request: Request) -> Self::Future {
+        self.admitted = false;
         ResponseFuture::new(self.inner.call(request))
     }
 }
@@ -0,0 +1,348 @@
+//! Retries of the subgraph requests. Implemented as a tower Layer.
+//!
+//! Queries failing with a retryable error (the subgraph could not be reached, did not respond in
+//! time, or answered that it is overloaded or unavailable) are sent again after an exponential
+//! backoff, capped and jittered, or after the delay of the `Retry-After` header of the subgraph.
+//! Mutations are never sent again, since they may have been applied already.
+//!
+//! The retries are limited by a budget shared by the requests to the subgraph, so that they do
+//! not multiply its load while it is failing.
+
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use rand::Rng;
+use tower::retry::budget::Budget;
+use tower::BoxError;
+use tower::Layer;
+use tower::ServiceExt;
+
+use crate::error::is_retryable_error;
+use crate::error::is_retryable_status;
+use crate::error::retry_after;
+use crate::error::FetchError;
+use crate::http_ext;
+use crate::query_planner::fetch::OperationKind;
+use crate::SubgraphRequest;
+use crate::SubgraphResponse;
+
+/// Time during which the requests are counted in the budget of the retries.
+const BUDGET_TTL: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
+pub(crate) struct RetryLayer {
+    name: String,
+    attempts: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+    budget: Arc<Budget>,
+}
+
+impl RetryLayer {
+    /// Send the requests up to `attempts` more times, waiting about `backoff` before the first
+    /// retry and twice as long before each of the following ones, up to `max_backoff`.
+    ///
+    /// The retries are allowed at `min_per_sec` per second, plus `retry_percent` of the requests
+    /// of the last seconds.
+    pub(crate) fn new(
+        name: &str,
+        attempts: u32,
+        backoff: Duration,
+        max_backoff: Duration,
+        min_per_sec: u32,
+        retry_percent: f32,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            attempts,
+            backoff,
+            max_backoff: max_backoff.max(backoff),
+            budget: Arc::new(Budget::new(BUDGET_TTL, min_per_sec, retry_percent)),
+        }
+    }
+}
+
+impl<S> Layer<S> for RetryLayer
+where
+    S: tower::Service<SubgraphRequest, Response = SubgraphResponse, Error = BoxError> + Clone,
+{
+    type Service = RetryService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        RetryService {
+            service,
+            layer: self.clone(),
+        }
+    }
+}
+
+pub(crate) struct RetryService<S> {
+    service: S,
+    layer: RetryLayer,
+}
+
+/// A copy of a request, to send it again.
+fn clone_request(request: &SubgraphRequest) -> SubgraphRequest {
+    SubgraphRequest::builder()
+        .originating_request(request.originating_request.clone())
+        .subgraph_request(http_ext::Request::from(&request.subgraph_request).inner)
+        .subgraph_name(request.subgraph_name.clone())
+        .operation_kind(request.operation_kind)
+        .context(request.context.clone())
+        .build()
+}
+
+impl<S> tower::Service<SubgraphRequest> for RetryService<S>
+where
+    S: tower::Service<SubgraphRequest, Response = SubgraphResponse, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    <S as tower::Service<SubgraphRequest>>::Future: Send + 'static,
+{
+    type Response = SubgraphResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: SubgraphRequest) -> Self::Future {
+        let service = self.service.clone();
+        if request.operation_kind == OperationKind::Mutation {
+            return Box::pin(service.oneshot(request));
+        }
+        let RetryLayer {
+            name,
+            attempts,
+            backoff,
+            max_backoff,
+            budget,
+        } = self.layer.clone();
+        budget.deposit();
+
+        Box::pin(async move {
+            let mut attempt = 0;
+            loop {
+                let call = service.clone().oneshot(clone_request(&request));
+                let result = call.await;
+                let (retryable, requested_delay) = match &result {
+                    Ok(response) => (
+                        is_retryable_status(response.response.status()),
+                        retry_after(response.response.headers()),
+                    ),
+                    Err(error) => (
+                        is_retryable_error(error),
+                        error
+                            .downcast_ref::<FetchError>()
+                            .and_then(FetchError::retry_after),
+                    ),
+                };
+                if !retryable || attempt >= attempts {
+                    return result;
+                }
+
+                let delay = match requested_delay {
+                    // the subgraph will not be available before the longest backoff
+                    Some(delay) if delay > max_backoff => return result,
+                    Some(delay) => delay,
+                    None => jittered(
+                        backoff
+                            .saturating_mul(2u32.saturating_pow(attempt))
+                            .min(max_backoff),
+                    ),
+                };
+                if budget.withdraw().is_err() {
+                    tracing::debug!(
+                        "not retrying the request to '{}': the retry budget is exhausted",
+                        name
+                    );
+                    return result;
+                }
+                attempt += 1;
+                tracing::debug!(
+                    "retrying the request to '{}' in {:?} (attempt {} of {})",
+                    name,
+                    delay,
+                    attempt,
+                    attempts
+                );
+                tokio::time::sleep(delay).await;
+            }
+        })
+    }
+}
+
+/// A delay between half the backoff and the backoff, so that the clients failing together do
+/// not retry together.
+fn jittered(backoff: Duration) -> Duration {
+    let half = backoff / 2;
+    half + Duration::from_nanos(
+        rand::thread_rng().gen_range(0..=(backoff - half).as_nanos().min(u64::MAX as u128) as u64),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicU32;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use http::StatusCode;
+    use serde_json_bytes::json;
+
+    use super::*;
+    use crate::graphql;
+
+    /// A subgraph which cannot be reached for the first `failures` requests.
+    fn subgraph(
+        failures: u32,
+        calls: Arc<AtomicU32>,
+    ) -> impl tower::Service<
+        SubgraphRequest,
+        Response = SubgraphResponse,
+        Error = BoxError,
+        Future = BoxFuture<'static, Result<SubgraphResponse, BoxError>>,
+    > + Clone {
+        tower::service_fn(move |_request: SubgraphRequest| {
+            let call = calls.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                if call < failures {
+                    Err(FetchError::SubrequestConnectError {
+                        service: "accounts".to_string(),
+                        reason: "connection refused".to_string(),
+                    }
+                    .into())
+                } else {
+                    Ok(SubgraphResponse::fake_builder()
+                        .data(json!({ "me": { "name": "Ada" } }))
+                        .build())
+                }
+            }) as BoxFuture<'static, _>
+        })
+    }
+
+    fn layer(attempts: u32, min_per_sec: u32) -> RetryLayer {
+        RetryLayer::new(
+            "accounts",
+            attempts,
+            Duration::from_millis(1),
+            Duration::from_millis(10),
+            min_per_sec,
+            0.0,
+        )
+    }
+
+    fn request(operation_kind: OperationKind) -> SubgraphRequest {
+        SubgraphRequest::fake_builder()
+            .subgraph_request(
+                http::Request::builder()
+                    .body(graphql::Request::builder().query("{me{name}}").build())
+                    .unwrap(),
+            )
+            .operation_kind(operation_kind)
+            .build()
+    }
+
+    #[tokio::test]
+    async fn it_retries_the_retryable_errors() {
+        let calls = Arc::new(AtomicU32::new(0));
+        let service = layer(2, 10).layer(subgraph(2, calls.clone()));
+        let response = service
+            .oneshot(request(OperationKind::Query))
+            .await
+            .unwrap();
+        assert_eq!(response.response.status(), StatusCode::OK);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn it_stops_after_the_last_attempt() {
+        let calls = Arc::new(AtomicU32::new(0));
+        let service = layer(2, 10).layer(subgraph(3, calls.clone()));
+        let error = service
+            .oneshot(request(OperationKind::Query))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<FetchError>(),
+            Some(FetchError::SubrequestConnectError { .. })
+        ));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn it_does_not_retry_the_mutations() {
+        let calls = Arc::new(AtomicU32::new(0));
+        let service = layer(2, 10).layer(subgraph(1, calls.clone()));
+        assert!(service
+            .oneshot(request(OperationKind::Mutation))
+            .await
+            .is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn it_stops_when_the_budget_is_exhausted() {
+        let calls = Arc::new(AtomicU32::new(0));
+        let layer = layer(2, 1);
+        // the budget allows one retry per second over ten seconds, not two per request
+        for _ in 0..10 {
+            let _ = layer
+                .layer(subgraph(u32::MAX, calls.clone()))
+                .oneshot(request(OperationKind::Query))
+                .await;
+        }
+        assert!(calls.load(Ordering::SeqCst) < 30);
+    }
+
+    #[tokio::test]
+    async fn it_waits_for_the_retry_after_delay() {
+        let calls = Arc::new(AtomicU32::new(0));
+        let unavailable = |retry_after: &'static str| {
+            let calls = calls.clone();
+            tower::service_fn(move |_request: SubgraphRequest| {
+                let call = calls.fetch_add(1, Ordering::SeqCst);
+                let mut response = SubgraphResponse::fake_builder().build();
+                if call == 0 {
+                    *response.response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                    response
+                        .response
+                        .headers_mut()
+                        .insert(http::header::RETRY_AFTER, retry_after.parse().unwrap());
+                }
+                Box::pin(async move { Ok(response) }) as BoxFuture<'static, _>
+            })
+        };
+
+        // longer than the longest backoff: the response is kept
+        let response = layer(2, 10)
+            .layer(unavailable("120"))
+            .oneshot(request(OperationKind::Query))
+            .await
+            .unwrap();
+        assert_eq!(response.response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        calls.store(0, Ordering::SeqCst);
+        let response = layer(2, 10)
+            .layer(unavailable("0"))
+            .oneshot(request(OperationKind::Query))
+            .await
+            .unwrap();
+        assert_eq!(response.response.status(), StatusCode::OK);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn it_jitters_the_backoff() {
+        let backoff = Duration::from_millis(100);
+        for _ in 0..100 {
+            let delay = jittered(backoff);
+            assert!(delay >= backoff / 2 && delay <= backoff);
+        }
+    }
+}
@@ -2,6 +2,7 @@
 
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::Context;
 use std::task::Poll;
 
@@ -9,6 +10,7 @@ use pin_project_lite::pin_project;
 use tokio::time::Sleep;
 
 use super::error::Elapsed;
+use crate::services::request_stages::RequestStages;
 
 pin_project! {
     /// [`Timeout`] response future
@@ -20,12 +22,24 @@ pin_project! {
         response: T,
         #[pin]
         sleep: Pin<Box<Sleep>>,
+        context: Option<crate::Context>,
+        stages: Option<Arc<RequestStages>>,
     }
 }
 
 impl<T> ResponseFuture<T> {
-    pub(crate) fn new(response: T, sleep: Pin<Box<Sleep>>) -> Self {
-        ResponseFuture { response, sleep }
+    pub(crate) fn new(
+        response: T,
+        sleep: Pin<Box<Sleep>>,
+        context: Option<crate::Context>,
+        stages: Option<Arc<RequestStages>>,
+    ) -> Self {
+        ResponseFuture {
+            response,
+            sleep,
+            context,
+            stages,
+        }
     }
 }
 
@@ -48,7 +62,16 @@ where
         // Now check the sleep
         match Pin::new(&mut this.sleep).poll(cx) {
             Poll::Pending => Poll::Pending,
-            Poll::Ready(_) => Poll::Ready(Err(Elapsed::new().into())),
+            Poll::Ready(_) => {
+                if let Some(context) = this.context.as_ref() {
+                    let _ = context.insert(super::TIMED_OUT_CONTEXT_KEY, true);
+                }
+                // the stages are only reported in the logs and spans, not to the client
+                if let Some(stages) = this.stages.as_ref() {
+                    tracing::warn!(stages = %stages.report(), "request timed out");
+                }
+                Poll::Ready(Err(Elapsed::new().into()))
+            }
         }
     }
 }
@@ -21,6 +21,43 @@ use tower::Service;
 use self::future::ResponseFuture;
 pub(crate) use self::layer::TimeoutLayer;
 pub(crate) use crate::plugins::traffic_shaping::timeout::error::Elapsed;
+use crate::services::layers::client_identification::CLIENT_TIMEOUT_CONTEXT_KEY;
+use crate::services::request_stages::RequestStages;
+use crate::services::subgraph;
+use crate::services::supergraph;
+
+/// Requests that can override the timeout of the layer.
+/// Set in the context of the requests whose timeout elapsed, before they are dropped.
+pub(crate) const TIMED_OUT_CONTEXT_KEY: &str = "apollo_router::timed_out";
+
+pub(crate) trait TimeoutOverride {
+    /// The timeout of this request, instead of the one of the layer.
+    fn timeout_override(&self) -> Option<Duration> {
+        None
+    }
+
+    /// The context of this request, in which its stages are recorded, to report them when it
+    /// times out.
+    fn timeout_context(&self) -> Option<crate::Context> {
+        None
+    }
+}
+
+impl TimeoutOverride for supergraph::Request {
+    fn timeout_override(&self) -> Option<Duration> {
+        self.context
+            .get::<_, u64>(CLIENT_TIMEOUT_CONTEXT_KEY)
+            .ok()
+            .flatten()
+            .map(Duration::from_millis)
+    }
+
+    fn timeout_context(&self) -> Option<crate::Context> {
+        Some(self.context.clone())
+    }
+}
+
+impl TimeoutOverride for subgraph::Request {}
 
 /// Applies a timeout to requests.
 #[derive(Debug)]
@@ -45,6 +82,7 @@ impl<T> Timeout<T> {
 
 impl<S, Request> Service<Request> for Timeout<S>
 where
+    Request: TimeoutOverride,
     S: Service<Request>,
     S::Error: Into<tower::BoxError>,
 {
@@ -81,13 +119,19 @@ where
     }
 
     fn call(&mut self, request: Request) -> Self::Future {
+        let sleep = self
+            .sleep
+            .take()
+            .expect("poll_ready must been called before");
+        // the timeout of the request can only be known once it is received
+        let sleep = match request.timeout_override() {
+            Some(timeout) => Box::pin(tokio::time::sleep(timeout)),
+            None => sleep,
+        };
+        let context = request.timeout_context();
+        let stages = context.as_ref().map(RequestStages::track);
         let response = self.inner.call(request);
 
-        ResponseFuture::new(
-            response,
-            self.sleep
-                .take()
-                .expect("poll_ready must been called before"),
-        )
+        ResponseFuture::new(response, sleep, context, stages)
     }
 }
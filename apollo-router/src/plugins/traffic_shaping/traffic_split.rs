@@ -0,0 +1,343 @@
+//! Split the requests of a subgraph between several endpoints.
+//!
+//! Every request is sent to one of the endpoints, picked with a probability proportional to its
+//! weight, e.g. to roll out a new version of a subgraph to a small share of the traffic. When a
+//! sticky header is configured, the requests with the same value for that header always go to
+//! the same endpoint. The requests, errors and latency of every endpoint are exported with the
+//! metrics of the router so that the versions can be compared.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Instant;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use http::header::HeaderName;
+use http::Uri;
+use opentelemetry::metrics::ObserverResult;
+use opentelemetry::KeyValue;
+use rand::Rng;
+use sha2::Digest;
+use sha2::Sha256;
+use tower::BoxError;
+use tower::Layer;
+use tower::Service;
+
+use crate::plugins::telemetry::metrics::PluginMetrics;
+use crate::SubgraphRequest;
+use crate::SubgraphResponse;
+
+#[derive(Debug, Default)]
+struct TargetStats {
+    requests: AtomicU64,
+    /// Requests that returned an error or a 5xx status code.
+    errors: AtomicU64,
+    duration_micros: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Target {
+    uri: Uri,
+    weight: u64,
+    stats: TargetStats,
+    /// The subgraph and target attributes of its metrics.
+    attributes: [KeyValue; 2],
+}
+
+/// Traffic split of a subgraph.
+///
+/// The statistics of the endpoints are shared by all the services created from the same layer.
+#[derive(Debug, Clone)]
+pub(crate) struct TrafficSplitLayer {
+    targets: Arc<Vec<Target>>,
+    total_weight: u64,
+    sticky_header: Option<HeaderName>,
+}
+
+impl TrafficSplitLayer {
+    /// Create the split from the name, URL and weight of the endpoints.
+    pub(crate) fn new(
+        subgraph: &str,
+        targets: impl IntoIterator<Item = (String, url::Url, u32)>,
+        sticky_header: Option<&str>,
+    ) -> Result<Self, BoxError> {
+        let targets = targets
+            .into_iter()
+            .map(|(name, url, weight)| {
+                Ok(Target {
+                    attributes: [
+                        KeyValue::new("subgraph", subgraph.to_string()),
+                        KeyValue::new("target", name),
+                    ],
+                    uri: Uri::from_str(url.as_str())?,
+                    weight: weight.into(),
+                    stats: Default::default(),
+                })
+            })
+            .collect::<Result<Vec<_>, BoxError>>()?;
+        let total_weight = targets.iter().map(|target| target.weight).sum();
+        if total_weight == 0 {
+            return Err("the traffic split needs a target with a weight greater than 0".into());
+        }
+        Ok(TrafficSplitLayer {
+            targets: Arc::new(targets),
+            total_weight,
+            sticky_header: sticky_header.map(HeaderName::from_str).transpose()?,
+        })
+    }
+
+    /// The index of the target receiving the request.
+    fn pick(&self, request: &SubgraphRequest) -> usize {
+        let sticky_value = self
+            .sticky_header
+            .as_ref()
+            .and_then(|header| request.originating_request.headers().get(header));
+        let value = match sticky_value {
+            // hashed with a fixed algorithm, so that all the router instances agree
+            Some(value) => {
+                let digest = Sha256::digest(value.as_bytes());
+                u64::from_be_bytes(digest[..8].try_into().expect("the digest is 32 bytes"))
+            }
+            None => rand::thread_rng().gen_range(0..self.total_weight),
+        };
+
+        let mut point = value % self.total_weight;
+        for (index, target) in self.targets.iter().enumerate() {
+            if point < target.weight {
+                return index;
+            }
+            point -= target.weight;
+        }
+        unreachable!("the point is lower than the total weight")
+    }
+}
+
+/// Export the statistics of the endpoints of the traffic splits with the metrics of the router.
+pub(crate) fn register_metrics(
+    splits: Arc<HashMap<String, TrafficSplitLayer>>,
+    metrics: &PluginMetrics,
+) {
+    metrics.register_observers(move |meter| {
+        let requests = splits.clone();
+        meter
+            .u64_sum_observer(
+                "apollo_router_subgraph_target_requests_total",
+                move |result: ObserverResult<u64>| {
+                    for target in requests.values().flat_map(|split| split.targets.iter()) {
+                        result.observe(
+                            target.stats.requests.load(Ordering::Relaxed),
+                            &target.attributes,
+                        )
+                    }
+                },
+            )
+            .with_description("Requests sent to the endpoint")
+            .init();
+        let errors = splits.clone();
+        meter
+            .u64_sum_observer(
+                "apollo_router_subgraph_target_errors_total",
+                move |result: ObserverResult<u64>| {
+                    for target in errors.values().flat_map(|split| split.targets.iter()) {
+                        result.observe(
+                            target.stats.errors.load(Ordering::Relaxed),
+                            &target.attributes,
+                        )
+                    }
+                },
+            )
+            .with_description(
+                "Requests to the endpoint that returned an error or a 5xx status code",
+            )
+            .init();
+        let duration = splits.clone();
+        meter
+            .f64_sum_observer(
+                "apollo_router_subgraph_target_request_duration_seconds_total",
+                move |result: ObserverResult<f64>| {
+                    for target in duration.values().flat_map(|split| split.targets.iter()) {
+                        result.observe(
+                            target.stats.duration_micros.load(Ordering::Relaxed) as f64
+                                / 1_000_000.0,
+                            &target.attributes,
+                        )
+                    }
+                },
+            )
+            .with_description("Time spent waiting for the responses of the endpoint")
+            .init();
+    });
+}
+
+impl<S> Layer<S> for TrafficSplitLayer {
+    type Service = TrafficSplit<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        TrafficSplit {
+            inner: service,
+            split: self.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TrafficSplit<S> {
+    inner: S,
+    split: TrafficSplitLayer,
+}
+
+impl<S> Service<SubgraphRequest> for TrafficSplit<S>
+where
+    S: Service<SubgraphRequest, Response = SubgraphResponse>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = SubgraphResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<SubgraphResponse, BoxError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, mut request: SubgraphRequest) -> Self::Future {
+        let index = self.split.pick(&request);
+        *request.subgraph_request.uri_mut() = self.split.targets[index].uri.clone();
+        let targets = self.split.targets.clone();
+        let start = Instant::now();
+        let response = self.inner.call(request);
+        async move {
+            let response = response.await.map_err(Into::into);
+            let stats = &targets[index].stats;
+            stats.requests.fetch_add(1, Ordering::Relaxed);
+            stats
+                .duration_micros
+                .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+            if !matches!(
+                &response,
+                Ok(response) if !response.response.status().is_server_error()
+            ) {
+                stats.errors.fetch_add(1, Ordering::Relaxed);
+            }
+            response
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use http::StatusCode;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::plugin::test::MockSubgraphService;
+
+    fn split(sticky_header: Option<&str>) -> TrafficSplitLayer {
+        TrafficSplitLayer::new(
+            "products",
+            vec![
+                (
+                    "v1".to_string(),
+                    url::Url::parse("http://products-v1/graphql").unwrap(),
+                    95,
+                ),
+                (
+                    "v2".to_string(),
+                    url::Url::parse("http://products-v2/graphql").unwrap(),
+                    5,
+                ),
+            ],
+            sticky_header,
+        )
+        .unwrap()
+    }
+
+    /// Send the requests through the split, and return the URIs they were sent to.
+    async fn send(split: &TrafficSplitLayer, requests: Vec<SubgraphRequest>) -> Vec<String> {
+        let uris = Arc::new(Mutex::new(Vec::new()));
+        for request in requests {
+            let mut service = MockSubgraphService::new();
+            let received = uris.clone();
+            service.expect_call().times(1).returning(move |request| {
+                received
+                    .lock()
+                    .unwrap()
+                    .push(request.subgraph_request.uri().to_string());
+                Ok(SubgraphResponse::fake_builder()
+                    .status_code(StatusCode::BAD_GATEWAY)
+                    .build())
+            });
+            split.layer(service).oneshot(request).await.unwrap();
+        }
+        let uris = uris.lock().unwrap().clone();
+        uris
+    }
+
+    #[tokio::test]
+    async fn it_splits_requests_by_weight() {
+        let split = split(None);
+        let uris = send(
+            &split,
+            (0..1000)
+                .map(|_| SubgraphRequest::fake_builder().build())
+                .collect(),
+        )
+        .await;
+
+        let canary = uris
+            .iter()
+            .filter(|uri| uri.as_str() == "http://products-v2/graphql")
+            .count();
+        assert!(canary > 0 && canary < 150, "{} canary requests", canary);
+        assert_eq!(
+            split.targets[1].stats.requests.load(Ordering::Relaxed),
+            canary as u64
+        );
+        assert_eq!(
+            split.targets[0].stats.errors.load(Ordering::Relaxed),
+            1000 - canary as u64
+        );
+    }
+
+    #[tokio::test]
+    async fn it_sends_the_requests_of_a_client_to_the_same_target() {
+        let split = split(Some("x-client-id"));
+        let request = |client: &str| {
+            SubgraphRequest::fake_builder()
+                .originating_request(Arc::new(
+                    http::Request::builder()
+                        .header("x-client-id", client)
+                        .body(Default::default())
+                        .unwrap(),
+                ))
+                .build()
+        };
+
+        for client in ["a", "b", "c", "d"] {
+            let uris = send(&split, (0..10).map(|_| request(client)).collect()).await;
+            assert!(uris.iter().all(|uri| *uri == uris[0]));
+        }
+    }
+
+    #[test]
+    fn it_rejects_splits_without_weight() {
+        assert!(TrafficSplitLayer::new(
+            "products",
+            vec![(
+                "v1".to_string(),
+                url::Url::parse("http://products-v1/graphql").unwrap(),
+                0,
+            )],
+            None,
+        )
+        .is_err());
+    }
+}
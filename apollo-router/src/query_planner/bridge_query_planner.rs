@@ -1,7 +1,10 @@
 //! Calls out to nodejs query planner
 
+use std::collections::BTreeMap;
 use std::fmt::Debug;
+use std::num::NonZeroUsize;
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures::future::BoxFuture;
 use opentelemetry::trace::SpanKind;
@@ -9,7 +12,11 @@ use router_bridge::planner::IncrementalDeliverySupport;
 use router_bridge::planner::PlanSuccess;
 use router_bridge::planner::Planner;
 use router_bridge::planner::QueryPlannerConfig;
+use router_bridge::planner::UsageReporting;
 use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
 use tower::BoxError;
 use tower::Service;
 use tracing::Instrument;
@@ -17,10 +24,15 @@ use tracing::Instrument;
 use super::PlanNode;
 use super::QueryKey;
 use super::QueryPlanOptions;
+use crate::cache::storage::KeyValueStorage;
 use crate::error::QueryPlannerError;
 use crate::introspection::Introspection;
+use crate::plugins::traffic_shaping::MissingEntities;
 use crate::plugins::traffic_shaping::TrafficShaping;
+use crate::services::request_stages;
+use crate::services::request_stages::Stage;
 use crate::services::QueryPlannerContent;
+use crate::spec::ValidationError;
 use crate::*;
 
 pub(crate) static USAGE_REPORTING: &str = "apollo_telemetry::usage_reporting";
@@ -35,6 +47,17 @@ pub(crate) struct BridgeQueryPlanner {
     introspection: Option<Arc<Introspection>>,
     configuration: Arc<Configuration>,
     deduplicate_variables: bool,
+    entity_batch_sizes: BTreeMap<String, NonZeroUsize>,
+    missing_entities: BTreeMap<String, MissingEntities>,
+    storage: Option<PlanStorage>,
+}
+
+/// Storage sharing the query plans with the other routers, which evicts them after their time to
+/// live.
+#[derive(Clone)]
+pub(crate) struct PlanStorage {
+    pub(crate) storage: Arc<dyn KeyValueStorage>,
+    pub(crate) ttl: Duration,
 }
 
 impl BridgeQueryPlanner {
@@ -46,6 +69,14 @@ impl BridgeQueryPlanner {
         // FIXME: The variables deduplication parameter lives in the traffic_shaping section of the config
         let deduplicate_variables =
             TrafficShaping::get_configuration_deduplicate_variables(&configuration);
+        let entity_batch_sizes = TrafficShaping::get_configuration_entity_batch_sizes(
+            &configuration,
+            schema.subgraphs().map(|(name, _)| name),
+        );
+        let missing_entities = TrafficShaping::get_configuration_missing_entities(
+            &configuration,
+            schema.subgraphs().map(|(name, _)| name),
+        );
         Ok(Self {
             planner: Arc::new(
                 Planner::new(
@@ -62,9 +93,107 @@ impl BridgeQueryPlanner {
             introspection,
             configuration,
             deduplicate_variables,
+            entity_batch_sizes,
+            missing_entities,
+            storage: None,
         })
     }
 
+    /// Share the query plans with the other routers through this storage.
+    pub(crate) fn with_storage(mut self, storage: PlanStorage) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// The key of a query plan in the storage. The plans depend on the schema, the planner
+    /// configuration and the version of the router, so the routers differing by any of them do
+    /// not share them.
+    fn storage_key(&self, query: &str, operation: Option<&str>) -> Option<String> {
+        let schema_id = self.schema.schema_id.as_deref()?;
+        let mut hasher = Sha256::new();
+        hasher.update(std::env!("CARGO_PKG_VERSION"));
+        hasher.update([u8::from(
+            self.configuration.server.experimental_defer_support,
+        )]);
+        hasher.update(operation.unwrap_or_default().len().to_be_bytes());
+        hasher.update(operation.unwrap_or_default());
+        hasher.update(query);
+        Some(format!(
+            "apollo_router:plan:{}:{}",
+            schema_id,
+            hex::encode(hasher.finalize())
+        ))
+    }
+
+    /// The plan of a query, stored by another router or else computed by the planner.
+    async fn planner_result(
+        &self,
+        query: &str,
+        operation: Option<String>,
+    ) -> Result<PlanSuccess<QueryPlanResult>, QueryPlannerError> {
+        let stored = match &self.storage {
+            Some(storage) => self
+                .storage_key(query, operation.as_deref())
+                .map(|key| (storage, key)),
+            None => None,
+        };
+        if let Some((storage, key)) = &stored {
+            match storage.storage.get(std::slice::from_ref(key)).await {
+                Ok(values) => {
+                    if let Some(value) = values.into_iter().next().flatten() {
+                        match serde_json::from_slice::<StoredPlan>(&value) {
+                            Ok(plan) => {
+                                return Ok(PlanSuccess {
+                                    data: plan.data,
+                                    usage_reporting: plan.usage_reporting,
+                                })
+                            }
+                            Err(e) => tracing::warn!("couldn't read the stored query plan: {}", e),
+                        }
+                    }
+                }
+                Err(e) => tracing::warn!("couldn't read the stored query plan: {}", e),
+            }
+        }
+
+        let planner_result = self
+            .planner
+            .plan(query.to_string(), operation)
+            .await
+            .map_err(QueryPlannerError::RouterBridgeError)?
+            .into_result()
+            .map_err(|errors| {
+                // the planner does not locate the errors in the query
+                let validation_errors = ValidationError::from_plan_errors(
+                    &errors,
+                    query,
+                    self.configuration.server.experimental_error_source_excerpts,
+                );
+                QueryPlannerError::PlanningErrors(errors, validation_errors)
+            })?;
+
+        if let Some((storage, key)) = stored {
+            if planner_result.data.query_plan.node.is_some() {
+                let plan = StoredPlan {
+                    data: planner_result.data.clone(),
+                    usage_reporting: planner_result.usage_reporting.clone(),
+                };
+                let stored = match serde_json::to_vec(&plan) {
+                    Ok(value) => storage
+                        .storage
+                        .insert(key, value, Some(storage.ttl))
+                        .await
+                        .map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                };
+                if let Err(e) = stored {
+                    tracing::warn!("couldn't store the query plan: {}", e);
+                }
+            }
+        }
+        Ok(planner_result)
+    }
+
     async fn parse_selections(&self, query: String) -> Result<Query, QueryPlannerError> {
         let schema = self.schema.clone();
         let configuration = self.configuration.clone();
@@ -85,8 +214,7 @@ impl BridgeQueryPlanner {
             Some(introspection) => {
                 let response = introspection
                     .execute(self.schema.as_string(), query)
-                    .await
-                    .map_err(QueryPlannerError::Introspection)?;
+                    .await?;
 
                 Ok(QueryPlannerContent::Introspection {
                     response: Box::new(response),
@@ -102,13 +230,8 @@ impl BridgeQueryPlanner {
         operation: Option<String>,
         mut selections: Query,
     ) -> Result<QueryPlannerContent, QueryPlannerError> {
-        let planner_result = self
-            .planner
-            .plan(query, operation)
-            .await
-            .map_err(QueryPlannerError::RouterBridgeError)?
-            .into_result()
-            .map_err(QueryPlannerError::from)?;
+        let stats_report_key = crate::signature::stats_report_key(&query, operation.as_deref());
+        let planner_result = self.planner_result(&query, operation).await?;
 
         match planner_result {
             PlanSuccess {
@@ -117,8 +240,12 @@ impl BridgeQueryPlanner {
                         query_plan: QueryPlan { node: Some(node) },
                         formatted_query_plan,
                     },
-                usage_reporting,
+                mut usage_reporting,
             } => {
+                // keep the stats report keys consistent with the signatures computed by the router
+                if let Ok(stats_report_key) = stats_report_key {
+                    usage_reporting.stats_report_key = stats_report_key;
+                }
                 let subselections = node.parse_subselections(&*self.schema);
                 selections.subselections = subselections;
                 Ok(QueryPlannerContent::Plan {
@@ -128,6 +255,8 @@ impl BridgeQueryPlanner {
                         formatted_query_plan,
                         options: QueryPlanOptions {
                             enable_deduplicate_variables: self.deduplicate_variables,
+                            entity_batch_sizes: self.entity_batch_sizes.clone(),
+                            missing_entities: self.missing_entities.clone(),
                         },
                     }),
                     query: Arc::new(selections),
@@ -165,10 +294,22 @@ impl Service<QueryPlannerRequest> for BridgeQueryPlanner {
     fn call(&mut self, req: QueryPlannerRequest) -> Self::Future {
         let this = self.clone();
         let fut = async move {
-            match this
-                .get((req.query.clone(), req.operation_name.to_owned()))
+            let context = &req.context;
+            let content = async {
+                let selections = request_stages::record(
+                    context,
+                    Stage::Parsing,
+                    this.parse_selections(req.query.clone()),
+                )
+                .await?;
+                request_stages::record(
+                    context,
+                    Stage::QueryPlanning,
+                    this.plan_selections(req.query.clone(), req.operation_name.clone(), selections),
+                )
                 .await
-            {
+            };
+            match content.await {
                 Ok(query_planner_content) => Ok(QueryPlannerResponse::new(
                     query_planner_content,
                     req.context,
@@ -183,26 +324,38 @@ impl Service<QueryPlannerRequest> for BridgeQueryPlanner {
 }
 
 impl BridgeQueryPlanner {
-    async fn get(&self, key: QueryKey) -> Result<QueryPlannerContent, QueryPlannerError> {
+    pub(crate) async fn get(
+        &self,
+        key: QueryKey,
+    ) -> Result<QueryPlannerContent, QueryPlannerError> {
         let selections = self.parse_selections(key.0.clone()).await?;
+        self.plan_selections(key.0, key.1, selections).await
+    }
 
+    /// Plan a parsed query, or execute it if it is an introspection query.
+    async fn plan_selections(
+        &self,
+        query: String,
+        operation: Option<String>,
+        selections: Query,
+    ) -> Result<QueryPlannerContent, QueryPlannerError> {
         if selections.contains_introspection() {
-            return self.introspection(key.0).await;
+            return self.introspection(query).await;
         }
 
-        self.plan(key.0, key.1, selections).await
+        self.plan(query, operation, selections).await
     }
 }
 
 /// Data coming from the `plan` method on the router_bridge
-#[derive(Debug, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct QueryPlanResult {
     formatted_query_plan: Option<String>,
     query_plan: QueryPlan,
 }
 
-#[derive(Debug, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 /// The root query plan container.
 struct QueryPlan {
@@ -210,6 +363,13 @@ struct QueryPlan {
     node: Option<PlanNode>,
 }
 
+/// A query plan as it is shared with the other routers.
+#[derive(Deserialize, Serialize)]
+struct StoredPlan {
+    data: QueryPlanResult,
+    usage_reporting: UsageReporting,
+}
+
 #[cfg(test)]
 mod tests {
     use serde_json::json;
@@ -242,6 +402,47 @@ mod tests {
         }
     }
 
+    #[test(tokio::test)]
+    async fn it_shares_the_plans_through_the_storage() {
+        let storage = PlanStorage {
+            storage: Arc::new(crate::cache::storage::InMemoryStorage::default()),
+            ttl: Duration::from_secs(60),
+        };
+        let query = include_str!("testdata/query.graphql").to_string();
+        let planner = BridgeQueryPlanner::new(Arc::new(example_schema()), None, Default::default())
+            .await
+            .unwrap()
+            .with_storage(storage.clone());
+        planner.get((query.clone(), None)).await.unwrap();
+
+        // the other routers read the plan instead of planning the query
+        let key = planner.storage_key(&query, None).unwrap();
+        let value = storage.storage.get(&[key.clone()]).await.unwrap().remove(0);
+        let mut stored: StoredPlan = serde_json::from_slice(&value.unwrap()).unwrap();
+        stored.data.formatted_query_plan = Some("stored".to_string());
+        storage
+            .storage
+            .insert(key, serde_json::to_vec(&stored).unwrap(), None)
+            .await
+            .unwrap();
+        let planner = BridgeQueryPlanner::new(Arc::new(example_schema()), None, Default::default())
+            .await
+            .unwrap()
+            .with_storage(storage);
+        match planner.get((query.clone(), None)).await.unwrap() {
+            QueryPlannerContent::Plan { plan, .. } => {
+                assert_eq!(plan.formatted_query_plan.as_deref(), Some("stored"))
+            }
+            _ => panic!("expected a query plan"),
+        }
+
+        // another operation of the document has its own plan
+        assert_ne!(
+            planner.storage_key(&query, Some("other")),
+            planner.storage_key(&query, None)
+        );
+    }
+
     #[test(tokio::test)]
     async fn test_plan_invalid_query() {
         let planner = BridgeQueryPlanner::new(
@@ -262,11 +463,22 @@ mod tests {
             .unwrap_err();
 
         match err {
-            QueryPlannerError::PlanningErrors(plan_errors) => {
+            QueryPlannerError::PlanningErrors(plan_errors, validation_errors) => {
                 insta::with_settings!({sort_maps => true}, {
                     insta::assert_json_snapshot!("plan_invalid_query_usage_reporting", plan_errors.usage_reporting);
                 });
                 insta::assert_debug_snapshot!("plan_invalid_query_errors", plan_errors.errors);
+                assert_eq!(
+                    validation_errors[0].token.as_deref(),
+                    Some("UnusedTestFragment")
+                );
+                assert_eq!(
+                    validation_errors[0].locations,
+                    vec![crate::error::Location {
+                        line: 1,
+                        column: 10
+                    }]
+                );
             }
             _ => {
                 panic!("invalid query planning should have failed");
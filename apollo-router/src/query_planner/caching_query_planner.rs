@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::num::NonZeroUsize;
 use std::sync::Arc;
 use std::task;
 
@@ -11,6 +12,9 @@ use tower::ServiceExt;
 
 use super::QueryKey;
 use super::USAGE_REPORTING;
+use crate::cache::partitioned::PartitionedCache;
+use crate::cache::partitioned::TENANT_CONTEXT_KEY;
+use crate::cache::CacheStatistics;
 use crate::cache::DeduplicatingCache;
 use crate::error::CacheResolverError;
 use crate::error::QueryPlannerError;
@@ -22,7 +26,7 @@ use crate::*;
 /// The query planner performs LRU caching.
 #[derive(Clone)]
 pub(crate) struct CachingQueryPlanner<T: Clone> {
-    cache: Arc<DeduplicatingCache<QueryKey, Result<QueryPlannerContent, Arc<BoxError>>>>,
+    cache: Arc<PartitionedCache<QueryKey, Result<QueryPlannerContent, Arc<BoxError>>>>,
     delegate: T,
 }
 
@@ -32,9 +36,23 @@ where
 {
     /// Creates a new query planner that caches the results of another [`QueryPlanner`].
     pub(crate) async fn new(delegate: T, plan_cache_limit: usize) -> CachingQueryPlanner<T> {
-        let cache = Arc::new(DeduplicatingCache::with_capacity(plan_cache_limit).await);
+        let cache = Arc::new(
+            DeduplicatingCache::with_capacity(plan_cache_limit)
+                .await
+                .into(),
+        );
         Self { cache, delegate }
     }
+
+    /// Give each tenant its own partition of the cache.
+    pub(crate) async fn with_tenants(mut self, max_tenants: NonZeroUsize) -> Self {
+        self.cache = Arc::new((*self.cache).clone().with_tenants(max_tenants).await);
+        self
+    }
+
+    pub(crate) fn cache(&self) -> Arc<dyn CacheStatistics> {
+        self.cache.clone()
+    }
 }
 
 impl<T: Clone + Send + 'static> tower::Service<QueryPlannerRequest> for CachingQueryPlanner<T>
@@ -53,126 +71,102 @@ where
     fn call(&mut self, request: QueryPlannerRequest) -> Self::Future {
         let mut qp = self.clone();
         Box::pin(async move {
-            let key = (request.query.clone(), request.operation_name.to_owned());
             let context = request.context.clone();
-            let entry = qp.cache.get(&key).await;
-            if entry.is_first() {
-                let res = qp.delegate.ready().await?.call(request).await;
-                match res {
-                    Ok(QueryPlannerResponse { content, context }) => {
-                        entry.insert(Ok(content.clone())).await;
-
-                        if let QueryPlannerContent::Plan { plan, .. } = &content {
-                            match (&plan.usage_reporting).serialize(Serializer) {
-                                Ok(v) => {
-                                    context.insert_json_value(USAGE_REPORTING, v);
-                                }
-                                Err(e) => {
-                                    tracing::error!(
-                                        "usage reporting was not serializable to context, {}",
-                                        e
-                                    );
-                                }
-                            }
-                        }
-                        Ok(QueryPlannerResponse { content, context })
+            let tenant: Option<String> = context.get(TENANT_CONTEXT_KEY).ok().flatten();
+            let cache = qp.cache.partition(tenant.as_deref()).await;
+            let key = (request.query.clone(), request.operation_name.to_owned());
+            let entry = cache.get(&key).await;
+            let res = if entry.is_first() {
+                // equivalent documents that only differ by their ignored tokens share a plan, the
+                // document is only normalized when it is not in the cache as it was sent
+                let normalized_entry = match crate::signature::normalize(&request.query) {
+                    Ok(query) if query != request.query => {
+                        Some(cache.get(&(query, key.1.clone())).await)
                     }
-                    Err(error) => {
-                        match error.downcast_ref::<QueryPlannerError>() {
-                            Some(QueryPlannerError::PlanningErrors(pe)) => {
-                                if let Err(inner_e) =
-                                    context.insert(USAGE_REPORTING, pe.usage_reporting.clone())
-                                {
-                                    tracing::error!(
-                                        "usage reporting was not serializable to context, {}",
-                                        inner_e
-                                    );
-                                }
-                            }
-                            Some(QueryPlannerError::SpecError(e)) => {
-                                let error_key = match e {
-                                    SpecError::ParsingError(_) => "## GraphQLParseFailure\n",
-                                    _ => "## GraphQLValidationFailure\n",
-                                };
-                                if let Err(inner_e) = context.insert(
-                                    USAGE_REPORTING,
-                                    UsageReporting {
-                                        stats_report_key: error_key.to_string(),
-                                        referenced_fields_by_type: HashMap::new(),
-                                    },
-                                ) {
-                                    tracing::error!(
-                                        "usage reporting was not serializable to context, {}",
-                                        inner_e
-                                    );
-                                }
-                            }
-                            _ => {}
+                    _ => None,
+                };
+                match normalized_entry {
+                    Some(normalized_entry) if !normalized_entry.is_first() => {
+                        let res = normalized_entry
+                            .get()
+                            .await
+                            .map_err(|_| QueryPlannerError::UnhandledPlannerResult)?;
+                        entry.insert(res.clone()).await;
+                        res
+                    }
+                    normalized_entry => {
+                        let res = qp
+                            .delegate
+                            .ready()
+                            .await?
+                            .call(request)
+                            .await
+                            .map(|response| response.content)
+                            .map_err(Arc::new);
+                        if let Some(normalized_entry) = normalized_entry {
+                            normalized_entry.insert(res.clone()).await;
                         }
-
-                        let e = Arc::new(error);
-                        entry.insert(Err(e.clone())).await;
-                        Err(CacheResolverError::RetrievalError(e).into())
+                        entry.insert(res.clone()).await;
+                        res
                     }
                 }
             } else {
-                let res = entry
+                entry
                     .get()
                     .await
-                    .map_err(|_| QueryPlannerError::UnhandledPlannerResult)?;
-
-                match res {
-                    Ok(content) => {
-                        if let QueryPlannerContent::Plan { plan, .. } = &content {
-                            match (&plan.usage_reporting).serialize(Serializer) {
-                                Ok(v) => {
-                                    context.insert_json_value(USAGE_REPORTING, v);
-                                }
-                                Err(e) => {
-                                    tracing::error!(
-                                        "usage reporting was not serializable to context, {}",
-                                        e
-                                    );
-                                }
+                    .map_err(|_| QueryPlannerError::UnhandledPlannerResult)?
+            };
+
+            match res {
+                Ok(content) => {
+                    if let QueryPlannerContent::Plan { plan, .. } = &content {
+                        match (&plan.usage_reporting).serialize(Serializer) {
+                            Ok(v) => {
+                                context.insert_json_value(USAGE_REPORTING, v);
+                            }
+                            Err(e) => {
+                                tracing::error!(
+                                    "usage reporting was not serializable to context, {}",
+                                    e
+                                );
                             }
                         }
-
-                        Ok(QueryPlannerResponse { content, context })
                     }
-                    Err(error) => {
-                        if let Some(error) = error.downcast_ref::<QueryPlannerError>() {
-                            if let QueryPlannerError::PlanningErrors(pe) = &error {
-                                if let Err(inner_e) = request
-                                    .context
-                                    .insert(USAGE_REPORTING, pe.usage_reporting.clone())
-                                {
-                                    tracing::error!(
-                                        "usage reporting was not serializable to context, {}",
-                                        inner_e
-                                    );
-                                }
-                            } else if let QueryPlannerError::SpecError(e) = &error {
-                                let error_key = match e {
-                                    SpecError::ParsingError(_) => "## GraphQLParseFailure\n",
-                                    _ => "## GraphQLValidationFailure\n",
-                                };
-                                if let Err(inner_e) = request.context.insert(
-                                    USAGE_REPORTING,
-                                    UsageReporting {
-                                        stats_report_key: error_key.to_string(),
-                                        referenced_fields_by_type: HashMap::new(),
-                                    },
-                                ) {
-                                    tracing::error!(
-                                        "usage reporting was not serializable to context, {}",
-                                        inner_e
-                                    );
-                                }
+
+                    Ok(QueryPlannerResponse { content, context })
+                }
+                Err(error) => {
+                    if let Some(error) = error.downcast_ref::<QueryPlannerError>() {
+                        if let QueryPlannerError::PlanningErrors(pe, _) = &error {
+                            if let Err(inner_e) =
+                                context.insert(USAGE_REPORTING, pe.usage_reporting.clone())
+                            {
+                                tracing::error!(
+                                    "usage reporting was not serializable to context, {}",
+                                    inner_e
+                                );
+                            }
+                        } else if let QueryPlannerError::SpecError(e) = &error {
+                            let error_key = match e {
+                                SpecError::ParsingError(_) => "## GraphQLParseFailure\n",
+                                _ => "## GraphQLValidationFailure\n",
+                            };
+                            if let Err(inner_e) = context.insert(
+                                USAGE_REPORTING,
+                                UsageReporting {
+                                    stats_report_key: error_key.to_string(),
+                                    referenced_fields_by_type: HashMap::new(),
+                                },
+                            ) {
+                                tracing::error!(
+                                    "usage reporting was not serializable to context, {}",
+                                    inner_e
+                                );
                             }
                         }
-
-                        Err(CacheResolverError::RetrievalError(error).into())
                     }
+
+                    Err(CacheResolverError::RetrievalError(error).into())
                 }
             }
         })
@@ -181,6 +175,9 @@ where
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
     use mockall::mock;
     use mockall::predicate::*;
     use query_planner::QueryPlan;
@@ -232,13 +229,16 @@ mod tests {
         delegate.expect_clone().returning(|| {
             let mut planner = MockMyQueryPlanner::new();
             planner.expect_sync_call().times(0..2).returning(|_| {
-                Err(QueryPlannerError::from(PlanErrors {
-                    errors: Default::default(),
-                    usage_reporting: UsageReporting {
-                        stats_report_key: "this is a test key".to_string(),
-                        referenced_fields_by_type: Default::default(),
+                Err(QueryPlannerError::PlanningErrors(
+                    PlanErrors {
+                        errors: Default::default(),
+                        usage_reporting: UsageReporting {
+                            stats_report_key: "this is a test key".to_string(),
+                            referenced_fields_by_type: Default::default(),
+                        },
                     },
-                })
+                    Vec::new(),
+                )
                 .into())
             });
             planner
@@ -266,6 +266,47 @@ mod tests {
             .is_err());
     }
 
+    #[test(tokio::test)]
+    async fn equivalent_documents_share_a_plan() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let mut delegate = MockMyQueryPlanner::new();
+        delegate.expect_clone().returning(move || {
+            let counter = counter.clone();
+            let mut planner = MockMyQueryPlanner::new();
+            planner.expect_sync_call().returning(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Err(QueryPlannerError::PlanningErrors(
+                    PlanErrors {
+                        errors: Default::default(),
+                        usage_reporting: UsageReporting {
+                            stats_report_key: "this is a test key".to_string(),
+                            referenced_fields_by_type: Default::default(),
+                        },
+                    },
+                    Vec::new(),
+                )
+                .into())
+            });
+            planner
+        });
+
+        let mut planner = CachingQueryPlanner::new(delegate, 10).await;
+
+        for query in [
+            "{ me { name } }",
+            "{ me{name} }",
+            "# comment\n{ me { name } }",
+            "{ me { name } }",
+        ] {
+            assert!(planner
+                .call(QueryPlannerRequest::new(query.into(), None, Context::new()))
+                .await
+                .is_err());
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
     macro_rules! test_query_plan {
         () => {
             include_str!("testdata/query_plan.json")
@@ -2,8 +2,10 @@
 
 #![allow(missing_docs)] // FIXME
 
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::fmt::Write;
+use std::num::NonZeroUsize;
 use std::sync::Arc;
 
 pub(crate) use bridge_query_planner::*;
@@ -20,11 +22,14 @@ use tracing::Instrument;
 
 pub(crate) use self::fetch::OperationKind;
 use crate::error::Error;
+use crate::error::FetchError;
 use crate::graphql::Request;
 use crate::graphql::Response;
 use crate::json_ext::Path;
 use crate::json_ext::Value;
 use crate::json_ext::ValueExt;
+use crate::plugins::traffic_shaping::MissingEntities;
+use crate::services::layers::partial_results::FAIL_FAST_CONTEXT_KEY;
 use crate::services::subgraph_service::SubgraphServiceFactory;
 use crate::*;
 
@@ -32,11 +37,22 @@ mod bridge_query_planner;
 mod caching_query_planner;
 mod selection;
 
+/// Context key of the request deadline, in milliseconds since the UNIX epoch.
+pub(crate) const DEADLINE_CONTEXT_KEY: &str = "apollo_router::deadline";
+/// Context key set to `true` when subgraph fetches were cancelled by the request deadline.
+pub(crate) const DEADLINE_EXCEEDED_CONTEXT_KEY: &str = "apollo_router::deadline_exceeded";
+/// Context key of the subgraphs whose fetches are skipped, their circuit breaker being open.
+pub(crate) const DEGRADED_SUBGRAPHS_CONTEXT_KEY: &str = "apollo_router::degraded_subgraphs";
+
 /// Query planning options.
 #[derive(Clone, Eq, Hash, PartialEq, Debug, Default)]
 pub(crate) struct QueryPlanOptions {
     /// Enable the variable deduplication optimization on the QueryPlan
     pub(crate) enable_deduplicate_variables: bool,
+    /// Maximum number of entity representations sent in a single request, by subgraph
+    pub(crate) entity_batch_sizes: BTreeMap<String, NonZeroUsize>,
+    /// Handling of the entities missing from the responses, by subgraph
+    pub(crate) missing_entities: BTreeMap<String, MissingEntities>,
 }
 /// A planner key.
 ///
@@ -269,7 +285,7 @@ impl QueryPlan {
         service_factory: &'a Arc<SF>,
         originating_request: &'a Arc<http::Request<Request>>,
         schema: &'a Schema,
-        sender: futures::channel::mpsc::Sender<Response>,
+        sender: tokio::sync::mpsc::Sender<Response>,
     ) -> Response
     where
         SF: SubgraphServiceFactory,
@@ -288,6 +304,12 @@ impl QueryPlan {
                     originating_request,
                     deferred_fetches: &deferred_fetches,
                     options: &self.options,
+                    deadline: deadline(context),
+                    fail_fast: context
+                        .get::<_, bool>(FAIL_FAST_CONTEXT_KEY)
+                        .ok()
+                        .flatten()
+                        .unwrap_or(false),
                 },
                 &root,
                 &Value::default(),
@@ -305,6 +327,17 @@ impl QueryPlan {
     pub fn contains_mutations(&self) -> bool {
         self.root.contains_mutations()
     }
+
+    /// The names of the subgraphs fetched by the plan, sorted and without duplicates.
+    ///
+    /// This includes the subgraphs of the deferred parts of the query, and of both branches of
+    /// the conditions.
+    pub fn subgraphs(&self) -> Vec<&str> {
+        let mut subgraphs: Vec<&str> = self.root.service_usage().collect();
+        subgraphs.sort_unstable();
+        subgraphs.dedup();
+        subgraphs
+    }
 }
 
 // holds the query plan executon arguments that do not change between calls
@@ -315,6 +348,24 @@ pub(crate) struct ExecutionParameters<'a, SF> {
     originating_request: &'a Arc<http::Request<Request>>,
     deferred_fetches: &'a HashMap<String, Sender<(Value, Vec<Error>)>>,
     options: &'a QueryPlanOptions,
+    /// Subgraph fetches still running at the deadline are cancelled.
+    deadline: Option<tokio::time::Instant>,
+    /// With the `fail_fast` partial results policy, the fetches are cancelled after the first
+    /// error.
+    fail_fast: bool,
+}
+
+/// Read the request deadline set in the context by the traffic shaping plugin.
+fn deadline(context: &Context) -> Option<tokio::time::Instant> {
+    let deadline = context.get::<_, u64>(DEADLINE_CONTEXT_KEY).ok().flatten()?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()?
+        .as_millis() as u64;
+    Some(
+        tokio::time::Instant::now()
+            + std::time::Duration::from_millis(deadline.saturating_sub(now)),
+    )
 }
 
 impl PlanNode {
@@ -323,7 +374,7 @@ impl PlanNode {
         parameters: &'a ExecutionParameters<'a, SF>,
         current_dir: &'a Path,
         parent_value: &'a Value,
-        sender: futures::channel::mpsc::Sender<Response>,
+        sender: tokio::sync::mpsc::Sender<Response>,
     ) -> future::BoxFuture<(Value, Option<String>, Vec<Error>)>
     where
         SF: SubgraphServiceFactory,
@@ -348,6 +399,9 @@ impl PlanNode {
                         value.deep_merge(v);
                         errors.extend(err.into_iter());
                         subselection = subselect;
+                        if parameters.fail_fast && !errors.is_empty() {
+                            break;
+                        }
                     }
                 }
                 PlanNode::Parallel { nodes } => {
@@ -376,6 +430,10 @@ impl PlanNode {
                     {
                         value.deep_merge(v);
                         errors.extend(err.into_iter());
+                        // dropping the stream cancels the other fetches
+                        if parameters.fail_fast && !errors.is_empty() {
+                            break;
+                        }
                     }
                 }
                 PlanNode::Flatten(FlattenNode { path, node }) => {
@@ -395,14 +453,27 @@ impl PlanNode {
                     subselection = subselect;
                 }
                 PlanNode::Fetch(fetch_node) => {
-                    match fetch_node
+                    let fetch = fetch_node
                         .fetch_node(parameters, parent_value, current_dir)
                         .instrument(tracing::info_span!(
                             "fetch",
                             "otel.kind" = %SpanKind::Internal,
-                        ))
-                        .await
-                    {
+                        ));
+                    // dropping the fetch cancels the subgraph request
+                    let result = match parameters.deadline {
+                        Some(deadline) => tokio::time::timeout_at(deadline, fetch)
+                            .await
+                            .unwrap_or_else(|_| {
+                                let _ = parameters
+                                    .context
+                                    .insert(DEADLINE_EXCEEDED_CONTEXT_KEY, true);
+                                Err(FetchError::SubrequestDeadlineExceeded {
+                                    service: fetch_node.service_name.clone(),
+                                })
+                            }),
+                        None => fetch.await,
+                    };
+                    match result {
                         Ok((v, e)) => {
                             value = v;
                             errors = e;
@@ -462,12 +533,14 @@ impl PlanNode {
                         let deferred_path = deferred_node.path.clone();
                         let subselection = deferred_node.subselection();
                         let label = deferred_node.label.clone();
-                        let mut tx = sender.clone();
+                        let tx = sender.clone();
                         let sc = parameters.schema.clone();
                         let orig = parameters.originating_request.clone();
                         let sf = parameters.service_factory.clone();
                         let ctx = parameters.context.clone();
                         let opt = parameters.options.clone();
+                        let deadline = parameters.deadline;
+                        let fail_fast = parameters.fail_fast;
                         let mut primary_receiver = primary_sender.subscribe();
                         let mut value = parent_value.clone();
                         let fut = async move {
@@ -503,6 +576,8 @@ impl PlanNode {
                                             originating_request: &orig,
                                             deferred_fetches: &deferred_fetches,
                                             options: &opt,
+                                            deadline,
+                                            fail_fast,
                                         },
                                         &Path::default(),
                                         &value,
@@ -565,9 +640,17 @@ impl PlanNode {
                         futures.push(fut);
                     }
 
+                    let tx = sender.clone();
                     tokio::task::spawn(
                         async move {
-                            join_all(futures).await;
+                            tokio::select! {
+                                _ = join_all(futures) => {}
+                                // the response stream was dropped, usually because the client
+                                // disconnected: cancel the deferred fetches
+                                _ = tx.closed() => {
+                                    tracing::debug!("response stream closed, cancelling deferred execution");
+                                }
+                            }
                         }
                         .in_current_span(),
                     );
@@ -585,6 +668,8 @@ impl PlanNode {
                                     originating_request: parameters.originating_request,
                                     deferred_fetches: &deferred_fetches,
                                     options: parameters.options,
+                                    deadline: parameters.deadline,
+                                    fail_fast: parameters.fail_fast,
                                 },
                                 current_dir,
                                 &value,
@@ -661,7 +746,6 @@ impl PlanNode {
         })
     }
 
-    #[cfg(test)]
     /// Retrieves all the services used across all plan nodes.
     ///
     /// Note that duplicates are not filtered.
@@ -729,7 +813,10 @@ pub(crate) mod fetch {
     use std::fmt::Display;
     use std::sync::Arc;
 
+    use apollo_parser::ast;
+    use futures::future::join_all;
     use indexmap::IndexSet;
+    use once_cell::sync::OnceCell;
     use serde::Deserialize;
     use serde::Serialize;
     use tower::ServiceExt;
@@ -739,13 +826,17 @@ pub(crate) mod fetch {
     use super::selection::select_object;
     use super::selection::Selection;
     use super::ExecutionParameters;
+    use super::DEGRADED_SUBGRAPHS_CONTEXT_KEY;
     use crate::error::Error;
     use crate::error::FetchError;
     use crate::graphql::Request;
     use crate::json_ext::Object;
     use crate::json_ext::Path;
+    use crate::json_ext::PathElement;
     use crate::json_ext::Value;
     use crate::json_ext::ValueExt;
+    use crate::plugins::traffic_shaping::MissingEntities;
+    use crate::services::request_stages;
     use crate::services::subgraph_service::SubgraphServiceFactory;
     use crate::*;
 
@@ -806,6 +897,8 @@ pub(crate) mod fetch {
     struct Variables {
         variables: Object,
         paths: HashMap<Path, usize>,
+        /// Errors of the entities that are missing required fields, and are not fetched.
+        errors: Vec<Error>,
     }
 
     impl Variables {
@@ -830,12 +923,13 @@ pub(crate) mod fetch {
                 }));
 
                 let mut paths: HashMap<Path, usize> = HashMap::new();
+                let mut errors = Vec::new();
                 let (paths, representations) = if enable_deduplicate_variables {
                     let mut values: IndexSet<Value> = IndexSet::new();
                     data.select_values_and_paths(current_dir, |path, value| {
                         if let Value::Object(content) = value {
-                            if let Ok(Some(value)) = select_object(content, requires, schema) {
-                                match values.get_index_of(&value) {
+                            match select_object(content, requires, schema) {
+                                Ok(Some(value)) => match values.get_index_of(&value) {
                                     Some(index) => {
                                         paths.insert(path.clone(), index);
                                     }
@@ -843,12 +937,14 @@ pub(crate) mod fetch {
                                         paths.insert(path.clone(), values.len());
                                         values.insert(value);
                                     }
-                                }
+                                },
+                                Ok(None) => {}
+                                Err(e) => errors.push(e.to_graphql_error(Some(path.clone()))),
                             }
                         }
                     });
 
-                    if values.is_empty() {
+                    if values.is_empty() && errors.is_empty() {
                         return None;
                     }
 
@@ -857,14 +953,18 @@ pub(crate) mod fetch {
                     let mut values: Vec<Value> = Vec::new();
                     data.select_values_and_paths(current_dir, |path, value| {
                         if let Value::Object(content) = value {
-                            if let Ok(Some(value)) = select_object(content, requires, schema) {
-                                paths.insert(path.clone(), values.len());
-                                values.push(value);
+                            match select_object(content, requires, schema) {
+                                Ok(Some(value)) => {
+                                    paths.insert(path.clone(), values.len());
+                                    values.push(value);
+                                }
+                                Ok(None) => {}
+                                Err(e) => errors.push(e.to_graphql_error(Some(path.clone()))),
                             }
                         }
                     });
 
-                    if values.is_empty() {
+                    if values.is_empty() && errors.is_empty() {
                         return None;
                     }
 
@@ -872,7 +972,11 @@ pub(crate) mod fetch {
                 };
                 variables.insert("representations", representations);
 
-                Some(Variables { variables, paths })
+                Some(Variables {
+                    variables,
+                    paths,
+                    errors,
+                })
             } else {
                 // with nested operations (Query or Mutation has an operation returning a Query or Mutation),
                 // when the first fetch fails, the query plan will still execute up until the second fetch,
@@ -898,11 +1002,139 @@ pub(crate) mod fetch {
                         })
                         .collect::<Object>(),
                     paths: HashMap::new(),
+                    errors: Vec::new(),
                 })
             }
         }
     }
 
+    /// Whether the fetches to the subgraph are skipped, as set in the context by the traffic shaping
+    /// plugin.
+    fn is_degraded(context: &Context, service_name: &str) -> bool {
+        context
+            .get::<_, Vec<String>>(DEGRADED_SUBGRAPHS_CONTEXT_KEY)
+            .ok()
+            .flatten()
+            .map(|subgraphs| subgraphs.iter().any(|subgraph| subgraph == service_name))
+            .unwrap_or_default()
+    }
+
+    fn collect_response_names(
+        selection_set: Option<ast::SelectionSet>,
+        entities: bool,
+        names: &mut Vec<String>,
+    ) {
+        for selection in selection_set
+            .iter()
+            .flat_map(|selection_set| selection_set.selections())
+        {
+            match selection {
+                ast::Selection::Field(field) => {
+                    let name = match field.name() {
+                        Some(name) => name.text().to_string(),
+                        None => continue,
+                    };
+                    if entities {
+                        // the entity fields are selected in the `_entities` field
+                        if name == "_entities" {
+                            collect_response_names(field.selection_set(), false, names);
+                        }
+                        continue;
+                    }
+                    let name = field
+                        .alias()
+                        .and_then(|alias| alias.name())
+                        .map(|alias| alias.text().to_string())
+                        .unwrap_or(name);
+                    if name != "__typename" && !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+                ast::Selection::InlineFragment(inline_fragment) => {
+                    collect_response_names(inline_fragment.selection_set(), entities, names)
+                }
+                ast::Selection::FragmentSpread(_) => {}
+            }
+        }
+    }
+
+    /// Fix the path of subgraph errors, as seen by the client: the errors of an entity are set at
+    /// the paths of this entity in the response, and the other errors are rebased on the current
+    /// directory. The entity indexes are shifted by the offset of the batch of representations the
+    /// errors belong to.
+    fn rebase_errors(
+        errors: Vec<Error>,
+        current_dir: &Path,
+        offset: usize,
+        entity_paths: &EntityPaths<'_>,
+    ) -> Vec<Error> {
+        errors
+            .into_iter()
+            .flat_map(|error| {
+                let paths: Vec<Option<Path>> = match &error.path {
+                    None => vec![None],
+                    Some(path) => match path.0.as_slice() {
+                        [PathElement::Key(key), PathElement::Index(index), rest @ ..]
+                            if key == "_entities" =>
+                        {
+                            match entity_paths.get(&(index + offset)) {
+                                Some(entity_paths) => entity_paths
+                                    .iter()
+                                    .map(|entity_path| {
+                                        let mut path = entity_path.clone();
+                                        path.0.extend(rest.iter().cloned());
+                                        Some(path)
+                                    })
+                                    .collect(),
+                                None => {
+                                    let mut path = path.clone();
+                                    path.0[1] = PathElement::Index(index + offset);
+                                    vec![Some(current_dir.join(path))]
+                                }
+                            }
+                        }
+                        _ => vec![Some(current_dir.join(path))],
+                    },
+                };
+                paths.into_iter().map(move |path| Error {
+                    path,
+                    ..error.clone()
+                })
+            })
+            .collect()
+    }
+
+    /// Paths of the entities in the response, by index of their representation. They are only
+    /// needed for the errors of the entities, so they are indexed on first use.
+    struct EntityPaths<'a> {
+        paths: &'a HashMap<Path, usize>,
+        by_index: OnceCell<HashMap<usize, Vec<Path>>>,
+    }
+
+    impl<'a> EntityPaths<'a> {
+        fn new(paths: &'a HashMap<Path, usize>) -> Self {
+            Self {
+                paths,
+                by_index: OnceCell::new(),
+            }
+        }
+
+        fn get(&self, index: &usize) -> Option<&Vec<Path>> {
+            self.by_index
+                .get_or_init(|| {
+                    let mut entity_paths: HashMap<usize, Vec<Path>> = HashMap::new();
+                    for (path, index) in self.paths {
+                        entity_paths.entry(*index).or_default().push(path.clone());
+                    }
+                    for paths in entity_paths.values_mut() {
+                        paths.sort_by_key(|path| path.to_string());
+                    }
+                    entity_paths
+                })
+                .get(index)
+        }
+    }
+
     impl FetchNode {
         #[allow(clippy::too_many_arguments)]
         pub(crate) async fn fetch_node<'a, SF>(
@@ -914,15 +1146,11 @@ pub(crate) mod fetch {
         where
             SF: SubgraphServiceFactory,
         {
-            let FetchNode {
-                operation,
-                operation_kind,
-                operation_name,
-                service_name,
-                ..
-            } = self;
-
-            let Variables { variables, paths } = match Variables::new(
+            let Variables {
+                variables,
+                paths,
+                errors: requires_errors,
+            } = match Variables::new(
                 &self.requires,
                 self.variable_usages.as_ref(),
                 data,
@@ -940,6 +1168,170 @@ pub(crate) mod fetch {
                 }
             };
 
+            // none of the entities has the data required to fetch them
+            if !self.requires.is_empty() && paths.is_empty() {
+                return Ok((Value::from_path(current_dir, Value::Null), requires_errors));
+            }
+
+            if is_degraded(parameters.context, &self.service_name) {
+                let errors = requires_errors
+                    .into_iter()
+                    .chain(self.degraded_errors(current_dir, paths))
+                    .collect();
+                return Ok((Value::from_path(current_dir, Value::Null), errors));
+            }
+
+            let batch_size = parameters
+                .options
+                .entity_batch_sizes
+                .get(self.service_name.as_str())
+                .map(|size| size.get());
+            let representations = match variables.get("representations") {
+                Some(Value::Array(representations)) => representations.len(),
+                _ => 0,
+            };
+
+            let entity_paths = EntityPaths::new(&paths);
+            let missing_entities = parameters
+                .options
+                .missing_entities
+                .get(self.service_name.as_str())
+                .copied();
+            let (mut data, errors) = match batch_size {
+                Some(batch_size) if representations > batch_size => {
+                    self.fetch_entities_in_batches(
+                        parameters,
+                        variables,
+                        batch_size,
+                        current_dir,
+                        &entity_paths,
+                        missing_entities,
+                    )
+                    .await
+                }
+                _ => {
+                    let response = self.subgraph_fetch(parameters, variables).await?;
+                    (
+                        response.data.unwrap_or_default(),
+                        rebase_errors(response.errors, current_dir, 0, &entity_paths),
+                    )
+                }
+            };
+            let mut errors: Vec<Error> = requires_errors.into_iter().chain(errors).collect();
+
+            if let Some(missing_entities) = missing_entities {
+                if !self.requires.is_empty() {
+                    let missing_errors = self.check_entities(
+                        &mut data,
+                        representations,
+                        missing_entities,
+                        &entity_paths,
+                        &errors,
+                    )?;
+                    errors.extend(missing_errors);
+                }
+            }
+
+            match self.response_at_path(current_dir, paths, data) {
+                Ok(value) => {
+                    if let Some(id) = &self.id {
+                        if let Some(sender) = parameters.deferred_fetches.get(id.as_str()) {
+                            if let Err(e) = sender.clone().send((value.clone(), errors.clone())) {
+                                tracing::error!("error sending fetch result at path {} and id {:?} for deferred response building: {}", current_dir, self.id, e);
+                            }
+                        }
+                    }
+
+                    Ok((value, errors))
+                }
+                Err(e) => Err(e),
+            }
+        }
+
+        /// Apply the handling of the missing entities to the `_entities` of a response: fail if
+        /// some are missing or null, or set the missing ones to null, with an error at the paths
+        /// of the entities which do not have one already.
+        fn check_entities(
+            &self,
+            data: &mut Value,
+            representations: usize,
+            missing_entities: MissingEntities,
+            entity_paths: &EntityPaths<'_>,
+            errors: &[Error],
+        ) -> Result<Vec<Error>, FetchError> {
+            let mut entities = match data.as_object_mut().and_then(|map| map.remove("_entities")) {
+                Some(Value::Array(entities)) => entities,
+                None | Some(Value::Null) => Vec::new(),
+                // invalid content, reported when inserting the response
+                Some(entities) => {
+                    if let Some(map) = data.as_object_mut() {
+                        map.insert("_entities", entities);
+                    }
+                    return Ok(Vec::new());
+                }
+            };
+
+            let missing: Vec<usize> = (0..representations)
+                .filter(|index| matches!(entities.get(*index), None | Some(Value::Null)))
+                .collect();
+            if !missing.is_empty() && missing_entities == MissingEntities::Error {
+                return Err(FetchError::SubrequestMissingEntities {
+                    service: self.service_name.clone(),
+                    missing: missing.len(),
+                    representations,
+                });
+            }
+
+            if entities.len() < representations {
+                entities.resize(representations, Value::Null);
+            }
+            if !data.is_object() {
+                *data = Value::Object(Object::new());
+            }
+            if let Some(map) = data.as_object_mut() {
+                map.insert("_entities", Value::Array(entities));
+            }
+
+            if missing_entities != MissingEntities::NullWithError {
+                return Ok(Vec::new());
+            }
+            let error = FetchError::SubrequestMissingEntity {
+                service: self.service_name.clone(),
+            };
+            Ok(missing
+                .iter()
+                .filter_map(|index| entity_paths.get(index))
+                .flatten()
+                .filter(|path| {
+                    !errors.iter().any(|error| {
+                        error
+                            .path
+                            .as_ref()
+                            .map(|error_path| error_path.0.starts_with(&path.0))
+                            .unwrap_or(false)
+                    })
+                })
+                .map(|path| error.to_graphql_error(Some(path.clone())))
+                .collect())
+        }
+
+        /// Send the operation to the subgraph, with the given variables.
+        async fn subgraph_fetch<'a, SF>(
+            &'a self,
+            parameters: &'a ExecutionParameters<'a, SF>,
+            variables: Object,
+        ) -> Result<graphql::Response, FetchError>
+        where
+            SF: SubgraphServiceFactory,
+        {
+            let FetchNode {
+                operation,
+                operation_kind,
+                operation_name,
+                service_name,
+                ..
+            } = self;
+
             let subgraph_request = SubgraphRequest::builder()
                 .originating_request(parameters.originating_request.clone())
                 .subgraph_request(
@@ -970,6 +1362,7 @@ pub(crate) mod fetch {
                             "it won't fail because the url is correct and already checked; qed",
                         ),
                 )
+                .subgraph_name(service_name.clone())
                 .operation_kind(*operation_kind)
                 .context(parameters.context.clone())
                 .build();
@@ -980,20 +1373,21 @@ pub(crate) mod fetch {
                 .expect("we already checked that the service exists during planning; qed");
 
             // TODO not sure if we need a RouterReponse here as we don't do anything with it
-            let (_parts, response) = service
-                .oneshot(subgraph_request)
-                .instrument(tracing::trace_span!("subfetch_stream"))
-                .await
-                // TODO this is a problem since it restores details about failed service
-                // when errors have been redacted in the include_subgraph_errors module.
-                // Unfortunately, not easy to fix here, because at this point we don't
-                // know if we should be redacting errors for this subgraph...
-                .map_err(|e| FetchError::SubrequestHttpError {
-                    service: service_name.to_string(),
-                    reason: e.to_string(),
-                })?
-                .response
-                .into_parts();
+            let (_parts, response) = request_stages::record(
+                parameters.context,
+                request_stages::Stage::Fetch(service_name),
+                service
+                    .oneshot(subgraph_request)
+                    .instrument(tracing::trace_span!("subfetch_stream")),
+            )
+            .await
+            // TODO this is a problem since it restores details about failed service
+            // when errors have been redacted in the include_subgraph_errors module.
+            // Unfortunately, not easy to fix here, because at this point we don't
+            // know if we should be redacting errors for this subgraph...
+            .map_err(|e| FetchError::from_service_error(service_name, e))?
+            .response
+            .into_parts();
 
             super::log::trace_subfetch(service_name, operation, &variables, &response);
 
@@ -1003,33 +1397,133 @@ pub(crate) mod fetch {
                 });
             }
 
-            // fix error path and erase subgraph error messages (we cannot expose subgraph information
-            // to the client)
-            let errors: Vec<Error> = response
-                .errors
-                .into_iter()
-                .map(|error| Error {
-                    locations: error.locations,
-                    path: error.path.map(|path| current_dir.join(path)),
-                    message: error.message,
-                    extensions: error.extensions,
-                })
-                .collect();
+            Ok(response)
+        }
 
-            match self.response_at_path(current_dir, paths, response.data.unwrap_or_default()) {
-                Ok(value) => {
-                    if let Some(id) = &self.id {
-                        if let Some(sender) = parameters.deferred_fetches.get(id.as_str()) {
-                            if let Err(e) = sender.clone().send((value.clone(), errors.clone())) {
-                                tracing::error!("error sending fetch result at path {} and id {:?} for deferred response building: {}", current_dir, self.id, e);
+        /// Fetch the entities with several requests sent in parallel, each of them with at most
+        /// `batch_size` representations.
+        ///
+        /// The entities of a failed request are set to null, so that the entities of the other
+        /// requests are still returned. With a tolerant handling of the missing entities, a
+        /// request returning too few of them is not considered failed.
+        async fn fetch_entities_in_batches<'a, SF>(
+            &'a self,
+            parameters: &'a ExecutionParameters<'a, SF>,
+            mut variables: Object,
+            batch_size: usize,
+            current_dir: &'a Path,
+            entity_paths: &'a EntityPaths<'a>,
+            missing_entities: Option<MissingEntities>,
+        ) -> (Value, Vec<Error>)
+        where
+            SF: SubgraphServiceFactory,
+        {
+            let representations = match variables.remove("representations") {
+                Some(Value::Array(representations)) => representations,
+                _ => Vec::new(),
+            };
+
+            let responses = join_all(representations.chunks(batch_size).map(|batch| {
+                let mut variables = variables.clone();
+                variables.insert("representations", Value::Array(batch.to_vec()));
+                self.subgraph_fetch(parameters, variables)
+            }))
+            .await;
+
+            let tolerant = matches!(
+                missing_entities,
+                Some(MissingEntities::Null) | Some(MissingEntities::NullWithError)
+            );
+            let mut entities = Vec::with_capacity(representations.len());
+            let mut errors = Vec::new();
+            for (batch, response) in representations.chunks(batch_size).zip(responses) {
+                let batch_entities = match response {
+                    Ok(response) => {
+                        errors.extend(rebase_errors(
+                            response.errors,
+                            current_dir,
+                            entities.len(),
+                            entity_paths,
+                        ));
+                        match response
+                            .data
+                            .and_then(|mut data| data.as_object_mut()?.remove("_entities"))
+                        {
+                            Some(Value::Array(batch_entities))
+                                if batch_entities.len() == batch.len() =>
+                            {
+                                Ok(batch_entities)
+                            }
+                            Some(Value::Array(mut batch_entities)) if tolerant => {
+                                batch_entities.resize(batch.len(), Value::Null);
+                                Ok(batch_entities)
+                            }
+                            None | Some(Value::Null) if tolerant => {
+                                Ok(vec![Value::Null; batch.len()])
                             }
+                            _ => Err(FetchError::ExecutionInvalidContent {
+                                reason: "Received invalid content for key `_entities`!".to_string(),
+                            }),
                         }
                     }
+                    Err(e) => Err(e),
+                };
 
-                    Ok((value, errors))
+                match batch_entities {
+                    Ok(batch_entities) => entities.extend(batch_entities),
+                    Err(e) => {
+                        errors.push(e.to_graphql_error(Some(current_dir.clone())));
+                        entities.extend(std::iter::repeat(Value::Null).take(batch.len()));
+                    }
+                }
+            }
+
+            let mut data = Object::new();
+            data.insert("_entities", Value::Array(entities));
+            (Value::Object(data), errors)
+        }
+
+        /// Errors of the fields the fetch would resolve, for a subgraph that is skipped.
+        fn degraded_errors(&self, current_dir: &Path, paths: HashMap<Path, usize>) -> Vec<Error> {
+            let error = FetchError::SubrequestCircuitOpen {
+                service: self.service_name.clone(),
+            };
+            let fields = self.response_names();
+            // entities are reported at their own path, and root fields at the current directory
+            let mut paths: Vec<Path> = if self.requires.is_empty() {
+                vec![current_dir.clone()]
+            } else {
+                paths.into_keys().collect()
+            };
+            paths.sort_by_key(|path| path.to_string());
+
+            paths
+                .iter()
+                .flat_map(|path| {
+                    fields.iter().map(|field| {
+                        let mut path = path.clone();
+                        path.push(PathElement::Key(field.clone()));
+                        error.to_graphql_error(Some(path))
+                    })
+                })
+                .collect()
+        }
+
+        /// Response names of the fields selected by the operation, on the entities for an entity
+        /// fetch.
+        fn response_names(&self) -> Vec<String> {
+            let tree = apollo_parser::Parser::new(&self.operation).parse();
+            let mut names = Vec::new();
+            for definition in tree.document().definitions() {
+                if let ast::Definition::OperationDefinition(operation) = definition {
+                    collect_response_names(
+                        operation.selection_set(),
+                        !self.requires.is_empty(),
+                        &mut names,
+                    );
                 }
-                Err(e) => Err(e),
             }
+            names
         }
 
         #[instrument(skip_all, level = "debug", name = "response_insert")]
@@ -1078,7 +1572,6 @@ pub(crate) mod fetch {
             }
         }
 
-        #[cfg(test)]
         pub(crate) fn service_name(&self) -> &str {
             &self.service_name
         }
@@ -1220,6 +1713,24 @@ mod tests {
         insta::assert_debug_snapshot!(query_plan);
     }
 
+    #[test]
+    fn deadline_from_context() {
+        let context = Context::new();
+        assert!(deadline(&context).is_none());
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        context.insert(DEADLINE_CONTEXT_KEY, now + 60_000).unwrap();
+        let remaining = deadline(&context).unwrap() - tokio::time::Instant::now();
+        assert!(remaining > std::time::Duration::from_secs(50));
+        assert!(remaining <= std::time::Duration::from_secs(60));
+
+        context.insert(DEADLINE_CONTEXT_KEY, now - 1_000).unwrap();
+        assert!(deadline(&context).unwrap() <= tokio::time::Instant::now());
+    }
+
     #[test]
     fn service_usage() {
         assert_eq!(
@@ -1231,6 +1742,15 @@ mod tests {
         );
     }
 
+    #[test]
+    fn subgraphs() {
+        let query_plan = QueryPlan::fake_builder()
+            .root(serde_json::from_str::<PlanNode>(test_query_plan!()).unwrap())
+            .build();
+        assert_eq!(query_plan.subgraphs(), vec!["books", "product"]);
+        assert!(QueryPlan::fake_builder().build().subgraphs().is_empty());
+    }
+
     /// This test panics in the product subgraph. HOWEVER, this does not result in a panic in the
     /// test, since the buffer() functionality in the tower stack "loses" the panic and we end up
     /// with a closed service.
@@ -1264,7 +1784,7 @@ mod tests {
             mock_products_service
         });
 
-        let (sender, _) = futures::channel::mpsc::channel(10);
+        let (sender, _) = tokio::sync::mpsc::channel(10);
         let sf = Arc::new(MockSubgraphFactory {
             subgraphs: HashMap::from([(
                 "product".into(),
@@ -1321,7 +1841,7 @@ mod tests {
             mock_products_service
         });
 
-        let (sender, _) = futures::channel::mpsc::channel(10);
+        let (sender, _) = tokio::sync::mpsc::channel(10);
 
         let sf = Arc::new(MockSubgraphFactory {
             subgraphs: HashMap::from([(
@@ -1375,7 +1895,7 @@ mod tests {
             mock_products_service
         });
 
-        let (sender, _) = futures::channel::mpsc::channel(10);
+        let (sender, _) = tokio::sync::mpsc::channel(10);
 
         let sf = Arc::new(MockSubgraphFactory {
             subgraphs: HashMap::from([(
@@ -1401,10 +1921,9 @@ mod tests {
         );
     }
 
-    #[tokio::test]
-    async fn defer() {
-        // plan for { t { x ... @defer { y } }}
-        let query_plan: QueryPlan = QueryPlan {
+    /// Plan for `{ t { x ... @defer { y } }}`.
+    fn defer_plan() -> QueryPlan {
+        QueryPlan {
             formatted_query_plan: Default::default(),
             root: PlanNode::Defer {
                 primary: Primary {
@@ -1467,7 +1986,12 @@ mod tests {
                 referenced_fields_by_type: Default::default(),
             },
             options: QueryPlanOptions::default(),
-        };
+        }
+    }
+
+    #[tokio::test]
+    async fn defer() {
+        let query_plan = defer_plan();
 
         let mut mock_x_service = plugin::test::MockSubgraphService::new();
         mock_x_service.expect_clone().return_once(|| {
@@ -1506,7 +2030,7 @@ mod tests {
             mock_y_service
         });
 
-        let (sender, mut receiver) = futures::channel::mpsc::channel(10);
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(10);
 
         let schema = include_str!("testdata/defer_schema.graphql");
         let schema = Schema::parse(schema, &Default::default()).unwrap();
@@ -1534,7 +2058,7 @@ mod tests {
             serde_json::json! {{"data":{"t":{"id":1234,"__typename":"T","x":"X"}}}}
         );
 
-        let response = receiver.next().await.unwrap();
+        let response = receiver.recv().await.unwrap();
 
         // deferred response
         assert_eq!(
@@ -1545,6 +2069,341 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn deferred_fetches_are_cancelled_when_the_response_stream_is_dropped() {
+        let query_plan = defer_plan();
+
+        let mut mock_x_service = plugin::test::MockSubgraphService::new();
+        mock_x_service.expect_clone().return_once(|| {
+            let mut mock_x_service = plugin::test::MockSubgraphService::new();
+            mock_x_service.expect_call().times(1).returning(|_| {
+                Ok(SubgraphResponse::fake_builder()
+                    .data(serde_json::json! {{
+                        "t": {"id": 1234, "__typename": "T", "x": "X"}
+                    }})
+                    .build())
+            });
+            mock_x_service
+        });
+
+        // the Y subgraph answers once it is released, and records the calls it completed
+        let started = Arc::new(tokio::sync::Notify::new());
+        let release = Arc::new(tokio::sync::Notify::new());
+        let completed = Arc::new(AtomicBool::new(false));
+        let y_service = {
+            let started = started.clone();
+            let release = release.clone();
+            let completed = completed.clone();
+            tower::service_fn(move |_request: SubgraphRequest| {
+                let started = started.clone();
+                let release = release.clone();
+                let completed = completed.clone();
+                async move {
+                    started.notify_one();
+                    release.notified().await;
+                    completed.store(true, Ordering::SeqCst);
+                    Ok::<_, tower::BoxError>(
+                        SubgraphResponse::fake_builder()
+                            .data(serde_json::json! {{
+                                "_entities": [{"y": "Y", "__typename": "T"}]
+                            }})
+                            .build(),
+                    )
+                }
+            })
+        };
+
+        let schema = include_str!("testdata/defer_schema.graphql");
+        let schema = Schema::parse(schema, &Default::default()).unwrap();
+        let sf = Arc::new(MockSubgraphFactory {
+            subgraphs: HashMap::from([
+                (
+                    "X".into(),
+                    Arc::new(mock_x_service) as Arc<dyn MakeSubgraphService>,
+                ),
+                (
+                    "Y".into(),
+                    Arc::new(y_service) as Arc<dyn MakeSubgraphService>,
+                ),
+            ]),
+            plugins: Default::default(),
+        });
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(10);
+        query_plan
+            .execute(&Context::new(), &sf, &Default::default(), &schema, sender)
+            .await;
+        tokio::time::timeout(std::time::Duration::from_secs(5), started.notified())
+            .await
+            .expect("the deferred fetch should have started");
+
+        // the client disconnects while the deferred fetch is in flight
+        drop(receiver);
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        release.notify_one();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!completed.load(Ordering::SeqCst));
+    }
+
+    /// Plan for `{ t { x y } }`, `y` being resolved by the `Y` subgraph.
+    fn entity_plan() -> QueryPlan {
+        QueryPlan {
+        formatted_query_plan: Default::default(),
+        root: PlanNode::Sequence {
+            nodes: vec![
+                PlanNode::Fetch(FetchNode {
+                    service_name: "X".to_string(),
+                    requires: vec![],
+                    variable_usages: vec![],
+                    operation: "{ t { id __typename x } }".to_string(),
+                    operation_name: None,
+                    operation_kind: OperationKind::Query,
+                    id: None,
+                }),
+                PlanNode::Flatten(FlattenNode {
+                    path: Path(vec![PathElement::Key("t".to_string())]),
+                    node: Box::new(PlanNode::Fetch(FetchNode {
+                        service_name: "Y".to_string(),
+                        requires: vec![query_planner::selection::Selection::InlineFragment(
+                            query_planner::selection::InlineFragment {
+                                type_condition: Some("T".into()),
+                                selections: vec![
+                                    query_planner::selection::Selection::Field(
+                                        query_planner::selection::Field {
+                                            alias: None,
+                                            name: "id".into(),
+                                            selections: None,
+                                        },
+                                    ),
+                                    query_planner::selection::Selection::Field(
+                                        query_planner::selection::Field {
+                                            alias: None,
+                                            name: "__typename".into(),
+                                            selections: None,
+                                        },
+                                    ),
+                                ],
+                            },
+                        )],
+                        variable_usages: vec![],
+                        operation: "query($representations:[_Any!]!){_entities(representations:$representations){...on T{__typename y}}}".to_string(),
+                        operation_name: None,
+                        operation_kind: OperationKind::Query,
+                        id: None,
+                    })),
+                }),
+            ],
+        },
+        usage_reporting: UsageReporting {
+            stats_report_key: "this is a test report key".to_string(),
+            referenced_fields_by_type: Default::default(),
+        },
+        options: QueryPlanOptions::default(),
+    }
+    }
+
+    #[tokio::test]
+    async fn degraded_subgraphs_are_skipped() {
+        let query_plan = entity_plan();
+
+        let mut mock_x_service = plugin::test::MockSubgraphService::new();
+        mock_x_service.expect_clone().return_once(|| {
+            let mut mock_x_service = plugin::test::MockSubgraphService::new();
+            mock_x_service.expect_call().times(1).returning(|_| {
+                Ok(SubgraphResponse::fake_builder()
+                    .data(serde_json::json! {{
+                        "t": {"id": 1234, "__typename": "T", "x": "X"}
+                    }})
+                    .build())
+            });
+            mock_x_service
+        });
+
+        // the circuit of Y is open, it is never called
+        let mut mock_y_service = plugin::test::MockSubgraphService::new();
+        mock_y_service.expect_clone().never();
+
+        let schema = include_str!("testdata/defer_schema.graphql");
+        let schema = Schema::parse(schema, &Default::default()).unwrap();
+        let sf = Arc::new(MockSubgraphFactory {
+            subgraphs: HashMap::from([
+                (
+                    "X".into(),
+                    Arc::new(mock_x_service) as Arc<dyn MakeSubgraphService>,
+                ),
+                (
+                    "Y".into(),
+                    Arc::new(mock_y_service) as Arc<dyn MakeSubgraphService>,
+                ),
+            ]),
+            plugins: Default::default(),
+        });
+
+        let context = Context::new();
+        context
+            .insert(DEGRADED_SUBGRAPHS_CONTEXT_KEY, vec!["Y".to_string()])
+            .unwrap();
+        let (sender, _) = tokio::sync::mpsc::channel(10);
+        let response = query_plan
+            .execute(&context, &sf, &Default::default(), &schema, sender)
+            .await;
+
+        // the rest of the query is served, with an error for the field of the skipped subgraph
+        assert_eq!(
+            response.data,
+            Some(serde_json::json! {{"t": {"id": 1234, "__typename": "T", "x": "X"}}}.into())
+        );
+        assert_eq!(response.errors.len(), 1);
+        assert_eq!(
+            response.errors[0].message,
+            "service 'Y' is unavailable: its circuit breaker is open"
+        );
+        assert_eq!(response.errors[0].path, Some(Path::from_slice(&["t", "y"])));
+    }
+
+    #[tokio::test]
+    async fn entity_errors_are_set_at_the_paths_of_the_entities() {
+        let query_plan = entity_plan();
+
+        let mut mock_x_service = plugin::test::MockSubgraphService::new();
+        mock_x_service.expect_clone().return_once(|| {
+            let mut mock_x_service = plugin::test::MockSubgraphService::new();
+            mock_x_service.expect_call().times(1).returning(|_| {
+                Ok(SubgraphResponse::fake_builder()
+                    .data(serde_json::json! {{
+                        "t": {"id": 1234, "__typename": "T", "x": "X"}
+                    }})
+                    .build())
+            });
+            mock_x_service
+        });
+
+        let mut mock_y_service = plugin::test::MockSubgraphService::new();
+        mock_y_service.expect_clone().return_once(|| {
+            let mut mock_y_service = plugin::test::MockSubgraphService::new();
+            mock_y_service.expect_call().times(1).returning(|_| {
+                Ok(SubgraphResponse::fake_builder()
+                    .data(serde_json::json! {{
+                        "_entities": [{"__typename": "T", "y": null}]
+                    }})
+                    .error(
+                        Error::builder()
+                            .message("y is not available")
+                            .path(Path::from("_entities/0/y"))
+                            .build(),
+                    )
+                    .build())
+            });
+            mock_y_service
+        });
+
+        let schema = include_str!("testdata/defer_schema.graphql");
+        let schema = Schema::parse(schema, &Default::default()).unwrap();
+        let sf = Arc::new(MockSubgraphFactory {
+            subgraphs: HashMap::from([
+                (
+                    "X".into(),
+                    Arc::new(mock_x_service) as Arc<dyn MakeSubgraphService>,
+                ),
+                (
+                    "Y".into(),
+                    Arc::new(mock_y_service) as Arc<dyn MakeSubgraphService>,
+                ),
+            ]),
+            plugins: Default::default(),
+        });
+
+        let (sender, _) = tokio::sync::mpsc::channel(10);
+        let response = query_plan
+            .execute(&Context::new(), &sf, &Default::default(), &schema, sender)
+            .await;
+
+        // the error is at the path of the entity in the response, not in the subgraph response
+        assert_eq!(response.errors.len(), 1);
+        assert_eq!(response.errors[0].message, "y is not available");
+        assert_eq!(response.errors[0].path, Some(Path::from_slice(&["t", "y"])));
+    }
+
+    /// Execute the entity plan, `Y` returning no entity.
+    async fn execute_with_missing_entities(missing_entities: MissingEntities) -> Response {
+        let mut query_plan = entity_plan();
+        query_plan.options.missing_entities = BTreeMap::from([("Y".to_string(), missing_entities)]);
+
+        let mut mock_x_service = plugin::test::MockSubgraphService::new();
+        mock_x_service.expect_clone().return_once(|| {
+            let mut mock_x_service = plugin::test::MockSubgraphService::new();
+            mock_x_service.expect_call().times(1).returning(|_| {
+                Ok(SubgraphResponse::fake_builder()
+                    .data(serde_json::json! {{
+                        "t": {"id": 1234, "__typename": "T", "x": "X"}
+                    }})
+                    .build())
+            });
+            mock_x_service
+        });
+
+        let mut mock_y_service = plugin::test::MockSubgraphService::new();
+        mock_y_service.expect_clone().return_once(|| {
+            let mut mock_y_service = plugin::test::MockSubgraphService::new();
+            mock_y_service.expect_call().times(1).returning(|_| {
+                Ok(SubgraphResponse::fake_builder()
+                    .data(serde_json::json! {{ "_entities": [] }})
+                    .build())
+            });
+            mock_y_service
+        });
+
+        let schema = include_str!("testdata/defer_schema.graphql");
+        let schema = Schema::parse(schema, &Default::default()).unwrap();
+        let sf = Arc::new(MockSubgraphFactory {
+            subgraphs: HashMap::from([
+                (
+                    "X".into(),
+                    Arc::new(mock_x_service) as Arc<dyn MakeSubgraphService>,
+                ),
+                (
+                    "Y".into(),
+                    Arc::new(mock_y_service) as Arc<dyn MakeSubgraphService>,
+                ),
+            ]),
+            plugins: Default::default(),
+        });
+
+        let (sender, _) = tokio::sync::mpsc::channel(10);
+        query_plan
+            .execute(&Context::new(), &sf, &Default::default(), &schema, sender)
+            .await
+    }
+
+    #[tokio::test]
+    async fn missing_entities_are_handled_as_configured() {
+        let response = execute_with_missing_entities(MissingEntities::Error).await;
+        assert_eq!(response.errors.len(), 1);
+        assert_eq!(
+            response.errors[0].message,
+            "service 'Y' did not return 1 of the 1 entities requested"
+        );
+
+        let response = execute_with_missing_entities(MissingEntities::NullWithError).await;
+        assert_eq!(
+            response.data,
+            Some(serde_json::json! {{"t": {"id": 1234, "__typename": "T", "x": "X"}}}.into())
+        );
+        assert_eq!(response.errors.len(), 1);
+        assert_eq!(
+            response.errors[0].message,
+            "service 'Y' did not return the entity"
+        );
+        assert_eq!(response.errors[0].path, Some(Path::from_slice(&["t"])));
+
+        let response = execute_with_missing_entities(MissingEntities::Null).await;
+        assert_eq!(
+            response.data,
+            Some(serde_json::json! {{"t": {"id": 1234, "__typename": "T", "x": "X"}}}.into())
+        );
+        assert!(response.errors.is_empty());
+    }
+
     #[tokio::test]
     async fn dependent_mutations() {
         let schema = r#"schema
@@ -1648,7 +2507,7 @@ mod tests {
             plugins: Default::default(),
         });
 
-        let (sender, _) = futures::channel::mpsc::channel(10);
+        let (sender, _) = tokio::sync::mpsc::channel(10);
         let _response = query_plan
             .execute(
                 &Context::new(),
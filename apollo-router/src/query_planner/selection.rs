@@ -36,6 +36,13 @@ pub(crate) struct Field {
     pub(crate) selections: Option<Vec<Selection>>,
 }
 
+impl Field {
+    /// The key of the field in the response: its alias, or its name.
+    pub(crate) fn response_name(&self) -> &str {
+        self.alias.as_deref().unwrap_or(self.name.as_str())
+    }
+}
+
 /// An inline fragment.
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -58,7 +65,7 @@ pub(crate) fn select_object(
         match selection {
             Selection::Field(field) => {
                 if let Some(value) = select_field(content, field, schema)? {
-                    match output.entry(field.name.to_owned()) {
+                    match output.entry(field.response_name().to_owned()) {
                         Entry::Occupied(mut existing) => existing.get_mut().deep_merge(value),
                         Entry::Vacant(vacant) => {
                             vacant.insert(value);
@@ -86,13 +93,12 @@ fn select_field(
     field: &Field,
     schema: &Schema,
 ) -> Result<Option<Value>, FetchError> {
-    let res = match (content.get(field.name.as_str()), &field.selections) {
-        (Some(v), _) => select_value(v, field, schema),
-        (None, _) => Err(FetchError::ExecutionFieldNotFound {
-            field: field.name.to_owned(),
+    match content.get(field.response_name()) {
+        Some(v) => select_value(v, field, schema),
+        None => Err(FetchError::ExecutionFieldNotFound {
+            field: field.response_name().to_owned(),
         }),
-    };
-    res
+    }
 }
 
 fn select_inline_fragment(
@@ -122,6 +128,8 @@ fn select_value(
     schema: &Schema,
 ) -> Result<Option<Value>, FetchError> {
     match (content, &field.selections) {
+        // a null required field is sent as is, the subgraph decides what to do with it
+        (Value::Null, _) => Ok(Some(Value::Null)),
         (Value::Object(child), Some(selections)) => select_object(child, selections, schema),
         (Value::Array(elements), Some(_)) => elements
             .iter()
@@ -336,6 +344,68 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_aliases_and_null_values() {
+        let schema = with_supergraph_boilerplate(
+            "type Query { me: String }
+            type Product { upc: String price: Int dimensions: Dimensions }
+            type Dimensions { size: Int weight: Int }",
+        );
+        let schema = Schema::parse(&schema, &Default::default()).unwrap();
+
+        let response = bjson!({
+            "__typename": "Product",
+            "upc": "1",
+            "productPrice": 899,
+            "dimensions": null
+        });
+
+        let requires = json!([
+            {
+                "kind": "InlineFragment",
+                "typeCondition": "Product",
+                "selections": [
+                    {
+                        "kind": "Field",
+                        "name": "__typename",
+                    },
+                    {
+                        "kind": "Field",
+                        "name": "upc",
+                    },
+                    {
+                        "kind": "Field",
+                        "alias": "productPrice",
+                        "name": "price",
+                    },
+                    {
+                        "kind": "Field",
+                        "name": "dimensions",
+                        "selections": [
+                            {
+                                "kind": "Field",
+                                "name": "weight",
+                            }
+                        ],
+                    }
+                ],
+            },
+        ]);
+        let selection: Vec<Selection> = serde_json::from_value(requires).unwrap();
+
+        assert_eq!(
+            select_object(response.as_object().unwrap(), &selection, &schema)
+                .unwrap()
+                .unwrap(),
+            bjson!({
+                "__typename": "Product",
+                "upc": "1",
+                "productPrice": 899,
+                "dimensions": null
+            })
+        );
+    }
+
     fn with_supergraph_boilerplate(content: &str) -> String {
         format!(
             "{}\n{}",
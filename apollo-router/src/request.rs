@@ -9,6 +9,9 @@ use serde_json_bytes::ByteString;
 use serde_json_bytes::Map as JsonMap;
 use serde_json_bytes::Value;
 
+use crate::extensions::with_request_extensions;
+use crate::extensions::ClientLibrary;
+use crate::extensions::PersistedQuery;
 use crate::json_ext::Object;
 
 /// A graphql request.
@@ -57,12 +60,14 @@ impl Request {
         // Skip the `Object` type alias in order to use buildstructor’s map special-casing
         variables: JsonMap<ByteString, Value>,
         extensions: JsonMap<ByteString, Value>,
+        persisted_query: Option<PersistedQuery>,
+        client_library: Option<ClientLibrary>,
     ) -> Self {
         Self {
             query,
             operation_name,
             variables,
-            extensions,
+            extensions: with_request_extensions(extensions, persisted_query, client_library),
         }
     }
 
@@ -73,12 +78,14 @@ impl Request {
         // Skip the `Object` type alias in order to use buildstructor’s map special-casing
         variables: JsonMap<ByteString, Value>,
         extensions: JsonMap<ByteString, Value>,
+        persisted_query: Option<PersistedQuery>,
+        client_library: Option<ClientLibrary>,
     ) -> Self {
         Self {
             query,
             operation_name,
             variables,
-            extensions,
+            extensions: with_request_extensions(extensions, persisted_query, client_library),
         }
     }
 
@@ -129,6 +136,32 @@ impl Request {
         Ok(request)
     }
 
+    /// Encode the request as the query string of a GET request, as decoded by
+    /// [`Request::from_urlencoded_query`].
+    pub(crate) fn to_urlencoded_query(&self) -> String {
+        let mut parameters = Vec::new();
+        if let Some(query) = &self.query {
+            parameters.push(("query", query.clone()));
+        }
+        if let Some(operation_name) = &self.operation_name {
+            parameters.push(("operationName", operation_name.clone()));
+        }
+        if !self.variables.is_empty() {
+            parameters.push((
+                "variables",
+                serde_json::to_string(&self.variables).expect("JSON serialization should not fail"),
+            ));
+        }
+        if !self.extensions.is_empty() {
+            parameters.push((
+                "extensions",
+                serde_json::to_string(&self.extensions)
+                    .expect("JSON serialization should not fail"),
+            ));
+        }
+        serde_urlencoded::to_string(parameters).expect("pairs of strings can be encoded; qed")
+    }
+
     pub fn from_bytes(b: Bytes) -> Result<Request, serde_json::error::Error> {
         let value = Value::from_bytes(b)?;
         let mut object = ensure_object!(value).map_err(serde::de::Error::custom)?;
@@ -270,4 +303,19 @@ mod tests {
 
         assert_eq!(expected_result, req);
     }
+
+    #[test]
+    fn to_urlencoded_query_works() {
+        let request = Request::builder()
+            .query("query Me($id: ID!) { user(id: $id) { name } }")
+            .operation_name("Me")
+            .variables(bjson!({ "id": "1" }).as_object().cloned().unwrap())
+            .extensions(bjson!({"extension": 1}).as_object().cloned().unwrap())
+            .build();
+
+        assert_eq!(
+            Request::from_urlencoded_query(request.to_urlencoded_query()).unwrap(),
+            request
+        );
+    }
 }
@@ -1,6 +1,5 @@
 #![allow(missing_docs)] // FIXME
 
-use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
 use std::pin::Pin;
@@ -16,13 +15,10 @@ use displaydoc::Display as DisplayDoc;
 use futures::channel::oneshot;
 use futures::prelude::*;
 use futures::FutureExt;
-use http_body::Body as _;
-use hyper::Body;
 use thiserror::Error;
 use tokio::sync::RwLock;
 use tokio::task::spawn;
 use tower::BoxError;
-use tower::ServiceExt;
 use tracing_futures::WithSubscriber;
 use url::Url;
 use Event::NoMoreConfiguration;
@@ -30,58 +26,19 @@ use Event::NoMoreSchema;
 use Event::Shutdown;
 use Event::UpdateConfiguration;
 use Event::UpdateSchema;
+use Event::UplinkError;
 
-use crate::axum_http_server_factory::make_axum_router;
 use crate::axum_http_server_factory::AxumHttpServerFactory;
-use crate::configuration::validate_configuration;
+use crate::configuration::load_configuration;
+use crate::configuration::secrets::Secrets;
 use crate::configuration::Configuration;
 use crate::configuration::ListenAddr;
-use crate::plugin::DynPlugin;
-use crate::router_factory::SupergraphServiceConfigurator;
+use crate::http_server_factory::InheritedListener;
 use crate::router_factory::YamlSupergraphServiceFactory;
-use crate::services::transport;
-use crate::spec::Schema;
 use crate::state_machine::StateMachine;
 
 type SchemaStream = Pin<Box<dyn Stream<Item = String> + Send>>;
 
-// For now this is unused:
-#[allow(unused)]
-// Later we might add a public API for this (probably a builder similar to `test_harness.rs`),
-// see https://github.com/apollographql/router/issues/1496.
-// In the meantime keeping this function helps make sure it still compiles.
-async fn make_transport_service<RF>(
-    schema: &str,
-    configuration: Arc<Configuration>,
-    extra_plugins: Vec<(String, Box<dyn DynPlugin>)>,
-) -> Result<transport::BoxCloneService, BoxError> {
-    let schema = Arc::new(Schema::parse(schema, &configuration)?);
-    let service_factory = YamlSupergraphServiceFactory
-        .create(configuration.clone(), schema, None, Some(extra_plugins))
-        .await?;
-    let extra = Default::default();
-    Ok(make_axum_router(service_factory, &configuration, extra)?
-        .map_response(|response| {
-            response.map(|body| {
-                // Axum makes this `body` have type:
-                // https://docs.rs/http-body/0.4.5/http_body/combinators/struct.UnsyncBoxBody.html
-                let mut body = Box::pin(body);
-                // We make a stream based on its `poll_data` method
-                // in order to create a `hyper::Body`.
-                Body::wrap_stream(stream::poll_fn(move |ctx| body.as_mut().poll_data(ctx)))
-                // … but we ignore the `poll_trailers` method:
-                // https://docs.rs/http-body/0.4.5/http_body/trait.Body.html#tymethod.poll_trailers
-                // Apparently HTTP/2 trailers are like headers, except after the response body.
-                // I (Simon) believe nothing in the Apollo Router uses trailers as of this writing,
-                // so ignoring `poll_trailers` is fine.
-                // If we want to use trailers, we may need remove this convertion to `hyper::Body`
-                // and return `UnsyncBoxBody` (a.k.a. `axum::BoxBody`) as-is.
-            })
-        })
-        .map_err(|error| match error {})
-        .boxed_clone())
-}
-
 /// Error types for FederatedServer.
 #[derive(Error, Debug, DisplayDoc)]
 pub enum ApolloRouterError {
@@ -130,6 +87,24 @@ pub enum SchemaSource {
         delay: Option<Duration>,
     },
 
+    /// A YAML file listing the subgraphs to compose the supergraph from, in the format of
+    /// `rover supergraph compose`.
+    #[display(fmt = "Subgraphs")]
+    Subgraphs {
+        /// The path of the supergraph configuration file.
+        path: PathBuf,
+
+        /// `true` to compose again when the configuration or a subgraph schema file changes.
+        watch: bool,
+
+        /// When watching, the delay to wait before composing the new supergraph.
+        delay: Option<Duration>,
+
+        /// If set, the subgraphs listed with a `subgraph_url` are introspected at this interval,
+        /// and the supergraph is composed again when their schema changes.
+        poll_interval: Option<Duration>,
+    },
+
     /// Apollo managed federation.
     #[display(fmt = "Registry")]
     Registry {
@@ -157,7 +132,7 @@ impl From<&'_ str> for SchemaSource {
 
 impl SchemaSource {
     /// Convert this schema into a stream regardless of if is static or not. Allows for unified handling later.
-    fn into_stream(self) -> impl Stream<Item = Event> {
+    pub(crate) fn into_stream(self) -> impl Stream<Item = Event> {
         match self {
             SchemaSource::Static { schema_sdl: schema } => {
                 stream::once(future::ready(UpdateSchema(schema))).boxed()
@@ -193,6 +168,14 @@ impl SchemaSource {
                     }
                 }
             }
+            SchemaSource::Subgraphs {
+                path,
+                watch,
+                delay,
+                poll_interval,
+            } => crate::composition::stream(path, watch, delay, poll_interval)
+                .map(UpdateSchema)
+                .boxed(),
             SchemaSource::Registry {
                 apollo_key,
                 apollo_graph_ref,
@@ -210,7 +193,7 @@ impl SchemaSource {
                                     "error downloading the schema from Uplink: {:?}",
                                     e
                                 );
-                                None
+                                Some(UplinkError(format!("{:?}", e)))
                             }
                         })
                     })
@@ -263,7 +246,7 @@ impl Default for ConfigurationSource {
 
 impl ConfigurationSource {
     /// Convert this config into a stream regardless of if is static or not. Allows for unified handling later.
-    fn into_stream(self) -> impl Stream<Item = Event> {
+    pub(crate) fn into_stream(self) -> impl Stream<Item = Event> {
         match self {
             ConfigurationSource::Static(instance) => {
                 stream::iter(vec![UpdateConfiguration(instance)]).boxed()
@@ -280,35 +263,60 @@ impl ConfigurationSource {
                     );
                     stream::empty().boxed()
                 } else {
-                    match ConfigurationSource::read_config(&path) {
-                        Ok(configuration) => {
-                            if watch {
-                                crate::files::watch(path.to_owned(), delay)
-                                    .filter_map(move |_| {
-                                        future::ready(
-                                            match ConfigurationSource::read_config(&path) {
-                                                Ok(config) => Some(config),
-                                                Err(err) => {
-                                                    tracing::error!("{}", err);
-                                                    None
-                                                }
-                                            },
-                                        )
-                                    })
-                                    .map(|x| UpdateConfiguration(Box::new(x)))
-                                    .boxed()
+                    // the secrets are scoped to the file, and renewed until the stream is dropped
+                    let secrets = Secrets::default();
+                    let initial_secrets = secrets.clone();
+                    stream::once(async move {
+                        let configuration =
+                            ConfigurationSource::read_config(&path, &initial_secrets).await;
+                        (path, configuration)
+                    })
+                    .flat_map(move |(path, configuration)| match configuration {
+                        Ok((configuration, uses_secrets)) => {
+                            let reloads = if watch {
+                                // the watch also sends an event for the initial read, skipped here
+                                let reloads = crate::files::watch(path.to_owned(), delay).skip(1);
+                                // on unix, SIGHUP also reloads the configuration
+                                #[cfg(unix)]
+                                let reloads = stream::select(reloads, crate::files::sighup());
+                                reloads.boxed()
+                            } else if uses_secrets {
+                                stream::pending().boxed()
+                            } else {
+                                stream::empty().boxed()
+                            };
+                            // the secrets renewed with new values reload the configuration too
+                            let reloads = if watch || uses_secrets {
+                                stream::select(reloads, secrets.changes()).boxed()
                             } else {
-                                stream::once(future::ready(UpdateConfiguration(Box::new(
-                                    configuration,
-                                ))))
+                                reloads
+                            };
+                            let secrets = secrets.clone();
+                            stream::once(future::ready(configuration))
+                                .chain(reloads.filter_map(move |_| {
+                                    let path = path.clone();
+                                    let secrets = secrets.clone();
+                                    async move {
+                                        match ConfigurationSource::read_config(&path, &secrets)
+                                            .await
+                                        {
+                                            Ok((config, _)) => Some(config),
+                                            Err(err) => {
+                                                tracing::error!("{}", err);
+                                                None
+                                            }
+                                        }
+                                    }
+                                }))
+                                .map(|x| UpdateConfiguration(Box::new(x)))
                                 .boxed()
-                            }
                         }
                         Err(err) => {
                             tracing::error!("{}", err);
                             stream::empty().boxed()
                         }
-                    }
+                    })
+                    .boxed()
                 }
             }
         }
@@ -316,11 +324,32 @@ impl ConfigurationSource {
         .boxed()
     }
 
-    fn read_config(path: &Path) -> Result<Configuration, ReadConfigError> {
-        let config = fs::read_to_string(path)?;
-        let config = validate_configuration(&config)?;
+    /// Apply the development mode options to every configuration of this source.
+    pub(crate) fn with_dev_mode(self) -> Self {
+        ConfigurationSource::Stream(
+            self.into_stream()
+                .filter_map(|event| {
+                    future::ready(match event {
+                        UpdateConfiguration(configuration) => Some(configuration.with_dev_mode()),
+                        _ => None,
+                    })
+                })
+                .boxed(),
+        )
+    }
 
-        Ok(config)
+    /// Read a configuration file, and whether it references secrets.
+    async fn read_config(
+        path: &Path,
+        secrets: &Secrets,
+    ) -> Result<(Configuration, bool), ReadConfigError> {
+        let raw_config = tokio::fs::read_to_string(path).await?;
+        let config = load_configuration(&raw_config, secrets).await?;
+
+        Ok((
+            config,
+            crate::configuration::secrets::has_references(&raw_config),
+        ))
     }
 }
 
@@ -354,7 +383,7 @@ pub enum ShutdownSource {
 
 impl ShutdownSource {
     /// Convert this shutdown hook into a future. Allows for unified handling later.
-    fn into_stream(self) -> impl Stream<Item = Event> {
+    pub(crate) fn into_stream(self) -> impl Stream<Item = Event> {
         match self {
             ShutdownSource::None => stream::pending::<Event>().boxed(),
             ShutdownSource::Custom(future) => future.map(|_| Shutdown).into_stream().boxed(),
@@ -472,6 +501,17 @@ impl RouterHttpServer {
         schema: SchemaSource,
         configuration: Option<ConfigurationSource>,
         shutdown: Option<ShutdownSource>,
+    ) -> RouterHttpServer {
+        Self::start_with_listener(schema, configuration, shutdown, None)
+    }
+
+    /// Start the server like [`RouterHttpServer::start`], on the listener inherited by the router
+    /// process if there is one.
+    pub(crate) fn start_with_listener(
+        schema: SchemaSource,
+        configuration: Option<ConfigurationSource>,
+        shutdown: Option<ShutdownSource>,
+        inherited_listener: Option<InheritedListener>,
     ) -> RouterHttpServer {
         let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
         let event_stream = generate_event_stream(
@@ -480,7 +520,7 @@ impl RouterHttpServer {
             schema,
             shutdown_receiver,
         );
-        let server_factory = AxumHttpServerFactory::new();
+        let server_factory = AxumHttpServerFactory::with_inherited_listener(inherited_listener);
         let router_factory = YamlSupergraphServiceFactory::default();
         let state_machine = StateMachine::new(server_factory, router_factory);
         let listen_address = state_machine.listen_address.clone();
@@ -544,6 +584,9 @@ pub(crate) enum Event {
     /// There are no more updates to the schema
     NoMoreSchema,
 
+    /// The schema could not be downloaded from Uplink.
+    UplinkError(String),
+
     /// The server should gracefully shutdown.
     Shutdown,
 }
@@ -0,0 +1,302 @@
+//! Embedding of the router in other binaries.
+
+use std::sync::Arc;
+
+use futures::prelude::*;
+use http_body::Body as _;
+use hyper::Body;
+use tower::ServiceExt;
+
+use crate::axum_http_server_factory::make_axum_router;
+use crate::configuration::Configuration;
+use crate::plugin::extra_plugin_name;
+use crate::plugin::DynPlugin;
+use crate::plugin::Plugin;
+use crate::router::Event;
+use crate::router_factory::SupergraphServiceConfigurator;
+use crate::router_factory::SupergraphServiceFactory;
+use crate::router_factory::YamlSupergraphServiceFactory;
+use crate::services::supergraph;
+use crate::services::transport;
+use crate::services::RouterCreator;
+use crate::ApolloRouterError;
+use crate::ConfigurationSource;
+use crate::Schema;
+use crate::SchemaSource;
+
+/// Builder for an Apollo Router embedded in another binary, as a [`tower::Service`] or as an
+/// [`axum::Router`] to serve with the other routes of the binary.
+///
+/// Unlike [`RouterHttpServer`][crate::RouterHttpServer], the embedded router does not listen on
+/// a socket, and it is not reloaded: only the first schema and configuration of their sources are
+/// used, and the binary builds a new router to apply new ones. The admin server configured with
+/// `admin` is not started either.
+///
+/// Example serving the router on `/graphql`, next to the routes of the binary:
+///
+/// ```no_run
+/// use apollo_router::RouterBuilder;
+/// use apollo_router::SchemaSource;
+///
+/// # #[tokio::main] async fn main() -> Result<(), tower::BoxError> {
+/// let config = serde_json::json!({"server": {"graphql_path": "/graphql"}});
+/// let router = RouterBuilder::builder()
+///     .schema(SchemaSource::File {
+///         path: "supergraph.graphql".into(),
+///         watch: false,
+///         delay: None,
+///     })
+///     .configuration(serde_json::from_value::<apollo_router::Configuration>(config)?)
+///     .build_axum_router()
+///     .await?
+///     .route("/ping", axum::routing::get(|| async { "pong" }));
+/// axum::Server::bind(&"127.0.0.1:4000".parse()?)
+///     .serve(router.into_make_service())
+///     .await?;
+/// # Ok(()) }
+/// ```
+pub struct RouterBuilder {
+    schema: Option<SchemaSource>,
+    configuration: Option<ConfigurationSource>,
+    extra_plugins: Vec<(String, Box<dyn DynPlugin>)>,
+}
+
+// Not using buildstructor because `extra_plugin` has non-trivial signature and behavior
+impl RouterBuilder {
+    /// Returns a builder for an embedded router.
+    pub fn builder() -> Self {
+        Self {
+            schema: None,
+            configuration: None,
+            extra_plugins: Vec::new(),
+        }
+    }
+
+    /// Specifies where to find the supergraph schema definition. Required.
+    ///
+    /// Panics if called more than once.
+    pub fn schema(mut self, schema: impl Into<SchemaSource>) -> Self {
+        assert!(self.schema.is_none(), "schema was specified twice");
+        self.schema = Some(schema.into());
+        self
+    }
+
+    /// Specifies where to find the router configuration.
+    ///
+    /// Panics if called more than once.
+    ///
+    /// If this isn’t called, the default configuration is used, as with an empty YAML file.
+    pub fn configuration(mut self, configuration: impl Into<ConfigurationSource>) -> Self {
+        assert!(
+            self.configuration.is_none(),
+            "configuration was specified twice"
+        );
+        self.configuration = Some(configuration.into());
+        self
+    }
+
+    /// Adds an extra, already instanciated plugin.
+    ///
+    /// May be called multiple times.
+    /// These extra plugins are added after plugins specified in configuration.
+    pub fn extra_plugin<P: Plugin>(mut self, plugin: P) -> Self {
+        let name = extra_plugin_name::<P>(self.extra_plugins.len());
+        self.extra_plugins.push((name, Box::new(plugin)));
+        self
+    }
+
+    /// Builds the service handling GraphQL requests, without the HTTP endpoints of the router.
+    pub async fn build_supergraph_service(
+        self,
+    ) -> Result<supergraph::BoxCloneService, ApolloRouterError> {
+        let (router_creator, _configuration) = self.create().await?;
+        Ok(tower::service_fn(move |request| {
+            let service = router_creator.make();
+            async move { service.oneshot(request).await }
+        })
+        .boxed_clone())
+    }
+
+    /// Builds the service handling HTTP requests, with all the endpoints of the router: the
+    /// GraphQL paths, the health check, the custom routes and the endpoints of the plugins.
+    pub async fn build_http_service(self) -> Result<transport::BoxCloneService, ApolloRouterError> {
+        Ok(self
+            .build_axum_router()
+            .await?
+            .map_response(|response| {
+                response.map(|body| {
+                    // Axum makes this `body` have type:
+                    // https://docs.rs/http-body/0.4.5/http_body/combinators/struct.UnsyncBoxBody.html
+                    let mut body = Box::pin(body);
+                    // We make a stream based on its `poll_data` method
+                    // in order to create a `hyper::Body`.
+                    Body::wrap_stream(stream::poll_fn(move |ctx| body.as_mut().poll_data(ctx)))
+                    // … but we ignore the `poll_trailers` method:
+                    // https://docs.rs/http-body/0.4.5/http_body/trait.Body.html#tymethod.poll_trailers
+                    // Apparently HTTP/2 trailers are like headers, except after the response body.
+                    // I (Simon) believe nothing in the Apollo Router uses trailers as of this writing,
+                    // so ignoring `poll_trailers` is fine.
+                    // If we want to use trailers, we may need remove this convertion to `hyper::Body`
+                    // and return `UnsyncBoxBody` (a.k.a. `axum::BoxBody`) as-is.
+                })
+            })
+            .map_err(|error| match error {})
+            .boxed_clone())
+    }
+
+    /// Builds an [`axum::Router`] with all the endpoints of the router, which can be merged with
+    /// the other routes of the binary.
+    pub async fn build_axum_router(self) -> Result<axum::Router, ApolloRouterError> {
+        let (router_creator, configuration) = self.create().await?;
+        let plugin_handlers = router_creator.custom_endpoints();
+        make_axum_router(router_creator, &configuration, plugin_handlers)
+    }
+
+    async fn create(self) -> Result<(RouterCreator, Arc<Configuration>), ApolloRouterError> {
+        let schema = self
+            .schema
+            .ok_or(ApolloRouterError::NoSchema)?
+            .into_stream()
+            .filter_map(|event| {
+                future::ready(match event {
+                    Event::UpdateSchema(schema) => Some(schema),
+                    _ => None,
+                })
+            })
+            .next()
+            .await
+            .ok_or(ApolloRouterError::NoSchema)?;
+        let configuration = self
+            .configuration
+            .unwrap_or_default()
+            .into_stream()
+            .filter_map(|event| {
+                future::ready(match event {
+                    Event::UpdateConfiguration(configuration) => Some(configuration),
+                    _ => None,
+                })
+            })
+            .next()
+            .await
+            .ok_or(ApolloRouterError::NoConfiguration)?;
+
+        let configuration = Arc::new(*configuration);
+        let schema = Schema::parse(&schema, &configuration)
+            .map_err(|e| ApolloRouterError::ServiceCreationError(e.into()))?;
+        let router_creator = YamlSupergraphServiceFactory
+            .create(
+                configuration.clone(),
+                Arc::new(schema),
+                None,
+                Some(self.extra_plugins),
+            )
+            .await
+            .map_err(ApolloRouterError::ServiceCreationError)?;
+        Ok((router_creator, configuration))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tower::BoxError;
+
+    use super::*;
+    use crate::plugin::PluginInit;
+
+    struct Me;
+
+    #[async_trait::async_trait]
+    impl Plugin for Me {
+        type Config = ();
+
+        async fn new(_: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+            unreachable!()
+        }
+
+        fn supergraph_service(&self, _service: supergraph::BoxService) -> supergraph::BoxService {
+            tower::service_fn(|request: supergraph::Request| async move {
+                supergraph::Response::fake_builder()
+                    .data(serde_json_bytes::json!({ "me": { "name": "Ada Lovelace" } }))
+                    .context(request.context)
+                    .build()
+            })
+            .boxed()
+        }
+    }
+
+    fn builder() -> RouterBuilder {
+        RouterBuilder::builder()
+            .schema(include_str!("../../examples/graphql/local.graphql"))
+            .extra_plugin(Me)
+    }
+
+    #[tokio::test]
+    async fn it_serves_the_router_endpoints() {
+        let service = builder()
+            .configuration(
+                serde_json::from_value::<Configuration>(
+                    json!({ "server": { "graphql_path": "/graphql" } }),
+                )
+                .unwrap(),
+            )
+            .build_http_service()
+            .await
+            .unwrap();
+
+        let response = service
+            .clone()
+            .oneshot(
+                http::Request::post("/graphql")
+                    .header("content-type", "application/json")
+                    .body(Body::from(r#"{"query":"{ me { name } }"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), http::StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()["data"],
+            json!({ "me": { "name": "Ada Lovelace" } })
+        );
+
+        let response = service
+            .oneshot(
+                http::Request::get("/.well-known/apollo/server-health")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn it_builds_the_supergraph_service() {
+        let response = builder()
+            .build_supergraph_service()
+            .await
+            .unwrap()
+            .oneshot(
+                supergraph::Request::fake_builder()
+                    .query("{ me { name } }")
+                    .build()
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+            .next_response()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.data,
+            Some(serde_json_bytes::json!({ "me": { "name": "Ada Lovelace" } }))
+        );
+
+        assert!(matches!(
+            RouterBuilder::builder().build_supergraph_service().await,
+            Err(ApolloRouterError::NoSchema)
+        ));
+    }
+}
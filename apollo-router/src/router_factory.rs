@@ -1,21 +1,49 @@
 use std::collections::HashMap;
+use std::collections::HashSet;
 // With regards to ELv2 licensing, this entire file is license key functionality
+use std::num::NonZeroUsize;
 use std::sync::Arc;
 
+use futures::future;
+use futures::future::BoxFuture;
+use futures::future::Shared;
 use futures::stream::BoxStream;
+use futures::Future;
+use futures::FutureExt;
 use serde_json::Map;
 use serde_json::Value;
 use tower::BoxError;
 use tower_service::Service;
 
+use crate::admin::RuntimeState;
+use crate::cache::storage::KeyValueStorage;
+use crate::cache::storage::Storages;
+use crate::canary;
 use crate::configuration::Configuration;
 use crate::configuration::ConfigurationError;
+use crate::entitlement::Entitlement;
 use crate::graphql;
 use crate::plugin::DynPlugin;
 use crate::plugin::Handler;
+use crate::plugin::PluginFactory;
+use crate::plugin::ResponseBodyTransformer;
+use crate::plugin::SchemaChange;
+use crate::plugins::authentication::SubgraphAuthentication;
+use crate::plugins::telemetry::metrics::register_entitlement_metrics;
+use crate::plugins::telemetry::metrics::PluginMetrics;
+use crate::plugins::traffic_shaping::SubgraphHealth;
+use crate::plugins::traffic_shaping::TrafficShaping;
+use crate::services::dns::DiscoveryConnector;
+use crate::services::dns::DnsResolver;
+use crate::services::layers::apq::APQLayer;
 use crate::services::new_service::NewService;
+use crate::services::proxy::ProxyConnector;
+use crate::services::proxy::ProxySettings;
+use crate::services::Plugins;
 use crate::services::RouterCreator;
 use crate::services::SubgraphService;
+use crate::webhooks;
+use crate::webhooks::Notifier;
 use crate::PluggableSupergraphServiceBuilder;
 use crate::Schema;
 
@@ -36,9 +64,42 @@ pub(crate) trait SupergraphServiceFactory:
             Error = BoxError,
             Future = Self::Future,
         > + Send;
-    type Future: Send;
+    type Future: Future<Output = Result<http::Response<BoxStream<'static, graphql::Response>>, BoxError>>
+        + Send;
 
     fn custom_endpoints(&self) -> HashMap<String, Handler>;
+
+    /// A service for the canary requests, which bypass the quotas and the plugins accounting,
+    /// reporting or mirroring the client requests
+    fn canary_service(&self) -> Self::SupergraphService {
+        self.new_service()
+    }
+
+    /// Runtime state exposed by the admin API
+    fn runtime_state(&self) -> RuntimeState {
+        RuntimeState::default()
+    }
+
+    /// The APQ layer, to check whether persisted queries are registered
+    fn apq(&self) -> Option<APQLayer> {
+        None
+    }
+
+    /// The transformations of the serialized response bodies defined by the plugins, in the order
+    /// they are applied
+    fn response_body_transformers(&self) -> Vec<ResponseBodyTransformer> {
+        Vec::new()
+    }
+
+    /// The plugins which are not ready to handle requests, failing the readiness check
+    fn unready_plugins(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// The subgraphs failing their health checks, failing the readiness check
+    fn unhealthy_subgraphs(&self) -> Vec<String> {
+        Vec::new()
+    }
 }
 
 /// Factory for creating a SupergraphServiceFactory
@@ -70,17 +131,88 @@ impl SupergraphServiceConfigurator for YamlSupergraphServiceFactory {
         &'a mut self,
         configuration: Arc<Configuration>,
         schema: Arc<Schema>,
-        _previous_router: Option<&'a Self::SupergraphServiceFactory>,
+        previous_router: Option<&'a Self::SupergraphServiceFactory>,
         extra_plugins: Option<Vec<(String, Box<dyn DynPlugin>)>>,
     ) -> Result<Self::SupergraphServiceFactory, BoxError> {
+        // the license is read again with every configuration
+        let entitlement = Entitlement::load(configuration.licensing.as_ref());
+        entitlement.check(&configuration)?;
+
         // Process the plugins.
-        let plugins = create_plugins(&configuration, &schema, extra_plugins).await?;
+        let previous_plugins = previous_router.map(|previous_router| previous_router.plugins());
+        let webhooks = Notifier::configured(configuration.webhooks.as_ref());
+        let subgraph_health = SubgraphHealth::default();
+        // the connections to the storages are kept while their configuration does not change,
+        // and the storage is opened before the plugins sharing their counters through it
+        let mut storages = match previous_router {
+            Some(previous_router) => Storages::reusing(&previous_router.storages()),
+            None => Storages::default(),
+        };
+        let storage = match &configuration.storage {
+            Some(storage) => Some(storages.open(storage).await),
+            None => None,
+        };
+        let (plugins, reconfigured) = create_plugins(
+            &configuration,
+            &schema,
+            previous_plugins.as_deref(),
+            extra_plugins,
+            webhooks.clone(),
+            subgraph_health.clone(),
+            storage,
+            &entitlement,
+        )
+        .await?;
+
+        if let Some(previous_router) = previous_router {
+            let previous_schema = previous_router.schema();
+            if previous_schema.schema_id != schema.schema_id {
+                notify_schema_change(&plugins, &previous_schema, &schema).await?;
+            }
+        }
 
         let mut builder = PluggableSupergraphServiceBuilder::new(schema.clone());
-        builder = builder.with_configuration(configuration);
+        builder = builder
+            .with_configuration(configuration.clone())
+            .with_subgraph_health(subgraph_health.clone())
+            .with_storages(storages)
+            .with_entitlement(entitlement);
 
+        // Manifests pushed through the admin API are not part of the configuration
+        if let Some(previous_router) = previous_router {
+            builder = builder.with_persisted_queries(previous_router.persisted_queries());
+            // and neither is the maintenance mode switched through it, until the maintenance
+            // configuration changes
+            if previous_router.maintenance().config() == configuration.maintenance.as_ref() {
+                builder = builder.with_maintenance_switch(previous_router.maintenance().switch());
+            }
+            // nor is the usage of the clients when it is kept in memory
+            if let Some(quota_memory) = previous_router.quota_memory() {
+                builder = builder.with_quota_memory(quota_memory);
+            }
+        }
+
+        let resolver = match &configuration.dns {
+            Some(dns) => Some(Arc::new(DnsResolver::new(dns)?)),
+            None => None,
+        };
         for (name, _) in schema.subgraphs() {
-            builder = builder.with_subgraph_service(name, SubgraphService::new(name));
+            let connector = ProxyConnector::new(
+                DiscoveryConnector::new(resolver.clone()),
+                ProxySettings::new(configuration.proxy.as_ref(), name)?,
+            );
+            let get_max_url_length =
+                TrafficShaping::get_configuration_get_max_url_length(&configuration, name)
+                    .map(NonZeroUsize::get);
+            let service = SubgraphService::with_connector(name, connector)
+                .with_get_max_url_length(get_max_url_length)
+                .with_signer(SubgraphAuthentication::get_configuration_sigv4(
+                    &configuration,
+                    name,
+                ));
+            // the probes are sent like the requests, stopping with the pipeline
+            subgraph_health.start(name, &service);
+            builder = builder.with_subgraph_service(name, service);
         }
 
         for (plugin_name, plugin) in plugins {
@@ -89,6 +221,22 @@ impl SupergraphServiceConfigurator for YamlSupergraphServiceFactory {
 
         // We're good to go with the new service.
         let pluggable_router_service = builder.build().await?;
+        let plugins = pluggable_router_service.plugins();
+        let started = start_plugins(&plugins, &reconfigured).await?;
+
+        // a reload is only applied once the canary operations succeed on the new pipeline
+        if let (Some(_), Some(canary)) = (previous_router, &configuration.canary) {
+            if let Err(e) = canary::evaluate(canary, &pluggable_router_service).await {
+                if let Some(webhooks) = &webhooks {
+                    webhooks.notify(webhooks::Event::CanaryFailed {
+                        operation: e.operation().map(str::to_string),
+                        error: e.to_string(),
+                    });
+                }
+                rollback_plugins(&plugins, &started).await;
+                return Err(e.into());
+            }
+        }
 
         Ok(pluggable_router_service)
     }
@@ -117,11 +265,17 @@ caused by
     );
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn create_plugins(
     configuration: &Configuration,
     schema: &Schema,
+    previous_plugins: Option<&Plugins>,
     extra_plugins: Option<Vec<(String, Box<dyn DynPlugin>)>>,
-) -> Result<Vec<(String, Box<dyn DynPlugin>)>, BoxError> {
+    webhooks: Option<Notifier>,
+    subgraph_health: SubgraphHealth,
+    storage: Option<Arc<dyn KeyValueStorage>>,
+    entitlement: &Entitlement,
+) -> Result<(Vec<(String, Box<dyn DynPlugin>)>, HashSet<String>), BoxError> {
     // List of mandatory plugins. Ordering is important!!
     let mandatory_plugins = vec![
         "experimental.include_subgraph_errors",
@@ -132,6 +286,10 @@ async fn create_plugins(
     let mut errors = Vec::new();
     let plugin_registry = crate::plugin::plugins();
     let mut plugin_instances = Vec::new();
+    let mut reconfigured = HashSet::new();
+    // the metrics of the plugins are exported by the telemetry plugin of the pipeline
+    let metrics = PluginMetrics::default();
+    register_entitlement_metrics(&metrics, entitlement.clone());
     let extra = extra_plugins.unwrap_or_default();
 
     for (name, mut configuration) in configuration.plugins().into_iter() {
@@ -151,11 +309,23 @@ async fn create_plugins(
                     inject_schema_id(schema, &mut configuration);
                 }
                 // expand any env variables in the config before processing.
-                match factory
-                    .create_instance(&configuration, schema.as_string().clone())
-                    .await
+                match create_plugin(
+                    &name,
+                    factory,
+                    &configuration,
+                    schema,
+                    previous_plugins,
+                    metrics.clone(),
+                    webhooks.clone(),
+                    subgraph_health.clone(),
+                    storage.clone(),
+                )
+                .await
                 {
-                    Ok(plugin) => {
+                    Ok((plugin, reused)) => {
+                        if reused {
+                            reconfigured.insert(name.clone());
+                        }
                         plugin_instances.push((name, plugin));
                     }
                     Err(err) => errors.push(ConfigurationError::PluginConfiguration {
@@ -199,11 +369,23 @@ async fn create_plugins(
                         if *name == "apollo.telemetry" {
                             inject_schema_id(schema, &mut config);
                         }
-                        match factory
-                            .create_instance(&config, schema.as_string().clone())
-                            .await
+                        match create_plugin(
+                            name,
+                            factory,
+                            &config,
+                            schema,
+                            previous_plugins,
+                            metrics.clone(),
+                            webhooks.clone(),
+                            subgraph_health.clone(),
+                            storage.clone(),
+                        )
+                        .await
                         {
-                            Ok(plugin) => {
+                            Ok((plugin, reused)) => {
+                                if reused {
+                                    reconfigured.insert(name.to_string());
+                                }
                                 plugin_instances
                                     .insert(desired_position, (name.to_string(), plugin));
                             }
@@ -238,7 +420,183 @@ async fn create_plugins(
                 .join("\n"),
         ))
     } else {
-        Ok(plugin_instances)
+        Ok((plugin_instances, reconfigured))
+    }
+}
+
+/// Create a plugin, from its previous instance if it can be reconfigured, in which case it is
+/// already started.
+#[allow(clippy::too_many_arguments)]
+async fn create_plugin(
+    name: &str,
+    factory: &PluginFactory,
+    configuration: &Value,
+    schema: &Schema,
+    previous_plugins: Option<&Plugins>,
+    metrics: PluginMetrics,
+    webhooks: Option<Notifier>,
+    subgraph_health: SubgraphHealth,
+    storage: Option<Arc<dyn KeyValueStorage>>,
+) -> Result<(Box<dyn DynPlugin>, bool), BoxError> {
+    if let Some(previous) = previous_plugins.and_then(|plugins| plugins.get(name)) {
+        if let Some(plugin) = previous
+            .reconfigure(
+                configuration,
+                schema.as_string().clone(),
+                metrics.clone(),
+                webhooks.clone(),
+                subgraph_health.clone(),
+                storage.clone(),
+            )
+            .await?
+        {
+            tracing::debug!("reconfigured plugin: '{}'", name);
+            return Ok((plugin, true));
+        }
+    }
+    let plugin = factory
+        .create_instance_in_pipeline(
+            configuration,
+            schema.as_string().clone(),
+            metrics,
+            webhooks,
+            subgraph_health,
+            storage,
+        )
+        .await?;
+    Ok((plugin, false))
+}
+
+/// Let the plugins know about a new schema, any of them being able to reject it.
+async fn notify_schema_change(
+    plugins: &[(String, Box<dyn DynPlugin>)],
+    previous_schema: &Schema,
+    schema: &Schema,
+) -> Result<(), BoxError> {
+    let change = SchemaChange::new(previous_schema, schema);
+    tracing::debug!(
+        added = ?change.added,
+        removed = ?change.removed,
+        changed = ?change.changed,
+        "supergraph schema changed from {} to {}",
+        change.previous_hash,
+        change.hash
+    );
+    for (name, plugin) in plugins {
+        plugin.schema_changed(&change).await.map_err(|e| {
+            BoxError::from(format!(
+                "plugin '{}' rejected the new supergraph schema: {}",
+                name, e
+            ))
+        })?;
+    }
+    Ok(())
+}
+
+/// Run the startup of the plugins, concurrently, each plugin starting as soon as the plugins it
+/// depends on are started. The reconfigured plugins are not started again, since they keep the
+/// state of their previous instance.
+///
+/// Returns the names of the started plugins, each one after the plugins it depends on. If any
+/// plugin fails to start, the startup of the others is rolled back.
+async fn start_plugins<'a>(
+    plugins: &'a Plugins,
+    reconfigured: &HashSet<String>,
+) -> Result<Vec<&'a str>, BoxError> {
+    let configured = |dependency: &str| plugins.contains_key(dependency);
+    // the plugins in an order where each one comes after the plugins it depends on
+    let mut ordered: Vec<(&String, &Box<dyn DynPlugin>)> = Vec::with_capacity(plugins.len());
+    let mut visited: HashSet<&str> = HashSet::new();
+    while ordered.len() < plugins.len() {
+        let next: Vec<(&String, &Box<dyn DynPlugin>)> = plugins
+            .iter()
+            .filter(|(name, plugin)| {
+                !visited.contains(name.as_str())
+                    && plugin
+                        .depends_on()
+                        .iter()
+                        .all(|dependency| visited.contains(dependency) || !configured(dependency))
+            })
+            .collect();
+        if next.is_empty() {
+            return Err(BoxError::from(format!(
+                "plugins {} depend on each other",
+                plugins
+                    .keys()
+                    .map(String::as_str)
+                    .filter(|name| !visited.contains(name))
+                    .map(|name| format!("'{}'", name))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )));
+        }
+        visited.extend(next.iter().map(|(name, _)| name.as_str()));
+        ordered.extend(next);
+    }
+
+    let ordered: Vec<(&String, &Box<dyn DynPlugin>)> = ordered
+        .into_iter()
+        .filter(|(name, _)| !reconfigured.contains(name.as_str()))
+        .collect();
+    let mut startups: HashMap<&str, Shared<BoxFuture<'_, Result<(), String>>>> = HashMap::new();
+    for (name, plugin) in &ordered {
+        let dependencies: Vec<_> = plugin
+            .depends_on()
+            .iter()
+            .filter_map(|dependency| startups.get(dependency).cloned())
+            .collect();
+        let startup = async move {
+            future::try_join_all(dependencies).await?;
+            let timeout = plugin.startup_timeout();
+            match tokio::time::timeout(timeout, plugin.startup()).await {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(e)) => Err(format!("plugin '{}' failed to start: {}", name, e)),
+                Err(_) => Err(format!(
+                    "plugin '{}' did not start within {:?}",
+                    name, timeout
+                )),
+            }
+        }
+        .boxed();
+        startups.insert(name.as_str(), startup.shared());
+    }
+    let results = future::join_all(
+        ordered
+            .iter()
+            .map(|(name, _)| startups[name.as_str()].clone()),
+    )
+    .await;
+
+    let started: Vec<&str> = ordered
+        .iter()
+        .zip(&results)
+        .filter(|(_, result)| result.is_ok())
+        .map(|((name, _), _)| name.as_str())
+        .collect();
+    if let Some(Err(e)) = results.into_iter().find(Result::is_err) {
+        rollback_plugins(plugins, &started).await;
+        return Err(BoxError::from(e));
+    }
+    Ok(started)
+}
+
+/// Roll back the startup of the plugins of a pipeline which is not applied, each plugin before
+/// the plugins it depends on.
+async fn rollback_plugins(plugins: &Plugins, started: &[&str]) {
+    for name in started.iter().rev() {
+        if let Some(plugin) = plugins.get(*name) {
+            let timeout = plugin.startup_timeout();
+            if tokio::time::timeout(timeout, plugin.rollback())
+                .await
+                .is_err()
+            {
+                tracing::warn!(
+                    "plugin '{}' did not roll back its startup within {:?}",
+                    name,
+                    timeout
+                );
+            }
+        }
     }
 }
 
@@ -266,20 +624,30 @@ fn inject_schema_id(schema: &Schema, configuration: &mut Value) {
 mod test {
     use std::error::Error;
     use std::fmt;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
     use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::time::Duration;
 
+    use futures::StreamExt;
     use schemars::JsonSchema;
     use serde::Deserialize;
     use serde_json::json;
+    use tower::ServiceExt;
     use tower_http::BoxError;
 
     use crate::configuration::Configuration;
+    use crate::graphql;
+    use crate::plugin::DynPlugin;
     use crate::plugin::Plugin;
     use crate::plugin::PluginInit;
+    use crate::plugin::SchemaChange;
     use crate::register_plugin;
     use crate::router_factory::inject_schema_id;
     use crate::router_factory::SupergraphServiceConfigurator;
     use crate::router_factory::YamlSupergraphServiceFactory;
+    use crate::services::new_service::NewService;
     use crate::Schema;
 
     #[derive(Debug)]
@@ -340,6 +708,35 @@ mod test {
         AlwaysFailsToStartPlugin
     );
 
+    // Records schema changes, and rejects them if configured to
+
+    #[derive(Debug)]
+    struct SchemaChangesPlugin {
+        changes: Arc<Mutex<Vec<SchemaChange>>>,
+        reject: bool,
+    }
+
+    #[async_trait::async_trait]
+    impl Plugin for SchemaChangesPlugin {
+        type Config = Conf;
+
+        async fn new(_init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+            Ok(SchemaChangesPlugin {
+                changes: Default::default(),
+                reject: false,
+            })
+        }
+
+        async fn schema_changed(&self, change: &SchemaChange) -> Result<(), BoxError> {
+            self.changes.lock().unwrap().push(change.clone());
+            if self.reject {
+                Err(BoxError::from(PluginError))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
     #[tokio::test]
     async fn test_yaml_no_extras() {
         let config = Configuration::builder().build();
@@ -391,6 +788,277 @@ mod test {
         assert!(service.is_err())
     }
 
+    #[tokio::test]
+    async fn test_plugins_are_notified_of_schema_changes() {
+        let configuration = Arc::new(Configuration::default());
+        let supergraph = include_str!("testdata/supergraph.graphql");
+        let schema = Arc::new(Schema::parse(supergraph, &configuration).unwrap());
+        let mut factory = YamlSupergraphServiceFactory::default();
+        let previous = factory
+            .create(configuration.clone(), schema.clone(), None, None)
+            .await
+            .unwrap();
+
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let plugins = |reject| {
+            let plugin: Box<dyn DynPlugin> = Box::new(SchemaChangesPlugin {
+                changes: changes.clone(),
+                reject,
+            });
+            Some(vec![("apollo.test.schema_changes".to_string(), plugin)])
+        };
+
+        // reloading with the same schema
+        factory
+            .create(
+                configuration.clone(),
+                schema.clone(),
+                Some(&previous),
+                plugins(false),
+            )
+            .await
+            .unwrap();
+        assert!(changes.lock().unwrap().is_empty());
+
+        let new_supergraph = supergraph
+            .replace(
+                "  body: String @join__field(graph: REVIEWS)\n",
+                "  rating: Int @join__field(graph: REVIEWS)\n",
+            )
+            .replace(
+                "  name: String @join__field(graph: ACCOUNTS)\n",
+                "  name: String! @join__field(graph: ACCOUNTS)\n",
+            );
+        let new_schema = Arc::new(Schema::parse(&new_supergraph, &configuration).unwrap());
+        factory
+            .create(
+                configuration.clone(),
+                new_schema.clone(),
+                Some(&previous),
+                plugins(false),
+            )
+            .await
+            .unwrap();
+        assert!(factory
+            .create(
+                configuration,
+                new_schema.clone(),
+                Some(&previous),
+                plugins(true)
+            )
+            .await
+            .is_err());
+
+        let changes = changes.lock().unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(
+            changes[0],
+            SchemaChange {
+                previous_hash: schema.schema_id.clone().unwrap(),
+                hash: new_schema.schema_id.clone().unwrap(),
+                added: vec!["Review.rating".to_string()],
+                removed: vec!["Review.body".to_string()],
+                changed: vec!["User.name".to_string()],
+            }
+        );
+    }
+
+    // Counts its instances, keeping them when reconfigured unless asked to reset
+
+    static NEW_INSTANCES: AtomicUsize = AtomicUsize::new(0);
+    static RECONFIGURED_INSTANCES: AtomicUsize = AtomicUsize::new(0);
+    static STARTED_INSTANCES: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Debug)]
+    struct ReconfigurablePlugin {
+        generation: usize,
+    }
+
+    #[async_trait::async_trait]
+    impl Plugin for ReconfigurablePlugin {
+        type Config = Conf;
+
+        async fn new(_init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+            NEW_INSTANCES.fetch_add(1, Ordering::SeqCst);
+            Ok(ReconfigurablePlugin { generation: 0 })
+        }
+
+        async fn reconfigure(
+            &self,
+            init: PluginInit<Self::Config>,
+        ) -> Result<Option<Self>, BoxError> {
+            if init.config.name == "reset" {
+                return Ok(None);
+            }
+            RECONFIGURED_INSTANCES.fetch_add(1, Ordering::SeqCst);
+            Ok(Some(ReconfigurablePlugin {
+                generation: self.generation + 1,
+            }))
+        }
+
+        async fn startup(&self) -> Result<(), BoxError> {
+            STARTED_INSTANCES.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    register_plugin!("apollo.test", "reconfigurable", ReconfigurablePlugin);
+
+    #[tokio::test]
+    async fn test_plugins_are_reconfigured_when_they_opt_in() {
+        let configuration = |name| {
+            let configuration: Configuration = serde_yaml::from_str(&format!(
+                "plugins:\n  apollo.test.reconfigurable:\n    name: {}\n",
+                name
+            ))
+            .unwrap();
+            Arc::new(configuration)
+        };
+        let supergraph = include_str!("testdata/supergraph.graphql");
+        let schema = Arc::new(Schema::parse(supergraph, &configuration("a")).unwrap());
+        let mut factory = YamlSupergraphServiceFactory::default();
+        let first = factory
+            .create(configuration("a"), schema.clone(), None, None)
+            .await
+            .unwrap();
+        assert_eq!(NEW_INSTANCES.load(Ordering::SeqCst), 1);
+
+        let second = factory
+            .create(configuration("b"), schema.clone(), Some(&first), None)
+            .await
+            .unwrap();
+        assert_eq!(NEW_INSTANCES.load(Ordering::SeqCst), 1);
+        assert_eq!(RECONFIGURED_INSTANCES.load(Ordering::SeqCst), 1);
+        // the reconfigured instance is not started again
+        assert_eq!(STARTED_INSTANCES.load(Ordering::SeqCst), 1);
+        assert!(second.plugins().contains_key("apollo.test.reconfigurable"));
+
+        factory
+            .create(configuration("reset"), schema, Some(&second), None)
+            .await
+            .unwrap();
+        assert_eq!(NEW_INSTANCES.load(Ordering::SeqCst), 2);
+        assert_eq!(RECONFIGURED_INSTANCES.load(Ordering::SeqCst), 1);
+        assert_eq!(STARTED_INSTANCES.load(Ordering::SeqCst), 2);
+    }
+
+    // Records its startup, once the plugins it depends on are started, until it is rolled back
+
+    struct StartupPlugin {
+        name: &'static str,
+        depends_on: Vec<&'static str>,
+        duration: Duration,
+        started: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Plugin for StartupPlugin {
+        type Config = Conf;
+
+        async fn new(_init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+            unreachable!()
+        }
+
+        async fn startup(&self) -> Result<(), BoxError> {
+            tokio::time::sleep(self.duration).await;
+            self.started.lock().unwrap().push(self.name);
+            Ok(())
+        }
+
+        async fn rollback(&self) {
+            self.started
+                .lock()
+                .unwrap()
+                .retain(|name| *name != self.name);
+        }
+
+        fn startup_timeout(&self) -> Duration {
+            Duration::from_millis(500)
+        }
+
+        fn depends_on(&self) -> Vec<&'static str> {
+            self.depends_on.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plugins_start_after_their_dependencies() {
+        let configuration = Arc::new(Configuration::default());
+        let schema = include_str!("testdata/supergraph.graphql");
+        let schema = Arc::new(Schema::parse(schema, &configuration).unwrap());
+        let started = Arc::new(Mutex::new(Vec::new()));
+        let plugin = |name, depends_on: &[&'static str], duration| {
+            let plugin: Box<dyn DynPlugin> = Box::new(StartupPlugin {
+                name,
+                depends_on: depends_on.to_vec(),
+                duration: Duration::from_millis(duration),
+                started: started.clone(),
+            });
+            (name.to_string(), plugin)
+        };
+        let create = |plugins| {
+            let configuration = configuration.clone();
+            let schema = schema.clone();
+            async move {
+                YamlSupergraphServiceFactory::default()
+                    .create(configuration, schema, None, Some(plugins))
+                    .await
+                    .map(|_| ())
+            }
+        };
+
+        create(vec![
+            plugin("test.cache", &["test.keys", "test.unknown"], 0),
+            plugin("test.keys", &[], 100),
+            plugin("test.other", &[], 0),
+        ])
+        .await
+        .unwrap();
+        assert_eq!(
+            *started.lock().unwrap(),
+            vec!["test.other", "test.keys", "test.cache"]
+        );
+
+        // a plugin only waits for its own dependencies
+        started.lock().unwrap().clear();
+        create(vec![
+            plugin("test.slow", &[], 100),
+            plugin("test.fast", &[], 0),
+            plugin("test.after_fast", &["test.fast"], 0),
+        ])
+        .await
+        .unwrap();
+        assert_eq!(
+            *started.lock().unwrap(),
+            vec!["test.fast", "test.after_fast", "test.slow"]
+        );
+
+        // the plugins which started are rolled back when another one fails to start
+        started.lock().unwrap().clear();
+        let error = create(vec![
+            plugin("test.fast", &[], 0),
+            plugin("test.slow", &[], 1000),
+        ])
+        .await
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "plugin 'test.slow' did not start within 500ms"
+        );
+        assert!(started.lock().unwrap().is_empty());
+
+        let error = create(vec![
+            plugin("test.a", &["test.b"], 0),
+            plugin("test.b", &["test.a"], 0),
+        ])
+        .await
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "plugins 'test.a', 'test.b' depend on each other"
+        );
+    }
+
     async fn create_service(config: Configuration) -> Result<(), BoxError> {
         let schema = include_str!("testdata/supergraph.graphql");
         let schema = Schema::parse(schema, &config).unwrap();
@@ -401,6 +1069,55 @@ mod test {
         service.map(|_| ())
     }
 
+    #[tokio::test]
+    async fn test_requests_matching_no_contract_are_served_the_default_contract() {
+        let schema = include_str!("testdata/contract_schema.graphql").replace(
+            "name: String @join__field(graph: PRODUCTS)",
+            r#"name: String @join__field(graph: PRODUCTS) @tag(name: "internal")"#,
+        );
+        let config: Configuration = serde_yaml::from_str(
+            r#"
+contracts:
+  internal:
+    exclude_tags: []
+    client_names: [internal-app]
+  partners:
+    exclude_tags: [internal]
+    default: true
+"#,
+        )
+        .unwrap();
+        let schema = Schema::parse(&schema, &config).unwrap();
+        let router = YamlSupergraphServiceFactory::default()
+            .create(Arc::new(config), Arc::new(schema), None, None)
+            .await
+            .unwrap();
+
+        // the client name sent in a header is not verified, it does not select a contract
+        let request = http::Request::builder()
+            .uri("http://localhost/graphql")
+            .header("apollographql-client-name", "internal-app")
+            .body(
+                graphql::Request::builder()
+                    .query("{ topProducts { name } }")
+                    .build(),
+            )
+            .unwrap();
+        let response = router
+            .new_service()
+            .oneshot(request)
+            .await
+            .unwrap()
+            .into_body()
+            .next()
+            .await
+            .unwrap();
+        assert!(response.data.is_none());
+        assert!(response.errors[0]
+            .message
+            .contains("Cannot query field \"name\" on type \"Product\"."));
+    }
+
     #[test]
     fn test_inject_schema_id() {
         let schema = include_str!("testdata/starstuff@current.graphql");
@@ -0,0 +1,280 @@
+//! Serialization of the responses sent to the clients.
+//!
+//! Besides the compact JSON of the GraphQL responses, the router can indent them, escape the
+//! non-ASCII characters of their strings, and drop the null fields of their data for the clients
+//! which opt in with a header.
+
+use std::io;
+
+use http::HeaderMap;
+use serde::Serialize;
+use serde_json::ser::CompactFormatter;
+use serde_json::ser::Formatter;
+use serde_json::ser::PrettyFormatter;
+use serde_json::Serializer;
+
+use crate::configuration::ResponseSerialization;
+use crate::graphql;
+use crate::json_ext::Value;
+
+/// Options of the serialization of a response.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct SerializationOptions {
+    /// Indent the response.
+    pub(crate) pretty: bool,
+    /// Escape the non-ASCII characters of the strings as `\uXXXX` sequences.
+    pub(crate) escape_unicode: bool,
+    /// Drop the fields of the data which are null.
+    pub(crate) trim_nulls: bool,
+}
+
+impl SerializationOptions {
+    /// The options of the responses to a request, which can opt in to the null trimming.
+    pub(crate) fn for_request(configuration: &ResponseSerialization, headers: &HeaderMap) -> Self {
+        Self {
+            pretty: configuration.pretty,
+            escape_unicode: configuration.escape_unicode,
+            trim_nulls: configuration
+                .trim_nulls_header
+                .as_ref()
+                .and_then(|header| headers.get(header.as_str()))
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.trim().eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// Apply the options changing the content of a response, before it is written.
+pub(crate) fn prepare(response: &mut graphql::Response, options: SerializationOptions) {
+    if options.trim_nulls {
+        if let Some(data) = response.data.as_mut() {
+            trim_nulls(data);
+        }
+        for incremental in &mut response.incremental {
+            if let Some(data) = incremental.data.as_mut() {
+                trim_nulls(data);
+            }
+        }
+    }
+}
+
+/// Write a response prepared with the same options.
+pub(crate) fn write<W: io::Write>(
+    writer: W,
+    response: &graphql::Response,
+    options: SerializationOptions,
+) -> serde_json::Result<()> {
+    match (options.pretty, options.escape_unicode) {
+        (false, false) => serde_json::to_writer(writer, response),
+        (true, false) => serde_json::to_writer_pretty(writer, response),
+        (false, true) => response.serialize(&mut Serializer::with_formatter(
+            writer,
+            EscapeUnicode(CompactFormatter),
+        )),
+        (true, true) => response.serialize(&mut Serializer::with_formatter(
+            writer,
+            EscapeUnicode(PrettyFormatter::new()),
+        )),
+    }
+}
+
+/// Remove the null fields of the objects of a value. The null items of lists are kept, since
+/// their position matters.
+fn trim_nulls(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            // only the objects with null fields are rebuilt, keeping the order of their fields
+            if object.values().any(Value::is_null) {
+                *object = std::mem::take(object)
+                    .into_iter()
+                    .filter(|(_, value)| !value.is_null())
+                    .collect();
+            }
+            object.values_mut().for_each(trim_nulls);
+        }
+        Value::Array(items) => items.iter_mut().for_each(trim_nulls),
+        _ => {}
+    }
+}
+
+/// A formatter escaping the non-ASCII characters of the strings, and formatting everything else
+/// like the one it wraps.
+struct EscapeUnicode<F>(F);
+
+impl<F: Formatter> Formatter for EscapeUnicode<F> {
+    fn write_string_fragment<W>(&mut self, writer: &mut W, fragment: &str) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        let mut start = 0;
+        for (index, character) in fragment.char_indices() {
+            if character.is_ascii() {
+                continue;
+            }
+            writer.write_all(fragment[start..index].as_bytes())?;
+            let mut units = [0; 2];
+            for unit in character.encode_utf16(&mut units) {
+                write!(writer, "\\u{:04x}", unit)?;
+            }
+            start = index + character.len_utf8();
+        }
+        writer.write_all(fragment[start..].as_bytes())
+    }
+
+    fn begin_array<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.0.begin_array(writer)
+    }
+
+    fn end_array<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.0.end_array(writer)
+    }
+
+    fn begin_array_value<W>(&mut self, writer: &mut W, first: bool) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.0.begin_array_value(writer, first)
+    }
+
+    fn end_array_value<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.0.end_array_value(writer)
+    }
+
+    fn begin_object<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.0.begin_object(writer)
+    }
+
+    fn end_object<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.0.end_object(writer)
+    }
+
+    fn begin_object_key<W>(&mut self, writer: &mut W, first: bool) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.0.begin_object_key(writer, first)
+    }
+
+    fn end_object_key<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.0.end_object_key(writer)
+    }
+
+    fn begin_object_value<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.0.begin_object_value(writer)
+    }
+
+    fn end_object_value<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        self.0.end_object_value(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+    use serde_json_bytes::json;
+
+    use super::*;
+
+    fn serialize(mut response: graphql::Response, options: SerializationOptions) -> String {
+        let mut buffer = Vec::new();
+        prepare(&mut response, options);
+        write(&mut buffer, &response, options).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    fn response() -> graphql::Response {
+        graphql::Response::builder()
+            .data(json!({
+                "me": { "name": "Zoë", "nickname": null, "friends": [null, { "name": null }] },
+                "ad": null
+            }))
+            .build()
+    }
+
+    #[test]
+    fn it_serializes_compact_responses_by_default() {
+        assert_eq!(
+            serialize(response(), SerializationOptions::default()),
+            r#"{"data":{"me":{"name":"Zoë","nickname":null,"friends":[null,{"name":null}]},"ad":null}}"#
+        );
+    }
+
+    #[test]
+    fn it_trims_the_null_fields() {
+        let options = SerializationOptions {
+            trim_nulls: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            serialize(response(), options),
+            r#"{"data":{"me":{"name":"Zoë","friends":[null,{}]}}}"#
+        );
+    }
+
+    #[test]
+    fn it_escapes_unicode() {
+        let options = SerializationOptions {
+            escape_unicode: true,
+            trim_nulls: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            serialize(response(), options),
+            r#"{"data":{"me":{"name":"Zo\u00eb","friends":[null,{}]}}}"#
+        );
+
+        let response = graphql::Response::builder()
+            .data(json!({ "emoji": "a🦀b" }))
+            .build();
+        let options = SerializationOptions {
+            escape_unicode: true,
+            pretty: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            serialize(response, options),
+            "{\n  \"data\": {\n    \"emoji\": \"a\\ud83e\\udd80b\"\n  }\n}"
+        );
+    }
+
+    #[test]
+    fn it_lets_clients_opt_in_to_the_null_trimming() {
+        let configuration = ResponseSerialization {
+            trim_nulls_header: Some("apollo-trim-nulls".to_string()),
+            ..Default::default()
+        };
+        let mut headers = HeaderMap::new();
+        assert!(!SerializationOptions::for_request(&configuration, &headers).trim_nulls);
+        headers.insert("apollo-trim-nulls", HeaderValue::from_static("true"));
+        assert!(SerializationOptions::for_request(&configuration, &headers).trim_nulls);
+        assert!(
+            !SerializationOptions::for_request(&ResponseSerialization::default(), &headers)
+                .trim_nulls
+        );
+    }
+}
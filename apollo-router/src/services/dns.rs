@@ -0,0 +1,373 @@
+//! Resolution of the subgraph hosts, with service discovery.
+//!
+//! Hosts are resolved again once the refresh interval elapsed, so that the endpoints added and
+//! removed behind a name, e.g. when a Kubernetes service scales, are picked up without a restart.
+//! Names starting with `_service._proto.` are resolved with their SRV records, which give the
+//! ports and weights of the endpoints too. New connections are opened to the endpoints in turn,
+//! or in proportion to their weights, and to the next endpoint when one can not be reached.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::future::join_all;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use futures::TryFutureExt;
+use http::Uri;
+use hyper::client::HttpConnector;
+use rand::Rng;
+use tokio::net::TcpStream;
+use tower::BoxError;
+use tower::Service;
+use trust_dns_resolver::TokioAsyncResolver;
+
+use crate::configuration::Dns;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Endpoint {
+    addr: SocketAddr,
+    /// Weight of the SRV record of the endpoint, the same for all the addresses of a name.
+    weight: u16,
+}
+
+struct Endpoints {
+    addrs: Arc<Vec<Endpoint>>,
+    resolved_at: Instant,
+    /// Index of the endpoint the next connection is opened to.
+    next: usize,
+}
+
+impl Endpoints {
+    /// The endpoints, starting with the one the next connection is opened to.
+    ///
+    /// Endpoints of equal weights are used in turn. Otherwise they are ordered randomly, in
+    /// proportion to their weights as described in RFC 2782, the endpoints with a zero weight
+    /// coming last.
+    fn rotate(&mut self) -> Vec<SocketAddr> {
+        let mut endpoints = self.addrs.to_vec();
+        let len = endpoints.len();
+        endpoints.rotate_left(self.next % len);
+        self.next = self.next.wrapping_add(1);
+        if endpoints
+            .windows(2)
+            .all(|pair| pair[0].weight == pair[1].weight)
+        {
+            return endpoints.iter().map(|endpoint| endpoint.addr).collect();
+        }
+
+        let mut rng = rand::thread_rng();
+        let mut ordered = Vec::with_capacity(endpoints.len());
+        while !endpoints.is_empty() {
+            let total: u32 = endpoints
+                .iter()
+                .map(|endpoint| u32::from(endpoint.weight))
+                .sum();
+            let mut index = 0;
+            if total > 0 {
+                let mut point = rng.gen_range(0..total);
+                for (i, endpoint) in endpoints.iter().enumerate() {
+                    if point < u32::from(endpoint.weight) {
+                        index = i;
+                        break;
+                    }
+                    point -= u32::from(endpoint.weight);
+                }
+            }
+            ordered.push(endpoints.remove(index).addr);
+        }
+        ordered
+    }
+}
+
+/// Resolver of the subgraph hosts, shared by the subgraph services of a router.
+pub(crate) struct DnsResolver {
+    resolver: TokioAsyncResolver,
+    refresh_interval: Duration,
+    hosts: Mutex<HashMap<String, Endpoints>>,
+}
+
+impl DnsResolver {
+    /// Create a resolver using the name servers of the system.
+    pub(crate) fn new(config: &Dns) -> Result<Self, BoxError> {
+        Ok(Self::with_resolver(
+            TokioAsyncResolver::tokio_from_system_conf()?,
+            config.refresh_interval,
+        ))
+    }
+
+    fn with_resolver(resolver: TokioAsyncResolver, refresh_interval: Duration) -> Self {
+        DnsResolver {
+            resolver,
+            refresh_interval,
+            hosts: Default::default(),
+        }
+    }
+
+    /// The endpoints of a host, starting with the one the next connection is opened to.
+    pub(crate) async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, BoxError> {
+        let previous = {
+            let mut hosts = self.hosts.lock().expect("lock poisoned");
+            match hosts.get_mut(host) {
+                Some(endpoints) if endpoints.resolved_at.elapsed() < self.refresh_interval => {
+                    return Ok(endpoints.rotate());
+                }
+                Some(endpoints) => Some(endpoints.addrs.clone()),
+                None => None,
+            }
+        };
+
+        let addrs = match self.lookup(host, port).await {
+            Ok(addrs) if !addrs.is_empty() => Arc::new(addrs),
+            result => {
+                let error = match result {
+                    Ok(_) => format!("no endpoint found for '{}'", host),
+                    Err(e) => format!("could not resolve '{}': {}", host, e),
+                };
+                // keep using the previous endpoints until the name resolves again
+                match previous {
+                    Some(addrs) => {
+                        tracing::warn!("{}, using the previous endpoints", error);
+                        addrs
+                    }
+                    None => return Err(error.into()),
+                }
+            }
+        };
+
+        let mut hosts = self.hosts.lock().expect("lock poisoned");
+        let endpoints = hosts.entry(host.to_string()).or_insert_with(|| Endpoints {
+            addrs: addrs.clone(),
+            resolved_at: Instant::now(),
+            next: 0,
+        });
+        endpoints.addrs = addrs;
+        endpoints.resolved_at = Instant::now();
+        Ok(endpoints.rotate())
+    }
+
+    async fn lookup(&self, host: &str, port: u16) -> Result<Vec<Endpoint>, BoxError> {
+        if !host.starts_with('_') {
+            let ips = self.resolver.lookup_ip(host).await?;
+            return Ok(ips
+                .iter()
+                .map(|ip| Endpoint {
+                    addr: SocketAddr::new(ip, port),
+                    weight: 0,
+                })
+                .collect());
+        }
+
+        // only the targets with the lowest priority are used, the others being fallbacks
+        let records = self.resolver.srv_lookup(host).await?;
+        let priority = records.iter().map(|record| record.priority()).min();
+        let lookups = records
+            .iter()
+            .filter(|record| Some(record.priority()) == priority)
+            .map(|record| async move {
+                let ips = self
+                    .resolver
+                    .lookup_ip(record.target().clone())
+                    .await
+                    .map_err(|e| {
+                        tracing::warn!("could not resolve '{}': {}", record.target(), e);
+                    })?;
+                Ok::<_, ()>(
+                    ips.iter()
+                        .map(|ip| Endpoint {
+                            addr: SocketAddr::new(ip, record.port()),
+                            weight: record.weight(),
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            });
+        // the targets are resolved concurrently, and those that can't be resolved are skipped
+        Ok(join_all(lookups)
+            .await
+            .into_iter()
+            .flatten()
+            .flatten()
+            .collect())
+    }
+}
+
+/// Connector opening the connections to the subgraphs, to the endpoints found by the resolver
+/// when there is one.
+#[derive(Clone)]
+pub(crate) struct DiscoveryConnector {
+    http: HttpConnector,
+    resolver: Option<Arc<DnsResolver>>,
+}
+
+impl DiscoveryConnector {
+    pub(crate) fn new(resolver: Option<Arc<DnsResolver>>) -> Self {
+        let mut http = HttpConnector::new();
+        // the HTTPS connector wrapping this one handles the TLS layer
+        http.enforce_http(false);
+        DiscoveryConnector { http, resolver }
+    }
+}
+
+impl Service<Uri> for DiscoveryConnector {
+    type Response = TcpStream;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<TcpStream, BoxError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let mut http = self.http.clone();
+        let resolver = match &self.resolver {
+            Some(resolver) => resolver.clone(),
+            None => return http.call(uri).map_err(Into::into).boxed(),
+        };
+
+        async move {
+            let host = uri.host().ok_or("the subgraph URL has no host")?;
+            let port = uri
+                .port_u16()
+                .unwrap_or(if uri.scheme_str() == Some("https") {
+                    443
+                } else {
+                    80
+                });
+
+            let mut last_error: Option<BoxError> = None;
+            for addr in resolver.resolve(host, port).await? {
+                match http.call(Uri::from_str(&format!("http://{}", addr))?).await {
+                    Ok(stream) => return Ok(stream),
+                    Err(e) => {
+                        tracing::debug!("could not connect to {} at {}: {}", host, addr, e);
+                        last_error = Some(e.into());
+                    }
+                }
+            }
+            Err(last_error.unwrap_or_else(|| format!("no endpoint found for '{}'", host).into()))
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn resolver(refresh_interval: Duration) -> DnsResolver {
+        DnsResolver::with_resolver(
+            TokioAsyncResolver::tokio(Default::default(), Default::default()).unwrap(),
+            refresh_interval,
+        )
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        SocketAddr::from_str(s).unwrap()
+    }
+
+    fn endpoints(addrs: &[(SocketAddr, u16)]) -> Endpoints {
+        Endpoints {
+            addrs: Arc::new(
+                addrs
+                    .iter()
+                    .map(|(addr, weight)| Endpoint {
+                        addr: *addr,
+                        weight: *weight,
+                    })
+                    .collect(),
+            ),
+            resolved_at: Instant::now(),
+            next: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn it_opens_connections_to_the_endpoints_in_turn() {
+        let resolver = resolver(Duration::from_secs(60));
+        resolver.hosts.lock().unwrap().insert(
+            "products".to_string(),
+            endpoints(&[(addr("10.0.0.1:4001"), 0), (addr("10.0.0.2:4001"), 0)]),
+        );
+
+        assert_eq!(
+            resolver.resolve("products", 80).await.unwrap(),
+            vec![addr("10.0.0.1:4001"), addr("10.0.0.2:4001")]
+        );
+        assert_eq!(
+            resolver.resolve("products", 80).await.unwrap(),
+            vec![addr("10.0.0.2:4001"), addr("10.0.0.1:4001")]
+        );
+    }
+
+    #[tokio::test]
+    async fn it_opens_connections_to_the_endpoints_according_to_their_weights() {
+        let resolver = resolver(Duration::from_secs(60));
+        resolver.hosts.lock().unwrap().insert(
+            "_graphql._tcp.products".to_string(),
+            endpoints(&[
+                (addr("10.0.0.1:4001"), 0),
+                (addr("10.0.0.2:4001"), 1),
+                (addr("10.0.0.3:4001"), 3),
+            ]),
+        );
+
+        let mut first = HashMap::new();
+        for _ in 0..400 {
+            let addrs = resolver
+                .resolve("_graphql._tcp.products", 80)
+                .await
+                .unwrap();
+            // endpoints with a zero weight are only used when the others can't be reached
+            assert_eq!(addrs.len(), 3);
+            assert_eq!(addrs[2], addr("10.0.0.1:4001"));
+            *first.entry(addrs[0]).or_insert(0) += 1;
+        }
+        assert!(first[&addr("10.0.0.3:4001")] > first[&addr("10.0.0.2:4001")]);
+    }
+
+    #[tokio::test]
+    async fn it_resolves_hosts_again_after_the_refresh_interval() {
+        let resolver = resolver(Duration::ZERO);
+        resolver.hosts.lock().unwrap().insert(
+            "127.0.0.1".to_string(),
+            endpoints(&[(addr("10.0.0.1:4001"), 0)]),
+        );
+
+        assert_eq!(
+            resolver.resolve("127.0.0.1", 4001).await.unwrap(),
+            vec![addr("127.0.0.1:4001")]
+        );
+    }
+
+    #[tokio::test]
+    async fn it_connects_to_the_next_endpoint_on_failure() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let reachable = listener.local_addr().unwrap();
+        // nothing listens on this port once the listener is dropped
+        let unreachable = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let resolver = resolver(Duration::from_secs(60));
+        resolver.hosts.lock().unwrap().insert(
+            "products".to_string(),
+            endpoints(&[(unreachable, 0), (reachable, 0)]),
+        );
+
+        let stream = DiscoveryConnector::new(Some(Arc::new(resolver)))
+            .call(Uri::from_static("http://products/graphql"))
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), reachable);
+    }
+}
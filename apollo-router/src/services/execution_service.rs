@@ -8,6 +8,7 @@ use futures::future::BoxFuture;
 use futures::stream::once;
 use futures::stream::BoxStream;
 use futures::StreamExt;
+use tokio_stream::wrappers::ReceiverStream;
 use tower::BoxError;
 use tower::ServiceBuilder;
 use tower::ServiceExt;
@@ -15,10 +16,13 @@ use tower_service::Service;
 use tracing::Instrument;
 
 use super::layers::allow_only_http_post_mutations::AllowOnlyHttpPostMutationsLayer;
+use super::layers::query_plan_checks::QueryPlanChecksLayer;
 use super::new_service::NewService;
 use super::subgraph_service::SubgraphServiceFactory;
 use super::Plugins;
+use crate::canary;
 use crate::graphql::Response;
+use crate::plugins::telemetry::Queued;
 use crate::services::execution;
 use crate::ExecutionRequest;
 use crate::ExecutionResponse;
@@ -50,12 +54,14 @@ where
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, req: ExecutionRequest) -> Self::Future {
+    fn call(&mut self, mut req: ExecutionRequest) -> Self::Future {
+        // the request is no longer queued once it reaches the execution service
+        req.originating_request.extensions_mut().remove::<Queued>();
         let this = self.clone();
         let fut = async move {
             let context = req.context;
             let ctx = context.clone();
-            let (sender, receiver) = futures::channel::mpsc::channel(10);
+            let (sender, receiver) = tokio::sync::mpsc::channel(10);
 
             let first = req
                 .query_plan
@@ -68,7 +74,7 @@ where
                 )
                 .await;
 
-            let rest = receiver;
+            let rest = ReceiverStream::new(receiver);
 
             let stream = once(ready(first)).chain(rest).boxed();
 
@@ -99,6 +105,8 @@ pub(crate) struct ExecutionCreator<SF: SubgraphServiceFactory> {
     pub(crate) schema: Arc<Schema>,
     pub(crate) plugins: Arc<Plugins>,
     pub(crate) subgraph_creator: Arc<SF>,
+    /// Whether the services handle the canary requests, which bypass some of the plugins.
+    pub(crate) canary: bool,
 }
 
 impl<SF> NewService<ExecutionRequest> for ExecutionCreator<SF>
@@ -110,15 +118,20 @@ where
     fn new_service(&self) -> Self::Service {
         ServiceBuilder::new()
             .layer(AllowOnlyHttpPostMutationsLayer::default())
+            .layer(QueryPlanChecksLayer::new(self.plugins.clone()))
             .service(
-                self.plugins.iter().rev().fold(
-                    crate::services::execution_service::ExecutionService {
-                        schema: self.schema.clone(),
-                        subgraph_creator: self.subgraph_creator.clone(),
-                    }
-                    .boxed(),
-                    |acc, (_, e)| e.execution_service(acc),
-                ),
+                self.plugins
+                    .iter()
+                    .rev()
+                    .filter(|(name, _)| !self.canary || !canary::bypasses(name))
+                    .fold(
+                        crate::services::execution_service::ExecutionService {
+                            schema: self.schema.clone(),
+                            subgraph_creator: self.subgraph_creator.clone(),
+                        }
+                        .boxed(),
+                        |acc, (_, e)| e.execution_service(acc),
+                    ),
             )
             .boxed()
     }
@@ -12,6 +12,7 @@ use tower::Layer;
 use tower::Service;
 
 use crate::graphql::Error;
+use crate::graphql::ErrorCode;
 use crate::json_ext::Object;
 use crate::layers::sync_checkpoint::CheckpointService;
 use crate::ExecutionRequest;
@@ -34,12 +35,10 @@ where
                 if req.originating_request.method() != Method::POST
                     && req.query_plan.contains_mutations()
                 {
-                    let errors = vec![Error {
-                        message: "Mutations can only be sent over HTTP POST".to_string(),
-                        locations: Default::default(),
-                        path: Default::default(),
-                        extensions: Default::default(),
-                    }];
+                    let errors = vec![Error::builder()
+                        .message("Mutations can only be sent over HTTP POST")
+                        .code(ErrorCode::BadRequest)
+                        .build()];
                     let mut res = ExecutionResponse::builder()
                         .errors(errors)
                         .extensions(Object::default())
@@ -146,12 +145,10 @@ mod forbid_http_get_mutations_tests {
 
     #[tokio::test]
     async fn it_doesnt_let_non_http_post_mutations_pass_through() {
-        let expected_error = Error {
-            message: "Mutations can only be sent over HTTP POST".to_string(),
-            locations: Default::default(),
-            path: Default::default(),
-            extensions: Default::default(),
-        };
+        let expected_error = Error::builder()
+            .message("Mutations can only be sent over HTTP POST")
+            .code(ErrorCode::BadRequest)
+            .build();
         let expected_status = StatusCode::METHOD_NOT_ALLOWED;
         let expected_allow_header = "POST";
 
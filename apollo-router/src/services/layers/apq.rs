@@ -3,10 +3,13 @@
 //!  For more information on APQ see:
 //!  <https://www.apollographql.com/docs/apollo-server/performance/apq/>
 
+use std::num::NonZeroUsize;
 use std::ops::ControlFlow;
+use std::sync::Arc;
+use std::time::Duration;
 
 use futures::future::BoxFuture;
-use serde::Deserialize;
+use http::StatusCode;
 use serde_json_bytes::json;
 use serde_json_bytes::Value;
 use sha2::Digest;
@@ -16,30 +19,112 @@ use tower::BoxError;
 use tower::Layer;
 use tower::Service;
 
+use crate::cache::partitioned::PartitionedCache;
+use crate::cache::partitioned::TENANT_CONTEXT_KEY;
+use crate::cache::storage::KeyValueStorage;
+use crate::cache::CacheStatistics;
 use crate::cache::DeduplicatingCache;
+use crate::configuration::PersistedQueryErrors;
+use crate::graphql;
+use crate::graphql::ErrorCode;
 use crate::layers::async_checkpoint::AsyncCheckpointService;
-use crate::layers::DEFAULT_BUFFER_SIZE;
+use crate::panics::CatchPanic;
+use crate::panics::CatchPanicBufferLayer;
+use crate::services::layers::client_identification::ClientInfo;
+use crate::services::layers::persisted_queries::OperationRef;
+use crate::services::layers::persisted_queries::PersistedQueryRegistry;
 use crate::SupergraphRequest;
 use crate::SupergraphResponse;
 
-/// A persisted query.
-#[derive(Deserialize, Clone, Debug)]
-struct PersistedQuery {
-    #[allow(unused)]
-    version: u8,
-    #[serde(rename = "sha256Hash")]
-    sha256hash: String,
-}
+/// The request extension holding the persisted query, unless configured otherwise.
+pub(crate) const DEFAULT_PERSISTED_QUERY_EXTENSION: &str = graphql::PERSISTED_QUERY_EXTENSION;
+
+/// The version of the persisted queries protocol supported by the router.
+const SUPPORTED_VERSION: u64 = 1;
 
 /// [`Layer`] for APQ implementation.
 #[derive(Clone)]
 pub(crate) struct APQLayer {
-    cache: DeduplicatingCache<Vec<u8>, String>,
+    cache: PartitionedCache<Vec<u8>, String>,
+    storage: Option<Arc<dyn KeyValueStorage>>,
+    /// Time to live of the queries in the storage.
+    storage_ttl: Duration,
+    persisted_queries: PersistedQueryRegistry,
+    extension: Arc<String>,
+    errors: PersistedQueryErrors,
+    safelist: bool,
 }
 
 impl APQLayer {
     pub(crate) fn with_cache(cache: DeduplicatingCache<Vec<u8>, String>) -> Self {
-        Self { cache }
+        Self {
+            cache: cache.into(),
+            storage: None,
+            storage_ttl: Duration::ZERO,
+            persisted_queries: Default::default(),
+            extension: Arc::new(DEFAULT_PERSISTED_QUERY_EXTENSION.to_string()),
+            errors: PersistedQueryErrors::Apollo,
+            safelist: false,
+        }
+    }
+
+    /// Give each tenant its own partition of the cache.
+    pub(crate) async fn with_tenants(mut self, max_tenants: NonZeroUsize) -> Self {
+        self.cache = self.cache.with_tenants(max_tenants).await;
+        self
+    }
+
+    /// Share the registered queries with the other routers through this storage, which evicts
+    /// them after their time to live.
+    pub(crate) fn with_storage(mut self, storage: Arc<dyn KeyValueStorage>, ttl: Duration) -> Self {
+        self.storage = Some(storage);
+        self.storage_ttl = ttl;
+        self
+    }
+
+    /// Answer with errors in this format.
+    pub(crate) fn with_errors(mut self, errors: PersistedQueryErrors) -> Self {
+        self.errors = errors;
+        self
+    }
+
+    /// Reject the operations which are not in the active persisted query manifest.
+    pub(crate) fn with_safelist(mut self, safelist: bool) -> Self {
+        self.safelist = safelist;
+        self
+    }
+
+    /// Read the persisted queries from this request extension instead of `persistedQuery`, e.g.
+    /// for the clients not following the Apollo convention.
+    pub(crate) fn with_extension(mut self, extension: String) -> Self {
+        self.extension = Arc::new(extension);
+        self
+    }
+
+    /// Resolve the persisted queries of the active manifest of this registry too.
+    pub(crate) fn with_persisted_queries(
+        mut self,
+        persisted_queries: PersistedQueryRegistry,
+    ) -> Self {
+        self.persisted_queries = persisted_queries;
+        self
+    }
+
+    pub(crate) fn cache(&self) -> Arc<dyn CacheStatistics> {
+        Arc::new(self.cache.clone())
+    }
+
+    /// Whether a query is registered under this SHA-256 hash, in hexadecimal, either in the active
+    /// persisted query manifest or in the cache of any tenant.
+    pub(crate) async fn is_registered(&self, hash: &str) -> bool {
+        let hash = hash.to_lowercase();
+        if self.persisted_queries.get(&hash).is_some() {
+            return true;
+        }
+        match hex::decode(hash.as_bytes()) {
+            Ok(hash) => self.cache.contains_key(&hash).await,
+            Err(_) => false,
+        }
     }
 }
 
@@ -49,7 +134,7 @@ where
     <S as Service<SupergraphRequest>>::Future: Send + 'static,
 {
     type Service = AsyncCheckpointService<
-        Buffer<S, SupergraphRequest>,
+        Buffer<CatchPanic<S>, SupergraphRequest>,
         BoxFuture<
             'static,
             Result<
@@ -62,29 +147,115 @@ where
 
     fn layer(&self, service: S) -> Self::Service {
         let cache = self.cache.clone();
+        let storage = self.storage.clone();
+        let storage_ttl = self.storage_ttl;
+        let persisted_queries = self.persisted_queries.clone();
+        let extension = self.extension.clone();
+        let errors = self.errors;
+        let safelist = self.safelist;
         AsyncCheckpointService::new(
             move |mut req| {
                 let cache = cache.clone();
+                let storage = storage.clone();
+                let persisted_queries = persisted_queries.clone();
+                let extension = extension.clone();
                 Box::pin(async move {
-                    let maybe_query_hash: Option<Vec<u8>> = req
+                    let tenant: Option<String> = req.context.get(TENANT_CONTEXT_KEY).ok().flatten();
+                    let cache = cache.partition(tenant.as_deref()).await;
+                    let maybe_persisted_query = req
                         .originating_request
                         .body()
                         .extensions
-                        .get("persistedQuery")
-                        .and_then(|value| {
-                            serde_json_bytes::from_value::<PersistedQuery>(value.clone()).ok()
-                        })
-                        .and_then(|persisted_query| {
-                            hex::decode(persisted_query.sha256hash.as_bytes()).ok()
+                        .get(extension.as_str())
+                        .and_then(graphql::PersistedQuery::from_extension);
+                    if let Some(persisted_query) = &maybe_persisted_query {
+                        if persisted_query.version != SUPPORTED_VERSION {
+                            tracing::trace!("apq: unsupported version {}", persisted_query.version);
+                            let res = SupergraphResponse::builder()
+                                .data(Value::default())
+                                .errors(vec![persisted_query_error(
+                                    errors,
+                                    "PersistedQueryNotSupported",
+                                    ErrorCode::PersistedQueryNotSupported,
+                                )])
+                                .context(req.context)
+                                .build()
+                                .expect("response is valid");
+                            return Ok(ControlFlow::Break(res));
+                        }
+                    }
+                    let maybe_query_hash: Option<Vec<u8>> =
+                        maybe_persisted_query.and_then(|persisted_query| {
+                            hex::decode(persisted_query.sha256_hash.as_bytes()).ok()
                         });
 
                     let body_query = req.originating_request.body().query.clone();
+                    // the body is hashed once, for both the client bindings and the safelist
+                    let body_id = body_query
+                        .as_ref()
+                        .filter(|_| safelist || persisted_queries.has_client_bindings())
+                        .map(|query| hex::encode(Sha256::digest(query.as_bytes())));
+
+                    if !client_allowed(
+                        &persisted_queries,
+                        &req,
+                        body_id.as_deref(),
+                        maybe_query_hash.as_deref(),
+                    ) {
+                        tracing::trace!("apq: query not allowed for the client");
+                        let res = SupergraphResponse::builder()
+                            .data(Value::default())
+                            .errors(vec![persisted_query_error(
+                                errors,
+                                "PersistedQueryClientNotAllowed",
+                                ErrorCode::PersistedQueryClientNotAllowed,
+                            )])
+                            .status_code(StatusCode::FORBIDDEN)
+                            .context(req.context)
+                            .build()
+                            .expect("response is valid");
+                        return Ok(ControlFlow::Break(res));
+                    }
+
+                    if safelist
+                        && !safelisted(
+                            &persisted_queries,
+                            body_id.as_deref(),
+                            maybe_query_hash.as_deref(),
+                        )
+                    {
+                        tracing::trace!("apq: query not in the safelist");
+                        let res = SupergraphResponse::builder()
+                            .data(Value::default())
+                            .errors(vec![persisted_query_error(
+                                errors,
+                                "PersistedQueryNotInSafelist",
+                                ErrorCode::PersistedQueryNotInSafelist,
+                            )])
+                            .status_code(StatusCode::FORBIDDEN)
+                            .context(req.context)
+                            .build()
+                            .expect("response is valid");
+                        return Ok(ControlFlow::Break(res));
+                    }
 
                     match (maybe_query_hash, body_query) {
                         (Some(query_hash), Some(query)) => {
                             if query_matches_hash(query.as_str(), query_hash.as_slice()) {
                                 tracing::trace!("apq: cache insert");
                                 let _ = req.context.insert("persisted_query_hit", false);
+                                if let Some(storage) = &storage {
+                                    if let Err(e) = storage
+                                        .insert(
+                                            storage_key(tenant.as_deref(), &query_hash),
+                                            query.clone().into_bytes(),
+                                            Some(storage_ttl),
+                                        )
+                                        .await
+                                    {
+                                        tracing::warn!("apq: couldn't store the query: {}", e);
+                                    }
+                                }
                                 cache.insert(query_hash, query).await;
                             } else {
                                 tracing::warn!(
@@ -94,30 +265,32 @@ where
                             Ok(ControlFlow::Continue(req))
                         }
                         (Some(apq_hash), _) => {
-                            if let Ok(cached_query) = cache.get(&apq_hash).await.get().await {
+                            if let Some(query) = persisted_queries.get(&hex::encode(&apq_hash)) {
+                                tracing::trace!("apq: persisted query manifest hit");
+                                let _ = req.context.insert("persisted_query_hit", true);
+                                req.originating_request.body_mut().query = Some(query);
+                                Ok(ControlFlow::Continue(req))
+                            } else if let Some(cached_query) = registered_query(
+                                &cache,
+                                storage.as_deref(),
+                                tenant.as_deref(),
+                                &apq_hash,
+                            )
+                            .await
+                            {
                                 let _ = req.context.insert("persisted_query_hit", true);
                                 tracing::trace!("apq: cache hit");
                                 req.originating_request.body_mut().query = Some(cached_query);
                                 Ok(ControlFlow::Continue(req))
                             } else {
                                 tracing::trace!("apq: cache miss");
-                                let errors = vec![crate::error::Error {
-                                    message: "PersistedQueryNotFound".to_string(),
-                                    locations: Default::default(),
-                                    path: Default::default(),
-                                    extensions: serde_json_bytes::from_value(json!({
-                                          "code": "PERSISTED_QUERY_NOT_FOUND",
-                                          "exception": {
-                                          "stacktrace": [
-                                              "PersistedQueryNotFoundError: PersistedQueryNotFound",
-                                          ],
-                                      },
-                                    }))
-                                    .unwrap(),
-                                }];
                                 let res = SupergraphResponse::builder()
                                     .data(Value::default())
-                                    .errors(errors)
+                                    .errors(vec![persisted_query_error(
+                                        errors,
+                                        "PersistedQueryNotFound",
+                                        ErrorCode::PersistedQueryNotFound,
+                                    )])
                                     .context(req.context)
                                     .build()
                                     .expect("response is valid");
@@ -139,11 +312,109 @@ where
                         >,
                     >
             },
-            Buffer::new(service, DEFAULT_BUFFER_SIZE),
+            CatchPanicBufferLayer::new().layer(service),
         )
     }
 }
 
+/// The key of a query in the storage shared with the other routers. The query of a hash does not
+/// depend on the schema, so the routers running other schemas share it.
+fn storage_key(tenant: Option<&str>, hash: &[u8]) -> String {
+    match tenant {
+        Some(tenant) => format!("apollo_router:apq:{}:{}", tenant, hex::encode(hash)),
+        None => format!("apollo_router:apq:{}", hex::encode(hash)),
+    }
+}
+
+/// The query registered under a hash, in the cache or else in the storage shared with the other
+/// routers.
+async fn registered_query(
+    cache: &DeduplicatingCache<Vec<u8>, String>,
+    storage: Option<&dyn KeyValueStorage>,
+    tenant: Option<&str>,
+    hash: &[u8],
+) -> Option<String> {
+    if let Ok(query) = cache.get(&hash.to_vec()).await.get().await {
+        return Some(query);
+    }
+    let stored = match storage?.get(&[storage_key(tenant, hash)]).await {
+        Ok(values) => values.into_iter().next().flatten()?,
+        Err(e) => {
+            tracing::warn!("apq: couldn't read the stored query: {}", e);
+            return None;
+        }
+    };
+    let query = String::from_utf8(stored).ok()?;
+    // the queries registered through other routers are cached too
+    cache.insert(hash.to_vec(), query.clone()).await;
+    Some(query)
+}
+
+/// Whether the client of a request can execute its query, when the persisted queries are bound
+/// to clients. The body of the query is compared once normalized, or else the hash it was sent
+/// with.
+fn client_allowed(
+    persisted_queries: &PersistedQueryRegistry,
+    req: &SupergraphRequest,
+    body_id: Option<&str>,
+    query_hash: Option<&[u8]>,
+) -> bool {
+    if !persisted_queries.has_client_bindings() {
+        return true;
+    }
+    let id;
+    let operation = match (&req.originating_request.body().query, body_id, query_hash) {
+        (Some(body), Some(id), _) => OperationRef::Body { id, body },
+        (_, _, Some(hash)) => {
+            id = hex::encode(hash);
+            OperationRef::Id(&id)
+        }
+        _ => return true,
+    };
+    // any client can claim a name in its headers, so only the verified ones are bound
+    let client = ClientInfo::from_context(&req.context);
+    if !client.verified {
+        return persisted_queries.allows_client(operation, None, None);
+    }
+    persisted_queries.allows_client(operation, client.name.as_deref(), client.version.as_deref())
+}
+
+/// Whether the query of a request is in the active persisted query manifest: the hash of its
+/// body, or else the hash it was sent with. The requests without a query are left to the next
+/// layers.
+fn safelisted(
+    persisted_queries: &PersistedQueryRegistry,
+    body_id: Option<&str>,
+    query_hash: Option<&[u8]>,
+) -> bool {
+    match (body_id, query_hash) {
+        (Some(id), _) => persisted_queries.get(id).is_some(),
+        (None, Some(hash)) => persisted_queries.get(&hex::encode(hash)).is_some(),
+        (None, None) => true,
+    }
+}
+
+/// An error of the persisted queries protocol.
+fn persisted_query_error(
+    format: PersistedQueryErrors,
+    message: &str,
+    code: ErrorCode,
+) -> crate::error::Error {
+    let extensions = match format {
+        PersistedQueryErrors::Apollo => json!({
+            "exception": {
+                "stacktrace": [format!("{}Error: {}", message, message)],
+            },
+        }),
+        PersistedQueryErrors::Minimal => json!({}),
+    };
+    crate::error::Error::builder()
+        .message(message)
+        .code(code)
+        .extensions(serde_json_bytes::from_value(extensions).unwrap())
+        .build()
+}
+
 fn query_matches_hash(query: &str, hash: &[u8]) -> bool {
     let mut digest = Sha256::new();
     digest.update(query.as_bytes());
@@ -161,6 +432,12 @@ mod apq_tests {
     use crate::error::Error;
     use crate::graphql::Response;
     use crate::plugin::test::MockSupergraphService;
+    use crate::services::layers::client_identification::CLIENT_NAME_CONTEXT_KEY;
+    use crate::services::layers::client_identification::CLIENT_VERIFIED_CONTEXT_KEY;
+    use crate::services::layers::client_identification::CLIENT_VERSION_CONTEXT_KEY;
+    use crate::services::layers::persisted_queries::ClientBinding;
+    use crate::services::layers::persisted_queries::Manifest;
+    use crate::services::layers::persisted_queries::ManifestOperation;
     use crate::Context;
 
     #[tokio::test]
@@ -195,10 +472,10 @@ mod apq_tests {
 
             let as_json = body.extensions.get("persistedQuery").unwrap();
 
-            let persisted_query: PersistedQuery =
+            let persisted_query: graphql::PersistedQuery =
                 serde_json_bytes::from_value(as_json.clone()).unwrap();
 
-            assert_eq!(persisted_query.sha256hash, hash2);
+            assert_eq!(persisted_query.sha256_hash, hash2);
 
             assert!(body.query.is_some());
 
@@ -215,10 +492,10 @@ mod apq_tests {
                 let body = req.originating_request.body();
                 let as_json = body.extensions.get("persistedQuery").unwrap();
 
-                let persisted_query: PersistedQuery =
+                let persisted_query: graphql::PersistedQuery =
                     serde_json_bytes::from_value(as_json.clone()).unwrap();
 
-                assert_eq!(persisted_query.sha256hash, hash3);
+                assert_eq!(persisted_query.sha256_hash, hash3);
 
                 assert!(body.query.is_some());
 
@@ -306,10 +583,10 @@ mod apq_tests {
             let body = req.originating_request.body();
             let as_json = body.extensions.get("persistedQuery").unwrap();
 
-            let persisted_query: PersistedQuery =
+            let persisted_query: graphql::PersistedQuery =
                 serde_json_bytes::from_value(as_json.clone()).unwrap();
 
-            assert_eq!(persisted_query.sha256hash, hash2);
+            assert_eq!(persisted_query.sha256_hash, hash2);
 
             assert!(body.query.is_some());
 
@@ -384,6 +661,475 @@ mod apq_tests {
         assert_error_matches(&expected_apq_miss_error, second_apq_error);
     }
 
+    #[tokio::test]
+    async fn it_resolves_persisted_queries_of_the_active_manifest() {
+        let mut mock_service = MockSupergraphService::new();
+        mock_service.expect_call().times(1).returning(move |req| {
+            assert_eq!(
+                req.originating_request.body().query.as_deref(),
+                Some("{__typename}")
+            );
+            Ok(SupergraphResponse::fake_builder()
+                .context(req.context)
+                .build()
+                .expect("expecting valid request"))
+        });
+
+        let persisted_queries = PersistedQueryRegistry::default();
+        persisted_queries
+            .register(Manifest {
+                version: "v1".to_string(),
+                operations: vec![ManifestOperation {
+                    id: "ecf4edb46db40b5132295c0291d62fb65d6759a9eedfa4d5d612dd5ec54a6b38"
+                        .to_string(),
+                    body: "{__typename}".to_string(),
+                    clients: Vec::new(),
+                }],
+                activate: true,
+            })
+            .unwrap();
+        let apq = APQLayer::with_cache(DeduplicatingCache::new().await)
+            .with_persisted_queries(persisted_queries);
+        let mut service_stack = apq.layer(mock_service);
+
+        let hash_only = SupergraphRequest::fake_builder()
+            .extension(
+                "persistedQuery",
+                json!({
+                    "version" : 1,
+                    "sha256Hash" : "ecf4edb46db40b5132295c0291d62fb65d6759a9eedfa4d5d612dd5ec54a6b38"
+                }),
+            )
+            .build()
+            .expect("expecting valid request");
+
+        let response = service_stack
+            .ready()
+            .await
+            .unwrap()
+            .call(hash_only)
+            .await
+            .unwrap();
+        assert_eq!(
+            response
+                .context
+                .get::<_, bool>("persisted_query_hit")
+                .unwrap(),
+            Some(true)
+        );
+    }
+
+    #[tokio::test]
+    async fn it_rejects_queries_bound_to_other_clients() {
+        let mut mock_service = MockSupergraphService::new();
+        mock_service.expect_call().times(1).returning(move |req| {
+            Ok(SupergraphResponse::fake_builder()
+                .context(req.context)
+                .build()
+                .expect("expecting valid request"))
+        });
+
+        let persisted_queries = PersistedQueryRegistry::default();
+        persisted_queries
+            .register(Manifest {
+                version: "v1".to_string(),
+                operations: vec![ManifestOperation {
+                    id: "ecf4edb46db40b5132295c0291d62fb65d6759a9eedfa4d5d612dd5ec54a6b38"
+                        .to_string(),
+                    body: "{__typename}".to_string(),
+                    clients: vec![ClientBinding {
+                        name: "partner".to_string(),
+                        min_version: Some("1.2".to_string()),
+                        max_version: None,
+                    }],
+                }],
+                activate: true,
+            })
+            .unwrap();
+        let apq = APQLayer::with_cache(DeduplicatingCache::new().await)
+            .with_persisted_queries(persisted_queries);
+        let mut service_stack = apq.layer(mock_service);
+
+        let request = |client: &str, version: &str, verified: bool| {
+            let request = SupergraphRequest::fake_builder()
+                .extension(
+                    "persistedQuery",
+                    json!({
+                        "version" : 1,
+                        "sha256Hash" : "ecf4edb46db40b5132295c0291d62fb65d6759a9eedfa4d5d612dd5ec54a6b38"
+                    }),
+                )
+                .build()
+                .expect("expecting valid request");
+            request
+                .context
+                .insert(CLIENT_NAME_CONTEXT_KEY, client.to_string())
+                .unwrap();
+            request
+                .context
+                .insert(CLIENT_VERSION_CONTEXT_KEY, version.to_string())
+                .unwrap();
+            request
+                .context
+                .insert(CLIENT_VERIFIED_CONTEXT_KEY, verified)
+                .unwrap();
+            request
+        };
+
+        let response = service_stack
+            .ready()
+            .await
+            .unwrap()
+            .call(request("partner", "1.10.0", true))
+            .await
+            .unwrap();
+        assert_eq!(response.response.status(), StatusCode::OK);
+
+        for request in [
+            request("partner", "1.1.9", true),
+            request("web", "2.0.0", true),
+            // the name of the client must be verified
+            request("partner", "1.10.0", false),
+            // the body of a bound query can not be sent instead of its hash
+            SupergraphRequest::fake_builder()
+                .query("{__typename}".to_string())
+                .build()
+                .expect("expecting valid request"),
+        ] {
+            let mut response = service_stack
+                .ready()
+                .await
+                .unwrap()
+                .call(request)
+                .await
+                .unwrap();
+            assert_eq!(response.response.status(), StatusCode::FORBIDDEN);
+            assert_eq!(
+                response.next_response().await.unwrap().errors[0]
+                    .extensions
+                    .get("code"),
+                Some(&Value::from("PERSISTED_QUERY_CLIENT_NOT_ALLOWED"))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn it_rejects_queries_outside_of_the_safelist() {
+        let mut mock_service = MockSupergraphService::new();
+        mock_service.expect_call().times(2).returning(move |req| {
+            Ok(SupergraphResponse::fake_builder()
+                .context(req.context)
+                .build()
+                .expect("expecting valid request"))
+        });
+
+        let persisted_queries = PersistedQueryRegistry::default();
+        persisted_queries
+            .register(Manifest {
+                version: "v1".to_string(),
+                operations: vec![ManifestOperation {
+                    id: "ecf4edb46db40b5132295c0291d62fb65d6759a9eedfa4d5d612dd5ec54a6b38"
+                        .to_string(),
+                    body: "{__typename}".to_string(),
+                    clients: Vec::new(),
+                }],
+                activate: true,
+            })
+            .unwrap();
+        let apq = APQLayer::with_cache(DeduplicatingCache::new().await)
+            .with_persisted_queries(persisted_queries)
+            .with_safelist(true);
+        let mut service_stack = apq.layer(mock_service);
+
+        let hash_only = |hash: &str| {
+            SupergraphRequest::fake_builder()
+                .extension(
+                    "persistedQuery",
+                    json!({
+                        "version" : 1,
+                        "sha256Hash" : hash
+                    }),
+                )
+                .build()
+                .expect("expecting valid request")
+        };
+        let with_query = |query: &str| {
+            SupergraphRequest::fake_builder()
+                .query(query.to_string())
+                .build()
+                .expect("expecting valid request")
+        };
+
+        for request in [
+            hash_only("ecf4edb46db40b5132295c0291d62fb65d6759a9eedfa4d5d612dd5ec54a6b38"),
+            with_query("{__typename}"),
+        ] {
+            let response = service_stack
+                .ready()
+                .await
+                .unwrap()
+                .call(request)
+                .await
+                .unwrap();
+            assert_eq!(response.response.status(), StatusCode::OK);
+        }
+
+        for request in [
+            hash_only("9d1474aa4d2dbb2ceb2ba2c7ef1c8c9cc9a4e6e1e3c3d0f2f6b1d9e2a6c1b4f0"),
+            with_query("{ me { id } }"),
+        ] {
+            let mut response = service_stack
+                .ready()
+                .await
+                .unwrap()
+                .call(request)
+                .await
+                .unwrap();
+            assert_eq!(response.response.status(), StatusCode::FORBIDDEN);
+            assert_eq!(
+                response.next_response().await.unwrap().errors[0]
+                    .extensions
+                    .get("code"),
+                Some(&Value::from("PERSISTED_QUERY_NOT_IN_SAFELIST"))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn it_checks_whether_queries_are_registered() {
+        let persisted_queries = PersistedQueryRegistry::default();
+        persisted_queries
+            .register(Manifest {
+                version: "v1".to_string(),
+                operations: vec![ManifestOperation {
+                    id: "ecf4edb46db40b5132295c0291d62fb65d6759a9eedfa4d5d612dd5ec54a6b38"
+                        .to_string(),
+                    body: "{__typename}".to_string(),
+                    clients: Vec::new(),
+                }],
+                activate: true,
+            })
+            .unwrap();
+        let apq = APQLayer::with_cache(DeduplicatingCache::new().await)
+            .with_persisted_queries(persisted_queries)
+            .with_tenants(NonZeroUsize::new(1).unwrap())
+            .await;
+        let cached = "b58723c4fd7ce18043ae53635b304ba6cee765a67009645b04ca01e80ce1c065";
+        apq.cache
+            .partition(Some("tenant"))
+            .await
+            .insert(hex::decode(cached).unwrap(), "{me{name}}".to_string())
+            .await;
+
+        assert!(
+            apq.is_registered("ECF4EDB46DB40B5132295C0291D62FB65D6759A9EEDFA4D5D612DD5EC54A6B38")
+                .await
+        );
+        assert!(apq.is_registered(cached).await);
+        assert!(
+            !apq.is_registered("0000000000000000000000000000000000000000000000000000000000000000")
+                .await
+        );
+        assert!(!apq.is_registered("not a hash").await);
+    }
+
+    #[tokio::test]
+    async fn it_rejects_unsupported_versions() {
+        let mut mock_service = MockSupergraphService::new();
+        mock_service.expect_call().times(0);
+        let apq = APQLayer::with_cache(DeduplicatingCache::new().await);
+        let mut service_stack = apq.layer(mock_service);
+
+        let request = SupergraphRequest::fake_builder()
+            .query("{__typename}")
+            .extension(
+                "persistedQuery",
+                json!({
+                    "version" : 2,
+                    "sha256Hash" : "ecf4edb46db40b5132295c0291d62fb65d6759a9eedfa4d5d612dd5ec54a6b38"
+                }),
+            )
+            .build()
+            .expect("expecting valid request");
+
+        let response = service_stack
+            .ready()
+            .await
+            .unwrap()
+            .call(request)
+            .await
+            .unwrap()
+            .next_response()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.errors[0].extensions.get("code"),
+            Some(&json!("PERSISTED_QUERY_NOT_SUPPORTED"))
+        );
+    }
+
+    #[tokio::test]
+    async fn it_answers_minimal_errors() {
+        let mut mock_service = MockSupergraphService::new();
+        mock_service.expect_call().times(0);
+        let apq = APQLayer::with_cache(DeduplicatingCache::new().await)
+            .with_errors(PersistedQueryErrors::Minimal);
+        let mut service_stack = apq.layer(mock_service);
+
+        let hash_only = SupergraphRequest::fake_builder()
+            .extension(
+                "persistedQuery",
+                json!({
+                    "version" : 1,
+                    "sha256Hash" : "ecf4edb46db40b5132295c0291d62fb65d6759a9eedfa4d5d612dd5ec54a6b38"
+                }),
+            )
+            .build()
+            .expect("expecting valid request");
+
+        let response = service_stack
+            .ready()
+            .await
+            .unwrap()
+            .call(hash_only)
+            .await
+            .unwrap()
+            .next_response()
+            .await
+            .unwrap();
+        assert_error_matches(
+            &Error {
+                message: "PersistedQueryNotFound".to_string(),
+                locations: Default::default(),
+                path: Default::default(),
+                extensions: serde_json_bytes::from_value(json!({
+                    "code": "PERSISTED_QUERY_NOT_FOUND"
+                }))
+                .unwrap(),
+            },
+            response,
+        );
+    }
+
+    #[tokio::test]
+    async fn it_reads_the_configured_extension() {
+        let mut mock_service = MockSupergraphService::new();
+        mock_service.expect_call().times(1).returning(move |req| {
+            assert_eq!(
+                req.originating_request.body().query.as_deref(),
+                Some("{__typename}")
+            );
+            Ok(SupergraphResponse::fake_builder()
+                .context(req.context)
+                .build()
+                .expect("expecting valid request"))
+        });
+
+        let apq = APQLayer::with_cache(DeduplicatingCache::new().await)
+            .with_extension("persisted".to_string());
+        apq.cache
+            .partition(None)
+            .await
+            .insert(
+                hex::decode("ecf4edb46db40b5132295c0291d62fb65d6759a9eedfa4d5d612dd5ec54a6b38")
+                    .unwrap(),
+                "{__typename}".to_string(),
+            )
+            .await;
+        let mut service_stack = apq.layer(mock_service);
+
+        let hash_only = SupergraphRequest::fake_builder()
+            .extension(
+                "persisted",
+                json!({
+                    "version" : 1,
+                    "sha256Hash" : "ecf4edb46db40b5132295c0291d62fb65d6759a9eedfa4d5d612dd5ec54a6b38"
+                }),
+            )
+            .build()
+            .expect("expecting valid request");
+
+        service_stack
+            .ready()
+            .await
+            .unwrap()
+            .call(hash_only)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_shares_the_queries_through_the_storage() {
+        let hash = "ecf4edb46db40b5132295c0291d62fb65d6759a9eedfa4d5d612dd5ec54a6b38";
+        let storage: Arc<dyn KeyValueStorage> =
+            Arc::new(crate::cache::storage::InMemoryStorage::default());
+
+        let mut registering_service = MockSupergraphService::new();
+        registering_service
+            .expect_call()
+            .times(1)
+            .returning(move |req| {
+                Ok(SupergraphResponse::fake_builder()
+                    .context(req.context)
+                    .build()
+                    .expect("expecting valid request"))
+            });
+        let mut reading_service = MockSupergraphService::new();
+        reading_service
+            .expect_call()
+            .times(1)
+            .returning(move |req| {
+                assert_eq!(
+                    req.originating_request.body().query.as_deref(),
+                    Some("{__typename}")
+                );
+                Ok(SupergraphResponse::fake_builder()
+                    .context(req.context)
+                    .build()
+                    .expect("expecting valid request"))
+            });
+
+        // two routers sharing the storage
+        let mut registering_stack = APQLayer::with_cache(DeduplicatingCache::new().await)
+            .with_storage(storage.clone(), Duration::from_secs(60))
+            .layer(registering_service);
+        let reading_apq = APQLayer::with_cache(DeduplicatingCache::new().await)
+            .with_storage(storage, Duration::from_secs(60));
+        let mut reading_stack = reading_apq.layer(reading_service);
+
+        let with_query = SupergraphRequest::fake_builder()
+            .query("{__typename}".to_string())
+            .extension(
+                "persistedQuery",
+                json!({ "version" : 1, "sha256Hash" : hash }),
+            )
+            .build()
+            .expect("expecting valid request");
+        registering_stack
+            .ready()
+            .await
+            .unwrap()
+            .call(with_query)
+            .await
+            .unwrap();
+
+        let hash_only = SupergraphRequest::fake_builder()
+            .extension(
+                "persistedQuery",
+                json!({ "version" : 1, "sha256Hash" : hash }),
+            )
+            .build()
+            .expect("expecting valid request");
+        reading_stack
+            .ready()
+            .await
+            .unwrap()
+            .call(hash_only)
+            .await
+            .unwrap();
+        assert!(reading_apq.is_registered(hash).await);
+    }
+
     fn assert_error_matches(expected_error: &Error, res: Response) {
         assert_eq!(&res.errors[0], expected_error);
     }
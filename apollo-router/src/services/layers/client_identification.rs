@@ -0,0 +1,664 @@
+//! Identification of the clients of the requests.
+//!
+//! The name and version of the client are read from the claims of the bearer token, from a request
+//! extension, or from headers, in this order of precedence, the first source holding a name
+//! providing both. They are trimmed and truncated, then stored in the [`Context`] once per request,
+//! where the telemetry, the quotas and the persisted queries find them. The claims are only read
+//! from the tokens whose signature is verified with the configured JSON Web Key Set. Any client
+//! can set the extension and the headers, so only the clients identified from the claims are
+//! verified, and only their identity can grant them anything, e.g. the quotas of a client.
+//!
+//! The configuration overridden for a verified client, if any, is stored in the context as well,
+//! where it takes precedence over the timeout, the deadline, the maximum size and the
+//! introspection configured for every request.
+
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::sync::Weak;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use http::HeaderMap;
+use tokio::task::JoinHandle;
+use tower::BoxError;
+
+use crate::cache::partitioned::TENANT_CONTEXT_KEY;
+use crate::configuration::ClientIdentification;
+use crate::configuration::Jwks;
+use crate::graphql;
+use crate::jwt;
+use crate::jwt::Claims;
+use crate::jwt::Jwk;
+use crate::jwt::JwkSet;
+use crate::query_planner::DEADLINE_CONTEXT_KEY;
+use crate::services::request_size::MAX_REQUEST_BYTES_CONTEXT_KEY;
+use crate::Context;
+use crate::SupergraphRequest;
+
+/// Context key of the name of the client.
+pub(crate) const CLIENT_NAME_CONTEXT_KEY: &str = "apollo_router::client_name";
+/// Context key of the version of the client.
+pub(crate) const CLIENT_VERSION_CONTEXT_KEY: &str = "apollo_router::client_version";
+/// Context key set to `true` when the client was identified from the claims of the token.
+pub(crate) const CLIENT_VERIFIED_CONTEXT_KEY: &str = "apollo_router::client_verified";
+/// Context key of the timeout of the request overridden for its client, in milliseconds.
+pub(crate) const CLIENT_TIMEOUT_CONTEXT_KEY: &str = "apollo_router::client_timeout";
+/// Context key set to whether the request can send introspection queries.
+pub(crate) const INTROSPECTION_CONTEXT_KEY: &str = "apollo_router::introspection";
+/// Context key set once the client of the request is identified.
+const IDENTIFIED_CONTEXT_KEY: &str = "apollo_router::client_identified";
+
+/// Maximum length of the names and versions, in characters.
+const MAX_LENGTH: usize = 128;
+/// Timeout of the fetches of the JSON Web Key Set.
+const JWKS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The client of a request.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct ClientInfo {
+    pub(crate) name: Option<String>,
+    pub(crate) version: Option<String>,
+    /// Whether the client was identified from the claims of the token, rather than from the
+    /// extension or the headers that any client can set.
+    pub(crate) verified: bool,
+}
+
+impl ClientInfo {
+    /// The client identified for a request.
+    pub(crate) fn from_context(context: &Context) -> Self {
+        Self {
+            name: context.get(CLIENT_NAME_CONTEXT_KEY).ok().flatten(),
+            version: context.get(CLIENT_VERSION_CONTEXT_KEY).ok().flatten(),
+            verified: context
+                .get(CLIENT_VERIFIED_CONTEXT_KEY)
+                .ok()
+                .flatten()
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Keys verifying the bearer tokens: the configured ones, and the ones fetched from the URL of
+/// the key set, refreshed until the keys are dropped. Only the tokens of the configured issuer
+/// and audience are verified.
+#[derive(Debug)]
+pub(crate) struct TokenKeys {
+    issuer: String,
+    audience: String,
+    configured: Vec<Jwk>,
+    fetched: RwLock<Vec<Jwk>>,
+    refresh: Option<JoinHandle<()>>,
+}
+
+impl TokenKeys {
+    /// The keys of a configuration, once the key set is fetched. A key set that can not be
+    /// fetched is fetched again at the next refresh, and only the configured keys are used until
+    /// then.
+    pub(crate) async fn new(config: &Jwks) -> Result<Arc<Self>, BoxError> {
+        let client = reqwest::Client::builder().timeout(JWKS_TIMEOUT).build()?;
+        let fetched = match &config.url {
+            Some(url) => fetch_jwks(&client, url).await.unwrap_or_else(|e| {
+                tracing::error!("could not fetch the JSON Web Key Set from {}: {}", url, e);
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+        Ok(Arc::new_cyclic(|keys: &Weak<Self>| {
+            let refresh = config.url.clone().map(|url| {
+                let keys = keys.clone();
+                let interval = config.refresh;
+                tokio::spawn(async move {
+                    loop {
+                        tokio::time::sleep(interval).await;
+                        let keys = match keys.upgrade() {
+                            Some(keys) => keys,
+                            None => return,
+                        };
+                        match fetch_jwks(&client, &url).await {
+                            Ok(fetched) => *keys.fetched.write().expect("lock poisoned") = fetched,
+                            Err(e) => tracing::warn!(
+                                "could not refresh the JSON Web Key Set from {}: {}",
+                                url,
+                                e
+                            ),
+                        }
+                    }
+                })
+            });
+            TokenKeys {
+                issuer: config.issuer.clone(),
+                audience: config.audience.clone(),
+                configured: config.keys.clone(),
+                fetched: RwLock::new(fetched),
+                refresh,
+            }
+        }))
+    }
+
+    /// The claims of a token, if one of the keys verifies it.
+    fn verify(&self, token: &str) -> Option<Claims> {
+        let verify = |keys: &[Jwk]| jwt::verify(token, keys, &self.issuer, &self.audience);
+        let verified = verify(&self.configured)
+            .or_else(|_| verify(&self.fetched.read().expect("lock poisoned")));
+        match verified {
+            Ok(claims) => Some(claims),
+            Err(e) => {
+                tracing::debug!("the bearer token does not identify the client: {}", e);
+                None
+            }
+        }
+    }
+}
+
+impl Drop for TokenKeys {
+    fn drop(&mut self) {
+        if let Some(refresh) = &self.refresh {
+            refresh.abort();
+        }
+    }
+}
+
+async fn fetch_jwks(client: &reqwest::Client, url: &url::Url) -> Result<Vec<Jwk>, BoxError> {
+    let set: JwkSet = client
+        .get(url.clone())
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(set.keys)
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct ClientIdentifier {
+    config: Arc<ClientIdentification>,
+    token_keys: Option<Arc<TokenKeys>>,
+    introspection: bool,
+    cache_partitioning: bool,
+}
+
+impl ClientIdentifier {
+    pub(crate) fn new(config: ClientIdentification) -> Self {
+        Self {
+            config: Arc::new(config),
+            token_keys: None,
+            introspection: true,
+            cache_partitioning: false,
+        }
+    }
+
+    /// The keys verifying the bearer tokens. Without them, the claims of the tokens are not read.
+    pub(crate) fn with_token_keys(mut self, token_keys: Option<Arc<TokenKeys>>) -> Self {
+        self.token_keys = token_keys;
+        self
+    }
+
+    /// Whether introspection is enabled for the clients that do not override it.
+    pub(crate) fn with_introspection(mut self, introspection: bool) -> Self {
+        self.introspection = introspection;
+        self
+    }
+
+    /// Whether the verified clients are the tenants of the partitions of the caches.
+    pub(crate) fn with_cache_partitioning(mut self, cache_partitioning: bool) -> Self {
+        self.cache_partitioning = cache_partitioning;
+        self
+    }
+
+    /// The client of a request, from its first source holding a name.
+    pub(crate) fn identify(&self, request: &http::Request<graphql::Request>) -> ClientInfo {
+        let client = self
+            .client_from_claims(request.headers())
+            .or_else(|| self.client_from_extension(request.body()))
+            .unwrap_or_else(|| self.client_from_headers(request.headers()));
+        ClientInfo {
+            name: client.name.and_then(|name| self.normalize(&name)),
+            version: client.version.and_then(|version| self.normalize(&version)),
+            verified: client.verified,
+        }
+    }
+
+    /// Identify the client of a request, storing it and its overridden configuration in its
+    /// context, unless it was already identified.
+    pub(crate) fn identify_request(&self, req: SupergraphRequest) -> SupergraphRequest {
+        let context = &req.context;
+        if context
+            .get::<_, bool>(IDENTIFIED_CONTEXT_KEY)
+            .ok()
+            .flatten()
+            .unwrap_or_default()
+        {
+            return req;
+        }
+        let _ = context.insert(IDENTIFIED_CONTEXT_KEY, true);
+        let client = self.identify(&req.originating_request);
+        let overrides = client
+            .name
+            .as_ref()
+            .filter(|_| client.verified)
+            .and_then(|name| self.config.overrides.get(name));
+        if let Some(name) = client.name.clone() {
+            let _ = context.insert(CLIENT_NAME_CONTEXT_KEY, name);
+        }
+        if let Some(version) = client.version {
+            let _ = context.insert(CLIENT_VERSION_CONTEXT_KEY, version);
+        }
+        if client.verified {
+            let _ = context.insert(CLIENT_VERIFIED_CONTEXT_KEY, true);
+            if let (true, Some(name)) = (self.cache_partitioning, client.name) {
+                let _ = context.insert(TENANT_CONTEXT_KEY, name);
+            }
+        }
+        let introspection = overrides
+            .and_then(|overrides| overrides.introspection)
+            .unwrap_or(self.introspection);
+        let _ = context.insert(INTROSPECTION_CONTEXT_KEY, introspection);
+        if let Some(overrides) = overrides {
+            if let Some(timeout) = overrides.timeout {
+                let _ = context.insert(CLIENT_TIMEOUT_CONTEXT_KEY, timeout.as_millis() as u64);
+            }
+            if let Some(deadline) = overrides.deadline {
+                let deadline = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    + deadline;
+                let _ = context.insert(DEADLINE_CONTEXT_KEY, deadline.as_millis() as u64);
+            }
+            if let Some(max_request_bytes) = overrides.max_request_bytes {
+                let _ = context.insert(MAX_REQUEST_BYTES_CONTEXT_KEY, max_request_bytes.get());
+            }
+        }
+        req
+    }
+
+    fn client_from_claims(&self, headers: &HeaderMap) -> Option<ClientInfo> {
+        let name_claim = self.config.name_claim.as_ref()?;
+        let claims = self
+            .token_keys
+            .as_ref()?
+            .verify(bearer_token(headers, &self.config.token_header)?)?;
+        let name = claims.get(name_claim.as_str()).and_then(claim_string)?;
+        Some(ClientInfo {
+            name: Some(name),
+            version: self
+                .config
+                .version_claim
+                .as_ref()
+                .and_then(|claim| claims.get(claim.as_str()))
+                .and_then(claim_string),
+            verified: true,
+        })
+    }
+
+    fn client_from_extension(&self, request: &graphql::Request) -> Option<ClientInfo> {
+        let extension = self.config.extension.as_ref()?;
+        let client =
+            graphql::ClientLibrary::from_extension(request.extensions.get(extension.as_str())?)?;
+        Some(ClientInfo {
+            name: Some(client.name),
+            version: client.version,
+            verified: false,
+        })
+    }
+
+    fn client_from_headers(&self, headers: &HeaderMap) -> ClientInfo {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        ClientInfo {
+            name: header(&self.config.name_header),
+            version: header(&self.config.version_header),
+            verified: false,
+        }
+    }
+
+    fn normalize(&self, value: &str) -> Option<String> {
+        let value = value.trim();
+        if value.is_empty() {
+            return None;
+        }
+        let value: String = value.chars().take(MAX_LENGTH).collect();
+        Some(if self.config.lowercase {
+            value.to_lowercase()
+        } else {
+            value
+        })
+    }
+}
+
+/// The bearer token of a request.
+fn bearer_token<'a>(headers: &'a HeaderMap, header: &str) -> Option<&'a str> {
+    let value = headers.get(header)?.to_str().ok()?;
+    value
+        .strip_prefix("Bearer ")
+        .or_else(|| value.strip_prefix("bearer "))
+}
+
+fn claim_string(claim: &serde_json::Value) -> Option<String> {
+    match claim {
+        serde_json::Value::String(claim) => Some(claim.clone()),
+        serde_json::Value::Number(claim) => Some(claim.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU64;
+    use std::time::Duration;
+
+    use serde_json_bytes::json;
+
+    use super::*;
+    use crate::configuration::ClientOverrides;
+    use crate::jwt::test::signed_token;
+
+    const ISSUER: &str = "https://auth.example.com";
+    const AUDIENCE: &str = "router";
+
+    /// A token of the configured issuer and audience.
+    fn token(mut claims: serde_json::Value) -> String {
+        claims["iss"] = ISSUER.into();
+        claims["aud"] = AUDIENCE.into();
+        format!("Bearer {}", signed_token(claims).0)
+    }
+
+    fn token_keys() -> Option<Arc<TokenKeys>> {
+        Some(Arc::new(TokenKeys {
+            issuer: ISSUER.to_string(),
+            audience: AUDIENCE.to_string(),
+            configured: vec![signed_token(serde_json::json!({})).1],
+            fetched: Default::default(),
+            refresh: None,
+        }))
+    }
+
+    fn identifier() -> ClientIdentifier {
+        ClientIdentifier::new(ClientIdentification {
+            extension: Some("clientLibrary".to_string()),
+            name_claim: Some("azp".to_string()),
+            version_claim: Some("ver".to_string()),
+            lowercase: true,
+            ..Default::default()
+        })
+        .with_token_keys(token_keys())
+    }
+
+    #[test]
+    fn it_identifies_clients_from_headers() {
+        let request = http::Request::builder()
+            .header("apollographql-client-name", "  Web ")
+            .header("apollographql-client-version", "")
+            .body(graphql::Request::default())
+            .unwrap();
+        assert_eq!(
+            identifier().identify(&request),
+            ClientInfo {
+                name: Some("web".to_string()),
+                version: None,
+                verified: false,
+            }
+        );
+        assert_eq!(
+            ClientIdentifier::new(Default::default())
+                .identify(&request)
+                .name,
+            Some("Web".to_string())
+        );
+    }
+
+    #[test]
+    fn it_prefers_the_claims_and_extensions_over_the_headers() {
+        let body = graphql::Request::builder()
+            .extensions(
+                json!({ "clientLibrary": { "name": "ios", "version": "2.1.0" } })
+                    .as_object()
+                    .cloned()
+                    .unwrap(),
+            )
+            .build();
+        let request = http::Request::builder()
+            .header("apollographql-client-name", "web")
+            .header("apollographql-client-version", "1.0.0")
+            .body(body.clone())
+            .unwrap();
+        assert_eq!(
+            identifier().identify(&request),
+            ClientInfo {
+                name: Some("ios".to_string()),
+                version: Some("2.1.0".to_string()),
+                verified: false,
+            }
+        );
+
+        let request = http::Request::builder()
+            .header("apollographql-client-name", "web")
+            .header(
+                "authorization",
+                token(serde_json::json!({ "azp": "Android", "ver": 3 })),
+            )
+            .body(body)
+            .unwrap();
+        assert_eq!(
+            identifier().identify(&request),
+            ClientInfo {
+                name: Some("android".to_string()),
+                version: Some("3".to_string()),
+                verified: true,
+            }
+        );
+
+        // a token without the claim does not identify the client
+        let request = http::Request::builder()
+            .header("apollographql-client-name", "web")
+            .header("authorization", token(serde_json::json!({ "sub": "ada" })))
+            .body(graphql::Request::default())
+            .unwrap();
+        assert_eq!(
+            identifier().identify(&request).name,
+            Some("web".to_string())
+        );
+    }
+
+    #[test]
+    fn it_only_reads_the_claims_of_verified_tokens() {
+        let forged = format!(
+            "Bearer e30.{}.signature",
+            base64::encode_config(r#"{"azp":"admin"}"#, base64::URL_SAFE_NO_PAD)
+        );
+        let request = http::Request::builder()
+            .header("apollographql-client-name", "web")
+            .header("authorization", forged)
+            .body(graphql::Request::default())
+            .unwrap();
+        assert_eq!(
+            identifier().identify(&request),
+            ClientInfo {
+                name: Some("web".to_string()),
+                version: None,
+                verified: false,
+            }
+        );
+
+        // nor the claims of the tokens of other audiences
+        let other = format!(
+            "Bearer {}",
+            signed_token(serde_json::json!({ "azp": "admin", "iss": ISSUER, "aud": "billing" })).0
+        );
+        let request = http::Request::builder()
+            .header("apollographql-client-name", "web")
+            .header("authorization", other)
+            .body(graphql::Request::default())
+            .unwrap();
+        assert!(!identifier().identify(&request).verified);
+
+        // without keys, the tokens are not read
+        let request = http::Request::builder()
+            .header("apollographql-client-name", "web")
+            .header("authorization", token(serde_json::json!({ "azp": "etl" })))
+            .body(graphql::Request::default())
+            .unwrap();
+        assert!(
+            !identifier()
+                .with_token_keys(None)
+                .identify(&request)
+                .verified
+        );
+    }
+
+    #[test]
+    fn it_identifies_the_requests_once() {
+        let request = SupergraphRequest::fake_builder()
+            .header("authorization", token(serde_json::json!({ "azp": "etl" })))
+            .build()
+            .unwrap();
+        let request = identifier().identify_request(request);
+        let request = ClientIdentifier::new(Default::default()).identify_request(request);
+        assert_eq!(
+            ClientInfo::from_context(&request.context).name,
+            Some("etl".to_string())
+        );
+    }
+
+    #[test]
+    fn it_stores_the_client_in_the_context() {
+        let request = SupergraphRequest::fake_builder()
+            .header("apollographql-client-name", "web")
+            .build()
+            .unwrap();
+        let request = identifier().identify_request(request);
+        assert_eq!(
+            ClientInfo::from_context(&request.context),
+            ClientInfo {
+                name: Some("web".to_string()),
+                version: None,
+                verified: false,
+            }
+        );
+    }
+
+    #[test]
+    fn it_partitions_the_caches_by_verified_client_only() {
+        let identifier = identifier().with_cache_partitioning(true);
+        let request = SupergraphRequest::fake_builder()
+            .header("apollographql-client-name", "web")
+            .build()
+            .unwrap();
+        let request = identifier.identify_request(request);
+        assert_eq!(
+            request
+                .context
+                .get::<_, String>(TENANT_CONTEXT_KEY)
+                .unwrap(),
+            None
+        );
+
+        let request = SupergraphRequest::fake_builder()
+            .header("authorization", token(serde_json::json!({ "azp": "etl" })))
+            .build()
+            .unwrap();
+        let request = identifier.identify_request(request);
+        assert_eq!(
+            request
+                .context
+                .get::<_, String>(TENANT_CONTEXT_KEY)
+                .unwrap(),
+            Some("etl".to_string())
+        );
+    }
+
+    #[test]
+    fn it_overrides_the_configuration_of_verified_clients() {
+        let identifier = ClientIdentifier::new(ClientIdentification {
+            name_claim: Some("azp".to_string()),
+            overrides: [
+                (
+                    "etl".to_string(),
+                    ClientOverrides {
+                        timeout: Some(Duration::from_secs(300)),
+                        max_request_bytes: NonZeroU64::new(1_000_000),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "tooling".to_string(),
+                    ClientOverrides {
+                        introspection: Some(true),
+                        ..Default::default()
+                    },
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        })
+        .with_token_keys(token_keys())
+        .with_introspection(false);
+        let identify = |client: &str| {
+            identifier.identify_request(
+                SupergraphRequest::fake_builder()
+                    .header("authorization", token(serde_json::json!({ "azp": client })))
+                    .build()
+                    .unwrap(),
+            )
+        };
+
+        let context = identify("etl").context;
+        assert_eq!(
+            context.get::<_, u64>(CLIENT_TIMEOUT_CONTEXT_KEY).unwrap(),
+            Some(300_000)
+        );
+        assert_eq!(
+            context
+                .get::<_, u64>(MAX_REQUEST_BYTES_CONTEXT_KEY)
+                .unwrap(),
+            Some(1_000_000)
+        );
+        assert_eq!(
+            context.get::<_, bool>(INTROSPECTION_CONTEXT_KEY).unwrap(),
+            Some(false)
+        );
+
+        let context = identify("tooling").context;
+        assert_eq!(
+            context.get::<_, u64>(CLIENT_TIMEOUT_CONTEXT_KEY).unwrap(),
+            None
+        );
+        assert_eq!(
+            context.get::<_, bool>(INTROSPECTION_CONTEXT_KEY).unwrap(),
+            Some(true)
+        );
+
+        // the clients identified by their headers are not overridden
+        let context = identifier
+            .identify_request(
+                SupergraphRequest::fake_builder()
+                    .header("apollographql-client-name", "etl")
+                    .build()
+                    .unwrap(),
+            )
+            .context;
+        assert_eq!(
+            context.get::<_, u64>(CLIENT_TIMEOUT_CONTEXT_KEY).unwrap(),
+            None
+        );
+
+        // nor allowed to introspect the schema
+        let context = identifier
+            .identify_request(
+                SupergraphRequest::fake_builder()
+                    .header("apollographql-client-name", "tooling")
+                    .build()
+                    .unwrap(),
+            )
+            .context;
+        assert_eq!(
+            context.get::<_, bool>(INTROSPECTION_CONTEXT_KEY).unwrap(),
+            Some(false)
+        );
+    }
+}
@@ -0,0 +1,324 @@
+//! Masking of response fields, based on the claims of the requests.
+//!
+//! The fields are masked by schema coordinate, unless the claims found in the context allow the
+//! request to see them. The masked values are replaced with null or with a redacted value, and an
+//! error can be added at the path of each of them.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use serde_json_bytes::Value;
+
+use crate::configuration::ClaimCondition;
+use crate::configuration::DataMasking;
+use crate::graphql;
+use crate::json_ext::Object;
+use crate::spec::Query;
+use crate::Context;
+use crate::Schema;
+
+#[derive(Clone, Debug)]
+pub(crate) struct DataMasker {
+    config: DataMasking,
+}
+
+impl DataMasker {
+    pub(crate) fn new(config: DataMasking) -> Self {
+        Self { config }
+    }
+
+    /// The values replacing the fields masked for a request, by schema coordinate, and the
+    /// coordinates of the fields reported with an error.
+    fn masked_fields(&self, context: &Context) -> (HashMap<String, Value>, HashSet<String>) {
+        let claims = match context.get_json_value(self.config.claims_context_key.as_str()) {
+            Some(Value::Object(claims)) => Some(claims),
+            _ => None,
+        };
+        let mut masked = HashMap::new();
+        let mut reported = HashSet::new();
+        for rule in &self.config.rules {
+            // without conditions, the field is never unmasked
+            let allowed = match &claims {
+                Some(claims) => rule
+                    .unless
+                    .iter()
+                    .any(|condition| allows(condition, claims)),
+                None => false,
+            };
+            if allowed {
+                continue;
+            }
+            masked.insert(
+                rule.field.clone(),
+                rule.redacted
+                    .as_ref()
+                    .map(|redacted| Value::from(redacted.as_str()))
+                    .unwrap_or(Value::Null),
+            );
+            if rule.error {
+                reported.insert(rule.field.clone());
+            }
+        }
+        (masked, reported)
+    }
+
+    /// Mask the fields of a response that the request is not allowed to see.
+    pub(crate) fn mask(
+        &self,
+        query: &Query,
+        operation_name: Option<&str>,
+        schema: &Schema,
+        context: &Context,
+        response: &mut graphql::Response,
+    ) {
+        let (masked, reported) = self.masked_fields(context);
+        if masked.is_empty() {
+            return;
+        }
+        for (path, coordinate) in query.mask_fields(response, operation_name, schema, &masked) {
+            if reported.contains(&coordinate) {
+                response.errors.push(
+                    graphql::Error::builder()
+                        .message(format!("field '{}' is masked", coordinate))
+                        .path(path)
+                        .code(graphql::ErrorCode::FieldMasked)
+                        .build(),
+                );
+            }
+        }
+    }
+}
+
+/// Whether the claims of a request satisfy a condition.
+fn allows(condition: &ClaimCondition, claims: &Object) -> bool {
+    let claim = match claims.get(condition.claim.as_str()) {
+        Some(claim) => claim,
+        None => return false,
+    };
+    if condition.values.is_empty() {
+        return true;
+    }
+    let matches = |value: &Value| {
+        let value = match value {
+            Value::String(value) => value.as_str().to_string(),
+            value => serde_json::to_string(value).unwrap_or_default(),
+        };
+        condition.values.contains(&value)
+    };
+    match claim {
+        Value::Array(values) => values.iter().any(matches),
+        value => matches(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+
+    use super::*;
+    use crate::configuration::MaskingRule;
+    use crate::json_ext::Path;
+    use crate::Configuration;
+
+    const SCHEMA: &str = r#"
+        schema
+            @core(feature: "https://specs.apollo.dev/core/v0.1")
+            @core(feature: "https://specs.apollo.dev/join/v0.1")
+        {
+            query: Query
+        }
+        directive @core(feature: String!) repeatable on SCHEMA
+        directive @join__graph(name: String!, url: String!) on ENUM_VALUE
+        enum join__Graph {
+            ACCOUNTS @join__graph(name: "accounts", url: "http://localhost:4001/graphql")
+        }
+
+        type Query {
+            me: User
+            users: [User]
+            contact: Contact
+        }
+
+        interface Contact {
+            email: String
+        }
+
+        type User implements Contact {
+            id: ID!
+            email: String
+            phone: String
+            age: Int
+            ssn: String!
+        }
+
+        type Company implements Contact {
+            email: String
+        }
+    "#;
+
+    fn masker() -> DataMasker {
+        DataMasker::new(DataMasking {
+            claims_context_key: "claims".to_string(),
+            rules: vec![
+                MaskingRule {
+                    field: "User.email".to_string(),
+                    unless: vec![ClaimCondition {
+                        claim: "roles".to_string(),
+                        values: vec!["admin".to_string()],
+                    }],
+                    redacted: None,
+                    error: true,
+                },
+                MaskingRule {
+                    field: "User.phone".to_string(),
+                    unless: Vec::new(),
+                    redacted: Some("***".to_string()),
+                    error: false,
+                },
+                MaskingRule {
+                    field: "User.age".to_string(),
+                    unless: Vec::new(),
+                    redacted: Some("***".to_string()),
+                    error: false,
+                },
+            ],
+        })
+    }
+
+    fn mask(query: &str, data: Value, rules: Vec<MaskingRule>) -> graphql::Response {
+        let schema = Schema::parse(SCHEMA, &Configuration::default()).unwrap();
+        let query = Query::parse(query, &schema, &Default::default()).unwrap();
+        let mut response = graphql::Response::builder().data(data).build();
+        DataMasker::new(DataMasking {
+            claims_context_key: "claims".to_string(),
+            rules,
+        })
+        .mask(&query, None, &schema, &Context::new(), &mut response);
+        response
+    }
+
+    fn rule(field: &str) -> MaskingRule {
+        MaskingRule {
+            field: field.to_string(),
+            unless: Vec::new(),
+            redacted: None,
+            error: false,
+        }
+    }
+
+    fn masked_response(claims: Option<serde_json::Value>) -> graphql::Response {
+        let schema = Schema::parse(SCHEMA, &Configuration::default()).unwrap();
+        let query = Query::parse(
+            "{ me { id email phone } users { ...contact } } fragment contact on User { email }",
+            &schema,
+            &Default::default(),
+        )
+        .unwrap();
+        let context = Context::new();
+        if let Some(claims) = claims {
+            context.insert("claims", claims).unwrap();
+        }
+        let mut response = graphql::Response::builder()
+            .data(json!({
+                "me": { "id": "1", "email": "ada@example.com", "phone": "555-0100" },
+                "users": [{ "email": "alan@example.com" }, null]
+            }))
+            .build();
+        masker().mask(&query, None, &schema, &context, &mut response);
+        response
+    }
+
+    #[test]
+    fn it_masks_fields_for_requests_without_claims() {
+        let response = masked_response(None);
+        assert_eq!(
+            response.data,
+            Some(json!({
+                "me": { "id": "1", "email": null, "phone": "***" },
+                "users": [{ "email": null }, null]
+            }))
+        );
+        assert_eq!(
+            response
+                .errors
+                .iter()
+                .map(|error| error.path.clone().unwrap())
+                .collect::<Vec<_>>(),
+            vec![
+                Path::from("me/email"),
+                Path::from_slice(&["users", "0", "email"])
+            ]
+        );
+    }
+
+    #[test]
+    fn it_shows_fields_allowed_by_the_claims() {
+        let response = masked_response(Some(serde_json::json!({ "roles": ["user", "admin"] })));
+        assert_eq!(
+            response.data,
+            Some(json!({
+                "me": { "id": "1", "email": "ada@example.com", "phone": "555-0100" },
+                "users": [{ "email": "alan@example.com" }, null]
+            }))
+        );
+        assert!(response.errors.is_empty());
+
+        let response = masked_response(Some(serde_json::json!({ "roles": "user" })));
+        assert_eq!(
+            response.data.unwrap()["me"],
+            json!({ "id": "1", "email": null, "phone": "***" })
+        );
+    }
+
+    #[test]
+    fn it_never_unmasks_fields_without_conditions() {
+        let response = masked_response(Some(serde_json::json!({ "roles": ["admin"] })));
+        assert_eq!(response.data.unwrap()["me"]["phone"], json!("***"));
+    }
+
+    #[test]
+    fn it_masks_fields_selected_on_an_abstract_type() {
+        // the concrete type is not selected, the field is masked for every possible type
+        let response = mask(
+            "{ contact { email } }",
+            json!({ "contact": { "email": "ada@example.com" } }),
+            vec![rule("User.email")],
+        );
+        assert_eq!(response.data, Some(json!({ "contact": { "email": null } })));
+
+        let response = mask(
+            "{ contact { __typename email } }",
+            json!({ "contact": { "__typename": "Company", "email": "info@example.com" } }),
+            vec![rule("User.email")],
+        );
+        assert_eq!(
+            response.data,
+            Some(json!({ "contact": { "__typename": "Company", "email": "info@example.com" } }))
+        );
+    }
+
+    #[test]
+    fn it_keeps_the_types_of_the_masked_fields() {
+        // a string can not replace an Int
+        let response = mask(
+            "{ me { id age } }",
+            json!({ "me": { "id": "1", "age": 36 } }),
+            masker().config.rules,
+        );
+        assert_eq!(
+            response.data,
+            Some(json!({ "me": { "id": "1", "age": null } }))
+        );
+
+        // a non-nullable field nulls its nearest nullable ancestor
+        let response = mask(
+            "{ me { id ssn } users { id ssn } }",
+            json!({
+                "me": { "id": "1", "ssn": "123" },
+                "users": [{ "id": "1", "ssn": "123" }]
+            }),
+            vec![rule("User.ssn")],
+        );
+        assert_eq!(response.data, Some(json!({ "me": null, "users": [null] })));
+    }
+}
@@ -12,6 +12,7 @@ use tower::BoxError;
 use tower::Layer;
 use tower::Service;
 
+use crate::graphql::ErrorCode;
 use crate::layers::sync_checkpoint::CheckpointService;
 use crate::SupergraphRequest;
 use crate::SupergraphResponse;
@@ -33,12 +34,10 @@ where
                 // A query must be available at this point
                 let query = req.originating_request.body().query.as_ref();
                 if query.is_none() || query.unwrap().trim().is_empty() {
-                    let errors = vec![crate::error::Error {
-                        message: "Must provide query string.".to_string(),
-                        locations: Default::default(),
-                        path: Default::default(),
-                        extensions: Default::default(),
-                    }];
+                    let errors = vec![crate::error::Error::builder()
+                        .message("Must provide query string.")
+                        .code(ErrorCode::BadRequest)
+                        .build()];
 
                     //We do not copy headers from the request to the response as this may lead to leakable of sensitive data
                     let res = SupergraphResponse::builder()
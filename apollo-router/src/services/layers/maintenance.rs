@@ -0,0 +1,263 @@
+//! Maintenance mode, answering operations with a static response.
+//!
+//! While it is enabled, operations are answered with a GraphQL error, or with the response of the
+//! configuration, before anything else happens to them. The mode follows the `maintenance`
+//! section of the configuration, unless it was switched on or off through the admin API: the
+//! switch is kept across reloads until the `maintenance` section changes.
+
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use http::StatusCode;
+use serde_json_bytes::Value;
+use tower::BoxError;
+use tower::Layer;
+use tower::Service;
+
+use crate::configuration::Maintenance;
+use crate::graphql;
+use crate::layers::sync_checkpoint::CheckpointService;
+use crate::SupergraphRequest;
+use crate::SupergraphResponse;
+
+/// Maintenance mode switched through the admin API.
+///
+/// It is kept across reloads of the schema and the configuration.
+#[derive(Clone, Default)]
+pub(crate) struct MaintenanceSwitch {
+    enabled: Arc<RwLock<Option<bool>>>,
+}
+
+impl MaintenanceSwitch {
+    fn get(&self) -> Option<bool> {
+        *self.enabled.read().expect("lock poisoned")
+    }
+
+    /// Enable or disable the maintenance mode, or follow the configuration again with `None`.
+    pub(crate) fn set(&self, enabled: Option<bool>) {
+        *self.enabled.write().expect("lock poisoned") = enabled;
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct MaintenanceLayer {
+    config: Option<Arc<Maintenance>>,
+    /// The response of the configuration, or the maintenance error, built when the layer is
+    /// created.
+    response: Arc<graphql::Response>,
+    status_code: StatusCode,
+    switch: MaintenanceSwitch,
+}
+
+impl MaintenanceLayer {
+    pub(crate) fn new(
+        config: Option<Maintenance>,
+        switch: MaintenanceSwitch,
+    ) -> Result<Self, BoxError> {
+        let default_config = Maintenance::default();
+        let maintenance = config.as_ref().unwrap_or(&default_config);
+        let status_code = StatusCode::from_u16(maintenance.status_code)?;
+        let response = match maintenance.response.clone() {
+            Some(response) => serde_json::from_value(response)?,
+            None => graphql::Response::builder()
+                .data(Value::Null)
+                .errors(vec![graphql::Error::builder()
+                    .message(maintenance.message.clone())
+                    .extension("code", maintenance.code.clone())
+                    .build()])
+                .build(),
+        };
+        Ok(MaintenanceLayer {
+            config: config.map(Arc::new),
+            response: Arc::new(response),
+            status_code,
+            switch,
+        })
+    }
+
+    pub(crate) fn config(&self) -> Option<&Maintenance> {
+        self.config.as_deref()
+    }
+
+    pub(crate) fn switch(&self) -> MaintenanceSwitch {
+        self.switch.clone()
+    }
+
+    /// Whether the static response is returned, as switched through the admin API or else as
+    /// configured.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.switch
+            .get()
+            .unwrap_or_else(|| self.config().map(|config| config.enabled).unwrap_or(false))
+    }
+
+    fn applies_to(&self, request: &SupergraphRequest) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        let operation_names = match self.config() {
+            Some(config) if !config.operation_names.is_empty() => &config.operation_names,
+            _ => return true,
+        };
+        request
+            .originating_request
+            .body()
+            .operation_name
+            .as_ref()
+            .map(|name| operation_names.contains(name))
+            .unwrap_or(false)
+    }
+
+    fn static_response(&self, request: SupergraphRequest) -> SupergraphResponse {
+        SupergraphResponse::builder()
+            .and_data(self.response.data.clone())
+            .errors(self.response.errors.clone())
+            .extensions(self.response.extensions.clone())
+            .status_code(self.status_code)
+            .context(request.context)
+            .build()
+            .expect("response is valid")
+    }
+}
+
+impl<S> Layer<S> for MaintenanceLayer
+where
+    S: Service<SupergraphRequest, Response = SupergraphResponse> + Send + 'static,
+    <S as Service<SupergraphRequest>>::Future: Send + 'static,
+    <S as Service<SupergraphRequest>>::Error: Into<BoxError> + Send + 'static,
+{
+    type Service = CheckpointService<S, SupergraphRequest>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        let maintenance = self.clone();
+        CheckpointService::new(
+            move |req: SupergraphRequest| {
+                if maintenance.applies_to(&req) {
+                    Ok(ControlFlow::Break(maintenance.static_response(req)))
+                } else {
+                    Ok(ControlFlow::Continue(req))
+                }
+            },
+            service,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::plugin::test::MockSupergraphService;
+
+    fn layer(config: serde_json::Value) -> MaintenanceLayer {
+        MaintenanceLayer::new(
+            Some(serde_json::from_value(config).unwrap()),
+            Default::default(),
+        )
+        .unwrap()
+    }
+
+    fn service(
+        layer: &MaintenanceLayer,
+        calls: usize,
+    ) -> CheckpointService<MockSupergraphService, SupergraphRequest> {
+        let mut mock_service = MockSupergraphService::new();
+        mock_service
+            .expect_call()
+            .times(calls)
+            .returning(|_| Ok(SupergraphResponse::fake_builder().build().unwrap()));
+        layer.layer(mock_service)
+    }
+
+    fn request(operation_name: &str) -> SupergraphRequest {
+        SupergraphRequest::fake_builder()
+            .query("query Me { me { id } }")
+            .operation_name(operation_name)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_returns_the_maintenance_error() {
+        let layer = layer(serde_json::json!({}));
+        let mut response = service(&layer, 0).oneshot(request("Me")).await.unwrap();
+
+        assert_eq!(response.response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = response.next_response().await.unwrap();
+        assert_eq!(body.data, Some(Value::Null));
+        assert_eq!(
+            body.errors[0].message,
+            "the service is under maintenance, try again later"
+        );
+        assert_eq!(
+            body.errors[0].extensions.get("code"),
+            Some(&json!("MAINTENANCE"))
+        );
+    }
+
+    #[tokio::test]
+    async fn it_returns_the_configured_response_to_matching_operations() {
+        let layer = layer(serde_json::json!({
+            "status_code": 200,
+            "response": { "data": { "me": null } },
+            "operation_names": ["Me"]
+        }));
+
+        service(&layer, 1).oneshot(request("Other")).await.unwrap();
+
+        let mut response = service(&layer, 0).oneshot(request("Me")).await.unwrap();
+        assert_eq!(response.response.status(), StatusCode::OK);
+        let body = response.next_response().await.unwrap();
+        assert_eq!(body.data, Some(json!({ "me": null })));
+        assert!(body.errors.is_empty());
+
+        assert!(MaintenanceLayer::new(
+            Some(
+                serde_json::from_value(serde_json::json!({ "response": { "errors": "invalid" } }))
+                    .unwrap()
+            ),
+            Default::default(),
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn it_follows_the_switch() {
+        let layer = layer(serde_json::json!({ "enabled": false }));
+        service(&layer, 1).oneshot(request("Me")).await.unwrap();
+
+        layer.switch().set(Some(true));
+        assert!(layer.is_enabled());
+        let mut response = service(&layer, 0).oneshot(request("Me")).await.unwrap();
+        assert_eq!(response.next_response().await.unwrap().errors.len(), 1);
+
+        layer.switch().set(None);
+        assert!(!layer.is_enabled());
+
+        // the mode can be switched on without any configuration
+        let layer = MaintenanceLayer::new(None, Default::default()).unwrap();
+        assert!(!layer.is_enabled());
+        layer.switch().set(Some(true));
+        let mut response = service(&layer, 0).oneshot(request("Me")).await.unwrap();
+        assert_eq!(response.response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response
+            .next_response()
+            .await
+            .unwrap()
+            .errors
+            .iter()
+            .any(|error| error.extensions.get("code") == Some(&json!("MAINTENANCE"))));
+    }
+
+    #[test]
+    fn it_rejects_an_invalid_status_code() {
+        assert!(MaintenanceLayer::new(
+            Some(serde_json::from_value(serde_json::json!({ "status_code": 1000 })).unwrap()),
+            Default::default(),
+        )
+        .is_err());
+    }
+}
@@ -1,4 +1,12 @@
 //! Layers that are internal to the execution pipeline.
 pub(crate) mod allow_only_http_post_mutations;
 pub(crate) mod apq;
+pub(crate) mod client_identification;
+pub(crate) mod data_masking;
 pub(crate) mod ensure_query_presence;
+pub(crate) mod maintenance;
+pub(crate) mod partial_results;
+pub(crate) mod persisted_queries;
+pub(crate) mod query_plan_checks;
+pub(crate) mod quotas;
+pub(crate) mod surrogate_keys;
@@ -0,0 +1,236 @@
+//! Partial results policies: whether a failed subgraph fetch fails the whole request.
+//!
+//! With the `best_effort` policy, the default, the data fetched from the other subgraphs is
+//! returned along with the errors. With the `fail_fast` policy, the fetches are cancelled after the
+//! first error, and the response stops there, without its data. The policy is chosen by operation name, and the clients can request the
+//! `fail_fast` policy with a header or with the `strict` request extension. Plugins can choose it
+//! as well, by setting a boolean in the context of the request.
+
+use std::sync::Arc;
+
+use futures::future;
+use futures::StreamExt;
+use serde_json_bytes::Value;
+
+use crate::configuration::PartialResults;
+use crate::configuration::PartialResultsPolicy;
+use crate::graphql;
+use crate::SupergraphRequest;
+use crate::SupergraphResponse;
+
+/// Context entry set to `true` for the requests with the `fail_fast` policy.
+pub(crate) const FAIL_FAST_CONTEXT_KEY: &str = "apollo_router::partial_results::fail_fast";
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PartialResultsPolicies {
+    config: Arc<PartialResults>,
+}
+
+impl PartialResultsPolicies {
+    pub(crate) fn new(config: PartialResults) -> Self {
+        Self {
+            config: Arc::new(config),
+        }
+    }
+
+    /// The policy of a request: `fail_fast` if its client requested it, or the policy of its
+    /// operation.
+    pub(crate) fn policy(&self, request: &http::Request<graphql::Request>) -> PartialResultsPolicy {
+        let strict_header = self
+            .config
+            .strict_header
+            .as_ref()
+            .and_then(|header| request.headers().get(header.as_str()))
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        if strict_header || request.body().strict().unwrap_or(false) {
+            return PartialResultsPolicy::FailFast;
+        }
+        request
+            .body()
+            .operation_name
+            .as_ref()
+            .and_then(|name| self.config.operations.get(name))
+            .copied()
+            .unwrap_or(self.config.policy)
+    }
+
+    /// Record the policy of a request in its context, unless a plugin already chose it.
+    pub(crate) fn record(&self, request: SupergraphRequest) -> SupergraphRequest {
+        let chosen = request
+            .context
+            .get::<_, bool>(FAIL_FAST_CONTEXT_KEY)
+            .ok()
+            .flatten()
+            .is_some();
+        if !chosen && self.policy(&request.originating_request) == PartialResultsPolicy::FailFast {
+            if let Err(e) = request.context.insert(FAIL_FAST_CONTEXT_KEY, true) {
+                tracing::error!("could not set the partial results policy: {}", e);
+            }
+        }
+        request
+    }
+}
+
+/// End the response at its first part with errors, without the data of this part, for the
+/// requests with the `fail_fast` policy. The deferred parts are not sent, and the deferred fetches
+/// are cancelled once the response is dropped.
+pub(crate) fn enforce(response: SupergraphResponse) -> SupergraphResponse {
+    let fail_fast = response
+        .context
+        .get::<_, bool>(FAIL_FAST_CONTEXT_KEY)
+        .ok()
+        .flatten()
+        .unwrap_or(false);
+    if !fail_fast {
+        return response;
+    }
+    response.map(|stream| {
+        stream
+            .scan(false, |failed, mut response| {
+                if *failed {
+                    return future::ready(None);
+                }
+                *failed = !response.errors.is_empty();
+                if *failed && response.data.is_some() {
+                    response.data = Some(Value::Null);
+                }
+                for incremental in &mut response.incremental {
+                    if !incremental.errors.is_empty() {
+                        *failed = true;
+                        if incremental.data.is_some() {
+                            incremental.data = Some(Value::Null);
+                        }
+                    }
+                }
+                if *failed && response.has_next.is_some() {
+                    response.has_next = Some(false);
+                }
+                future::ready(Some(response))
+            })
+            .boxed()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json_bytes::json;
+
+    use super::*;
+    use crate::json_ext::Path;
+
+    fn policies() -> PartialResultsPolicies {
+        PartialResultsPolicies::new(PartialResults {
+            policy: PartialResultsPolicy::BestEffort,
+            operations: HashMap::from([("Checkout".to_string(), PartialResultsPolicy::FailFast)]),
+            strict_header: Some("x-strict".to_string()),
+        })
+    }
+
+    fn request(operation_name: &str, strict_header: bool) -> http::Request<graphql::Request> {
+        let mut request = http::Request::builder();
+        if strict_header {
+            request = request.header("x-strict", "true");
+        }
+        request
+            .body(
+                graphql::Request::builder()
+                    .query("{ me { name } }")
+                    .operation_name(operation_name)
+                    .build(),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn it_chooses_the_policy_of_a_request() {
+        let policies = policies();
+        assert_eq!(
+            policies.policy(&request("Me", false)),
+            PartialResultsPolicy::BestEffort
+        );
+        assert_eq!(
+            policies.policy(&request("Checkout", false)),
+            PartialResultsPolicy::FailFast
+        );
+        assert_eq!(
+            policies.policy(&request("Me", true)),
+            PartialResultsPolicy::FailFast
+        );
+        let mut strict = request("Me", false);
+        strict.body_mut().extensions.insert("strict", json!(true));
+        assert_eq!(policies.policy(&strict), PartialResultsPolicy::FailFast);
+    }
+
+    #[tokio::test]
+    async fn it_drops_the_data_of_the_responses_with_errors() {
+        let response = || {
+            SupergraphResponse::fake_builder()
+                .data(json!({ "me": { "name": null } }))
+                .error(
+                    graphql::Error::builder()
+                        .message("could not fetch the name")
+                        .build(),
+                )
+                .build()
+                .unwrap()
+        };
+
+        let mut best_effort = enforce(response());
+        assert_eq!(
+            best_effort.next_response().await.unwrap().data,
+            Some(json!({ "me": { "name": null } }))
+        );
+
+        let fail_fast = response();
+        fail_fast
+            .context
+            .insert(FAIL_FAST_CONTEXT_KEY, true)
+            .unwrap();
+        let mut fail_fast = enforce(fail_fast);
+        let response = fail_fast.next_response().await.unwrap();
+        assert_eq!(response.data, Some(Value::Null));
+        assert_eq!(response.errors.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn it_ends_the_response_at_the_first_error() {
+        let response = SupergraphResponse::fake_builder()
+            .build()
+            .unwrap()
+            .map(|_| {
+                futures::stream::iter(vec![
+                    graphql::Response::builder()
+                        .data(json!({ "me": { "name": null } }))
+                        .error(
+                            graphql::Error::builder()
+                                .message("could not fetch the name")
+                                .build(),
+                        )
+                        .has_next(true)
+                        .build(),
+                    graphql::Response::builder()
+                        .incremental(vec![graphql::IncrementalResponse::builder()
+                            .data(json!({ "reviews": [] }))
+                            .path(Path::from("me"))
+                            .build()])
+                        .has_next(false)
+                        .build(),
+                ])
+                .boxed()
+            });
+        response
+            .context
+            .insert(FAIL_FAST_CONTEXT_KEY, true)
+            .unwrap();
+
+        let mut response = enforce(response);
+        let primary = response.next_response().await.unwrap();
+        assert_eq!(primary.data, Some(Value::Null));
+        assert_eq!(primary.has_next, Some(false));
+        assert!(response.next_response().await.is_none());
+    }
+}
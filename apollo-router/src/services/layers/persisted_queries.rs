@@ -0,0 +1,469 @@
+//! Registry of the persisted query manifests pushed through the admin API, or fetched from the
+//! Apollo operation registry.
+//!
+//! Manifests are uploaded under a version, and are only used once activated. Activation swaps
+//! the active manifest at once, so requests see either all the operations of the previous
+//! version or all those of the new one. The [`APQLayer`](super::apq::APQLayer) resolves the
+//! persisted query hashes of requests with the active manifest before looking them up in the
+//! APQ cache.
+//!
+//! Operations can be bound to clients, identified by the verified claims of their bearer token
+//! with `client_identification`: any client can set the client awareness headers, so they do not
+//! bind. Only those clients can execute them, whether they send the hash or the body of the
+//! operation. Bodies are
+//! compared once normalized, so that whitespace, commas and comments do not make them differ.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use displaydoc::Display;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use thiserror::Error;
+
+use crate::signature;
+
+/// Number of manifest versions kept, besides the active one.
+const MAX_VERSIONS: usize = 10;
+
+/// A manifest of persisted queries, as pushed by CI pipelines.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Manifest {
+    /// Version of the manifest, e.g. the commit it was built from.
+    pub(crate) version: String,
+    pub(crate) operations: Vec<ManifestOperation>,
+    /// Activate the manifest once registered.
+    #[serde(default)]
+    pub(crate) activate: bool,
+}
+
+/// An operation of a manifest.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct ManifestOperation {
+    /// SHA-256 hash of the body, in hexadecimal.
+    pub(crate) id: String,
+    pub(crate) body: String,
+    /// Clients allowed to execute the operation, any client if empty.
+    #[serde(default)]
+    pub(crate) clients: Vec<ClientBinding>,
+}
+
+/// A client allowed to execute an operation.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ClientBinding {
+    /// Name of the client.
+    pub(crate) name: String,
+    /// Minimum version of the client, included.
+    #[serde(default)]
+    pub(crate) min_version: Option<String>,
+    /// Maximum version of the client, excluded.
+    #[serde(default)]
+    pub(crate) max_version: Option<String>,
+}
+
+impl ClientBinding {
+    fn allows(&self, name: Option<&str>, version: Option<&str>) -> bool {
+        if name != Some(self.name.as_str()) {
+            return false;
+        }
+        if self.min_version.is_none() && self.max_version.is_none() {
+            return true;
+        }
+        let version = match version {
+            Some(version) => version,
+            None => return false,
+        };
+        self.min_version
+            .as_deref()
+            .map(|min| compare_versions(version, min) != Ordering::Less)
+            .unwrap_or(true)
+            && self
+                .max_version
+                .as_deref()
+                .map(|max| compare_versions(version, max) == Ordering::Less)
+                .unwrap_or(true)
+    }
+}
+
+/// Compare dotted versions, numerically for the numeric components: `1.10` is after `1.9.2`.
+fn compare_versions(left: &str, right: &str) -> Ordering {
+    let mut left = left.split('.');
+    let mut right = right.split('.');
+    loop {
+        let (left, right) = match (left.next(), right.next()) {
+            (None, None) => return Ordering::Equal,
+            // missing components are zeros: `1.2` is `1.2.0`
+            (left, right) => (left.unwrap_or("0"), right.unwrap_or("0")),
+        };
+        let ordering = match (left.parse::<u64>(), right.parse::<u64>()) {
+            (Ok(left), Ok(right)) => left.cmp(&right),
+            _ => left.cmp(right),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+/// The hash of the normalized body of an operation, in hexadecimal. The bodies that can not be
+/// parsed are hashed as they are.
+fn normalized_id(body: &str) -> String {
+    let normalized = signature::normalize(body);
+    let body = normalized.as_deref().unwrap_or(body);
+    hex::encode(Sha256::digest(body.as_bytes()))
+}
+
+/// Errors when registering or activating a manifest.
+#[derive(Error, Debug, Display, Clone, PartialEq, Eq)]
+pub(crate) enum RegistrationError {
+    /// manifest version '{0}' is already registered
+    VersionExists(String),
+    /// manifest version '{0}' is not registered
+    UnknownVersion(String),
+    /// operation '{0}' does not match the SHA-256 hash of its body
+    InvalidOperation(String),
+}
+
+/// Versions of the registry, as exposed by the admin API.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct RegistryStatus {
+    pub(crate) active: Option<String>,
+    pub(crate) versions: Vec<VersionStatus>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct VersionStatus {
+    pub(crate) version: String,
+    pub(crate) operations: usize,
+}
+
+/// An operation of a registered manifest.
+struct PersistedOperation {
+    body: String,
+    clients: Vec<ClientBinding>,
+}
+
+/// The operations of a registered manifest.
+#[derive(Default)]
+struct ManifestOperations {
+    /// Operations by hash.
+    by_id: HashMap<String, PersistedOperation>,
+    /// Hashes of the operations bound to clients, by hash of their normalized body.
+    bound: HashMap<String, String>,
+}
+
+/// The operation of a request, checked against the client bindings.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum OperationRef<'a> {
+    /// The hash the operation was sent with.
+    Id(&'a str),
+    /// The body of the operation, with its hash.
+    Body { id: &'a str, body: &'a str },
+}
+
+type Operations = Arc<ManifestOperations>;
+
+#[derive(Default)]
+struct Registry {
+    /// Registered versions, from the oldest to the most recent.
+    versions: Vec<(String, Operations)>,
+    active: Option<(String, Operations)>,
+    /// Version of the last manifest fetched from the operation registry.
+    operation_registry_version: Option<String>,
+}
+
+/// Registry of the persisted query manifests.
+///
+/// It is kept across reloads of the schema and the configuration.
+#[derive(Clone, Default)]
+pub(crate) struct PersistedQueryRegistry {
+    inner: Arc<RwLock<Registry>>,
+}
+
+impl PersistedQueryRegistry {
+    /// The body of a query of the active manifest.
+    pub(crate) fn get(&self, id: &str) -> Option<String> {
+        let registry = self.inner.read().expect("lock poisoned");
+        registry
+            .active
+            .as_ref()
+            .and_then(|(_, operations)| operations.by_id.get(id))
+            .map(|operation| operation.body.clone())
+    }
+
+    /// Whether operations of the active manifest are bound to clients.
+    pub(crate) fn has_client_bindings(&self) -> bool {
+        let registry = self.inner.read().expect("lock poisoned");
+        registry
+            .active
+            .as_ref()
+            .map(|(_, operations)| !operations.bound.is_empty())
+            .unwrap_or(false)
+    }
+
+    /// Whether a client can execute an operation. Operations that are not in the active manifest,
+    /// or not bound to clients, can be executed by any client. Bodies are only hashed when some
+    /// operations are bound.
+    pub(crate) fn allows_client(
+        &self,
+        operation: OperationRef<'_>,
+        name: Option<&str>,
+        version: Option<&str>,
+    ) -> bool {
+        let registry = self.inner.read().expect("lock poisoned");
+        let operations = match &registry.active {
+            Some((_, operations)) if !operations.bound.is_empty() => operations,
+            _ => return true,
+        };
+        let operation = match operation {
+            OperationRef::Id(id) => operations.by_id.get(id),
+            // the body is only normalized when it is not exactly the one of the manifest
+            OperationRef::Body { id, body } => operations.by_id.get(id).or_else(|| {
+                operations
+                    .bound
+                    .get(&normalized_id(body))
+                    .and_then(|id| operations.by_id.get(id))
+            }),
+        };
+        match operation {
+            Some(operation) if !operation.clients.is_empty() => operation
+                .clients
+                .iter()
+                .any(|client| client.allows(name, version)),
+            _ => true,
+        }
+    }
+
+    /// Register a manifest under its version, and activate it if requested.
+    pub(crate) fn register(&self, manifest: Manifest) -> Result<(), RegistrationError> {
+        let mut operations = ManifestOperations {
+            by_id: HashMap::with_capacity(manifest.operations.len()),
+            bound: HashMap::new(),
+        };
+        for operation in manifest.operations {
+            let id = operation.id.to_lowercase();
+            if id != hex::encode(Sha256::digest(operation.body.as_bytes())) {
+                return Err(RegistrationError::InvalidOperation(operation.id));
+            }
+            if !operation.clients.is_empty() {
+                operations
+                    .bound
+                    .insert(normalized_id(&operation.body), id.clone());
+            }
+            operations.by_id.insert(
+                id,
+                PersistedOperation {
+                    body: operation.body,
+                    clients: operation.clients,
+                },
+            );
+        }
+        let operations = Arc::new(operations);
+
+        let mut registry = self.inner.write().expect("lock poisoned");
+        if registry
+            .versions
+            .iter()
+            .any(|(version, _)| *version == manifest.version)
+        {
+            return Err(RegistrationError::VersionExists(manifest.version));
+        }
+        registry
+            .versions
+            .push((manifest.version.clone(), operations.clone()));
+        if registry.versions.len() > MAX_VERSIONS {
+            registry.versions.remove(0);
+        }
+        if manifest.activate {
+            registry.active = Some((manifest.version, operations));
+        }
+        Ok(())
+    }
+
+    /// Make a registered version the active one.
+    pub(crate) fn activate(&self, version: &str) -> Result<(), RegistrationError> {
+        let mut registry = self.inner.write().expect("lock poisoned");
+        let operations = registry
+            .versions
+            .iter()
+            .find(|(registered, _)| registered == version)
+            .map(|(_, operations)| operations.clone())
+            .ok_or_else(|| RegistrationError::UnknownVersion(version.to_string()))?;
+        registry.active = Some((version.to_string(), operations));
+        Ok(())
+    }
+
+    /// The version of the last manifest fetched from the operation registry, kept across reloads.
+    pub(crate) fn operation_registry_version(&self) -> Option<String> {
+        let registry = self.inner.read().expect("lock poisoned");
+        registry.operation_registry_version.clone()
+    }
+
+    pub(crate) fn set_operation_registry_version(&self, version: String) {
+        let mut registry = self.inner.write().expect("lock poisoned");
+        registry.operation_registry_version = Some(version);
+    }
+
+    pub(crate) fn status(&self) -> RegistryStatus {
+        let registry = self.inner.read().expect("lock poisoned");
+        RegistryStatus {
+            active: registry.active.as_ref().map(|(version, _)| version.clone()),
+            versions: registry
+                .versions
+                .iter()
+                .map(|(version, operations)| VersionStatus {
+                    version: version.clone(),
+                    operations: operations.by_id.len(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUERY: &str = "{__typename}";
+    const HASH: &str = "ecf4edb46db40b5132295c0291d62fb65d6759a9eedfa4d5d612dd5ec54a6b38";
+
+    fn manifest(version: &str, activate: bool) -> Manifest {
+        Manifest {
+            version: version.to_string(),
+            operations: vec![ManifestOperation {
+                id: HASH.to_string(),
+                body: QUERY.to_string(),
+                clients: Vec::new(),
+            }],
+            activate,
+        }
+    }
+
+    #[test]
+    fn it_activates_registered_versions() {
+        let registry = PersistedQueryRegistry::default();
+
+        registry.register(manifest("v1", false)).unwrap();
+        assert_eq!(registry.get(HASH), None);
+
+        registry.activate("v1").unwrap();
+        assert_eq!(registry.get(HASH).as_deref(), Some(QUERY));
+
+        registry
+            .register(Manifest {
+                version: "v2".to_string(),
+                operations: Vec::new(),
+                activate: true,
+            })
+            .unwrap();
+        assert_eq!(registry.get(HASH), None);
+        assert_eq!(
+            registry.status(),
+            RegistryStatus {
+                active: Some("v2".to_string()),
+                versions: vec![
+                    VersionStatus {
+                        version: "v1".to_string(),
+                        operations: 1,
+                    },
+                    VersionStatus {
+                        version: "v2".to_string(),
+                        operations: 0,
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_manifests() {
+        let registry = PersistedQueryRegistry::default();
+        registry.register(manifest("v1", true)).unwrap();
+
+        assert_eq!(
+            registry.register(manifest("v1", false)),
+            Err(RegistrationError::VersionExists("v1".to_string()))
+        );
+        assert_eq!(
+            registry.register(Manifest {
+                version: "v2".to_string(),
+                operations: vec![ManifestOperation {
+                    id: HASH.to_string(),
+                    body: "{ me { id } }".to_string(),
+                    clients: Vec::new(),
+                }],
+                activate: true,
+            }),
+            Err(RegistrationError::InvalidOperation(HASH.to_string()))
+        );
+        assert_eq!(
+            registry.activate("v3"),
+            Err(RegistrationError::UnknownVersion("v3".to_string()))
+        );
+
+        // the active manifest is left untouched
+        assert_eq!(registry.get(HASH).as_deref(), Some(QUERY));
+    }
+
+    #[test]
+    fn it_binds_operations_to_clients() {
+        let binding = ClientBinding {
+            name: "partner".to_string(),
+            min_version: Some("1.9".to_string()),
+            max_version: Some("2".to_string()),
+        };
+        assert!(binding.allows(Some("partner"), Some("1.10.0")));
+        assert!(binding.allows(Some("partner"), Some("1.9")));
+        assert!(!binding.allows(Some("partner"), Some("1.8.12")));
+        assert!(!binding.allows(Some("partner"), Some("2.0.0")));
+        assert!(!binding.allows(Some("partner"), None));
+        assert!(!binding.allows(Some("web"), Some("1.10.0")));
+        assert!(!binding.allows(None, None));
+
+        let registry = PersistedQueryRegistry::default();
+        registry.register(manifest("v1", true)).unwrap();
+        assert!(!registry.has_client_bindings());
+        assert!(registry.allows_client(OperationRef::Id(HASH), None, None));
+
+        let mut bound = manifest("v2", true);
+        bound.operations[0].clients.push(binding);
+        registry.register(bound).unwrap();
+        assert!(registry.has_client_bindings());
+        assert!(registry.allows_client(OperationRef::Id(HASH), Some("partner"), Some("1.9.1")));
+        assert!(!registry.allows_client(OperationRef::Id(HASH), Some("web"), None));
+        // the bodies are bound whatever their insignificant characters
+        for body in [QUERY, "{ __typename }", "{\n  # comment\n  __typename,\n}"] {
+            let id = hex::encode(Sha256::digest(body.as_bytes()));
+            let operation = OperationRef::Body { id: &id, body };
+            assert!(!registry.allows_client(operation, Some("web"), None));
+        }
+        // the operations outside of the manifest are not bound
+        assert!(registry.allows_client(OperationRef::Id("unknown"), Some("web"), None));
+        let operation = OperationRef::Body {
+            id: "unknown",
+            body: "{ me }",
+        };
+        assert!(registry.allows_client(operation, Some("web"), None));
+    }
+
+    #[test]
+    fn it_keeps_the_most_recent_versions() {
+        let registry = PersistedQueryRegistry::default();
+        for i in 0..=MAX_VERSIONS {
+            registry.register(manifest(&i.to_string(), i == 0)).unwrap();
+        }
+
+        let status = registry.status();
+        assert_eq!(status.versions.len(), MAX_VERSIONS);
+        assert_eq!(status.versions[0].version, "1");
+        // the active version is still served after being evicted
+        assert_eq!(status.active.as_deref(), Some("0"));
+        assert_eq!(registry.get(HASH).as_deref(), Some(QUERY));
+    }
+}
@@ -0,0 +1,171 @@
+//! Let the plugins check the query plans before their execution.
+//!
+//! Each plugin can veto the execution of a query plan, or annotate the context of the request,
+//! with [`crate::plugin::Plugin::check_query_plan`]. The plugins are checked in their order, and
+//! the first veto is sent to the client with a 403 status code.
+
+use std::ops::ControlFlow;
+use std::sync::Arc;
+
+use http::StatusCode;
+use tower::BoxError;
+use tower::Layer;
+use tower::Service;
+
+use crate::json_ext::Object;
+use crate::layers::sync_checkpoint::CheckpointService;
+use crate::services::Plugins;
+use crate::ExecutionRequest;
+use crate::ExecutionResponse;
+
+pub(crate) struct QueryPlanChecksLayer {
+    plugins: Arc<Plugins>,
+}
+
+impl QueryPlanChecksLayer {
+    pub(crate) fn new(plugins: Arc<Plugins>) -> Self {
+        Self { plugins }
+    }
+}
+
+impl<S> Layer<S> for QueryPlanChecksLayer
+where
+    S: Service<ExecutionRequest, Response = ExecutionResponse> + Send + 'static,
+    <S as Service<ExecutionRequest>>::Future: Send + 'static,
+    <S as Service<ExecutionRequest>>::Error: Into<BoxError> + Send + 'static,
+{
+    type Service = CheckpointService<S, ExecutionRequest>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        let plugins = self.plugins.clone();
+        CheckpointService::new(
+            move |req: ExecutionRequest| {
+                for (name, plugin) in plugins.iter() {
+                    if let Err(error) = plugin.check_query_plan(&req.query_plan, &req.context) {
+                        tracing::debug!(
+                            "plugin '{}' vetoed the query plan: {}",
+                            name,
+                            error.message
+                        );
+                        return Ok(ControlFlow::Break(
+                            ExecutionResponse::builder()
+                                .error(error)
+                                .extensions(Object::default())
+                                .status_code(StatusCode::FORBIDDEN)
+                                .context(req.context)
+                                .build(),
+                        ));
+                    }
+                }
+                Ok(ControlFlow::Continue(req))
+            },
+            service,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::graphql;
+    use crate::plugin::test::MockExecutionService;
+    use crate::plugin::Plugin;
+    use crate::plugin::PluginInit;
+    use crate::query_planner::PlanNode;
+    use crate::query_planner::QueryPlan;
+    use crate::Context;
+
+    /// Blocks the plans fetching from the `accounts` subgraph for the `mobile` client.
+    struct AccountsPolicy;
+
+    #[async_trait]
+    impl Plugin for AccountsPolicy {
+        type Config = ();
+
+        async fn new(_init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+            Ok(AccountsPolicy)
+        }
+
+        fn check_query_plan(
+            &self,
+            query_plan: &QueryPlan,
+            context: &Context,
+        ) -> Result<(), graphql::Error> {
+            let client: Option<String> = context.get("apollo_router::client_name").unwrap();
+            context
+                .insert("policy::subgraphs", query_plan.subgraphs().join(","))
+                .unwrap();
+            if client.as_deref() == Some("mobile") && query_plan.subgraphs().contains(&"accounts") {
+                return Err(graphql::Error::builder()
+                    .message("the accounts are not available to mobile clients")
+                    .build());
+            }
+            Ok(())
+        }
+    }
+
+    fn request(client: &str) -> ExecutionRequest {
+        let root: PlanNode = serde_json::from_value(json!({
+            "kind": "Fetch",
+            "serviceName": "accounts",
+            "variableUsages": [],
+            "operation": "{ me { name } }",
+            "operationKind": "query"
+        }))
+        .unwrap();
+        let context = Context::new();
+        context
+            .insert("apollo_router::client_name", client.to_string())
+            .unwrap();
+        ExecutionRequest::fake_builder()
+            .query_plan(QueryPlan::fake_builder().root(root).build())
+            .context(context)
+            .build()
+    }
+
+    fn layer() -> QueryPlanChecksLayer {
+        let mut plugins = Plugins::new();
+        plugins.insert(
+            "test.accounts_policy".to_string(),
+            Box::new(AccountsPolicy) as Box<dyn crate::plugin::DynPlugin>,
+        );
+        QueryPlanChecksLayer::new(Arc::new(plugins))
+    }
+
+    #[tokio::test]
+    async fn it_executes_the_plans_accepted_by_the_plugins() {
+        let mut mock_service = MockExecutionService::new();
+        mock_service.expect_call().times(1).returning(|req| {
+            assert_eq!(
+                req.context.get::<_, String>("policy::subgraphs").unwrap(),
+                Some("accounts".to_string())
+            );
+            Ok(ExecutionResponse::fake_builder().build())
+        });
+
+        let response = layer()
+            .layer(mock_service)
+            .oneshot(request("web"))
+            .await
+            .unwrap();
+        assert_eq!(response.response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn it_rejects_the_plans_vetoed_by_a_plugin() {
+        let mut response = layer()
+            .layer(MockExecutionService::new())
+            .oneshot(request("mobile"))
+            .await
+            .unwrap();
+        assert_eq!(response.response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            response.next_response().await.unwrap().errors[0].message,
+            "the accounts are not available to mobile clients"
+        );
+    }
+}
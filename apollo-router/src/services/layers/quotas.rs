@@ -0,0 +1,613 @@
+//! Quotas of operations per client, over daily and monthly windows.
+//!
+//! The number of operations of every client, and their cost when it is enabled, are accounted
+//! per UTC day and month, once the operations are planned so that their cost is computed from
+//! the parsed query. The requests of a client that reached one of its limits are rejected with a
+//! 429 status code, or flagged in the context and let through, depending on the mode.
+//!
+//! The cost and depth of the operation, and the remaining quota of the client, can be exposed in
+//! an extension of the response, so that clients can regulate themselves.
+
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use http::StatusCode;
+use serde::Deserialize;
+use serde::Serialize;
+use tower::BoxError;
+
+use crate::cache::storage::counter;
+use crate::cache::storage::InMemoryStorage;
+use crate::cache::storage::Increment;
+use crate::cache::storage::KeyValueStorage;
+use crate::configuration::QuotaLimits;
+use crate::configuration::QuotaMode;
+use crate::configuration::Quotas;
+use crate::graphql;
+use crate::services::layers::client_identification::ClientInfo;
+use crate::Context;
+use crate::SupergraphResponse;
+
+/// Context key set to `true` for the requests of clients over quota, in the `flag` mode.
+pub(crate) const QUOTA_EXCEEDED_CONTEXT_KEY: &str = "apollo_router::quota_exceeded";
+/// Context key of the [`CostExtension`] of the request, when it is exposed.
+pub(crate) const COST_EXTENSION_CONTEXT_KEY: &str = "apollo_router::cost_extension";
+
+/// Client of the requests that do not identify a verified client.
+const ANONYMOUS_CLIENT: &str = "anonymous";
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Usage of a client over a window.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub(crate) struct WindowUsage {
+    /// The day or the month of the window, e.g. `2022-09-14` or `2022-09`.
+    pub(crate) period: String,
+    pub(crate) operations: u64,
+    pub(crate) cost: u64,
+}
+
+/// Usage of a client over the current day and month, with its limits.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct Usage {
+    pub(crate) client: String,
+    pub(crate) daily: WindowUsage,
+    pub(crate) monthly: WindowUsage,
+    pub(crate) limits: QuotaLimits,
+}
+
+impl Usage {
+    /// What remains of the limits of the client, once `operations` more operations of this cost
+    /// are accounted.
+    fn remaining(&self, operations: u64, cost: u64) -> RemainingQuota {
+        let remaining =
+            |limit: Option<u64>, usage: u64| limit.map(|limit| limit.saturating_sub(usage));
+        RemainingQuota {
+            daily_operations: remaining(
+                self.limits.daily_operations,
+                self.daily.operations + operations,
+            ),
+            monthly_operations: remaining(
+                self.limits.monthly_operations,
+                self.monthly.operations + operations,
+            ),
+            daily_cost: remaining(self.limits.daily_cost, self.daily.cost + cost),
+            monthly_cost: remaining(self.limits.monthly_cost, self.monthly.cost + cost),
+        }
+    }
+
+    /// The first limit that the client reached, if any.
+    fn reached_limit(&self) -> Option<&'static str> {
+        [
+            (
+                "daily operations",
+                self.limits.daily_operations,
+                self.daily.operations,
+            ),
+            (
+                "monthly operations",
+                self.limits.monthly_operations,
+                self.monthly.operations,
+            ),
+            ("daily cost", self.limits.daily_cost, self.daily.cost),
+            ("monthly cost", self.limits.monthly_cost, self.monthly.cost),
+        ]
+        .into_iter()
+        .find(|(_, limit, usage)| matches!(limit, Some(limit) if usage >= limit))
+        .map(|(name, _, _)| name)
+    }
+}
+
+/// The cost and depth of an operation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct OperationCost {
+    /// The number of fields the operation selects, those of its fragments included.
+    pub(crate) cost: u64,
+    /// The nesting depth of the fields of the operation.
+    pub(crate) depth: u64,
+}
+
+/// What remains of the limits of a client. Limits that are not set are left out.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RemainingQuota {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) daily_operations: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) monthly_operations: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) daily_cost: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) monthly_cost: Option<u64>,
+}
+
+/// The extension of the response exposing the cost of the operation to the client.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct CostExtension {
+    pub(crate) cost: u64,
+    pub(crate) depth: u64,
+    pub(crate) remaining: RemainingQuota,
+}
+
+/// Add the [`CostExtension`] of the request to the first part of its response.
+pub(crate) fn expose_cost(extension: &str, response: SupergraphResponse) -> SupergraphResponse {
+    let cost = match response
+        .context
+        .get::<_, CostExtension>(COST_EXTENSION_CONTEXT_KEY)
+        .ok()
+        .flatten()
+        .and_then(|cost| serde_json::to_value(cost).ok())
+    {
+        Some(cost) => cost,
+        None => return response,
+    };
+    let extension = extension.to_string();
+    let mut cost = Some(cost);
+    response.map_stream(move |mut response| {
+        if let Some(cost) = cost.take() {
+            response
+                .extensions
+                .insert(extension.as_str(), serde_json_bytes::Value::from(cost));
+        }
+        response
+    })
+}
+
+/// Accounting of the usage of the clients, enforcing their quotas.
+#[derive(Clone)]
+pub(crate) struct QuotaEnforcer {
+    config: Arc<Quotas>,
+    store: Arc<dyn KeyValueStorage>,
+    /// The usage kept in memory when it is not stored remotely, carried over to the next
+    /// pipeline when the router reloads.
+    memory: Arc<InMemoryStorage>,
+}
+
+impl QuotaEnforcer {
+    /// Quotas accounting the usage in this store if any, or else in this memory.
+    pub(crate) fn new(
+        config: Quotas,
+        memory: Arc<InMemoryStorage>,
+        store: Option<Arc<dyn KeyValueStorage>>,
+    ) -> Self {
+        Self {
+            config: Arc::new(config),
+            store: store.unwrap_or_else(|| memory.clone()),
+            memory,
+        }
+    }
+
+    /// The memory keeping the usage when it is not stored remotely.
+    pub(crate) fn memory(&self) -> Arc<InMemoryStorage> {
+        self.memory.clone()
+    }
+
+    /// The extension of the responses exposing the cost of the operations, if any.
+    pub(crate) fn cost_extension(&self) -> Option<&str> {
+        self.config.extension.as_deref()
+    }
+
+    /// Whether the cost of the operations is accounted or exposed.
+    pub(crate) fn needs_cost(&self) -> bool {
+        self.config.cost || self.config.extension.is_some()
+    }
+
+    /// The key of a counter. When the store spreads the keys across nodes, the client is a hash
+    /// tag, so that the counters of a client are stored together.
+    fn key(&self, counter: &str, client: &str, period: &str) -> String {
+        if self.store.needs_hash_tags() {
+            format!("apollo_router:quota:{}:{{{}}}:{}", counter, client, period)
+        } else {
+            format!("apollo_router:quota:{}:{}:{}", counter, client, period)
+        }
+    }
+
+    fn limits(&self, client: &str) -> &QuotaLimits {
+        self.config
+            .clients
+            .get(client)
+            .unwrap_or(&self.config.limits)
+    }
+
+    /// Usage of a client over the current day and month.
+    pub(crate) async fn usage(&self, client: &str) -> Result<Usage, BoxError> {
+        let [(day, _), (month, _)] = windows(SystemTime::now());
+        let values: Vec<u64> = self
+            .store
+            .get(&[
+                self.key("operations", client, &day),
+                self.key("operations", client, &month),
+                self.key("cost", client, &day),
+                self.key("cost", client, &month),
+            ])
+            .await?
+            .into_iter()
+            .map(counter)
+            .collect();
+        Ok(self.usage_from(client, day, month, &values))
+    }
+
+    /// Usage of a client from the values of its operations and cost counters, for the day and
+    /// the month. The missing values are 0.
+    fn usage_from(&self, client: &str, day: String, month: String, values: &[u64]) -> Usage {
+        let value = |index: usize| values.get(index).copied().unwrap_or_default();
+        Usage {
+            client: client.to_string(),
+            daily: WindowUsage {
+                period: day,
+                operations: value(0),
+                cost: value(2),
+            },
+            monthly: WindowUsage {
+                period: month,
+                operations: value(1),
+                cost: value(3),
+            },
+            limits: self.limits(client).clone(),
+        }
+    }
+
+    /// Account for an operation of this cost, unless its client reached one of its limits in the
+    /// `reject` mode, in which case the response rejecting the request is returned.
+    ///
+    /// The usage is checked and incremented at once, so that concurrent requests can't exceed the
+    /// limits. Only verified clients are accounted by name, the others are accounted together as
+    /// the anonymous client, since they could pick any name.
+    pub(crate) async fn enforce(
+        &self,
+        context: &Context,
+        cost: OperationCost,
+    ) -> Result<Option<SupergraphResponse>, BoxError> {
+        let client = match ClientInfo::from_context(context) {
+            ClientInfo {
+                name: Some(name),
+                verified: true,
+                ..
+            } => name,
+            _ => ANONYMOUS_CLIENT.to_string(),
+        };
+        let limits = self.limits(&client);
+        let accounted_cost = if self.config.cost { cost.cost } else { 0 };
+
+        // in the `reject` mode, the operations of the clients over quota are not accounted
+        let enforced = |limit: Option<u64>| match self.config.mode {
+            QuotaMode::Reject => limit,
+            QuotaMode::Flag => None,
+        };
+        let [(day, day_ttl), (month, month_ttl)] = windows(SystemTime::now());
+        let mut increments = vec![
+            Increment {
+                key: self.key("operations", &client, &day),
+                value: 1,
+                ttl: day_ttl,
+                limit: enforced(limits.daily_operations),
+            },
+            Increment {
+                key: self.key("operations", &client, &month),
+                value: 1,
+                ttl: month_ttl,
+                limit: enforced(limits.monthly_operations),
+            },
+        ];
+        if self.config.cost {
+            increments.push(Increment {
+                key: self.key("cost", &client, &day),
+                value: accounted_cost,
+                ttl: day_ttl,
+                limit: enforced(limits.daily_cost),
+            });
+            increments.push(Increment {
+                key: self.key("cost", &client, &month),
+                value: accounted_cost,
+                ttl: month_ttl,
+                limit: enforced(limits.monthly_cost),
+            });
+        }
+
+        let counters = match self.store.increment(&increments).await {
+            Ok(counters) => counters,
+            Err(e) => {
+                // the quotas are still enforced by this instance while the usage store is unavailable
+                tracing::error!(
+                    "couldn't account the usage of client '{}', accounting it locally: {}",
+                    client,
+                    e
+                );
+                self.memory.increment(&increments).await?
+            }
+        };
+        // the usage before this operation
+        let values: Vec<u64> = increments
+            .iter()
+            .zip(&counters.values)
+            .map(|(increment, value)| {
+                if counters.incremented {
+                    value.saturating_sub(increment.value)
+                } else {
+                    *value
+                }
+            })
+            .collect();
+        let usage = self.usage_from(&client, day, month, &values);
+
+        if self.config.extension.is_some() {
+            let operations = u64::from(counters.incremented);
+            let _ = context.insert(
+                COST_EXTENSION_CONTEXT_KEY,
+                CostExtension {
+                    cost: cost.cost,
+                    depth: cost.depth,
+                    remaining: usage.remaining(operations, accounted_cost * operations),
+                },
+            );
+        }
+
+        if let Some(limit) = usage.reached_limit() {
+            let message = format!("client '{}' reached its quota of {}", client, limit);
+            match self.config.mode {
+                QuotaMode::Reject => {
+                    tracing::debug!("{}, rejecting the request", message);
+                    let response = SupergraphResponse::error_builder()
+                        .errors(vec![graphql::Error::builder()
+                            .message(message)
+                            .code(graphql::ErrorCode::QuotaExceeded)
+                            .build()])
+                        .status_code(StatusCode::TOO_MANY_REQUESTS)
+                        .context(context.clone())
+                        .build()?;
+                    return Ok(Some(response));
+                }
+                QuotaMode::Flag => {
+                    tracing::warn!("{}", message);
+                    let _ = context.insert(QUOTA_EXCEEDED_CONTEXT_KEY, true);
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// The UTC day and month containing this time, with the time to live of their counters.
+fn windows(now: SystemTime) -> [(String, Duration); 2] {
+    // e.g. 2022-09-14T08:30:00Z
+    let timestamp = humantime::format_rfc3339_seconds(now.max(UNIX_EPOCH)).to_string();
+    [
+        (timestamp[..10].to_string(), DAY * 2),
+        (timestamp[..7].to_string(), DAY * 32),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::storage::ShardedStorage;
+    use crate::services::layers::client_identification::CLIENT_NAME_CONTEXT_KEY;
+    use crate::services::layers::client_identification::CLIENT_VERIFIED_CONTEXT_KEY;
+
+    const COST: OperationCost = OperationCost { cost: 3, depth: 2 };
+
+    fn config(mode: QuotaMode) -> Quotas {
+        Quotas {
+            limits: QuotaLimits {
+                daily_operations: Some(2),
+                ..Default::default()
+            },
+            clients: [(
+                "unlimited".to_string(),
+                QuotaLimits {
+                    daily_operations: None,
+                    ..Default::default()
+                },
+            )]
+            .into_iter()
+            .collect(),
+            mode,
+            cost: true,
+            redis_url: None,
+            extension: Some("cost".to_string()),
+        }
+    }
+
+    async fn quotas(mode: QuotaMode) -> QuotaEnforcer {
+        QuotaEnforcer::new(config(mode), Default::default(), None)
+    }
+
+    fn context(client: &str, verified: bool) -> Context {
+        let context = Context::new();
+        context
+            .insert(CLIENT_NAME_CONTEXT_KEY, client.to_string())
+            .unwrap();
+        if verified {
+            context.insert(CLIENT_VERIFIED_CONTEXT_KEY, true).unwrap();
+        }
+        context
+    }
+
+    #[test]
+    fn it_computes_the_windows() {
+        let window = |days: u64| {
+            let [(day, _), (month, _)] = windows(UNIX_EPOCH + DAY * days as u32);
+            (day, month)
+        };
+        assert_eq!(window(0), ("1970-01-01".to_string(), "1970-01".to_string()));
+        assert_eq!(
+            window(19_249),
+            ("2022-09-14".to_string(), "2022-09".to_string())
+        );
+        assert_eq!(
+            window(19_782),
+            ("2024-02-29".to_string(), "2024-02".to_string())
+        );
+        assert_eq!(
+            window(19_783),
+            ("2024-03-01".to_string(), "2024-03".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn it_rejects_clients_over_quota() {
+        let quotas = quotas(QuotaMode::Reject).await;
+
+        for _ in 0..2 {
+            assert!(quotas
+                .enforce(&context("limited", true), COST)
+                .await
+                .unwrap()
+                .is_none());
+        }
+
+        let mut response = quotas
+            .enforce(&context("limited", true), COST)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let response = response.next_response().await.unwrap();
+        assert_eq!(
+            response.errors[0].extensions.get("code"),
+            Some(&serde_json_bytes::Value::from("QUOTA_EXCEEDED"))
+        );
+
+        // the limits of other clients are their own
+        assert!(quotas
+            .enforce(&context("unlimited", true), COST)
+            .await
+            .unwrap()
+            .is_none());
+
+        // the rejected operation is not accounted
+        let usage = quotas.usage("limited").await.unwrap();
+        assert_eq!(usage.daily.operations, 2);
+        assert_eq!(usage.monthly.operations, 2);
+        assert_eq!(usage.daily.cost, 6);
+        assert_eq!(usage.limits.daily_operations, Some(2));
+    }
+
+    #[tokio::test]
+    async fn it_accounts_the_unverified_clients_as_anonymous() {
+        let quotas = quotas(QuotaMode::Reject).await;
+
+        // a client can't pick the name of a client with higher limits
+        for _ in 0..2 {
+            assert!(quotas
+                .enforce(&context("unlimited", false), COST)
+                .await
+                .unwrap()
+                .is_none());
+        }
+        assert!(quotas
+            .enforce(&context("unlimited", false), COST)
+            .await
+            .unwrap()
+            .is_some());
+
+        assert_eq!(
+            quotas
+                .usage(ANONYMOUS_CLIENT)
+                .await
+                .unwrap()
+                .daily
+                .operations,
+            2
+        );
+        assert_eq!(quotas.usage("unlimited").await.unwrap().daily.operations, 0);
+    }
+
+    #[tokio::test]
+    async fn it_tags_the_keys_only_when_the_store_spreads_them() {
+        let quotas = quotas(QuotaMode::Reject).await;
+        assert_eq!(
+            quotas.key("cost", "web", "2022-10"),
+            "apollo_router:quota:cost:web:2022-10"
+        );
+
+        let shards = ShardedStorage::new(
+            &["redis://127.0.0.1:6379".to_string()],
+            vec![Arc::new(InMemoryStorage::default())],
+        );
+        let quotas = QuotaEnforcer::new(
+            config(QuotaMode::Reject),
+            Default::default(),
+            Some(Arc::new(shards)),
+        );
+        assert_eq!(
+            quotas.key("cost", "web", "2022-10"),
+            "apollo_router:quota:cost:{web}:2022-10"
+        );
+    }
+
+    #[tokio::test]
+    async fn it_keeps_the_usage_in_memory_across_reloads() {
+        let quotas = quotas(QuotaMode::Reject).await;
+        assert!(quotas
+            .enforce(&context("limited", true), COST)
+            .await
+            .unwrap()
+            .is_none());
+
+        let reloaded = QuotaEnforcer::new(config(QuotaMode::Reject), quotas.memory(), None);
+        assert_eq!(reloaded.usage("limited").await.unwrap().daily.operations, 1);
+    }
+
+    #[tokio::test]
+    async fn it_exposes_the_cost_of_operations() {
+        let quotas = quotas(QuotaMode::Reject).await;
+        let context = context("limited", true);
+        assert!(quotas.enforce(&context, COST).await.unwrap().is_none());
+
+        let response = SupergraphResponse::fake_builder()
+            .context(context)
+            .build()
+            .unwrap();
+        let response = expose_cost("cost", response).next_response().await.unwrap();
+        assert_eq!(
+            response.extensions.get("cost"),
+            Some(&serde_json_bytes::Value::from(serde_json::json!({
+                "cost": 3,
+                "depth": 2,
+                "remaining": { "dailyOperations": 1 }
+            })))
+        );
+    }
+
+    #[test]
+    fn it_computes_the_cost_only_when_accounted_or_exposed() {
+        let needs_cost = |cost: bool, extension: Option<&str>| {
+            QuotaEnforcer::new(
+                Quotas {
+                    cost,
+                    extension: extension.map(str::to_string),
+                    ..config(QuotaMode::Reject)
+                },
+                Default::default(),
+                None,
+            )
+            .needs_cost()
+        };
+        assert!(!needs_cost(false, None));
+        assert!(needs_cost(true, None));
+        assert!(needs_cost(false, Some("cost")));
+    }
+
+    #[tokio::test]
+    async fn it_flags_clients_over_quota() {
+        let quotas = quotas(QuotaMode::Flag).await;
+
+        for expected in [false, false, true] {
+            let context = context("limited", true);
+            assert!(quotas.enforce(&context, COST).await.unwrap().is_none());
+            assert_eq!(
+                context
+                    .get::<_, bool>(QUOTA_EXCEEDED_CONTEXT_KEY)
+                    .unwrap()
+                    .unwrap_or_default(),
+                expected
+            );
+        }
+        // the flagged operations are accounted
+        assert_eq!(quotas.usage("limited").await.unwrap().daily.operations, 3);
+    }
+}
@@ -0,0 +1,207 @@
+//! Surrogate keys of the responses, for the CDNs purging their cached responses by key.
+//!
+//! The keys are computed from the first part of a response, since the headers are sent with it:
+//! the types of the objects it returns and the entities identified by one of the id fields, then
+//! the keys added by plugins through the context.
+
+use std::sync::Arc;
+
+use http::header::HeaderName;
+use http::header::InvalidHeaderName;
+use http::HeaderValue;
+use indexmap::IndexSet;
+use serde_json_bytes::Value;
+
+use crate::configuration::SurrogateKeys;
+use crate::graphql;
+use crate::plugin::surrogate_keys::surrogate_keys;
+use crate::query_planner::OperationKind;
+use crate::spec::Query;
+use crate::Context;
+use crate::Schema;
+
+#[derive(Clone, Debug)]
+pub(crate) struct SurrogateKeyHeader {
+    config: Arc<SurrogateKeys>,
+    name: HeaderName,
+}
+
+impl SurrogateKeyHeader {
+    pub(crate) fn new(config: SurrogateKeys) -> Result<Self, InvalidHeaderName> {
+        Ok(Self {
+            name: HeaderName::try_from(config.header.as_str())?,
+            config: Arc::new(config),
+        })
+    }
+
+    pub(crate) fn name(&self) -> &HeaderName {
+        &self.name
+    }
+
+    /// The surrogate keys of the first part of a response, if it has any.
+    pub(crate) fn value(
+        &self,
+        query: Option<(&Query, Option<&str>)>,
+        schema: &Schema,
+        context: &Context,
+        response: &graphql::Response,
+    ) -> Option<HeaderValue> {
+        let mut keys: IndexSet<String> = IndexSet::new();
+        let mut add = |key: String| {
+            keys.insert(key);
+        };
+
+        if let Some((query, operation_name)) = query {
+            let root_types = [
+                OperationKind::Query,
+                OperationKind::Mutation,
+                OperationKind::Subscription,
+            ]
+            .map(|kind| schema.root_operation_name(kind));
+            query.visit_typed_objects(
+                response,
+                operation_name,
+                schema,
+                &mut |type_name, object| {
+                    if root_types.contains(&type_name) {
+                        return;
+                    }
+                    if self.config.types {
+                        add(type_name.to_string());
+                    }
+                    if self.config.entities {
+                        let id = self.config.id_fields.iter().find_map(|field| {
+                            match object.get(field.as_str()) {
+                                Some(Value::String(id)) => Some(id.as_str().to_string()),
+                                Some(Value::Number(id)) => Some(id.to_string()),
+                                _ => None,
+                            }
+                        });
+                        if let Some(id) = id {
+                            add(format!("{}:{}", type_name, id));
+                        }
+                    }
+                },
+            );
+        }
+        for key in surrogate_keys(context) {
+            add(key);
+        }
+
+        let mut header = String::new();
+        for key in keys {
+            // header values can only hold visible ASCII characters
+            if key.is_empty() || !key.bytes().all(|byte| byte.is_ascii_graphic()) {
+                continue;
+            }
+            let separator = if header.is_empty() {
+                ""
+            } else {
+                self.config.separator.as_str()
+            };
+            if header.len() + separator.len() + key.len() > self.config.max_size {
+                tracing::debug!(
+                    "the surrogate keys exceed {} bytes, the next ones are not sent",
+                    self.config.max_size
+                );
+                break;
+            }
+            header.push_str(separator);
+            header.push_str(&key);
+        }
+        if header.is_empty() {
+            return None;
+        }
+        HeaderValue::from_str(&header).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+
+    use super::*;
+    use crate::plugin::surrogate_keys::add_surrogate_key;
+    use crate::Configuration;
+
+    const SCHEMA: &str = r#"
+        schema
+            @core(feature: "https://specs.apollo.dev/core/v0.1")
+            @core(feature: "https://specs.apollo.dev/join/v0.1")
+        {
+            query: Query
+        }
+        directive @core(feature: String!) repeatable on SCHEMA
+        directive @join__graph(name: String!, url: String!) on ENUM_VALUE
+        enum join__Graph {
+            PRODUCTS @join__graph(name: "products", url: "http://localhost:4003/graphql")
+        }
+
+        type Query {
+            topProducts: [Product]
+        }
+
+        type Product {
+            upc: String!
+            name: String
+            reviews: [Review]
+        }
+
+        type Review {
+            id: ID!
+            body: String
+        }
+    "#;
+
+    fn header(config: serde_json::Value, context: &Context) -> Option<HeaderValue> {
+        let schema = Schema::parse(SCHEMA, &Configuration::default()).unwrap();
+        let query = Query::parse(
+            "{ topProducts { upc name reviews { id } } }",
+            &schema,
+            &Default::default(),
+        )
+        .unwrap();
+        let response = graphql::Response::builder()
+            .data(json!({
+                "topProducts": [
+                    { "upc": "1", "name": "Table", "reviews": [{ "id": "r1" }, { "id": "r2" }] },
+                    { "upc": "2", "name": "Couch", "reviews": [] },
+                ]
+            }))
+            .build();
+        SurrogateKeyHeader::new(serde_json::from_value(config).unwrap())
+            .unwrap()
+            .value(Some((&query, None)), &schema, context, &response)
+    }
+
+    #[test]
+    fn it_sends_the_keys_of_the_types_and_entities() {
+        let context = Context::new();
+        add_surrogate_key(&context, "tenant:acme");
+        assert_eq!(
+            header(serde_json::json!({ "id_fields": ["id", "upc"] }), &context).unwrap(),
+            "Product Product:1 Review Review:r1 Review:r2 Product:2 tenant:acme"
+        );
+        assert_eq!(
+            header(
+                serde_json::json!({ "types": false, "separator": ",", "id_fields": ["upc"] }),
+                &Context::new()
+            )
+            .unwrap(),
+            "Product:1,Product:2"
+        );
+    }
+
+    #[test]
+    fn it_limits_the_size_of_the_header() {
+        assert_eq!(
+            header(serde_json::json!({ "max_size": 20 }), &Context::new()).unwrap(),
+            "Product Product:1"
+        );
+        assert!(header(
+            serde_json::json!({ "types": false, "entities": false }),
+            &Context::new()
+        )
+        .is_none());
+    }
+}
@@ -19,11 +19,16 @@ pub(crate) use crate::services::subgraph::Response as SubgraphResponse;
 pub(crate) use crate::services::supergraph::Request as SupergraphRequest;
 pub(crate) use crate::services::supergraph::Response as SupergraphResponse;
 
+pub(crate) mod dns;
 pub mod execution;
 mod execution_service;
 pub(crate) mod layers;
 pub(crate) mod new_service;
+pub(crate) mod progressive_override;
+pub(crate) mod proxy;
 pub(crate) mod query_planner;
+pub(crate) mod request_size;
+pub(crate) mod request_stages;
 pub mod subgraph;
 pub(crate) mod subgraph_service;
 pub mod supergraph;
@@ -0,0 +1,267 @@
+//! Progressive overrides: fields migrated from one subgraph to another for a share of the requests.
+//!
+//! The query planner does not know about override labels, so the router plans every request with
+//! a variant of the supergraph where the overrides are resolved for the labels it enabled.
+
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use http::HeaderMap;
+use itertools::Itertools;
+use sha2::Digest;
+use sha2::Sha256;
+use tokio::sync::OnceCell;
+use tower::BoxError;
+
+use super::create_query_planner;
+use crate::error::ServiceBuildError;
+use crate::query_planner::BridgeQueryPlanner;
+use crate::query_planner::CachingQueryPlanner;
+use crate::query_planner::PlanStorage;
+use crate::Configuration;
+use crate::Context;
+use crate::Schema;
+
+/// Plugins can insert a list of labels under this key to enable them for a request, whatever
+/// their rollout percentage.
+pub(crate) const ENABLED_LABELS_CONTEXT_KEY: &str = "apollo_override::enabled_labels";
+
+type PlannerCell = Arc<OnceCell<CachingQueryPlanner<BridgeQueryPlanner>>>;
+
+/// Query planners of the variants of a supergraph, by enabled labels.
+#[derive(Clone)]
+pub(crate) struct OverrideQueryPlanners {
+    schema: Arc<Schema>,
+    configuration: Arc<Configuration>,
+    plan_cache_limit: usize,
+    plan_storage: Option<PlanStorage>,
+    labels: BTreeSet<String>,
+    // the lock is only held to find the cell of a planner, not while the planner is created
+    planners: Arc<Mutex<HashMap<BTreeSet<String>, PlannerCell>>>,
+}
+
+impl OverrideQueryPlanners {
+    /// Returns `None` if the supergraph has no progressive override.
+    pub(crate) fn new(
+        schema: Arc<Schema>,
+        configuration: Arc<Configuration>,
+        plan_cache_limit: usize,
+        plan_storage: Option<PlanStorage>,
+    ) -> Option<Self> {
+        let labels = schema.override_labels();
+        if labels.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            schema,
+            configuration,
+            plan_cache_limit,
+            plan_storage,
+            labels,
+            planners: Default::default(),
+        })
+    }
+
+    /// Labels enabled for a request.
+    ///
+    /// A single bucket is drawn for the request, so a label rolled out to 50% of the requests is
+    /// enabled whenever a label rolled out to 25% is.
+    pub(crate) fn enabled_labels(
+        &self,
+        headers: &HeaderMap,
+        context: &Context,
+    ) -> Result<BTreeSet<String>, BoxError> {
+        let forced: Vec<String> = context.get(ENABLED_LABELS_CONTEXT_KEY)?.unwrap_or_default();
+        let bucket = self.bucket(headers);
+
+        Ok(self
+            .labels
+            .iter()
+            .filter(|label| forced.contains(label) || bucket < self.percentage(label))
+            .cloned()
+            .collect())
+    }
+
+    /// Query planner for the supergraph with these labels enabled.
+    ///
+    /// Planners are created on first use, since there is one per combination of labels. Once
+    /// `max_planners` combinations are in use, the other combinations are planned with no label
+    /// enabled.
+    pub(crate) async fn query_planner(
+        &self,
+        enabled: BTreeSet<String>,
+    ) -> Result<CachingQueryPlanner<BridgeQueryPlanner>, ServiceBuildError> {
+        let (enabled, cell) = {
+            let mut planners = self.planners.lock().expect("lock poisoned");
+            match planners.get(&enabled) {
+                Some(cell) => (enabled, cell.clone()),
+                None if planners.len() < self.configuration.progressive_override.max_planners
+                    || enabled.is_empty() =>
+                {
+                    let cell = PlannerCell::default();
+                    planners.insert(enabled.clone(), cell.clone());
+                    (enabled, cell)
+                }
+                None => {
+                    tracing::debug!(
+                        labels = ?enabled,
+                        "too many combinations of override labels, planning without labels"
+                    );
+                    let enabled = BTreeSet::new();
+                    let cell = planners.entry(enabled.clone()).or_default().clone();
+                    (enabled, cell)
+                }
+            }
+        };
+
+        cell.get_or_try_init(|| async {
+            tracing::debug!(labels = ?enabled, "creating the query planner for override labels");
+            let schema = Schema::parse(
+                &self.schema.with_override_labels(&enabled),
+                &self.configuration,
+            )
+            .map_err(|e| {
+                ServiceBuildError::ProgressiveOverride(enabled.iter().join(", "), e.to_string())
+            })?;
+            create_query_planner(
+                Arc::new(schema),
+                self.configuration.clone(),
+                self.plan_cache_limit,
+                self.plan_storage.clone(),
+            )
+            .await
+        })
+        .await
+        .map(Clone::clone)
+    }
+
+    fn percentage(&self, label: &str) -> f64 {
+        if let Some(percentage) = self.configuration.progressive_override.labels.get(label) {
+            return *percentage;
+        }
+        label
+            .strip_prefix("percent(")
+            .and_then(|rest| rest.strip_suffix(')'))
+            .and_then(|percentage| percentage.trim().parse().ok())
+            .unwrap_or(0.0)
+    }
+
+    /// A number in `[0, 100)`.
+    fn bucket(&self, headers: &HeaderMap) -> f64 {
+        let value = match &self.configuration.progressive_override.bucket_header {
+            Some(header) => match headers.get(header.as_str()) {
+                // hashed with a fixed algorithm, so that all the router instances agree
+                Some(value) => {
+                    let digest = Sha256::digest(value.as_bytes());
+                    u64::from_be_bytes(digest[..8].try_into().expect("the digest is 32 bytes"))
+                }
+                None => rand::random(),
+            },
+            None => rand::random(),
+        };
+        (value % 10_000) as f64 / 100.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+    use serde_json::json;
+
+    use super::*;
+
+    fn override_planners(configuration: serde_json::Value) -> OverrideQueryPlanners {
+        let configuration: Configuration = serde_json::from_value(configuration).unwrap();
+        let schema = Schema::parse(
+            include_str!("../testdata/progressive_override.graphql"),
+            &configuration,
+        )
+        .unwrap();
+        OverrideQueryPlanners::new(Arc::new(schema), Arc::new(configuration), 10, None).unwrap()
+    }
+
+    #[test]
+    fn it_uses_schema_percentages() {
+        let planners = override_planners(json!({}));
+        assert_eq!(planners.percentage("percent(25)"), 25.0);
+        assert_eq!(planners.percentage("percent(12.5)"), 12.5);
+        assert_eq!(planners.percentage("new_inventory"), 0.0);
+    }
+
+    #[test]
+    fn it_uses_configured_percentages() {
+        let planners = override_planners(json!({
+            "progressive_override": { "labels": { "percent(25)": 100 } }
+        }));
+        assert_eq!(
+            planners
+                .enabled_labels(&HeaderMap::new(), &Context::new())
+                .unwrap(),
+            BTreeSet::from(["percent(25)".to_string()])
+        );
+
+        let planners = override_planners(json!({
+            "progressive_override": { "labels": { "percent(25)": 0 } }
+        }));
+        assert!(planners
+            .enabled_labels(&HeaderMap::new(), &Context::new())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn plugins_can_enable_labels() {
+        let planners = override_planners(json!({
+            "progressive_override": { "labels": { "percent(25)": 0 } }
+        }));
+        let context = Context::new();
+        context
+            .insert(ENABLED_LABELS_CONTEXT_KEY, vec!["percent(25)".to_string()])
+            .unwrap();
+        assert_eq!(
+            planners
+                .enabled_labels(&HeaderMap::new(), &context)
+                .unwrap(),
+            BTreeSet::from(["percent(25)".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn it_limits_the_number_of_planners() {
+        let planners = override_planners(json!({
+            "progressive_override": { "max_planners": 1 }
+        }));
+        planners.query_planner(BTreeSet::new()).await.unwrap();
+        planners
+            .query_planner(BTreeSet::from(["percent(25)".to_string()]))
+            .await
+            .unwrap();
+        assert_eq!(
+            planners
+                .planners
+                .lock()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>(),
+            vec![BTreeSet::new()]
+        );
+    }
+
+    #[test]
+    fn it_buckets_by_header() {
+        let planners = override_planners(json!({
+            "progressive_override": { "bucket_header": "x-user-id" }
+        }));
+        let mut headers = HeaderMap::new();
+        headers.insert("x-user-id", HeaderValue::from_static("1234"));
+        let bucket = planners.bucket(&headers);
+        assert!((0.0..100.0).contains(&bucket));
+        for _ in 0..10 {
+            assert_eq!(planners.bucket(&headers), bucket);
+        }
+    }
+}
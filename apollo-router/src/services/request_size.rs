@@ -0,0 +1,251 @@
+//! Approximate size of the data held by each request: its body, the responses of its subgraphs and
+//! its response, accounted in the [`Context`] as the request goes through the router.
+//!
+//! The sizes are recorded in the telemetry and the slow operations log once the response is sent,
+//! and the requests going over the `traffic_shaping.router.max_request_bytes` cap stop fetching
+//! from the subgraphs. The response is built from the subgraph responses, so it is reported but not
+//! counted against the cap.
+
+use std::cell::Cell;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::graphql;
+use crate::json_ext::PathElement;
+use crate::json_ext::Value;
+use crate::Context;
+
+/// Context key of the [`RequestSize`] of the request.
+pub(crate) const REQUEST_SIZE_CONTEXT_KEY: &str = "apollo_router::request_size";
+/// Context key of the maximum size of the request, in bytes.
+pub(crate) const MAX_REQUEST_BYTES_CONTEXT_KEY: &str = "apollo_router::max_request_bytes";
+/// Context key set to `true` when subgraph fetches were aborted by the maximum size of the request.
+pub(crate) const REQUEST_BYTES_EXCEEDED_CONTEXT_KEY: &str = "apollo_router::request_bytes_exceeded";
+
+/// Approximate size of the data held by a request, in bytes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct RequestSize {
+    /// The GraphQL request received.
+    pub(crate) request: u64,
+    /// The bodies of the subgraph responses.
+    pub(crate) subgraph_responses: u64,
+    /// The parts of the response sent, as JSON.
+    pub(crate) response: u64,
+}
+
+impl RequestSize {
+    /// The data held by the request, compared with its maximum size: the response is made of the
+    /// data of the subgraph responses, which are already counted.
+    pub(crate) fn total(&self) -> u64 {
+        self.request + self.subgraph_responses
+    }
+
+    /// The size accounted in the context so far.
+    pub(crate) fn from_context(context: &Context) -> Self {
+        context
+            .get(REQUEST_SIZE_CONTEXT_KEY)
+            .ok()
+            .flatten()
+            .unwrap_or_default()
+    }
+}
+
+fn add(context: &Context, update: impl Fn(&mut RequestSize)) -> RequestSize {
+    let updated = Cell::new(RequestSize::default());
+    if let Err(e) = context.upsert(REQUEST_SIZE_CONTEXT_KEY, |mut size: RequestSize| {
+        update(&mut size);
+        updated.set(size);
+        size
+    }) {
+        tracing::error!("could not account for the size of the request: {}", e);
+    }
+    updated.get()
+}
+
+/// Account for the GraphQL request received.
+pub(crate) fn record_request(context: &Context, request: &graphql::Request) {
+    let bytes = request.query.as_ref().map(String::len).unwrap_or_default()
+        + request
+            .operation_name
+            .as_ref()
+            .map(String::len)
+            .unwrap_or_default()
+        + request
+            .variables
+            .iter()
+            .map(|(name, value)| name.as_str().len() + estimated_size(value))
+            .sum::<usize>()
+        + request
+            .extensions
+            .iter()
+            .map(|(name, value)| name.as_str().len() + estimated_size(value))
+            .sum::<usize>();
+    add(context, |size| size.request += bytes as u64);
+}
+
+/// Account for the body of a subgraph response, returning the maximum size of the request if it
+/// is now exceeded.
+pub(crate) fn record_subgraph_response(context: &Context, bytes: usize) -> Result<(), u64> {
+    let size = add(context, |size| size.subgraph_responses += bytes as u64);
+    check(context, size)
+}
+
+/// Account for a part of the response sent.
+pub(crate) fn record_response(context: &Context, response: &graphql::Response) {
+    let mut bytes = response
+        .data
+        .as_ref()
+        .map(estimated_size)
+        .unwrap_or_default()
+        + response
+            .incremental
+            .iter()
+            .filter_map(|incremental| incremental.data.as_ref())
+            .map(estimated_size)
+            .sum::<usize>();
+    bytes += response
+        .errors
+        .iter()
+        .chain(
+            response
+                .incremental
+                .iter()
+                .flat_map(|incremental| incremental.errors.iter()),
+        )
+        .map(estimated_error_size)
+        .sum::<usize>();
+    add(context, |size| size.response += bytes as u64);
+}
+
+/// Returns the maximum size of the request if it is exceeded, before fetching from a subgraph.
+pub(crate) fn check_size(context: &Context) -> Result<(), u64> {
+    check(context, RequestSize::from_context(context))
+}
+
+/// The bytes a subgraph response can still hold before the maximum size of the request is
+/// exceeded, if the request has one.
+pub(crate) fn remaining(context: &Context) -> Option<u64> {
+    max_request_bytes(context)
+        .map(|max| max.saturating_sub(RequestSize::from_context(context).total()))
+}
+
+fn max_request_bytes(context: &Context) -> Option<u64> {
+    context
+        .get::<_, u64>(MAX_REQUEST_BYTES_CONTEXT_KEY)
+        .ok()
+        .flatten()
+}
+
+fn check(context: &Context, size: RequestSize) -> Result<(), u64> {
+    match max_request_bytes(context) {
+        Some(max) if size.total() > max => {
+            let _ = context.insert(REQUEST_BYTES_EXCEEDED_CONTEXT_KEY, true);
+            Err(max)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Size of the JSON serialization of an error, without serializing it. The locations are counted
+/// as 32 bytes.
+fn estimated_error_size(error: &graphql::Error) -> usize {
+    let path = error
+        .path
+        .as_ref()
+        .map(|path| {
+            path.iter()
+                .map(|element| match element {
+                    PathElement::Key(key) => key.len() + 3,
+                    PathElement::Index(_) => 8,
+                    PathElement::Flatten => 4,
+                })
+                .sum::<usize>()
+                + 2
+        })
+        .unwrap_or(4);
+    let extensions = error
+        .extensions
+        .iter()
+        .map(|(name, value)| name.as_str().len() + 3 + estimated_size(value))
+        .sum::<usize>();
+    error.message.len() + error.locations.len() * 32 + path + extensions + 40
+}
+
+/// Size of the JSON serialization of a value, without serializing it. Numbers are counted as 8
+/// bytes.
+fn estimated_size(value: &Value) -> usize {
+    match value {
+        Value::Null | Value::Bool(true) => 4,
+        Value::Bool(false) => 5,
+        Value::Number(_) => 8,
+        Value::String(s) => s.as_str().len() + 2,
+        Value::Array(values) => {
+            values.iter().map(estimated_size).sum::<usize>() + values.len().saturating_sub(1) + 2
+        }
+        Value::Object(fields) => {
+            fields
+                .iter()
+                .map(|(name, value)| name.as_str().len() + 3 + estimated_size(value))
+                .sum::<usize>()
+                + fields.len().saturating_sub(1)
+                + 2
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+
+    use super::*;
+
+    #[test]
+    fn it_estimates_the_size_of_values() {
+        let value = json!({ "me": { "name": "Ada", "friends": [null, true], "age": 36 } });
+        let serialized = serde_json::to_vec(&value).unwrap().len();
+        assert_eq!(estimated_size(&json!("Ada")), 5);
+        assert_eq!(estimated_size(&json!([null, true])), 11);
+        // only the size of the numbers is approximated
+        assert_eq!(estimated_size(&value), serialized + 8 - 2);
+    }
+
+    #[test]
+    fn it_stops_requests_over_their_maximum_size() {
+        let context = Context::new();
+        record_request(
+            &context,
+            &graphql::Request::builder()
+                .query("{ me { name } }".to_string())
+                .build(),
+        );
+        assert_eq!(RequestSize::from_context(&context).request, 15);
+        assert_eq!(record_subgraph_response(&context, 1_000), Ok(()));
+
+        context
+            .insert(MAX_REQUEST_BYTES_CONTEXT_KEY, 2_000u64)
+            .unwrap();
+        assert_eq!(check_size(&context), Ok(()));
+        assert_eq!(record_subgraph_response(&context, 1_000), Err(2_000));
+        assert_eq!(check_size(&context), Err(2_000));
+        assert_eq!(
+            context
+                .get::<_, bool>(REQUEST_BYTES_EXCEEDED_CONTEXT_KEY)
+                .unwrap(),
+            Some(true)
+        );
+
+        record_response(
+            &context,
+            &graphql::Response::builder()
+                .data(json!({ "me": { "name": "Ada" } }))
+                .build(),
+        );
+        let size = RequestSize::from_context(&context);
+        assert_eq!(size.subgraph_responses, 2_000);
+        assert_eq!(size.response, 21);
+        // the response is not counted again
+        assert_eq!(size.total(), 2_015);
+        assert_eq!(remaining(&context), Some(0));
+    }
+}
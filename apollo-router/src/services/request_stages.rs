@@ -0,0 +1,206 @@
+//! Stages of each request and their timings, recorded as the request goes through the router:
+//! query parsing, query planning, execution and each subgraph fetch.
+//!
+//! The stages are only recorded for the requests with a router timeout, in the extensions of
+//! their [`Context`]. When such a request times out, the stages still in progress are logged,
+//! along with the time spent in each stage.
+
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::Context;
+
+/// A stage of a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Stage<'a> {
+    /// Parsing of the query.
+    Parsing,
+    /// Planning of the query.
+    QueryPlanning,
+    /// Execution of the query plan, until the primary response.
+    Execution,
+    /// Fetch from a subgraph.
+    Fetch(&'a str),
+}
+
+impl fmt::Display for Stage<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Parsing => f.write_str("parsing"),
+            Stage::QueryPlanning => f.write_str("query_planning"),
+            Stage::Execution => f.write_str("execution"),
+            Stage::Fetch(service_name) => write!(f, "fetch from subgraph '{}'", service_name),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RecordedStage {
+    name: String,
+    execution: bool,
+    start: Instant,
+    end: Option<Instant>,
+    /// Whether the stage was dropped before it was over, e.g. a cancelled fetch.
+    cancelled: bool,
+}
+
+/// The stages of a request, in the extensions of its context.
+#[derive(Debug, Default)]
+pub(crate) struct RequestStages {
+    stages: Mutex<Vec<RecordedStage>>,
+}
+
+impl RequestStages {
+    /// Record the stages of the request of this context from now on.
+    pub(crate) fn track(context: &Context) -> Arc<Self> {
+        let stages = Arc::new(Self::default());
+        context.extensions().insert(stages.clone());
+        stages
+    }
+
+    fn start(self: &Arc<Self>, stage: Stage<'_>) -> StageGuard {
+        let mut stages = self.stages.lock().unwrap();
+        stages.push(RecordedStage {
+            name: stage.to_string(),
+            execution: stage == Stage::Execution,
+            start: Instant::now(),
+            end: None,
+            cancelled: false,
+        });
+        StageGuard {
+            stages: self.clone(),
+            index: stages.len() - 1,
+            finished: false,
+        }
+    }
+
+    /// The stages of the request in progress, and the time spent in each stage, e.g. `in fetch
+    /// from subgraph 'products' (parsing: 1ms, query_planning: 12ms, execution: 30.001s (in
+    /// progress), fetch from subgraph 'products': 29.98s (in progress))`.
+    pub(crate) fn report(&self) -> String {
+        let stages = self.stages.lock().unwrap();
+        let now = Instant::now();
+        let in_progress: Vec<&str> = stages
+            .iter()
+            .filter(|stage| stage.end.is_none())
+            // the execution is in progress during the subgraph fetches
+            .filter(|stage| {
+                !stage.execution
+                    || stages
+                        .iter()
+                        .all(|other| other.end.is_some() || other.start < stage.start)
+            })
+            .map(|stage| stage.name.as_str())
+            .collect();
+        let timings = stages
+            .iter()
+            .map(|stage| {
+                format!(
+                    "{}: {:?}{}",
+                    stage.name,
+                    stage.end.unwrap_or(now).duration_since(stage.start),
+                    match (stage.end, stage.cancelled) {
+                        (None, _) => " (in progress)",
+                        (Some(_), true) => " (cancelled)",
+                        (Some(_), false) => "",
+                    }
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        if in_progress.is_empty() {
+            format!(
+                "after {} ({})",
+                stages
+                    .iter()
+                    .max_by_key(|stage| stage.end)
+                    .map(|stage| stage.name.as_str())
+                    .unwrap_or_default(),
+                timings
+            )
+        } else {
+            format!("in {} ({})", in_progress.join(" and "), timings)
+        }
+    }
+}
+
+/// Ends a stage when it is dropped, marking it as cancelled if it was not finished.
+struct StageGuard {
+    stages: Arc<RequestStages>,
+    index: usize,
+    finished: bool,
+}
+
+impl Drop for StageGuard {
+    fn drop(&mut self) {
+        if let Some(stage) = self.stages.stages.lock().unwrap().get_mut(self.index) {
+            stage.end = Some(Instant::now());
+            stage.cancelled = !self.finished;
+        }
+    }
+}
+
+/// Record a stage of the request while the future runs, if the stages of the request are
+/// tracked.
+pub(crate) async fn record<F: Future>(context: &Context, stage: Stage<'_>, future: F) -> F::Output {
+    let stages = context.extensions().get::<Arc<RequestStages>>().cloned();
+    let stages = match stages {
+        Some(stages) => stages,
+        None => return future.await,
+    };
+
+    let mut guard = stages.start(stage);
+    let output = future.await;
+    guard.finished = true;
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_reports_the_stages_in_progress() {
+        let context = Context::new();
+        // not tracked
+        record(&context, Stage::Parsing, async {}).await;
+        assert!(context.extensions().get::<Arc<RequestStages>>().is_none());
+
+        let stages = RequestStages::track(&context);
+        record(&context, Stage::Parsing, async {}).await;
+        let in_fetch = record(&context, Stage::Execution, async {
+            record(&context, Stage::Fetch("products"), async {
+                stages.report()
+            })
+            .await
+        })
+        .await;
+        assert!(in_fetch.starts_with("in fetch from subgraph 'products' (parsing: "));
+        assert!(in_fetch.contains("execution: "));
+        assert!(in_fetch.ends_with(" (in progress))"));
+        assert!(stages.report().starts_with("after "));
+    }
+
+    #[tokio::test]
+    async fn it_reports_the_cancelled_stages() {
+        let context = Context::new();
+        let stages = RequestStages::track(&context);
+        let fetch = record(
+            &context,
+            Stage::Fetch("products"),
+            futures::future::pending::<()>(),
+        );
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(1), fetch)
+                .await
+                .is_err()
+        );
+        let report = stages.report();
+        assert!(report.starts_with("after fetch from subgraph 'products' "));
+        assert!(report.ends_with(" (cancelled))"), "{}", report);
+    }
+}
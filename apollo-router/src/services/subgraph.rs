@@ -26,8 +26,12 @@ pub struct Request {
     /// Original request to the Router.
     pub originating_request: Arc<http::Request<graphql::Request>>,
 
+    /// The HTTP request sent to the subgraph, which plugins can modify, headers and body.
     pub subgraph_request: http::Request<graphql::Request>,
 
+    /// Name of the subgraph the request is sent to.
+    pub subgraph_name: String,
+
     pub operation_kind: OperationKind,
 
     pub context: Context,
@@ -42,12 +46,14 @@ impl Request {
     fn new(
         originating_request: Arc<http::Request<graphql::Request>>,
         subgraph_request: http::Request<graphql::Request>,
+        subgraph_name: String,
         operation_kind: OperationKind,
         context: Context,
     ) -> Request {
         Self {
             originating_request,
             subgraph_request,
+            subgraph_name,
             operation_kind,
             context,
         }
@@ -62,12 +68,14 @@ impl Request {
     fn fake_new(
         originating_request: Option<Arc<http::Request<graphql::Request>>>,
         subgraph_request: Option<http::Request<graphql::Request>>,
+        subgraph_name: Option<String>,
         operation_kind: Option<OperationKind>,
         context: Option<Context>,
     ) -> Request {
         Request::new(
             originating_request.unwrap_or_default(),
             subgraph_request.unwrap_or_default(),
+            subgraph_name.unwrap_or_default(),
             operation_kind.unwrap_or(OperationKind::Query),
             context.unwrap_or_default(),
         )
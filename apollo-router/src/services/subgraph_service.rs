@@ -9,6 +9,9 @@ use ::serde::Deserialize;
 use async_compression::tokio::write::BrotliEncoder;
 use async_compression::tokio::write::GzipEncoder;
 use async_compression::tokio::write::ZlibEncoder;
+use bytes::BufMut;
+use bytes::Bytes;
+use bytes::BytesMut;
 use futures::future::BoxFuture;
 use global::get_text_map_propagator;
 use http::header::ACCEPT;
@@ -17,7 +20,10 @@ use http::header::CONTENT_TYPE;
 use http::header::{self};
 use http::HeaderMap;
 use http::HeaderValue;
-use hyper::client::HttpConnector;
+use http::Method;
+use http::StatusCode;
+use http::Uri;
+use hyper::body::HttpBody;
 use hyper_rustls::HttpsConnector;
 use opentelemetry::global;
 use opentelemetry::trace::SpanKind;
@@ -35,8 +41,18 @@ use tracing::Span;
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 
 use super::Plugins;
+use crate::canary;
+use crate::canary::SubgraphMocks;
+use crate::error::retry_after;
 use crate::error::FetchError;
 use crate::graphql;
+use crate::plugins::authentication::sigv4::SigV4Config;
+use crate::plugins::telemetry::Queued;
+use crate::query_planner::OperationKind;
+use crate::services::dns::DiscoveryConnector;
+use crate::services::proxy::ProxyConnector;
+use crate::services::proxy::ProxySettings;
+use crate::services::request_size;
 
 #[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema, Copy)]
 #[serde(rename_all = "lowercase")]
@@ -62,25 +78,78 @@ impl Display for Compression {
 /// Client for interacting with subgraphs.
 #[derive(Clone)]
 pub(crate) struct SubgraphService {
-    client: Decompression<hyper::Client<HttpsConnector<HttpConnector>>>,
+    client: Decompression<hyper::Client<HttpsConnector<ProxyConnector>>>,
     service: Arc<String>,
+    proxy: Option<Arc<ProxySettings>>,
+    get_max_url_length: Option<usize>,
+    signer: Option<Arc<SigV4Config>>,
 }
 
 impl SubgraphService {
     pub(crate) fn new(service: impl Into<String>) -> Self {
+        Self::with_connector(
+            service,
+            ProxyConnector::new(DiscoveryConnector::new(None), None),
+        )
+    }
+
+    /// Open the connections to the subgraph with the connector, e.g. through a proxy.
+    pub(crate) fn with_connector(service: impl Into<String>, connector: ProxyConnector) -> Self {
+        let proxy = connector.proxy();
         let connector = hyper_rustls::HttpsConnectorBuilder::new()
             .with_native_roots()
             .https_or_http()
             .enable_http1()
             .enable_http2()
-            .build();
+            .wrap_connector(connector);
 
         Self {
             client: ServiceBuilder::new()
                 .layer(DecompressionLayer::new())
                 .service(hyper::Client::builder().build(connector)),
             service: Arc::new(service.into()),
+            proxy,
+            get_max_url_length: None,
+            signer: None,
+        }
+    }
+
+    /// Send the queries as GET requests, for HTTP caches to cache them, when their URL is at
+    /// most that many bytes long.
+    pub(crate) fn with_get_max_url_length(mut self, get_max_url_length: Option<usize>) -> Self {
+        self.get_max_url_length = get_max_url_length;
+        self
+    }
+
+    /// Sign the requests with AWS Signature Version 4.
+    pub(crate) fn with_signer(mut self, signer: Option<SigV4Config>) -> Self {
+        self.signer = signer.map(Arc::new);
+        self
+    }
+
+    /// Send a GET request to the subgraph, through the same connections, proxy and signer as its
+    /// queries, e.g. to probe its health endpoint. Returns the status code of the response.
+    pub(crate) async fn get(&self, uri: Uri) -> Result<StatusCode, BoxError> {
+        let mut request = http::Request::get(uri).body(Vec::new())?;
+        if let Some(authorization) = self
+            .proxy
+            .as_ref()
+            .and_then(|proxy| proxy.forwarding_authorization(request.uri()))
+        {
+            request
+                .headers_mut()
+                .insert(header::PROXY_AUTHORIZATION, authorization);
         }
+        if let Some(signer) = &self.signer {
+            signer.sign(&mut request)?;
+        }
+        let response = self
+            .client
+            .clone()
+            .oneshot(request.map(hyper::Body::from))
+            .await
+            .map_err(|e| Box::new(e) as BoxError)?;
+        Ok(response.status())
     }
 }
 
@@ -95,40 +164,95 @@ impl tower::Service<crate::SubgraphRequest> for SubgraphService {
             .map(|res| res.map_err(|e| Box::new(e) as BoxError))
     }
 
-    fn call(&mut self, request: crate::SubgraphRequest) -> Self::Future {
+    fn call(&mut self, mut request: crate::SubgraphRequest) -> Self::Future {
+        // the request is no longer queued once it reaches the subgraph service
+        request.subgraph_request.extensions_mut().remove::<Queued>();
+
+        // the canary operations of a new pipeline can be answered with mocked responses
+        if let Some(response) = request
+            .originating_request
+            .extensions()
+            .get::<SubgraphMocks>()
+            .and_then(|mocks| mocks.respond(&self.service, request.subgraph_request.body()))
+        {
+            let response = crate::SubgraphResponse::new_from_response(
+                http::Response::new(response),
+                request.context,
+            );
+            return Box::pin(async { Ok(response) });
+        }
+
         let crate::SubgraphRequest {
             subgraph_request,
+            operation_kind,
             context,
             ..
         } = request;
 
         let mut client = self.client.clone();
         let service_name = (*self.service).to_owned();
+        let proxy = self.proxy.clone();
+        let get_max_url_length = self.get_max_url_length;
+        let signer = self.signer.clone();
 
         Box::pin(async move {
-            let (parts, body) = subgraph_request.into_parts();
+            // the request already holds too much data to fetch more
+            if let Err(limit) = request_size::check_size(&context) {
+                return Err(FetchError::SubrequestSizeLimitExceeded {
+                    service: service_name,
+                    limit,
+                }
+                .into());
+            }
+            let (mut parts, body) = subgraph_request.into_parts();
 
-            let body = serde_json::to_string(&body).expect("JSON serialization should not fail");
+            let get_uri = get_max_url_length
+                .filter(|_| operation_kind == OperationKind::Query)
+                .and_then(|max_length| get_uri(&parts.uri, &body, max_length));
+            let mut request = match get_uri {
+                Some(uri) => {
+                    parts.method = Method::GET;
+                    parts.uri = uri;
+                    parts.headers.remove(CONTENT_ENCODING);
+                    parts.headers.remove(CONTENT_TYPE);
+                    http::request::Request::from_parts(parts, Vec::new())
+                }
+                None => {
+                    let body =
+                        serde_json::to_string(&body).expect("JSON serialization should not fail");
 
-            let compressed_body = compress(body, &parts.headers)
-                .instrument(tracing::debug_span!("body_compression"))
-                .await
-                .map_err(|err| {
-                    tracing::error!(compress_error = format!("{:?}", err).as_str());
+                    let compressed_body = compress(body, &parts.headers)
+                        .instrument(tracing::debug_span!("body_compression"))
+                        .await
+                        .map_err(|err| {
+                            tracing::error!(compress_error = format!("{:?}", err).as_str());
 
-                    FetchError::CompressionError {
-                        service: service_name.clone(),
-                        reason: err.to_string(),
-                    }
-                })?;
+                            FetchError::CompressionError {
+                                service: service_name.clone(),
+                                reason: err.to_string(),
+                            }
+                        })?;
 
-            let mut request = http::request::Request::from_parts(parts, compressed_body.into());
+                    let mut request = http::request::Request::from_parts(parts, compressed_body);
+                    request
+                        .headers_mut()
+                        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                    request
+                }
+            };
             let app_json: HeaderValue = HeaderValue::from_static("application/json");
             let app_graphql_json: HeaderValue =
                 HeaderValue::from_static("application/graphql+json");
-            request.headers_mut().insert(CONTENT_TYPE, app_json.clone());
             request.headers_mut().insert(ACCEPT, app_json);
             request.headers_mut().append(ACCEPT, app_graphql_json);
+            if let Some(authorization) = proxy
+                .as_ref()
+                .and_then(|proxy| proxy.forwarding_authorization(request.uri()))
+            {
+                request
+                    .headers_mut()
+                    .insert(header::PROXY_AUTHORIZATION, authorization);
+            }
 
             get_text_map_propagator(|propagator| {
                 propagator.inject_context(
@@ -137,6 +261,17 @@ impl tower::Service<crate::SubgraphRequest> for SubgraphService {
                 )
             });
 
+            // signed last, over the bytes sent over the wire
+            if let Some(signer) = &signer {
+                signer
+                    .sign(&mut request)
+                    .map_err(|err| FetchError::SubrequestHttpError {
+                        service: service_name.clone(),
+                        reason: format!("could not sign the request: {}", err),
+                    })?;
+            }
+            let request = request.map(hyper::Body::from);
+
             let schema_uri = request.uri();
             let host = schema_uri.host().map(String::from).unwrap_or_default();
             let port = schema_uri.port_u16().unwrap_or_else(|| {
@@ -163,10 +298,7 @@ impl tower::Service<crate::SubgraphRequest> for SubgraphService {
                 .map_err(|err| {
                     tracing::error!(fetch_error = format!("{:?}", err).as_str());
 
-                    FetchError::SubrequestHttpError {
-                        service: service_name.clone(),
-                        reason: err.to_string(),
-                    }
+                    FetchError::from_hyper_error(&service_name, &err)
                 })?;
 
             // Keep our parts, we'll need them later
@@ -177,15 +309,25 @@ impl tower::Service<crate::SubgraphRequest> for SubgraphService {
                     if !content_type_str.contains("application/json")
                         && !content_type_str.contains("application/graphql+json")
                     {
+                        let reason = format!("subgraph didn't return JSON (expected content-type: application/json or content-type: application/graphql+json; found content-type: {content_type:?})");
+                        // an error page rather than a GraphQL response
+                        if !parts.status.is_success() {
+                            return Err(BoxError::from(FetchError::SubrequestHttpStatusError {
+                                service: service_name.clone(),
+                                status: parts.status.as_u16(),
+                                reason,
+                                retry_after: retry_after(&parts.headers),
+                            }));
+                        }
                         return Err(BoxError::from(FetchError::SubrequestHttpError {
                             service: service_name.clone(),
-                            reason: format!("subgraph didn't return JSON (expected content-type: application/json or content-type: application/graphql+json; found content-type: {content_type:?})"),
+                            reason,
                         }));
                     }
                 }
             }
 
-            let body = hyper::body::to_bytes(body)
+            let body = read_body(body, request_size::remaining(&context))
                 .instrument(tracing::debug_span!("aggregate_response_data"))
                 .await
                 .map_err(|err| {
@@ -196,6 +338,14 @@ impl tower::Service<crate::SubgraphRequest> for SubgraphService {
                         reason: err.to_string(),
                     }
                 })?;
+            // a body over the remaining size is only read up to it
+            if let Err(limit) = request_size::record_subgraph_response(&context, body.len()) {
+                return Err(FetchError::SubrequestSizeLimitExceeded {
+                    service: service_name,
+                    limit,
+                }
+                .into());
+            }
 
             let graphql: graphql::Response = tracing::debug_span!("parse_subgraph_response")
                 .in_scope(|| {
@@ -214,6 +364,53 @@ impl tower::Service<crate::SubgraphRequest> for SubgraphService {
     }
 }
 
+/// Read a response body, stopping as soon as it is longer than `max_length`: the bytes read so far
+/// are then returned with the chunk going over it.
+async fn read_body<B>(body: B, max_length: Option<u64>) -> Result<Bytes, B::Error>
+where
+    B: HttpBody,
+{
+    let max_length = match max_length {
+        Some(max_length) => max_length,
+        None => return hyper::body::to_bytes(body).await,
+    };
+    tokio::pin!(body);
+    let mut bytes = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        bytes.put(chunk?);
+        if bytes.len() as u64 > max_length {
+            break;
+        }
+    }
+    Ok(bytes.freeze())
+}
+
+/// The URI of a GET request carrying the GraphQL request in its query string.
+/// The URI of the GET request sending a GraphQL request, unless it is longer than `max_length`.
+fn get_uri(uri: &Uri, request: &graphql::Request, max_length: usize) -> Option<Uri> {
+    let query_string = request.to_urlencoded_query();
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}&{}", uri.path(), query, query_string),
+        None => format!("{}?{}", uri.path(), query_string),
+    };
+    // the length of `scheme://authority/path?query`, without formatting the URI
+    let length = uri
+        .scheme_str()
+        .map(|scheme| scheme.len() + "://".len())
+        .unwrap_or_default()
+        + uri
+            .authority()
+            .map(|authority| authority.as_str().len())
+            .unwrap_or_default()
+        + path_and_query.len();
+    if length > max_length {
+        return None;
+    }
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    Uri::from_parts(parts).ok()
+}
+
 pub(crate) async fn compress(body: String, headers: &HeaderMap) -> Result<Vec<u8>, BoxError> {
     let content_encoding = headers.get(&CONTENT_ENCODING);
     match content_encoding {
@@ -270,6 +467,9 @@ pub(crate) struct SubgraphCreator {
     pub(crate) services: Arc<HashMap<String, Arc<dyn MakeSubgraphService>>>,
 
     pub(crate) plugins: Arc<Plugins>,
+
+    /// Whether the services handle the canary requests, which bypass some of the plugins.
+    canary: bool,
 }
 
 impl SubgraphCreator {
@@ -280,6 +480,16 @@ impl SubgraphCreator {
         SubgraphCreator {
             services: Arc::new(services.into_iter().collect()),
             plugins,
+            canary: false,
+        }
+    }
+
+    /// The creator of the services handling the canary requests.
+    pub(crate) fn for_canary(&self) -> Self {
+        SubgraphCreator {
+            services: self.services.clone(),
+            plugins: self.plugins.clone(),
+            canary: true,
         }
     }
 }
@@ -318,6 +528,7 @@ impl SubgraphServiceFactory for SubgraphCreator {
             self.plugins
                 .iter()
                 .rev()
+                .filter(|(plugin, _)| !self.canary || !canary::bypasses(plugin))
                 .fold(service, |acc, (_, e)| e.subgraph_service(name, acc))
         })
     }
@@ -341,6 +552,7 @@ mod tests {
     use tower::ServiceExt;
 
     use super::*;
+    use crate::error::FetchErrorKind;
     use crate::graphql::Error;
     use crate::graphql::Request;
     use crate::graphql::Response;
@@ -348,6 +560,21 @@ mod tests {
     use crate::Context;
     use crate::SubgraphRequest;
 
+    #[tokio::test]
+    async fn it_stops_reading_bodies_over_their_maximum_length() {
+        let body = || {
+            Body::wrap_stream(futures::stream::iter(vec![
+                Ok::<_, std::io::Error>("abc"),
+                Ok("def"),
+                Ok("ghi"),
+            ]))
+        };
+        assert_eq!(read_body(body(), None).await.unwrap(), "abcdefghi");
+        assert_eq!(read_body(body(), Some(9)).await.unwrap(), "abcdefghi");
+        // the read stops with the chunk going over the maximum length
+        assert_eq!(read_body(body(), Some(4)).await.unwrap(), "abcdef");
+    }
+
     // starts a local server emulating a subgraph returning status code 400
     async fn emulate_subgraph_bad_request(socket_addr: SocketAddr) {
         async fn handle(_request: http::Request<Body>) -> Result<http::Response<Body>, Infallible> {
@@ -389,6 +616,23 @@ mod tests {
         }
     }
 
+    // starts a local server emulating a subgraph behind a proxy answering with an error page
+    async fn emulate_subgraph_unavailable(socket_addr: SocketAddr) {
+        async fn handle(_request: http::Request<Body>) -> Result<http::Response<Body>, Infallible> {
+            Ok(http::Response::builder()
+                .header("Content-Type", "text/html")
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(r#"<h1>Service Unavailable</h1>"#.into())
+                .unwrap())
+        }
+
+        let make_svc = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
+        let server = Server::bind(&socket_addr).serve(make_svc);
+        if let Err(e) = server.await {
+            eprintln!("server error: {}", e);
+        }
+    }
+
     // starts a local server emulating a subgraph returning compressed response
     async fn emulate_subgraph_compressed_response(socket_addr: SocketAddr) {
         async fn handle(request: http::Request<Body>) -> Result<http::Response<Body>, Infallible> {
@@ -460,6 +704,7 @@ mod tests {
                     .uri(url)
                     .body(Request::builder().query("query").build())
                     .expect("expecting valid request"),
+                subgraph_name: String::from("test"),
                 operation_kind: OperationKind::Query,
                 context: Context::new(),
             })
@@ -471,6 +716,45 @@ mod tests {
         );
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_canary_requests_are_answered_with_the_mocks() {
+        let subgraph_service = SubgraphService::new("test");
+        let mut originating_request = http::Request::builder()
+            .header(HOST, "host")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Request::builder().query("query").build())
+            .expect("expecting valid request");
+        let mocks = serde_json::from_value(serde_json::json!({
+            "test": { "response": { "data": { "me": { "name": "Ada" } } } },
+        }))
+        .unwrap();
+        originating_request
+            .extensions_mut()
+            .insert(SubgraphMocks::parse(&mocks).unwrap());
+
+        // nothing listens on this port, the request is not sent
+        let url = Uri::from_str("http://127.0.0.1:2929").unwrap();
+        let response = subgraph_service
+            .oneshot(SubgraphRequest {
+                originating_request: Arc::new(originating_request),
+                subgraph_request: http::Request::builder()
+                    .header(HOST, "rhost")
+                    .header(CONTENT_TYPE, "application/json")
+                    .uri(url)
+                    .body(Request::builder().query("query").build())
+                    .expect("expecting valid request"),
+                subgraph_name: String::from("test"),
+                operation_kind: OperationKind::Query,
+                context: Context::new(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            response.response.body().data,
+            Some(serde_json_bytes::json!({ "me": { "name": "Ada" } }))
+        );
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_bad_content_type() {
         let socket_addr = SocketAddr::from_str("127.0.0.1:2525").unwrap();
@@ -493,6 +777,7 @@ mod tests {
                     .uri(url)
                     .body(Request::builder().query("query").build())
                     .expect("expecting valid request"),
+                subgraph_name: String::from("test"),
                 operation_kind: OperationKind::Query,
                 context: Context::new(),
             })
@@ -504,6 +789,51 @@ mod tests {
         );
     }
 
+    fn subgraph_request(url: Uri) -> SubgraphRequest {
+        SubgraphRequest::fake_builder()
+            .subgraph_request(
+                http::Request::builder()
+                    .header(HOST, "rhost")
+                    .header(CONTENT_TYPE, "application/json")
+                    .uri(url)
+                    .body(Request::builder().query("query").build())
+                    .expect("expecting valid request"),
+            )
+            .subgraph_name("test")
+            .build()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_unavailable_subgraph_is_retryable() {
+        let socket_addr = SocketAddr::from_str("127.0.0.1:2323").unwrap();
+        tokio::task::spawn(emulate_subgraph_unavailable(socket_addr));
+        let url = Uri::from_str(&format!("http://{}", socket_addr)).unwrap();
+        let err = SubgraphService::new("test")
+            .oneshot(subgraph_request(url))
+            .await
+            .unwrap_err();
+        let err = FetchError::from_service_error("test", err);
+        assert!(matches!(
+            err,
+            FetchError::SubrequestHttpStatusError { status: 503, .. }
+        ));
+        assert_eq!(err.kind(), FetchErrorKind::HttpStatus);
+        assert!(err.is_retryable());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_connection_refused_is_retryable() {
+        // nothing listens on this port
+        let url = Uri::from_str("http://127.0.0.1:2424").unwrap();
+        let err = SubgraphService::new("test")
+            .oneshot(subgraph_request(url))
+            .await
+            .unwrap_err();
+        let err = FetchError::from_service_error("test", err);
+        assert_eq!(err.kind(), FetchErrorKind::Connect);
+        assert!(err.is_retryable());
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_compressed_request_response_body() {
         let socket_addr = SocketAddr::from_str("127.0.0.1:2727").unwrap();
@@ -527,6 +857,7 @@ mod tests {
                     .uri(url)
                     .body(Request::builder().query("query".to_string()).build())
                     .expect("expecting valid request"),
+                subgraph_name: String::from("test"),
                 operation_kind: OperationKind::Query,
                 context: Context::new(),
             })
@@ -540,4 +871,236 @@ mod tests {
 
         assert_eq!(resp.response.body(), &resp_from_subgraph);
     }
+
+    // starts a local server emulating a subgraph telling the method of the requests and their query
+    async fn emulate_subgraph_method(socket_addr: SocketAddr) {
+        async fn handle(request: http::Request<Body>) -> Result<http::Response<Body>, Infallible> {
+            let data = if request.method() == Method::GET {
+                let query = Request::from_urlencoded_query(
+                    request.uri().query().unwrap_or_default().to_string(),
+                )
+                .unwrap()
+                .query
+                .unwrap_or_default();
+                format!("GET {}", query)
+            } else {
+                "POST".to_string()
+            };
+            Ok(http::Response::builder()
+                .header("Content-Type", "application/json")
+                .status(StatusCode::OK)
+                .body(
+                    serde_json::to_string(&Response {
+                        data: Some(Value::String(ByteString::from(data))),
+                        ..Response::default()
+                    })
+                    .expect("always valid")
+                    .into(),
+                )
+                .unwrap())
+        }
+
+        let make_svc = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
+        let server = Server::bind(&socket_addr).serve(make_svc);
+        if let Err(e) = server.await {
+            eprintln!("server error: {}", e);
+        }
+    }
+
+    #[test]
+    fn test_get_uri_length() {
+        let request = Request::builder().query("{ me { name } }").build();
+        for url in [
+            "http://products:4001/graphql",
+            "https://products.example.com/graphql?tenant=acme",
+        ] {
+            let uri = Uri::from_str(url).unwrap();
+            let length = get_uri(&uri, &request, usize::MAX)
+                .unwrap()
+                .to_string()
+                .len();
+            assert!(get_uri(&uri, &request, length).is_some());
+            assert!(get_uri(&uri, &request, length - 1).is_none());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_get_requests_below_the_max_url_length() {
+        let socket_addr = SocketAddr::from_str("127.0.0.1:2828").unwrap();
+        tokio::task::spawn(emulate_subgraph_method(socket_addr));
+
+        let url = Uri::from_str(&format!("http://{}/graphql", socket_addr)).unwrap();
+        let request = |operation_kind| SubgraphRequest {
+            originating_request: Arc::new(
+                http::Request::builder()
+                    .header(HOST, "host")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Request::builder().query("{ me { name } }").build())
+                    .expect("expecting valid request"),
+            ),
+            subgraph_request: http::Request::builder()
+                .method(Method::POST)
+                .header(HOST, "rhost")
+                .header(CONTENT_TYPE, "application/json")
+                .uri(url.clone())
+                .body(Request::builder().query("{ me { name } }").build())
+                .expect("expecting valid request"),
+            subgraph_name: String::from("test"),
+            operation_kind,
+            context: Context::new(),
+        };
+        let data = |response: crate::SubgraphResponse| response.response.body().data.clone();
+
+        let subgraph_service = SubgraphService::new("test").with_get_max_url_length(Some(1024));
+        let response = subgraph_service
+            .clone()
+            .oneshot(request(OperationKind::Query))
+            .await
+            .unwrap();
+        assert_eq!(
+            data(response),
+            Some(Value::String(ByteString::from("GET { me { name } }")))
+        );
+        let response = subgraph_service
+            .oneshot(request(OperationKind::Mutation))
+            .await
+            .unwrap();
+        assert_eq!(
+            data(response),
+            Some(Value::String(ByteString::from("POST")))
+        );
+
+        let response = SubgraphService::new("test")
+            .with_get_max_url_length(Some(32))
+            .oneshot(request(OperationKind::Query))
+            .await
+            .unwrap();
+        assert_eq!(
+            data(response),
+            Some(Value::String(ByteString::from("POST")))
+        );
+    }
+
+    fn sigv4_config() -> SigV4Config {
+        SigV4Config {
+            region: "us-east-1".to_string(),
+            service: "lambda".to_string(),
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "secret".to_string(),
+            session_token: None,
+        }
+    }
+
+    // starts a local server emulating a subgraph checking the signature of the requests over
+    // the bytes it receives
+    async fn emulate_subgraph_sigv4(socket_addr: SocketAddr, since: std::time::SystemTime) {
+        let handle = move |request: http::Request<Body>| async move {
+            let (parts, body) = request.into_parts();
+            let body = hyper::body::to_bytes(body).await.unwrap();
+            let authorization = parts.headers.get(header::AUTHORIZATION).cloned();
+            let uri = format!(
+                "http://{}{}",
+                parts.headers.get(header::HOST).unwrap().to_str().unwrap(),
+                parts.uri
+            );
+            // the request was signed during the test
+            let signed = (0..30).any(|seconds| {
+                let mut expected = http::Request::builder()
+                    .method(parts.method.clone())
+                    .uri(uri.as_str())
+                    .body(body.clone())
+                    .unwrap();
+                sigv4_config()
+                    .sign_at(
+                        &mut expected,
+                        since + std::time::Duration::from_secs(seconds),
+                    )
+                    .unwrap();
+                expected.headers().get(header::AUTHORIZATION) == authorization.as_ref()
+            });
+            Ok::<_, Infallible>(
+                http::Response::builder()
+                    .header("Content-Type", "application/json")
+                    .status(StatusCode::OK)
+                    .body(Body::from(
+                        serde_json::to_string(&Response {
+                            data: Some(Value::Bool(signed)),
+                            ..Response::default()
+                        })
+                        .expect("always valid"),
+                    ))
+                    .unwrap(),
+            )
+        };
+
+        let make_svc =
+            make_service_fn(move |_conn| async move { Ok::<_, Infallible>(service_fn(handle)) });
+        let server = Server::bind(&socket_addr).serve(make_svc);
+        if let Err(e) = server.await {
+            eprintln!("server error: {}", e);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_requests_are_signed_as_sent() {
+        let socket_addr = SocketAddr::from_str("127.0.0.1:3030").unwrap();
+        // timestamps have a precision of one second
+        let since = std::time::UNIX_EPOCH
+            + std::time::Duration::from_secs(
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+            );
+        tokio::task::spawn(emulate_subgraph_sigv4(socket_addr, since));
+
+        let url = Uri::from_str(&format!("http://{}/graphql", socket_addr)).unwrap();
+        let request = |operation_kind, body: Request| SubgraphRequest {
+            originating_request: Arc::new(
+                http::Request::builder()
+                    .header(HOST, "host")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Request::builder().query("{ me { name } }").build())
+                    .expect("expecting valid request"),
+            ),
+            subgraph_request: http::Request::builder()
+                .header(HOST, "rhost")
+                .header(CONTENT_TYPE, "application/json")
+                .header(CONTENT_ENCODING, "gzip")
+                .uri(url.clone())
+                .body(body)
+                .expect("expecting valid request"),
+            subgraph_name: String::from("test"),
+            operation_kind,
+            context: Context::new(),
+        };
+        let query = || Request::builder().query("{ me { name } }").build();
+        // the requests of the automatic persisted queries of the traffic shaping plugin are
+        // rewritten before they reach the subgraph service, which signs them as they are sent
+        let hash_only = || {
+            let mut request = Request::builder().build();
+            request.set_persisted_query(&crate::graphql::PersistedQuery::new(
+                "ecf4edb46db40b5132295c0291d62fb65d6759a9eedfa4d5d612dd5ec54a6b38",
+            ));
+            request
+        };
+
+        let subgraph_service = SubgraphService::new("test")
+            .with_get_max_url_length(Some(1024))
+            .with_signer(Some(sigv4_config()));
+        // a compressed POST request, a GET request, and the GET and POST requests of APQ
+        for (operation_kind, body) in [
+            (OperationKind::Mutation, query()),
+            (OperationKind::Query, query()),
+            (OperationKind::Query, hash_only()),
+            (OperationKind::Mutation, hash_only()),
+        ] {
+            let response = subgraph_service
+                .clone()
+                .oneshot(request(operation_kind, body))
+                .await
+                .unwrap();
+            assert_eq!(response.response.body().data, Some(Value::Bool(true)));
+        }
+    }
 }
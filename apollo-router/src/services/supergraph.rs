@@ -63,6 +63,8 @@ impl Request {
         // Skip the `Object` type alias in order to use buildstructor’s map special-casing
         variables: JsonMap<ByteString, Value>,
         extensions: JsonMap<ByteString, Value>,
+        persisted_query: Option<graphql::PersistedQuery>,
+        client_library: Option<graphql::ClientLibrary>,
         context: Context,
         headers: MultiMap<TryIntoHeaderName, TryIntoHeaderValue>,
         uri: Uri,
@@ -73,6 +75,8 @@ impl Request {
             .and_operation_name(operation_name)
             .variables(variables)
             .extensions(extensions)
+            .and_persisted_query(persisted_query)
+            .and_client_library(client_library)
             .build();
         let mut originating_request = http::Request::builder()
             .uri(uri)
@@ -92,6 +96,7 @@ impl Request {
     /// difficult to construct and not required for the purposes of the test.
     ///
     /// In addition, fake requests are expected to be valid, and will panic if given invalid values.
+    #[allow(clippy::too_many_arguments)]
     #[builder(visibility = "pub")]
     fn fake_new(
         query: Option<String>,
@@ -99,6 +104,8 @@ impl Request {
         // Skip the `Object` type alias in order to use buildstructor’s map special-casing
         variables: JsonMap<ByteString, Value>,
         extensions: JsonMap<ByteString, Value>,
+        persisted_query: Option<graphql::PersistedQuery>,
+        client_library: Option<graphql::ClientLibrary>,
         context: Option<Context>,
         mut headers: MultiMap<TryIntoHeaderName, TryIntoHeaderValue>,
         method: Option<Method>,
@@ -112,6 +119,8 @@ impl Request {
             operation_name,
             variables,
             extensions,
+            persisted_query,
+            client_library,
             context.unwrap_or_default(),
             headers,
             Uri::from_static("http://default"),
@@ -148,6 +157,8 @@ impl Request {
             operation_name,
             variables,
             extensions,
+            None,
+            None,
             context,
             headers,
             None,
@@ -1,5 +1,6 @@
 //! Implements the router phase of the request lifecycle.
 
+use std::collections::BTreeSet;
 use std::sync::Arc;
 use std::task::Poll;
 
@@ -23,6 +24,8 @@ use serde_json_bytes::ByteString;
 use serde_json_bytes::Map;
 use serde_json_bytes::Value;
 use tower::util::BoxService;
+use tower::util::MapRequestLayer;
+use tower::util::MapResponseLayer;
 use tower::BoxError;
 use tower::ServiceBuilder;
 use tower::ServiceExt;
@@ -30,6 +33,9 @@ use tower_service::Service;
 use tracing_futures::Instrument;
 
 use super::new_service::NewService;
+use super::progressive_override::OverrideQueryPlanners;
+use super::request_size;
+use super::request_stages;
 use super::subgraph_service::MakeSubgraphService;
 use super::subgraph_service::SubgraphCreator;
 use super::ExecutionCreator;
@@ -37,22 +43,52 @@ use super::ExecutionServiceFactory;
 use super::QueryPlannerContent;
 use super::MULTIPART_DEFER_SPEC_PARAMETER;
 use super::MULTIPART_DEFER_SPEC_VALUE;
+use crate::admin::PluginState;
+use crate::admin::RuntimeState;
+use crate::cache::storage::InMemoryStorage;
+use crate::cache::storage::Storages;
 use crate::cache::DeduplicatingCache;
+use crate::canary;
+use crate::configuration::Contract;
+use crate::configuration::Storage;
+use crate::entitlement::Entitlement;
+use crate::error::ErrorCode;
 use crate::error::QueryPlannerError;
 use crate::error::ServiceBuildError;
 use crate::graphql;
 use crate::graphql::Response;
 use crate::introspection::Introspection;
 use crate::json_ext::ValueExt;
+use crate::operation_registry::OperationRegistryPoller;
 use crate::plugin::DynPlugin;
 use crate::plugin::Handler;
+use crate::plugin::ResponseBodyTransformer;
+use crate::plugins::telemetry::Queued;
+use crate::plugins::traffic_shaping::SubgraphHealth;
 use crate::query_planner::BridgeQueryPlanner;
 use crate::query_planner::CachingQueryPlanner;
+use crate::query_planner::PlanStorage;
 use crate::response::IncrementalResponse;
 use crate::router_factory::SupergraphServiceFactory;
 use crate::services::layers::apq::APQLayer;
+use crate::services::layers::client_identification::ClientIdentifier;
+use crate::services::layers::client_identification::ClientInfo;
+use crate::services::layers::client_identification::TokenKeys;
+use crate::services::layers::client_identification::INTROSPECTION_CONTEXT_KEY;
+use crate::services::layers::data_masking::DataMasker;
 use crate::services::layers::ensure_query_presence::EnsureQueryPresence;
+use crate::services::layers::maintenance::MaintenanceLayer;
+use crate::services::layers::maintenance::MaintenanceSwitch;
+use crate::services::layers::partial_results;
+use crate::services::layers::partial_results::PartialResultsPolicies;
+use crate::services::layers::persisted_queries::PersistedQueryRegistry;
+use crate::services::layers::quotas::expose_cost;
+use crate::services::layers::quotas::OperationCost;
+use crate::services::layers::quotas::QuotaEnforcer;
+use crate::services::layers::surrogate_keys::SurrogateKeyHeader;
 use crate::spec::Query;
+use crate::spec::SpecError;
+use crate::spec::ValidationError;
 use crate::Configuration;
 use crate::Context;
 use crate::ExecutionRequest;
@@ -72,7 +108,9 @@ pub(crate) struct SupergraphService<ExecutionFactory> {
     execution_service_factory: ExecutionFactory,
     query_planner_service: CachingQueryPlanner<BridgeQueryPlanner>,
     ready_query_planner_service: Option<CachingQueryPlanner<BridgeQueryPlanner>>,
+    override_planners: Option<OverrideQueryPlanners>,
     schema: Arc<Schema>,
+    quotas: Option<QuotaEnforcer>,
 }
 
 #[buildstructor::buildstructor]
@@ -81,13 +119,17 @@ impl<ExecutionFactory> SupergraphService<ExecutionFactory> {
     pub(crate) fn new(
         query_planner_service: CachingQueryPlanner<BridgeQueryPlanner>,
         execution_service_factory: ExecutionFactory,
+        override_planners: Option<OverrideQueryPlanners>,
         schema: Arc<Schema>,
+        quotas: Option<QuotaEnforcer>,
     ) -> Self {
         SupergraphService {
             query_planner_service,
             execution_service_factory,
             ready_query_planner_service: None,
+            override_planners,
             schema,
+            quotas,
         }
     }
 }
@@ -110,40 +152,81 @@ where
             .poll_ready(cx)
     }
 
-    fn call(&mut self, req: SupergraphRequest) -> Self::Future {
+    fn call(&mut self, mut req: SupergraphRequest) -> Self::Future {
+        // the request is no longer queued once it reaches the supergraph service
+        req.originating_request.extensions_mut().remove::<Queued>();
         // Consume our cloned services and allow ownership to be transferred to the async block.
         let planning = self.ready_query_planner_service.take().unwrap();
         let execution = self.execution_service_factory.new_service();
+        let override_planners = self.override_planners.clone();
 
         let schema = self.schema.clone();
+        let quotas = self.quotas.clone();
 
         let context_cloned = req.context.clone();
-        let fut =
-            service_call(planning, execution, schema, req).or_else(|error: BoxError| async move {
-                let errors = vec![crate::error::Error {
-                    message: error.to_string(),
-                    ..Default::default()
-                }];
-                let status_code = match error.downcast_ref::<crate::error::CacheResolverError>() {
-                    Some(crate::error::CacheResolverError::RetrievalError(retrieval_error))
-                        if matches!(
-                            retrieval_error.deref().downcast_ref::<QueryPlannerError>(),
-                            Some(QueryPlannerError::SpecError(_))
-                                | Some(QueryPlannerError::SchemaValidationErrors(_))
-                        ) =>
-                    {
-                        StatusCode::BAD_REQUEST
-                    }
-                    _ => StatusCode::INTERNAL_SERVER_ERROR,
-                };
+        request_size::record_request(&req.context, req.originating_request.body());
+        let fut = async move {
+            // the labels are chosen after the plugins ran, so they can enable some of them
+            let planning = match override_planners {
+                Some(override_planners) => {
+                    let enabled = override_planners
+                        .enabled_labels(req.originating_request.headers(), &req.context)?;
+                    override_planners.query_planner(enabled).await?
+                }
+                None => planning,
+            };
+            service_call(planning, execution, schema, quotas, req).await
+        }
+        .or_else(|error: BoxError| async move {
+            let planning_error = match error.downcast_ref::<crate::error::CacheResolverError>() {
+                Some(crate::error::CacheResolverError::RetrievalError(retrieval_error)) => {
+                    retrieval_error.deref().downcast_ref::<QueryPlannerError>()
+                }
+                None => None,
+            };
+            let errors = match planning_error {
+                // syntax errors are reported with their location in the query
+                Some(QueryPlannerError::SpecError(spec_error @ SpecError::ParsingError(_))) => {
+                    spec_error.to_graphql_errors()
+                }
+                Some(QueryPlannerError::IntrospectionLimit(limit_error)) => {
+                    vec![crate::error::Error::builder()
+                        .message(limit_error.to_string())
+                        .code(ErrorCode::IntrospectionLimitExceeded)
+                        .build()]
+                }
+                // validation errors are located from the names they quote
+                Some(QueryPlannerError::PlanningErrors(_, validation_errors)) => validation_errors
+                    .iter()
+                    .map(ValidationError::to_graphql_error)
+                    .collect(),
+                Some(QueryPlannerError::SpecError(_))
+                | Some(QueryPlannerError::SchemaValidationErrors(_)) => {
+                    vec![crate::error::Error::builder()
+                        .message(error.to_string())
+                        .code(ErrorCode::GraphqlValidationFailed)
+                        .build()]
+                }
+                _ => vec![crate::error::Error::builder()
+                    .message(error.to_string())
+                    .code(ErrorCode::InternalServerError)
+                    .build()],
+            };
+            let status_code = match planning_error {
+                Some(QueryPlannerError::SpecError(_))
+                | Some(QueryPlannerError::SchemaValidationErrors(_))
+                | Some(QueryPlannerError::PlanningErrors(_, _))
+                | Some(QueryPlannerError::IntrospectionLimit(_)) => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
 
-                Ok(SupergraphResponse::builder()
-                    .errors(errors)
-                    .status_code(status_code)
-                    .context(context_cloned)
-                    .build()
-                    .expect("building a response like this should not fail"))
-            });
+            Ok(SupergraphResponse::builder()
+                .errors(errors)
+                .status_code(status_code)
+                .context(context_cloned)
+                .build()
+                .expect("building a response like this should not fail"))
+        });
 
         Box::pin(fut)
     }
@@ -153,6 +236,7 @@ async fn service_call<ExecutionService>(
     planning: CachingQueryPlanner<BridgeQueryPlanner>,
     execution: ExecutionService,
     schema: Arc<Schema>,
+    quotas: Option<QuotaEnforcer>,
     req: SupergraphRequest,
 ) -> Result<SupergraphResponse, BoxError>
 where
@@ -161,9 +245,38 @@ where
 {
     let context = req.context;
     let body = req.originating_request.body();
-    let variables = body.variables.clone();
     let QueryPlannerResponse { content, context } = plan_query(planning, body, context).await?;
 
+    // the planner answers introspection queries when any verified client may send them, so
+    // they are only answered for the requests explicitly allowed to
+    let content = match content {
+        QueryPlannerContent::Introspection { .. }
+            if context
+                .get::<_, bool>(INTROSPECTION_CONTEXT_KEY)
+                .ok()
+                .flatten()
+                != Some(true) =>
+        {
+            QueryPlannerContent::IntrospectionDisabled
+        }
+        content => content,
+    };
+
+    // operations are accounted once planned, with the cost of their parsed query
+    if let Some(quotas) =
+        quotas.filter(|_| !matches!(content, QueryPlannerContent::IntrospectionDisabled))
+    {
+        let cost = match &content {
+            QueryPlannerContent::Plan { query, .. } if quotas.needs_cost() => {
+                query.cost(body.operation_name.as_deref())
+            }
+            _ => OperationCost::default(),
+        };
+        if let Some(response) = quotas.enforce(&context, cost).await? {
+            return Ok(response);
+        }
+    }
+
     match content {
         QueryPlannerContent::Introspection { response } => Ok(
             SupergraphResponse::new_from_graphql_response(*response, context),
@@ -173,6 +286,7 @@ where
                 graphql::Response::builder()
                     .errors(vec![crate::error::Error::builder()
                         .message(String::from("introspection has been disabled"))
+                        .code(ErrorCode::GraphqlValidationFailed)
                         .build()])
                     .build(),
                 context,
@@ -187,26 +301,39 @@ where
                 let mut response = SupergraphResponse::new_from_graphql_response(graphql::Response::builder()
                     .errors(vec![crate::error::Error::builder()
                         .message(String::from("the router received a query with the @defer directive but the client does not accept multipart/mixed HTTP responses. To enable @defer support, add the HTTP header 'Accept: multipart/mixed; deferSpec=20220824'"))
+                        .code(ErrorCode::BadRequest)
                         .build()])
                     .build(), context);
                 *response.response.status_mut() = StatusCode::NOT_ACCEPTABLE;
                 Ok(response)
-            } else if let Some(err) = query.validate_variables(body, &schema).err() {
-                let mut res = SupergraphResponse::new_from_graphql_response(err, context);
-                *res.response.status_mut() = StatusCode::BAD_REQUEST;
-                Ok(res)
             } else {
+                let variables = match query.validate_variables(body, &schema) {
+                    Ok(variables) => variables,
+                    Err(err) => {
+                        let mut res = SupergraphResponse::new_from_graphql_response(err, context);
+                        *res.response.status_mut() = StatusCode::BAD_REQUEST;
+                        return Ok(res);
+                    }
+                };
                 let operation_name = body.operation_name.clone();
 
-                let execution_response = execution
-                    .oneshot(
+                // subgraphs receive the coerced variables, with the default values applied
+                let mut originating_request = req.originating_request;
+                originating_request.body_mut().variables = variables.clone();
+
+                let stages_context = context.clone();
+                let execution_response = request_stages::record(
+                    &stages_context,
+                    request_stages::Stage::Execution,
+                    execution.oneshot(
                         ExecutionRequest::builder()
-                            .originating_request(req.originating_request)
+                            .originating_request(originating_request)
                             .query_plan(plan)
                             .context(context)
                             .build(),
-                    )
-                    .await?;
+                    ),
+                )
+                .await?;
 
                 process_execution_response(
                     execution_response,
@@ -282,8 +409,13 @@ fn process_execution_response(
     can_be_deferred: bool,
 ) -> Result<SupergraphResponse, BoxError> {
     let ExecutionResponse { response, context } = execution_response;
+    let response_context = context.clone();
 
-    let (parts, response_stream) = response.into_parts();
+    let (mut parts, response_stream) = response.into_parts();
+    parts.extensions.insert(FormattedQuery {
+        query: query.clone(),
+        operation_name: operation_name.clone(),
+    });
 
     let stream = response_stream.map(move |mut response: Response| {
         tracing::debug_span!("format_response").in_scope(|| {
@@ -294,6 +426,7 @@ fn process_execution_response(
                 schema.api_schema(),
             )
         });
+        request_size::record_response(&response_context, &response);
 
         match (response.path.as_ref(), response.data.as_ref()) {
             (None, _) | (_, None) => {
@@ -357,6 +490,151 @@ fn process_execution_response(
     })
 }
 
+/// The query a response was formatted for, kept in the extensions of the HTTP response to order
+/// its fields again, or mask some of them, once the plugins modified it.
+#[derive(Clone)]
+struct FormattedQuery {
+    query: Arc<Query>,
+    operation_name: Option<String>,
+}
+
+fn order_response_fields(mut response: SupergraphResponse) -> SupergraphResponse {
+    match response
+        .response
+        .extensions_mut()
+        .remove::<FormattedQuery>()
+    {
+        Some(FormattedQuery {
+            query,
+            operation_name,
+        }) => response.map_stream(move |mut response| {
+            query.order_response_fields(&mut response, operation_name.as_deref());
+            response
+        }),
+        None => response,
+    }
+}
+
+/// Remove the fields the query does not select from the responses, once the plugins modified
+/// them.
+fn prune_response_fields(schema: &Arc<Schema>, response: SupergraphResponse) -> SupergraphResponse {
+    match response
+        .response
+        .extensions()
+        .get::<FormattedQuery>()
+        .cloned()
+    {
+        Some(FormattedQuery {
+            query,
+            operation_name,
+        }) => {
+            let schema = schema.clone();
+            response.map_stream(move |mut response| {
+                let pruned = query.prune_fields(
+                    &mut response,
+                    operation_name.as_deref(),
+                    schema.api_schema(),
+                );
+                if !pruned.is_empty() {
+                    tracing::debug!(
+                        "removed {} unrequested fields from the response, the first one at {}",
+                        pruned.len(),
+                        pruned[0]
+                    );
+                }
+                response
+            })
+        }
+        None => response,
+    }
+}
+
+fn mask_response_fields(
+    masker: &DataMasker,
+    schema: &Arc<Schema>,
+    response: SupergraphResponse,
+) -> SupergraphResponse {
+    match response
+        .response
+        .extensions()
+        .get::<FormattedQuery>()
+        .cloned()
+    {
+        Some(FormattedQuery {
+            query,
+            operation_name,
+        }) => {
+            let masker = masker.clone();
+            let schema = schema.clone();
+            let context = response.context.clone();
+            response.map_stream(move |mut response| {
+                masker.mask(
+                    &query,
+                    operation_name.as_deref(),
+                    schema.api_schema(),
+                    &context,
+                    &mut response,
+                );
+                response
+            })
+        }
+        None => response,
+    }
+}
+
+/// Send the surrogate keys of a response in a header, computed from its first part once the
+/// plugins modified it.
+async fn surrogate_key_header(
+    surrogate_keys: &SurrogateKeyHeader,
+    schema: &Arc<Schema>,
+    response: SupergraphResponse,
+) -> SupergraphResponse {
+    let SupergraphResponse { response, context } = response;
+    let query = response.extensions().get::<FormattedQuery>().cloned();
+    let (mut parts, mut stream) = response.into_parts();
+    let first = match stream.next().await {
+        Some(first) => first,
+        None => {
+            return SupergraphResponse::new_from_response(
+                http::Response::from_parts(parts, stream),
+                context,
+            )
+        }
+    };
+    if let Some(value) = surrogate_keys.value(
+        query
+            .as_ref()
+            .map(|query| (query.query.as_ref(), query.operation_name.as_deref())),
+        schema.api_schema(),
+        &context,
+        &first,
+    ) {
+        parts.headers.insert(surrogate_keys.name().clone(), value);
+    }
+    SupergraphResponse::new_from_response(
+        http::Response::from_parts(parts, once(ready(first)).chain(stream).boxed()),
+        context,
+    )
+}
+
+fn add_surrogate_keys<S>(
+    surrogate_keys: SurrogateKeyHeader,
+    schema: Arc<Schema>,
+    service: S,
+) -> BoxService<SupergraphRequest, SupergraphResponse, BoxError>
+where
+    S: Service<SupergraphRequest, Response = SupergraphResponse, Error = BoxError> + Send + 'static,
+    <S as Service<SupergraphRequest>>::Future: Send + 'static,
+{
+    service
+        .map_future(move |response| {
+            let surrogate_keys = surrogate_keys.clone();
+            let schema = schema.clone();
+            async move { Ok(surrogate_key_header(&surrogate_keys, &schema, response.await?).await) }
+        })
+        .boxed()
+}
+
 /// Builder which generates a plugin pipeline.
 ///
 /// This is at the heart of the delegation of responsibility model for the router. A schema,
@@ -368,6 +646,12 @@ pub(crate) struct PluggableSupergraphServiceBuilder {
     plugins: Plugins,
     subgraph_services: Vec<(String, Arc<dyn MakeSubgraphService>)>,
     configuration: Option<Arc<Configuration>>,
+    persisted_queries: PersistedQueryRegistry,
+    maintenance_switch: MaintenanceSwitch,
+    quota_memory: Arc<InMemoryStorage>,
+    storages: Storages,
+    subgraph_health: SubgraphHealth,
+    entitlement: Option<Entitlement>,
 }
 
 impl PluggableSupergraphServiceBuilder {
@@ -377,6 +661,12 @@ impl PluggableSupergraphServiceBuilder {
             plugins: Default::default(),
             subgraph_services: Default::default(),
             configuration: None,
+            persisted_queries: Default::default(),
+            maintenance_switch: Default::default(),
+            quota_memory: Default::default(),
+            storages: Default::default(),
+            subgraph_health: Default::default(),
+            entitlement: None,
         }
     }
 
@@ -410,6 +700,58 @@ impl PluggableSupergraphServiceBuilder {
         self
     }
 
+    /// Keep the persisted query manifests of a previous router.
+    pub(crate) fn with_persisted_queries(
+        mut self,
+        persisted_queries: PersistedQueryRegistry,
+    ) -> PluggableSupergraphServiceBuilder {
+        self.persisted_queries = persisted_queries;
+        self
+    }
+
+    /// Keep the maintenance mode switched through the admin API of a previous router.
+    pub(crate) fn with_maintenance_switch(
+        mut self,
+        maintenance_switch: MaintenanceSwitch,
+    ) -> PluggableSupergraphServiceBuilder {
+        self.maintenance_switch = maintenance_switch;
+        self
+    }
+
+    /// Keep the usage of the clients accounted in memory by a previous router.
+    pub(crate) fn with_quota_memory(
+        mut self,
+        quota_memory: Arc<InMemoryStorage>,
+    ) -> PluggableSupergraphServiceBuilder {
+        self.quota_memory = quota_memory;
+        self
+    }
+
+    /// Report the health of the subgraphs checked by this pipeline.
+    pub(crate) fn with_subgraph_health(
+        mut self,
+        subgraph_health: SubgraphHealth,
+    ) -> PluggableSupergraphServiceBuilder {
+        self.subgraph_health = subgraph_health;
+        self
+    }
+
+    /// Report the entitlement the configuration was loaded with.
+    pub(crate) fn with_entitlement(
+        mut self,
+        entitlement: Entitlement,
+    ) -> PluggableSupergraphServiceBuilder {
+        self.entitlement = Some(entitlement);
+        self
+    }
+
+    /// Open the storages of the pipeline with the ones already opened for its plugins, reusing
+    /// those of a previous router.
+    pub(crate) fn with_storages(mut self, storages: Storages) -> PluggableSupergraphServiceBuilder {
+        self.storages = storages;
+        self
+    }
+
     pub(crate) async fn build(self) -> Result<RouterCreator, crate::error::ServiceBuildError> {
         // Note: The plugins are always applied in reverse, so that the
         // fold is applied in the correct sequence. We could reverse
@@ -425,47 +767,290 @@ impl PluggableSupergraphServiceBuilder {
             .and_then(|x| x.parse().ok())
             .unwrap_or(100);
 
-        let introspection = if configuration.server.introspection {
-            Some(Arc::new(Introspection::new(&configuration).await))
-        } else {
-            None
+        let mut storages = self.storages;
+        let storage = match &configuration.storage {
+            Some(storage) => Some((storages.open(storage).await, storage.apq_ttl)),
+            None => None,
+        };
+        let plan_storage = match (&storage, &configuration.storage) {
+            (
+                Some((storage, _)),
+                Some(Storage {
+                    query_plan_ttl: Some(ttl),
+                    ..
+                }),
+            ) => Some(PlanStorage {
+                storage: storage.clone(),
+                ttl: *ttl,
+            }),
+            _ => None,
         };
 
-        // QueryPlannerService takes an UnplannedRequest and outputs PlannedRequest
-        let bridge_query_planner =
-            BridgeQueryPlanner::new(self.schema.clone(), introspection, configuration)
-                .await
-                .map_err(ServiceBuildError::QueryPlannerError)?;
-        let query_planner_service =
-            CachingQueryPlanner::new(bridge_query_planner, plan_cache_limit).await;
+        let (query_planner_service, override_planners) = create_query_planners(
+            self.schema.clone(),
+            configuration.clone(),
+            plan_cache_limit,
+            plan_storage.clone(),
+        )
+        .await?;
+
+        // Every contract has its own schema, so it needs its own query planner and introspection
+        let mut contracts = Vec::new();
+        for (name, contract) in &configuration.contracts {
+            let schema = self
+                .schema
+                .contract(&contract.exclude_tags)
+                .and_then(|sdl| Schema::parse(&sdl, &configuration))
+                .map_err(|e| ServiceBuildError::Contract(name.clone(), e.to_string()))?;
+            let schema = Arc::new(schema);
+            let (query_planner_service, override_planners) = create_query_planners(
+                schema.clone(),
+                configuration.clone(),
+                plan_cache_limit,
+                plan_storage.clone(),
+            )
+            .await?;
+            contracts.push(ContractRouter {
+                contract: contract.clone(),
+                query_planner_service,
+                override_planners,
+                schema,
+            });
+        }
 
         let plugins = Arc::new(self.plugins);
+        // like the services, the response bodies go through the plugins in reverse order
+        let response_body_transformers = plugins
+            .values()
+            .rev()
+            .filter_map(|plugin| plugin.response_body_transformer())
+            .collect();
 
         let subgraph_creator = Arc::new(SubgraphCreator::new(
             self.subgraph_services,
             plugins.clone(),
         ));
 
-        let apq = APQLayer::with_cache(DeduplicatingCache::new().await);
+        let apq = APQLayer::with_cache(DeduplicatingCache::new().await)
+            .with_persisted_queries(self.persisted_queries.clone())
+            .with_extension(
+                configuration
+                    .server
+                    .experimental_persisted_query_extension
+                    .clone(),
+            )
+            .with_errors(configuration.server.experimental_persisted_query_errors)
+            .with_safelist(configuration.server.experimental_persisted_query_safelist);
+        let apq = match &configuration.cache_partitioning {
+            Some(cache_partitioning) => apq.with_tenants(cache_partitioning.max_tenants).await,
+            None => apq,
+        };
+        let apq = match &storage {
+            Some((storage, ttl)) => apq.with_storage(storage.clone(), *ttl),
+            None => apq,
+        };
+
+        let quotas = match &configuration.quotas {
+            Some(quotas) => {
+                // the quotas with their own Redis server do not use the storage
+                let store = match (&quotas.redis_url, &storage) {
+                    (Some(url), _) => Some(
+                        storages
+                            .open(&Storage {
+                                redis_url: Some(url.clone()),
+                                ..Default::default()
+                            })
+                            .await,
+                    ),
+                    (None, Some((storage, _))) => Some(storage.clone()),
+                    (None, None) => None,
+                };
+                Some(QuotaEnforcer::new(quotas.clone(), self.quota_memory, store))
+            }
+            None => None,
+        };
+
+        let maintenance =
+            MaintenanceLayer::new(configuration.maintenance.clone(), self.maintenance_switch)
+                .map_err(|e| ServiceBuildError::Maintenance(e.to_string()))?;
+
+        let operation_registry = configuration
+            .operation_registry
+            .clone()
+            .map(|operation_registry| {
+                OperationRegistryPoller::spawn(operation_registry, self.persisted_queries.clone())
+                    .map(Arc::new)
+                    .map_err(|e| ServiceBuildError::OperationRegistry(e.to_string()))
+            })
+            .transpose()?;
+
+        let token_keys = match &configuration.client_identification.jwks {
+            Some(jwks) => Some(
+                TokenKeys::new(jwks)
+                    .await
+                    .map_err(|e| ServiceBuildError::ClientIdentification(e.to_string()))?,
+            ),
+            None => None,
+        };
+
+        let surrogate_keys = configuration
+            .surrogate_keys
+            .clone()
+            .map(SurrogateKeyHeader::new)
+            .transpose()
+            .map_err(|e| ServiceBuildError::SurrogateKeys(e.to_string()))?;
 
         Ok(RouterCreator {
             query_planner_service,
+            override_planners,
             subgraph_creator,
             schema: self.schema,
             plugins,
             apq,
+            persisted_queries: self.persisted_queries,
+            _operation_registry: operation_registry,
+            quotas,
+            storages,
+            subgraph_health: self.subgraph_health,
+            entitlement: self.entitlement,
+            maintenance,
+            contracts: Arc::new(contracts),
+            stable_field_order: configuration.server.experimental_stable_field_order,
+            prune_response_fields: configuration.server.experimental_prune_response_fields,
+            client_identifier: ClientIdentifier::new(configuration.client_identification.clone())
+                .with_token_keys(token_keys)
+                .with_introspection(configuration.server.introspection)
+                .with_cache_partitioning(configuration.cache_partitioning.is_some()),
+            response_body_transformers,
+            data_masker: configuration.data_masking.clone().map(DataMasker::new),
+            surrogate_keys,
+            partial_results: PartialResultsPolicies::new(configuration.partial_results.clone()),
         })
     }
 }
 
+/// Creates the query planner of a supergraph, and the ones of its variants if it has progressive
+/// overrides.
+async fn create_query_planners(
+    schema: Arc<Schema>,
+    configuration: Arc<Configuration>,
+    plan_cache_limit: usize,
+    plan_storage: Option<PlanStorage>,
+) -> Result<
+    (
+        CachingQueryPlanner<BridgeQueryPlanner>,
+        Option<OverrideQueryPlanners>,
+    ),
+    ServiceBuildError,
+> {
+    match OverrideQueryPlanners::new(
+        schema.clone(),
+        configuration.clone(),
+        plan_cache_limit,
+        plan_storage.clone(),
+    ) {
+        // without any enabled label, fields are resolved by the subgraph they come from
+        Some(override_planners) => Ok((
+            override_planners.query_planner(BTreeSet::new()).await?,
+            Some(override_planners),
+        )),
+        None => Ok((
+            create_query_planner(schema, configuration, plan_cache_limit, plan_storage).await?,
+            None,
+        )),
+    }
+}
+
+pub(crate) async fn create_query_planner(
+    schema: Arc<Schema>,
+    configuration: Arc<Configuration>,
+    plan_cache_limit: usize,
+    plan_storage: Option<PlanStorage>,
+) -> Result<CachingQueryPlanner<BridgeQueryPlanner>, ServiceBuildError> {
+    let introspection = if configuration.server.introspection
+        || configuration
+            .client_identification
+            .overrides
+            .values()
+            .any(|overrides| overrides.introspection == Some(true))
+    {
+        Some(Arc::new(
+            Introspection::new(&configuration)
+                .await
+                .with_schema(schema.as_string()),
+        ))
+    } else {
+        None
+    };
+
+    let max_tenants = configuration
+        .cache_partitioning
+        .as_ref()
+        .map(|cache_partitioning| cache_partitioning.max_tenants);
+
+    // QueryPlannerService takes an UnplannedRequest and outputs PlannedRequest
+    let bridge_query_planner = BridgeQueryPlanner::new(schema, introspection, configuration)
+        .await
+        .map_err(ServiceBuildError::QueryPlannerError)?;
+    let bridge_query_planner = match plan_storage {
+        Some(plan_storage) => bridge_query_planner.with_storage(plan_storage),
+        None => bridge_query_planner,
+    };
+    let query_planner = CachingQueryPlanner::new(bridge_query_planner, plan_cache_limit).await;
+    Ok(match max_tenants {
+        Some(max_tenants) => query_planner.with_tenants(max_tenants).await,
+        None => query_planner,
+    })
+}
+
+/// A filtered variant of the supergraph, served to the requests matching its contract.
+#[derive(Clone)]
+struct ContractRouter {
+    contract: Contract,
+    query_planner_service: CachingQueryPlanner<BridgeQueryPlanner>,
+    override_planners: Option<OverrideQueryPlanners>,
+    schema: Arc<Schema>,
+}
+
+impl ContractRouter {
+    fn matches(
+        &self,
+        request: &http::Request<graphql::Request>,
+        client_name: Option<&str>,
+    ) -> bool {
+        self.contract.graphql_path.as_deref() == Some(request.uri().path())
+            || client_name
+                .map(|name| self.contract.client_names.iter().any(|n| n == name))
+                .unwrap_or(false)
+    }
+}
+
 /// A collection of services and data which may be used to create a "router".
 #[derive(Clone)]
 pub(crate) struct RouterCreator {
     query_planner_service: CachingQueryPlanner<BridgeQueryPlanner>,
+    override_planners: Option<OverrideQueryPlanners>,
     subgraph_creator: Arc<SubgraphCreator>,
     schema: Arc<Schema>,
     plugins: Arc<Plugins>,
     apq: APQLayer,
+    persisted_queries: PersistedQueryRegistry,
+    /// Polling of the operation registry, stopped when the last clone is dropped.
+    _operation_registry: Option<Arc<OperationRegistryPoller>>,
+    quotas: Option<QuotaEnforcer>,
+    storages: Storages,
+    /// Health checks of the subgraphs, stopped when the last clone is dropped.
+    subgraph_health: SubgraphHealth,
+    entitlement: Option<Entitlement>,
+    maintenance: MaintenanceLayer,
+    contracts: Arc<Vec<ContractRouter>>,
+    stable_field_order: bool,
+    prune_response_fields: bool,
+    client_identifier: ClientIdentifier,
+    response_body_transformers: Vec<ResponseBodyTransformer>,
+    data_masker: Option<DataMasker>,
+    surrogate_keys: Option<SurrogateKeyHeader>,
+    partial_results: PartialResultsPolicies,
 }
 
 impl NewService<http::Request<graphql::Request>> for RouterCreator {
@@ -475,10 +1060,7 @@ impl NewService<http::Request<graphql::Request>> for RouterCreator {
         BoxError,
     >;
     fn new_service(&self) -> Self::Service {
-        self.make()
-            .map_request(|http_request: http::Request<graphql::Request>| http_request.into())
-            .map_response(|response| response.response)
-            .boxed()
+        self.new_service_with(false)
     }
 }
 
@@ -494,6 +1076,10 @@ impl SupergraphServiceFactory for RouterCreator {
             http::Request<graphql::Request>,
         >>::Future;
 
+    fn canary_service(&self) -> Self::SupergraphService {
+        self.new_service_with(true)
+    }
+
     fn custom_endpoints(&self) -> std::collections::HashMap<String, crate::plugin::Handler> {
         self.plugins
             .iter()
@@ -505,9 +1091,144 @@ impl SupergraphServiceFactory for RouterCreator {
             })
             .collect()
     }
+
+    fn runtime_state(&self) -> RuntimeState {
+        let registered = crate::plugin::plugins();
+        RuntimeState {
+            schema: Some(self.schema.clone()),
+            plugins: self
+                .plugins
+                .keys()
+                .map(|name| PluginState {
+                    name: name.clone(),
+                    version: registered
+                        .get(name)
+                        .and_then(|factory| factory.version)
+                        .map(str::to_string),
+                })
+                .collect(),
+            caches: vec![
+                ("apq", self.apq.cache()),
+                ("query_plan", self.query_planner_service.cache()),
+            ],
+            persisted_queries: Some(self.persisted_queries.clone()),
+            quotas: self.quotas.clone(),
+            maintenance: Some(self.maintenance.clone()),
+            subgraph_health: self.subgraph_health.clone(),
+            entitlement: self.entitlement.clone(),
+        }
+    }
+
+    fn apq(&self) -> Option<APQLayer> {
+        Some(self.apq.clone())
+    }
+
+    fn response_body_transformers(&self) -> Vec<ResponseBodyTransformer> {
+        self.response_body_transformers.clone()
+    }
+
+    fn unready_plugins(&self) -> Vec<String> {
+        self.plugins
+            .iter()
+            .filter(|(_, plugin)| !plugin.is_ready())
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    fn unhealthy_subgraphs(&self) -> Vec<String> {
+        self.subgraph_health.unhealthy()
+    }
 }
 
 impl RouterCreator {
+    pub(crate) fn persisted_queries(&self) -> PersistedQueryRegistry {
+        self.persisted_queries.clone()
+    }
+
+    pub(crate) fn maintenance(&self) -> &MaintenanceLayer {
+        &self.maintenance
+    }
+
+    /// The storages opened by the router.
+    pub(crate) fn storages(&self) -> Storages {
+        self.storages.clone()
+    }
+
+    /// The memory of the quotas accounting the usage of the clients, if any.
+    pub(crate) fn quota_memory(&self) -> Option<Arc<InMemoryStorage>> {
+        self.quotas.as_ref().map(QuotaEnforcer::memory)
+    }
+
+    pub(crate) fn schema(&self) -> Arc<Schema> {
+        self.schema.clone()
+    }
+
+    pub(crate) fn plugins(&self) -> Arc<Plugins> {
+        self.plugins.clone()
+    }
+
+    /// The pipeline of the client requests, or of the canary requests, which do not go through
+    /// the quotas and the plugins bypassed by the canary operations.
+    fn new_service_with(
+        &self,
+        canary: bool,
+    ) -> BoxService<
+        http::Request<graphql::Request>,
+        http::Response<BoxStream<'static, Response>>,
+        BoxError,
+    > {
+        if self.contracts.is_empty() {
+            return self
+                .make_with(
+                    self.query_planner_service.clone(),
+                    self.override_planners.clone(),
+                    self.schema.clone(),
+                    canary,
+                )
+                .map_request(|http_request: http::Request<graphql::Request>| http_request.into())
+                .map_response(|response| response.response)
+                .boxed();
+        }
+
+        // the contract can only be chosen once the client is identified, which is done once
+        let creator = self.clone();
+        tower::service_fn(move |http_request: http::Request<graphql::Request>| {
+            let request = creator
+                .client_identifier
+                .identify_request(http_request.into());
+            let client = ClientInfo::from_context(&request.context);
+            let verified_name = client.name.as_deref().filter(|_| client.verified);
+            // the requests matching no contract must not see more than the default contract
+            let service = match creator
+                .contracts
+                .iter()
+                .find(|contract| contract.matches(&request.originating_request, verified_name))
+                .or_else(|| creator.contracts.iter().find(|c| c.contract.default))
+            {
+                Some(contract) => creator
+                    .make_with(
+                        contract.query_planner_service.clone(),
+                        contract.override_planners.clone(),
+                        contract.schema.clone(),
+                        canary,
+                    )
+                    .boxed(),
+                None => creator
+                    .make_with(
+                        creator.query_planner_service.clone(),
+                        creator.override_planners.clone(),
+                        creator.schema.clone(),
+                        canary,
+                    )
+                    .boxed(),
+            };
+            service
+                .oneshot(request)
+                .map_ok(|response| response.response)
+        })
+        .boxed()
+    }
+
     pub(crate) fn make(
         &self,
     ) -> impl Service<
@@ -516,24 +1237,99 @@ impl RouterCreator {
         Error = BoxError,
         Future = BoxFuture<'static, Result<SupergraphResponse, BoxError>>,
     > + Send {
+        self.make_with(
+            self.query_planner_service.clone(),
+            self.override_planners.clone(),
+            self.schema.clone(),
+            false,
+        )
+    }
+
+    fn make_with(
+        &self,
+        query_planner_service: CachingQueryPlanner<BridgeQueryPlanner>,
+        override_planners: Option<OverrideQueryPlanners>,
+        schema: Arc<Schema>,
+        canary: bool,
+    ) -> impl Service<
+        SupergraphRequest,
+        Response = SupergraphResponse,
+        Error = BoxError,
+        Future = BoxFuture<'static, Result<SupergraphResponse, BoxError>>,
+    > + Send {
+        let client_identifier = self.client_identifier.clone();
+        let partial_results = self.partial_results.clone();
+        // the canary requests are not accounted
+        let quotas = self.quotas.clone().filter(|_| !canary);
+        let subgraph_creator = if canary {
+            Arc::new(self.subgraph_creator.for_canary())
+        } else {
+            self.subgraph_creator.clone()
+        };
         ServiceBuilder::new()
+            .layer(MapRequestLayer::new(move |req: SupergraphRequest| {
+                client_identifier.identify_request(req)
+            }))
+            .layer(MapRequestLayer::new(move |req: SupergraphRequest| {
+                partial_results.record(req)
+            }))
+            .layer(self.maintenance.clone())
             .layer(self.apq.clone())
             .layer(EnsureQueryPresence::default())
+            .option_layer(
+                quotas
+                    .as_ref()
+                    .and_then(|quotas| quotas.cost_extension())
+                    .map(|extension| {
+                        let extension = extension.to_string();
+                        MapResponseLayer::new(move |response| expose_cost(&extension, response))
+                    }),
+            )
+            // the keys are computed from the fields sent to the client
+            .option_layer(self.surrogate_keys.clone().map(|surrogate_keys| {
+                let schema = schema.clone();
+                tower::layer::layer_fn(move |service| {
+                    add_surrogate_keys(surrogate_keys.clone(), schema.clone(), service)
+                })
+            }))
+            .option_layer(
+                self.stable_field_order
+                    .then(|| MapResponseLayer::new(order_response_fields)),
+            )
+            .option_layer(self.prune_response_fields.then(|| {
+                let schema = schema.clone();
+                MapResponseLayer::new(move |response| prune_response_fields(&schema, response))
+            }))
+            .option_layer(self.data_masker.clone().map(|masker| {
+                let schema = schema.clone();
+                MapResponseLayer::new(move |response| {
+                    mask_response_fields(&masker, &schema, response)
+                })
+            }))
+            // the errors of the masked fields do not fail the requests
+            .layer(MapResponseLayer::new(partial_results::enforce))
             .service(
-                self.plugins.iter().rev().fold(
-                    BoxService::new(
-                        SupergraphService::builder()
-                            .query_planner_service(self.query_planner_service.clone())
-                            .execution_service_factory(ExecutionCreator {
-                                schema: self.schema.clone(),
-                                plugins: self.plugins.clone(),
-                                subgraph_creator: self.subgraph_creator.clone(),
-                            })
-                            .schema(self.schema.clone())
-                            .build(),
+                self.plugins
+                    .iter()
+                    .rev()
+                    .filter(|(name, _)| !canary || !canary::bypasses(name))
+                    .fold(
+                        BoxService::new(
+                            SupergraphService::builder()
+                                .query_planner_service(query_planner_service)
+                                .execution_service_factory(ExecutionCreator {
+                                    schema: self.schema.clone(),
+                                    plugins: self.plugins.clone(),
+                                    subgraph_creator,
+                                    canary,
+                                })
+                                .and_override_planners(override_planners)
+                                .schema(schema)
+                                .and_quotas(quotas)
+                                .build(),
+                        ),
+                        |acc, (_, e)| e.supergraph_service(acc),
                     ),
-                    |acc, (_, e)| e.supergraph_service(acc),
-                ),
             )
     }
 
@@ -0,0 +1,790 @@
+//! Operation normalization and signatures.
+//!
+//! * [`normalize`] removes the ignored tokens (whitespace, comments, commas) of a document. The
+//!   result is equivalent to the original document, and is used as the query plan cache key.
+//! * [`signature`] computes the operation signature used by Apollo Studio to group the statistics
+//!   of operations that only differ by their literals, aliases, or the order of their fields.
+//! * [`stats_report_key`] prefixes the signature with the operation name, as expected in the
+//!   usage reports.
+
+use std::collections::HashSet;
+
+use apollo_parser::ast;
+use displaydoc::Display;
+use thiserror::Error;
+
+/// Errors returned when a signature can not be computed.
+#[derive(Error, Debug, Display, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SignatureError {
+    /// parsing error: {0}
+    Parse(String),
+    /// unknown operation '{0}'
+    UnknownOperation(String),
+    /// the operation name is required when the document contains several operations
+    OperationNameRequired,
+}
+
+/// Returns the document without its ignored tokens.
+pub fn normalize(query: &str) -> Result<String, SignatureError> {
+    let definitions = parse(query)?;
+    let mut printer = Printer::default();
+    for definition in &definitions {
+        printer.definition(definition);
+    }
+    Ok(printer.finish())
+}
+
+/// Returns the signature of an operation.
+///
+/// The document is reduced to the operation and the fragments it uses, and then:
+/// * literals are replaced by `0`, `""`, `[]` or `{}`
+/// * aliases are removed
+/// * definitions, fields, arguments and directives are sorted
+/// * ignored tokens are removed
+pub fn signature(query: &str, operation_name: Option<&str>) -> Result<String, SignatureError> {
+    let mut definitions = parse(query)?;
+    drop_unused_definitions(&mut definitions, operation_name)?;
+    for definition in &mut definitions {
+        definition.hide_literals();
+        definition.remove_aliases();
+        definition.sort();
+    }
+    definitions.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+
+    let mut printer = Printer::default();
+    for definition in &definitions {
+        printer.definition(definition);
+    }
+    Ok(printer.finish())
+}
+
+/// Returns the key of an operation in the usage reports: `# <operation name>\n<signature>`.
+pub fn stats_report_key(
+    query: &str,
+    operation_name: Option<&str>,
+) -> Result<String, SignatureError> {
+    Ok(format!(
+        "# {}\n{}",
+        operation_name.unwrap_or("-"),
+        signature(query, operation_name)?
+    ))
+}
+
+#[derive(Clone, Debug)]
+enum Definition {
+    Operation {
+        kind: &'static str,
+        name: Option<String>,
+        variables: Vec<VariableDefinition>,
+        directives: Vec<Directive>,
+        selections: Vec<Selection>,
+    },
+    Fragment {
+        name: String,
+        type_condition: String,
+        directives: Vec<Directive>,
+        selections: Vec<Selection>,
+    },
+}
+
+#[derive(Clone, Debug)]
+struct VariableDefinition {
+    name: String,
+    ty: String,
+    default_value: Option<Value>,
+    directives: Vec<Directive>,
+}
+
+#[derive(Clone, Debug)]
+enum Selection {
+    Field {
+        alias: Option<String>,
+        name: String,
+        arguments: Vec<(String, Value)>,
+        directives: Vec<Directive>,
+        selections: Vec<Selection>,
+    },
+    FragmentSpread {
+        name: String,
+        directives: Vec<Directive>,
+    },
+    InlineFragment {
+        type_condition: Option<String>,
+        directives: Vec<Directive>,
+        selections: Vec<Selection>,
+    },
+}
+
+#[derive(Clone, Debug)]
+struct Directive {
+    name: String,
+    arguments: Vec<(String, Value)>,
+}
+
+#[derive(Clone, Debug)]
+enum Value {
+    Variable(String),
+    /// Int, float, string, boolean, null and enum values, as written in the document.
+    Scalar(String),
+    List(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+fn parse(query: &str) -> Result<Vec<Definition>, SignatureError> {
+    let tree = apollo_parser::Parser::new(query).parse();
+    if let Some(error) = tree.errors().next() {
+        return Err(SignatureError::Parse(format!("{:?}", error)));
+    }
+
+    tree.document()
+        .definitions()
+        .filter_map(|definition| match definition {
+            ast::Definition::OperationDefinition(operation) => {
+                Some(Definition::try_from_operation(operation))
+            }
+            ast::Definition::FragmentDefinition(fragment) => {
+                Some(Definition::try_from_fragment(fragment))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn name(name: Option<ast::Name>) -> Result<String, SignatureError> {
+    name.map(|name| name.text().to_string())
+        .ok_or_else(|| SignatureError::Parse("missing name".to_string()))
+}
+
+fn ty(node: Option<ast::Type>) -> Result<String, SignatureError> {
+    match node {
+        Some(ast::Type::NamedType(named)) => name(named.name()),
+        Some(ast::Type::ListType(list)) => Ok(format!("[{}]", ty(list.ty())?)),
+        Some(ast::Type::NonNullType(non_null)) => {
+            match (non_null.named_type(), non_null.list_type()) {
+                (Some(named), _) => Ok(format!("{}!", name(named.name())?)),
+                (None, Some(list)) => Ok(format!("[{}]!", ty(list.ty())?)),
+                (None, None) => Err(SignatureError::Parse("missing type".to_string())),
+            }
+        }
+        None => Err(SignatureError::Parse("missing type".to_string())),
+    }
+}
+
+fn type_condition(condition: Option<ast::TypeCondition>) -> Result<Option<String>, SignatureError> {
+    condition
+        .map(|condition| name(condition.named_type().and_then(|named| named.name())))
+        .transpose()
+}
+
+fn directives(directives: Option<ast::Directives>) -> Result<Vec<Directive>, SignatureError> {
+    directives
+        .into_iter()
+        .flat_map(|directives| directives.directives())
+        .map(|directive| {
+            Ok(Directive {
+                name: name(directive.name())?,
+                arguments: arguments(directive.arguments())?,
+            })
+        })
+        .collect()
+}
+
+fn arguments(arguments: Option<ast::Arguments>) -> Result<Vec<(String, Value)>, SignatureError> {
+    arguments
+        .into_iter()
+        .flat_map(|arguments| arguments.arguments())
+        .map(|argument| Ok((name(argument.name())?, value(argument.value())?)))
+        .collect()
+}
+
+fn value(value: Option<ast::Value>) -> Result<Value, SignatureError> {
+    Ok(match value {
+        Some(ast::Value::Variable(variable)) => Value::Variable(name(variable.name())?),
+        Some(ast::Value::ListValue(list)) => Value::List(
+            list.values()
+                .map(|value| self::value(Some(value)))
+                .collect::<Result<_, _>>()?,
+        ),
+        Some(ast::Value::ObjectValue(object)) => Value::Object(
+            object
+                .object_fields()
+                .map(|field| Ok((name(field.name())?, self::value(field.value())?)))
+                .collect::<Result<_, _>>()?,
+        ),
+        Some(ast::Value::StringValue(string)) => Value::Scalar(literal(string.to_string())),
+        Some(ast::Value::FloatValue(float)) => Value::Scalar(literal(float.to_string())),
+        Some(ast::Value::IntValue(int)) => Value::Scalar(literal(int.to_string())),
+        Some(ast::Value::BooleanValue(boolean)) => {
+            Value::Scalar(boolean.true_token().is_some().to_string())
+        }
+        Some(ast::Value::NullValue(_)) => Value::Scalar("null".to_string()),
+        Some(ast::Value::EnumValue(enum_value)) => Value::Scalar(name(enum_value.name())?),
+        None => return Err(SignatureError::Parse("missing value".to_string())),
+    })
+}
+
+/// Literals are printed with the ignored tokens that follow them.
+fn literal(text: String) -> String {
+    text.trim_end_matches(|c: char| c.is_whitespace() || c == ',')
+        .trim_start()
+        .to_string()
+}
+
+fn selections(selection_set: Option<ast::SelectionSet>) -> Result<Vec<Selection>, SignatureError> {
+    selection_set
+        .into_iter()
+        .flat_map(|selection_set| selection_set.selections())
+        .map(|selection| {
+            Ok(match selection {
+                ast::Selection::Field(field) => Selection::Field {
+                    alias: field.alias().map(|alias| name(alias.name())).transpose()?,
+                    name: name(field.name())?,
+                    arguments: arguments(field.arguments())?,
+                    directives: directives(field.directives())?,
+                    selections: selections(field.selection_set())?,
+                },
+                ast::Selection::FragmentSpread(spread) => Selection::FragmentSpread {
+                    name: name(spread.fragment_name().and_then(|name| name.name()))?,
+                    directives: directives(spread.directives())?,
+                },
+                ast::Selection::InlineFragment(fragment) => Selection::InlineFragment {
+                    type_condition: type_condition(fragment.type_condition())?,
+                    directives: directives(fragment.directives())?,
+                    selections: selections(fragment.selection_set())?,
+                },
+            })
+        })
+        .collect()
+}
+
+impl Definition {
+    fn try_from_operation(operation: ast::OperationDefinition) -> Result<Self, SignatureError> {
+        let kind = match operation.operation_type() {
+            Some(kind) if kind.mutation_token().is_some() => "mutation",
+            Some(kind) if kind.subscription_token().is_some() => "subscription",
+            _ => "query",
+        };
+        Ok(Definition::Operation {
+            kind,
+            name: operation.name().map(|name| name.text().to_string()),
+            variables: operation
+                .variable_definitions()
+                .into_iter()
+                .flat_map(|definitions| definitions.variable_definitions())
+                .map(|definition| {
+                    Ok(VariableDefinition {
+                        name: name(definition.variable().and_then(|variable| variable.name()))?,
+                        ty: ty(definition.ty())?,
+                        default_value: definition
+                            .default_value()
+                            .map(|default| value(default.value()))
+                            .transpose()?,
+                        directives: directives(definition.directives())?,
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+            directives: directives(operation.directives())?,
+            selections: selections(operation.selection_set())?,
+        })
+    }
+
+    fn try_from_fragment(fragment: ast::FragmentDefinition) -> Result<Self, SignatureError> {
+        Ok(Definition::Fragment {
+            name: name(fragment.fragment_name().and_then(|name| name.name()))?,
+            type_condition: type_condition(fragment.type_condition())?
+                .ok_or_else(|| SignatureError::Parse("missing type condition".to_string()))?,
+            directives: directives(fragment.directives())?,
+            selections: selections(fragment.selection_set())?,
+        })
+    }
+
+    fn sort_key(&self) -> (&'static str, &str) {
+        match self {
+            Definition::Fragment { name, .. } => ("FragmentDefinition", name),
+            Definition::Operation { name, .. } => {
+                ("OperationDefinition", name.as_deref().unwrap_or_default())
+            }
+        }
+    }
+
+    fn parts_mut(&mut self) -> (Vec<&mut Directive>, &mut Vec<Selection>) {
+        match self {
+            Definition::Operation {
+                variables,
+                directives,
+                selections,
+                ..
+            } => (
+                variables
+                    .iter_mut()
+                    .flat_map(|variable| variable.directives.iter_mut())
+                    .chain(directives.iter_mut())
+                    .collect(),
+                selections,
+            ),
+            Definition::Fragment {
+                directives,
+                selections,
+                ..
+            } => (directives.iter_mut().collect(), selections),
+        }
+    }
+
+    fn hide_literals(&mut self) {
+        if let Definition::Operation { variables, .. } = self {
+            for variable in variables {
+                if let Some(default_value) = &mut variable.default_value {
+                    default_value.hide_literals();
+                }
+            }
+        }
+        let (directives, selections) = self.parts_mut();
+        directives
+            .into_iter()
+            .for_each(|directive| hide_literals(&mut directive.arguments));
+        selections.iter_mut().for_each(Selection::hide_literals);
+    }
+
+    fn remove_aliases(&mut self) {
+        let (_, selections) = self.parts_mut();
+        selections.iter_mut().for_each(Selection::remove_aliases);
+    }
+
+    fn sort(&mut self) {
+        if let Definition::Operation { variables, .. } = self {
+            variables.sort_by(|a, b| a.name.cmp(&b.name));
+            for variable in variables {
+                sort_directives(&mut variable.directives);
+            }
+        }
+        let (directives, selections) = self.parts_mut();
+        directives
+            .into_iter()
+            .for_each(|directive| directive.arguments.sort_by(|a, b| a.0.cmp(&b.0)));
+        sort_selections(selections);
+        match self {
+            Definition::Operation { directives, .. } | Definition::Fragment { directives, .. } => {
+                directives.sort_by(|a, b| a.name.cmp(&b.name))
+            }
+        }
+    }
+
+    fn used_fragments(&self, fragments: &mut Vec<String>) {
+        let selections = match self {
+            Definition::Operation { selections, .. } | Definition::Fragment { selections, .. } => {
+                selections
+            }
+        };
+        Selection::used_fragments(selections, fragments);
+    }
+}
+
+impl Selection {
+    fn directives_mut(&mut self) -> &mut Vec<Directive> {
+        match self {
+            Selection::Field { directives, .. }
+            | Selection::FragmentSpread { directives, .. }
+            | Selection::InlineFragment { directives, .. } => directives,
+        }
+    }
+
+    fn selections_mut(&mut self) -> Option<&mut Vec<Selection>> {
+        match self {
+            Selection::Field { selections, .. } | Selection::InlineFragment { selections, .. } => {
+                Some(selections)
+            }
+            Selection::FragmentSpread { .. } => None,
+        }
+    }
+
+    fn hide_literals(&mut self) {
+        if let Selection::Field { arguments, .. } = self {
+            hide_literals(arguments);
+        }
+        for directive in self.directives_mut() {
+            hide_literals(&mut directive.arguments);
+        }
+        if let Some(selections) = self.selections_mut() {
+            selections.iter_mut().for_each(Selection::hide_literals);
+        }
+    }
+
+    fn remove_aliases(&mut self) {
+        if let Selection::Field { alias, .. } = self {
+            *alias = None;
+        }
+        if let Some(selections) = self.selections_mut() {
+            selections.iter_mut().for_each(Selection::remove_aliases);
+        }
+    }
+
+    /// Selections are sorted by kind, then by name.
+    fn sort_key(&self) -> (&'static str, &str) {
+        match self {
+            Selection::Field { name, .. } => ("Field", name),
+            Selection::FragmentSpread { name, .. } => ("FragmentSpread", name),
+            Selection::InlineFragment { .. } => ("InlineFragment", ""),
+        }
+    }
+
+    fn used_fragments(selections: &[Selection], fragments: &mut Vec<String>) {
+        for selection in selections {
+            match selection {
+                Selection::FragmentSpread { name, .. } => fragments.push(name.clone()),
+                Selection::Field { selections, .. }
+                | Selection::InlineFragment { selections, .. } => {
+                    Selection::used_fragments(selections, fragments)
+                }
+            }
+        }
+    }
+}
+
+impl Value {
+    fn hide_literals(&mut self) {
+        match self {
+            Value::Variable(_) => {}
+            Value::Scalar(scalar) => {
+                if scalar.starts_with('"') {
+                    *scalar = "\"\"".to_string();
+                } else if scalar.starts_with(|c: char| c == '-' || c.is_ascii_digit()) {
+                    *scalar = "0".to_string();
+                }
+            }
+            Value::List(values) => values.clear(),
+            Value::Object(fields) => fields.clear(),
+        }
+    }
+}
+
+fn hide_literals(arguments: &mut [(String, Value)]) {
+    arguments
+        .iter_mut()
+        .for_each(|(_, value)| value.hide_literals());
+}
+
+fn sort_directives(directives: &mut [Directive]) {
+    directives.sort_by(|a, b| a.name.cmp(&b.name));
+    for directive in directives {
+        directive.arguments.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+}
+
+fn sort_selections(selections: &mut [Selection]) {
+    selections.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+    for selection in selections {
+        if let Selection::Field { arguments, .. } = selection {
+            arguments.sort_by(|a, b| a.0.cmp(&b.0));
+        }
+        sort_directives(selection.directives_mut());
+        if let Some(selections) = selection.selections_mut() {
+            sort_selections(selections);
+        }
+    }
+}
+
+/// Keeps the operation and the fragments it uses, transitively.
+fn drop_unused_definitions(
+    definitions: &mut Vec<Definition>,
+    operation_name: Option<&str>,
+) -> Result<(), SignatureError> {
+    let operations = definitions
+        .iter()
+        .filter_map(|definition| match definition {
+            Definition::Operation { name, .. } => Some(name.as_deref()),
+            Definition::Fragment { .. } => None,
+        })
+        .collect::<Vec<_>>();
+    let operation = match operation_name {
+        Some(operation_name) => operations
+            .iter()
+            .find(|name| **name == Some(operation_name))
+            .ok_or_else(|| SignatureError::UnknownOperation(operation_name.to_string()))?,
+        None if operations.len() == 1 => &operations[0],
+        None => return Err(SignatureError::OperationNameRequired),
+    }
+    .map(|name| name.to_string());
+
+    let mut used = HashSet::new();
+    let mut pending = Vec::new();
+    for definition in definitions.iter() {
+        if matches!(definition, Definition::Operation { name, .. } if *name == operation) {
+            definition.used_fragments(&mut pending);
+        }
+    }
+    while let Some(fragment) = pending.pop() {
+        if used.insert(fragment.clone()) {
+            for definition in definitions.iter() {
+                if matches!(definition, Definition::Fragment { name, .. } if *name == fragment) {
+                    definition.used_fragments(&mut pending);
+                }
+            }
+        }
+    }
+
+    definitions.retain(|definition| match definition {
+        Definition::Operation { name, .. } => *name == operation,
+        Definition::Fragment { name, .. } => used.contains(name),
+    });
+    Ok(())
+}
+
+/// Prints tokens separated by a space only when both sides are part of a name.
+#[derive(Default)]
+struct Printer {
+    output: String,
+}
+
+impl Printer {
+    fn finish(self) -> String {
+        self.output
+    }
+
+    fn token(&mut self, token: &str) {
+        let is_name_char = |c: char| c == '_' || c.is_ascii_alphanumeric();
+        if let (Some(last), Some(first)) = (self.output.chars().last(), token.chars().next()) {
+            if is_name_char(last) && is_name_char(first) {
+                self.output.push(' ');
+            }
+        }
+        self.output.push_str(token);
+    }
+
+    fn definition(&mut self, definition: &Definition) {
+        match definition {
+            Definition::Operation {
+                kind,
+                name,
+                variables,
+                directives,
+                selections,
+            } => {
+                if name.is_some()
+                    || !variables.is_empty()
+                    || !directives.is_empty()
+                    || *kind != "query"
+                {
+                    self.token(kind);
+                    if let Some(name) = name {
+                        self.token(name);
+                    }
+                    if !variables.is_empty() {
+                        self.token("(");
+                        for (i, variable) in variables.iter().enumerate() {
+                            if i > 0 {
+                                self.token(",");
+                            }
+                            self.token("$");
+                            self.token(&variable.name);
+                            self.token(":");
+                            self.token(&variable.ty);
+                            if let Some(default_value) = &variable.default_value {
+                                self.token("=");
+                                self.value(default_value);
+                            }
+                            self.directives(&variable.directives);
+                        }
+                        self.token(")");
+                    }
+                    self.directives(directives);
+                }
+                self.selections(selections);
+            }
+            Definition::Fragment {
+                name,
+                type_condition,
+                directives,
+                selections,
+            } => {
+                self.token("fragment");
+                self.token(name);
+                self.token("on");
+                self.token(type_condition);
+                self.directives(directives);
+                self.selections(selections);
+            }
+        }
+    }
+
+    fn selections(&mut self, selections: &[Selection]) {
+        if selections.is_empty() {
+            return;
+        }
+        self.token("{");
+        for selection in selections {
+            match selection {
+                Selection::Field {
+                    alias,
+                    name,
+                    arguments,
+                    directives,
+                    selections,
+                } => {
+                    if let Some(alias) = alias {
+                        self.token(alias);
+                        self.token(":");
+                    }
+                    self.token(name);
+                    self.arguments(arguments);
+                    self.directives(directives);
+                    self.selections(selections);
+                }
+                Selection::FragmentSpread { name, directives } => {
+                    self.token("...");
+                    self.token(name);
+                    self.directives(directives);
+                }
+                Selection::InlineFragment {
+                    type_condition,
+                    directives,
+                    selections,
+                } => {
+                    self.token("...");
+                    if let Some(type_condition) = type_condition {
+                        self.token("on");
+                        self.token(type_condition);
+                    }
+                    self.directives(directives);
+                    self.selections(selections);
+                }
+            }
+        }
+        self.token("}");
+    }
+
+    fn directives(&mut self, directives: &[Directive]) {
+        for directive in directives {
+            self.token("@");
+            self.token(&directive.name);
+            self.arguments(&directive.arguments);
+        }
+    }
+
+    fn arguments(&mut self, arguments: &[(String, Value)]) {
+        if arguments.is_empty() {
+            return;
+        }
+        self.token("(");
+        self.fields(arguments);
+        self.token(")");
+    }
+
+    fn fields(&mut self, fields: &[(String, Value)]) {
+        for (i, (name, value)) in fields.iter().enumerate() {
+            if i > 0 {
+                self.token(",");
+            }
+            self.token(name);
+            self.token(":");
+            self.value(value);
+        }
+    }
+
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::Variable(name) => {
+                self.token("$");
+                self.token(name);
+            }
+            Value::Scalar(scalar) => self.token(scalar),
+            Value::List(values) => {
+                self.token("[");
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        self.token(",");
+                    }
+                    self.value(value);
+                }
+                self.token("]");
+            }
+            Value::Object(fields) => {
+                self.token("{");
+                self.fields(fields);
+                self.token("}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_normalizes() {
+        assert_eq!(
+            normalize(
+                r#"
+                # comment
+                query Search($term: String = "a b", $first: Int) {
+                  search(term: $term, first: $first, filter: { tags: ["x", "y"] }) {
+                    ... on Book { title }
+                    ...Author
+                  }
+                }
+
+                fragment Author on Author { name }
+                "#
+            )
+            .unwrap(),
+            r#"query Search($term:String="a b",$first:Int){search(term:$term,first:$first,filter:{tags:["x","y"]}){...on Book{title}...Author}}fragment Author on Author{name}"#
+        );
+    }
+
+    #[test]
+    fn it_computes_signatures() {
+        assert_eq!(
+            signature(
+                r#"query GetMostPopularBooks {
+                  books(orderBy: "popularity", first: 10) {
+                    title
+                    writer: author { name }
+                  }
+                }"#,
+                None
+            )
+            .unwrap(),
+            r#"query GetMostPopularBooks{books(first:0,orderBy:""){author{name}title}}"#
+        );
+    }
+
+    #[test]
+    fn it_keeps_the_operation_and_its_fragments() {
+        let query = r#"
+            fragment Unused on User { id }
+            query Other { me { id } }
+            query Me($id: ID = "1") { me { ...Details @include(if: true) } }
+            fragment Details on User { name ...Nested }
+            fragment Nested on User { email }
+        "#;
+        assert_eq!(
+            signature(query, Some("Me")).unwrap(),
+            r#"fragment Details on User{name...Nested}fragment Nested on User{email}query Me($id:ID=""){me{...Details@include(if:true)}}"#
+        );
+        assert_eq!(
+            stats_report_key(query, Some("Other")).unwrap(),
+            "# Other\nquery Other{me{id}}"
+        );
+        assert_eq!(
+            signature(query, None),
+            Err(SignatureError::OperationNameRequired)
+        );
+        assert_eq!(
+            signature(query, Some("Unknown")),
+            Err(SignatureError::UnknownOperation("Unknown".to_string()))
+        );
+    }
+
+    #[test]
+    fn anonymous_operations() {
+        assert_eq!(
+            stats_report_key("{ me { id } }", None).unwrap(),
+            "# -\n{me{id}}"
+        );
+        assert!(matches!(
+            signature("{ me { id }", None),
+            Err(SignatureError::Parse(_))
+        ));
+    }
+}
@@ -32,6 +32,24 @@ pub(crate) enum FieldType {
 }
 
 impl FieldType {
+    /// Set the fields missing from the input objects of the value to their default value.
+    pub(crate) fn apply_default_values(&self, value: &mut Value, schema: &Schema) {
+        match (self, value) {
+            (FieldType::List(inner_ty), Value::Array(values)) => values
+                .iter_mut()
+                .for_each(|value| inner_ty.apply_default_values(value, schema)),
+            (FieldType::List(inner_ty), value) | (FieldType::NonNull(inner_ty), value) => {
+                inner_ty.apply_default_values(value, schema)
+            }
+            (FieldType::Named(name), Value::Object(object)) => {
+                if let Some(object_ty) = schema.input_types.get(name) {
+                    object_ty.apply_default_values(object, schema)
+                }
+            }
+            _ => {}
+        }
+    }
+
     // This function validates input values according to the graphql specification.
     // Each of the values are validated against the "input coercion" rules.
     pub(crate) fn validate_input_value(
@@ -86,11 +104,13 @@ impl FieldType {
                     inner_ty.validate_input_value(value, schema)
                 }
             }
-            (FieldType::Named(name), _)
-                if schema.custom_scalars.contains(name) || schema.enums.contains_key(name) =>
-            {
-                Ok(())
-            }
+            (FieldType::Named(name), _) if schema.custom_scalars.contains(name) => Ok(()),
+            // Spec: https://spec.graphql.org/draft/#sec-Enums.Input-Coercion
+            (FieldType::Named(name), value) if schema.enums.contains_key(name) => match value {
+                Value::Null => Ok(()),
+                Value::String(value) if schema.enums[name].contains(value.as_str()) => Ok(()),
+                _ => Err(InvalidValue),
+            },
             (FieldType::Named(name), value) if value.is_object() => {
                 if let Some(object_ty) = schema.input_types.get(name) {
                     object_ty
@@ -138,6 +158,20 @@ impl FieldType {
     pub(crate) fn is_non_null(&self) -> bool {
         matches!(self, FieldType::NonNull(_))
     }
+
+    /// The type as written in a GraphQL document, e.g. `[String!]`.
+    pub(crate) fn to_graphql_type(&self) -> String {
+        match self {
+            FieldType::Named(name) | FieldType::Introspection(name) => name.clone(),
+            FieldType::List(inner) => format!("[{}]", inner.to_graphql_type()),
+            FieldType::NonNull(inner) => format!("{}!", inner.to_graphql_type()),
+            FieldType::String => "String".to_string(),
+            FieldType::Int => "Int".to_string(),
+            FieldType::Float => "Float".to_string(),
+            FieldType::Id => "ID".to_string(),
+            FieldType::Boolean => "Boolean".to_string(),
+        }
+    }
 }
 
 impl From<ast::Type> for FieldType {
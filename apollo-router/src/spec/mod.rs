@@ -4,14 +4,23 @@ mod query;
 mod schema;
 mod selection;
 
+use apollo_parser::ast;
+use apollo_parser::ast::AstNode;
 use displaydoc::Display;
 pub(crate) use field_type::*;
 pub(crate) use fragments::*;
 pub(crate) use query::Query;
+use router_bridge::planner::PlanErrors;
 pub(crate) use schema::Schema;
 pub(crate) use selection::*;
+use serde_json_bytes::json;
 use thiserror::Error;
 
+use crate::error::ErrorCode;
+use crate::error::Location;
+use crate::graphql;
+use crate::json_ext::Object;
+
 /// GraphQL parsing errors.
 #[derive(Error, Debug, Display, Clone)]
 #[non_exhaustive]
@@ -21,7 +30,228 @@ pub(crate) enum SpecError {
     /// invalid type error, expected another type than '{0}'
     InvalidType(String),
     /// parsing error: {0}
-    ParsingError(String),
+    ParsingError(SyntaxErrors),
     /// subscription operation is not supported
     SubscriptionNotSupported,
 }
+
+impl SpecError {
+    /// Convert the error to GraphQL errors, syntax errors being reported separately with their
+    /// location in the query.
+    pub(crate) fn to_graphql_errors(&self) -> Vec<graphql::Error> {
+        match self {
+            SpecError::ParsingError(errors) => {
+                errors.0.iter().map(SyntaxError::to_graphql_error).collect()
+            }
+            _ => vec![graphql::Error::builder()
+                .message(self.to_string())
+                .code(ErrorCode::GraphqlValidationFailed)
+                .build()],
+        }
+    }
+}
+
+/// Syntax errors found when parsing a query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SyntaxErrors(pub(crate) Vec<SyntaxError>);
+
+impl std::fmt::Display for SyntaxErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(
+            &self
+                .0
+                .iter()
+                .map(std::string::ToString::to_string)
+                .collect::<Vec<String>>()
+                .join(", "),
+        )
+    }
+}
+
+/// A syntax error, with the token that caused it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SyntaxError {
+    pub(crate) message: String,
+    pub(crate) token: String,
+    pub(crate) location: Location,
+    /// The query line containing the error, with the token underlined.
+    pub(crate) source_excerpt: Option<String>,
+}
+
+impl SyntaxError {
+    pub(crate) fn new(error: &apollo_parser::Error, query: &str, source_excerpt: bool) -> Self {
+        let (location, source_excerpt) = locate(query, error.index(), error.data(), source_excerpt);
+        SyntaxError {
+            message: error.message().to_string(),
+            token: error.data().to_string(),
+            location,
+            source_excerpt,
+        }
+    }
+
+    fn to_graphql_error(&self) -> graphql::Error {
+        let mut extensions = Object::new();
+        extensions.insert("token", json!(self.token));
+        if let Some(source_excerpt) = &self.source_excerpt {
+            extensions.insert("sourceExcerpt", json!(source_excerpt));
+        }
+        graphql::Error::builder()
+            .message(self.message.clone())
+            .location(self.location.clone())
+            .code(ErrorCode::GraphqlParseFailed)
+            .extensions(extensions)
+            .build()
+    }
+}
+
+impl std::fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} at {}:{} ({:?})",
+            self.message, self.location.line, self.location.column, self.token
+        )
+    }
+}
+
+/// A validation error returned by the query planner, located in the query by the name it quotes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ValidationError {
+    pub(crate) message: String,
+    pub(crate) token: Option<String>,
+    pub(crate) locations: Vec<Location>,
+    /// The query line containing the error, with the token underlined.
+    pub(crate) source_excerpt: Option<String>,
+}
+
+impl ValidationError {
+    /// Locate the planning errors in the query.
+    ///
+    /// The planner does not return the locations of the errors, so they are found from the name
+    /// quoted first in their message, like `"UnusedFragment"` in `Fragment "UnusedFragment" is
+    /// never used.`. When the name appears several times in the query, the errors with the same
+    /// message are matched with its occurrences in order, and left without location if their
+    /// counts differ.
+    pub(crate) fn from_plan_errors(
+        errors: &PlanErrors,
+        query: &str,
+        source_excerpt: bool,
+    ) -> Vec<ValidationError> {
+        let tree = apollo_parser::Parser::new(query).parse();
+        let names = tree
+            .document()
+            .syntax()
+            .descendants()
+            .filter_map(ast::Name::cast)
+            .map(|name| {
+                (
+                    name.text().to_string(),
+                    usize::from(name.syntax().text_range().start()),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let messages = errors
+            .errors
+            .iter()
+            .map(|error| error.message.clone().unwrap_or_default())
+            .collect::<Vec<_>>();
+        messages
+            .iter()
+            .enumerate()
+            .map(|(i, message)| {
+                let token = message
+                    .split('"')
+                    .nth(1)
+                    .map(|quoted| quoted.trim_start_matches('$'))
+                    .filter(|quoted| !quoted.is_empty());
+                let occurrences = token
+                    .map(|token| {
+                        names
+                            .iter()
+                            .filter(|(name, _)| name == token)
+                            .map(|(_, index)| *index)
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_default();
+                let same_message = messages.iter().filter(|m| *m == message).count();
+                let rank = messages[..i].iter().filter(|m| *m == message).count();
+                let index = if occurrences.len() == 1 {
+                    occurrences.first()
+                } else if occurrences.len() == same_message {
+                    occurrences.get(rank)
+                } else {
+                    None
+                };
+
+                let (locations, source_excerpt) = match (index, token) {
+                    (Some(index), Some(token)) => {
+                        let (location, excerpt) = locate(query, *index, token, source_excerpt);
+                        (vec![location], excerpt)
+                    }
+                    _ => (Vec::new(), None),
+                };
+                ValidationError {
+                    message: message.clone(),
+                    token: index.and(token).map(str::to_string),
+                    locations,
+                    source_excerpt,
+                }
+            })
+            .collect()
+    }
+
+    pub(crate) fn to_graphql_error(&self) -> graphql::Error {
+        let mut extensions = Object::new();
+        if let Some(token) = &self.token {
+            extensions.insert("token", json!(token));
+        }
+        if let Some(source_excerpt) = &self.source_excerpt {
+            extensions.insert("sourceExcerpt", json!(source_excerpt));
+        }
+        graphql::Error::builder()
+            .message(self.message.clone())
+            .locations(self.locations.clone())
+            .code(ErrorCode::GraphqlValidationFailed)
+            .extensions(extensions)
+            .build()
+    }
+}
+
+/// The location of the token at `index` in the query, and the line containing it with the token
+/// underlined if `source_excerpt` is set.
+fn locate(
+    query: &str,
+    index: usize,
+    token: &str,
+    source_excerpt: bool,
+) -> (Location, Option<String>) {
+    let index = index.min(query.len());
+    let line_start = query[..index].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_end = query[index..]
+        .find('\n')
+        .map(|i| index + i)
+        .unwrap_or_else(|| query.len());
+    let line = query[..index].matches('\n').count() + 1;
+    let column = query[line_start..index].chars().count() + 1;
+
+    let source_excerpt = source_excerpt.then(|| {
+        let gutter = line.to_string();
+        format!(
+            "{} | {}\n{} | {}{}",
+            gutter,
+            &query[line_start..line_end],
+            " ".repeat(gutter.len()),
+            " ".repeat(column - 1),
+            "^".repeat(token.chars().count().max(1))
+        )
+    });
+
+    (
+        Location {
+            line: line as i32,
+            column: column as i32,
+        },
+        source_excerpt,
+    )
+}
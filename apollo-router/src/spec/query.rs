@@ -7,16 +7,22 @@ use std::collections::HashSet;
 
 use apollo_parser::ast;
 use derivative::Derivative;
+use indexmap::IndexMap;
 use serde_json_bytes::ByteString;
 use tracing::level_filters::LevelFilter;
 
+use crate::configuration::ResponseValidation;
 use crate::error::FetchError;
+use crate::graphql::Error;
+use crate::graphql::ErrorCode;
 use crate::graphql::Request;
 use crate::graphql::Response;
 use crate::json_ext::Object;
 use crate::json_ext::Path;
+use crate::json_ext::PathElement;
 use crate::json_ext::Value;
 use crate::query_planner::fetch::OperationKind;
+use crate::services::layers::quotas::OperationCost;
 use crate::*;
 
 const TYPENAME: &str = "__typename";
@@ -32,13 +38,16 @@ pub(crate) struct Query {
     operations: Vec<Operation>,
     #[derivative(PartialEq = "ignore", Hash = "ignore")]
     pub(crate) subselections: HashMap<(Option<Path>, String), Query>,
+    #[derivative(PartialEq = "ignore", Hash = "ignore")]
+    response_validation: ResponseValidation,
 }
 
 impl Query {
     /// Re-format the response value to match this query.
     ///
     /// This will discard unrequested fields and re-order the output to match the order of the
-    /// query.
+    /// query. Values that do not match their type in the schema are replaced by null, and
+    /// reported according to the configured response validation.
     #[tracing::instrument(skip_all, level = "trace")]
     pub(crate) fn format_response(
         &self,
@@ -49,14 +58,7 @@ impl Query {
     ) {
         let data = std::mem::take(&mut response.data);
         if let Some(Value::Object(mut input)) = data {
-            let operation = match operation_name {
-                Some(name) => self
-                    .operations
-                    .iter()
-                    // we should have an error if the only operation is anonymous but the query specifies a name
-                    .find(|op| op.name.is_some() && op.name.as_deref().unwrap() == name),
-                None => self.operations.get(0),
-            };
+            let operation = self.operation(operation_name);
             if let Some(subselection) = &response.subselection {
                 // Get subselection from hashmap
                 match self.subselections.get(&(
@@ -67,18 +69,26 @@ impl Query {
                     Some(subselection_query) => {
                         let mut output = Object::default();
                         let operation = &subselection_query.operations[0];
+                        let mut parameters = FormatParameters {
+                            variables: &variables,
+                            schema,
+                            path: response.path.clone().unwrap_or_default(),
+                            nulls: Vec::new(),
+                            mismatches: Vec::new(),
+                        };
                         response.data = Some(
                             match self.apply_root_selection_set(
+                                &mut parameters,
                                 operation,
-                                &variables,
                                 &mut input,
                                 &mut output,
-                                schema,
                             ) {
                                 Ok(()) => output.into(),
                                 Err(InvalidValue) => Value::Null,
                             },
                         );
+                        self.report_mismatches(&parameters.mismatches, &mut response.errors);
+                        parameters.add_null_errors(&mut response.errors);
 
                         return;
                     }
@@ -99,18 +109,26 @@ impl Query {
                         .collect()
                 };
 
+                let mut parameters = FormatParameters {
+                    variables: &all_variables,
+                    schema,
+                    path: Path::default(),
+                    nulls: Vec::new(),
+                    mismatches: Vec::new(),
+                };
                 response.data = Some(
                     match self.apply_root_selection_set(
+                        &mut parameters,
                         operation,
-                        &all_variables,
                         &mut input,
                         &mut output,
-                        schema,
                     ) {
                         Ok(()) => output.into(),
                         Err(InvalidValue) => Value::Null,
                     },
                 );
+                self.report_mismatches(&parameters.mismatches, &mut response.errors);
+                parameters.add_null_errors(&mut response.errors);
 
                 return;
             } else {
@@ -123,6 +141,635 @@ impl Query {
         response.data = Some(Value::default());
     }
 
+    fn operation(&self, operation_name: Option<&str>) -> Option<&Operation> {
+        match operation_name {
+            Some(name) => self
+                .operations
+                .iter()
+                // we should have an error if the only operation is anonymous but the query specifies a name
+                .find(|op| op.name.is_some() && op.name.as_deref().unwrap() == name),
+            None => self.operations.get(0),
+        }
+    }
+
+    /// The cost and depth of an operation, from its parsed selections.
+    pub(crate) fn cost(&self, operation_name: Option<&str>) -> OperationCost {
+        self.operation(operation_name)
+            .map(|operation| {
+                selection_set_cost(&operation.selection_set, &self.fragments, &mut Vec::new())
+            })
+            .unwrap_or_default()
+    }
+
+    /// Re-order the fields of the response data to match the order of the query, after the
+    /// response was formatted and then modified, e.g. by plugins.
+    ///
+    /// Unlike [`Query::format_response`], this keeps the fields that are not selected, after the
+    /// selected ones, and does not check the values.
+    pub(crate) fn order_response_fields(
+        &self,
+        response: &mut Response,
+        operation_name: Option<&str>,
+    ) {
+        let operation = match self.operation(operation_name) {
+            Some(operation) => operation,
+            None => return,
+        };
+        let root = vec![operation.selection_set.as_slice()];
+
+        if let Some(data) = response.data.as_mut() {
+            let selection_sets = match &response.path {
+                Some(path) => self.selection_sets_at(root.clone(), path),
+                None => root.clone(),
+            };
+            self.order_fields(data, &selection_sets);
+        }
+        for incremental in &mut response.incremental {
+            if let (Some(data), Some(path)) = (incremental.data.as_mut(), &incremental.path) {
+                let selection_sets = self.selection_sets_at(root.clone(), path);
+                self.order_fields(data, &selection_sets);
+            }
+        }
+    }
+
+    /// Collect the selection sets of the fields selected by a selection set, by response name and
+    /// in the order of the query.
+    ///
+    /// The fragments are collected whatever their type condition: the fields they select are
+    /// only present in the response if they apply, so their order is not affected.
+    fn collect_fields<'a>(
+        &'a self,
+        selection_set: &'a [Selection],
+        fields: &mut IndexMap<&'a str, Vec<&'a [Selection]>>,
+    ) {
+        for selection in selection_set {
+            match selection {
+                Selection::Field {
+                    name,
+                    alias,
+                    selection_set,
+                    ..
+                } => {
+                    let field_selection_sets = fields
+                        .entry(alias.as_ref().unwrap_or(name).as_str())
+                        .or_default();
+                    if let Some(selection_set) = selection_set {
+                        field_selection_sets.push(selection_set.as_slice());
+                    }
+                }
+                Selection::InlineFragment { selection_set, .. } => {
+                    self.collect_fields(selection_set, fields)
+                }
+                Selection::FragmentSpread { name, .. } => {
+                    if let Some(fragment) = self.fragments.get(name) {
+                        self.collect_fields(&fragment.selection_set, fields)
+                    }
+                }
+            }
+        }
+    }
+
+    /// The selection sets of the values at a path of the response.
+    fn selection_sets_at<'a>(
+        &'a self,
+        mut selection_sets: Vec<&'a [Selection]>,
+        path: &Path,
+    ) -> Vec<&'a [Selection]> {
+        for element in path.iter() {
+            if let PathElement::Key(key) = element {
+                let mut fields = IndexMap::new();
+                for selection_set in &selection_sets {
+                    self.collect_fields(selection_set, &mut fields);
+                }
+                selection_sets = fields.remove(key.as_str()).unwrap_or_default();
+            }
+        }
+        selection_sets
+    }
+
+    fn order_fields(&self, value: &mut Value, selection_sets: &[&[Selection]]) {
+        match value {
+            Value::Array(values) => {
+                for value in values {
+                    self.order_fields(value, selection_sets);
+                }
+            }
+            Value::Object(object) => {
+                let mut fields = IndexMap::new();
+                for selection_set in selection_sets {
+                    self.collect_fields(selection_set, &mut fields);
+                }
+                let mut unordered = std::mem::take(object);
+                for (name, field_selection_sets) in fields {
+                    if let Some(mut field_value) = unordered.remove(name) {
+                        self.order_fields(&mut field_value, &field_selection_sets);
+                        object.insert(name, field_value);
+                    }
+                }
+                for (name, field_value) in unordered {
+                    object.insert(name, field_value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Replace the values of the masked fields in the response data, returning the paths of the
+    /// masked values with their schema coordinates.
+    ///
+    /// The fields are masked by schema coordinate (`Type.field`), the type being the one the field
+    /// is selected on, or the `__typename` of the object if it was selected. When the concrete type
+    /// of an object selected on an interface or union is not known, the field is masked if it is
+    /// masked on any of the possible types.
+    ///
+    /// A string replacement is only used for the `String` and `ID` fields, the other ones are
+    /// nulled. A non-nullable field masked with null nulls its nearest nullable ancestor, as a
+    /// field error would.
+    pub(crate) fn mask_fields(
+        &self,
+        response: &mut Response,
+        operation_name: Option<&str>,
+        schema: &Schema,
+        masked: &HashMap<String, Value>,
+    ) -> Vec<(Path, String)> {
+        let mut found = Vec::new();
+        let operation = match self.operation(operation_name) {
+            Some(operation) => operation,
+            None => return found,
+        };
+        let root = vec![(
+            schema.root_operation_name(operation.kind),
+            operation.selection_set.as_slice(),
+        )];
+
+        if let Some(data) = response.data.as_mut() {
+            let mut path = response.path.clone().unwrap_or_default();
+            let selection_sets = self.typed_selection_sets_at(root.clone(), &path, schema);
+            if self.mask_value(
+                data,
+                None,
+                &selection_sets,
+                schema,
+                masked,
+                &mut path,
+                &mut found,
+            ) {
+                *data = Value::Null;
+            }
+        }
+        for incremental in &mut response.incremental {
+            if let (Some(data), Some(path)) = (incremental.data.as_mut(), &incremental.path) {
+                let mut path = path.clone();
+                let selection_sets = self.typed_selection_sets_at(root.clone(), &path, schema);
+                if self.mask_value(
+                    data,
+                    None,
+                    &selection_sets,
+                    schema,
+                    masked,
+                    &mut path,
+                    &mut found,
+                ) {
+                    *data = Value::Null;
+                }
+            }
+        }
+        found
+    }
+
+    /// Visit the objects of the response data with their type: their `__typename` when the
+    /// response has it, or else the type of the field they are the value of. The objects of an
+    /// interface or union field without `__typename` are only visited when a single type is
+    /// possible, since their concrete type is unknown otherwise.
+    pub(crate) fn visit_typed_objects(
+        &self,
+        response: &Response,
+        operation_name: Option<&str>,
+        schema: &Schema,
+        visitor: &mut dyn FnMut(&str, &Object),
+    ) {
+        let operation = match self.operation(operation_name) {
+            Some(operation) => operation,
+            None => return,
+        };
+        let root = vec![(
+            schema.root_operation_name(operation.kind),
+            operation.selection_set.as_slice(),
+        )];
+
+        if let Some(data) = response.data.as_ref() {
+            let path = response.path.clone().unwrap_or_default();
+            let selection_sets = self.typed_selection_sets_at(root.clone(), &path, schema);
+            self.visit_value(data, &selection_sets, schema, visitor);
+        }
+        for incremental in &response.incremental {
+            if let (Some(data), Some(path)) = (incremental.data.as_ref(), &incremental.path) {
+                let selection_sets = self.typed_selection_sets_at(root.clone(), path, schema);
+                self.visit_value(data, &selection_sets, schema, visitor);
+            }
+        }
+    }
+
+    fn visit_value(
+        &self,
+        value: &Value,
+        selection_sets: &[(&str, &[Selection])],
+        schema: &Schema,
+        visitor: &mut dyn FnMut(&str, &Object),
+    ) {
+        match value {
+            Value::Array(values) => {
+                for value in values {
+                    self.visit_value(value, selection_sets, schema, visitor);
+                }
+            }
+            Value::Object(object) => {
+                let concrete_type = object.get(TYPENAME).and_then(|typename| typename.as_str());
+                if let Some(type_name) = concrete_type.or_else(|| {
+                    selection_sets
+                        .first()
+                        .and_then(|(type_name, _)| object_type(schema, type_name))
+                }) {
+                    visitor(type_name, object);
+                }
+                let mut fields = Vec::new();
+                for (parent_type, selection_set) in selection_sets {
+                    self.collect_typed_fields(
+                        *parent_type,
+                        *selection_set,
+                        concrete_type,
+                        schema,
+                        &mut fields,
+                    );
+                }
+
+                for (name, field_value) in object.iter() {
+                    let field_selection_sets: Vec<_> = fields
+                        .iter()
+                        .filter(|(field_name, _, _)| *field_name == name.as_str())
+                        .filter_map(|(_, _, selection)| typed_selection_set(selection))
+                        .collect();
+                    if !field_selection_sets.is_empty() {
+                        self.visit_value(field_value, &field_selection_sets, schema, visitor);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Collect the fields selected by a selection set on a type, with their response name and
+    /// the type they are selected on.
+    ///
+    /// The fragments are only collected if they apply to the concrete type of the object, when
+    /// it is known.
+    fn collect_typed_fields<'a>(
+        &'a self,
+        parent_type: &'a str,
+        selection_set: &'a [Selection],
+        concrete_type: Option<&str>,
+        schema: &Schema,
+        fields: &mut Vec<(&'a str, &'a str, &'a Selection)>,
+    ) {
+        let applies = |type_condition: &str| {
+            concrete_type
+                .map(|concrete| {
+                    type_condition == concrete || schema.is_subtype(type_condition, concrete)
+                })
+                .unwrap_or(true)
+        };
+        for selection in selection_set {
+            match selection {
+                Selection::Field { name, alias, .. } => fields.push((
+                    alias.as_ref().unwrap_or(name).as_str(),
+                    parent_type,
+                    selection,
+                )),
+                Selection::InlineFragment {
+                    type_condition,
+                    selection_set,
+                    ..
+                } => {
+                    if applies(type_condition) {
+                        self.collect_typed_fields(
+                            type_condition,
+                            selection_set,
+                            concrete_type,
+                            schema,
+                            fields,
+                        )
+                    }
+                }
+                Selection::FragmentSpread { name, .. } => {
+                    if let Some(fragment) = self.fragments.get(name) {
+                        if applies(&fragment.type_condition) {
+                            self.collect_typed_fields(
+                                &fragment.type_condition,
+                                &fragment.selection_set,
+                                concrete_type,
+                                schema,
+                                fields,
+                            )
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The selection sets of the values at a path of the response, with the type they are
+    /// selected on.
+    fn typed_selection_sets_at<'a>(
+        &'a self,
+        mut selection_sets: Vec<(&'a str, &'a [Selection])>,
+        path: &Path,
+        schema: &Schema,
+    ) -> Vec<(&'a str, &'a [Selection])> {
+        for element in path.iter() {
+            if let PathElement::Key(key) = element {
+                let mut fields = Vec::new();
+                for (parent_type, selection_set) in &selection_sets {
+                    self.collect_typed_fields(
+                        *parent_type,
+                        *selection_set,
+                        None,
+                        schema,
+                        &mut fields,
+                    );
+                }
+                selection_sets = fields
+                    .into_iter()
+                    .filter(|(name, _, _)| *name == key.as_str())
+                    .filter_map(|(_, _, selection)| typed_selection_set(selection))
+                    .collect();
+            }
+        }
+        selection_sets
+    }
+
+    /// Mask the fields of a value of type `field_type`, if known, returning `true` if the value
+    /// must be nulled because a non-nullable field of it was masked with null.
+    #[allow(clippy::too_many_arguments)]
+    fn mask_value(
+        &self,
+        value: &mut Value,
+        field_type: Option<&FieldType>,
+        selection_sets: &[(&str, &[Selection])],
+        schema: &Schema,
+        masked: &HashMap<String, Value>,
+        path: &mut Path,
+        found: &mut Vec<(Path, String)>,
+    ) -> bool {
+        match value {
+            Value::Array(values) => {
+                let item_type = field_type.and_then(list_item_type);
+                let mut nulled = false;
+                for (index, value) in values.iter_mut().enumerate() {
+                    path.push(PathElement::Index(index));
+                    if self.mask_value(
+                        value,
+                        item_type,
+                        selection_sets,
+                        schema,
+                        masked,
+                        path,
+                        found,
+                    ) && null_value(value, item_type)
+                    {
+                        nulled = true;
+                    }
+                    path.pop();
+                }
+                nulled
+            }
+            Value::Object(object) => {
+                let concrete_type = object
+                    .get(TYPENAME)
+                    .and_then(|typename| typename.as_str())
+                    .map(|typename| typename.to_string());
+                let mut fields = Vec::new();
+                for (parent_type, selection_set) in selection_sets {
+                    self.collect_typed_fields(
+                        *parent_type,
+                        *selection_set,
+                        concrete_type.as_deref(),
+                        schema,
+                        &mut fields,
+                    );
+                }
+
+                let mut masked_names = HashSet::new();
+                let mut nulled = false;
+                for (name, parent_type, selection) in &fields {
+                    let (field_name, field_type) = match selection {
+                        Selection::Field {
+                            name, field_type, ..
+                        } => (name.as_str(), field_type),
+                        _ => continue,
+                    };
+                    // the field is masked on any of the types the object may have
+                    let possible_types: Vec<&str> = match concrete_type.as_deref() {
+                        Some(concrete_type) => vec![*parent_type, concrete_type],
+                        None => std::iter::once(*parent_type)
+                            .chain(schema.possible_types(parent_type))
+                            .collect(),
+                    };
+                    let coordinate = possible_types
+                        .into_iter()
+                        .map(|type_name| format!("{}.{}", type_name, field_name))
+                        .find(|coordinate| masked.contains_key(coordinate));
+                    if let Some(coordinate) = coordinate {
+                        if let Some(field_value) = object.get_mut(*name) {
+                            if masked_names.insert(*name) {
+                                *field_value = match &masked[&coordinate] {
+                                    Value::String(redacted) if accepts_strings(field_type) => {
+                                        Value::String(redacted.clone())
+                                    }
+                                    _ => Value::Null,
+                                };
+                                if field_value.is_null() && field_type.is_non_null() {
+                                    nulled = true;
+                                }
+                                let mut field_path = path.clone();
+                                field_path.push(PathElement::Key(name.to_string()));
+                                found.push((field_path, coordinate));
+                            }
+                        }
+                    }
+                }
+
+                for (name, field_value) in object.iter_mut() {
+                    if masked_names.contains(name.as_str()) {
+                        continue;
+                    }
+                    let field_selections: Vec<_> = fields
+                        .iter()
+                        .filter(|(field_name, _, _)| *field_name == name.as_str())
+                        .map(|(_, _, selection)| *selection)
+                        .collect();
+                    let field_type =
+                        field_selections
+                            .iter()
+                            .copied()
+                            .find_map(|selection| match selection {
+                                Selection::Field { field_type, .. } => Some(field_type),
+                                _ => None,
+                            });
+                    let field_selection_sets: Vec<_> = field_selections
+                        .into_iter()
+                        .filter_map(typed_selection_set)
+                        .collect();
+                    if !field_selection_sets.is_empty() {
+                        path.push(PathElement::Key(name.as_str().to_string()));
+                        if self.mask_value(
+                            field_value,
+                            field_type,
+                            &field_selection_sets,
+                            schema,
+                            masked,
+                            path,
+                            found,
+                        ) && null_value(field_value, field_type)
+                        {
+                            nulled = true;
+                        }
+                        path.pop();
+                    }
+                }
+                nulled
+            }
+            _ => false,
+        }
+    }
+
+    /// Remove the fields of the response data that the query does not select, such as the
+    /// fields added by plugins once the response was formatted, returning the paths of the
+    /// removed fields.
+    ///
+    /// The fragments only select fields on the objects they apply to, when the `__typename` of
+    /// the object is known.
+    pub(crate) fn prune_fields(
+        &self,
+        response: &mut Response,
+        operation_name: Option<&str>,
+        schema: &Schema,
+    ) -> Vec<Path> {
+        let mut pruned = Vec::new();
+        let operation = match self.operation(operation_name) {
+            Some(operation) => operation,
+            None => return pruned,
+        };
+        let root = vec![(
+            schema.root_operation_name(operation.kind),
+            operation.selection_set.as_slice(),
+        )];
+
+        if let Some(data) = response.data.as_mut() {
+            let mut path = response.path.clone().unwrap_or_default();
+            let selection_sets = self.typed_selection_sets_at(root.clone(), &path, schema);
+            self.prune_value(data, &selection_sets, schema, &mut path, &mut pruned);
+        }
+        for incremental in &mut response.incremental {
+            if let (Some(data), Some(path)) = (incremental.data.as_mut(), &incremental.path) {
+                let mut path = path.clone();
+                let selection_sets = self.typed_selection_sets_at(root.clone(), &path, schema);
+                self.prune_value(data, &selection_sets, schema, &mut path, &mut pruned);
+            }
+        }
+        pruned
+    }
+
+    fn prune_value(
+        &self,
+        value: &mut Value,
+        selection_sets: &[(&str, &[Selection])],
+        schema: &Schema,
+        path: &mut Path,
+        pruned: &mut Vec<Path>,
+    ) {
+        match value {
+            Value::Array(values) => {
+                for (index, value) in values.iter_mut().enumerate() {
+                    path.push(PathElement::Index(index));
+                    self.prune_value(value, selection_sets, schema, path, pruned);
+                    path.pop();
+                }
+            }
+            Value::Object(object) => {
+                let concrete_type = object
+                    .get(TYPENAME)
+                    .and_then(|typename| typename.as_str())
+                    .map(|typename| typename.to_string());
+                let mut fields = Vec::new();
+                for (parent_type, selection_set) in selection_sets {
+                    self.collect_typed_fields(
+                        *parent_type,
+                        *selection_set,
+                        concrete_type.as_deref(),
+                        schema,
+                        &mut fields,
+                    );
+                }
+
+                for (name, mut field_value) in std::mem::take(object) {
+                    path.push(PathElement::Key(name.as_str().to_string()));
+                    let selected: Vec<_> = fields
+                        .iter()
+                        .filter(|(field_name, _, _)| *field_name == name.as_str())
+                        .collect();
+                    if selected.is_empty() {
+                        pruned.push(path.clone());
+                    } else {
+                        let field_selection_sets: Vec<_> = selected
+                            .into_iter()
+                            .filter_map(|(_, _, selection)| typed_selection_set(selection))
+                            .collect();
+                        if !field_selection_sets.is_empty() {
+                            self.prune_value(
+                                &mut field_value,
+                                &field_selection_sets,
+                                schema,
+                                path,
+                                pruned,
+                            );
+                        }
+                        object.insert(name, field_value);
+                    }
+                    path.pop();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Log the values of the response that did not match the schema, or add an error for each
+    /// of them, depending on the configured response validation.
+    fn report_mismatches(&self, mismatches: &[Mismatch], errors: &mut Vec<Error>) {
+        match self.response_validation {
+            ResponseValidation::Disabled => {}
+            ResponseValidation::Log => {
+                for mismatch in mismatches {
+                    tracing::warn!(
+                        path = %mismatch.path,
+                        "response validation failed: {}",
+                        mismatch.message()
+                    );
+                }
+            }
+            ResponseValidation::Error => {
+                for mismatch in mismatches {
+                    errors.push(
+                        Error::builder()
+                            .message(mismatch.message())
+                            .path(mismatch.path.clone())
+                            .code(ErrorCode::ResponseValidationFailed)
+                            .build(),
+                    );
+                }
+            }
+        }
+    }
+
     pub(crate) fn parse(
         query: impl Into<String>,
         schema: &Schema,
@@ -142,11 +789,17 @@ impl Query {
 
         let errors = tree
             .errors()
-            .map(|err| format!("{:?}", err))
+            .map(|err| {
+                SyntaxError::new(
+                    err,
+                    &string,
+                    configuration.server.experimental_error_source_excerpts,
+                )
+            })
             .collect::<Vec<_>>();
 
         if !errors.is_empty() {
-            let errors = errors.join(", ");
+            let errors = SyntaxErrors(errors);
             failfast_debug!("parsing error(s): {}", errors);
             return Err(SpecError::ParsingError(errors));
         }
@@ -171,17 +824,17 @@ impl Query {
             fragments,
             operations,
             subselections: HashMap::new(),
+            response_validation: configuration.server.experimental_response_validation,
         })
     }
 
     fn format_value(
         &self,
+        parameters: &mut FormatParameters,
         field_type: &FieldType,
-        variables: &Object,
         input: &mut Value,
         output: &mut Value,
         selection_set: &[Selection],
-        schema: &Schema,
     ) -> Result<(), InvalidValue> {
         // for every type, if we have an invalid value, we will replace it with null
         // and return Ok(()), because values are optional by default
@@ -190,11 +843,11 @@ impl Query {
             // we set it to null and immediately return an error instead of Ok(()), because we
             // want the error to go up until the next nullable parent
             FieldType::NonNull(inner_type) => {
-                match self.format_value(inner_type, variables, input, output, selection_set, schema)
-                {
+                match self.format_value(parameters, inner_type, input, output, selection_set) {
                     Err(_) => Err(InvalidValue),
                     Ok(_) => {
                         if output.is_null() {
+                            parameters.nullified(None);
                             Err(InvalidValue)
                         } else {
                             Ok(())
@@ -221,14 +874,16 @@ impl Query {
                         .iter_mut()
                         .enumerate()
                         .try_for_each(|(i, element)| {
-                            self.format_value(
+                            parameters.path.push(PathElement::Index(i));
+                            let res = self.format_value(
+                                parameters,
                                 inner_type,
-                                variables,
                                 element,
                                 &mut output_array[i],
                                 selection_set,
-                                schema,
-                            )
+                            );
+                            parameters.path.pop();
+                            res
                         }) {
                         Err(InvalidValue) => {
                             *output = Value::Null;
@@ -237,27 +892,32 @@ impl Query {
                         Ok(()) => Ok(()),
                     }
                 }
-                _ => Ok(()),
+                _ => {
+                    parameters.mismatch("a list", input);
+                    Ok(())
+                }
             },
 
             FieldType::Named(type_name) | FieldType::Introspection(type_name) => {
                 // we cannot know about the expected format of custom scalars
                 // so we must pass them directly to the client
-                if schema.custom_scalars.contains(type_name) {
+                if parameters.schema.custom_scalars.contains(type_name) {
                     *output = input.clone();
                     return Ok(());
-                } else if let Some(enum_type) = schema.enums.get(type_name) {
+                } else if let Some(enum_type) = parameters.schema.enums.get(type_name) {
                     return match input.as_str() {
                         Some(s) => {
                             if enum_type.contains(s) {
                                 *output = input.clone();
                                 Ok(())
                             } else {
+                                parameters.mismatch(type_name, input);
                                 *output = Value::Null;
                                 Ok(())
                             }
                         }
                         None => {
+                            parameters.mismatch(type_name, input);
                             *output = Value::Null;
                             Ok(())
                         }
@@ -269,7 +929,11 @@ impl Query {
                         if let Some(input_type) =
                             input_object.get(TYPENAME).and_then(|val| val.as_str())
                         {
-                            if !schema.object_types.contains_key(input_type) {
+                            if !parameters.schema.object_types.contains_key(input_type) {
+                                parameters.record_mismatch(
+                                    type_name,
+                                    format!("an object of type {}", input_type),
+                                );
                                 *output = Value::Null;
                                 return Ok(());
                             }
@@ -281,11 +945,10 @@ impl Query {
                         let output_object = output.as_object_mut().ok_or(InvalidValue)?;
 
                         match self.apply_selection_set(
+                            parameters,
                             selection_set,
-                            variables,
                             input_object,
                             output_object,
-                            schema,
                         ) {
                             Ok(()) => Ok(()),
                             Err(InvalidValue) => {
@@ -295,6 +958,7 @@ impl Query {
                         }
                     }
                     _ => {
+                        parameters.mismatch(type_name, input);
                         *output = Value::Null;
                         Ok(())
                     }
@@ -316,6 +980,7 @@ impl Query {
                 if opt.is_some() {
                     *output = input.clone();
                 } else {
+                    parameters.mismatch("Int", input);
                     *output = Value::Null;
                 }
                 Ok(())
@@ -324,6 +989,7 @@ impl Query {
                 if input.as_f64().is_some() {
                     *output = input.clone();
                 } else {
+                    parameters.mismatch("Float", input);
                     *output = Value::Null;
                 }
                 Ok(())
@@ -332,6 +998,7 @@ impl Query {
                 if input.as_bool().is_some() {
                     *output = input.clone();
                 } else {
+                    parameters.mismatch("Boolean", input);
                     *output = Value::Null;
                 }
                 Ok(())
@@ -340,6 +1007,7 @@ impl Query {
                 if input.as_str().is_some() {
                     *output = input.clone();
                 } else {
+                    parameters.mismatch("String", input);
                     *output = Value::Null;
                 }
                 Ok(())
@@ -348,6 +1016,7 @@ impl Query {
                 if input.is_string() || input.is_i64() || input.is_u64() || input.is_f64() {
                     *output = input.clone();
                 } else {
+                    parameters.mismatch("ID", input);
                     *output = Value::Null;
                 }
                 Ok(())
@@ -357,11 +1026,10 @@ impl Query {
 
     fn apply_selection_set(
         &self,
+        parameters: &mut FormatParameters,
         selection_set: &[Selection],
-        variables: &Object,
         input: &mut Object,
         output: &mut Object,
-        schema: &Schema,
     ) -> Result<(), InvalidValue> {
         // For skip and include, using .unwrap_or is legit here because
         // validate_variables should have already checked that
@@ -377,11 +1045,11 @@ impl Query {
                     include,
                 } => {
                     let field_name = alias.as_ref().unwrap_or(name);
-                    if skip.should_skip(variables).unwrap_or(false) {
+                    if skip.should_skip(parameters.variables).unwrap_or(false) {
                         continue;
                     }
 
-                    if !include.should_include(variables).unwrap_or(true) {
+                    if !include.should_include(parameters.variables).unwrap_or(true) {
                         continue;
                     }
 
@@ -404,20 +1072,25 @@ impl Query {
                                 *output_value = input_value.clone();
                             }
                         } else {
-                            self.format_value(
+                            parameters
+                                .path
+                                .push(PathElement::Key(field_name.as_str().to_string()));
+                            let res = self.format_value(
+                                parameters,
                                 field_type,
-                                variables,
                                 input_value,
                                 output_value,
                                 selection_set,
-                                schema,
-                            )?;
+                            );
+                            parameters.path.pop();
+                            res?;
                         }
                     } else {
                         if !output.contains_key(field_name.as_str()) {
                             output.insert((*field_name).clone(), Value::Null);
                         }
                         if field_type.is_non_null() {
+                            parameters.nullified(Some(field_name.as_str()));
                             return Err(InvalidValue);
                         }
                     }
@@ -429,11 +1102,11 @@ impl Query {
                     include,
                     known_type,
                 } => {
-                    if skip.should_skip(variables).unwrap_or(false) {
+                    if skip.should_skip(parameters.variables).unwrap_or(false) {
                         continue;
                     }
 
-                    if !include.should_include(variables).unwrap_or(true) {
+                    if !include.should_include(parameters.variables).unwrap_or(true) {
                         continue;
                     }
 
@@ -443,7 +1116,7 @@ impl Query {
                         // check if the fragment matches the input type directly, and if not, check if the
                         // input type is a subtype of the fragment's type condition (interface, union)
                         input_type == type_condition.as_str()
-                            || schema.is_subtype(type_condition, input_type)
+                            || parameters.schema.is_subtype(type_condition, input_type)
                     } else {
                         // known_type = true means that from the query's shape, we know
                         // we should get the right type here. But in the case we get a
@@ -452,13 +1125,13 @@ impl Query {
                         // If the type condition is an interface and the current known type implements it
                         known_type
                             .as_ref()
-                            .map(|k| schema.is_subtype(type_condition, k))
+                            .map(|k| parameters.schema.is_subtype(type_condition, k))
                             .unwrap_or_default()
                             || known_type.as_deref() == Some(type_condition.as_str())
                     };
 
                     if is_apply {
-                        self.apply_selection_set(selection_set, variables, input, output, schema)?;
+                        self.apply_selection_set(parameters, selection_set, input, output)?;
                     }
                 }
                 Selection::FragmentSpread {
@@ -467,19 +1140,27 @@ impl Query {
                     skip,
                     include,
                 } => {
-                    if skip.should_skip(variables).unwrap_or(false) {
+                    if skip.should_skip(parameters.variables).unwrap_or(false) {
                         continue;
                     }
 
-                    if !include.should_include(variables).unwrap_or(true) {
+                    if !include.should_include(parameters.variables).unwrap_or(true) {
                         continue;
                     }
 
                     if let Some(fragment) = self.fragments.get(name) {
-                        if fragment.skip.should_skip(variables).unwrap_or(false) {
+                        if fragment
+                            .skip
+                            .should_skip(parameters.variables)
+                            .unwrap_or(false)
+                        {
                             continue;
                         }
-                        if !fragment.include.should_include(variables).unwrap_or(true) {
+                        if !fragment
+                            .include
+                            .should_include(parameters.variables)
+                            .unwrap_or(true)
+                        {
                             continue;
                         }
 
@@ -489,23 +1170,24 @@ impl Query {
                             // check if the fragment matches the input type directly, and if not, check if the
                             // input type is a subtype of the fragment's type condition (interface, union)
                             input_type == fragment.type_condition.as_str()
-                                || schema.is_subtype(&fragment.type_condition, input_type)
+                                || parameters
+                                    .schema
+                                    .is_subtype(&fragment.type_condition, input_type)
                         } else {
                             // If the type condition is an interface and the current known type implements it
                             known_type
                                 .as_ref()
-                                .map(|k| schema.is_subtype(&fragment.type_condition, k))
+                                .map(|k| parameters.schema.is_subtype(&fragment.type_condition, k))
                                 .unwrap_or_default()
                                 || known_type.as_deref() == Some(fragment.type_condition.as_str())
                         };
 
                         if is_apply {
                             self.apply_selection_set(
+                                parameters,
                                 &fragment.selection_set,
-                                variables,
                                 input,
                                 output,
-                                schema,
                             )?;
                         }
                     } else {
@@ -521,11 +1203,10 @@ impl Query {
 
     fn apply_root_selection_set(
         &self,
+        parameters: &mut FormatParameters,
         operation: &Operation,
-        variables: &Object,
         input: &mut Object,
         output: &mut Object,
-        schema: &Schema,
     ) -> Result<(), InvalidValue> {
         for selection in &operation.selection_set {
             match selection {
@@ -540,11 +1221,11 @@ impl Query {
                     // Using .unwrap_or is legit here because
                     // validate_variables should have already checked that
                     // the variable is present and it is of the correct type
-                    if skip.should_skip(variables).unwrap_or(false) {
+                    if skip.should_skip(parameters.variables).unwrap_or(false) {
                         continue;
                     }
 
-                    if !include.should_include(variables).unwrap_or(true) {
+                    if !include.should_include(parameters.variables).unwrap_or(true) {
                         continue;
                     }
 
@@ -564,14 +1245,18 @@ impl Query {
                         let selection_set = selection_set.as_deref().unwrap_or_default();
                         let output_value =
                             output.entry((*field_name).clone()).or_insert(Value::Null);
-                        self.format_value(
+                        parameters
+                            .path
+                            .push(PathElement::Key(field_name_str.to_string()));
+                        let res = self.format_value(
+                            parameters,
                             field_type,
-                            variables,
                             input_value,
                             output_value,
                             selection_set,
-                            schema,
-                        )?;
+                        );
+                        parameters.path.pop();
+                        res?;
                     } else if field_name_str == TYPENAME {
                         if !output.contains_key(field_name_str) {
                             output.insert(
@@ -580,6 +1265,7 @@ impl Query {
                             );
                         }
                     } else if field_type.is_non_null() {
+                        parameters.nullified(Some(field_name_str));
                         return Err(InvalidValue);
                     } else {
                         output.insert(field_name.clone(), Value::Null);
@@ -591,11 +1277,13 @@ impl Query {
                     ..
                 } => {
                     // top level objects will not provide a __typename field
-                    if type_condition.as_str() != schema.root_operation_name(operation.kind) {
+                    if type_condition.as_str()
+                        != parameters.schema.root_operation_name(operation.kind)
+                    {
                         return Err(InvalidValue);
                     }
 
-                    self.apply_selection_set(selection_set, variables, input, output, schema)?;
+                    self.apply_selection_set(parameters, selection_set, input, output)?;
                 }
                 Selection::FragmentSpread {
                     name,
@@ -604,12 +1292,15 @@ impl Query {
                     include: _,
                 } => {
                     if let Some(fragment) = self.fragments.get(name) {
-                        let operation_type_name = schema.root_operation_name(operation.kind);
+                        let operation_type_name =
+                            parameters.schema.root_operation_name(operation.kind);
                         let is_apply = {
                             // check if the fragment matches the input type directly, and if not, check if the
                             // input type is a subtype of the fragment's type condition (interface, union)
                             operation_type_name == fragment.type_condition.as_str()
-                                || schema.is_subtype(&fragment.type_condition, operation_type_name)
+                                || parameters
+                                    .schema
+                                    .is_subtype(&fragment.type_condition, operation_type_name)
                         };
 
                         if !is_apply {
@@ -617,11 +1308,10 @@ impl Query {
                         }
 
                         self.apply_selection_set(
+                            parameters,
                             &fragment.selection_set,
-                            variables,
                             input,
                             output,
-                            schema,
                         )?;
                     } else {
                         // the fragment should have been already checked with the schema
@@ -635,12 +1325,17 @@ impl Query {
     }
 
     /// Validate a [`Request`]'s variables against this [`Query`] using a provided [`Schema`].
+    ///
+    /// Returns the coerced variables, where the variables that were not provided take the
+    /// default value from their definition, and the fields missing from the input objects take
+    /// the default value from the schema.
+    /// Spec: https://spec.graphql.org/draft/#sec-Coercing-Variable-Values
     #[tracing::instrument(skip_all, level = "trace")]
     pub(crate) fn validate_variables(
         &self,
         request: &Request,
         schema: &Schema,
-    ) -> Result<(), Response> {
+    ) -> Result<Object, Response> {
         let operation_name = request.operation_name.as_deref();
         let operation_variable_types =
             self.operations
@@ -670,21 +1365,53 @@ impl Query {
             }
         }
 
+        let mut variables = request.variables.clone();
         let errors = operation_variable_types
             .iter()
-            .filter_map(|(name, (ty, _))| {
-                let value = request.variables.get(*name).unwrap_or(&Value::Null);
-                ty.validate_input_value(value, schema).err().map(|_| {
+            .filter_map(|(name, (ty, default_value))| {
+                let message = match (request.variables.get(*name), default_value) {
+                    (None, Some(default_value)) => {
+                        variables.insert(*name, default_value.clone());
+                        return None;
+                    }
+                    (None, None) if ty.is_non_null() => format!(
+                        "Variable \"${}\" of required type \"{}\" was not provided.",
+                        name,
+                        ty.to_graphql_type()
+                    ),
+                    (None, None) => return None,
+                    (Some(Value::Null), _) if ty.is_non_null() => format!(
+                        "Variable \"${}\" of non-null type \"{}\" must not be null.",
+                        name,
+                        ty.to_graphql_type()
+                    ),
+                    (Some(value), _) => {
+                        let mut coerced = value.clone();
+                        ty.apply_default_values(&mut coerced, schema);
+                        if ty.validate_input_value(&coerced, schema).is_ok() {
+                            variables.insert(*name, coerced);
+                            return None;
+                        }
+                        format!(
+                            "Variable \"${}\" got invalid value {}; Expected type \"{}\".",
+                            name,
+                            serde_json::to_string(value).unwrap_or_default(),
+                            ty.to_graphql_type()
+                        )
+                    }
+                };
+                Some(
                     FetchError::ValidationInvalidTypeVariable {
                         name: name.to_string(),
+                        message,
                     }
-                    .to_graphql_error(None)
-                })
+                    .to_graphql_error(None),
+                )
             })
             .collect::<Vec<_>>();
 
         if errors.is_empty() {
-            Ok(())
+            Ok(variables)
         } else {
             Err(Response::builder().errors(errors).build())
         }
@@ -695,6 +1422,154 @@ impl Query {
     }
 }
 
+/// State shared while formatting a response.
+struct FormatParameters<'a> {
+    variables: &'a Object,
+    schema: &'a Schema,
+    /// Path of the value being formatted.
+    path: Path,
+    /// Paths of the non-nullable values that were null or missing.
+    nulls: Vec<Path>,
+    /// Values that did not match their type in the schema.
+    mismatches: Vec<Mismatch>,
+}
+
+impl<'a> FormatParameters<'a> {
+    /// Record that the non-nullable value at the current path, or at its `field`, is null.
+    fn nullified(&mut self, field: Option<&str>) {
+        let mut path = self.path.clone();
+        if let Some(field) = field {
+            path.push(PathElement::Key(field.to_string()));
+        }
+        self.nulls.push(path);
+    }
+
+    /// Record that the value at the current path does not match its `expected` type.
+    ///
+    /// A value selected several times, e.g. by different fragments, is only recorded once.
+    fn mismatch(&mut self, expected: impl Into<String>, found: &Value) {
+        if !found.is_null() {
+            self.record_mismatch(expected, describe_value(found));
+        }
+    }
+
+    fn record_mismatch(&mut self, expected: impl Into<String>, found: String) {
+        if self.mismatches.iter().any(|m| m.path == self.path) {
+            return;
+        }
+        self.mismatches.push(Mismatch {
+            path: self.path.clone(),
+            expected: expected.into(),
+            found,
+        });
+    }
+
+    /// Add an error for the null values that were propagated to their nullable parent.
+    ///
+    /// Only the deepest null of a branch gets an error, and only if the response does not
+    /// already contain an error at or under its path, e.g. from the subgraph that returned it.
+    fn add_null_errors(self, errors: &mut Vec<Error>) {
+        let covered = |path: &Path, errors: &[Error]| {
+            errors.iter().any(|error| {
+                error
+                    .path
+                    .as_ref()
+                    .map(|error_path| error_path.0.starts_with(&path.0))
+                    .unwrap_or(false)
+            }) || self
+                .nulls
+                .iter()
+                .any(|other| other.len() > path.len() && other.0.starts_with(&path.0))
+        };
+
+        for path in &self.nulls {
+            if !covered(path, errors) {
+                errors.push(Error {
+                    message: "Cannot return null for non-nullable field".to_string(),
+                    path: Some(path.clone()),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+}
+
+/// A value of the response that does not match its type in the schema.
+#[derive(Debug)]
+struct Mismatch {
+    path: Path,
+    expected: String,
+    found: String,
+}
+
+impl Mismatch {
+    fn message(&self) -> String {
+        format!("expected {}, found {}", self.expected, self.found)
+    }
+}
+
+/// The selection set of a field, with the type it is selected on.
+fn typed_selection_set(selection: &Selection) -> Option<(&str, &[Selection])> {
+    match selection {
+        Selection::Field {
+            selection_set: Some(selection_set),
+            field_type,
+            ..
+        } => Some((field_type.inner_type_name()?, selection_set.as_slice())),
+        _ => None,
+    }
+}
+
+/// The object type of the values of a field type: the type itself for object types, or the only
+/// possible type of an interface or union.
+fn object_type<'a>(schema: &'a Schema, type_name: &'a str) -> Option<&'a str> {
+    if schema.object_types.contains_key(type_name) {
+        return Some(type_name);
+    }
+    let mut possible_types = schema.possible_types(type_name);
+    match (possible_types.next(), possible_types.next()) {
+        (Some(possible_type), None) => Some(possible_type),
+        _ => None,
+    }
+}
+
+/// The type of the items of a list type.
+fn list_item_type(field_type: &FieldType) -> Option<&FieldType> {
+    match field_type {
+        FieldType::NonNull(inner) => list_item_type(inner),
+        FieldType::List(item_type) => Some(item_type),
+        _ => None,
+    }
+}
+
+/// Whether a string can replace the value of a field of this type.
+fn accepts_strings(field_type: &FieldType) -> bool {
+    match field_type {
+        FieldType::NonNull(inner) => accepts_strings(inner),
+        FieldType::String | FieldType::Id => true,
+        _ => false,
+    }
+}
+
+/// Null a value that has a non-nullable field masked with null, returning `true` if its own type
+/// is not nullable either, so that its parent must be nulled in turn.
+fn null_value(value: &mut Value, field_type: Option<&FieldType>) -> bool {
+    *value = Value::Null;
+    field_type.map_or(false, FieldType::is_non_null)
+}
+
+/// A short description of a response value, without the content of objects and lists.
+fn describe_value(value: &Value) -> String {
+    match value {
+        Value::Null => "null".to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => format!("{:?}", s.as_str()),
+        Value::Array(_) => "a list".to_string(),
+        Value::Object(_) => "an object".to_string(),
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct Operation {
     name: Option<String>,
@@ -818,10 +1693,10 @@ fn parse_default_value(definition: &ast::VariableDefinition) -> Option<Value> {
         .and_then(|value| parse_value(&value))
 }
 
-fn parse_value(value: &ast::Value) -> Option<Value> {
+pub(super) fn parse_value(value: &ast::Value) -> Option<Value> {
     match value {
         ast::Value::Variable(_) => None,
-        ast::Value::StringValue(s) => Some(s.to_string().into()),
+        ast::Value::StringValue(s) => Some(Into::<String>::into(s.clone()).into()),
         ast::Value::FloatValue(f) => f.to_string().parse::<f64>().ok().map(Into::into),
         ast::Value::IntValue(i) => {
             let s = i.to_string();
@@ -857,12 +1732,52 @@ fn parse_value(value: &ast::Value) -> Option<Value> {
     }
 }
 
+fn selection_set_cost<'a>(
+    selection_set: &'a [Selection],
+    fragments: &'a Fragments,
+    // fragments being expanded, to stop on cycles
+    visited: &mut Vec<&'a str>,
+) -> OperationCost {
+    let mut cost = OperationCost::default();
+    for selection in selection_set {
+        let selection_cost = match selection {
+            Selection::Field { selection_set, .. } => {
+                let field_cost = selection_set
+                    .as_deref()
+                    .map(|selection_set| selection_set_cost(selection_set, fragments, visited))
+                    .unwrap_or_default();
+                OperationCost {
+                    cost: 1 + field_cost.cost,
+                    depth: 1 + field_cost.depth,
+                }
+            }
+            Selection::InlineFragment { selection_set, .. } => {
+                selection_set_cost(selection_set, fragments, visited)
+            }
+            Selection::FragmentSpread { name, .. } => match fragments.get(name) {
+                Some(fragment) if !visited.contains(&name.as_str()) => {
+                    visited.push(name.as_str());
+                    let cost = selection_set_cost(&fragment.selection_set, fragments, visited);
+                    visited.pop();
+                    cost
+                }
+                _ => OperationCost::default(),
+            },
+        };
+        cost.cost += selection_cost.cost;
+        cost.depth = cost.depth.max(selection_cost.depth);
+    }
+    cost
+}
+
 #[cfg(test)]
 mod tests {
     use serde_json_bytes::json;
     use test_log::test;
 
     use super::*;
+    use crate::error::Location;
+    use crate::graphql::IncrementalResponse;
     use crate::json_ext::ValueExt;
 
     macro_rules! assert_eq_and_ordered {
@@ -1969,6 +2884,16 @@ mod tests {
             "query GetProductsByAvailability($availability: Availability!){products(availability: $availability) {name}}",
             json!({"availability": "AVAILABLE"})
         );
+        assert_validation_error!(
+            "enum Availability{AVAILABLE} type Product{availability:Availability! name:String} type Query{products(availability: Availability!): [Product]!}",
+            "query GetProductsByAvailability($availability: Availability!){products(availability: $availability) {name}}",
+            json!({"availability": "UNAVAILABLE"})
+        );
+        assert_validation_error!(
+            "enum Availability{AVAILABLE} type Product{availability:Availability! name:String} type Query{products(availability: Availability!): [Product]!}",
+            "query GetProductsByAvailability($availability: Availability!){products(availability: $availability) {name}}",
+            json!({"availability": 1})
+        );
 
         assert_validation!(
             "input MessageInput {
@@ -5664,4 +6589,485 @@ mod tests {
             Value::Null,
         );
     }
+
+    #[test]
+    fn null_propagation_errors() {
+        let schema = with_supergraph_boilerplate(
+            "type Query { me: User products: [Product!] }
+            type User { name: String! }
+            type Product { upc: String! }",
+        );
+        let schema = Schema::parse(&schema, &Default::default()).expect("could not parse schema");
+        let query = Query::parse(
+            "{ me { name } products { upc } }",
+            &schema,
+            &Default::default(),
+        )
+        .expect("could not parse query");
+
+        let mut response = Response::builder()
+            .data(json! {{
+                "me": { "name": null },
+                "products": [{ "upc": "1" }, { "upc": null }],
+            }})
+            .build();
+        query.format_response(&mut response, None, Object::new(), schema.api_schema());
+        assert_eq!(
+            response.data,
+            Some(json! {{ "me": null, "products": null }})
+        );
+        assert_eq!(
+            response
+                .errors
+                .iter()
+                .map(|error| error.path.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                Some(Path::from("me/name")),
+                Some(Path::from("products/1/upc"))
+            ]
+        );
+
+        // the subgraph already reported why the field is null
+        let mut response = Response::builder()
+            .data(json! {{ "me": {}, "products": [] }})
+            .errors(vec![Error {
+                message: "could not fetch the name".to_string(),
+                path: Some(Path::from("me/name")),
+                ..Default::default()
+            }])
+            .build();
+        query.format_response(&mut response, None, Object::new(), schema.api_schema());
+        assert_eq!(response.data, Some(json! {{ "me": null, "products": [] }}));
+        assert_eq!(response.errors.len(), 1);
+    }
+
+    #[test]
+    fn variable_coercion() {
+        let schema = with_supergraph_boilerplate("type Query { x(foo: Int): String }");
+
+        // default values are used when the variable is not provided
+        let variables = run_validation!(schema, "query($foo:Int! = 1){x(foo: $foo)}", json!({}))
+            .expect("validation should have succeeded");
+        assert_eq!(variables.get("foo"), Some(&Value::from(1)));
+        let variables = run_validation!(
+            schema,
+            "query($foo:Int = 1){x(foo: $foo)}",
+            json!({ "foo": null })
+        )
+        .expect("validation should have succeeded");
+        assert_eq!(variables.get("foo"), Some(&Value::Null));
+
+        let messages = |response: Response| {
+            response
+                .errors
+                .into_iter()
+                .map(|error| error.message)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            messages(
+                run_validation!(schema, "query($foo:Int!){x(foo: $foo)}", json!({})).unwrap_err()
+            ),
+            vec![r#"Variable "$foo" of required type "Int!" was not provided."#]
+        );
+        assert_eq!(
+            messages(
+                run_validation!(
+                    schema,
+                    "query($foo:Int! = 1){x(foo: $foo)}",
+                    json!({ "foo": null })
+                )
+                .unwrap_err()
+            ),
+            vec![r#"Variable "$foo" of non-null type "Int!" must not be null."#]
+        );
+        assert_eq!(
+            messages(
+                run_validation!(
+                    schema,
+                    "query($foo:[Int!]){x(foo: $foo)}",
+                    json!({ "foo": ["1"] })
+                )
+                .unwrap_err()
+            ),
+            vec![r#"Variable "$foo" got invalid value ["1"]; Expected type "[Int!]"."#]
+        );
+    }
+
+    #[test]
+    fn input_object_coercion() {
+        let schema = with_supergraph_boilerplate(
+            "input Filter { name: String! = \"table\" limit: Int = 10 nested: [Nested!] }
+            input Nested { enabled: Boolean! = true }
+            type Query { x(filter: Filter): String }",
+        );
+
+        // the fields missing from the input objects take their default value, even when nested
+        let variables = run_validation!(
+            schema,
+            "query($filter:Filter){x(filter: $filter)}",
+            json!({ "filter": { "limit": null, "nested": [{}, { "enabled": false }] } })
+        )
+        .expect("validation should have succeeded");
+        assert_eq!(
+            variables.get("filter"),
+            Some(&json!({
+                "name": "table",
+                "limit": null,
+                "nested": [{ "enabled": true }, { "enabled": false }]
+            }))
+        );
+    }
+
+    #[test]
+    fn it_locates_syntax_errors() {
+        let schema = with_supergraph_boilerplate(
+            "type Query {
+            me(id: ID): User
+        }
+
+        type User {
+            name: String
+        }",
+        );
+        let schema = Schema::parse(&schema, &Default::default()).expect("could not parse schema");
+        let query = "{\n  me(id: ) { name }\n}";
+
+        let error = Query::parse(query, &schema, &Default::default()).unwrap_err();
+        let errors = match error {
+            SpecError::ParsingError(errors) => errors.0,
+            _ => panic!("expected a parsing error"),
+        };
+        assert_eq!(errors[0].token, ")");
+        assert_eq!(
+            errors[0].location,
+            Location {
+                line: 2,
+                column: 10
+            }
+        );
+        assert_eq!(errors[0].source_excerpt, None);
+
+        let mut configuration = Configuration::default();
+        configuration.server.experimental_error_source_excerpts = true;
+        let error = Query::parse(query, &schema, &configuration).unwrap_err();
+        let graphql_errors = error.to_graphql_errors();
+        assert_eq!(
+            graphql_errors[0].locations,
+            vec![Location {
+                line: 2,
+                column: 10
+            }]
+        );
+        assert_eq!(
+            graphql_errors[0].extensions.get("code"),
+            Some(&Value::from("GRAPHQL_PARSE_FAILED"))
+        );
+        assert_eq!(
+            graphql_errors[0].extensions.get("sourceExcerpt"),
+            Some(&Value::from("2 |   me(id: ) { name }\n  |          ^"))
+        );
+    }
+
+    #[test]
+    fn it_reports_values_not_matching_the_schema() {
+        let schema = with_supergraph_boilerplate(
+            "type Query {
+                me: User
+            }
+
+            type User {
+                id: ID!
+                age: Int
+                color: Color
+                friends: [User]
+            }
+
+            enum Color {
+                RED
+                GREEN
+            }",
+        );
+        let schema = Schema::parse(&schema, &Default::default()).expect("could not parse schema");
+        let data = json! {{
+            "me": {
+                "id": "a",
+                "age": "thirty",
+                "color": "PURPLE",
+                "friends": { "id": "b" },
+            }
+        }};
+
+        let format = |configuration: &Configuration| {
+            let query = Query::parse(
+                "{ me { id age color friends { id } age } }",
+                &schema,
+                configuration,
+            )
+            .expect("could not parse query");
+            let mut response = Response::builder().data(data.clone()).build();
+            query.format_response(&mut response, None, Object::default(), schema.api_schema());
+            response
+        };
+
+        let response = format(&Configuration::default());
+        assert_eq!(
+            response.data,
+            Some(json! {{
+                "me": {
+                    "id": "a",
+                    "age": null,
+                    "color": null,
+                    "friends": null,
+                }
+            }})
+        );
+        assert!(response.errors.is_empty());
+
+        let mut configuration = Configuration::default();
+        configuration.server.experimental_response_validation = ResponseValidation::Error;
+        let response = format(&configuration);
+        assert_eq!(
+            response
+                .errors
+                .iter()
+                .map(|error| (
+                    error.path.clone().unwrap().to_string(),
+                    error.message.as_str()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("/me/age".to_string(), "expected Int, found \"thirty\""),
+                ("/me/color".to_string(), "expected Color, found \"PURPLE\""),
+                (
+                    "/me/friends".to_string(),
+                    "expected a list, found an object"
+                ),
+            ]
+        );
+        assert!(response.errors.iter().all(|error| {
+            error.extensions.get("code") == Some(&Value::from("RESPONSE_VALIDATION_FAILED"))
+        }));
+    }
+
+    #[test]
+    fn it_orders_the_response_fields() {
+        let schema = with_supergraph_boilerplate(
+            "type Query {
+                me: User
+            }
+
+            type User {
+                id: ID!
+                name: String
+                friends: [User]
+            }",
+        );
+        let schema = Schema::parse(&schema, &Default::default()).expect("could not parse schema");
+        let query = Query::parse(
+            "{ me { id ...UserFields friends { name id } } } fragment UserFields on User { name }",
+            &schema,
+            &Default::default(),
+        )
+        .expect("could not parse query");
+
+        let mut response = Response::builder()
+            .data(json! {{
+                "me": {
+                    "extra": true,
+                    "friends": [{ "id": "b", "name": "B" }],
+                    "name": "A",
+                    "id": "a",
+                }
+            }})
+            .build();
+        query.order_response_fields(&mut response, None);
+        assert_eq_and_ordered!(
+            response.data.as_ref().unwrap(),
+            &json! {{
+                "me": {
+                    "id": "a",
+                    "name": "A",
+                    "friends": [{ "name": "B", "id": "b" }],
+                    "extra": true,
+                }
+            }}
+        );
+
+        let mut response = Response::builder()
+            .incremental(vec![IncrementalResponse::builder()
+                .data(json! {{ "id": "c", "name": "C" }})
+                .path(Path::from("me/friends/0"))
+                .build()])
+            .build();
+        query.order_response_fields(&mut response, None);
+        assert_eq_and_ordered!(
+            response.incremental[0].data.clone().unwrap(),
+            json! {{ "name": "C", "id": "c" }}
+        );
+    }
+
+    #[test]
+    fn it_prunes_the_unrequested_fields() {
+        let schema = with_supergraph_boilerplate(
+            "type Query {
+                node: Node
+            }
+
+            interface Node {
+                id: ID!
+            }
+
+            type User implements Node {
+                id: ID!
+                name: String
+                secret: String
+            }
+
+            type Product implements Node {
+                id: ID!
+                price: Int
+            }",
+        );
+        let schema = Schema::parse(&schema, &Default::default()).expect("could not parse schema");
+        let query = Query::parse(
+            "{ node { id ... on User { name } ... on Product { price } } }",
+            &schema,
+            &Default::default(),
+        )
+        .expect("could not parse query");
+
+        let mut response = Response::builder()
+            .data(json! {{
+                "node": {
+                    "__typename": "User",
+                    "id": "1",
+                    "name": "A",
+                    "price": 3,
+                    "secret": "s",
+                }
+            }})
+            .build();
+        let pruned = query.prune_fields(&mut response, None, &schema);
+        assert_eq!(
+            response.data.unwrap(),
+            json! {{ "node": { "id": "1", "name": "A" } }}
+        );
+        assert_eq!(
+            pruned,
+            vec![
+                Path::from("node/__typename"),
+                Path::from("node/price"),
+                Path::from("node/secret"),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_visits_the_objects_with_their_type() {
+        let schema = with_supergraph_boilerplate(
+            "type Query {
+                me: User
+                node: Node
+                anyNode: Node
+                named: Named
+            }
+
+            interface Node {
+                id: ID!
+            }
+
+            interface Named {
+                id: ID!
+            }
+
+            type User implements Node {
+                id: ID!
+                friends: [User]
+            }
+
+            type Product implements Node & Named {
+                id: ID!
+            }",
+        );
+        let schema = Schema::parse(&schema, &Default::default()).expect("could not parse schema");
+        let query = Query::parse(
+            "{ me { id friends { id } } node { __typename id } anyNode { id } named { id } }",
+            &schema,
+            &Default::default(),
+        )
+        .expect("could not parse query");
+
+        let response = Response::builder()
+            .data(json! {{
+                "me": { "id": "1", "friends": [{ "id": "2" }, null] },
+                "node": { "__typename": "Product", "id": "3" },
+                "anyNode": { "id": "4" },
+                "named": { "id": "5" },
+            }})
+            .build();
+        let mut visited = Vec::new();
+        query.visit_typed_objects(&response, None, &schema, &mut |type_name, object| {
+            visited.push(format!(
+                "{}:{}",
+                type_name,
+                object
+                    .get("id")
+                    .and_then(|id| id.as_str())
+                    .unwrap_or_default()
+            ))
+        });
+        // the type of `anyNode` is unknown without its `__typename`, `Product` is the only `Named`
+        assert_eq!(
+            visited,
+            vec!["Query:", "User:1", "User:2", "Product:3", "Product:5"]
+        );
+    }
+
+    #[test]
+    fn it_computes_the_cost_of_operations() {
+        let schema = with_supergraph_boilerplate(
+            "type Query {
+                me: User
+            }
+
+            type User {
+                id: ID!
+                name: String
+                friends: [User]
+                reviews: [Review]
+            }
+
+            type Review {
+                body: String
+            }",
+        );
+        let schema = Schema::parse(&schema, &Default::default()).expect("could not parse schema");
+        let cost = |query: &str, operation_name: Option<&str>| {
+            Query::parse(query, &schema, &Default::default())
+                .expect("could not parse query")
+                .cost(operation_name)
+        };
+
+        assert_eq!(
+            cost("{ me { id name } }", None),
+            OperationCost { cost: 3, depth: 2 }
+        );
+        assert_eq!(
+            cost(
+                "query A { me { id } } query B { me { ...F ... on User { id } } } fragment F on User { name reviews { body } }",
+                Some("B")
+            ),
+            OperationCost { cost: 5, depth: 3 }
+        );
+        // cycles are not followed
+        assert_eq!(
+            cost(
+                "{ me { ...F } } fragment F on User { id friends { ...F } }",
+                None
+            ),
+            OperationCost { cost: 3, depth: 2 }
+        );
+    }
 }
@@ -1,11 +1,14 @@
 //! GraphQL schema.
 
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::str::FromStr;
 use std::sync::Arc;
 
 use apollo_parser::ast;
+use apollo_parser::ast::AstNode;
 use http::Uri;
 use itertools::Itertools;
 use router_bridge::api_schema;
@@ -38,7 +41,16 @@ pub(crate) struct Schema {
 impl Schema {
     pub(crate) fn parse(s: &str, configuration: &Configuration) -> Result<Self, SchemaError> {
         let mut schema = parse(s, configuration)?;
-        schema.api_schema = Some(Box::new(api_schema(s, configuration)?));
+        // the API schema does not depend on progressive overrides, which the query planner
+        // does not know about
+        schema.api_schema = Some(Box::new(if schema.override_labels().is_empty() {
+            api_schema(s, configuration)?
+        } else {
+            api_schema(
+                &schema.with_override_labels(&BTreeSet::new()),
+                configuration,
+            )?
+        }));
         return Ok(schema);
 
         fn api_schema(schema: &str, configuration: &Configuration) -> Result<Schema, SchemaError> {
@@ -328,6 +340,7 @@ impl Schema {
                         .for_each(|extension| {
                             if let Some(instance) = map.get_mut(&extension.name) {
                                 instance.fields.extend(extension.fields);
+                                instance.default_values.extend(extension.default_values);
                             } else {
                                 failfast_debug!(
                                     concat!(
@@ -443,6 +456,15 @@ impl Schema {
             .unwrap_or(false)
     }
 
+    /// The types implementing an interface, or the members of a union.
+    pub(crate) fn possible_types(&self, abstract_type: &str) -> impl Iterator<Item = &str> {
+        self.subtype_map
+            .get(abstract_type)
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+    }
+
     /// Return an iterator over subgraphs that yields the subgraph name and its URL.
     pub(crate) fn subgraphs(&self) -> impl Iterator<Item = (&String, &Uri)> {
         self.subgraphs.iter()
@@ -455,6 +477,237 @@ impl Schema {
         }
     }
 
+    /// Types and fields of the schema by schema coordinate (`Type` or `Type.field`), along with
+    /// the type of the fields.
+    pub(crate) fn coordinates(&self) -> BTreeMap<String, Option<FieldType>> {
+        let mut coordinates = BTreeMap::new();
+        let types = self
+            .object_types
+            .iter()
+            .map(|(name, ty)| (name, ty.fields().collect::<Vec<_>>()))
+            .chain(
+                self.interfaces
+                    .iter()
+                    .map(|(name, ty)| (name, ty.fields().collect())),
+            );
+        for (name, fields) in types {
+            coordinates.insert(name.clone(), None);
+            for (field, ty) in fields {
+                coordinates.insert(format!("{}.{}", name, field), Some(ty.clone()));
+            }
+        }
+        for name in self
+            .input_types
+            .keys()
+            .chain(self.enums.keys())
+            .chain(self.custom_scalars.iter())
+        {
+            coordinates.insert(name.clone(), None);
+        }
+        coordinates
+    }
+
+    /// Returns the SDL of a contract of this supergraph.
+    ///
+    /// Types, fields, arguments, input fields and enum values tagged with one of `exclude_tags` are
+    /// marked `@inaccessible`, so they are removed from the API schema of the contract. Version
+    /// 0.1 of the inaccessible spec only applies to object, interface and union types and to
+    /// fields, so excluding other elements requires version 0.2.
+    pub(crate) fn contract(&self, exclude_tags: &[String]) -> Result<String, SchemaError> {
+        if !self
+            .string
+            .contains("https://specs.apollo.dev/inaccessible/")
+        {
+            return Err(SchemaError::Contract(
+                "the supergraph must use the inaccessible spec".to_string(),
+            ));
+        }
+
+        let tree = apollo_parser::Parser::new(&self.string).parse();
+        let mut insertions = Vec::new();
+        let mut mark = |directives: Option<ast::Directives>, requires_v02: bool| {
+            let directives = match directives {
+                Some(directives) => directives,
+                None => return,
+            };
+            let mut excluded = false;
+            for directive in directives.directives() {
+                match directive
+                    .name()
+                    .map(|name| name.text().to_string())
+                    .as_deref()
+                {
+                    Some("inaccessible") => return,
+                    Some("tag") => {
+                        excluded |= directive
+                            .arguments()
+                            .into_iter()
+                            .flat_map(|arguments| arguments.arguments())
+                            .filter(|argument| {
+                                argument.name().map(|name| name.text().to_string())
+                                    == Some("name".to_string())
+                            })
+                            .any(|argument| match argument.value() {
+                                Some(ast::Value::StringValue(value)) => {
+                                    exclude_tags.contains(&value.into())
+                                }
+                                _ => false,
+                            })
+                    }
+                    _ => {}
+                }
+            }
+            if excluded {
+                insertions.push((
+                    usize::from(directives.syntax().text_range().end()),
+                    requires_v02,
+                ));
+            }
+        };
+        fn mark_fields(
+            fields: Option<ast::FieldsDefinition>,
+            mark: &mut impl FnMut(Option<ast::Directives>, bool),
+        ) {
+            for field in fields
+                .into_iter()
+                .flat_map(|fields| fields.field_definitions())
+            {
+                mark(field.directives(), false);
+                for argument in field
+                    .arguments_definition()
+                    .into_iter()
+                    .flat_map(|arguments| arguments.input_value_definitions())
+                {
+                    mark(argument.directives(), true);
+                }
+            }
+        }
+
+        for definition in tree.document().definitions() {
+            match definition {
+                ast::Definition::ObjectTypeDefinition(object) => {
+                    mark(object.directives(), false);
+                    mark_fields(object.fields_definition(), &mut mark);
+                }
+                ast::Definition::InterfaceTypeDefinition(interface) => {
+                    mark(interface.directives(), false);
+                    mark_fields(interface.fields_definition(), &mut mark);
+                }
+                ast::Definition::UnionTypeDefinition(union) => mark(union.directives(), false),
+                ast::Definition::ScalarTypeDefinition(scalar) => mark(scalar.directives(), true),
+                ast::Definition::EnumTypeDefinition(enum_type) => {
+                    mark(enum_type.directives(), true);
+                    for value in enum_type
+                        .enum_values_definition()
+                        .into_iter()
+                        .flat_map(|values| values.enum_value_definitions())
+                    {
+                        mark(value.directives(), true);
+                    }
+                }
+                ast::Definition::InputObjectTypeDefinition(input) => {
+                    mark(input.directives(), true);
+                    for field in input
+                        .input_fields_definition()
+                        .into_iter()
+                        .flat_map(|fields| fields.input_value_definitions())
+                    {
+                        mark(field.directives(), true);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if insertions.iter().any(|(_, requires_v02)| *requires_v02)
+            && !self
+                .string
+                .contains("https://specs.apollo.dev/inaccessible/v0.2")
+        {
+            return Err(SchemaError::Contract(
+                "excluding scalars, enums, input objects, arguments or enum values requires the inaccessible spec v0.2".to_string(),
+            ));
+        }
+
+        let mut contract = self.string.as_str().to_string();
+        for (offset, _) in insertions.into_iter().rev() {
+            contract.insert_str(offset, " @inaccessible");
+        }
+        Ok(contract)
+    }
+
+    /// Returns the labels of the progressive overrides of this supergraph.
+    ///
+    /// A field migrated from one subgraph to another is marked with
+    /// `@join__field(override: "from", overrideLabel: "label")`.
+    pub(crate) fn override_labels(&self) -> BTreeSet<String> {
+        let tree = apollo_parser::Parser::new(&self.string).parse();
+        join_fields(&tree.document())
+            .filter_map(|directive| string_argument(&directive, "overrideLabel"))
+            .collect()
+    }
+
+    /// Returns the SDL of this supergraph with the progressive overrides resolved.
+    ///
+    /// A field with an override label in `enabled` is resolved by the subgraph it is overridden
+    /// by, other ones are resolved by the subgraph they come from. The `overrideLabel` argument
+    /// is removed, so the SDL can be used by a query planner that does not know about it.
+    pub(crate) fn with_override_labels(&self, enabled: &BTreeSet<String>) -> String {
+        let tree = apollo_parser::Parser::new(&self.string).parse();
+        let document = tree.document();
+        let mut removals = Vec::new();
+        for directive in join_fields(&document) {
+            let label = match string_argument(&directive, "overrideLabel") {
+                Some(label) => label,
+                None => continue,
+            };
+            let overriding = string_argument(&directive, "override").is_some();
+            if overriding == enabled.contains(&label) {
+                removals.extend(
+                    directive
+                        .arguments()
+                        .into_iter()
+                        .flat_map(|arguments| arguments.arguments())
+                        .filter(|argument| {
+                            argument.name().map(|name| name.text().to_string())
+                                == Some("overrideLabel".to_string())
+                        })
+                        .map(|argument| argument.syntax().text_range()),
+                );
+            } else {
+                removals.push(directive.syntax().text_range());
+            }
+        }
+
+        // the argument must also be removed from the directive definition
+        for definition in document.definitions() {
+            if let ast::Definition::DirectiveDefinition(definition) = definition {
+                if definition.name().map(|name| name.text().to_string())
+                    == Some("join__field".to_string())
+                {
+                    removals.extend(
+                        definition
+                            .arguments_definition()
+                            .into_iter()
+                            .flat_map(|arguments| arguments.input_value_definitions())
+                            .filter(|argument| {
+                                argument.name().map(|name| name.text().to_string())
+                                    == Some("overrideLabel".to_string())
+                            })
+                            .map(|argument| argument.syntax().text_range()),
+                    );
+                }
+            }
+        }
+
+        let mut sdl = self.string.as_str().to_string();
+        removals.sort_by_key(|range| range.start());
+        for range in removals.into_iter().rev() {
+            sdl.replace_range(usize::from(range.start())..usize::from(range.end()), "");
+        }
+        sdl
+    }
+
     fn with_introspection(schema: &str) -> String {
         format!(
             "{}\n{}",
@@ -478,6 +731,35 @@ impl Schema {
 #[derive(Debug)]
 pub(crate) struct InvalidObject;
 
+/// `@join__field` directives of the fields of object and interface types.
+fn join_fields(document: &ast::Document) -> impl Iterator<Item = ast::Directive> {
+    document
+        .definitions()
+        .flat_map(|definition| match definition {
+            ast::Definition::ObjectTypeDefinition(object) => object.fields_definition(),
+            ast::Definition::InterfaceTypeDefinition(interface) => interface.fields_definition(),
+            _ => None,
+        })
+        .flat_map(|fields| fields.field_definitions())
+        .flat_map(|field| field.directives())
+        .flat_map(|directives| directives.directives())
+        .filter(|directive| {
+            directive.name().map(|name| name.text().to_string()) == Some("join__field".to_string())
+        })
+}
+
+fn string_argument(directive: &ast::Directive, name: &str) -> Option<String> {
+    directive
+        .arguments()
+        .into_iter()
+        .flat_map(|arguments| arguments.arguments())
+        .find(|argument| argument.name().map(|n| n.text().to_string()).as_deref() == Some(name))
+        .and_then(|argument| match argument.value() {
+            Some(ast::Value::StringValue(value)) => Some(value.into()),
+            _ => None,
+        })
+}
+
 macro_rules! implement_object_type_or_interface {
     ($visibility:vis $name:ident => $( $ast_ty:ty ),+ $(,)?) => {
         #[derive(Debug, Clone)]
@@ -491,6 +773,10 @@ macro_rules! implement_object_type_or_interface {
             pub(crate) fn field(&self, name: &str) -> Option<&FieldType> {
                 self.fields.get(name)
             }
+
+            pub(crate) fn fields(&self) -> impl Iterator<Item = (&String, &FieldType)> {
+                self.fields.iter()
+            }
         }
 
         $(
@@ -562,9 +848,26 @@ macro_rules! implement_input_object_type_or_interface {
         $visibility struct $name {
             name: String,
             fields: HashMap<String, FieldType>,
+            default_values: HashMap<String, Value>,
         }
 
         impl $name {
+            /// Set the fields missing from the object to their default value, in the object and in
+            /// the input objects it contains.
+            /// Spec: https://spec.graphql.org/draft/#sec-Input-Objects.Input-Coercion
+            pub(crate) fn apply_default_values(&self, object: &mut Object, schema: &Schema) {
+                for (name, default_value) in &self.default_values {
+                    if !object.contains_key(name.as_str()) {
+                        object.insert(name.as_str(), default_value.clone());
+                    }
+                }
+                for (name, ty) in &self.fields {
+                    if let Some(value) = object.get_mut(name.as_str()) {
+                        ty.apply_default_values(value, schema);
+                    }
+                }
+            }
+
             pub(crate) fn validate_object(
                 &self,
                 object: &Object,
@@ -589,6 +892,16 @@ macro_rules! implement_input_object_type_or_interface {
                     .expect("the node Name is not optional in the spec; qed")
                     .text()
                     .to_string();
+                let default_values = definition
+                    .input_fields_definition()
+                    .iter()
+                    .flat_map(|x| x.input_value_definitions())
+                    .filter_map(|x| {
+                        let name = x.name()?.text().to_string();
+                        let value = super::query::parse_value(&x.default_value()?.value()?)?;
+                        Some((name, value))
+                    })
+                    .collect();
                 let fields = definition
                     .input_fields_definition()
                     .iter()
@@ -610,6 +923,7 @@ macro_rules! implement_input_object_type_or_interface {
                 $name {
                     name,
                     fields,
+                    default_values,
                 }
             }
         }
@@ -809,6 +1123,114 @@ mod tests {
             .is_none());
     }
 
+    #[test]
+    fn contract() {
+        let schema = include_str!("../testdata/contract_schema.graphql").replace(
+            "name: String @join__field(graph: PRODUCTS)",
+            r#"name: String @join__field(graph: PRODUCTS) @tag(name: "internal")"#,
+        );
+        let schema = Schema::parse(&schema, &Default::default()).unwrap();
+
+        let contract = schema.contract(&["internal".to_string()]).unwrap();
+        assert!(contract.contains(
+            r#"name: String @join__field(graph: PRODUCTS) @tag(name: "internal") @inaccessible"#
+        ));
+        // already inaccessible, it is not marked twice
+        assert!(contract.contains(
+            r#"inStock: Boolean @join__field(graph: INVENTORY) @tag(name: "private") @inaccessible
+"#
+        ));
+
+        let contract = Schema::parse(&contract, &Default::default()).unwrap();
+        assert!(contract.api_schema().object_types["Product"]
+            .fields
+            .get("name")
+            .is_none());
+        assert!(contract.api_schema().object_types["Product"]
+            .fields
+            .get("price")
+            .is_some());
+    }
+
+    /// The contract schema, with an argument tagged `internal`.
+    fn contract_schema_with_tagged_argument() -> String {
+        include_str!("../testdata/contract_schema.graphql")
+            .replace(
+                "directive @tag(name: String!) repeatable on FIELD_DEFINITION | INTERFACE | OBJECT | UNION",
+                "directive @tag(name: String!) repeatable on FIELD_DEFINITION | INTERFACE | OBJECT | UNION | ARGUMENT_DEFINITION | SCALAR | ENUM | ENUM_VALUE | INPUT_OBJECT | INPUT_FIELD_DEFINITION",
+            )
+            .replace(
+                "topProducts(first: Int = 5)",
+                r#"topProducts(first: Int = 5 @tag(name: "internal"))"#,
+            )
+    }
+
+    #[test]
+    fn contract_excludes_arguments() {
+        let schema = contract_schema_with_tagged_argument()
+            .replace("inaccessible/v0.1", "inaccessible/v0.2")
+            .replace(
+                "directive @inaccessible on OBJECT | FIELD_DEFINITION | INTERFACE | UNION",
+                "directive @inaccessible on FIELD_DEFINITION | OBJECT | INTERFACE | UNION | ARGUMENT_DEFINITION | SCALAR | ENUM | ENUM_VALUE | INPUT_OBJECT | INPUT_FIELD_DEFINITION",
+            );
+        let schema = Schema::parse(&schema, &Default::default()).unwrap();
+
+        let contract = schema.contract(&["internal".to_string()]).unwrap();
+        assert!(contract.contains(
+            r#"topProducts(first: Int = 5 @tag(name: "internal") @inaccessible): [Product]"#
+        ));
+    }
+
+    #[test]
+    fn contract_requires_inaccessible_v02_for_arguments() {
+        let schema =
+            Schema::parse(&contract_schema_with_tagged_argument(), &Default::default()).unwrap();
+        assert!(schema.contract(&["internal".to_string()]).is_err());
+    }
+
+    #[test]
+    fn contract_requires_inaccessible() {
+        let schema = include_str!("../testdata/minimal_supergraph.graphql");
+        let schema = Schema::parse(schema, &Default::default()).unwrap();
+        assert!(schema.contract(&["internal".to_string()]).is_err());
+    }
+
+    #[test]
+    fn override_labels() {
+        let schema = include_str!("../testdata/progressive_override.graphql");
+        let schema = Schema::parse(schema, &Default::default()).unwrap();
+        assert_eq!(
+            schema.override_labels(),
+            BTreeSet::from(["percent(25)".to_string()])
+        );
+
+        let in_stock = |sdl: &str| {
+            sdl.lines()
+                .find(|line| line.trim_start().starts_with("inStock"))
+                .unwrap()
+                .to_string()
+        };
+
+        let enabled = schema.with_override_labels(&schema.override_labels());
+        assert!(!enabled.contains("overrideLabel"));
+        assert!(
+            in_stock(&enabled).contains(r#"@join__field(graph: INVENTORY, override: "products""#)
+        );
+        assert!(!in_stock(&enabled).contains("PRODUCTS"));
+
+        let disabled = schema.with_override_labels(&BTreeSet::new());
+        assert!(!disabled.contains("overrideLabel"));
+        assert!(in_stock(&disabled).contains("@join__field(graph: PRODUCTS"));
+        assert!(!in_stock(&disabled).contains("INVENTORY"));
+
+        let disabled = Schema::parse(&disabled, &Default::default()).unwrap();
+        assert!(disabled.override_labels().is_empty());
+        assert!(disabled.api_schema().object_types["Product"]
+            .fields
+            .get("inStock")
+            .is_some());
+    }
+
     #[test]
     fn schema_id() {
         #[cfg(not(windows))]
@@ -16,6 +16,7 @@ use super::router::ApolloRouterError::NoSchema;
 use super::router::ApolloRouterError::{self};
 use super::router::Event::UpdateConfiguration;
 use super::router::Event::UpdateSchema;
+use super::router::Event::UplinkError;
 use super::router::Event::{self};
 use super::state_machine::State::Errored;
 use super::state_machine::State::Running;
@@ -23,8 +24,15 @@ use super::state_machine::State::Startup;
 use super::state_machine::State::Stopped;
 use crate::configuration::Configuration;
 use crate::configuration::ListenAddr;
+use crate::executable::set_log_level;
+use crate::executable::GLOBAL_ENV_FILTER;
+use crate::panics;
 use crate::router_factory::SupergraphServiceConfigurator;
 use crate::router_factory::SupergraphServiceFactory;
+#[cfg(unix)]
+use crate::systemd;
+use crate::webhooks;
+use crate::webhooks::Notifier;
 use crate::Schema;
 
 /// This state maintains private information that is not exposed to the user via state listener.
@@ -75,6 +83,13 @@ where
     // The reason we have listen_address and listen_address_guard is that on startup we want ensure that we update the listen address before users can read the value.
     pub(crate) listen_address: Arc<RwLock<Option<ListenAddr>>>,
     listen_address_guard: Option<OwnedRwLockWriteGuard<Option<ListenAddr>>>,
+
+    /// Webhooks of the running configuration, notified of the reloads and Uplink errors.
+    webhooks: Option<Notifier>,
+
+    /// Notifications of the readiness of the running pipeline to systemd.
+    #[cfg(unix)]
+    service_notifier: Option<systemd::ServiceNotifier>,
 }
 
 impl<S, FA> StateMachine<S, FA>
@@ -91,6 +106,15 @@ where
             router_configurator: router_factory,
             listen_address: ready,
             listen_address_guard: Some(ready_guard),
+            webhooks: None,
+            #[cfg(unix)]
+            service_notifier: None,
+        }
+    }
+
+    fn notify(&self, event: webhooks::Event) {
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.notify(event);
         }
     }
 
@@ -141,6 +165,8 @@ where
                 // Running: Handle shutdown.
                 (Running { server_handle, .. }, Shutdown) => {
                     tracing::debug!("shutting down");
+                    #[cfg(unix)]
+                    systemd::notify("STOPPING=1");
                     match server_handle.shutdown().await {
                         Ok(_) => Stopped,
                         Err(err) => Errored(err),
@@ -220,6 +246,12 @@ where
                     }
                 }
 
+                // Uplink errors are reported to the webhooks whatever the state
+                (state, UplinkError(error)) => {
+                    self.notify(webhooks::Event::UplinkError { error });
+                    state
+                }
+
                 // Anything else we don't care about
                 (state, message) => {
                     tracing::debug!("ignoring message transition {:?}", message);
@@ -322,6 +354,16 @@ where
                     Errored(err)
                 })?;
 
+            if configuration.logging.level.is_some() {
+                apply_log_level(&configuration);
+            }
+            self.webhooks = Notifier::configured(configuration.webhooks.as_ref());
+            panics::configure(configuration.crash_reports.as_ref());
+            #[cfg(unix)]
+            {
+                self.service_notifier = systemd::ServiceNotifier::start(&router_factory);
+            }
+
             Ok(Running {
                 configuration,
                 schema,
@@ -374,6 +416,28 @@ where
                         tracing::error!("cannot start the router: {}", err);
                         Errored(err)
                     })?;
+                if new_configuration.logging != configuration.logging {
+                    apply_log_level(&new_configuration);
+                }
+                if new_configuration.webhooks != configuration.webhooks {
+                    self.webhooks = Notifier::configured(new_configuration.webhooks.as_ref());
+                }
+                if new_configuration.crash_reports != configuration.crash_reports {
+                    panics::configure(new_configuration.crash_reports.as_ref());
+                }
+                #[cfg(unix)]
+                if let Some(service_notifier) = &self.service_notifier {
+                    service_notifier.follow(&new_router_service);
+                }
+                if !Arc::ptr_eq(&new_schema, &schema) {
+                    self.notify(webhooks::Event::SchemaReloaded {
+                        previous_hash: schema.schema_id.clone(),
+                        hash: new_schema.schema_id.clone(),
+                    });
+                }
+                if !Arc::ptr_eq(&new_configuration, &configuration) {
+                    self.notify(webhooks::Event::ConfigurationReloaded);
+                }
                 Ok(Running {
                     configuration: new_configuration,
                     schema: new_schema,
@@ -397,6 +461,20 @@ where
     }
 }
 
+/// Apply the log level from the configuration, falling back to the one from the command line.
+fn apply_log_level(configuration: &Configuration) {
+    let log_level = configuration
+        .logging
+        .level
+        .clone()
+        .or_else(|| GLOBAL_ENV_FILTER.get().cloned())
+        .unwrap_or_else(|| "info".to_string());
+    tracing::info!("setting log level to '{}'", log_level);
+    if let Err(e) = set_log_level(&log_level) {
+        tracing::error!("could not set the log level: {}", e);
+    }
+}
+
 trait ResultExt<T> {
     // Unstable method can be deleted in future
     fn into_ok_or_err2(self) -> T;
@@ -768,6 +846,8 @@ mod tests {
                     Ok(HttpServerHandle::new(
                         shutdown_sender,
                         Box::pin(server),
+                        Box::pin(future::ready(())),
+                        configuration.server.drain_timeout,
                         configuration.server.listen.clone(),
                     ))
                 },
@@ -0,0 +1,302 @@
+//! Supervision of the router by its own process, for deployments without an external supervisor.
+//!
+//! With `--supervise`, the router process does not handle any traffic: it starts a worker process
+//! with the same arguments, and starts it again when it crashes, after an exponential backoff. The
+//! worker is told how many times it was restarted, and exposes it with the
+//! `apollo_router_worker_restarts_total` metric. A worker which could not start, e.g. because of
+//! its configuration, is not restarted since it would fail again.
+//!
+//! The signals sent to the supervisor are forwarded to the worker: `SIGHUP` to reload its
+//! configuration, and `SIGTERM` to shut it down.
+
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::path::Path;
+use std::time::Duration;
+use std::time::Instant;
+
+use displaydoc::Display;
+use futures::stream::BoxStream;
+use futures::Stream;
+use futures::StreamExt;
+use thiserror::Error;
+use tokio::process::Child;
+use tokio::process::Command;
+
+use crate::router::ShutdownSource;
+
+/// Environment variable set in the worker processes to the number of times they were restarted.
+pub(crate) const WORKER_RESTARTS_ENV: &str = "APOLLO_ROUTER_WORKER_RESTARTS";
+
+/// Delay before the first restart of a crashed worker.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Maximum delay between the restarts of a worker crashing repeatedly.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A worker which ran this long was healthy: the backoff starts over when it crashes.
+const HEALTHY_UPTIME: Duration = Duration::from_secs(60);
+/// Time given to the worker to shut down gracefully before it is killed.
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Exit code of the workers which could not start, `EX_CONFIG` of `sysexits.h`. They are not
+/// restarted.
+pub(crate) const STARTUP_FAILURE_EXIT_CODE: i32 = 78;
+
+/// Supervision errors.
+#[derive(Error, Debug, Display)]
+pub(crate) enum SupervisorError {
+    /// could not find the router executable: {0}
+    Executable(std::io::Error),
+    /// could not start the worker: {0}
+    Spawn(std::io::Error),
+    /// could not wait for the worker: {0}
+    Wait(std::io::Error),
+    /// could not handle the signals of the supervisor: {0}
+    Signal(std::io::Error),
+    /// the worker could not start, it is not restarted
+    Startup,
+}
+
+/// The number of times this worker was restarted, if it is supervised.
+pub(crate) fn worker_restarts() -> Option<u64> {
+    std::env::var(WORKER_RESTARTS_ENV)
+        .ok()
+        .and_then(|restarts| restarts.parse().ok())
+}
+
+/// Delay before restarting the worker after its consecutive crashes.
+fn backoff(crashes: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(crashes.saturating_sub(1)))
+        .min(MAX_BACKOFF)
+}
+
+/// Run the worker until it exits successfully or the supervisor is shut down, restarting it when
+/// it crashes.
+pub(crate) async fn supervise(shutdown: ShutdownSource) -> Result<(), SupervisorError> {
+    let executable = std::env::current_exe().map_err(SupervisorError::Executable)?;
+    let args: Vec<OsString> = std::env::args_os().skip(1).collect();
+    run(&executable, &args, shutdown.into_stream(), hangups()?).await
+}
+
+/// The `SIGHUP` signals received by the supervisor, which would kill it otherwise.
+#[cfg(unix)]
+fn hangups() -> Result<BoxStream<'static, ()>, SupervisorError> {
+    let mut signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .map_err(SupervisorError::Signal)?;
+    Ok(futures::stream::poll_fn(move |cx| signal.poll_recv(cx)).boxed())
+}
+
+#[cfg(not(unix))]
+fn hangups() -> Result<BoxStream<'static, ()>, SupervisorError> {
+    Ok(futures::stream::pending().boxed())
+}
+
+async fn run(
+    executable: &Path,
+    args: &[impl AsRef<OsStr>],
+    mut shutdown: impl Stream + Unpin,
+    hangups: impl Stream<Item = ()> + Unpin,
+) -> Result<(), SupervisorError> {
+    let mut hangups = hangups.fuse();
+    let mut restarts: u64 = 0;
+    let mut crashes: u32 = 0;
+
+    loop {
+        let started = Instant::now();
+        let mut worker = Command::new(executable)
+            .args(args)
+            .env(WORKER_RESTARTS_ENV, restarts.to_string())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(SupervisorError::Spawn)?;
+        tracing::info!(
+            "started the worker (pid {})",
+            worker.id().unwrap_or_default()
+        );
+
+        let status = loop {
+            tokio::select! {
+                status = worker.wait() => break Some(status.map_err(SupervisorError::Wait)?),
+                _ = shutdown.next() => break None,
+                Some(()) = hangups.next() => {
+                    tracing::info!("forwarding SIGHUP to the worker");
+                    #[cfg(unix)]
+                    signal(&worker, libc::SIGHUP);
+                }
+            }
+        };
+        let status = match status {
+            Some(status) => status,
+            None => return stop(&mut worker, STOP_TIMEOUT).await,
+        };
+        if status.success() {
+            tracing::info!("the worker exited");
+            return Ok(());
+        }
+        if status.code() == Some(STARTUP_FAILURE_EXIT_CODE) {
+            tracing::error!("the worker could not start, it is not restarted");
+            return Err(SupervisorError::Startup);
+        }
+
+        if started.elapsed() >= HEALTHY_UPTIME {
+            crashes = 0;
+        }
+        crashes += 1;
+        let delay = backoff(crashes);
+        tracing::error!(
+            "the worker crashed ({}), restarting it in {:?}",
+            status,
+            delay
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.next() => return Ok(()),
+        }
+        restarts += 1;
+    }
+}
+
+/// Send a signal to the worker, if it is still running.
+#[cfg(unix)]
+fn signal(worker: &Child, signal: libc::c_int) {
+    if let Some(pid) = worker.id() {
+        // SAFETY: the worker was not waited for yet, the pid is still its own
+        unsafe { libc::kill(pid as libc::pid_t, signal) };
+    }
+}
+
+/// Shut the worker down, gracefully where signals are supported, killing it if it is still running
+/// after the timeout.
+async fn stop(worker: &mut Child, timeout: Duration) -> Result<(), SupervisorError> {
+    #[cfg(unix)]
+    {
+        if worker.id().is_some() {
+            signal(worker, libc::SIGTERM);
+            match tokio::time::timeout(timeout, worker.wait()).await {
+                Ok(status) => return status.map(|_| ()).map_err(SupervisorError::Wait),
+                Err(_) => tracing::warn!(
+                    "the worker did not shut down within {:?}, killing it",
+                    timeout
+                ),
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = timeout;
+    worker.kill().await.map_err(SupervisorError::Wait)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_backs_off_exponentially() {
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(2), Duration::from_secs(2));
+        assert_eq!(backoff(4), Duration::from_secs(8));
+        assert_eq!(backoff(7), MAX_BACKOFF);
+        assert_eq!(backoff(40), MAX_BACKOFF);
+    }
+
+    /// Supervise a shell worker running a script.
+    #[cfg(unix)]
+    async fn run_script(
+        script: &str,
+        shutdown: impl Stream + Unpin,
+        hangups: impl Stream<Item = ()> + Unpin,
+    ) -> Result<(), SupervisorError> {
+        run(Path::new("sh"), &["-c", script], shutdown, hangups).await
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn it_forwards_the_signals_to_the_worker() {
+        let dir = tempfile::tempdir().unwrap();
+        let received = dir.path().join("received");
+        let script = format!(
+            "trap 'echo HUP >> {0}' HUP; trap 'echo TERM >> {0}; exit 0' TERM; \
+             while true; do sleep 0.1; done",
+            received.display()
+        );
+        let (hangup, hangups) = futures::channel::mpsc::unbounded();
+        let (shutdown, shutdown_signal) = futures::channel::mpsc::unbounded::<()>();
+        let supervisor =
+            tokio::spawn(async move { run_script(&script, shutdown_signal, hangups).await });
+
+        // let the shell set its traps before the signals are sent
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        hangup.unbounded_send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        shutdown.unbounded_send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), supervisor)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&received).unwrap(), "HUP\nTERM\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn it_does_not_restart_the_workers_which_could_not_start() {
+        let dir = tempfile::tempdir().unwrap();
+        let runs = dir.path().join("runs");
+        let script = format!(
+            "echo run >> {}; exit {}",
+            runs.display(),
+            STARTUP_FAILURE_EXIT_CODE
+        );
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            run_script(
+                &script,
+                futures::stream::pending::<()>(),
+                futures::stream::pending(),
+            ),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(result, Err(SupervisorError::Startup)));
+        assert_eq!(std::fs::read_to_string(&runs).unwrap(), "run\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn it_restarts_the_crashed_workers() {
+        let dir = tempfile::tempdir().unwrap();
+        let runs = dir.path().join("runs");
+        // the first worker crashes, the second one exits
+        let script = format!(
+            "echo $APOLLO_ROUTER_WORKER_RESTARTS >> {0}; [ $APOLLO_ROUTER_WORKER_RESTARTS -gt 0 ]",
+            runs.display()
+        );
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            run_script(
+                &script,
+                futures::stream::pending::<()>(),
+                futures::stream::pending(),
+            ),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(std::fs::read_to_string(&runs).unwrap(), "0\n1\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn it_kills_the_workers_ignoring_the_shutdown() {
+        let mut worker = Command::new("sh")
+            .args(["-c", "trap '' TERM; sleep 10"])
+            .spawn()
+            .unwrap();
+        // let the shell ignore the signal before it is sent
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let started = Instant::now();
+        stop(&mut worker, Duration::from_millis(200)).await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(worker.try_wait().unwrap().is_some());
+    }
+}
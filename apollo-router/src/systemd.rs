@@ -0,0 +1,299 @@
+//! Integration with systemd: socket activation and service notifications.
+//!
+//! When systemd starts the router with socket activation, the listener it passes is used instead
+//! of binding the listen address. When the service is of type `notify`, the router sends `READY=1`
+//! once its readiness check passes, `WATCHDOG=1` periodically while it keeps passing it if the
+//! systemd watchdog is enabled, and `STOPPING=1` when it shuts down.
+
+use std::io;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TryRecvError;
+
+use crate::http_server_factory::InheritedListener;
+use crate::router_factory::SupergraphServiceFactory;
+
+/// The first file descriptor passed by socket activation.
+const LISTEN_FDS_START: RawFd = 3;
+/// Interval between the readiness checks, until the router is ready.
+const READINESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The listener passed by systemd socket activation, if any.
+///
+/// The environment variables of the activation are removed, so that the socket is only taken
+/// once: this must be called before the runtime starts its threads.
+pub(crate) fn take_activated_listener() -> io::Result<Option<InheritedListener>> {
+    let for_this_process = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    let fds = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<u32>().ok())
+        .unwrap_or_default();
+    if !for_this_process || fds == 0 {
+        return Ok(None);
+    }
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if fds > 1 {
+        tracing::warn!("systemd passed {} sockets, only the first one is used", fds);
+    }
+
+    let listener = listener(LISTEN_FDS_START)?;
+    tracing::info!("using the socket passed by systemd");
+    Ok(Some(listener))
+}
+
+/// The listener of a file descriptor, which must be a listening stream socket.
+fn listener(fd: RawFd) -> io::Result<InheritedListener> {
+    let invalid = |reason: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("the socket passed by systemd {}", reason),
+        )
+    };
+    if socket_option(fd, libc::SO_TYPE)? != libc::SOCK_STREAM {
+        return Err(invalid("is not a stream socket"));
+    }
+    if socket_option(fd, libc::SO_ACCEPTCONN)? == 0 {
+        return Err(invalid("is not listening"));
+    }
+
+    // SAFETY: the address is only read up to the length written by getsockname
+    let family = unsafe {
+        let mut address: libc::sockaddr_storage = std::mem::zeroed();
+        let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        if libc::getsockname(
+            fd,
+            &mut address as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        libc::c_int::from(address.ss_family)
+    };
+    // SAFETY: socket activation passes the sockets from the first file descriptor onwards, and
+    // they are not used anywhere else since the environment variables were removed
+    match family {
+        libc::AF_INET | libc::AF_INET6 => Ok(InheritedListener::Tcp(unsafe {
+            std::net::TcpListener::from_raw_fd(fd)
+        })),
+        libc::AF_UNIX => Ok(InheritedListener::Unix(unsafe {
+            std::os::unix::net::UnixListener::from_raw_fd(fd)
+        })),
+        _ => Err(invalid("is neither a TCP nor a Unix socket")),
+    }
+}
+
+fn socket_option(fd: RawFd, option: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: the value is an integer, as the length
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            option,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+/// Send a notification to systemd, if the service is of type `notify`.
+pub(crate) fn notify(state: &str) {
+    if let Ok(socket) = std::env::var("NOTIFY_SOCKET") {
+        if let Err(e) = send(&socket, state) {
+            tracing::warn!("could not notify systemd of '{}': {}", state, e);
+        }
+    }
+}
+
+fn send(socket: &str, state: &str) -> std::io::Result<()> {
+    if socket.starts_with('@') {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "abstract notification sockets are not supported",
+        ));
+    }
+    UnixDatagram::unbound()?.send_to(state.as_bytes(), socket)?;
+    Ok(())
+}
+
+/// The interval of the watchdog notifications: half of the watchdog timeout, if it is enabled.
+fn watchdog_interval() -> Option<Duration> {
+    let for_this_process = std::env::var("WATCHDOG_PID")
+        .ok()
+        .map(|pid| pid.parse::<u32>().ok() == Some(std::process::id()))
+        .unwrap_or(true);
+    std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .filter(|usec| for_this_process && *usec > 0)
+        .map(|usec| Duration::from_micros(usec / 2))
+}
+
+/// What a pipeline waits for before it is ready, if it is not.
+trait Readiness: Send {
+    fn waiting_for(&self) -> Option<String>;
+}
+
+impl<RF: SupergraphServiceFactory> Readiness for RF {
+    fn waiting_for(&self) -> Option<String> {
+        let unhealthy = self.unhealthy_subgraphs();
+        let unready = self.unready_plugins();
+        if unhealthy.is_empty() && unready.is_empty() {
+            None
+        } else {
+            Some(format!(
+                "the subgraphs {:?} and the plugins {:?}",
+                unhealthy, unready
+            ))
+        }
+    }
+}
+
+/// Notifies systemd of the readiness of the running pipeline, if the service is of type `notify`.
+///
+/// The notifications are sent from a task which only holds the latest pipeline: the previous
+/// ones are dropped as soon as the router reloads, and all of them once the router is ready if
+/// the watchdog is not enabled.
+pub(crate) struct ServiceNotifier {
+    pipelines: mpsc::UnboundedSender<Box<dyn Readiness>>,
+}
+
+impl ServiceNotifier {
+    /// Notify systemd once the first pipeline passes its readiness check, then keep notifying
+    /// its watchdog while the running pipeline passes it: a stalled or unhealthy router stops
+    /// the notifications, and is restarted by systemd.
+    pub(crate) fn start<RF: SupergraphServiceFactory>(service_factory: &RF) -> Option<Self> {
+        std::env::var_os("NOTIFY_SOCKET")?;
+        let (pipelines, receiver) = mpsc::unbounded_channel();
+        let notifier = Self { pipelines };
+        notifier.follow(service_factory);
+        tokio::spawn(notify_readiness(receiver));
+        Some(notifier)
+    }
+
+    /// Follow the readiness of a new pipeline.
+    pub(crate) fn follow<RF: SupergraphServiceFactory>(&self, service_factory: &RF) {
+        // the notifications are over once the router is ready, if the watchdog is not enabled
+        let _ = self.pipelines.send(Box::new(service_factory.clone()));
+    }
+}
+
+async fn notify_readiness(mut pipelines: mpsc::UnboundedReceiver<Box<dyn Readiness>>) {
+    let mut pipeline = match pipelines.recv().await {
+        Some(pipeline) => pipeline,
+        None => return,
+    };
+    loop {
+        if !follow_latest(&mut pipelines, &mut pipeline) {
+            return;
+        }
+        match pipeline.waiting_for() {
+            None => break,
+            Some(waiting_for) => notify(&format!("STATUS=waiting for {}", waiting_for)),
+        }
+        tokio::time::sleep(READINESS_INTERVAL).await;
+    }
+    notify("READY=1\nSTATUS=ready");
+
+    let interval = match watchdog_interval() {
+        Some(interval) => interval,
+        None => return,
+    };
+    let mut interval = tokio::time::interval(interval);
+    let mut ready = true;
+    loop {
+        interval.tick().await;
+        if !follow_latest(&mut pipelines, &mut pipeline) {
+            return;
+        }
+        // an unhealthy router is not kept alive
+        match pipeline.waiting_for() {
+            None if ready => notify("WATCHDOG=1"),
+            None => {
+                notify("WATCHDOG=1\nSTATUS=ready");
+                ready = true;
+            }
+            Some(waiting_for) => {
+                notify(&format!("STATUS=unhealthy, waiting for {}", waiting_for));
+                ready = false;
+            }
+        }
+    }
+}
+
+/// Replace the pipeline with the latest one, returning false once the router is stopped.
+fn follow_latest(
+    pipelines: &mut mpsc::UnboundedReceiver<Box<dyn Readiness>>,
+    pipeline: &mut Box<dyn Readiness>,
+) -> bool {
+    loop {
+        match pipelines.try_recv() {
+            Ok(latest) => *pipeline = latest,
+            Err(TryRecvError::Empty) => return true,
+            Err(TryRecvError::Disconnected) => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_sends_the_notifications() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("notify.sock");
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        send(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buffer = [0; 16];
+        let len = socket.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"READY=1");
+
+        assert!(send("@router/notify", "READY=1").is_err());
+    }
+
+    #[test]
+    fn it_only_takes_listening_stream_sockets() {
+        use std::os::unix::io::IntoRawFd;
+
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(matches!(
+            listener(tcp.into_raw_fd()).unwrap(),
+            InheritedListener::Tcp(_)
+        ));
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let unix =
+            std::os::unix::net::UnixListener::bind(temp_dir.path().join("router.sock")).unwrap();
+        assert!(matches!(
+            listener(unix.into_raw_fd()).unwrap(),
+            InheritedListener::Unix(_)
+        ));
+
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .into_raw_fd();
+        assert_eq!(
+            listener(udp).unwrap_err().to_string(),
+            "the socket passed by systemd is not a stream socket"
+        );
+        // SAFETY: the socket was not taken
+        unsafe { libc::close(udp) };
+    }
+}
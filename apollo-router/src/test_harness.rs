@@ -4,6 +4,7 @@ use tower::BoxError;
 use tower::ServiceExt;
 
 use crate::configuration::Configuration;
+use crate::plugin::extra_plugin_name;
 use crate::plugin::test::canned;
 use crate::plugin::DynPlugin;
 use crate::plugin::Plugin;
@@ -112,19 +113,7 @@ impl<'a> TestHarness<'a> {
     /// May be called multiple times.
     /// These extra plugins are added after plugins specified in configuration.
     pub fn extra_plugin<P: Plugin>(mut self, plugin: P) -> Self {
-        let type_id = std::any::TypeId::of::<P>();
-        let name = match crate::plugin::plugins()
-            .iter()
-            .find(|(_name, factory)| factory.type_id == type_id)
-        {
-            Some((name, _factory)) => name.clone(),
-            None => format!(
-                "extra_plugins.{}.{}",
-                self.extra_plugins.len(),
-                std::any::type_name::<P>(),
-            ),
-        };
-
+        let name = extra_plugin_name::<P>(self.extra_plugins.len());
         self.extra_plugins.push((name, Box::new(plugin)));
         self
     }
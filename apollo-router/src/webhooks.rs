@@ -0,0 +1,279 @@
+//! Webhook notifications of operational events.
+//!
+//! Events are sent in the background to the endpoints of a configuration, as JSON POST requests,
+//! by the [`Notifier`] of the state machine for the reloads and of each pipeline for its own
+//! events. Deliveries are retried with an exponential backoff, and signed with HMAC-SHA256 when the
+//! endpoint has a secret, so that the receiver can check they come from the router.
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use hmac::Hmac;
+use hmac::Mac;
+use http::header::CONTENT_TYPE;
+use serde::Serialize;
+use sha2::Sha256;
+use tower::BoxError;
+
+use crate::configuration::WebhookEndpoint;
+use crate::configuration::WebhookEvent;
+use crate::configuration::Webhooks;
+
+/// Header carrying the signature of the events.
+const SIGNATURE_HEADER: &str = "x-router-signature";
+
+/// Delay before the first retry of a failed delivery, doubled after every attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// An operational event.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Event {
+    SchemaReloaded {
+        previous_hash: Option<String>,
+        hash: Option<String>,
+    },
+    ConfigurationReloaded,
+    CircuitOpened {
+        subgraph: String,
+    },
+    CircuitClosed {
+        subgraph: String,
+    },
+    UplinkError {
+        error: String,
+    },
+    CanaryFailed {
+        operation: Option<String>,
+        error: String,
+    },
+}
+
+impl Event {
+    fn kind(&self) -> WebhookEvent {
+        match self {
+            Event::SchemaReloaded { .. } => WebhookEvent::SchemaReloaded,
+            Event::ConfigurationReloaded => WebhookEvent::ConfigurationReloaded,
+            Event::CircuitOpened { .. } => WebhookEvent::CircuitOpened,
+            Event::CircuitClosed { .. } => WebhookEvent::CircuitClosed,
+            Event::UplinkError { .. } => WebhookEvent::UplinkError,
+            Event::CanaryFailed { .. } => WebhookEvent::CanaryFailed,
+        }
+    }
+}
+
+/// The body of the webhook requests.
+#[derive(Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    event: &'a Event,
+    /// Seconds since the UNIX epoch.
+    timestamp: u64,
+}
+
+/// Sends the events to the webhook endpoints of a configuration.
+#[derive(Debug, Clone)]
+pub(crate) struct Notifier {
+    config: Arc<Webhooks>,
+    client: reqwest::Client,
+}
+
+impl Notifier {
+    fn new(config: Webhooks) -> Result<Self, BoxError> {
+        let client = reqwest::Client::builder().timeout(config.timeout).build()?;
+        Ok(Notifier {
+            config: Arc::new(config),
+            client,
+        })
+    }
+
+    /// The notifier of the webhooks of a configuration, if there are any.
+    pub(crate) fn configured(webhooks: Option<&Webhooks>) -> Option<Self> {
+        webhooks.and_then(|webhooks| match Notifier::new(webhooks.clone()) {
+            Ok(notifier) => Some(notifier),
+            Err(e) => {
+                tracing::error!("could not create the webhook client: {}", e);
+                None
+            }
+        })
+    }
+
+    /// Send an event to the webhook endpoints subscribed to it.
+    pub(crate) fn notify(&self, event: Event) {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        let body = match serde_json::to_vec(&Payload {
+            event: &event,
+            timestamp,
+        }) {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::error!("could not serialize the webhook event: {}", e);
+                return;
+            }
+        };
+
+        for (index, endpoint) in self.config.endpoints.iter().enumerate() {
+            if !endpoint.events.is_empty() && !endpoint.events.contains(&event.kind()) {
+                continue;
+            }
+            let notifier = self.clone();
+            let body = body.clone();
+            tokio::spawn(async move {
+                let endpoint = &notifier.config.endpoints[index];
+                if let Err(e) = notifier.deliver(endpoint, body).await {
+                    tracing::error!(
+                        "could not send the webhook event to {}: {}",
+                        endpoint.url,
+                        e
+                    );
+                }
+            });
+        }
+    }
+
+    /// Send the event to an endpoint, retrying failed deliveries.
+    async fn deliver(&self, endpoint: &WebhookEndpoint, body: Vec<u8>) -> Result<(), BoxError> {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 0;
+        loop {
+            let mut request = self
+                .client
+                .post(endpoint.url.clone())
+                .header(CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(secret) = &endpoint.secret {
+                request = request.header(
+                    SIGNATURE_HEADER,
+                    format!("sha256={}", sign(secret.as_bytes(), &body)),
+                );
+            }
+            let result = match request.send().await {
+                Ok(response) => response.error_for_status().map(|_| ()),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt >= self.config.max_retries => return Err(e.into()),
+                Err(e) => {
+                    tracing::debug!(
+                        "webhook delivery to {} failed, retrying in {:?}: {}",
+                        endpoint.url,
+                        backoff,
+                        e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+/// HMAC-SHA256 of the body, in hexadecimal.
+fn sign(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Mutex;
+
+    use axum::http::HeaderMap;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::Router;
+    use bytes::Bytes;
+
+    use super::*;
+
+    #[test]
+    fn it_signs_with_hmac_sha256() {
+        // RFC 4231, test cases 2 and 6
+        assert_eq!(
+            sign(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            sign(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            ),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[tokio::test]
+    async fn it_retries_signed_deliveries() {
+        // the endpoint fails once before accepting the event
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let endpoint_attempts = attempts.clone();
+        let endpoint_received = received.clone();
+        let app = Router::new().route(
+            "/",
+            post(move |headers: HeaderMap, body: Bytes| {
+                let attempt = endpoint_attempts.fetch_add(1, Ordering::SeqCst);
+                endpoint_received.lock().unwrap().push((
+                    headers
+                        .get(SIGNATURE_HEADER)
+                        .map(|signature| signature.to_str().unwrap().to_string()),
+                    body,
+                ));
+                async move {
+                    if attempt == 0 {
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    } else {
+                        StatusCode::OK
+                    }
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(
+            hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let notifier = Notifier::new(
+            serde_json::from_value(serde_json::json!({
+                "endpoints": [{ "url": url, "secret": "secret" }]
+            }))
+            .unwrap(),
+        )
+        .unwrap();
+        let body = serde_json::to_vec(&Payload {
+            event: &Event::CircuitOpened {
+                subgraph: "products".to_string(),
+            },
+            timestamp: 1663113600,
+        })
+        .unwrap();
+        assert_eq!(
+            std::str::from_utf8(&body).unwrap(),
+            r#"{"type":"circuit_opened","subgraph":"products","timestamp":1663113600}"#
+        );
+
+        notifier
+            .deliver(&notifier.config.endpoints[0], body.clone())
+            .await
+            .unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        let received = received.lock().unwrap();
+        assert_eq!(received[1].1, body);
+        assert_eq!(
+            received[1].0.as_deref(),
+            Some(format!("sha256={}", sign(b"secret", &body)).as_str())
+        );
+    }
+}
@@ -258,6 +258,7 @@ async fn service_errors_should_be_propagated() {
     let message = "value retrieval failed: couldn't plan query: query validation errors: Unknown operation named \"invalidOperationName\"";
     let expected_error = apollo_router::graphql::Error::builder()
         .message(message)
+        .code(apollo_router::graphql::ErrorCode::GraphqlValidationFailed)
         .build();
 
     let request = supergraph::Request::fake_builder()
@@ -466,12 +467,16 @@ async fn missing_variables() {
     let mut response = http_response.next_response().await.unwrap();
     let mut expected = vec![
         graphql::Error::builder()
-            .message("invalid type for variable: 'missingVariable'")
+            .message(r#"Variable "$missingVariable" of required type "Int!" was not provided."#)
+            .code(graphql::ErrorCode::BadUserInput)
             .extension("type", "ValidationInvalidTypeVariable")
             .extension("name", "missingVariable")
             .build(),
         graphql::Error::builder()
-            .message("invalid type for variable: 'yetAnotherMissingVariable'")
+            .message(
+                r#"Variable "$yetAnotherMissingVariable" of required type "ID!" was not provided."#,
+            )
+            .code(graphql::ErrorCode::BadUserInput)
             .extension("type", "ValidationInvalidTypeVariable")
             .extension("name", "yetAnotherMissingVariable")
             .build(),
@@ -644,6 +649,225 @@ async fn defer_query_without_accept() {
     insta::assert_json_snapshot!(first);
 }
 
+#[tokio::test(flavor = "multi_thread")]
+async fn requires_fields_are_sent_to_the_dependent_subgraph() {
+    let (router, representations) = setup_requires_router(
+        json!({"topProducts": [
+            {"__typename": "Product", "upc": "1", "name": "Table", "price": 899, "weight": 100},
+            {"__typename": "Product", "upc": "2", "name": "Couch", "price": 1299, "weight": 1000}
+        ]}),
+        json!({"_entities": [{"shippingEstimate": 50}, {"shippingEstimate": 0}]}),
+    )
+    .await;
+    let request = supergraph::Request::fake_builder()
+        .query("{ topProducts { name shippingEstimate } }")
+        .build()
+        .expect("expecting valid request");
+
+    let response = query_with_router(router, request).await;
+
+    assert_eq!(response.errors, []);
+    assert_eq!(
+        response.data,
+        Some(json!({"topProducts": [
+            {"name": "Table", "shippingEstimate": 50},
+            {"name": "Couch", "shippingEstimate": 0}
+        ]}))
+    );
+    assert_eq!(
+        representations.lock().unwrap().take(),
+        Some(json!([
+            {"__typename": "Product", "upc": "1", "price": 899, "weight": 100},
+            {"__typename": "Product", "upc": "2", "price": 1299, "weight": 1000}
+        ]))
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn missing_required_fields_are_reported() {
+    let (router, representations) = setup_requires_router(
+        json!({"topProducts": [
+            {"__typename": "Product", "upc": "1", "name": "Table", "price": 899},
+            {"__typename": "Product", "upc": "2", "name": "Couch", "price": 1299, "weight": null}
+        ]}),
+        json!({"_entities": [{"shippingEstimate": 0}]}),
+    )
+    .await;
+    let request = supergraph::Request::fake_builder()
+        .query("{ topProducts { name shippingEstimate } }")
+        .build()
+        .expect("expecting valid request");
+
+    let response = query_with_router(router, request).await;
+
+    // the entity missing a required field is not fetched, and the error points to it
+    assert_eq!(response.errors.len(), 1);
+    assert_eq!(
+        response.errors[0].message,
+        "subquery requires field 'weight' but it was not found in the current response"
+    );
+    assert_eq!(
+        serde_json::to_value(&response.errors[0].path).unwrap(),
+        serde_json::json!(["topProducts", 0])
+    );
+    assert_eq!(
+        response.data,
+        Some(json!({"topProducts": [
+            {"name": "Table", "shippingEstimate": null},
+            {"name": "Couch", "shippingEstimate": 0}
+        ]}))
+    );
+    // null required fields are sent as is
+    assert_eq!(
+        representations.lock().unwrap().take(),
+        Some(json!([
+            {"__typename": "Product", "upc": "2", "price": 1299, "weight": null}
+        ]))
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn plugins_modify_the_subgraph_requests_and_responses() {
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let recorded_requests = requests.clone();
+    let router = apollo_router::TestHarness::builder()
+        .with_subgraph_network_requests()
+        .schema(include_str!("../../examples/graphql/local.graphql"))
+        .extra_plugin(SubgraphAuth)
+        .subgraph_hook(move |subgraph_name, service| {
+            if subgraph_name != "products" {
+                return service;
+            }
+            let requests = recorded_requests.clone();
+            tower::service_fn(move |request: subgraph::Request| {
+                let subgraph_request = &request.subgraph_request;
+                requests.lock().unwrap().push((
+                    request.subgraph_name.clone(),
+                    subgraph_request.headers().get("authorization").cloned(),
+                    subgraph_request.body().extensions.get("tenant").cloned(),
+                ));
+                let response = subgraph::Response::fake_builder()
+                    .data(json!({"topProducts": [{"name": "Table"}]}))
+                    .context(request.context)
+                    .build();
+                std::future::ready(Ok::<_, BoxError>(response))
+            })
+            .boxed()
+        })
+        .build()
+        .await
+        .unwrap();
+    let request = supergraph::Request::fake_builder()
+        .query("{ topProducts { name } }")
+        .build()
+        .expect("expecting valid request");
+
+    let response = query_with_router(router, request).await;
+
+    assert_eq!(
+        response.data,
+        Some(json!({"topProducts": [{"name": "Table (products)"}]}))
+    );
+    assert_eq!(
+        *requests.lock().unwrap(),
+        vec![(
+            "products".to_string(),
+            Some(http::HeaderValue::from_static("Bearer products")),
+            Some(json!("acme")),
+        )]
+    );
+}
+
+/// Authenticates the requests to the subgraphs, and marks the names in their responses.
+struct SubgraphAuth;
+
+#[async_trait::async_trait]
+impl Plugin for SubgraphAuth {
+    type Config = ();
+
+    async fn new(_: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        unreachable!()
+    }
+
+    fn subgraph_service(
+        &self,
+        subgraph_name: &str,
+        service: subgraph::BoxService,
+    ) -> subgraph::BoxService {
+        let name = subgraph_name.to_owned();
+        service
+            .map_request(|mut request: subgraph::Request| {
+                let token = format!("Bearer {}", request.subgraph_name);
+                let subgraph_request = &mut request.subgraph_request;
+                subgraph_request
+                    .headers_mut()
+                    .insert("authorization", token.parse().unwrap());
+                subgraph_request
+                    .body_mut()
+                    .extensions
+                    .insert("tenant", json!("acme"));
+                request
+            })
+            .map_response(move |mut response: subgraph::Response| {
+                if let Some(Value::Object(data)) = response.response.body_mut().data.as_mut() {
+                    if let Some(Value::Array(products)) = data.get_mut("topProducts") {
+                        for product in products {
+                            if let Value::Object(product) = product {
+                                if let Some(Value::String(product_name)) = product.get_mut("name") {
+                                    *product_name =
+                                        format!("{} ({})", product_name.as_str(), name).into();
+                                }
+                            }
+                        }
+                    }
+                }
+                response
+            })
+            .boxed()
+    }
+}
+
+/// A router with mocked `products` and `inventory` subgraphs, recording the representations
+/// sent to `inventory` to resolve the `shippingEstimate` field, which requires `price weight`.
+async fn setup_requires_router(
+    products_data: Value,
+    inventory_data: Value,
+) -> (supergraph::BoxCloneService, Arc<Mutex<Option<Value>>>) {
+    let representations = Arc::new(Mutex::new(None));
+    let recorded_representations = representations.clone();
+    let router = apollo_router::TestHarness::builder()
+        .with_subgraph_network_requests()
+        .schema(include_str!("../../examples/graphql/local.graphql"))
+        .subgraph_hook(move |subgraph_name, service| {
+            let data = match subgraph_name {
+                "products" => products_data.clone(),
+                "inventory" => inventory_data.clone(),
+                _ => return service,
+            };
+            let representations = recorded_representations.clone();
+            tower::service_fn(move |request: subgraph::Request| {
+                if let Some(value) = request
+                    .subgraph_request
+                    .body()
+                    .variables
+                    .get("representations")
+                {
+                    *representations.lock().unwrap() = Some(value.clone());
+                }
+                let response = subgraph::Response::fake_builder()
+                    .data(data.clone())
+                    .context(request.context)
+                    .build();
+                std::future::ready(Ok::<_, BoxError>(response))
+            })
+            .boxed()
+        })
+        .build()
+        .await
+        .unwrap();
+    (router, representations)
+}
+
 async fn query_node(request: &supergraph::Request) -> Result<graphql::Response, String> {
     reqwest::Client::new()
         .post("https://federation-demo-gateway.fly.dev/")
@@ -48,6 +48,24 @@ impl ReportSpaceport {
     pub async fn new(
         addr: SocketAddr,
         shutdown_signal: Option<Pin<Box<dyn Future<Output = ()> + Send + Sync>>>,
+    ) -> Result<Self, std::io::Error> {
+        Self::start(addr, shutdown_signal, None).await
+    }
+
+    /// Create a new ReportSpaceport like [`ReportSpaceport::new`], sending the reports it
+    /// failed to transfer to `failed_reports` instead of discarding them.
+    pub async fn with_failed_reports(
+        addr: SocketAddr,
+        shutdown_signal: Option<Pin<Box<dyn Future<Output = ()> + Send + Sync>>>,
+        failed_reports: Sender<ReporterRequest>,
+    ) -> Result<Self, std::io::Error> {
+        Self::start(addr, shutdown_signal, Some(failed_reports)).await
+    }
+
+    async fn start(
+        addr: SocketAddr,
+        shutdown_signal: Option<Pin<Box<dyn Future<Output = ()> + Send + Sync>>>,
+        failed_reports: Option<Sender<ReporterRequest>>,
     ) -> Result<Self, std::io::Error> {
         let listener = TcpListener::bind(addr).await?;
         let addr = listener.local_addr()?;
@@ -58,12 +76,19 @@ impl ReportSpaceport {
         tokio::task::spawn(async move {
             let client = Client::new();
             while let Some(report) = rx.recv().await {
-                if let Some(report_to_send) = report.report {
-                    match ReportSpaceport::submit_report(&client, report.apollo_key, report_to_send)
+                if let Some(report_to_send) = &report.report {
+                    match ReportSpaceport::send(&client, &report.apollo_key, report_to_send, 5)
                         .await
                     {
                         Ok(v) => tracing::debug!("report submission succeeded: {:?}", v),
-                        Err(e) => tracing::error!("report submission failed: {}", e),
+                        Err(e) => {
+                            tracing::error!("report submission failed: {}", e);
+                            if let Some(failed_reports) = &failed_reports {
+                                if let Err(e) = failed_reports.try_send(report) {
+                                    tracing::warn!("failed report discarded: {}", e);
+                                }
+                            }
+                        }
                     }
                 }
             }
@@ -96,10 +121,20 @@ impl ReportSpaceport {
             .await
     }
 
-    async fn submit_report(
+    /// Make a single attempt to transfer a report to the Apollo Ingress.
+    pub async fn transfer(
         client: &Client,
-        key: String,
-        report: Report,
+        key: &str,
+        report: &Report,
+    ) -> Result<Response<ReporterResponse>, Status> {
+        Self::send(client, key, report, 1).await
+    }
+
+    async fn send(
+        client: &Client,
+        key: &str,
+        report: &Report,
+        attempts: usize,
     ) -> Result<Response<ReporterResponse>, Status> {
         tracing::debug!("submitting report: {:?}", report);
         // Protobuf encode message
@@ -141,7 +176,7 @@ impl ReportSpaceport {
             .map_err(|e| Status::unavailable(e.to_string()))?;
 
         let mut msg = "default error message".to_string();
-        for i in 0..5 {
+        for i in 0..attempts {
             // We know these requests can be cloned
             let task_req = req.try_clone().expect("requests must be clone-able");
             match client.execute(task_req).await {
@@ -177,8 +212,10 @@ impl ReportSpaceport {
                     msg = e.to_string();
                 }
             }
-            backoff += Duration::from_millis(50);
-            tokio::time::sleep(backoff).await;
+            if i + 1 < attempts {
+                backoff += Duration::from_millis(50);
+                tokio::time::sleep(backoff).await;
+            }
         }
         Err(Status::unavailable(msg))
     }